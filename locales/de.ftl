@@ -0,0 +1,186 @@
+document-not-found = Dokument nicht gefunden.
+document-load-failed = Das Dokument konnte nicht geladen werden.
+internal-error = Bei uns ist ein Fehler aufgetreten. Bitte versuchen Sie es erneut.
+update-buffer-full = Zu viele ungespeicherte Änderungen. Bitte warten Sie, bis das Dokument gespeichert ist.
+update-storage-unavailable = Der Dokumentspeicher ist nicht erreichbar, daher werden keine Änderungen angenommen.
+update-failed = Ihre Änderung konnte nicht übernommen werden.
+invalid-timezone = Unbekannte Zeitzone „{ $timezone }“. Verwenden Sie einen IANA-Namen wie Europe/Berlin.
+rate-limited = Zu viele Anfragen. Bitte versuchen Sie es in Kürze erneut.
+invalid-request-body = Der Anfragetext ist für diesen Endpunkt kein gültiges JSON.
+invalid-analytics-event = Ereignis { $index } wurde abgelehnt: { $reason }
+invalid-analytics-batch = Der Ereignisstapel wurde abgelehnt: { $reason }
+invalid-token = Das Zugriffstoken ist ungültig, abgelaufen oder widerrufen.
+document-forbidden = Sie haben keinen Zugriff auf dieses Dokument.
+update-forbidden = Sie können dieses Dokument ansehen, aber nicht bearbeiten.
+presence-only-connection = Diese Verbindung zeigt nur an, wer anwesend ist. Verbinden Sie sich ohne mode=presence neu, um zu bearbeiten.
+invalid-search-query = Geben Sie mit ?q= einen Suchbegriff an.
+admin-disabled = Administrationsendpunkte sind auf diesem Server deaktiviert.
+server-draining = Dieser Server wird gleich heruntergefahren. Verbinden Sie sich erneut, um einen anderen zu erreichen.
+admin-forbidden = Das dürfen nur Administratoren.
+admin-outranked = Sie können nur Konten verwalten und Rollen vergeben, die unter Ihrer eigenen Rolle liegen.
+update-protected-range = Dieser Teil des Dokuments ist gesperrt, daher wurde Ihre Änderung rückgängig gemacht.
+invalid-protected-range = Dieser Bereich ist leer oder liegt außerhalb des Dokuments.
+protection-forbidden = Nur Eigentümer des Dokuments können Teile davon sperren oder entsperren.
+invalid-client-frame = Diese Nachricht konnte nicht verarbeitet werden.
+invalid-display-name = Geben Sie einen Anzeigenamen mit höchstens { $max } Zeichen ein.
+invalid-email = Geben Sie eine gültige E-Mail-Adresse ein.
+weak-password = Wählen Sie ein Passwort mit mindestens { $min } Zeichen.
+email-taken = Es gibt bereits ein Konto mit dieser E-Mail-Adresse.
+invalid-credentials = E-Mail-Adresse oder Passwort ist falsch.
+sync-too-many-documents = Synchronisieren Sie höchstens { $max } Dokumente pro Anfrage.
+sign-in-required = Bitte melden Sie sich an, um fortzufahren.
+document-create-forbidden = Bot-Tokens können keine Dokumente erstellen.
+invalid-causality-token = Der X-Causality-Token-Header enthält kein von diesem Server ausgestelltes Token.
+invalid-document-content = Dokumentinhalte müssen Base64-kodiert sein.
+invalid-document-name = Geben Sie einen Dokumentnamen mit höchstens { $max } Zeichen ein.
+document-owner-required = Nur Eigentümer eines Dokuments können es umbenennen oder löschen.
+invalid-listing-query = Verwenden Sie ?limit= mit einer Zahl und ?sort= mit updated_desc, updated_asc, created_desc, created_asc oder name.
+invalid-cursor = Dieser Seitencursor ist ungültig oder gehört zu einer anderen Sortierung. Beginnen Sie erneut bei der ersten Seite.
+attachments-disabled = Dateianhänge sind auf diesem Server nicht aktiviert.
+attachment-not-found = Anhang nicht gefunden.
+invalid-attachment-name = Benennen Sie die Datei mit ?name= und höchstens { $max } Zeichen.
+attachment-too-large = Anhänge dürfen höchstens { $max } Byte groß sein.
+attachment-scan-pending = Diese Datei wird noch auf Schadsoftware geprüft. Versuchen Sie es gleich noch einmal.
+attachment-infected = Diese Datei enthält Schadsoftware und kann nicht heruntergeladen werden.
+workspace-member-required = Nur Mitglieder dieses Arbeitsbereichs können das tun.
+workspace-admin-required = Nur Administratoren des Arbeitsbereichs können Mitglieder und Richtlinien verwalten.
+invalid-workspace-name = Geben Sie einen Namen für den Arbeitsbereich mit höchstens { $max } Zeichen ein.
+user-not-found = Benutzer nicht gefunden.
+default-policy-not-found = Für diesen Arbeitsbereich ist keine Standardrichtlinie festgelegt.
+export-transforms-not-found = Für diesen Arbeitsbereich sind keine Export-Transformationen festgelegt.
+preview-unavailable = Für dieses Dokument ist keine Vorschau verfügbar.
+digest-email-subject = Ihre Dokumentübersicht
+digest-email-watched-heading = Änderungen an Dokumenten, die Sie beobachten
+digest-email-watched-document = { $name } (Änderungen: { $changes })
+digest-email-other-notification = Sie haben eine neue Benachrichtigung.
+digest-email-owned-heading = Ihre geänderten Dokumente
+digest-email-unsubscribe = Diese E-Mails abbestellen
+digest-unsubscribed = Sie erhalten keine Übersichts-E-Mails mehr.
+invalid-unsubscribe-link = Dieser Abmeldelink ist ungültig.
+document-name-taken = Hier gibt es bereits ein Dokument namens „{ $name }“. Versuchen Sie { $suggestions }.
+version-not-found = Diese Version des Dokuments existiert nicht.
+missing-field = Dieses Feld ist erforderlich.
+invalid-field-value = Dieses Feld hat den falschen Typ oder einen unbekannten Wert.
+document-deleted = Dieses Dokument wurde gelöscht.
+server-shutting-down = Der Server wird neu gestartet. Sie werden in Kürze wieder verbunden.
+invalid-color = Wählen Sie eine Farbe in der Form #rrggbb.
+guest-not-found = In diesem Browser wurde noch kein Gastname gewählt.
+guest-signed-in = Sie sind angemeldet und benötigen keinen Gastnamen.
+invalid-bulk-documents = Geben Sie zwischen 1 und { $max } Dokumente an.
+verify-email-subject = Bestätigen Sie Ihre E-Mail-Adresse
+verify-email-body = Bestätigen Sie, dass dies Ihre E-Mail-Adresse ist, um die Einrichtung Ihres Kontos abzuschließen. Der Link ist zwei Tage gültig.
+verify-email-action = E-Mail-Adresse bestätigen
+email-verified = Ihre E-Mail-Adresse ist bestätigt.
+invalid-verification-link = Dieser Bestätigungslink ist ungültig oder abgelaufen.
+email-already-verified = Ihre E-Mail-Adresse ist bereits bestätigt.
+email-disabled = Dieser Server versendet keine E-Mails.
+inactive-account-email-subject = Ihr Konto wird deaktiviert
+inactive-account-email-body = Sie haben sich lange nicht angemeldet. Melden Sie sich vor dem { $date } an, damit Ihr Konto aktiv bleibt; andernfalls wird es bis zu Ihrer nächsten Anmeldung deaktiviert.
+password-reset-subject = Setzen Sie Ihr Passwort zurück
+password-reset-body = Jemand hat angefordert, das Passwort Ihres Kontos zurückzusetzen. Wenn Sie das waren, wählen Sie innerhalb der nächsten Stunde ein neues Passwort. Andernfalls können Sie diese E-Mail ignorieren.
+password-reset-action = Neues Passwort wählen
+invalid-password-reset-link = Dieser Link zum Zurücksetzen des Passworts ist ungültig oder abgelaufen.
+session-not-found = Sie haben keine aktive Sitzung mit dieser ID.
+account-deletion-forbidden = Nur Sie selbst können Ihr Konto löschen, und nur, während Sie angemeldet sind; API-Schlüssel und Bots können das nicht.
+invalid-comment-body = Geben Sie einen Kommentar mit höchstens { $max } Zeichen ein.
+comment-forbidden = Sie können dieses Dokument ansehen, aber nicht kommentieren.
+comment-rejected = Der Kommentar wurde abgelehnt: { $reason }
+account-locked = Zu viele falsche Passwörter. Versuchen Sie es in { $minutes } Minuten erneut.
+document-not-in-trash = Dieses Dokument befindet sich nicht im Papierkorb.
+oauth-authorize-forbidden = Mit API-Schlüsseln können keine Apps autorisiert werden. Melden Sie sich an, um eine zu autorisieren.
+oauth-invalid-request = In der OAuth-Anfrage fehlt ein Parameter oder ein Parameter ist ungültig.
+oauth-unknown-client = Unbekannter OAuth-Client oder eine nicht registrierte Weiterleitungs-URI.
+oauth-unsupported-response-type = Nur der Antworttyp "code" wird unterstützt.
+oauth-pkce-required = Eine PKCE-Code-Challenge mit der Methode S256 ist erforderlich.
+oauth-invalid-scope = Der angeforderte Geltungsbereich ist leer oder unbekannt.
+oauth-unsupported-grant-type = Nur der Grant-Typ "authorization_code" wird unterstützt.
+oauth-invalid-grant = Der Autorisierungscode ist ungültig, abgelaufen, bereits verwendet oder passt nicht zur Anfrage.
+oauth-consent-not-found = Sie haben dieser Anwendung keinen Zugriff gewährt.
+invalid-folder-name = Geben Sie einen Ordnernamen mit höchstens { $max } Zeichen ein.
+folder-not-found = Ordner nicht gefunden.
+folder-name-taken = Hier gibt es bereits einen Ordner mit diesem Namen.
+folder-move-cycle = Ein Ordner kann nicht in sich selbst oder einen seiner Unterordner verschoben werden.
+folder-different-root = Ordner und Dokumente können nur innerhalb desselben Arbeitsbereichs oder der eigenen Dokumente verschoben werden.
+storage-quota-exceeded = Speicherkontingent überschritten: { $used } von { $quota } Bytes sind belegt.
+invalid-event-stream-query = Geben Sie mit ?from_seq= die Sequenznummer an, ab der wiedergegeben werden soll.
+magic-link-subject = Ihr Anmeldelink
+magic-link-body = Jemand hat angefordert, sich ohne Passwort bei Ihrem Konto anzumelden. Wenn Sie das waren, folgen Sie diesem Link innerhalb der nächsten 15 Minuten in demselben Browser, in dem Sie ihn angefordert haben. Andernfalls können Sie diese E-Mail ignorieren.
+magic-link-action = Anmelden
+invalid-magic-link = Dieser Anmeldelink ist ungültig, abgelaufen oder wurde in einem anderen Browser geöffnet.
+magic-links-disabled = Dieser Server bietet keine Anmeldelinks an.
+update-rate-limited = Sie bearbeiten schneller, als dieses Dokument zulässt, daher wurde Ihre Änderung rückgängig gemacht.
+update-too-large = Diese Änderung ist zu groß, daher wurde sie rückgängig gemacht.
+message-limit-exceeded = Diese Verbindung hat zu viele Änderungen zu schnell gesendet und wurde geschlossen.
+update-server-migrating = Dieses Dokument wird auf einen anderen Server verschoben. Ihre Änderung wird erneut gesendet, sobald Sie wieder verbunden sind.
+update-document-expired = Dieses Dokument ist abgelaufen und kann nicht mehr bearbeitet werden.
+update-document-too-large = Diese Änderung würde das Dokument zu groß machen, daher wurde sie rückgängig gemacht.
+document-too-large = Dokumente dürfen höchstens { $max } Byte groß sein.
+request-body-too-large = Der Anfragetext ist zu groß.
+invalid-document-expiry = Ein Dokument muss in der Zukunft ablaufen.
+document-expired = Dieses Dokument ist abgelaufen. Es ist ab jetzt schreibgeschützt.
+export-attachments = Anhänge
+export-image-undescribed = { $file } (Bild, noch ohne Beschreibung)
+invalid-export-query = Verwenden Sie ?profile= mit standard oder a11y.
+export-unavailable = Dieses Dokument kann nicht exportiert werden.
+export-transform-failed = Die Export-Transformationen dieses Arbeitsbereichs sind fehlgeschlagen. Versuchen Sie es später erneut.
+invalid-share-link = Dieser Freigabelink ist ungültig, abgelaufen oder wurde widerrufen.
+invalid-share-link-expiry = Ein Freigabelink muss in der Zukunft ablaufen.
+share-link-not-found = Freigabelink nicht gefunden.
+publication-blocked = Dieses Dokument kann erst per Link freigegeben werden, wenn es moderiert wurde.
+moderation-review-not-found = Diese Moderationsprüfung existiert nicht oder wurde bereits entschieden.
+alert-crossed-subject = Weiches Limit erreicht: { $resource }
+alert-crossed-body = Die Nutzung von { $resource } beträgt { $usage } und hat das weiche Limit von { $threshold } erreicht.
+alert-cleared-subject = Wieder unter dem weichen Limit: { $resource }
+alert-cleared-body = Die Nutzung von { $resource } ist auf { $usage } gesunken und liegt wieder unter dem weichen Limit von { $threshold }.
+invalid-comment-anchor = Verankern Sie eine neue Diskussion an einer oder zwei kodierten Positionen; Antworten teilen den Anker ihrer Diskussion.
+comment-not-found = Dieser Kommentar existiert nicht.
+comment-delete-forbidden = Nur die Person, die den Kommentar verfasst hat, oder der Eigentümer des Dokuments kann ihn löschen.
+deletion-request-not-found = Diese Löschanfrage existiert nicht oder steht nicht mehr aus.
+deletion-self-approval = Das Löschen dieses Dokuments muss von einer anderen Person als der anfragenden genehmigt werden.
+folder-deletion-protected = Dokumente in diesem Ordner dürfen nur mit einer zweiten Genehmigung gelöscht werden. Löschen Sie sie einzeln oder heben Sie zuerst die Löschgenehmigung des Ordners auf.
+invalid-organization-name = Geben Sie einen Organisationsnamen mit höchstens { $max } Zeichen ein.
+invalid-team-name = Geben Sie einen Teamnamen mit höchstens { $max } Zeichen ein.
+too-many-export-transforms = Fügen Sie höchstens { $max } Export-Transformationen hinzu.
+export-transform-too-long = Diese Transformation ist zu lang.
+invalid-redaction-pattern = Geben Sie ein gültiges Schwärzungsmuster ein.
+invalid-export-webhook-url = Geben Sie eine https-URL für den Webhook ein.
+invalid-encryption-key-id = Geben Sie die ID des Schlüssels in Ihrem Schlüsselverwaltungsdienst mit höchstens { $max } Zeichen ein.
+organization-not-found = Diese Organisation existiert nicht.
+organization-admin-required = Nur Administratoren der Organisation können das tun.
+organization-owner-required = Nur Eigentümer der Organisation können Administratoren und Eigentümer ernennen oder entfernen.
+organization-member-not-found = Diese Person ist kein Mitglied dieser Organisation.
+organization-last-owner = Eine Organisation braucht mindestens einen Eigentümer. Ernennen Sie zuerst eine andere Person zum Eigentümer.
+invite-not-found = Diese Einladung existiert nicht oder ist abgelaufen.
+invite-for-someone-else = Diese Einladung wurde an eine andere E-Mail-Adresse gesendet.
+team-not-found = Dieses Team existiert nicht.
+team-name-taken = Die Organisation hat bereits ein Team mit diesem Namen.
+team-member-not-found = Diese Person gehört nicht zu diesem Team.
+team-member-not-in-organization = Nur Mitglieder der Organisation können ihren Teams beitreten.
+encryption-disabled = Verschlüsselung mit eigenem Schlüssel ist auf diesem Server nicht eingerichtet.
+encryption-key-not-found = Diese Organisation verwendet keinen Verschlüsselungsschlüssel.
+encryption-key-unusable = Der Schlüsselverwaltungsdienst konnte diesen Schlüssel nicht verwenden. Prüfen Sie die Schlüssel-ID und ob dieser Server den Schlüssel verwenden darf.
+encryption-key-revoked = Diese Organisation hat ihren Verschlüsselungsschlüssel widerrufen, daher können ihre Dokumente nicht geändert werden.
+document-unreadable = Der Inhalt dieses Dokuments ist nicht mehr lesbar, da seine Organisation ihren Verschlüsselungsschlüssel widerrufen hat.
+invalid-import-query = Geben Sie an, aus welchem Programm das Archiv exportiert wurde: confluence oder notion.
+import-archive-empty = Senden Sie das exportierte ZIP-Archiv als Anfragetext.
+import-job-not-found = Import nicht gefunden.
+invalid-blame-range = Geben Sie den Bereich als Byte-Offsets im Format Anfang-Ende an, wobei der Anfang nicht größer als das Ende sein darf.
+organization-invite-subject = { $inviter } hat Sie zu { $organization } eingeladen
+organization-invite-body = { $inviter } hat Sie eingeladen, { $organization } beizutreten. Melden Sie sich mit dieser E-Mail-Adresse an oder erstellen Sie damit ein Konto, um die Einladung anzunehmen. Die Einladung läuft in sieben Tagen ab.
+organization-invite-action = Einladung annehmen
+login-provider-not-found = Dieser Server bietet keine Anmeldung über diesen Anbieter an.
+invalid-login-callback = Der Anmeldeanbieter hat eine unvollständige Antwort gesendet. Bitte melden Sie sich erneut an.
+login-declined = Die Anmeldung wurde beim Anbieter abgebrochen.
+login-expired = Diese Anmeldung ist abgelaufen oder wurde in einem anderen Browser begonnen. Bitte melden Sie sich erneut an.
+login-provider-failed = Der Anmeldeanbieter war nicht erreichbar oder hat Ihre Identität nicht bestätigt. Bitte versuchen Sie es später erneut.
+account-unavailable = Mit diesem Konto ist keine Anmeldung mehr möglich.
+identity-linked-elsewhere = Dieses Konto beim Anbieter ist bereits mit einem anderen Konto hier verknüpft.
+identity-email-taken = Ein Konto hier verwendet diese E-Mail-Adresse bereits. Melden Sie sich mit Ihrem Passwort an und verknüpfen Sie den Anbieter von dort aus.
+invalid-api-key-name = Geben Sie dem API-Schlüssel einen Namen mit höchstens { $max } Zeichen.
+invalid-api-key-documents = Beschränken Sie einen API-Schlüssel auf 1 bis { $max } Dokumente oder lassen Sie die Liste weg.
+invalid-api-key-expiry = Ein API-Schlüssel muss in der Zukunft ablaufen.
+too-many-api-keys = Sie haben bereits { $max } API-Schlüssel. Widerrufen Sie einen, bevor Sie einen neuen erstellen.
+api-key-not-found = Sie haben keinen solchen API-Schlüssel.
+api-key-forbidden = Mit API-Schlüsseln können keine weiteren API-Schlüssel erstellt werden. Melden Sie sich an, um einen zu erstellen.
+api-key-read-only = Dieser API-Schlüssel kann nur zum Lesen verwendet werden.
+api-key-documents-only = Dieser API-Schlüssel ist auf bestimmte Dokumente beschränkt und kann hier nicht verwendet werden.
@@ -0,0 +1,188 @@
+# English (fallback). Every other catalog must define the same message keys.
+
+document-not-found = Document not found.
+document-load-failed = The document could not be loaded.
+internal-error = Something went wrong on our end. Please try again.
+update-buffer-full = Too many unsaved changes. Please wait until the document is saved.
+update-storage-unavailable = Document storage is unavailable, so changes are not being accepted.
+update-failed = Your change could not be applied.
+invalid-timezone = Unknown timezone "{ $timezone }". Use an IANA name such as Europe/Berlin.
+rate-limited = Too many requests. Please slow down and try again shortly.
+invalid-request-body = The request body is not valid JSON for this endpoint.
+invalid-analytics-event = Event { $index } was rejected: { $reason }
+invalid-analytics-batch = The event batch was rejected: { $reason }
+invalid-token = The access token is invalid, expired or revoked.
+document-forbidden = You do not have access to this document.
+update-forbidden = You can view this document but not edit it.
+presence-only-connection = This connection only shows who is here. Reconnect without mode=presence to edit.
+invalid-search-query = Add a search term with ?q=.
+admin-disabled = Administration endpoints are disabled on this server.
+server-draining = This server is about to shut down. Connect again to reach another one.
+admin-forbidden = Only administrators can do that.
+admin-outranked = You can only manage accounts, and give roles, below your own role.
+update-protected-range = This part of the document is locked, so your change was undone.
+invalid-protected-range = That range is empty or lies outside the document.
+protection-forbidden = Only document owners can lock or unlock parts of a document.
+invalid-client-frame = That message could not be understood.
+invalid-display-name = Enter a display name of at most { $max } characters.
+invalid-email = Enter a valid email address.
+weak-password = Choose a password of at least { $min } characters.
+email-taken = An account with this email address already exists.
+invalid-credentials = The email address or password is incorrect.
+sync-too-many-documents = Sync at most { $max } documents per request.
+sign-in-required = Sign in to continue.
+document-create-forbidden = Bot tokens cannot create documents.
+invalid-causality-token = The X-Causality-Token header is not a token this server issued.
+invalid-document-content = Document content must be base64 encoded.
+invalid-document-name = Enter a document name of at most { $max } characters.
+document-owner-required = Only document owners can rename or delete a document.
+invalid-listing-query = Use ?limit= with a number and ?sort= with updated_desc, updated_asc, created_desc, created_asc or name.
+invalid-cursor = That page cursor is invalid or belongs to a different sort order. Start again from the first page.
+attachments-disabled = File attachments are not enabled on this server.
+attachment-not-found = Attachment not found.
+invalid-attachment-name = Name the file with ?name=, using at most { $max } characters.
+attachment-too-large = Attachments can be at most { $max } bytes.
+attachment-scan-pending = This file is still being checked for malware. Try again shortly.
+attachment-infected = This file contains malware and cannot be downloaded.
+workspace-member-required = Only members of this workspace can do that.
+workspace-admin-required = Only workspace admins can manage members and policies.
+invalid-workspace-name = Enter a workspace name of at most { $max } characters.
+user-not-found = User not found.
+default-policy-not-found = This workspace has no default policy.
+export-transforms-not-found = This workspace has no export transforms.
+preview-unavailable = This document has no preview.
+digest-email-subject = Your document digest
+digest-email-watched-heading = Changes to documents you watch
+digest-email-watched-document = { $name } (changes: { $changes })
+digest-email-other-notification = You have a new notification.
+digest-email-owned-heading = Your documents that changed
+digest-email-unsubscribe = Unsubscribe from these emails
+digest-unsubscribed = You will no longer receive digest emails.
+invalid-unsubscribe-link = This unsubscribe link is invalid.
+document-name-taken = A document named "{ $name }" already exists here. Try { $suggestions }.
+version-not-found = That version of the document does not exist.
+missing-field = This field is required.
+invalid-field-value = This field has the wrong type or an unknown value.
+document-deleted = This document was deleted.
+server-shutting-down = The server is restarting. You will be reconnected shortly.
+invalid-color = Enter a color in the form #rrggbb.
+guest-not-found = No guest name has been chosen in this browser.
+guest-signed-in = You are signed in, so you do not need a guest name.
+invalid-bulk-documents = List between 1 and { $max } documents.
+verify-email-subject = Verify your email address
+verify-email-body = Confirm that this is your email address to finish setting up your account. The link expires in two days.
+verify-email-action = Verify email address
+email-verified = Your email address is verified.
+invalid-verification-link = This verification link is invalid or has expired.
+email-already-verified = Your email address is already verified.
+email-disabled = This server does not send email.
+inactive-account-email-subject = Your account will be deactivated
+inactive-account-email-body = You have not signed in for a long time. Sign in before { $date } to keep your account active; otherwise it will be deactivated until you next sign in.
+password-reset-subject = Reset your password
+password-reset-body = Someone asked to reset the password of your account. If it was you, choose a new password within the next hour. Otherwise, you can ignore this email.
+password-reset-action = Choose a new password
+invalid-password-reset-link = This password reset link is invalid or has expired.
+session-not-found = You have no active session with this ID.
+account-deletion-forbidden = Only you can delete your account, and only while signed in to it; API keys and bots can't.
+invalid-comment-body = Enter a comment of at most { $max } characters.
+comment-forbidden = You can view this document but not comment on it.
+comment-rejected = The comment was rejected: { $reason }
+account-locked = Too many wrong passwords. Try again in { $minutes } minutes.
+document-not-in-trash = This document is not in the trash.
+oauth-authorize-forbidden = API keys can't be used to authorize apps. Sign in to authorize one.
+oauth-invalid-request = The OAuth request is missing a parameter or has a malformed one.
+oauth-unknown-client = Unknown OAuth client, or a redirect URI it did not register.
+oauth-unsupported-response-type = Only the "code" response type is supported.
+oauth-pkce-required = A PKCE code challenge using the S256 method is required.
+oauth-invalid-scope = The requested scope is empty or names an unknown scope.
+oauth-unsupported-grant-type = Only the "authorization_code" grant type is supported.
+oauth-invalid-grant = The authorization code is invalid, expired, already used, or does not match the request.
+oauth-consent-not-found = You have not granted that application access.
+invalid-folder-name = Enter a folder name of at most { $max } characters.
+folder-not-found = Folder not found.
+folder-name-taken = A folder with this name already exists here.
+folder-move-cycle = A folder cannot be moved into itself or one of its subfolders.
+folder-different-root = Folders and documents can only move within the same workspace or personal documents.
+storage-quota-exceeded = Storage quota exceeded: { $used } of { $quota } bytes are in use.
+invalid-event-stream-query = Give the sequence number to replay from with ?from_seq=.
+magic-link-subject = Your sign-in link
+magic-link-body = Someone asked to sign in to your account without a password. If it was you, follow this link within the next 15 minutes, in the same browser you asked from. Otherwise, you can ignore this email.
+magic-link-action = Sign in
+invalid-magic-link = This sign-in link is invalid, has expired or was opened in another browser.
+magic-links-disabled = This server does not offer sign-in links.
+update-rate-limited = You are editing faster than this document allows, so your change was undone.
+update-too-large = That change is too large to send, so it was undone.
+message-limit-exceeded = This connection sent too many changes too quickly and was closed.
+update-server-migrating = This document is moving to another server. Your change will be sent again once you reconnect.
+update-document-expired = This document has expired and can no longer be edited.
+update-document-too-large = That change would make the document too large, so it was undone.
+document-too-large = Documents can be at most { $max } bytes.
+request-body-too-large = The request body is too large.
+invalid-document-expiry = A document must expire in the future.
+document-expired = This document has expired. It is read-only from now on.
+export-attachments = Attachments
+export-image-undescribed = { $file } (image, no description yet)
+invalid-export-query = Use ?profile= with standard or a11y.
+export-unavailable = This document cannot be exported.
+export-transform-failed = This workspace's export transforms failed. Try again later.
+invalid-share-link = This share link is invalid, has expired or was revoked.
+invalid-share-link-expiry = A share link must expire in the future.
+share-link-not-found = Share link not found.
+publication-blocked = This document cannot be shared by link until a moderator has reviewed it.
+moderation-review-not-found = That moderation review does not exist or was already decided.
+alert-crossed-subject = Soft limit reached: { $resource }
+alert-crossed-body = Usage of { $resource } is { $usage }, at or above its soft limit of { $threshold }.
+alert-cleared-subject = Back under the soft limit: { $resource }
+alert-cleared-body = Usage of { $resource } is down to { $usage }, back under its soft limit of { $threshold }.
+invalid-comment-anchor = Anchor a new thread at one or two encoded positions; replies share their thread's anchor.
+comment-not-found = That comment does not exist.
+comment-delete-forbidden = Only the comment's author or the document's owner can delete it.
+deletion-request-not-found = That deletion request does not exist or is no longer pending.
+deletion-self-approval = Someone other than whoever asked to delete this document must approve it.
+folder-deletion-protected = Documents in this folder need a second approval to delete. Delete them one at a time, or clear the folder's deletion approval first.
+invalid-organization-name = Enter an organization name of at most { $max } characters.
+invalid-team-name = Enter a team name of at most { $max } characters.
+too-many-export-transforms = Add at most { $max } export transforms.
+export-transform-too-long = This transform is too long.
+invalid-redaction-pattern = Enter a valid redaction pattern.
+invalid-export-webhook-url = Enter an https URL for the webhook.
+invalid-encryption-key-id = Enter the key's ID in your key management service, of at most { $max } characters.
+organization-not-found = That organization does not exist.
+organization-admin-required = Only organization admins can do that.
+organization-owner-required = Only organization owners can appoint or remove admins and owners.
+organization-member-not-found = That person is not a member of this organization.
+organization-last-owner = An organization must keep at least one owner. Make someone else an owner first.
+invite-not-found = This invite does not exist or has expired.
+invite-for-someone-else = This invite was sent to a different email address.
+team-not-found = That team does not exist.
+team-name-taken = The organization already has a team with this name.
+team-member-not-found = That person is not on this team.
+team-member-not-in-organization = Only members of the organization can join its teams.
+encryption-disabled = Bring-your-own-key encryption is not configured on this server.
+encryption-key-not-found = This organization has no encryption key in use.
+encryption-key-unusable = The key management service could not use this key. Check the key ID and that this server may use the key.
+encryption-key-revoked = This organization revoked its encryption key, so its documents cannot be changed.
+document-unreadable = This document's content is no longer readable because its organization revoked its encryption key.
+invalid-import-query = Say which tool the archive was exported from: confluence or notion.
+import-archive-empty = Send the exported ZIP archive as the request body.
+import-job-not-found = Import not found.
+invalid-blame-range = Give the range as byte offsets, start-end, with start no greater than end.
+organization-invite-subject = { $inviter } invited you to { $organization }
+organization-invite-body = { $inviter } invited you to join { $organization }. Sign in or create an account with this email address to accept. The invite expires in seven days.
+organization-invite-action = Accept invite
+login-provider-not-found = This server does not offer sign-in with that provider.
+invalid-login-callback = The sign-in provider sent back an incomplete response. Try signing in again.
+login-declined = Sign-in was cancelled at the provider.
+login-expired = This sign-in has expired or was started in another browser. Try signing in again.
+login-provider-failed = The sign-in provider could not be reached or did not confirm who you are. Try again later.
+account-unavailable = This account can no longer sign in.
+identity-linked-elsewhere = That account at the provider is already linked to another account here.
+identity-email-taken = An account here already uses this email address. Sign in to it with your password, then link the provider from there.
+invalid-api-key-name = Give the API key a name of at most { $max } characters.
+invalid-api-key-documents = Limit an API key to between 1 and { $max } documents, or leave out the list.
+invalid-api-key-expiry = An API key must expire in the future.
+too-many-api-keys = You already have { $max } API keys. Revoke one before creating another.
+api-key-not-found = You have no such API key.
+api-key-forbidden = API keys can't be used to create other API keys. Sign in to create one.
+api-key-read-only = This API key can only be used to read.
+api-key-documents-only = This API key is limited to particular documents and can't be used here.
@@ -0,0 +1,186 @@
+document-not-found = Document introuvable.
+document-load-failed = Le document n'a pas pu être chargé.
+internal-error = Une erreur s'est produite de notre côté. Veuillez réessayer.
+update-buffer-full = Trop de modifications non enregistrées. Veuillez attendre que le document soit enregistré.
+update-storage-unavailable = Le stockage des documents est indisponible ; les modifications ne sont pas acceptées.
+update-failed = Votre modification n'a pas pu être appliquée.
+invalid-timezone = Fuseau horaire « { $timezone } » inconnu. Utilisez un nom IANA comme Europe/Berlin.
+rate-limited = Trop de requêtes. Veuillez réessayer dans un instant.
+invalid-request-body = Le corps de la requête n'est pas un JSON valide pour ce point d'accès.
+invalid-analytics-event = L'événement { $index } a été refusé : { $reason }
+invalid-analytics-batch = Le lot d'événements a été refusé : { $reason }
+invalid-token = Le jeton d'accès est invalide, expiré ou révoqué.
+document-forbidden = Vous n'avez pas accès à ce document.
+update-forbidden = Vous pouvez consulter ce document mais pas le modifier.
+presence-only-connection = Cette connexion indique seulement qui est présent. Reconnectez-vous sans mode=presence pour modifier.
+invalid-search-query = Indiquez un terme de recherche avec ?q=.
+admin-disabled = Les points d'accès d'administration sont désactivés sur ce serveur.
+server-draining = Ce serveur va bientôt s'arrêter. Reconnectez-vous pour en joindre un autre.
+admin-forbidden = Seuls les administrateurs peuvent faire cela.
+admin-outranked = Vous ne pouvez gérer que des comptes, et attribuer que des rôles, inférieurs à votre propre rôle.
+update-protected-range = Cette partie du document est verrouillée ; votre modification a été annulée.
+invalid-protected-range = Cette plage est vide ou se trouve en dehors du document.
+protection-forbidden = Seuls les propriétaires du document peuvent en verrouiller ou déverrouiller des parties.
+invalid-client-frame = Ce message n'a pas pu être compris.
+invalid-display-name = Saisissez un nom d'affichage de { $max } caractères au maximum.
+invalid-email = Saisissez une adresse e-mail valide.
+weak-password = Choisissez un mot de passe d'au moins { $min } caractères.
+email-taken = Un compte existe déjà avec cette adresse e-mail.
+invalid-credentials = L'adresse e-mail ou le mot de passe est incorrect.
+sync-too-many-documents = Synchronisez au plus { $max } documents par requête.
+sign-in-required = Connectez-vous pour continuer.
+document-create-forbidden = Les jetons de bot ne peuvent pas créer de documents.
+invalid-causality-token = L'en-tête X-Causality-Token ne contient pas un jeton émis par ce serveur.
+invalid-document-content = Le contenu du document doit être encodé en base64.
+invalid-document-name = Saisissez un nom de document d'au plus { $max } caractères.
+document-owner-required = Seuls les propriétaires d'un document peuvent le renommer ou le supprimer.
+invalid-listing-query = Utilisez ?limit= avec un nombre et ?sort= avec updated_desc, updated_asc, created_desc, created_asc ou name.
+invalid-cursor = Ce curseur de page est invalide ou appartient à un autre ordre de tri. Recommencez à la première page.
+attachments-disabled = Les pièces jointes ne sont pas activées sur ce serveur.
+attachment-not-found = Pièce jointe introuvable.
+invalid-attachment-name = Nommez le fichier avec ?name=, en { $max } caractères au maximum.
+attachment-too-large = Les pièces jointes ne peuvent pas dépasser { $max } octets.
+attachment-scan-pending = Ce fichier est encore en cours d'analyse antivirus. Réessayez dans un instant.
+attachment-infected = Ce fichier contient un logiciel malveillant et ne peut pas être téléchargé.
+workspace-member-required = Seuls les membres de cet espace de travail peuvent faire cela.
+workspace-admin-required = Seuls les administrateurs de l'espace de travail peuvent gérer les membres et les règles.
+invalid-workspace-name = Saisissez un nom d'espace de travail de { $max } caractères au maximum.
+user-not-found = Utilisateur introuvable.
+default-policy-not-found = Cet espace de travail n'a pas de règle par défaut.
+export-transforms-not-found = Cet espace de travail n'a pas de transformations d'export.
+preview-unavailable = Aucun aperçu n'est disponible pour ce document.
+digest-email-subject = Votre récapitulatif de documents
+digest-email-watched-heading = Modifications des documents que vous suivez
+digest-email-watched-document = { $name } (modifications : { $changes })
+digest-email-other-notification = Vous avez une nouvelle notification.
+digest-email-owned-heading = Vos documents modifiés
+digest-email-unsubscribe = Se désabonner de ces e-mails
+digest-unsubscribed = Vous ne recevrez plus d'e-mails récapitulatifs.
+invalid-unsubscribe-link = Ce lien de désabonnement n'est pas valide.
+document-name-taken = Un document nommé « { $name } » existe déjà ici. Essayez { $suggestions }.
+version-not-found = Cette version du document n'existe pas.
+missing-field = Ce champ est obligatoire.
+invalid-field-value = Ce champ a un type incorrect ou une valeur inconnue.
+document-deleted = Ce document a été supprimé.
+server-shutting-down = Le serveur redémarre. Vous serez reconnecté sous peu.
+invalid-color = Choisissez une couleur au format #rrggbb.
+guest-not-found = Aucun nom d'invité n'a été choisi dans ce navigateur.
+guest-signed-in = Vous êtes connecté et n'avez pas besoin de nom d'invité.
+invalid-bulk-documents = Indiquez entre 1 et { $max } documents.
+verify-email-subject = Confirmez votre adresse e-mail
+verify-email-body = Confirmez qu'il s'agit bien de votre adresse e-mail pour terminer la création de votre compte. Le lien expire dans deux jours.
+verify-email-action = Confirmer l'adresse e-mail
+email-verified = Votre adresse e-mail est confirmée.
+invalid-verification-link = Ce lien de confirmation n'est pas valide ou a expiré.
+email-already-verified = Votre adresse e-mail est déjà confirmée.
+email-disabled = Ce serveur n'envoie pas d'e-mails.
+inactive-account-email-subject = Votre compte va être désactivé
+inactive-account-email-body = Vous ne vous êtes pas connecté depuis longtemps. Connectez-vous avant le { $date } pour garder votre compte actif ; sinon, il sera désactivé jusqu'à votre prochaine connexion.
+password-reset-subject = Réinitialisez votre mot de passe
+password-reset-body = Quelqu'un a demandé à réinitialiser le mot de passe de votre compte. Si c'était vous, choisissez un nouveau mot de passe dans l'heure qui suit. Sinon, vous pouvez ignorer cet e-mail.
+password-reset-action = Choisir un nouveau mot de passe
+invalid-password-reset-link = Ce lien de réinitialisation du mot de passe n'est pas valide ou a expiré.
+session-not-found = Vous n'avez aucune session active avec cet identifiant.
+account-deletion-forbidden = Vous seul pouvez supprimer votre compte, et seulement en y étant connecté ; ni les clés d'API ni les bots ne le peuvent.
+invalid-comment-body = Saisissez un commentaire d'au plus { $max } caractères.
+comment-forbidden = Vous pouvez consulter ce document mais pas le commenter.
+comment-rejected = Le commentaire a été refusé : { $reason }
+account-locked = Trop de mots de passe erronés. Réessayez dans { $minutes } minutes.
+document-not-in-trash = Ce document n'est pas dans la corbeille.
+oauth-authorize-forbidden = Une clé d'API ne peut pas servir à autoriser une application. Connectez-vous pour en autoriser une.
+oauth-invalid-request = Un paramètre de la requête OAuth est manquant ou mal formé.
+oauth-unknown-client = Client OAuth inconnu, ou URI de redirection qu'il n'a pas enregistrée.
+oauth-unsupported-response-type = Seul le type de réponse "code" est pris en charge.
+oauth-pkce-required = Un défi de code PKCE utilisant la méthode S256 est requis.
+oauth-invalid-scope = La portée demandée est vide ou inconnue.
+oauth-unsupported-grant-type = Seul le type d'autorisation "authorization_code" est pris en charge.
+oauth-invalid-grant = Le code d'autorisation est invalide, expiré, déjà utilisé ou ne correspond pas à la requête.
+oauth-consent-not-found = Vous n'avez pas accordé l'accès à cette application.
+invalid-folder-name = Saisissez un nom de dossier d'au plus { $max } caractères.
+folder-not-found = Dossier introuvable.
+folder-name-taken = Un dossier portant ce nom existe déjà ici.
+folder-move-cycle = Un dossier ne peut pas être déplacé dans lui-même ni dans l'un de ses sous-dossiers.
+folder-different-root = Les dossiers et les documents ne peuvent être déplacés qu'au sein du même espace de travail ou des documents personnels.
+storage-quota-exceeded = Quota de stockage dépassé : { $used } octets utilisés sur { $quota }.
+invalid-event-stream-query = Indiquez avec ?from_seq= le numéro de séquence à partir duquel rejouer.
+magic-link-subject = Votre lien de connexion
+magic-link-body = Quelqu'un a demandé à se connecter à votre compte sans mot de passe. Si c'était vous, suivez ce lien dans les 15 prochaines minutes, dans le navigateur depuis lequel vous l'avez demandé. Sinon, vous pouvez ignorer cet e-mail.
+magic-link-action = Se connecter
+invalid-magic-link = Ce lien de connexion n'est pas valide, a expiré ou a été ouvert dans un autre navigateur.
+magic-links-disabled = Ce serveur ne propose pas de liens de connexion.
+update-rate-limited = Vous modifiez plus vite que ce document ne le permet ; votre modification a été annulée.
+update-too-large = Cette modification est trop volumineuse ; elle a été annulée.
+message-limit-exceeded = Cette connexion a envoyé trop de modifications trop rapidement et a été fermée.
+update-server-migrating = Ce document est en cours de transfert vers un autre serveur. Votre modification sera renvoyée dès que vous serez reconnecté.
+update-document-expired = Ce document a expiré et ne peut plus être modifié.
+update-document-too-large = Cette modification rendrait le document trop volumineux ; elle a été annulée.
+document-too-large = Les documents ne peuvent pas dépasser { $max } octets.
+request-body-too-large = Le corps de la requête est trop volumineux.
+invalid-document-expiry = Un document doit expirer dans le futur.
+document-expired = Ce document a expiré. Il est désormais en lecture seule.
+export-attachments = Pièces jointes
+export-image-undescribed = { $file } (image, sans description pour l’instant)
+invalid-export-query = Utilisez ?profile= avec standard ou a11y.
+export-unavailable = Ce document ne peut pas être exporté.
+export-transform-failed = Les transformations d'export de cet espace de travail ont échoué. Réessayez plus tard.
+invalid-share-link = Ce lien de partage est invalide, a expiré ou a été révoqué.
+invalid-share-link-expiry = Un lien de partage doit expirer dans le futur.
+share-link-not-found = Lien de partage introuvable.
+publication-blocked = Ce document ne peut pas être partagé par lien avant d’avoir été examiné par un modérateur.
+moderation-review-not-found = Cet examen de modération n’existe pas ou a déjà été tranché.
+alert-crossed-subject = Limite souple atteinte : { $resource }
+alert-crossed-body = L’utilisation de { $resource } est de { $usage }, ce qui atteint ou dépasse sa limite souple de { $threshold }.
+alert-cleared-subject = De nouveau sous la limite souple : { $resource }
+alert-cleared-body = L’utilisation de { $resource } est redescendue à { $usage }, sous sa limite souple de { $threshold }.
+invalid-comment-anchor = Ancrez une nouvelle discussion à une ou deux positions encodées ; les réponses partagent l'ancre de leur discussion.
+comment-not-found = Ce commentaire n'existe pas.
+comment-delete-forbidden = Seul l'auteur du commentaire ou le propriétaire du document peut le supprimer.
+deletion-request-not-found = Cette demande de suppression n'existe pas ou n'est plus en attente.
+deletion-self-approval = La suppression de ce document doit être approuvée par une autre personne que celle qui l'a demandée.
+folder-deletion-protected = La suppression des documents de ce dossier nécessite une seconde approbation. Supprimez-les un par un ou désactivez d'abord l'approbation de suppression du dossier.
+invalid-organization-name = Saisissez un nom d'organisation d'au plus { $max } caractères.
+invalid-team-name = Saisissez un nom d'équipe d'au plus { $max } caractères.
+too-many-export-transforms = Ajoutez au plus { $max } transformations d'export.
+export-transform-too-long = Cette transformation est trop longue.
+invalid-redaction-pattern = Saisissez un motif de caviardage valide.
+invalid-export-webhook-url = Saisissez une URL https pour le webhook.
+invalid-encryption-key-id = Saisissez l'identifiant de la clé dans votre service de gestion des clés, d'au plus { $max } caractères.
+organization-not-found = Cette organisation n'existe pas.
+organization-admin-required = Seuls les administrateurs de l'organisation peuvent faire cela.
+organization-owner-required = Seuls les propriétaires de l'organisation peuvent nommer ou retirer des administrateurs et des propriétaires.
+organization-member-not-found = Cette personne n'est pas membre de cette organisation.
+organization-last-owner = Une organisation doit garder au moins un propriétaire. Nommez d'abord une autre personne propriétaire.
+invite-not-found = Cette invitation n'existe pas ou a expiré.
+invite-for-someone-else = Cette invitation a été envoyée à une autre adresse e-mail.
+team-not-found = Cette équipe n'existe pas.
+team-name-taken = L'organisation a déjà une équipe portant ce nom.
+team-member-not-found = Cette personne ne fait pas partie de cette équipe.
+team-member-not-in-organization = Seuls les membres de l'organisation peuvent rejoindre ses équipes.
+encryption-disabled = Le chiffrement avec votre propre clé n'est pas configuré sur ce serveur.
+encryption-key-not-found = Cette organisation n'utilise aucune clé de chiffrement.
+encryption-key-unusable = Le service de gestion des clés n'a pas pu utiliser cette clé. Vérifiez l'identifiant de la clé et que ce serveur est autorisé à l'utiliser.
+encryption-key-revoked = Cette organisation a révoqué sa clé de chiffrement : ses documents ne peuvent plus être modifiés.
+document-unreadable = Le contenu de ce document n'est plus lisible, car son organisation a révoqué sa clé de chiffrement.
+invalid-import-query = Indiquez l'outil d'où l'archive a été exportée : confluence ou notion.
+import-archive-empty = Envoyez l'archive ZIP exportée dans le corps de la requête.
+import-job-not-found = Import introuvable.
+invalid-blame-range = Indiquez la plage en octets, sous la forme début-fin, le début ne dépassant pas la fin.
+organization-invite-subject = { $inviter } vous a invité à rejoindre { $organization }
+organization-invite-body = { $inviter } vous a invité à rejoindre { $organization }. Connectez-vous ou créez un compte avec cette adresse e-mail pour accepter. L'invitation expire dans sept jours.
+organization-invite-action = Accepter l'invitation
+login-provider-not-found = Ce serveur ne propose pas la connexion avec ce fournisseur.
+invalid-login-callback = Le fournisseur de connexion a renvoyé une réponse incomplète. Réessayez de vous connecter.
+login-declined = La connexion a été annulée chez le fournisseur.
+login-expired = Cette connexion a expiré ou a été commencée dans un autre navigateur. Réessayez de vous connecter.
+login-provider-failed = Le fournisseur de connexion est injoignable ou n'a pas confirmé votre identité. Réessayez plus tard.
+account-unavailable = Ce compte ne peut plus se connecter.
+identity-linked-elsewhere = Ce compte chez le fournisseur est déjà lié à un autre compte ici.
+identity-email-taken = Un compte utilise déjà cette adresse e-mail ici. Connectez-vous avec votre mot de passe, puis liez le fournisseur depuis votre compte.
+invalid-api-key-name = Donnez à la clé d'API un nom d'au plus { $max } caractères.
+invalid-api-key-documents = Limitez une clé d'API à entre 1 et { $max } documents, ou omettez la liste.
+invalid-api-key-expiry = Une clé d'API doit expirer dans le futur.
+too-many-api-keys = Vous avez déjà { $max } clés d'API. Révoquez-en une avant d'en créer une autre.
+api-key-not-found = Vous n'avez pas de telle clé d'API.
+api-key-forbidden = Une clé d'API ne peut pas servir à créer d'autres clés d'API. Connectez-vous pour en créer une.
+api-key-read-only = Cette clé d'API ne permet que la lecture.
+api-key-documents-only = Cette clé d'API est limitée à certains documents et ne peut pas être utilisée ici.
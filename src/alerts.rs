@@ -0,0 +1,283 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Soft limits on what a node uses, so operators hear about a resource running short before
+//! a hard limit fails requests. A monitor compares usage against thresholds set in the
+//! environment and, when one is crossed, notifies operators by webhook and email; it
+//! notifies again once usage falls back below 90% of the threshold, so a level hovering
+//! around the threshold doesn't notify on every check.
+//!
+//! Storage is measured across the deployment; connections and unsaved updates are this
+//! node's.
+
+use crate::document_room::RoomRegistry;
+use crate::document_service::DocumentService;
+use crate::email::{Email, Mailer};
+use crate::events::schema::{self, EventSchema};
+use crate::i18n::{self, Locale};
+use crate::metrics::ConnectionCounter;
+use crate::preview::escape_html;
+use crate::webhooks::Webhooks;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+const STORAGE_BYTES_ENV: &str = "COLLABORATE_ALERT_STORAGE_BYTES";
+const CONNECTIONS_ENV: &str = "COLLABORATE_ALERT_CONNECTIONS";
+const PENDING_UPDATES_ENV: &str = "COLLABORATE_ALERT_PENDING_UPDATES";
+const ALERT_EMAIL_ENV: &str = "COLLABORATE_ALERT_EMAIL";
+/// Usage must fall below this share of a threshold before the alert is cleared.
+const CLEAR_RATIO: f64 = 0.9;
+
+/// What a monitor watches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    /// Bytes stored by every owner's documents, as counted toward storage quotas.
+    Storage,
+    /// WebSockets open on this node.
+    Connections,
+    /// Updates broadcast on this node but not yet saved.
+    PendingUpdates,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Resource::Storage => "storage",
+            Resource::Connections => "connections",
+            Resource::PendingUpdates => "pending_updates",
+        })
+    }
+}
+
+/// Soft limits, each optional, and who to tell when one is crossed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AlertConfig {
+    pub storage_bytes: Option<i64>,
+    pub connections: Option<i64>,
+    pub pending_updates: Option<i64>,
+    /// Addresses alerts are emailed to, if email is configured.
+    pub recipients: Vec<String>,
+    pub interval: Duration,
+}
+
+impl AlertConfig {
+    /// Reads `COLLABORATE_ALERT_STORAGE_BYTES`, `COLLABORATE_ALERT_CONNECTIONS` and
+    /// `COLLABORATE_ALERT_PENDING_UPDATES`, and the comma-separated recipients in
+    /// `COLLABORATE_ALERT_EMAIL`. Returns `None` when no threshold is set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let config = AlertConfig {
+            storage_bytes: threshold_from_env(STORAGE_BYTES_ENV)?,
+            connections: threshold_from_env(CONNECTIONS_ENV)?,
+            pending_updates: threshold_from_env(PENDING_UPDATES_ENV)?,
+            recipients: env::var(ALERT_EMAIL_ENV)
+                .map(|addresses| addresses.split(',').map(str::trim).filter(|a| !a.is_empty()).map(str::to_string).collect())
+                .unwrap_or_default(),
+            interval: Duration::from_secs(60),
+        };
+        let any = config.storage_bytes.is_some() || config.connections.is_some() || config.pending_updates.is_some();
+        Ok(any.then_some(config))
+    }
+
+    fn threshold(&self, resource: Resource) -> Option<i64> {
+        match resource {
+            Resource::Storage => self.storage_bytes,
+            Resource::Connections => self.connections,
+            Resource::PendingUpdates => self.pending_updates,
+        }
+    }
+}
+
+fn threshold_from_env(name: &str) -> Result<Option<i64>> {
+    match env::var(name) {
+        Ok(value) => Ok(Some(i64::from_str(&value).ok().filter(|threshold| *threshold > 0).context(format!("{} must be a positive number", name))?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// A threshold crossed, or usage back below it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Alert {
+    pub resource: Resource,
+    pub usage: i64,
+    pub threshold: i64,
+    /// False once usage has fallen back below the threshold.
+    pub firing: bool,
+}
+
+impl Alert {
+    pub fn schema(&self) -> &'static EventSchema {
+        if self.firing { &schema::RESOURCE_THRESHOLD_CROSSED } else { &schema::RESOURCE_THRESHOLD_CLEARED }
+    }
+}
+
+/// Which thresholds are currently crossed, so each crossing is reported once.
+#[derive(Debug, Default)]
+pub struct AlertState {
+    firing: HashSet<Resource>,
+}
+
+impl AlertState {
+    /// Compares one reading of `resource` with its threshold, returning an alert if that
+    /// changes whether the threshold is crossed.
+    pub fn observe(&mut self, config: &AlertConfig, resource: Resource, usage: i64) -> Option<Alert> {
+        let threshold = config.threshold(resource)?;
+        let firing = if self.firing.contains(&resource) {
+            usage as f64 >= threshold as f64 * CLEAR_RATIO
+        } else {
+            usage >= threshold
+        };
+        let changed = if firing { self.firing.insert(resource) } else { self.firing.remove(&resource) };
+        changed.then_some(Alert { resource, usage, threshold, firing })
+    }
+}
+
+/// Where a monitor reads usage from and who it tells about alerts.
+pub struct ResourceMonitor {
+    config: AlertConfig,
+    doc_service: Arc<DocumentService>,
+    rooms: Arc<RoomRegistry>,
+    connections: Arc<ConnectionCounter>,
+    webhooks: Option<Arc<Webhooks>>,
+    mailer: Option<Arc<dyn Mailer>>,
+}
+
+impl ResourceMonitor {
+    pub fn new(
+        config: AlertConfig,
+        doc_service: Arc<DocumentService>,
+        rooms: Arc<RoomRegistry>,
+        connections: Arc<ConnectionCounter>,
+    ) -> Self {
+        ResourceMonitor { config, doc_service, rooms, connections, webhooks: None, mailer: None }
+    }
+
+    pub fn with_webhooks(mut self, webhooks: Arc<Webhooks>) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Emails alerts to the configured recipients.
+    pub fn with_mailer(mut self, mailer: Arc<dyn Mailer>) -> Self {
+        self.mailer = Some(mailer);
+        self
+    }
+
+    async fn notify(&self, alert: &Alert) {
+        warn!(
+            "{} is at {}, {} its soft limit of {}",
+            alert.resource, alert.usage, if alert.firing { "over" } else { "back under" }, alert.threshold
+        );
+        if let Some(webhooks) = &self.webhooks
+            && let Err(e) = webhooks.send(alert.schema(), alert).await
+        {
+            warn!("Failed to deliver a resource alert: {:#}", e);
+        }
+        if let Some(mailer) = &self.mailer {
+            for recipient in &self.config.recipients {
+                if let Err(e) = mailer.send(&alert_email(recipient, alert)).await {
+                    warn!("Failed to email a resource alert to {}: {:#}", recipient, e);
+                }
+            }
+        }
+    }
+}
+
+fn alert_email(to: &str, alert: &Alert) -> Email {
+    let args = [
+        ("resource", alert.resource.to_string()),
+        ("usage", alert.usage.to_string()),
+        ("threshold", alert.threshold.to_string()),
+    ];
+    let (subject, body) = if alert.firing {
+        ("alert-crossed-subject", "alert-crossed-body")
+    } else {
+        ("alert-cleared-subject", "alert-cleared-body")
+    };
+    let text = i18n::message(Locale::FALLBACK, body, &args);
+    Email {
+        to: to.to_string(),
+        subject: i18n::message(Locale::FALLBACK, subject, &args),
+        html: format!("<p>{}</p>", escape_html(&text)),
+        text,
+        unsubscribe_url: None,
+    }
+}
+
+/// Periodically checks usage against the monitor's thresholds, notifying as they are
+/// crossed and cleared. A reading that fails is skipped until the next check.
+pub async fn run_monitor(monitor: ResourceMonitor) {
+    let mut state = AlertState::default();
+    let mut ticker = tokio::time::interval(monitor.config.interval);
+    loop {
+        ticker.tick().await;
+        let mut readings = vec![
+            (Resource::Connections, monitor.connections.current() as i64),
+            (Resource::PendingUpdates, monitor.rooms.stats().pending_updates() as i64),
+        ];
+        if monitor.config.storage_bytes.is_some() {
+            match monitor.doc_service.total_storage_bytes().await {
+                Ok(bytes) => readings.push((Resource::Storage, bytes)),
+                Err(e) => warn!("Failed to measure storage for alerts; will retry: {:#}", e),
+            }
+        }
+        for (resource, usage) in readings {
+            if let Some(alert) = state.observe(&monitor.config, resource, usage) {
+                monitor.notify(&alert).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alerts_fire_once_and_clear_below_the_threshold() {
+        let config = AlertConfig { connections: Some(100), ..Default::default() };
+        let mut state = AlertState::default();
+        assert_eq!(state.observe(&config, Resource::Connections, 99), None);
+        assert_eq!(
+            state.observe(&config, Resource::Connections, 100),
+            Some(Alert { resource: Resource::Connections, usage: 100, threshold: 100, firing: true })
+        );
+        assert_eq!(state.observe(&config, Resource::Connections, 120), None);
+        // Dipping just under the threshold doesn't clear it.
+        assert_eq!(state.observe(&config, Resource::Connections, 95), None);
+        assert_eq!(
+            state.observe(&config, Resource::Connections, 89),
+            Some(Alert { resource: Resource::Connections, usage: 89, threshold: 100, firing: false })
+        );
+        assert_eq!(state.observe(&config, Resource::Connections, 95), None);
+        // Resources without a threshold are never reported.
+        assert_eq!(state.observe(&config, Resource::Storage, i64::MAX), None);
+    }
+
+    #[test]
+    fn test_alert_emails_name_the_resource() {
+        let alert = Alert { resource: Resource::Storage, usage: 2048, threshold: 1024, firing: true };
+        let email = alert_email("ops@example.com", &alert);
+        assert_eq!(email.to, "ops@example.com");
+        assert!(email.subject.contains("storage"));
+        assert!(email.text.contains("2048") && email.text.contains("1024"));
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::db::Manager;
+use crate::migrations::{self, Component, Migration};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::env;
+use std::sync::Arc;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+const SAMPLE_RATE_ENV: &str = "COLLABORATE_ANALYTICS_SAMPLE_RATE";
+pub const MAX_BATCH_EVENTS: usize = 100;
+const MAX_PROPERTIES_BYTES: usize = 4096;
+
+/// Client events we accept. Anything else is rejected so the table stays queryable.
+const EVENT_NAMES: &[&str] = &[
+    "document_opened",
+    "document_closed",
+    "export_clicked",
+    "share_dialog_opened",
+    "search_performed",
+    "error_shown",
+];
+
+/// One telemetry event as sent by a client.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClientEvent {
+    pub name: String,
+    pub occurred_at: DateTime<Utc>,
+    #[serde(default)]
+    pub document_id: Option<Uuid>,
+    #[serde(default)]
+    pub properties: Map<String, Value>,
+}
+
+impl ClientEvent {
+    fn validate(&self, now: DateTime<Utc>) -> Result<()> {
+        if !EVENT_NAMES.contains(&self.name.as_str()) {
+            bail!("Unknown event name '{}'", self.name);
+        }
+        // Clients buffer events while offline, but not indefinitely.
+        if self.occurred_at < now - TimeDelta::days(7) || self.occurred_at > now + TimeDelta::hours(1) {
+            bail!("Event time {} is out of range", self.occurred_at);
+        }
+        if self.properties.values().any(|value| value.is_object() || value.is_array()) {
+            bail!("Event properties must be flat");
+        }
+        if serde_json::to_vec(&self.properties)?.len() > MAX_PROPERTIES_BYTES {
+            bail!("Event properties exceed {} bytes", MAX_PROPERTIES_BYTES);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClientEventBatch {
+    pub events: Vec<ClientEvent>,
+}
+
+/// Why a batch was refused; `index` identifies the offending event, if any.
+#[derive(Debug)]
+pub struct InvalidBatch {
+    pub index: Option<usize>,
+    pub reason: anyhow::Error,
+}
+
+impl ClientEventBatch {
+    pub fn validate(&self, now: DateTime<Utc>) -> Result<(), InvalidBatch> {
+        if self.events.len() > MAX_BATCH_EVENTS {
+            return Err(InvalidBatch {
+                index: None,
+                reason: anyhow::anyhow!("Batches are limited to {} events", MAX_BATCH_EVENTS),
+            });
+        }
+        for (index, event) in self.events.iter().enumerate() {
+            event.validate(now).map_err(|reason| InvalidBatch { index: Some(index), reason })?;
+        }
+        Ok(())
+    }
+}
+
+/// Stores client telemetry in an append-only table for product analytics.
+pub struct AnalyticsService {
+    db_manager: Arc<Manager>,
+    sample_rate: f64,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "analytics",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS client_events (
+                id UUID PRIMARY KEY,
+                name TEXT NOT NULL,
+                occurred_at TIMESTAMPTZ NOT NULL,
+                received_at TIMESTAMPTZ NOT NULL,
+                document_id UUID,
+                properties JSONB NOT NULL,
+                sample_rate FLOAT8 NOT NULL
+            )",
+        ],
+        down: &[
+            "DROP TABLE IF EXISTS client_events",
+        ],
+    }],
+};
+
+impl AnalyticsService {
+    /// `sample_rate` is the fraction of events kept, between 0 and 1.
+    pub async fn new(db_manager: Arc<Manager>, sample_rate: f64) -> Result<Self> {
+        let service = AnalyticsService { db_manager, sample_rate: sample_rate.clamp(0.0, 1.0) };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    /// Reads the sample rate from `COLLABORATE_ANALYTICS_SAMPLE_RATE`, keeping every event by default.
+    pub fn sample_rate_from_env() -> Result<f64> {
+        match env::var(SAMPLE_RATE_ENV) {
+            Ok(rate) => rate.parse().context(format!("{} must be a number between 0 and 1", SAMPLE_RATE_ENV)),
+            Err(_) => Ok(1.0),
+        }
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("Analytics schema initialized.");
+        Ok(())
+    }
+
+    /// Appends a validated batch, keeping each event with probability `sample_rate`.
+    /// Returns how many events were stored.
+    #[instrument(skip_all)]
+    pub async fn record(&self, batch: &ClientEventBatch) -> Result<usize> {
+        let received_at = Utc::now();
+        let mut stored = 0;
+        for event in &batch.events {
+            if rand::random::<f64>() >= self.sample_rate {
+                continue;
+            }
+            // The sample rate is stored with each event so counts can be scaled back up.
+            sqlx::query(
+                "INSERT INTO client_events (id, name, occurred_at, received_at, document_id, properties, sample_rate)
+                 VALUES ($1, $2, $3, $4, $5, $6::JSONB, $7)",
+            )
+            .bind(Uuid::new_v4())
+            .bind(&event.name)
+            .bind(event.occurred_at)
+            .bind(received_at)
+            .bind(event.document_id)
+            .bind(serde_json::to_string(&event.properties)?)
+            .bind(self.sample_rate)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to store client event '{}'", event.name))?;
+            stored += 1;
+        }
+        Ok(stored)
+    }
+
+    #[instrument(skip_all)]
+    pub async fn count_events(&self, name: &str) -> Result<i64> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM client_events WHERE name = $1")
+            .bind(name)
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to count client events '{}'", name))?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    fn event(value: Value) -> ClientEvent {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_batch_validation() {
+        let now = Utc::now();
+        let valid = event(json!({
+            "name": "export_clicked",
+            "occurred_at": now,
+            "properties": { "format": "pdf", "pages": 3 },
+        }));
+        assert!(ClientEventBatch { events: vec![valid.clone()] }.validate(now).is_ok());
+
+        let unknown = event(json!({ "name": "mined_bitcoin", "occurred_at": now }));
+        let nested = event(json!({ "name": "error_shown", "occurred_at": now, "properties": { "a": { "b": 1 } } }));
+        let stale = event(json!({ "name": "document_opened", "occurred_at": now - TimeDelta::days(30) }));
+        for invalid in [unknown, nested, stale] {
+            let error = ClientEventBatch { events: vec![valid.clone(), invalid] }.validate(now).unwrap_err();
+            assert_eq!(error.index, Some(1));
+        }
+
+        let oversized = ClientEventBatch { events: vec![valid; MAX_BATCH_EVENTS + 1] };
+        assert_eq!(oversized.validate(now).unwrap_err().index, None);
+    }
+
+    #[tokio::test]
+    async fn test_sampling_drops_events() -> Result<()> {
+        let db_manager = Arc::new(
+            Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME)
+                .await
+                .context(format!("Failed to initialize Manager for test database '{}'", TEST_DB_NAME))?,
+        );
+        let name = "share_dialog_opened";
+        let batch = ClientEventBatch {
+            events: vec![event(json!({ "name": name, "occurred_at": Utc::now() })); 5],
+        };
+
+        let before = AnalyticsService::new(db_manager.clone(), 1.0).await?.count_events(name).await?;
+        assert_eq!(AnalyticsService::new(db_manager.clone(), 1.0).await?.record(&batch).await?, 5);
+        assert_eq!(AnalyticsService::new(db_manager.clone(), 0.0).await?.record(&batch).await?, 0);
+        let after = AnalyticsService::new(db_manager, 1.0).await?.count_events(name).await?;
+        assert_eq!(after - before, 5);
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::document_service::DocumentTooLarge;
+use crate::encryption::EncryptionError;
+use crate::i18n::{self, Locale};
+use crate::storage_quota::QuotaExceeded;
+use crate::validation::FieldErrors;
+use axum::extract::Request;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::error;
+
+/// An error returned to API clients. It carries a message key rather than text so the
+/// body can be rendered in the caller's language; see `localize_errors`.
+#[derive(Clone, Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    key: &'static str,
+    args: Vec<(&'static str, String)>,
+    fields: FieldErrors,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    /// Stable, machine-readable identifier; identical to the message key.
+    code: &'a str,
+    message: String,
+    /// What is wrong with each invalid request field, keyed by its path in the request.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    fields: BTreeMap<&'a str, FieldDetail<'a>>,
+}
+
+#[derive(Serialize)]
+struct FieldDetail<'a> {
+    code: &'a str,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, key: &'static str) -> Self {
+        ApiError { status, key, args: Vec::new(), fields: FieldErrors::default() }
+    }
+
+    /// Adds a value to interpolate into the message.
+    pub fn with_arg(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.args.push((name, value.to_string()));
+        self
+    }
+
+    /// A 422 for a request with invalid fields, led by the first field's error.
+    pub fn invalid_fields(fields: FieldErrors) -> Self {
+        let first = fields.iter().next().map(|(_, error)| error.clone());
+        let mut error = match first {
+            Some(first) => ApiError { args: first.args, ..ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, first.key) },
+            None => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid-request-body"),
+        };
+        error.fields = fields;
+        error
+    }
+
+    pub fn not_found(key: &'static str) -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, key)
+    }
+
+    /// Logs the underlying error and hides it from the client.
+    pub fn internal(e: anyhow::Error) -> Self {
+        error!("Internal error: {:#}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal-error")
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn key(&self) -> &'static str {
+        self.key
+    }
+
+    /// The error message in the given locale.
+    pub fn message(&self, locale: Locale) -> String {
+        i18n::message(locale, self.key, &self.args)
+    }
+
+    fn render(&self, locale: Locale) -> Response {
+        let body = ErrorBody {
+            error: ErrorDetail {
+                code: self.key,
+                message: self.message(locale),
+                fields: self.fields
+                    .iter()
+                    .map(|(field, error)| {
+                        (field, FieldDetail { code: error.key, message: i18n::message(locale, error.key, &error.args) })
+                    })
+                    .collect(),
+            },
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        response.extensions_mut().insert(self.clone());
+        response
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        // Any write can run out of quota or outgrow the size limit, and any read or write of
+        // an organization's content can meet its revoked key, so these are not left to each
+        // handler.
+        if let Some(too_large) = e.downcast_ref::<DocumentTooLarge>() {
+            return ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "document-too-large").with_arg("max", too_large.max_bytes);
+        }
+        match e.downcast::<QuotaExceeded>() {
+            Ok(exceeded) => ApiError::new(StatusCode::INSUFFICIENT_STORAGE, "storage-quota-exceeded")
+                .with_arg("used", exceeded.used_bytes)
+                .with_arg("quota", exceeded.quota_bytes),
+            Err(e) => match e.downcast_ref::<EncryptionError>() {
+                Some(EncryptionError::KeyRevoked) => ApiError::new(StatusCode::FORBIDDEN, "encryption-key-revoked"),
+                Some(EncryptionError::Unreadable) => ApiError::new(StatusCode::GONE, "document-unreadable"),
+                Some(EncryptionError::KeyUnusable) => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "encryption-key-unusable"),
+                None => ApiError::internal(e),
+            },
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        // Rendered in the fallback locale; `localize_errors` re-renders it for the caller.
+        self.render(Locale::FALLBACK)
+    }
+}
+
+/// Middleware that re-renders `ApiError` responses in the language negotiated from the request.
+pub async fn localize_errors(locale: Locale, request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    match response.extensions().get::<ApiError>() {
+        Some(error) if locale != Locale::FALLBACK => error.render(locale),
+        _ => response,
+    }
+}
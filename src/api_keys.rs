@@ -0,0 +1,345 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Long-lived keys for scripts and integrations to call the API as a user.
+//!
+//! A key is sent as `Authorization: Bearer ck_…` in place of an access token. It is stored
+//! hashed, lasts until it expires or is revoked, and can be narrowed to reading only, to
+//! particular documents, or both; it never grants more than its user has.
+
+use crate::db::Manager;
+use crate::migrations::{self, Component, Migration};
+use crate::user_service::{generate_secret, hash_token, DocumentAccess};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::FromRow;
+use std::sync::Arc;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+/// Prefix that makes API keys recognisable in logs and secret scanners.
+pub const API_KEY_PREFIX: &str = "ck_";
+/// How many of a key's last characters are kept to tell keys apart in listings.
+const HINT_LENGTH: usize = 4;
+pub const MAX_KEYS_PER_USER: i64 = 50;
+pub const MAX_KEY_DOCUMENTS: usize = 100;
+pub const MAX_KEY_NAME_LENGTH: usize = 100;
+
+/// A user's API key, as listed to them. The key itself is only shown when created.
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    /// The key's last few characters.
+    pub hint: String,
+    pub read_only: bool,
+    /// The only documents the key may be used on, or `None` for any the user can use.
+    pub documents: Option<Vec<Uuid>>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A newly created key. `secret` is shown once and never stored.
+pub struct IssuedApiKey {
+    pub key: ApiKey,
+    pub secret: String,
+}
+
+/// What a presented API key lets its caller do.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiKeyGrant {
+    pub key_id: Uuid,
+    pub user_id: Uuid,
+    pub read_only: bool,
+    pub documents: Option<Vec<Uuid>>,
+}
+
+impl ApiKeyGrant {
+    pub fn allows_document(&self, doc_id: Uuid) -> bool {
+        self.documents.as_ref().is_none_or(|documents| documents.contains(&doc_id))
+    }
+
+    /// Narrows the user's own access to a document to what the key allows.
+    pub fn limit(&self, doc_id: Uuid, access: DocumentAccess) -> DocumentAccess {
+        if !self.allows_document(doc_id) {
+            return DocumentAccess { read: false, write: false, comment: false, role: access.role };
+        }
+        DocumentAccess {
+            write: access.write && !self.read_only,
+            comment: access.comment && !self.read_only,
+            ..access
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct GrantRow {
+    id: Uuid,
+    user_id: Uuid,
+    read_only: bool,
+    documents: Option<Vec<Uuid>>,
+}
+
+#[derive(Clone)]
+pub struct ApiKeyService {
+    db_manager: Arc<Manager>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "api_keys",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS api_keys (
+                id UUID PRIMARY KEY,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                name TEXT NOT NULL,
+                key_hash TEXT NOT NULL UNIQUE,
+                hint TEXT NOT NULL,
+                read_only BOOL NOT NULL,
+                documents UUID[],
+                created_at TIMESTAMPTZ NOT NULL,
+                expires_at TIMESTAMPTZ,
+                revoked_at TIMESTAMPTZ
+            )",
+            "CREATE INDEX IF NOT EXISTS api_keys_by_user ON api_keys (user_id)",
+        ],
+        down: &["DROP TABLE IF EXISTS api_keys"],
+    }],
+};
+
+impl ApiKeyService {
+    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
+        let service = ApiKeyService { db_manager };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("API key schema initialized.");
+        Ok(())
+    }
+
+    /// Creates a key for a user. The caller must have checked `name`, `documents` and
+    /// `expires_at`. Returns `None` once the user has `MAX_KEYS_PER_USER` live keys.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn create(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        read_only: bool,
+        documents: Option<&[Uuid]>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Option<IssuedApiKey>> {
+        let now = Utc::now();
+        let live: i64 = sqlx::query_scalar(
+                "SELECT count(*) FROM api_keys
+                 WHERE user_id = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > $2)"
+            )
+            .bind(user_id)
+            .bind(now)
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to count API keys of user ID {}", user_id))?;
+        if live >= MAX_KEYS_PER_USER {
+            return Ok(None);
+        }
+
+        let secret = generate_secret(API_KEY_PREFIX);
+        let mut documents = documents.map(<[Uuid]>::to_vec);
+        if let Some(documents) = &mut documents {
+            documents.sort();
+            documents.dedup();
+        }
+        let key = ApiKey {
+            id: Uuid::new_v4(),
+            name: name.trim().to_string(),
+            hint: secret[secret.len() - HINT_LENGTH..].to_string(),
+            read_only,
+            documents,
+            created_at: DateTime::from_timestamp_millis(now.timestamp_millis()).unwrap_or_default(),
+            expires_at,
+        };
+        sqlx::query(
+                "INSERT INTO api_keys (id, user_id, name, key_hash, hint, read_only, documents, created_at, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"
+            )
+            .bind(key.id)
+            .bind(user_id)
+            .bind(&key.name)
+            .bind(hash_token(&secret))
+            .bind(&key.hint)
+            .bind(key.read_only)
+            .bind(&key.documents)
+            .bind(key.created_at)
+            .bind(key.expires_at)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to create API key for user ID {}", user_id))?;
+        info!("Created API key {} for user ID {}", key.id, user_id);
+        Ok(Some(IssuedApiKey { key, secret }))
+    }
+
+    /// A user's keys that have neither been revoked nor expired, newest first.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn list(&self, user_id: Uuid) -> Result<Vec<ApiKey>> {
+        sqlx::query_as(
+                "SELECT id, name, hint, read_only, documents, created_at, expires_at FROM api_keys
+                 WHERE user_id = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > $2)
+                 ORDER BY created_at DESC"
+            )
+            .bind(user_id)
+            .bind(Utc::now())
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list API keys of user ID {}", user_id))
+    }
+
+    /// Revokes one of a user's keys. Returns false for keys that are unknown, someone
+    /// else's or already revoked.
+    #[instrument(skip_all, fields(user_id = %user_id, key_id = %key_id))]
+    pub async fn revoke(&self, user_id: Uuid, key_id: Uuid) -> Result<bool> {
+        let result = sqlx::query(
+                "UPDATE api_keys SET revoked_at = $1 WHERE id = $2 AND user_id = $3 AND revoked_at IS NULL"
+            )
+            .bind(Utc::now())
+            .bind(key_id)
+            .bind(user_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to revoke API key {}", key_id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Resolves a presented key to what it grants, if the key is live and its user can
+    /// still sign in.
+    #[instrument(skip_all)]
+    pub async fn authenticate(&self, secret: &str) -> Result<Option<ApiKeyGrant>> {
+        if !secret.starts_with(API_KEY_PREFIX) {
+            return Ok(None);
+        }
+        let row: Option<GrantRow> = sqlx::query_as(
+                "SELECT k.id, k.user_id, k.read_only, k.documents
+                 FROM api_keys k JOIN users u ON u.id = k.user_id
+                 WHERE k.key_hash = $1 AND k.revoked_at IS NULL AND (k.expires_at IS NULL OR k.expires_at > $2)
+                   AND u.deactivated_at IS NULL AND u.anonymized_at IS NULL"
+            )
+            .bind(hash_token(secret))
+            .bind(Utc::now())
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to look up API key")?;
+        Ok(row.map(|row| ApiKeyGrant {
+            key_id: row.id,
+            user_id: row.user_id,
+            read_only: row.read_only,
+            documents: row.documents,
+        }))
+    }
+
+    /// Whether a key would still authenticate, for connections that outlive a request.
+    #[instrument(skip_all, fields(key_id = %key_id))]
+    pub async fn key_active(&self, key_id: Uuid) -> Result<bool> {
+        sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM api_keys k JOIN users u ON u.id = k.user_id
+                 WHERE k.id = $1 AND k.revoked_at IS NULL AND (k.expires_at IS NULL OR k.expires_at > $2)
+                   AND u.deactivated_at IS NULL AND u.anonymized_at IS NULL)"
+            )
+            .bind(key_id)
+            .bind(Utc::now())
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to check API key {}", key_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_service::{DocumentRole, UserService};
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[test]
+    fn test_grants_narrow_access() {
+        let doc_id = Uuid::new_v4();
+        let grant = ApiKeyGrant { key_id: Uuid::new_v4(), user_id: Uuid::new_v4(), read_only: true, documents: Some(vec![doc_id]) };
+        let limited = grant.limit(doc_id, DocumentAccess::FULL);
+        assert_eq!(limited, DocumentAccess { read: true, write: false, comment: false, role: DocumentRole::Owner });
+        assert!(!grant.limit(Uuid::new_v4(), DocumentAccess::FULL).any());
+
+        let unrestricted = ApiKeyGrant { read_only: false, documents: None, ..grant };
+        assert_eq!(unrestricted.limit(Uuid::new_v4(), DocumentAccess::FULL), DocumentAccess::FULL);
+        let viewer = DocumentAccess { read: true, write: false, comment: false, role: DocumentRole::Editor };
+        assert_eq!(unrestricted.limit(doc_id, viewer), viewer, "A key never grants more than its user has");
+    }
+
+    #[tokio::test]
+    async fn test_api_keys_authenticate_until_revoked() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let users = UserService::new(manager.clone()).await?;
+        let keys = ApiKeyService::new(manager).await?;
+        let user = users.register_user("Key Holder", &format!("keys-{}@example.com", Uuid::new_v4()), "correct horse battery").await?;
+
+        let doc_id = Uuid::new_v4();
+        let issued = keys.create(user.id, " CI export ", true, Some(&[doc_id, doc_id]), None).await?.unwrap();
+        assert!(issued.secret.starts_with(API_KEY_PREFIX));
+        assert!(issued.secret.ends_with(&issued.key.hint));
+        assert_eq!(issued.key.name, "CI export");
+        assert_eq!(issued.key.documents, Some(vec![doc_id]));
+
+        let grant = keys.authenticate(&issued.secret).await?.expect("key should authenticate");
+        assert_eq!((grant.key_id, grant.user_id, grant.read_only), (issued.key.id, user.id, true));
+        assert_eq!(grant.documents, Some(vec![doc_id]));
+        assert!(keys.authenticate("ck_guess").await?.is_none());
+        assert!(keys.authenticate("cbt_guess").await?.is_none());
+        assert_eq!(keys.list(user.id).await?.iter().map(|key| key.id).collect::<Vec<_>>(), vec![issued.key.id]);
+
+        assert!(!keys.revoke(Uuid::new_v4(), issued.key.id).await?, "Only the key's user may revoke it");
+        assert!(keys.key_active(issued.key.id).await?);
+        assert!(keys.revoke(user.id, issued.key.id).await?);
+        assert!(!keys.revoke(user.id, issued.key.id).await?);
+        assert!(keys.authenticate(&issued.secret).await?.is_none());
+        assert!(!keys.key_active(issued.key.id).await?);
+        assert!(keys.list(user.id).await?.is_empty());
+
+        let expired = keys.create(user.id, "Expired", false, None, Some(Utc::now())).await?.unwrap();
+        assert!(keys.authenticate(&expired.secret).await?.is_none());
+
+        let live = keys.create(user.id, "Anonymized", false, None, None).await?.unwrap();
+        users.anonymize_user(user.id).await?;
+        assert!(keys.authenticate(&live.secret).await?.is_none());
+        assert!(!keys.key_active(live.key.id).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keys_go_inactive_with_their_user() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let users = UserService::new(manager.clone()).await?;
+        let keys = ApiKeyService::new(manager).await?;
+        let user = users.register_user("Key Holder", &format!("keys-{}@example.com", Uuid::new_v4()), "correct horse battery").await?;
+        let issued = keys.create(user.id, "Sync", false, None, None).await?.unwrap();
+        assert!(keys.key_active(issued.key.id).await?);
+
+        assert!(users.deactivate_user(user.id).await?);
+        assert!(!keys.key_active(issued.key.id).await?, "Open connections close with the account");
+        assert!(keys.authenticate(&issued.secret).await?.is_none());
+        Ok(())
+    }
+}
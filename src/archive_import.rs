@@ -0,0 +1,1063 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Importing whole spaces exported from other tools: a Confluence space exported as HTML,
+//! or a Notion workspace exported as Markdown & CSV, each uploaded as a ZIP.
+//!
+//! Pages become documents and the page tree becomes folders: a page with children turns
+//! into a folder of the same name holding the page itself and its children. Titles are
+//! kept; formatting is carried over as far as the text conventions `export` renders, i.e.
+//! headings and paragraphs, with lists and emphasis written the Markdown way. Files
+//! attached to a page become its attachments when attachments are enabled. A Notion
+//! database becomes a document holding its CSV, with its rows as pages beneath it.
+//!
+//! An import runs in the background as a job whose progress and final report any node
+//! can read back. Problems with single pages or files are reported and skipped; only an
+//! archive that cannot be read fails the whole job.
+
+use crate::attachments::AttachmentService;
+use crate::concurrency::RouteBudget;
+use crate::db::Manager;
+use crate::document_service::{DocumentMetadata, DocumentService, NameTaken, MAX_DOCUMENT_NAME_LENGTH};
+use crate::folders::{FolderRejection, FolderService};
+use crate::migrations::{self, Component, Migration};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use sqlx::types::Json;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+/// Largest archive accepted for upload.
+pub const MAX_ARCHIVE_BYTES: usize = 256 * 1024 * 1024;
+/// Limits on what an archive may unpack to, so a small upload cannot exhaust memory.
+const MAX_EXTRACTED_BYTES: u64 = 512 * 1024 * 1024;
+const MAX_ENTRIES: usize = 20_000;
+/// A running job that has not made progress for this long was cut off, e.g. by its node
+/// shutting down.
+const STALE_AFTER: TimeDelta = TimeDelta::minutes(15);
+
+/// The tool an archive was exported from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportSource {
+    /// A space exported as HTML.
+    Confluence,
+    /// A workspace or page exported as Markdown & CSV.
+    Notion,
+}
+
+impl ExportSource {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ExportSource::Confluence => "confluence",
+            ExportSource::Notion => "notion",
+        }
+    }
+}
+
+impl FromStr for ExportSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "confluence" => Ok(ExportSource::Confluence),
+            "notion" => Ok(ExportSource::Notion),
+            other => Err(anyhow!("Unknown export source '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for ExportSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Waiting for an import slot.
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "succeeded" => Ok(JobStatus::Succeeded),
+            "failed" => Ok(JobStatus::Failed),
+            other => Err(anyhow!("Unknown import job status '{}'", other)),
+        }
+    }
+}
+
+/// Where imported pages go: the top of a workspace or of `owner_id`'s personal documents,
+/// or inside `folder_id` there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImportDestination {
+    pub owner_id: Uuid,
+    pub workspace_id: Option<Uuid>,
+    pub folder_id: Option<Uuid>,
+}
+
+/// A page that became a document, by its place in the page tree.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImportedPage {
+    /// Titles from the top of the tree down, joined with " / ".
+    pub path: String,
+    pub document_id: Uuid,
+}
+
+/// What an import created, and what it had to skip.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub documents: Vec<ImportedPage>,
+    pub folders: usize,
+    pub attachments: usize,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ImportJob {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub workspace_id: Option<Uuid>,
+    pub folder_id: Option<Uuid>,
+    pub source: ExportSource,
+    pub status: JobStatus,
+    /// Pages found in the archive; zero until it has been read.
+    pub total_pages: i64,
+    pub imported_pages: i64,
+    /// Filled in as pages are imported.
+    pub report: ImportReport,
+    /// Why the job failed.
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(FromRow)]
+struct ImportJobRow {
+    id: Uuid,
+    owner_id: Uuid,
+    workspace_id: Option<Uuid>,
+    folder_id: Option<Uuid>,
+    source: String,
+    status: String,
+    total_pages: i64,
+    imported_pages: i64,
+    report: Json<ImportReport>,
+    error: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<ImportJobRow> for ImportJob {
+    type Error = anyhow::Error;
+
+    fn try_from(row: ImportJobRow) -> Result<Self> {
+        let mut job = ImportJob {
+            id: row.id,
+            owner_id: row.owner_id,
+            workspace_id: row.workspace_id,
+            folder_id: row.folder_id,
+            source: row.source.parse()?,
+            status: row.status.parse()?,
+            total_pages: row.total_pages,
+            imported_pages: row.imported_pages,
+            report: row.report.0,
+            error: row.error,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            finished_at: row.finished_at,
+        };
+        if job.status == JobStatus::Running && job.updated_at < Utc::now() - STALE_AFTER {
+            job.status = JobStatus::Failed;
+            job.error = Some("The import was interrupted".to_string());
+        }
+        Ok(job)
+    }
+}
+
+const JOB_COLUMNS: &str = "id, owner_id, workspace_id, folder_id, source, status, total_pages, imported_pages, report, error, created_at, updated_at, finished_at";
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "archive_imports",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS import_jobs (
+                id UUID PRIMARY KEY,
+                owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                workspace_id UUID,
+                folder_id UUID,
+                source TEXT NOT NULL,
+                status TEXT NOT NULL,
+                total_pages BIGINT NOT NULL DEFAULT 0,
+                imported_pages BIGINT NOT NULL DEFAULT 0,
+                report JSONB NOT NULL,
+                error TEXT,
+                created_at TIMESTAMPTZ NOT NULL,
+                updated_at TIMESTAMPTZ NOT NULL,
+                finished_at TIMESTAMPTZ
+            )",
+            "CREATE INDEX IF NOT EXISTS import_jobs_by_owner ON import_jobs (owner_id, created_at)",
+        ],
+        down: &["DROP TABLE IF EXISTS import_jobs"],
+    }],
+};
+
+/// Runs archive imports and keeps track of them.
+pub struct ArchiveImports {
+    db_manager: Arc<Manager>,
+    doc_service: Arc<DocumentService>,
+    folders: Arc<FolderService>,
+    /// `None` when attachments are disabled; attached files are then skipped.
+    attachments: Option<Arc<AttachmentService>>,
+}
+
+impl ArchiveImports {
+    pub async fn new(db_manager: Arc<Manager>, doc_service: Arc<DocumentService>, folders: Arc<FolderService>) -> Result<Self> {
+        let service = ArchiveImports { db_manager, doc_service, folders, attachments: None };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    pub fn with_attachments(mut self, attachments: Arc<AttachmentService>) -> Self {
+        self.attachments = Some(attachments);
+        self
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("Archive import schema initialized.");
+        Ok(())
+    }
+
+    /// Records a job for `archive` and starts it in the background once `budget` has a
+    /// slot for it. Returns the job as queued.
+    #[instrument(skip_all, fields(owner_id = %destination.owner_id, source = source.as_str()))]
+    pub async fn start(
+        self: &Arc<Self>,
+        source: ExportSource,
+        archive: Vec<u8>,
+        destination: ImportDestination,
+        budget: Arc<RouteBudget>,
+    ) -> Result<ImportJob> {
+        let now = Utc::now();
+        let row: ImportJobRow = sqlx::query_as(&format!(
+                "INSERT INTO import_jobs (id, owner_id, workspace_id, folder_id, source, status, report, created_at, updated_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $8)
+                 RETURNING {}",
+                JOB_COLUMNS,
+            ))
+            .bind(Uuid::new_v4())
+            .bind(destination.owner_id)
+            .bind(destination.workspace_id)
+            .bind(destination.folder_id)
+            .bind(source.as_str())
+            .bind(JobStatus::Queued.as_str())
+            .bind(Json(ImportReport::default()))
+            .bind(now)
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context("Failed to record import job")?;
+        let job = ImportJob::try_from(row)?;
+        let imports = self.clone();
+        let job_id = job.id;
+        tokio::spawn(async move {
+            let _slot = budget.admit().await;
+            if let Err(e) = imports.run(job_id, source, archive, destination).await {
+                warn!("Import job {} failed: {:#}", job_id, e);
+                if let Err(e) = imports.finish(job_id, JobStatus::Failed, Some(format!("{:#}", e))).await {
+                    warn!("Failed to record failure of import job {}: {:#}", job_id, e);
+                }
+            }
+        });
+        Ok(job)
+    }
+
+    pub async fn get(&self, job_id: Uuid) -> Result<Option<ImportJob>> {
+        let row: Option<ImportJobRow> = sqlx::query_as(&format!("SELECT {} FROM import_jobs WHERE id = $1", JOB_COLUMNS))
+            .bind(job_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to get import job {}", job_id))?;
+        row.map(ImportJob::try_from).transpose()
+    }
+
+    /// The user's jobs, newest first.
+    #[instrument(skip_all, fields(owner_id = %owner_id))]
+    pub async fn jobs_of(&self, owner_id: Uuid, limit: i64) -> Result<Vec<ImportJob>> {
+        let rows: Vec<ImportJobRow> = sqlx::query_as(&format!(
+                "SELECT {} FROM import_jobs WHERE owner_id = $1 ORDER BY created_at DESC, id LIMIT $2",
+                JOB_COLUMNS,
+            ))
+            .bind(owner_id)
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list import jobs of user ID {}", owner_id))?;
+        rows.into_iter().map(ImportJob::try_from).collect()
+    }
+
+    async fn run(&self, job_id: Uuid, source: ExportSource, archive: Vec<u8>, destination: ImportDestination) -> Result<()> {
+        self.set_status(job_id, JobStatus::Running).await?;
+        let export = tokio::task::spawn_blocking(move || parse(source, &archive))
+            .await
+            .context("Archive parsing panicked")??;
+        let total_pages = export.pages.iter().map(Page::count).sum::<usize>();
+        let mut report = ImportReport { warnings: export.warnings, ..ImportReport::default() };
+        self.record_progress(job_id, total_pages, &report).await?;
+        let mut pending: Vec<(Page, Option<Uuid>, String)> = export.pages.into_iter()
+            .rev()
+            .map(|page| (page, destination.folder_id, String::new()))
+            .collect();
+        while let Some((page, folder_id, parent_path)) = pending.pop() {
+            let path = if parent_path.is_empty() { page.title.clone() } else { format!("{} / {}", parent_path, page.title) };
+            let folder_id = if page.children.is_empty() {
+                folder_id
+            } else {
+                match self.create_folder(&page.title, destination, folder_id).await {
+                    Ok(folder) => {
+                        report.folders += 1;
+                        Some(folder)
+                    }
+                    Err(e) => {
+                        report.warnings.push(format!("{}: could not create a folder, so its pages were put beside it: {:#}", path, e));
+                        folder_id
+                    }
+                }
+            };
+            match self.import_page(&page, destination, folder_id, &path, &mut report).await {
+                Ok(document_id) => report.documents.push(ImportedPage { path: path.clone(), document_id }),
+                Err(e) => report.warnings.push(format!("{}: not imported: {:#}", path, e)),
+            }
+            self.record_progress(job_id, total_pages, &report).await?;
+            pending.extend(page.children.into_iter().rev().map(|child| (child, folder_id, path.clone())));
+        }
+        self.finish(job_id, JobStatus::Succeeded, None).await?;
+        info!("Import job {} created {} documents", job_id, report.documents.len());
+        Ok(())
+    }
+
+    /// Creates a folder, numbering the name if it is taken.
+    async fn create_folder(&self, title: &str, destination: ImportDestination, parent_id: Option<Uuid>) -> Result<Uuid> {
+        let mut name = title.to_string();
+        for attempt in 2.. {
+            match self.folders.create_folder(&name, Some(destination.owner_id), destination.workspace_id, parent_id).await {
+                Ok(folder) => return Ok(folder.id),
+                Err(e) if attempt < 100 && e.downcast_ref::<FolderRejection>() == Some(&FolderRejection::NameTaken) => {
+                    name = fit_name(&format!("{} ({})", title, attempt));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!("folder name attempts are bounded")
+    }
+
+    /// Creates the page's document in `folder_id` and uploads its files. Returns the document's ID.
+    async fn import_page(
+        &self,
+        page: &Page,
+        destination: ImportDestination,
+        folder_id: Option<Uuid>,
+        path: &str,
+        report: &mut ImportReport,
+    ) -> Result<Uuid> {
+        let metadata = self.create_document(&page.title, destination, folder_id).await?;
+        self.doc_service.update_document_content(metadata.id, page.text.clone().into_bytes()).await?;
+        for file in &page.files {
+            let Some(attachments) = &self.attachments else {
+                report.warnings.push(format!("{}: skipped {}, as attachments are disabled", path, file.name));
+                continue;
+            };
+            if file.data.len() > attachments.max_bytes() {
+                report.warnings.push(format!("{}: skipped {}, which is larger than attachments may be", path, file.name));
+                continue;
+            }
+            let content_type = mime_guess::from_path(&file.name).first_or_octet_stream();
+            match attachments.upload(metadata.id, &file.name, content_type.as_ref(), file.data.clone(), Some(destination.owner_id)).await {
+                Ok(_) => report.attachments += 1,
+                Err(e) => report.warnings.push(format!("{}: could not attach {}: {:#}", path, file.name, e)),
+            }
+        }
+        Ok(metadata.id)
+    }
+
+    /// Creates a document named `title` in `folder_id`. Names are only unique within a
+    /// folder, so the document is created under a placeholder, moved, and then named; if
+    /// the folder already has a document called `title`, the first free variant is used.
+    async fn create_document(&self, title: &str, destination: ImportDestination, folder_id: Option<Uuid>) -> Result<DocumentMetadata> {
+        let placeholder = Uuid::new_v4().to_string();
+        let metadata = self.doc_service.create_document_in(&placeholder, Some(destination.owner_id), destination.workspace_id).await?;
+        if folder_id.is_some() {
+            self.folders.move_document(metadata.id, folder_id).await?;
+        }
+        let renamed = match self.doc_service.rename_document(metadata.id, title).await {
+            Err(e) => match e.downcast_ref::<NameTaken>().and_then(|taken| taken.suggestions.first()) {
+                Some(suggestion) => self.doc_service.rename_document(metadata.id, suggestion).await?,
+                None => return Err(e),
+            },
+            renamed => renamed?,
+        };
+        renamed.ok_or_else(|| anyhow!("Document ID {} disappeared during import", metadata.id))
+    }
+
+    async fn set_status(&self, job_id: Uuid, status: JobStatus) -> Result<()> {
+        sqlx::query("UPDATE import_jobs SET status = $2, updated_at = $3 WHERE id = $1")
+            .bind(job_id)
+            .bind(status.as_str())
+            .bind(Utc::now())
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to update import job {}", job_id))?;
+        Ok(())
+    }
+
+    async fn record_progress(&self, job_id: Uuid, total_pages: usize, report: &ImportReport) -> Result<()> {
+        sqlx::query("UPDATE import_jobs SET total_pages = $2, imported_pages = $3, report = $4, updated_at = $5 WHERE id = $1")
+            .bind(job_id)
+            .bind(i64::try_from(total_pages).unwrap_or(i64::MAX))
+            .bind(i64::try_from(report.documents.len()).unwrap_or(i64::MAX))
+            .bind(Json(report))
+            .bind(Utc::now())
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to record progress of import job {}", job_id))?;
+        Ok(())
+    }
+
+    async fn finish(&self, job_id: Uuid, status: JobStatus, error: Option<String>) -> Result<()> {
+        let now = Utc::now();
+        sqlx::query("UPDATE import_jobs SET status = $2, error = $3, updated_at = $4, finished_at = $4 WHERE id = $1")
+            .bind(job_id)
+            .bind(status.as_str())
+            .bind(error)
+            .bind(now)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to finish import job {}", job_id))?;
+        Ok(())
+    }
+}
+
+/// A page read from an archive, with the pages beneath it.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Page {
+    title: String,
+    text: String,
+    files: Vec<PageFile>,
+    children: Vec<Page>,
+}
+
+impl Page {
+    fn count(&self) -> usize {
+        1 + self.children.iter().map(Page::count).sum::<usize>()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct PageFile {
+    name: String,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct Export {
+    /// The top of the page tree, ordered by title.
+    pages: Vec<Page>,
+    warnings: Vec<String>,
+}
+
+fn parse(source: ExportSource, archive: &[u8]) -> Result<Export> {
+    let files = unzip(archive)?;
+    let export = match source {
+        ExportSource::Confluence => parse_confluence(files),
+        ExportSource::Notion => parse_notion(files),
+    };
+    if export.pages.is_empty() {
+        bail!("The archive holds no {} pages", source.as_str());
+    }
+    Ok(export)
+}
+
+/// The files in a ZIP by path, leaving out directories. An archive holding nothing but
+/// another ZIP, as Notion sometimes wraps its exports, is opened in turn.
+fn unzip(archive: &[u8]) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(archive)).context("The upload is not a ZIP archive")?;
+    if zip.len() > MAX_ENTRIES {
+        bail!("The archive holds more than {} files", MAX_ENTRIES);
+    }
+    let mut files = BTreeMap::new();
+    let mut extracted = 0u64;
+    for index in 0..zip.len() {
+        let entry = zip.by_index(index).context("Failed to read the archive")?;
+        if entry.is_dir() {
+            continue;
+        }
+        let name = entry.name().trim_start_matches('/').to_string();
+        let mut data = Vec::new();
+        let limit = MAX_EXTRACTED_BYTES - extracted;
+        entry.take(limit + 1).read_to_end(&mut data).context(format!("Failed to unpack {}", name))?;
+        extracted += data.len() as u64;
+        if extracted > MAX_EXTRACTED_BYTES {
+            bail!("The archive unpacks to more than {} bytes", MAX_EXTRACTED_BYTES);
+        }
+        files.insert(name, data);
+    }
+    if files.len() == 1
+        && let Some((name, inner)) = files.first_key_value()
+        && name.to_ascii_lowercase().ends_with(".zip")
+    {
+        return unzip(inner);
+    }
+    Ok(files)
+}
+
+/// Splits a path into its directory, without a trailing slash, and file name.
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some((dir, name)) => (dir, name),
+        None => ("", path),
+    }
+}
+
+fn fit_name(title: &str) -> String {
+    let title = title.trim();
+    let title = if title.is_empty() { "Untitled" } else { title };
+    title.chars().take(MAX_DOCUMENT_NAME_LENGTH).collect()
+}
+
+/// Turns pages keyed by their place in the archive into a tree, given each page's
+/// parent's key. Pages whose parent is not in the archive go at the top.
+fn build_tree(mut pages: HashMap<String, Page>, parents: HashMap<String, String>) -> Vec<Page> {
+    let mut children: HashMap<String, Vec<String>> = HashMap::new();
+    let mut roots = Vec::new();
+    for key in pages.keys() {
+        match parents.get(key).filter(|parent| pages.contains_key(*parent)) {
+            Some(parent) => children.entry(parent.clone()).or_default().push(key.clone()),
+            None => roots.push(key.clone()),
+        }
+    }
+    fn assemble(key: &str, pages: &mut HashMap<String, Page>, children: &HashMap<String, Vec<String>>) -> Option<Page> {
+        let mut page = pages.remove(key)?;
+        for child in children.get(key).into_iter().flatten() {
+            page.children.extend(assemble(child, pages, children));
+        }
+        page.children.sort_by_key(|page| page.title.to_lowercase());
+        Some(page)
+    }
+    let mut tree: Vec<Page> = roots.iter().filter_map(|key| assemble(key, &mut pages, &children)).collect();
+    tree.sort_by_key(|page| page.title.to_lowercase());
+    tree
+}
+
+/// Notion names each page `Title <32 hex digits>.md`, and puts the pages and files beneath
+/// it in a directory named the same way without the extension. Databases are a CSV file
+/// beside a directory of their rows.
+fn parse_notion(files: BTreeMap<String, Vec<u8>>) -> Export {
+    let mut export = Export::default();
+    let mut pages = HashMap::new();
+    let mut parents = HashMap::new();
+    let mut loose_files = Vec::new();
+    for (path, data) in files {
+        let (dir, name) = split_path(&path);
+        let lower = name.to_ascii_lowercase();
+        let stem = if lower.ends_with(".md") {
+            &name[..name.len() - 3]
+        } else if lower.ends_with(".csv") && !lower.ends_with("_all.csv") {
+            &name[..name.len() - 4]
+        } else {
+            if !lower.ends_with("_all.csv") {
+                loose_files.push((dir.to_string(), name.to_string(), data));
+            }
+            continue;
+        };
+        let key = if dir.is_empty() { stem.to_string() } else { format!("{}/{}", dir, stem) };
+        let Ok(text) = String::from_utf8(data) else {
+            export.warnings.push(format!("{}: skipped, as it is not UTF-8 text", path));
+            continue;
+        };
+        let (title, text) = match text.strip_prefix("# ").and_then(|rest| rest.split_once('\n')) {
+            Some((title, body)) if lower.ends_with(".md") => (title.trim().to_string(), body.trim_start().to_string()),
+            _ => (notion_title(stem), text),
+        };
+        parents.insert(key.clone(), dir.to_string());
+        pages.insert(key, Page { title: fit_name(&title), text, ..Page::default() });
+    }
+    for (dir, name, data) in loose_files {
+        match pages.get_mut(&dir) {
+            Some(page) => page.files.push(PageFile { name, data }),
+            None => export.warnings.push(format!("{}/{}: skipped, as it belongs to no page", dir, name)),
+        }
+    }
+    export.pages = build_tree(pages, parents);
+    export
+}
+
+/// A file stem without Notion's trailing ID.
+fn notion_title(stem: &str) -> String {
+    match stem.rsplit_once(' ') {
+        Some((title, id)) if id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit()) => title.to_string(),
+        _ => stem.to_string(),
+    }
+}
+
+/// Confluence writes each page to `Title_<page ID>.html` (or `<page ID>.html`) beside an
+/// `index.html` for the space, lists a page's ancestors in its breadcrumbs, and puts its
+/// attachments in `attachments/<page ID>/`.
+fn parse_confluence(files: BTreeMap<String, Vec<u8>>) -> Export {
+    let mut export = Export::default();
+    let mut pages = HashMap::new();
+    let mut parents = HashMap::new();
+    let mut page_ids = HashMap::new();
+    let mut attachment_names = HashMap::new();
+    for (path, data) in &files {
+        let (dir, name) = split_path(path);
+        if !name.to_ascii_lowercase().ends_with(".html") || name == "index.html" || dir.split('/').any(|part| part == "attachments") {
+            continue;
+        }
+        let html = String::from_utf8_lossy(data);
+        let stem = &name[..name.len() - 5];
+        let title = element(&html, "<title")
+            .map(|title| decode_entities(title.trim()))
+            .map(|title| title.split_once(" : ").map_or(title.clone(), |(_, page)| page.trim().to_string()))
+            .unwrap_or_else(|| stem.to_string());
+        let content = element(&html, "id=\"main-content\"").unwrap_or(&html);
+        let breadcrumbs = element(&html, "id=\"breadcrumbs\"").map(links).unwrap_or_default();
+        if let Some((href, _)) = breadcrumbs.iter().rev().find(|(href, _)| href != "index.html" && href.ends_with(".html")) {
+            parents.insert(path.clone(), join_path(dir, href));
+        }
+        let page_id = stem.rsplit('_').next().filter(|id| id.chars().all(|c| c.is_ascii_digit())).unwrap_or(stem);
+        page_ids.insert(join_path(dir, &format!("attachments/{}", page_id)), path.clone());
+        for (href, text) in links(&html) {
+            if href.starts_with("attachments/") && !text.is_empty() {
+                attachment_names.insert(join_path(dir, &href), text);
+            }
+        }
+        pages.insert(path.clone(), Page { title: fit_name(&title), text: html_to_text(content), ..Page::default() });
+    }
+    for (path, data) in files {
+        let (dir, file_name) = split_path(&path);
+        if !dir.split('/').any(|part| part == "attachments") {
+            continue;
+        }
+        let name = attachment_names.remove(&path).unwrap_or_else(|| file_name.to_string());
+        match page_ids.get(dir).and_then(|page| pages.get_mut(page)) {
+            Some(page) => page.files.push(PageFile { name, data }),
+            None => export.warnings.push(format!("{}: skipped, as it belongs to no page", path)),
+        }
+    }
+    export.pages = build_tree(pages, parents);
+    export
+}
+
+/// `href` resolved against the directory of the page linking to it.
+fn join_path(dir: &str, href: &str) -> String {
+    let href = href.split(['#', '?']).next().unwrap_or_default();
+    if dir.is_empty() { href.to_string() } else { format!("{}/{}", dir, href) }
+}
+
+/// What is inside the first element whose opening tag contains `marker`, up to its
+/// matching closing tag.
+fn element<'a>(html: &'a str, marker: &str) -> Option<&'a str> {
+    let at = html.find(marker)?;
+    let open = html[..=at].rfind('<')?;
+    let name_end = html[open + 1..].find(|c: char| !c.is_ascii_alphanumeric())? + open + 1;
+    let name = html[open + 1..name_end].to_ascii_lowercase();
+    let start = html[at..].find('>')? + at + 1;
+    let (opening, closing) = (format!("<{}", name), format!("</{}", name));
+    let mut depth = 1;
+    let mut cursor = start;
+    while let Some(offset) = html[cursor..].find('<') {
+        let tag = cursor + offset;
+        let rest = html[tag..].get(..closing.len()).unwrap_or_default().to_ascii_lowercase();
+        if rest == closing {
+            depth -= 1;
+            if depth == 0 {
+                return Some(&html[start..tag]);
+            }
+        } else if rest.starts_with(&opening)
+            && html[tag + opening.len()..].starts_with(|c: char| c == '>' || c.is_whitespace())
+        {
+            depth += 1;
+        }
+        cursor = tag + 1;
+    }
+    Some(&html[start..])
+}
+
+/// Every link's target and text.
+fn links(html: &str) -> Vec<(String, String)> {
+    let mut found = Vec::new();
+    let mut rest = html;
+    while let Some(at) = rest.find("<a ") {
+        rest = &rest[at..];
+        let Some(end) = rest.find('>') else { break };
+        let href = attribute(&rest[..end], "href");
+        let Some(close) = rest.find("</a>") else { break };
+        let text = html_to_text(&rest[end + 1..close]);
+        if let Some(href) = href {
+            found.push((decode_entities(&href), text));
+        }
+        rest = &rest[close..];
+    }
+    found
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let at = tag.find(&format!("{}=", name))? + name.len() + 1;
+    let quote = tag[at..].chars().next()?;
+    if quote == '"' || quote == '\'' {
+        let value = &tag[at + 1..];
+        Some(value[..value.find(quote)?].to_string())
+    } else {
+        Some(tag[at..].split(|c: char| c.is_whitespace() || c == '>').next()?.to_string())
+    }
+}
+
+/// Renders HTML in the text conventions documents use: headings as `#` lines, blocks
+/// separated by blank lines, list items as `- ` lines, bold as `**`, italics as `_`, code
+/// as backticks and table cells separated by ` | `. Everything else is left as its text.
+fn html_to_text(html: &str) -> String {
+    let mut text = TextWriter::default();
+    let mut lists = 0usize;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_text(&decode_entities(&rest[..start]));
+        rest = &rest[start..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(end) = rest.find('>') else { break };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        let closing = tag.starts_with('/');
+        let name = tag.trim_start_matches('/').split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default().to_ascii_lowercase();
+        match name.as_str() {
+            "script" | "style" if !closing => {
+                let close = format!("</{}", name);
+                rest = rest.to_ascii_lowercase().find(&close).map_or("", |at| &rest[at..]);
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                text.break_block();
+                if !closing {
+                    text.push_raw(&format!("{} ", "#".repeat(usize::from(name.as_bytes()[1] - b'0'))));
+                }
+            }
+            "p" | "div" | "table" | "blockquote" => text.break_block(),
+            "pre" => {
+                text.break_block();
+                text.preformatted = !closing;
+            }
+            "ul" | "ol" => {
+                lists = if closing { lists.saturating_sub(1) } else { lists + 1 };
+                if lists == 0 {
+                    text.break_block();
+                }
+            }
+            "li" if !closing => {
+                text.break_line();
+                text.push_raw(&format!("{}- ", "  ".repeat(lists.saturating_sub(1))));
+            }
+            "br" | "tr" => text.break_line(),
+            "td" | "th" if !closing && !text.at_line_start() => text.push_raw(" | "),
+            "strong" | "b" => text.push_raw("**"),
+            "em" | "i" => text.push_raw("_"),
+            "code" if !text.preformatted => text.push_raw("`"),
+            _ => {}
+        }
+    }
+    text.push_text(&decode_entities(rest));
+    text.finish()
+}
+
+/// Builds text a block at a time, collapsing whitespace outside preformatted blocks.
+#[derive(Default)]
+struct TextWriter {
+    out: String,
+    preformatted: bool,
+    pending_space: bool,
+}
+
+impl TextWriter {
+    fn at_line_start(&self) -> bool {
+        self.out.is_empty() || self.out.ends_with('\n')
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if self.preformatted {
+            self.out.push_str(text);
+            return;
+        }
+        for (index, word) in text.split_whitespace().enumerate() {
+            if (index > 0 || self.pending_space || text.starts_with(char::is_whitespace)) && !self.at_line_start() && !self.out.ends_with(' ') {
+                self.out.push(' ');
+            }
+            self.out.push_str(word);
+            self.pending_space = false;
+        }
+        if text.ends_with(char::is_whitespace) && !text.trim().is_empty() {
+            self.pending_space = true;
+        }
+    }
+
+    fn push_raw(&mut self, raw: &str) {
+        if self.pending_space && !self.at_line_start() {
+            self.out.push(' ');
+        }
+        self.pending_space = false;
+        self.out.push_str(raw);
+    }
+
+    fn break_line(&mut self) {
+        self.pending_space = false;
+        if !self.at_line_start() {
+            self.out.push('\n');
+        }
+    }
+
+    fn break_block(&mut self) {
+        self.break_line();
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn finish(self) -> String {
+        let lines: Vec<&str> = self.out.lines().map(str::trim_end).collect();
+        lines.join("\n").trim().to_string()
+    }
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('&') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let decoded = rest[1..].find(';').filter(|end| *end <= 10).and_then(|end| {
+            let entity = &rest[1..end + 1];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => entity.strip_prefix('#').and_then(|code| match code.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => code.parse().ok(),
+                }).and_then(char::from_u32),
+            };
+            c.map(|c| (c, end + 2))
+        });
+        match decoded {
+            Some((c, length)) => {
+                out.push(c);
+                rest = &rest[length..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_service::UserService;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, data) in files {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn titles(pages: &[Page]) -> Vec<(String, Vec<String>)> {
+        pages.iter()
+            .map(|page| (page.title.clone(), page.children.iter().map(|child| child.title.clone()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let html = "<h2>Plan &amp; goals</h2><p>Ship <strong>soon</strong>, not <em>later</em>.</p>\
+                    <ul><li>One</li><li>Two<ul><li>Nested</li></ul></li></ul>\
+                    <script>ignored()</script><table><tr><th>A</th><th>B</th></tr><tr><td>1</td><td>2</td></tr></table>\
+                    <pre>  keep\n  spacing</pre><p>Call <code>run()</code><br/>then stop</p>";
+        assert_eq!(
+            html_to_text(html),
+            "## Plan & goals\n\nShip **soon**, not _later_.\n\n- One\n- Two\n  - Nested\n\nA | B\n1 | 2\n\n  keep\n  spacing\n\nCall `run()`\nthen stop",
+        );
+    }
+
+    #[test]
+    fn test_notion_pages_nest_under_their_parents() -> Result<()> {
+        let id = "0123456789abcdef0123456789abcdef";
+        let archive = zip(&[
+            (&format!("Projects {}.md", id), b"# Projects\n\nAll of them."),
+            (&format!("Projects {}/Launch {}.md", id, id), b"# Launch\n\nSoon."),
+            (&format!("Projects {}/diagram.png", id), b"png"),
+            (&format!("Projects {}/Tasks {}.csv", id, id), b"Name,Done\nWrite,No\n"),
+            (&format!("Projects {}/Tasks {}_all.csv", id, id), b"Name,Done\nWrite,No\n"),
+            (&format!("Projects {}/Tasks {}/Write {}.md", id, id, id), b"# Write\n\nDone: No"),
+            (&format!("Notes {}.md", id), b"No heading here"),
+        ]);
+        let export = parse(ExportSource::Notion, &archive)?;
+        assert_eq!(titles(&export.pages), vec![
+            ("Notes".to_string(), vec![]),
+            ("Projects".to_string(), vec!["Launch".to_string(), "Tasks".to_string()]),
+        ]);
+        let projects = &export.pages[1];
+        assert_eq!(projects.text, "All of them.");
+        assert_eq!(projects.files, vec![PageFile { name: "diagram.png".to_string(), data: b"png".to_vec() }]);
+        assert_eq!(titles(&projects.children[1..]), vec![("Tasks".to_string(), vec!["Write".to_string()])]);
+        assert_eq!(export.pages[0].text, "No heading here");
+
+        // Notion sometimes wraps the export in a second ZIP.
+        let wrapped = zip(&[("Export.zip", &archive)]);
+        assert_eq!(titles(&parse(ExportSource::Notion, &wrapped)?.pages), titles(&export.pages));
+        assert!(parse(ExportSource::Confluence, &archive).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_confluence_pages_follow_their_breadcrumbs() -> Result<()> {
+        let page = |title: &str, crumbs: &str, body: &str| format!(
+            "<html><head><title>Team Space : {}</title></head><body>\
+             <div id=\"breadcrumbs\"><ol><li><a href=\"index.html\">Team Space</a></li>{}</ol></div>\
+             <div id=\"main-content\" class=\"wiki-content\">{}</div><div id=\"footer\">Generated</div></body></html>",
+            title, crumbs, body,
+        );
+        let home = page("Home", "", "<p>Welcome</p>");
+        let guide = page(
+            "Guide",
+            "<li><a href=\"Home_101.html\">Home</a></li>",
+            "<div><p>Read <a href=\"attachments/102/555.pdf\">manual.pdf</a></p></div>",
+        );
+        let archive = zip(&[
+            ("TEAM/index.html", b"<html></html>"),
+            ("TEAM/Home_101.html", home.as_bytes()),
+            ("TEAM/Guide_102.html", guide.as_bytes()),
+            ("TEAM/attachments/102/555.pdf", b"%PDF"),
+            ("TEAM/attachments/999/1.txt", b"orphan"),
+        ]);
+        let export = parse(ExportSource::Confluence, &archive)?;
+        assert_eq!(titles(&export.pages), vec![("Home".to_string(), vec!["Guide".to_string()])]);
+        assert_eq!(export.pages[0].text, "Welcome");
+        let guide = &export.pages[0].children[0];
+        assert_eq!(guide.text, "Read manual.pdf");
+        assert_eq!(guide.files, vec![PageFile { name: "manual.pdf".to_string(), data: b"%PDF".to_vec() }]);
+        assert_eq!(export.warnings.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_job_builds_folders_and_documents() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = Arc::new(DocumentService::new(manager.clone()).await?);
+        let users = UserService::new(manager.clone()).await?;
+        let folders = Arc::new(FolderService::new(manager.clone(), doc_service.clone()).await?);
+        let imports = Arc::new(ArchiveImports::new(manager, doc_service.clone(), folders.clone()).await?);
+        let owner = users.register_user("Importer", &format!("importer-{}@example.com", Uuid::new_v4()), "correct horse battery").await?;
+        let target = folders.create_folder("Imported", Some(owner.id), None, None).await?;
+        folders.create_folder("Projects", Some(owner.id), None, Some(target.id)).await?;
+
+        let id = "0123456789abcdef0123456789abcdef";
+        let archive = zip(&[
+            (&format!("Projects {}.md", id), b"# Projects\n\nAll of them."),
+            (&format!("Projects {}/Launch {}.md", id, id), b"# Launch\n\nSoon."),
+            (&format!("Projects {}/diagram.png", id), b"png"),
+        ]);
+        let destination = ImportDestination { owner_id: owner.id, workspace_id: None, folder_id: Some(target.id) };
+        let budget = Arc::new(RouteBudget::new(crate::concurrency::RouteClass::Import, 1));
+        let queued = imports.start(ExportSource::Notion, archive, destination, budget).await?;
+        assert_eq!(queued.status, JobStatus::Queued);
+
+        let job = tokio::time::timeout(std::time::Duration::from_secs(30), async {
+            loop {
+                let job = imports.get(queued.id).await?.expect("job should exist");
+                if job.finished_at.is_some() {
+                    return Ok::<_, anyhow::Error>(job);
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        }).await??;
+        assert_eq!((job.status, job.total_pages, job.imported_pages), (JobStatus::Succeeded, 2, 2));
+        assert_eq!(job.report.folders, 1);
+        // Attachments are disabled here, so the image is reported rather than uploaded.
+        assert_eq!((job.report.attachments, job.report.warnings.len()), (0, 1));
+        let paths: Vec<&str> = job.report.documents.iter().map(|page| page.path.as_str()).collect();
+        assert_eq!(paths, vec!["Projects", "Projects / Launch"]);
+
+        // The existing folder keeps its name; the imported one is numbered beside it.
+        let contents = folders.children(target.id).await?;
+        let names: Vec<&str> = contents.folders.iter().map(|folder| folder.name.as_str()).collect();
+        assert_eq!(names, vec!["Projects", "Projects (2)"]);
+        let imported = folders.children(contents.folders[1].id).await?;
+        let names: Vec<&str> = imported.documents.iter().map(|doc| doc.name.as_str()).collect();
+        assert_eq!(names, vec!["Launch", "Projects"]);
+        let launch = doc_service.get_document(job.report.documents[1].document_id).await?.expect("document should exist");
+        assert_eq!(launch.content.map(|content| content.crdt_data), Some(b"Soon.".to_vec()));
+
+        assert_eq!(imports.jobs_of(owner.id, 10).await?.iter().map(|job| job.id).collect::<Vec<_>>(), vec![job.id]);
+        Ok(())
+    }
+}
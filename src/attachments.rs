@@ -0,0 +1,537 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! File attachments on documents. Files live in object storage and, when a scanner is
+//! configured, stay quarantined until it has scanned them clean.
+
+use crate::blob_store;
+use crate::db::Manager;
+use crate::events::schema::{self, AttachmentInfected};
+use crate::migrations::{self, Component, Migration};
+use crate::scanning::{ScanVerdict, Scanner};
+use crate::storage_quota;
+use crate::webhooks::Webhooks;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use object_store::path::Path;
+use object_store::{ObjectStore, PutPayload};
+use serde::Serialize;
+use sqlx::{FromRow};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+const ATTACHMENT_URL_ENV: &str = "COLLABORATE_ATTACHMENT_URL";
+const ATTACHMENT_MAX_BYTES_ENV: &str = "COLLABORATE_ATTACHMENT_MAX_BYTES";
+const DEFAULT_MAX_BYTES: usize = 25 * 1024 * 1024;
+/// Pending scans older than this are assumed lost, e.g. to a restart or a scanner outage, and retried.
+const RESCAN_AFTER: TimeDelta = TimeDelta::minutes(5);
+const RESCAN_BATCH_SIZE: i64 = 32;
+
+/// Where attachments are stored and how large they may be.
+#[derive(Clone, Debug)]
+pub struct AttachmentConfig {
+    /// Object storage URL; see `blob_store::open`.
+    pub url: String,
+    pub max_bytes: usize,
+}
+
+impl AttachmentConfig {
+    /// Returns `None` when `COLLABORATE_ATTACHMENT_URL` is unset, i.e. attachments are disabled.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(url) = env::var(ATTACHMENT_URL_ENV) else {
+            return Ok(None);
+        };
+        let max_bytes = match env::var(ATTACHMENT_MAX_BYTES_ENV) {
+            Ok(bytes) => bytes.parse().context(format!("{} must be a number of bytes", ATTACHMENT_MAX_BYTES_ENV))?,
+            Err(_) => DEFAULT_MAX_BYTES,
+        };
+        Ok(Some(AttachmentConfig { url, max_bytes }))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanStatus {
+    /// Waiting for the scanner; not downloadable.
+    Pending,
+    Clean,
+    /// The scanner found malware; never downloadable.
+    Infected,
+    /// Uploaded while no scanner was configured.
+    Unscanned,
+}
+
+impl ScanStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ScanStatus::Pending => "pending",
+            ScanStatus::Clean => "clean",
+            ScanStatus::Infected => "infected",
+            ScanStatus::Unscanned => "unscanned",
+        }
+    }
+
+    pub fn is_downloadable(&self) -> bool {
+        matches!(self, ScanStatus::Clean | ScanStatus::Unscanned)
+    }
+}
+
+impl FromStr for ScanStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pending" => Ok(ScanStatus::Pending),
+            "clean" => Ok(ScanStatus::Clean),
+            "infected" => Ok(ScanStatus::Infected),
+            "unscanned" => Ok(ScanStatus::Unscanned),
+            other => Err(anyhow!("Unknown scan status '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub document_id: Uuid,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: i64,
+    pub scan_status: ScanStatus,
+    /// What the scanner found, for infected attachments.
+    pub scan_signature: Option<String>,
+    pub uploaded_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub scanned_at: Option<DateTime<Utc>>,
+}
+
+#[derive(FromRow)]
+struct AttachmentRow {
+    id: Uuid,
+    document_id: Uuid,
+    file_name: String,
+    content_type: String,
+    size_bytes: i64,
+    scan_status: String,
+    scan_signature: Option<String>,
+    uploaded_by: Option<Uuid>,
+    created_at: DateTime<Utc>,
+    scanned_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<AttachmentRow> for Attachment {
+    type Error = anyhow::Error;
+
+    fn try_from(row: AttachmentRow) -> Result<Self> {
+        Ok(Attachment {
+            id: row.id,
+            document_id: row.document_id,
+            file_name: row.file_name,
+            content_type: row.content_type,
+            size: row.size_bytes,
+            scan_status: row.scan_status.parse()?,
+            scan_signature: row.scan_signature,
+            uploaded_by: row.uploaded_by,
+            created_at: row.created_at,
+            scanned_at: row.scanned_at,
+        })
+    }
+}
+
+const ATTACHMENT_COLUMNS: &str =
+    "id, document_id, file_name, content_type, size_bytes, scan_status, scan_signature, uploaded_by, created_at, scanned_at";
+
+/// Returned by `download` for attachments that are pending a scan or infected.
+#[derive(Debug)]
+pub struct Quarantined(pub ScanStatus);
+
+impl fmt::Display for Quarantined {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Attachment is quarantined ({})", self.0.as_str())
+    }
+}
+
+impl std::error::Error for Quarantined {}
+
+#[derive(Clone)]
+pub struct AttachmentService {
+    db_manager: Arc<Manager>,
+    store: Arc<dyn ObjectStore>,
+    root: Path,
+    max_bytes: usize,
+    scanner: Option<Arc<dyn Scanner>>,
+    webhooks: Option<Arc<Webhooks>>,
+    storage_quota: Option<i64>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "attachments",
+    migrations: &[
+        Migration {
+            version: 1,
+            description: "baseline",
+            up: &[
+                "CREATE TABLE IF NOT EXISTS attachments (
+                    id UUID PRIMARY KEY,
+                    document_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                    file_name TEXT NOT NULL,
+                    content_type TEXT NOT NULL,
+                    size_bytes BIGINT NOT NULL,
+                    object_key TEXT NOT NULL,
+                    uploaded_by UUID,
+                    created_at TIMESTAMPTZ NOT NULL,
+                    scan_status TEXT NOT NULL,
+                    scan_signature TEXT,
+                    scanned_at TIMESTAMPTZ
+                )",
+                "CREATE INDEX IF NOT EXISTS attachments_document ON attachments (document_id, created_at)",
+            ],
+            down: &[
+                "DROP TABLE IF EXISTS attachments",
+            ],
+        },
+        storage_quota::COUNT_ATTACHMENTS,
+    ],
+};
+
+impl AttachmentService {
+    pub async fn new(db_manager: Arc<Manager>, store: Arc<dyn ObjectStore>, root: Path) -> Result<Self> {
+        let service = AttachmentService { db_manager, store, root, max_bytes: DEFAULT_MAX_BYTES, scanner: None, webhooks: None, storage_quota: None };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    pub async fn from_config(db_manager: Arc<Manager>, config: &AttachmentConfig) -> Result<Self> {
+        let (store, root) = blob_store::open(&config.url)?;
+        let mut service = AttachmentService::new(db_manager, store, root).await?;
+        service.max_bytes = config.max_bytes;
+        Ok(service)
+    }
+
+    /// The largest upload accepted.
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Quarantines new uploads until `scanner` has scanned them clean.
+    pub fn with_scanner(mut self, scanner: Arc<dyn Scanner>) -> Self {
+        self.scanner = Some(scanner);
+        self
+    }
+
+    /// Reports infected uploads as `attachment.infected` webhook events.
+    pub fn with_webhooks(mut self, webhooks: Arc<Webhooks>) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Charges uploads to the document's owner, refusing those that would take them past
+    /// `quota_bytes` with `QuotaExceeded`; see `storage_quota`.
+    pub fn with_storage_quota(mut self, quota_bytes: i64) -> Self {
+        self.storage_quota = Some(quota_bytes);
+        self
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("Attachment schema initialized.");
+        Ok(())
+    }
+
+    /// Stores a file and, if a scanner is configured, starts scanning it in the background.
+    /// The returned attachment is `Pending` until the scan finishes. Fails with
+    /// `QuotaExceeded` if the document's owner has no room for the file.
+    #[instrument(skip_all, fields(doc_id = %doc_id, uploaded_by = ?uploaded_by))]
+    pub async fn upload(
+        &self,
+        doc_id: Uuid,
+        file_name: &str,
+        content_type: &str,
+        data: Vec<u8>,
+        uploaded_by: Option<Uuid>,
+    ) -> Result<Attachment> {
+        let id = Uuid::new_v4();
+        let location = self.root.child("attachments").child(doc_id.to_string()).child(id.to_string());
+        self.store
+            .put(&location, PutPayload::from(data.clone()))
+            .await
+            .context(format!("Failed to store attachment for document ID {}", doc_id))?;
+
+        let status = if self.scanner.is_some() { ScanStatus::Pending } else { ScanStatus::Unscanned };
+        let recorded = async {
+            let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+            let row: AttachmentRow = sqlx::query_as(&format!(
+                    "INSERT INTO attachments (id, document_id, file_name, content_type, size_bytes, object_key, uploaded_by, created_at, scan_status)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING {}",
+                    ATTACHMENT_COLUMNS
+                ))
+                .bind(id)
+                .bind(doc_id)
+                .bind(file_name)
+                .bind(content_type)
+                .bind(data.len() as i64)
+                .bind(location.to_string())
+                .bind(uploaded_by)
+                .bind(Utc::now())
+                .bind(status.as_str())
+                .fetch_one(&mut *tx)
+                .await
+                .context(format!("Failed to record attachment for document ID {}", doc_id))?;
+            storage_quota::charge(&mut tx, doc_id, data.len() as i64, self.storage_quota).await?;
+            tx.commit().await.context(format!("Failed to commit attachment for document ID {}", doc_id))?;
+            Ok::<_, anyhow::Error>(row)
+        };
+        // Nothing refers to the object unless its row committed.
+        let row = match recorded.await {
+            Ok(row) => row,
+            Err(e) => {
+                self.store.delete(&location).await.ok();
+                return Err(e);
+            }
+        };
+
+        if self.scanner.is_some() {
+            let service = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = service.scan(id, &data).await {
+                    warn!("Scanning attachment ID {} failed; will retry: {:#}", id, e);
+                }
+            });
+        }
+        row.try_into()
+    }
+
+    /// Scans an attachment's content and records the verdict. Does nothing without a scanner.
+    async fn scan(&self, id: Uuid, data: &[u8]) -> Result<ScanStatus> {
+        let Some(scanner) = &self.scanner else {
+            return Ok(ScanStatus::Unscanned);
+        };
+        let (status, signature) = match scanner.scan(data).await? {
+            ScanVerdict::Clean => (ScanStatus::Clean, None),
+            ScanVerdict::Infected { signature } => (ScanStatus::Infected, Some(signature)),
+        };
+        let row: Option<AttachmentRow> = sqlx::query_as(&format!(
+                "UPDATE attachments SET scan_status = $1, scan_signature = $2, scanned_at = $3
+                 WHERE id = $4 AND scan_status = 'pending' RETURNING {}",
+                ATTACHMENT_COLUMNS
+            ))
+            .bind(status.as_str())
+            .bind(&signature)
+            .bind(Utc::now())
+            .bind(id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to record scan result for attachment ID {}", id))?;
+
+        // No row means a concurrent rescan already recorded (and reported) the verdict.
+        if let Some(row) = row
+            && status == ScanStatus::Infected
+        {
+            warn!("Attachment ID {} is infected ({}); quarantined", id, signature.as_deref().unwrap_or_default());
+            if let Some(webhooks) = &self.webhooks {
+                let attachment = Attachment::try_from(row)?;
+                let event = AttachmentInfected { attachment: &attachment, scanner: scanner.name() };
+                // The verdict is recorded either way; a lost event is not retried.
+                if let Err(e) = webhooks.send(&schema::ATTACHMENT_INFECTED, event).await {
+                    warn!("Failed to report infected attachment ID {}: {:#}", id, e);
+                }
+            }
+        }
+        Ok(status)
+    }
+
+    /// Retries scans that have been pending for a while. Returns the number completed.
+    #[instrument(skip_all)]
+    pub async fn rescan_pending(&self) -> Result<usize> {
+        if self.scanner.is_none() {
+            return Ok(0);
+        }
+        let stale: Vec<(Uuid, String)> = sqlx::query_as(
+                "SELECT id, object_key FROM attachments
+                 WHERE scan_status = 'pending' AND created_at < $1 ORDER BY created_at LIMIT $2"
+            )
+            .bind(Utc::now() - RESCAN_AFTER)
+            .bind(RESCAN_BATCH_SIZE)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to find attachments pending a scan")?;
+        let mut scanned = 0;
+        for (id, key) in stale {
+            let data = self.fetch(&key).await?;
+            self.scan(id, &data).await?;
+            scanned += 1;
+        }
+        Ok(scanned)
+    }
+
+    #[instrument(skip_all, fields(id = %id))]
+    pub async fn get(&self, id: Uuid) -> Result<Option<Attachment>> {
+        let row: Option<AttachmentRow> = sqlx::query_as(&format!("SELECT {} FROM attachments WHERE id = $1", ATTACHMENT_COLUMNS))
+            .bind(id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to get attachment ID {}", id))?;
+        row.map(Attachment::try_from).transpose()
+    }
+
+    /// A document's attachments, oldest first, including quarantined ones.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn list(&self, doc_id: Uuid) -> Result<Vec<Attachment>> {
+        let rows: Vec<AttachmentRow> = sqlx::query_as(&format!(
+                "SELECT {} FROM attachments WHERE document_id = $1 ORDER BY created_at, id",
+                ATTACHMENT_COLUMNS
+            ))
+            .bind(doc_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list attachments of document ID {}", doc_id))?;
+        rows.into_iter().map(Attachment::try_from).collect()
+    }
+
+    /// An attachment's content. Fails with `Quarantined` unless it is clean or was uploaded unscanned.
+    #[instrument(skip_all, fields(id = %id))]
+    pub async fn download(&self, id: Uuid) -> Result<Option<(Attachment, Vec<u8>)>> {
+        let Some(attachment) = self.get(id).await? else {
+            return Ok(None);
+        };
+        if !attachment.scan_status.is_downloadable() {
+            return Err(Quarantined(attachment.scan_status).into());
+        }
+        let key: String = sqlx::query_scalar("SELECT object_key FROM attachments WHERE id = $1")
+            .bind(id)
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to get attachment ID {}", id))?;
+        let data = self.fetch(&key).await?;
+        Ok(Some((attachment, data)))
+    }
+
+    /// Round-trip time of a metadata lookup in the object store. The probed key never exists,
+    /// so a not-found answer counts as success.
+    pub async fn probe_store(&self) -> Result<Duration> {
+        let started = Instant::now();
+        match self.store.head(&self.root.child(".diagnostics")).await {
+            Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(started.elapsed()),
+            Err(e) => Err(e).context("Failed to reach attachment storage"),
+        }
+    }
+
+    async fn fetch(&self, key: &str) -> Result<Vec<u8>> {
+        let data = self.store
+            .get(&Path::from(key))
+            .await
+            .context(format!("Failed to fetch attachment {}", key))?
+            .bytes()
+            .await?;
+        Ok(data.to_vec())
+    }
+}
+
+/// Periodically retries attachment scans that never finished.
+pub async fn run_rescanner(service: Arc<AttachmentService>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match service.rescan_pending().await {
+            Ok(0) => {}
+            Ok(scanned) => info!("Rescanned {} pending attachments", scanned),
+            Err(e) => warn!("Rescanning pending attachments failed; will retry: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_service::DocumentService;
+    use async_trait::async_trait;
+    use object_store::memory::InMemory;
+    use tokio::sync::Notify;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    // Flags files containing "EVIL", once released; lets the test observe the pending state.
+    struct FakeScanner {
+        release: Notify,
+    }
+
+    #[async_trait]
+    impl Scanner for FakeScanner {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        async fn scan(&self, data: &[u8]) -> Result<ScanVerdict> {
+            self.release.notified().await;
+            if data.windows(4).any(|window| window == b"EVIL") {
+                return Ok(ScanVerdict::Infected { signature: "Test-Signature".to_string() });
+            }
+            Ok(ScanVerdict::Clean)
+        }
+    }
+
+    async fn wait_for_scan(service: &AttachmentService, id: Uuid) -> Result<Attachment> {
+        for _ in 0..100 {
+            let attachment = service.get(id).await?.unwrap();
+            if attachment.scan_status != ScanStatus::Pending {
+                return Ok(attachment);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("attachment ID {} was never scanned", id);
+    }
+
+    #[tokio::test]
+    async fn test_attachments_are_quarantined_until_scanned() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let doc = doc_service.create_document("Test Document for Attachments").await?;
+        let scanner = Arc::new(FakeScanner { release: Notify::new() });
+        let service = AttachmentService::new(manager.clone(), Arc::new(InMemory::new()), Path::from("test"))
+            .await?
+            .with_scanner(scanner.clone());
+
+        let clean = service.upload(doc.id, "notes.txt", "text/plain", b"hello".to_vec(), None).await?;
+        assert_eq!(clean.scan_status, ScanStatus::Pending);
+        let error = service.download(clean.id).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<Quarantined>(), Some(Quarantined(ScanStatus::Pending))));
+        scanner.release.notify_one();
+        assert_eq!(wait_for_scan(&service, clean.id).await?.scan_status, ScanStatus::Clean);
+        let (_, data) = service.download(clean.id).await?.unwrap();
+        assert_eq!(data, b"hello");
+
+        let infected = service.upload(doc.id, "invoice.exe", "application/octet-stream", b"xEVILx".to_vec(), None).await?;
+        scanner.release.notify_one();
+        let infected = wait_for_scan(&service, infected.id).await?;
+        assert_eq!(infected.scan_status, ScanStatus::Infected);
+        assert_eq!(infected.scan_signature.as_deref(), Some("Test-Signature"));
+        assert!(service.download(infected.id).await.unwrap_err().downcast_ref::<Quarantined>().is_some());
+
+        let listed: Vec<Uuid> = service.list(doc.id).await?.iter().map(|attachment| attachment.id).collect();
+        assert_eq!(listed, vec![clean.id, infected.id]);
+
+        // Without a scanner, uploads are downloadable straight away.
+        let unscanned_service = AttachmentService::new(manager, Arc::new(InMemory::new()), Path::from("test")).await?;
+        let unscanned = unscanned_service.upload(doc.id, "a.txt", "text/plain", b"a".to_vec(), None).await?;
+        assert_eq!(unscanned.scan_status, ScanStatus::Unscanned);
+        assert!(unscanned_service.download(unscanned.id).await?.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! An append-only record of security-sensitive operations: sign-ins and failed sign-ins,
+//! password changes, sharing, API keys, and what administrators do. Each event notes who
+//! acted, from which address and user agent, and when. Nothing here changes or removes
+//! events once written, and they outlive the accounts they name.
+
+use crate::db::Manager;
+use crate::migrations::{self, Component, Migration};
+use anyhow::{anyhow, Context, Result};
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::header::USER_AGENT;
+use axum::http::request::Parts;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::FromRow;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+/// Longer user agents are cut short; they are for telling clients apart, not fingerprinting.
+const MAX_USER_AGENT_CHARS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    PasswordChanged,
+    PermissionGranted,
+    PermissionRevoked,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    AccountSuspended,
+    AccountReactivated,
+    RoleChanged,
+    ModerationDecided,
+    DrainStarted,
+    EncryptionKeySet,
+    EncryptionKeyRevoked,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Login => "login",
+            AuditAction::LoginFailed => "login_failed",
+            AuditAction::PasswordChanged => "password_changed",
+            AuditAction::PermissionGranted => "permission_granted",
+            AuditAction::PermissionRevoked => "permission_revoked",
+            AuditAction::ApiKeyCreated => "api_key_created",
+            AuditAction::ApiKeyRevoked => "api_key_revoked",
+            AuditAction::AccountSuspended => "account_suspended",
+            AuditAction::AccountReactivated => "account_reactivated",
+            AuditAction::RoleChanged => "role_changed",
+            AuditAction::ModerationDecided => "moderation_decided",
+            AuditAction::DrainStarted => "drain_started",
+            AuditAction::EncryptionKeySet => "encryption_key_set",
+            AuditAction::EncryptionKeyRevoked => "encryption_key_revoked",
+        }
+    }
+}
+
+impl FromStr for AuditAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "login" => Ok(AuditAction::Login),
+            "login_failed" => Ok(AuditAction::LoginFailed),
+            "password_changed" => Ok(AuditAction::PasswordChanged),
+            "permission_granted" => Ok(AuditAction::PermissionGranted),
+            "permission_revoked" => Ok(AuditAction::PermissionRevoked),
+            "api_key_created" => Ok(AuditAction::ApiKeyCreated),
+            "api_key_revoked" => Ok(AuditAction::ApiKeyRevoked),
+            "account_suspended" => Ok(AuditAction::AccountSuspended),
+            "account_reactivated" => Ok(AuditAction::AccountReactivated),
+            "role_changed" => Ok(AuditAction::RoleChanged),
+            "moderation_decided" => Ok(AuditAction::ModerationDecided),
+            "drain_started" => Ok(AuditAction::DrainStarted),
+            "encryption_key_set" => Ok(AuditAction::EncryptionKeySet),
+            "encryption_key_revoked" => Ok(AuditAction::EncryptionKeyRevoked),
+            other => Err(anyhow!("Unknown audit action '{}'", other)),
+        }
+    }
+}
+
+/// Where a request came from: the peer address, which is the nearest proxy's when behind
+/// one, and the client's own account of itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequestOrigin {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestOrigin {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(RequestOrigin {
+            ip: parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip()),
+            user_agent: parts.headers
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|agent| agent.chars().take(MAX_USER_AGENT_CHARS).collect()),
+        })
+    }
+}
+
+/// An event to record; see `AuditLog::record`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditRecord {
+    action: AuditAction,
+    actor: Option<Uuid>,
+    target: Option<String>,
+    detail: Option<Value>,
+}
+
+impl AuditRecord {
+    pub fn new(action: AuditAction) -> Self {
+        AuditRecord { action, actor: None, target: None, detail: None }
+    }
+
+    /// The account that acted. Left unset for the admin token and for failed sign-ins.
+    pub fn with_actor(mut self, actor: Uuid) -> Self {
+        self.actor = Some(actor);
+        self
+    }
+
+    /// What was acted on: an account, document, key or review ID, or the email address a
+    /// failed sign-in gave.
+    pub fn with_target(mut self, target: impl ToString) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn with_detail(mut self, detail: Value) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    pub action: AuditAction,
+    pub actor: Option<Uuid>,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub detail: Option<Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct AuditEventRow {
+    id: Uuid,
+    action: String,
+    actor: Option<Uuid>,
+    target: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    detail: Option<Value>,
+    created_at: DateTime<Utc>,
+}
+
+impl TryFrom<AuditEventRow> for AuditEvent {
+    type Error = anyhow::Error;
+
+    fn try_from(row: AuditEventRow) -> Result<Self> {
+        Ok(AuditEvent {
+            id: row.id,
+            action: row.action.parse()?,
+            actor: row.actor,
+            target: row.target,
+            ip: row.ip,
+            user_agent: row.user_agent,
+            detail: row.detail,
+            created_at: row.created_at,
+        })
+    }
+}
+
+/// Which events to list; unset fields match everything.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditFilter {
+    pub action: Option<AuditAction>,
+    pub actor: Option<Uuid>,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Continues a listing after this event, which came last on the page before.
+    pub before: Option<Uuid>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "audit",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS audit_events (
+                id UUID PRIMARY KEY,
+                action TEXT NOT NULL,
+                actor UUID,
+                target TEXT,
+                ip TEXT,
+                user_agent TEXT,
+                detail JSONB,
+                created_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE INDEX IF NOT EXISTS audit_events_by_time ON audit_events (created_at, id)",
+            "CREATE INDEX IF NOT EXISTS audit_events_by_actor ON audit_events (actor, created_at)",
+            "CREATE INDEX IF NOT EXISTS audit_events_by_target ON audit_events (target, created_at)",
+        ],
+        down: &["DROP TABLE IF EXISTS audit_events"],
+    }],
+};
+
+const EVENT_COLUMNS: &str = "id, action, actor, target, ip, user_agent, detail, created_at";
+
+pub struct AuditLog {
+    db_manager: Arc<Manager>,
+}
+
+impl AuditLog {
+    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
+        migrations::apply(&db_manager.pool, &SCHEMA).await?;
+        info!("Audit schema initialized.");
+        Ok(AuditLog { db_manager })
+    }
+
+    /// Appends an event. The operation it records has already happened, so a failure to
+    /// record it is logged rather than returned.
+    pub async fn record(&self, origin: &RequestOrigin, record: AuditRecord) {
+        if let Err(e) = self.append(origin, &record).await {
+            warn!("Failed to record audit event {} by {:?} on {:?}: {:#}", record.action.as_str(), record.actor, record.target, e);
+        }
+    }
+
+    #[instrument(skip_all, fields(action = record.action.as_str()))]
+    async fn append(&self, origin: &RequestOrigin, record: &AuditRecord) -> Result<()> {
+        sqlx::query(
+                "INSERT INTO audit_events (id, action, actor, target, ip, user_agent, detail, created_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"
+            )
+            .bind(Uuid::new_v4())
+            .bind(record.action.as_str())
+            .bind(record.actor)
+            .bind(&record.target)
+            .bind(origin.ip.map(|ip| ip.to_string()))
+            .bind(&origin.user_agent)
+            .bind(&record.detail)
+            .bind(Utc::now())
+            .execute(&*self.db_manager.pool)
+            .await
+            .context("Failed to record audit event")?;
+        Ok(())
+    }
+
+    /// Up to `limit` events matching `filter`, newest first.
+    #[instrument(skip_all)]
+    pub async fn events(&self, filter: &AuditFilter, limit: i64) -> Result<Vec<AuditEvent>> {
+        let rows: Vec<AuditEventRow> = sqlx::query_as(&format!(
+                "SELECT {EVENT_COLUMNS} FROM audit_events
+                 WHERE ($1::TEXT IS NULL OR action = $1)
+                   AND ($2::UUID IS NULL OR actor = $2)
+                   AND ($3::TEXT IS NULL OR target = $3)
+                   AND ($4::TEXT IS NULL OR ip = $4)
+                   AND ($5::TIMESTAMPTZ IS NULL OR created_at >= $5)
+                   AND ($6::TIMESTAMPTZ IS NULL OR created_at < $6)
+                   AND ($7::UUID IS NULL OR (created_at, id) < (SELECT created_at, id FROM audit_events WHERE id = $7))
+                 ORDER BY created_at DESC, id DESC LIMIT $8"
+            ))
+            .bind(filter.action.map(|action| action.as_str()))
+            .bind(filter.actor)
+            .bind(&filter.target)
+            .bind(&filter.ip)
+            .bind(filter.since)
+            .bind(filter.until)
+            .bind(filter.before)
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to list audit events")?;
+        rows.into_iter().map(AuditEvent::try_from).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[test]
+    fn test_actions_round_trip() {
+        for action in [AuditAction::Login, AuditAction::LoginFailed, AuditAction::ApiKeyCreated, AuditAction::DrainStarted] {
+            assert_eq!(action.as_str().parse::<AuditAction>().unwrap(), action);
+            assert_eq!(serde_json::to_value(action).unwrap(), json!(action.as_str()));
+        }
+        assert!("logout".parse::<AuditAction>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_events_filter_and_page_newest_first() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let audit = AuditLog::new(manager).await?;
+        let actor = Uuid::new_v4();
+        let origin = RequestOrigin { ip: Some("192.0.2.7".parse()?), user_agent: Some("test-agent".to_string()) };
+        audit.record(&origin, AuditRecord::new(AuditAction::Login).with_actor(actor)).await;
+        audit.record(&origin, AuditRecord::new(AuditAction::ApiKeyCreated).with_actor(actor).with_target("key")).await;
+        audit.record(&origin, AuditRecord::new(AuditAction::Login).with_actor(actor).with_detail(json!({ "method": "password" }))).await;
+
+        let by_actor = AuditFilter { actor: Some(actor), ..AuditFilter::default() };
+        let events = audit.events(&by_actor, 10).await?;
+        let actions: Vec<AuditAction> = events.iter().map(|event| event.action).collect();
+        assert_eq!(actions, [AuditAction::Login, AuditAction::ApiKeyCreated, AuditAction::Login]);
+        assert_eq!(events[0].detail, Some(json!({ "method": "password" })));
+        assert_eq!((events[1].ip.as_deref(), events[1].user_agent.as_deref()), (Some("192.0.2.7"), Some("test-agent")));
+
+        let logins = AuditFilter { action: Some(AuditAction::Login), ..by_actor.clone() };
+        assert_eq!(audit.events(&logins, 10).await?.len(), 2);
+        let next_page = AuditFilter { before: Some(events[0].id), ..by_actor.clone() };
+        assert_eq!(audit.events(&next_page, 1).await?[0].id, events[1].id);
+        let later = AuditFilter { since: Some(Utc::now()), ..by_actor };
+        assert!(audit.events(&later, 10).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Signing in with an account at an identity provider such as Google or GitHub, with the
+//! OAuth 2.0 authorization code flow and PKCE.
+//!
+//! `GET /api/auth/:provider/login` sends the browser to the provider, leaving the request's
+//! state and PKCE verifier in a cookie only the provider's callback sees, so a callback
+//! can't be completed in another browser. The callback trades its code for an access token
+//! and asks the provider who signed in; `UserService::login_with_identity` decides which
+//! account that is.
+//!
+//! `COLLABORATE_LOGIN_PROVIDERS` lists the providers by name, and each needs
+//! `COLLABORATE_LOGIN_<NAME>_CLIENT_ID` and `COLLABORATE_LOGIN_<NAME>_CLIENT_SECRET`.
+//! `google` and `github` come with their endpoints; any other provider is taken to speak
+//! OpenID Connect and needs `_AUTHORIZE_URL`, `_TOKEN_URL` and `_USERINFO_URL` as well.
+//! The redirect URI to register with a provider is `<public URL>/api/auth/<name>/callback`.
+
+use crate::user_service::ExternalIdentity;
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::HeaderValue;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use chrono::TimeDelta;
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::time::Duration;
+use url::Url;
+
+const PROVIDERS_ENV: &str = "COLLABORATE_LOGIN_PROVIDERS";
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Holds a login's state and PKCE verifier while the browser is away at the provider.
+pub const PENDING_LOGIN_COOKIE: &str = "collaborate_login";
+/// How long someone has to sign in at the provider.
+pub const PENDING_LOGIN_LIFETIME: TimeDelta = TimeDelta::minutes(10);
+
+/// How a provider tells who signed in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfileApi {
+    /// The OpenID Connect userinfo endpoint.
+    OpenIdConnect,
+    /// GitHub's user API, with verified addresses listed separately.
+    GitHub,
+}
+
+/// An identity provider people may sign in with.
+#[derive(Clone, Debug)]
+pub struct LoginProvider {
+    /// Lowercase letters, digits and dashes; appears in the provider's routes.
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub authorize_url: Url,
+    pub token_url: Url,
+    pub userinfo_url: Url,
+    pub scopes: String,
+    pub profile: ProfileApi,
+}
+
+impl LoginProvider {
+    pub fn google(client_id: String, client_secret: String) -> Self {
+        LoginProvider {
+            name: "google".to_string(),
+            client_id,
+            client_secret,
+            authorize_url: Url::parse("https://accounts.google.com/o/oauth2/v2/auth").expect("valid URL"),
+            token_url: Url::parse("https://oauth2.googleapis.com/token").expect("valid URL"),
+            userinfo_url: Url::parse("https://openidconnect.googleapis.com/v1/userinfo").expect("valid URL"),
+            scopes: "openid email profile".to_string(),
+            profile: ProfileApi::OpenIdConnect,
+        }
+    }
+
+    pub fn github(client_id: String, client_secret: String) -> Self {
+        LoginProvider {
+            name: "github".to_string(),
+            client_id,
+            client_secret,
+            authorize_url: Url::parse("https://github.com/login/oauth/authorize").expect("valid URL"),
+            token_url: Url::parse("https://github.com/login/oauth/access_token").expect("valid URL"),
+            userinfo_url: Url::parse("https://api.github.com/user").expect("valid URL"),
+            scopes: "read:user user:email".to_string(),
+            profile: ProfileApi::GitHub,
+        }
+    }
+
+    /// Reads the provider called `name` from `COLLABORATE_LOGIN_<NAME>_*`.
+    pub fn from_env(name: &str) -> Result<Self> {
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-') {
+            bail!("Login provider names may only use lowercase letters, digits and dashes: '{}'", name);
+        }
+        let prefix = format!("COLLABORATE_LOGIN_{}_", name.to_uppercase().replace('-', "_"));
+        let var = |key: &str| {
+            let key = format!("{}{}", prefix, key);
+            env::var(&key).map_err(|_| anyhow!("{} must be set to sign in with {}", key, name))
+        };
+        let url = |key: &str| var(key).and_then(|url| Url::parse(&url).context(format!("{}{} is not a URL", prefix, key)));
+        let (client_id, client_secret) = (var("CLIENT_ID")?, var("CLIENT_SECRET")?);
+        Ok(match name {
+            "google" => LoginProvider::google(client_id, client_secret),
+            "github" => LoginProvider::github(client_id, client_secret),
+            _ => LoginProvider {
+                name: name.to_string(),
+                client_id,
+                client_secret,
+                authorize_url: url("AUTHORIZE_URL")?,
+                token_url: url("TOKEN_URL")?,
+                userinfo_url: url("USERINFO_URL")?,
+                scopes: var("SCOPES").unwrap_or_else(|_| "openid email profile".to_string()),
+                profile: ProfileApi::OpenIdConnect,
+            },
+        })
+    }
+}
+
+/// The providers listed in `COLLABORATE_LOGIN_PROVIDERS`, if any.
+pub fn providers_from_env() -> Result<Vec<LoginProvider>> {
+    let Ok(names) = env::var(PROVIDERS_ENV) else {
+        return Ok(Vec::new());
+    };
+    names.split(',').map(str::trim).filter(|name| !name.is_empty()).map(LoginProvider::from_env).collect()
+}
+
+/// What the browser keeps while it is away at the provider.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingLogin {
+    /// Sent to the provider and expected back, tying the callback to this browser.
+    pub state: String,
+    /// The PKCE secret whose hash was sent to the provider.
+    pub verifier: String,
+}
+
+impl PendingLogin {
+    fn new() -> Self {
+        PendingLogin { state: random_secret(), verifier: random_secret() }
+    }
+
+    /// Reads the value of the pending login cookie.
+    pub fn parse(cookie: &str) -> Option<Self> {
+        let (state, verifier) = cookie.split_once('.')?;
+        let well_formed = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        (well_formed(state) && well_formed(verifier))
+            .then(|| PendingLogin { state: state.to_string(), verifier: verifier.to_string() })
+    }
+
+    /// A `Set-Cookie` value keeping this login for `max_age`, or removing it when `max_age`
+    /// is not positive. Only sent to `provider`'s routes.
+    pub fn cookie(&self, provider: &str, max_age: TimeDelta) -> Option<HeaderValue> {
+        let cookie = format!(
+            "{}={}.{}; Max-Age={}; Path=/api/auth/{}; Secure; HttpOnly; SameSite=Lax",
+            PENDING_LOGIN_COOKIE,
+            self.state,
+            self.verifier,
+            max_age.num_seconds().max(0),
+            provider,
+        );
+        HeaderValue::from_str(&cookie).ok()
+    }
+}
+
+fn random_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand::rng().fill_bytes(&mut secret);
+    BASE64_URL.encode(secret)
+}
+
+fn code_challenge(verifier: &str) -> String {
+    BASE64_URL.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    // GitHub reports failures with a successful status.
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OpenIdUserInfo {
+    sub: String,
+    email: Option<String>,
+    // Some providers send a string rather than a boolean.
+    #[serde(default)]
+    email_verified: Option<serde_json::Value>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitHubUser {
+    id: u64,
+    login: String,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitHubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+/// Signs people in with the configured providers.
+pub struct FederatedLogin {
+    providers: Vec<LoginProvider>,
+    public_url: String,
+    client: reqwest::Client,
+}
+
+impl FederatedLogin {
+    /// Providers redirect back to the server at `public_url`.
+    pub fn new(providers: Vec<LoginProvider>, public_url: String) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(PROVIDER_TIMEOUT)
+            // GitHub's API refuses requests without one.
+            .user_agent("collaborate-core")
+            .build()
+            .context("Failed to build login provider HTTP client")?;
+        Ok(FederatedLogin { providers, public_url: public_url.trim_end_matches('/').to_string(), client })
+    }
+
+    pub fn provider(&self, name: &str) -> Option<&LoginProvider> {
+        self.providers.iter().find(|provider| provider.name == name)
+    }
+
+    fn redirect_uri(&self, provider: &LoginProvider) -> String {
+        format!("{}/api/auth/{}/callback", self.public_url, provider.name)
+    }
+
+    /// Where to send the browser to sign in with `provider`, and what it has to keep until
+    /// it comes back.
+    pub fn start(&self, provider: &LoginProvider) -> (Url, PendingLogin) {
+        let pending = PendingLogin::new();
+        let mut url = provider.authorize_url.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &provider.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri(provider))
+            .append_pair("scope", &provider.scopes)
+            .append_pair("state", &pending.state)
+            .append_pair("code_challenge", &code_challenge(&pending.verifier))
+            .append_pair("code_challenge_method", "S256");
+        (url, pending)
+    }
+
+    /// Trades the code the provider redirected back with for an access token, and asks the
+    /// provider whose account it is.
+    pub async fn finish(&self, provider: &LoginProvider, pending: &PendingLogin, code: &str) -> Result<ExternalIdentity> {
+        let redirect_uri = self.redirect_uri(provider);
+        let response: TokenResponse = self.client
+            .post(provider.token_url.clone())
+            .header(reqwest::header::ACCEPT, "application/json")
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &redirect_uri),
+                ("client_id", &provider.client_id),
+                ("client_secret", &provider.client_secret),
+                ("code_verifier", &pending.verifier),
+            ])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context(format!("Failed to redeem {} authorization code", provider.name))?
+            .json()
+            .await
+            .context(format!("Malformed {} token response", provider.name))?;
+        let access_token = match (response.access_token, response.error) {
+            (Some(token), None) => token,
+            (_, error) => bail!("{} refused the authorization code: {}", provider.name, error.unwrap_or_default()),
+        };
+        match provider.profile {
+            ProfileApi::OpenIdConnect => self.openid_identity(provider, &access_token).await,
+            ProfileApi::GitHub => self.github_identity(provider, &access_token).await,
+        }
+    }
+
+    async fn get<T: for<'de> Deserialize<'de>>(&self, provider: &LoginProvider, url: Url, access_token: &str) -> Result<T> {
+        self.client
+            .get(url)
+            .bearer_auth(access_token)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context(format!("Failed to look up {} account", provider.name))?
+            .json()
+            .await
+            .context(format!("Malformed {} account", provider.name))
+    }
+
+    async fn openid_identity(&self, provider: &LoginProvider, access_token: &str) -> Result<ExternalIdentity> {
+        let info: OpenIdUserInfo = self.get(provider, provider.userinfo_url.clone(), access_token).await?;
+        let email_verified = match info.email_verified {
+            Some(serde_json::Value::Bool(verified)) => verified,
+            Some(serde_json::Value::String(verified)) => verified == "true",
+            _ => false,
+        };
+        Ok(ExternalIdentity {
+            provider: provider.name.clone(),
+            subject: info.sub,
+            email: info.email,
+            email_verified,
+            display_name: info.name,
+        })
+    }
+
+    async fn github_identity(&self, provider: &LoginProvider, access_token: &str) -> Result<ExternalIdentity> {
+        let user: GitHubUser = self.get(provider, provider.userinfo_url.clone(), access_token).await?;
+        let emails_url = Url::parse(&format!("{}/emails", provider.userinfo_url)).context("Invalid GitHub emails URL")?;
+        let emails: Vec<GitHubEmail> = self.get(provider, emails_url, access_token).await?;
+        let email = emails.into_iter().find(|email| email.primary && email.verified).map(|email| email.email);
+        Ok(ExternalIdentity {
+            provider: provider.name.clone(),
+            subject: user.id.to_string(),
+            email_verified: email.is_some(),
+            email,
+            display_name: user.name.or(Some(user.login)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_redirect_carries_pkce_challenge_and_state() {
+        let provider = LoginProvider::github("client".to_string(), "secret".to_string());
+        let login = FederatedLogin::new(vec![provider], "https://collaborate.example/".to_string()).unwrap();
+        let provider = login.provider("github").unwrap();
+        let (url, pending) = login.start(provider);
+        let params: std::collections::HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["state"], pending.state);
+        assert_eq!(params["redirect_uri"], "https://collaborate.example/api/auth/github/callback");
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(params["code_challenge"], code_challenge(&pending.verifier));
+        assert!(crate::oauth::is_code_challenge(&params["code_challenge"]));
+        assert_ne!(login.start(provider).1, pending, "Every login gets its own secrets");
+
+        let cookie = pending.cookie("github", PENDING_LOGIN_LIFETIME).unwrap();
+        let cookie = cookie.to_str().unwrap();
+        assert!(cookie.ends_with("; Max-Age=600; Path=/api/auth/github; Secure; HttpOnly; SameSite=Lax"));
+        let value = cookie.split(';').next().unwrap().strip_prefix("collaborate_login=").unwrap();
+        assert_eq!(PendingLogin::parse(value), Some(pending));
+        assert_eq!(PendingLogin::parse("no-verifier"), None);
+        assert_eq!(PendingLogin::parse("state.ver ifier"), None);
+        assert!(LoginProvider::from_env("Not A Name").is_err());
+    }
+}
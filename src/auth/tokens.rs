@@ -0,0 +1,326 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Access tokens and session cookies for signed-in users.
+//!
+//! Logging in starts a session whose opaque secret is the refresh token; it is stored
+//! hashed and can be revoked (see `UserService`). Requests carry a short-lived JWT access
+//! token instead, which is checked without a database round trip, so revoking a session
+//! takes effect once its current access token expires.
+//!
+//! Browsers may log in for a cookie session instead. The cookie holds the session's secret
+//! and is checked against the database on every request, so revoking it takes effect at once.
+//! So are API keys (see `api_keys`), which scripts present in place of an access token.
+
+use crate::api_error::ApiError;
+use crate::api_keys::{ApiKeyGrant, ApiKeyService, API_KEY_PREFIX};
+use crate::rate_limit::RateLimitSubject;
+use crate::user_service::UserService;
+use anyhow::{anyhow, Context, Result};
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::header::{AUTHORIZATION, COOKIE, SET_COOKIE};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, TimeDelta, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::Arc;
+use tracing::{warn, Span};
+use uuid::Uuid;
+
+const JWT_SECRET_ENV: &str = "COLLABORATE_JWT_SECRET";
+const ACCESS_TOKEN_TTL_ENV: &str = "COLLABORATE_ACCESS_TOKEN_TTL_SECS";
+const MIN_SECRET_LENGTH: usize = 32;
+/// Holds the secret of a cookie session.
+pub const SESSION_COOKIE: &str = "collaborate_session";
+
+/// How access tokens are signed and how long they last.
+#[derive(Clone, Debug)]
+pub struct TokenConfig {
+    /// HMAC key shared by every node. A random one is generated when unset, which
+    /// only works for a single node and signs everyone out on restart.
+    pub secret: Option<Vec<u8>>,
+    pub access_lifetime: TimeDelta,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        TokenConfig { secret: None, access_lifetime: TimeDelta::minutes(15) }
+    }
+}
+
+impl TokenConfig {
+    /// Reads `COLLABORATE_JWT_SECRET` and `COLLABORATE_ACCESS_TOKEN_TTL_SECS`.
+    pub fn from_env() -> Result<Self> {
+        let mut config = TokenConfig::default();
+        if let Ok(secret) = env::var(JWT_SECRET_ENV) {
+            if secret.len() < MIN_SECRET_LENGTH {
+                return Err(anyhow!("{} must be at least {} bytes", JWT_SECRET_ENV, MIN_SECRET_LENGTH));
+            }
+            config.secret = Some(secret.into_bytes());
+        }
+        if let Ok(secs) = env::var(ACCESS_TOKEN_TTL_ENV) {
+            let secs = secs.parse().context(format!("{} must be a number of seconds", ACCESS_TOKEN_TTL_ENV))?;
+            config.access_lifetime = TimeDelta::seconds(secs);
+        }
+        Ok(config)
+    }
+}
+
+/// What an access token asserts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccessClaims {
+    /// The user ID.
+    pub sub: Uuid,
+    /// The session the token was issued for.
+    pub sid: Uuid,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+pub struct IssuedAccessToken {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Mints and checks HS256 access tokens.
+pub struct AccessTokens {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Validation,
+    lifetime: TimeDelta,
+}
+
+impl AccessTokens {
+    pub fn new(config: &TokenConfig) -> Self {
+        let secret = config.secret.clone().unwrap_or_else(|| {
+            warn!("{} is not set; access tokens will not survive a restart or work across nodes", JWT_SECRET_ENV);
+            let mut secret = vec![0u8; MIN_SECRET_LENGTH];
+            rand::rng().fill_bytes(&mut secret);
+            secret
+        });
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        AccessTokens {
+            encoding: EncodingKey::from_secret(&secret),
+            decoding: DecodingKey::from_secret(&secret),
+            validation,
+            lifetime: config.access_lifetime,
+        }
+    }
+
+    pub fn issue(&self, user_id: Uuid, session_id: Uuid) -> Result<IssuedAccessToken> {
+        let now = Utc::now();
+        let expires_at = now + self.lifetime;
+        let claims = AccessClaims { sub: user_id, sid: session_id, iat: now.timestamp(), exp: expires_at.timestamp() };
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+            .context(format!("Failed to sign access token for user ID {}", user_id))?;
+        Ok(IssuedAccessToken { token, expires_at })
+    }
+
+    /// The token's claims, if it was signed by this deployment and has not expired.
+    pub fn verify(&self, token: &str) -> Option<AccessClaims> {
+        jsonwebtoken::decode(token, &self.decoding, &self.validation).ok().map(|data| data.claims)
+    }
+}
+
+/// What a signed-in request presented to prove who it is from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Credential {
+    /// An access token or cookie for this session.
+    Session(Uuid),
+    /// An API key that may be used on any of the user's documents.
+    ApiKey { key_id: Uuid, read_only: bool },
+}
+
+/// The signed-in user making a request. Rejects requests without a valid access token,
+/// session cookie or API key. Keys limited to particular documents are only accepted where
+/// documents are used, so they are rejected here.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AuthenticatedUser {
+    pub user_id: Uuid,
+    pub credential: Credential,
+}
+
+impl AuthenticatedUser {
+    /// The session signed in to, unless the request used an API key.
+    pub fn session_id(&self) -> Option<Uuid> {
+        match self.credential {
+            Credential::Session(session_id) => Some(session_id),
+            Credential::ApiKey { .. } => None,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuthenticatedUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(user) = parts.extensions.get::<AuthenticatedUser>() {
+            return Ok(*user);
+        }
+        Err(match bearer_token(&parts.headers) {
+            Some(_) if parts.extensions.get::<ApiKeyGrant>().is_some() => {
+                ApiError::new(StatusCode::FORBIDDEN, "api-key-documents-only")
+            }
+            Some(_) => ApiError::new(StatusCode::UNAUTHORIZED, "invalid-token"),
+            None => ApiError::new(StatusCode::UNAUTHORIZED, "sign-in-required"),
+        })
+    }
+}
+
+/// What `authenticate` checks credentials against.
+pub struct Authenticator {
+    pub tokens: Arc<AccessTokens>,
+    pub users: Arc<UserService>,
+    pub api_keys: Arc<ApiKeyService>,
+}
+
+/// Middleware that checks a bearer access token or API key, or failing that a session
+/// cookie, and if valid records the caller as an [`AuthenticatedUser`] and counts their
+/// requests per user rather than per address. Read-only keys are refused for anything but
+/// reading, and keys limited to particular documents are left for handlers to check against
+/// the document. Other bearer credentials, such as bot tokens, pass through for handlers to
+/// check. A cookie whose session was extended is sent again with the response.
+pub async fn authenticate(State(auth): State<Arc<Authenticator>>, mut request: Request, next: Next) -> Response {
+    let mut user = bearer_token(request.headers())
+        .and_then(|token| auth.tokens.verify(token))
+        .map(|claims| AuthenticatedUser { user_id: claims.sub, credential: Credential::Session(claims.sid) });
+    let key = bearer_token(request.headers()).filter(|token| token.starts_with(API_KEY_PREFIX));
+    let presented_key = key.is_some();
+    if let Some(secret) = key {
+        match auth.api_keys.authenticate(secret).await {
+            Ok(Some(grant)) if grant.read_only && !request.method().is_safe() => {
+                return ApiError::new(StatusCode::FORBIDDEN, "api-key-read-only").into_response();
+            }
+            Ok(Some(grant)) if grant.documents.is_some() => {
+                Span::current().record("user_id", tracing::field::display(grant.user_id));
+                let subject = RateLimitSubject { key: format!("user:{}", grant.user_id), limit: None };
+                request.extensions_mut().insert(subject);
+                request.extensions_mut().insert(grant);
+            }
+            Ok(Some(grant)) => {
+                let credential = Credential::ApiKey { key_id: grant.key_id, read_only: grant.read_only };
+                user = Some(AuthenticatedUser { user_id: grant.user_id, credential });
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to check API key: {:#}", e),
+        }
+    }
+    let mut reissue = None;
+    // A request presenting a key is limited to what the key allows, whatever cookie it has.
+    if user.is_none() && !presented_key && let Some(secret) = cookie(request.headers(), SESSION_COOKIE) {
+        match auth.users.authenticate_cookie(secret).await {
+            Ok(Some(session)) => {
+                user = Some(AuthenticatedUser { user_id: session.user_id, credential: Credential::Session(session.session_id) });
+                reissue = session.extended_until.and_then(|until| session_cookie(secret, until - Utc::now()));
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to check session cookie: {:#}", e),
+        }
+    }
+    if let Some(user) = user {
+        Span::current().record("user_id", tracing::field::display(user.user_id));
+        request.extensions_mut().insert(user);
+        request.extensions_mut().insert(RateLimitSubject { key: format!("user:{}", user.user_id), limit: None });
+    }
+    let mut response = next.run(request).await;
+    if let Some(cookie) = reissue {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
+    response
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")
+}
+
+/// The value of the request's cookie called `name`, if any.
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find_map(|(cookie_name, value)| (cookie_name == name).then_some(value))
+}
+
+/// A `Set-Cookie` value that stores a session's secret for `max_age`, or removes it when
+/// `max_age` is not positive. Only sent over HTTPS and hidden from scripts.
+pub fn session_cookie(secret: &str, max_age: TimeDelta) -> Option<HeaderValue> {
+    let cookie = format!(
+        "{}={}; Max-Age={}; Path=/; Secure; HttpOnly; SameSite=Lax",
+        SESSION_COOKIE,
+        secret,
+        max_age.num_seconds().max(0),
+    );
+    HeaderValue::from_str(&cookie).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(secret: &[u8]) -> AccessTokens {
+        AccessTokens::new(&TokenConfig { secret: Some(secret.to_vec()), ..TokenConfig::default() })
+    }
+
+    #[test]
+    fn test_access_tokens() {
+        let tokens = tokens(&[7; MIN_SECRET_LENGTH]);
+        let (user_id, session_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let issued = tokens.issue(user_id, session_id).unwrap();
+        let claims = tokens.verify(&issued.token).unwrap();
+        assert_eq!((claims.sub, claims.sid), (user_id, session_id));
+        assert_eq!(claims.exp, issued.expires_at.timestamp());
+
+        assert!(self::tokens(&[8; MIN_SECRET_LENGTH]).verify(&issued.token).is_none(), "Signed with another key");
+        let (header, rest) = issued.token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged = AccessClaims { sub: Uuid::new_v4(), ..claims };
+        let forged_payload = base64::Engine::encode(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+            serde_json::to_vec(&forged).unwrap(),
+        );
+        assert!(tokens.verify(&format!("{}.{}.{}", header, forged_payload, signature)).is_none());
+        assert!(tokens.verify("cbt_not-a-jwt").is_none());
+
+        let expired = AccessTokens::new(&TokenConfig {
+            secret: Some(vec![7; MIN_SECRET_LENGTH]),
+            access_lifetime: TimeDelta::minutes(-5),
+        });
+        assert!(tokens.verify(&expired.issue(user_id, session_id).unwrap().token).is_none());
+    }
+
+    #[test]
+    fn test_session_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("theme=dark; collaborate_session=csc_abc"));
+        assert_eq!(cookie(&headers, SESSION_COOKIE), Some("csc_abc"));
+        assert_eq!(cookie(&headers, "collaborate_guest"), None);
+
+        let set = session_cookie("csc_abc", TimeDelta::days(14)).unwrap();
+        assert!(set.to_str().unwrap().starts_with("collaborate_session=csc_abc; Max-Age=1209600;"));
+        assert!(set.to_str().unwrap().contains("Secure; HttpOnly"));
+        let cleared = session_cookie("csc_abc", TimeDelta::minutes(-1)).unwrap();
+        assert!(cleared.to_str().unwrap().contains("Max-Age=0;"));
+    }
+}
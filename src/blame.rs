@@ -0,0 +1,178 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Edit attribution: which user wrote each byte of a document's content.
+//!
+//! Every update in a document's log records who made it, so walking the log forward from
+//! a snapshot tells who wrote what since. What the snapshot itself holds is attributed by a
+//! copy cached alongside it whenever a snapshot is taken, as compaction then discards the
+//! updates the attribution was worked out from. Content saved before authors were recorded,
+//! or replaced wholesale without one, is attributed to no one.
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use uuid::Uuid;
+
+/// Consecutive bytes last written by one author.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuthorRun {
+    pub len: usize,
+    /// `None` if the author is not known.
+    pub author: Option<Uuid>,
+    /// When the author last wrote within the run.
+    pub at: Option<DateTime<Utc>>,
+}
+
+/// Who wrote each byte of some content, as runs from its start.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Attribution {
+    runs: Vec<AuthorRun>,
+}
+
+/// Part of a blame answer: bytes `start..end` of the content were written by `author`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BlameSpan {
+    pub start: usize,
+    pub end: usize,
+    pub author: Option<Uuid>,
+    pub at: Option<DateTime<Utc>>,
+}
+
+impl Attribution {
+    /// `len` bytes of content whose author is not known.
+    pub fn unknown(len: usize) -> Self {
+        Self::written(len, None, None)
+    }
+
+    /// `len` bytes of content all written by `author`.
+    pub fn written(len: usize, author: Option<Uuid>, at: Option<DateTime<Utc>>) -> Self {
+        let mut attribution = Attribution::default();
+        attribution.push(AuthorRun { len, author, at });
+        attribution
+    }
+
+    /// Length of the content attributed.
+    pub fn len(&self) -> usize {
+        self.runs.iter().map(|run| run.len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    pub fn runs(&self) -> &[AuthorRun] {
+        &self.runs
+    }
+
+    /// Follows an edit by `author` that replaced `delete_len` bytes at `start` with
+    /// `insert_len` new ones. Fails if the edit reaches past the end of the content.
+    pub fn apply(&mut self, start: usize, delete_len: usize, insert_len: usize, author: Option<Uuid>, at: DateTime<Utc>) -> Result<()> {
+        let end = start + delete_len;
+        let len = self.len();
+        if end > len {
+            bail!("Edit replaces bytes {}..{} of content {} bytes long", start, end, len);
+        }
+        let inserted = AuthorRun { len: insert_len, author, at: Some(at) };
+        let mut edited = Attribution::default();
+        let mut pending = Some(inserted);
+        let mut offset = 0;
+        for run in std::mem::take(&mut self.runs) {
+            let (run_start, run_end) = (offset, offset + run.len);
+            offset = run_end;
+            if run_start < start {
+                edited.push(AuthorRun { len: run_end.min(start) - run_start, ..run.clone() });
+            }
+            if run_end >= start && let Some(inserted) = pending.take() {
+                edited.push(inserted);
+            }
+            if run_end > end {
+                edited.push(AuthorRun { len: run_end - run_start.max(end), ..run });
+            }
+        }
+        if let Some(inserted) = pending {
+            edited.push(inserted);
+        }
+        *self = edited;
+        Ok(())
+    }
+
+    /// Who wrote the bytes in `range`, cut to the content's length.
+    pub fn spans(&self, range: Range<usize>) -> Vec<BlameSpan> {
+        let mut spans = Vec::new();
+        let mut offset = 0;
+        for run in &self.runs {
+            let (start, end) = (offset.max(range.start), (offset + run.len).min(range.end));
+            offset += run.len;
+            if start < end {
+                spans.push(BlameSpan { start, end, author: run.author, at: run.at });
+            }
+        }
+        spans
+    }
+
+    /// Appends a run, merging it into the last one if the author is the same.
+    fn push(&mut self, run: AuthorRun) {
+        if run.len == 0 {
+            return;
+        }
+        match self.runs.last_mut() {
+            Some(last) if last.author == run.author => {
+                last.len += run.len;
+                last.at = last.at.max(run.at);
+            }
+            _ => self.runs.push(run),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    fn authors(attribution: &Attribution) -> Vec<(usize, Option<Uuid>)> {
+        attribution.runs().iter().map(|run| (run.len, run.author)).collect()
+    }
+
+    #[test]
+    fn test_edits_take_over_what_they_write() -> Result<()> {
+        let (alice, bob) = (Some(Uuid::new_v4()), Some(Uuid::new_v4()));
+        let now = Utc::now();
+        // "hello world" by Alice, then Bob replaces "world" with "there, friend".
+        let mut attribution = Attribution::unknown(0);
+        attribution.apply(0, 0, 11, alice, now)?;
+        attribution.apply(6, 5, 13, bob, now)?;
+        assert_eq!(authors(&attribution), vec![(6, alice), (13, bob)]);
+
+        // Alice inserts in the middle of Bob's text, and deletes across both.
+        attribution.apply(12, 0, 3, alice, now + TimeDelta::seconds(1))?;
+        assert_eq!(authors(&attribution), vec![(6, alice), (6, bob), (3, alice), (7, bob)]);
+        attribution.apply(4, 4, 0, alice, now)?;
+        assert_eq!(authors(&attribution), vec![(4, alice), (4, bob), (3, alice), (7, bob)]);
+        assert_eq!(attribution.len(), 18);
+
+        let spans = attribution.spans(2..9);
+        assert_eq!(
+            spans.iter().map(|span| (span.start, span.end, span.author)).collect::<Vec<_>>(),
+            vec![(2, 4, alice), (4, 8, bob), (8, 9, alice)],
+        );
+        assert_eq!(spans[2].at, Some(now + TimeDelta::seconds(1)));
+        assert!(attribution.spans(18..40).is_empty());
+        assert!(attribution.apply(10, 9, 0, bob, now).is_err());
+        Ok(())
+    }
+}
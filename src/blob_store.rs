@@ -0,0 +1,38 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::{Context, Result};
+use object_store::aws::AmazonS3Builder;
+use object_store::path::Path;
+use object_store::ObjectStore;
+use std::sync::Arc;
+use url::Url;
+
+/// Opens the object store named by a URL, returning it along with the path prefix the URL points at.
+///
+/// Supports `s3://bucket/prefix` (credentials and endpoint from the usual `AWS_*` variables, so any
+/// S3-compatible service works), `file:///path`, and `memory://`.
+pub fn open(url: &str) -> Result<(Arc<dyn ObjectStore>, Path)> {
+    let parsed = Url::parse(url).context(format!("Invalid object storage URL: {}", url))?;
+    if parsed.scheme() == "s3" {
+        let store = AmazonS3Builder::from_env()
+            .with_url(parsed.as_str())
+            .build()
+            .context(format!("Failed to configure S3 object storage for {}", url))?;
+        return Ok((Arc::new(store), Path::from(parsed.path())));
+    }
+    let (store, root) = object_store::parse_url(&parsed)
+        .context(format!("Unsupported object storage URL: {}", url))?;
+    Ok((Arc::from(store), root))
+}
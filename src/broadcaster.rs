@@ -0,0 +1,228 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Relaying room broadcasts between nodes over a shared message bus, so that a document's
+//! clients see each other's edits and presence whichever node they are connected to.
+//!
+//! Unlike the relays in `cluster`, every node hosts its own room for a document and saves
+//! the edits its own clients make; the bus carries only what rooms broadcast, and the latest
+//! edit wins as it does within a room. Participants on other nodes are relayed as they come
+//! and go, but rooms list only their own.
+
+use crate::document_room::{RoomMap, RoomNotice};
+use crate::presence::PresenceChange;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const REDIS_URL_ENV: &str = "COLLABORATE_REDIS_URL";
+/// Each document's events are published to `<prefix>:<document ID>`.
+const CHANNEL_PREFIX: &str = "collaborate:rooms";
+const PUBLISH_QUEUE_CAPACITY: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A message bus that carries payloads to every node, including the one that published them.
+#[async_trait]
+pub trait Broadcaster: Send + Sync {
+    async fn publish(&self, doc_id: Uuid, payload: Vec<u8>) -> Result<()>;
+    /// Everything published from now on, until the connection to the bus drops.
+    async fn subscribe(&self) -> Result<BoxStream<'static, Vec<u8>>>;
+}
+
+/// Redis pub/sub, configured with `COLLABORATE_REDIS_URL`.
+pub struct RedisBroadcaster {
+    client: redis::Client,
+    // Opened on first publish, and again after a failure.
+    connection: Mutex<Option<redis::aio::MultiplexedConnection>>,
+}
+
+impl RedisBroadcaster {
+    /// Reads `COLLABORATE_REDIS_URL`, e.g. `redis://redis.internal:6379`. Returns `None` when unset.
+    pub fn from_env() -> Result<Option<Self>> {
+        match env::var(REDIS_URL_ENV) {
+            Ok(url) => Ok(Some(Self::new(&url)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn new(url: &str) -> Result<Self> {
+        let client = redis::Client::open(url).context(format!("Invalid {}", REDIS_URL_ENV))?;
+        Ok(RedisBroadcaster { client, connection: Mutex::new(None) })
+    }
+
+    async fn connection(&self) -> Result<redis::aio::MultiplexedConnection> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = &*connection {
+            return Ok(connection.clone());
+        }
+        let opened = self.client.get_multiplexed_async_connection().await.context("Failed to connect to Redis")?;
+        *connection = Some(opened.clone());
+        Ok(opened)
+    }
+}
+
+#[async_trait]
+impl Broadcaster for RedisBroadcaster {
+    async fn publish(&self, doc_id: Uuid, payload: Vec<u8>) -> Result<()> {
+        let mut connection = self.connection().await?;
+        let published: redis::RedisResult<()> = connection.publish(format!("{}:{}", CHANNEL_PREFIX, doc_id), payload).await;
+        if let Err(e) = published {
+            *self.connection.lock().await = None;
+            return Err(e).context("Failed to publish to Redis");
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<BoxStream<'static, Vec<u8>>> {
+        let mut pubsub = self.client.get_async_pubsub().await.context("Failed to connect to Redis")?;
+        pubsub
+            .psubscribe(format!("{}:*", CHANNEL_PREFIX))
+            .await
+            .context("Failed to subscribe to Redis")?;
+        Ok(pubsub.into_on_message().map(|message| message.get_payload_bytes().to_vec()).boxed())
+    }
+}
+
+/// What rooms pass on to their counterparts on other nodes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RelayedEvent {
+    Update {
+        origin: u64,
+        #[serde(with = "content")]
+        data: Vec<u8>,
+    },
+    Presence(PresenceChange),
+    Notice(RoomNotice),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    /// The publishing node, which ignores its own events.
+    node: Uuid,
+    doc_id: Uuid,
+    event: RelayedEvent,
+}
+
+mod content {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        BASE64.decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// This node's link to the bus: publishes what local rooms broadcast and delivers what
+/// other nodes' rooms broadcast to the local rooms for the same documents.
+pub(crate) struct Relay {
+    node: Uuid,
+    outgoing: mpsc::Sender<Envelope>,
+    // Set while events are dropped because the bus can't keep up; logged once per episode.
+    dropping: AtomicBool,
+}
+
+impl Relay {
+    /// Starts relaying for the rooms in `rooms`. Must be called within a Tokio runtime.
+    pub(crate) fn start(broadcaster: Arc<dyn Broadcaster>, rooms: RoomMap) -> Arc<Self> {
+        let node = Uuid::new_v4();
+        let (outgoing, queue) = mpsc::channel(PUBLISH_QUEUE_CAPACITY);
+        tokio::spawn(publish(broadcaster.clone(), queue));
+        tokio::spawn(deliver(broadcaster, node, rooms));
+        info!("Relaying room broadcasts as node {}", node);
+        Arc::new(Relay { node, outgoing, dropping: AtomicBool::new(false) })
+    }
+
+    /// Queues an event for the other nodes. Never waits, so a slow bus can't stall a room.
+    pub(crate) fn publish(&self, doc_id: Uuid, event: RelayedEvent) {
+        match self.outgoing.try_send(Envelope { node: self.node, doc_id, event }) {
+            Ok(()) => {
+                if self.dropping.swap(false, Ordering::Relaxed) {
+                    info!("Relaying room broadcasts again");
+                }
+            }
+            Err(_) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    warn!("Relay queue is full; dropping room broadcasts for other nodes");
+                }
+            }
+        }
+    }
+}
+
+// Publishes in order, one at a time, so other nodes see a room's updates in sequence.
+async fn publish(broadcaster: Arc<dyn Broadcaster>, mut queue: mpsc::Receiver<Envelope>) {
+    while let Some(envelope) = queue.recv().await {
+        let payload = serde_json::to_vec(&envelope).expect("relayed events are always serializable");
+        if let Err(e) = broadcaster.publish(envelope.doc_id, payload).await {
+            warn!("Failed to relay event for document {}: {:#}", envelope.doc_id, e);
+        }
+    }
+}
+
+// Resubscribes whenever the connection drops; events published meanwhile are missed, and
+// clients catch up with the next update.
+async fn deliver(broadcaster: Arc<dyn Broadcaster>, node: Uuid, rooms: RoomMap) {
+    loop {
+        match broadcaster.subscribe().await {
+            Ok(mut payloads) => {
+                while let Some(payload) = payloads.next().await {
+                    match serde_json::from_slice::<Envelope>(&payload) {
+                        Ok(envelope) if envelope.node == node => {}
+                        Ok(envelope) => rooms.deliver(envelope.doc_id, envelope.event),
+                        Err(e) => warn!("Ignoring malformed relayed event: {}", e),
+                    }
+                }
+                warn!("Lost the subscription to relayed room broadcasts; resubscribing");
+            }
+            Err(e) => warn!("Failed to subscribe to relayed room broadcasts: {:#}", e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::close_code::CloseCode;
+
+    #[test]
+    fn test_relayed_events_round_trip() {
+        let events = [
+            RelayedEvent::Update { origin: u64::MAX, data: vec![0, 1, 2, 255] },
+            RelayedEvent::Presence(PresenceChange::Left { connection_id: 7 }),
+            RelayedEvent::Notice(RoomNotice::Closing(CloseCode::DocumentDeleted)),
+        ];
+        for event in events {
+            let envelope = Envelope { node: Uuid::new_v4(), doc_id: Uuid::new_v4(), event };
+            let decoded: Envelope = serde_json::from_slice(&serde_json::to_vec(&envelope).unwrap()).unwrap();
+            assert_eq!((decoded.node, decoded.doc_id, decoded.event), (envelope.node, envelope.doc_id, envelope.event));
+        }
+    }
+}
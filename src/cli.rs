@@ -0,0 +1,219 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! What the command-line subcommands share so they can be scripted: `--output json` prints
+//! the result as a single JSON object on stdout (logs stay on stderr), `--dry-run` reports
+//! what a command would change without changing it, and the process exits with an
+//! [`ExitStatus`] saying how the command ended.
+
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use serde_json::{json, Value};
+use std::fmt;
+use std::process::ExitCode;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Exit statuses are part of the command-line interface: scripts branch on them, so
+/// existing values must never be renumbered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitStatus {
+    Success = 0,
+    /// Anything not covered below, such as the database being unreachable.
+    Failed = 1,
+    /// The arguments were malformed.
+    Usage = 2,
+    /// The document, user or snapshot the command was asked about does not exist.
+    NotFound = 3,
+    /// The command ran to completion but found problems, such as corrupt documents.
+    ProblemsFound = 4,
+}
+
+impl ExitStatus {
+    /// The status as reported in JSON output.
+    pub fn name(self) -> &'static str {
+        match self {
+            ExitStatus::Success => "success",
+            ExitStatus::Failed => "failed",
+            ExitStatus::Usage => "usage",
+            ExitStatus::NotFound => "not_found",
+            ExitStatus::ProblemsFound => "problems_found",
+        }
+    }
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+/// An error that ends a command with a particular status; any other error exits with
+/// [`ExitStatus::Failed`].
+#[derive(Debug)]
+pub struct CommandError {
+    pub status: ExitStatus,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn usage(message: impl Into<String>) -> Self {
+        CommandError { status: ExitStatus::Usage, message: message.into() }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        CommandError { status: ExitStatus::NotFound, message: message.into() }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// How a command that ran to completion ended: its status, the fields of its JSON result
+/// and, unless it succeeded, what went wrong.
+pub struct Outcome {
+    pub status: ExitStatus,
+    pub result: Value,
+    pub problem: Option<String>,
+}
+
+impl Outcome {
+    pub fn success(result: Value) -> Self {
+        Outcome { status: ExitStatus::Success, result, problem: None }
+    }
+
+    pub fn problems_found(result: Value, problem: String) -> Self {
+        Outcome { status: ExitStatus::ProblemsFound, result, problem: Some(problem) }
+    }
+}
+
+/// The options every subcommand accepts, wherever they appear among its arguments.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Args)]
+pub struct Options {
+    /// How to report the result.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub output: OutputFormat,
+    /// Report what the command would change without changing it.
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+}
+
+impl Options {
+    /// Prints a line of human-readable output. JSON output carries the same information
+    /// in the command's result instead.
+    pub fn say(&self, line: impl fmt::Display) {
+        if self.output == OutputFormat::Text {
+            println!("{}", line);
+        }
+    }
+
+    /// Reports how a command ended and returns the code to exit with.
+    pub fn finish(&self, outcome: Result<Outcome>) -> ExitCode {
+        let (status, result, problem) = match outcome {
+            Ok(Outcome { status, result, problem }) => (status, result, problem),
+            Err(e) => {
+                let status = e.downcast_ref::<CommandError>().map_or(ExitStatus::Failed, |e| e.status);
+                (status, json!({}), Some(format!("{:#}", e)))
+            }
+        };
+        match self.output {
+            OutputFormat::Text => {
+                if let Some(problem) = problem {
+                    eprintln!("Error: {}", problem);
+                }
+            }
+            OutputFormat::Json => println!("{}", self.json_report(status, result, problem)),
+        }
+        status.into()
+    }
+
+    fn json_report(&self, status: ExitStatus, result: Value, problem: Option<String>) -> Value {
+        let mut report = match result {
+            Value::Object(fields) => fields,
+            other => serde_json::Map::from_iter([("result".to_string(), other)]),
+        };
+        report.insert("status".to_string(), json!(status.name()));
+        report.insert("exit_code".to_string(), json!(status as u8));
+        report.insert("dry_run".to_string(), json!(self.dry_run));
+        if let Some(problem) = problem {
+            report.insert("error".to_string(), json!(problem));
+        }
+        Value::Object(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use clap::{Parser, Subcommand};
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        options: Options,
+        #[command(subcommand)]
+        command: Command,
+    }
+
+    #[derive(Debug, PartialEq, Subcommand)]
+    enum Command {
+        Verify {
+            #[arg(long)]
+            restore: bool,
+        },
+        Reindex,
+    }
+
+    #[test]
+    fn test_common_options_are_accepted_anywhere() -> Result<()> {
+        let cli = Cli::try_parse_from(["collaborate", "verify", "--output", "json", "--restore", "--dry-run"])?;
+        assert_eq!(cli.options, Options { output: OutputFormat::Json, dry_run: true });
+        assert_eq!(cli.command, Command::Verify { restore: true });
+
+        let cli = Cli::try_parse_from(["collaborate", "--output=text", "reindex"])?;
+        assert_eq!(cli.options, Options { output: OutputFormat::Text, dry_run: false });
+        assert_eq!(cli.command, Command::Reindex);
+
+        for malformed in [&["collaborate", "verify", "--output", "yaml"][..], &["collaborate", "verify", "--output"]] {
+            let e = Cli::try_parse_from(malformed).err().unwrap();
+            assert_eq!(e.exit_code(), ExitStatus::Usage as i32);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_reports_carry_status_and_errors() {
+        let options = Options { output: OutputFormat::Json, dry_run: true };
+        let report = options.json_report(ExitStatus::Success, json!({ "indexed": 3 }), None);
+        assert_eq!(report, json!({ "indexed": 3, "status": "success", "exit_code": 0, "dry_run": true }));
+
+        let e = anyhow::Error::new(CommandError::not_found("No account with ID 1"));
+        assert_eq!(e.downcast_ref::<CommandError>().map(|e| e.status), Some(ExitStatus::NotFound));
+        let report = options.json_report(ExitStatus::NotFound, json!({}), Some(format!("{:#}", e)));
+        assert_eq!(report["exit_code"], 3);
+        assert_eq!(report["error"], "No account with ID 1");
+        assert!(anyhow!("connection refused").downcast_ref::<CommandError>().is_none());
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+//! Why the server closed a document WebSocket. Each reason has its own close code in the
+//! range reserved for applications, and the server sends a `closing` frame with the same
+//! code, a localised message and whether to reconnect just before closing.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseCode {
+    /// The client sent a frame the protocol doesn't define.
+    ProtocolError,
+    /// The connection's session or token was revoked or expired.
+    Unauthorized,
+    /// The connection lost its access to the document.
+    Kicked,
+    DocumentDeleted,
+    /// The document's organization revoked its encryption key.
+    DocumentUnreadable,
+    /// The client kept sending more than its message limits allow.
+    MessageLimit,
+    /// This node is shutting down; other nodes will take over.
+    ShuttingDown,
+}
+
+impl CloseCode {
+    /// The WebSocket close code, modelled on the matching HTTP status.
+    pub fn code(self) -> u16 {
+        match self {
+            CloseCode::ProtocolError => 4400,
+            CloseCode::Unauthorized => 4401,
+            CloseCode::Kicked => 4403,
+            CloseCode::DocumentDeleted => 4410,
+            CloseCode::DocumentUnreadable => 4451,
+            CloseCode::MessageLimit => 4429,
+            CloseCode::ShuttingDown => 4503,
+        }
+    }
+
+    /// The i18n message key shown to users.
+    pub fn message_key(self) -> &'static str {
+        match self {
+            CloseCode::ProtocolError => "invalid-client-frame",
+            CloseCode::Unauthorized => "invalid-token",
+            CloseCode::Kicked => "document-forbidden",
+            CloseCode::DocumentDeleted => "document-deleted",
+            CloseCode::DocumentUnreadable => "document-unreadable",
+            CloseCode::MessageLimit => "message-limit-exceeded",
+            CloseCode::ShuttingDown => "server-shutting-down",
+        }
+    }
+
+    /// Whether reconnecting straight away is likely to succeed. Clients closed as
+    /// unauthorized should refresh their token before trying again.
+    pub fn reconnect(self) -> bool {
+        matches!(self, CloseCode::ShuttingDown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_codes_are_application_codes() {
+        let all = [
+            CloseCode::ProtocolError,
+            CloseCode::Unauthorized,
+            CloseCode::Kicked,
+            CloseCode::DocumentDeleted,
+            CloseCode::DocumentUnreadable,
+            CloseCode::MessageLimit,
+            CloseCode::ShuttingDown,
+        ];
+        for code in all {
+            assert!((4000..5000).contains(&code.code()), "{:?}", code);
+        }
+        let mut codes: Vec<u16> = all.iter().map(|code| code.code()).collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), all.len());
+    }
+}
@@ -0,0 +1,835 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Node-to-node relaying of document rooms.
+//!
+//! Every document is owned by exactly one node, chosen by rendezvous hashing over the
+//! configured peers. A node that receives a WebSocket for a document it doesn't own opens a
+//! relay connection to the owner: updates and snapshot requests are forwarded over it, and the
+//! owner's room broadcasts are streamed back and re-broadcast locally. This removes the need
+//! for sticky routing at the load balancer.
+
+use crate::close_code::CloseCode;
+use crate::content_analysis::AnnotationSet;
+use crate::document_room::{RoomCommand, RoomEvent, RoomHandle, RoomMap, RoomNotice, RoomRegistry, SaveStatus, SenderLimits, UpdateRejection};
+use crate::presence::{Participant, PresenceChange, PresenceUpdate};
+use crate::protected_ranges::{ProtectedRange, ProtectionChange};
+use crate::user_service::DocumentRole;
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::stream::SplitStream;
+use futures::{SinkExt, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const NODE_ID_ENV: &str = "COLLABORATE_NODE_ID";
+const CLUSTER_LISTEN_ENV: &str = "COLLABORATE_CLUSTER_LISTEN";
+const CLUSTER_PEERS_ENV: &str = "COLLABORATE_CLUSTER_PEERS";
+const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long documents are routed around a node after it hands its rooms over, long enough
+/// for it to finish shutting down and a replacement to start.
+pub const RELEASE_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterPeer {
+    pub node_id: String,
+    pub addr: SocketAddr,
+}
+
+/// This node's identity and the full set of nodes sharing document rooms.
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    pub node_id: String,
+    pub listen_addr: SocketAddr,
+    /// Every node in the cluster, including this one.
+    pub peers: Vec<ClusterPeer>,
+}
+
+impl ClusterConfig {
+    /// Reads `COLLABORATE_NODE_ID`, `COLLABORATE_CLUSTER_LISTEN` and `COLLABORATE_CLUSTER_PEERS`
+    /// (`node-a=10.0.0.1:4000,node-b=10.0.0.2:4000`). Returns `None` when running single-node.
+    pub fn from_env() -> Result<Option<Self>> {
+        let (Ok(node_id), Ok(peers)) = (env::var(NODE_ID_ENV), env::var(CLUSTER_PEERS_ENV)) else {
+            return Ok(None);
+        };
+        let peers = Self::parse_peers(&peers)?;
+        let listen_addr = match env::var(CLUSTER_LISTEN_ENV) {
+            Ok(addr) => addr.parse().context(format!("Invalid {}: {}", CLUSTER_LISTEN_ENV, addr))?,
+            Err(_) => peers
+                .iter()
+                .find(|peer| peer.node_id == node_id)
+                .map(|peer| peer.addr)
+                .ok_or_else(|| anyhow!("{} must include this node ({})", CLUSTER_PEERS_ENV, node_id))?,
+        };
+        if !peers.iter().any(|peer| peer.node_id == node_id) {
+            bail!("{} must include this node ({})", CLUSTER_PEERS_ENV, node_id);
+        }
+        Ok(Some(ClusterConfig { node_id, listen_addr, peers }))
+    }
+
+    pub fn parse_peers(spec: &str) -> Result<Vec<ClusterPeer>> {
+        spec.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (node_id, addr) = entry
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Cluster peer must be node-id=host:port: {}", entry))?;
+                Ok(ClusterPeer {
+                    node_id: node_id.trim().to_string(),
+                    addr: addr.trim().parse().context(format!("Invalid cluster peer address: {}", addr))?,
+                })
+            })
+            .collect()
+    }
+
+    /// The node that owns a document's room.
+    pub fn owner(&self, doc_id: Uuid) -> &ClusterPeer {
+        self.peers
+            .iter()
+            .max_by_key(|peer| rendezvous_score(&peer.node_id, doc_id))
+            .expect("cluster configuration has no peers")
+    }
+
+    /// The node that hosts a document's room right now: its owner, unless the owner has
+    /// released its rooms while shutting down, in which case the next node in line.
+    pub(crate) fn current_owner(&self, doc_id: Uuid, released: &Releases) -> &ClusterPeer {
+        let now = Instant::now();
+        self.peers
+            .iter()
+            .filter(|peer| self.is_local(peer) || !released.is_released(&peer.node_id, now))
+            .max_by_key(|peer| rendezvous_score(&peer.node_id, doc_id))
+            .unwrap_or_else(|| self.owner(doc_id))
+    }
+
+    pub fn is_local(&self, peer: &ClusterPeer) -> bool {
+        peer.node_id == self.node_id
+    }
+}
+
+/// Peers that handed their rooms over while shutting down, and until when to route around them.
+#[derive(Debug, Default)]
+pub(crate) struct Releases(Mutex<HashMap<String, Instant>>);
+
+impl Releases {
+    pub(crate) fn release(&self, node_id: &str, until: Instant) {
+        self.lock().insert(node_id.to_string(), until);
+    }
+
+    pub(crate) fn is_released(&self, node_id: &str, now: Instant) -> bool {
+        let mut released = self.lock();
+        match released.get(node_id) {
+            Some(until) if *until > now => true,
+            Some(_) => {
+                released.remove(node_id);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Instant>> {
+        self.0.lock().expect("cluster release lock poisoned")
+    }
+}
+
+// FNV-1a over the node id and document id. A hand-rolled hash keeps ownership stable across
+// Rust versions, which matters during rolling deploys.
+fn rendezvous_score(node_id: &str, doc_id: Uuid) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in node_id.bytes().chain(doc_id.as_bytes().iter().copied()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Messages exchanged on a relay connection. The first frame a relay sends is `Join`;
+/// everything after refers to that document.
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Join { doc_id: Uuid },
+    Update { request_id: u64, origin: u64, author: Option<Uuid>, role: DocumentRole, data: Vec<u8> },
+    Snapshot { request_id: u64 },
+    ChangeProtection { request_id: u64, change: ProtectionChange },
+    Broadcast { origin: u64, data: Vec<u8> },
+    SaveStatus(SaveStatus),
+    Annotations(AnnotationSet),
+    /// Presence updates get no reply.
+    Presence { origin: u64, update: PresenceUpdate },
+    PresenceChange(PresenceChange),
+    Participants { request_id: u64 },
+    Notify { request_id: u64, notice: RoomNotice },
+    Notice(RoomNotice),
+    Reply { request_id: u64, result: Result<Option<Vec<u8>>, String> },
+}
+
+const TAG_JOIN: u8 = 1;
+const TAG_UPDATE: u8 = 2;
+const TAG_SNAPSHOT: u8 = 3;
+const TAG_BROADCAST: u8 = 4;
+const TAG_SAVE_STATUS: u8 = 5;
+const TAG_REPLY_OK: u8 = 6;
+const TAG_REPLY_EMPTY: u8 = 7;
+const TAG_REPLY_ERR: u8 = 8;
+const TAG_ANNOTATIONS: u8 = 9;
+const TAG_CHANGE_PROTECTION: u8 = 10;
+const TAG_PRESENCE: u8 = 11;
+const TAG_PRESENCE_CHANGE: u8 = 12;
+const TAG_PARTICIPANTS: u8 = 13;
+const TAG_NOTIFY: u8 = 14;
+const TAG_NOTICE: u8 = 15;
+
+const ROLE_EDITOR: u8 = 0;
+const ROLE_OWNER: u8 = 1;
+
+impl Frame {
+    fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        match self {
+            Frame::Join { doc_id } => {
+                buf.put_u8(TAG_JOIN);
+                buf.put_slice(doc_id.as_bytes());
+            }
+            Frame::Update { request_id, origin, author, role, data } => {
+                buf.put_u8(TAG_UPDATE);
+                buf.put_u64(*request_id);
+                buf.put_u64(*origin);
+                // The nil UUID stands for no author.
+                buf.put_slice(author.unwrap_or_else(Uuid::nil).as_bytes());
+                buf.put_u8(match role {
+                    DocumentRole::Editor => ROLE_EDITOR,
+                    DocumentRole::Owner => ROLE_OWNER,
+                });
+                buf.put_slice(data);
+            }
+            Frame::Snapshot { request_id } => {
+                buf.put_u8(TAG_SNAPSHOT);
+                buf.put_u64(*request_id);
+            }
+            Frame::ChangeProtection { request_id, change } => {
+                buf.put_u8(TAG_CHANGE_PROTECTION);
+                buf.put_u64(*request_id);
+                buf.put_slice(&serde_json::to_vec(change).expect("protection changes are always serializable"));
+            }
+            Frame::Broadcast { origin, data } => {
+                buf.put_u8(TAG_BROADCAST);
+                buf.put_u64(*origin);
+                buf.put_slice(data);
+            }
+            Frame::SaveStatus(status) => {
+                buf.put_u8(TAG_SAVE_STATUS);
+                buf.put_slice(&serde_json::to_vec(status).expect("save status is always serializable"));
+            }
+            Frame::Annotations(annotations) => {
+                buf.put_u8(TAG_ANNOTATIONS);
+                buf.put_slice(&serde_json::to_vec(annotations).expect("annotations are always serializable"));
+            }
+            Frame::Presence { origin, update } => {
+                buf.put_u8(TAG_PRESENCE);
+                buf.put_u64(*origin);
+                buf.put_slice(&serde_json::to_vec(update).expect("presence updates are always serializable"));
+            }
+            Frame::PresenceChange(change) => {
+                buf.put_u8(TAG_PRESENCE_CHANGE);
+                buf.put_slice(&serde_json::to_vec(change).expect("presence changes are always serializable"));
+            }
+            Frame::Participants { request_id } => {
+                buf.put_u8(TAG_PARTICIPANTS);
+                buf.put_u64(*request_id);
+            }
+            Frame::Notify { request_id, notice } => {
+                buf.put_u8(TAG_NOTIFY);
+                buf.put_u64(*request_id);
+                buf.put_slice(&serde_json::to_vec(notice).expect("room notices are always serializable"));
+            }
+            Frame::Notice(notice) => {
+                buf.put_u8(TAG_NOTICE);
+                buf.put_slice(&serde_json::to_vec(notice).expect("room notices are always serializable"));
+            }
+            Frame::Reply { request_id, result } => {
+                match result {
+                    Ok(Some(data)) => {
+                        buf.put_u8(TAG_REPLY_OK);
+                        buf.put_u64(*request_id);
+                        buf.put_slice(data);
+                    }
+                    Ok(None) => {
+                        buf.put_u8(TAG_REPLY_EMPTY);
+                        buf.put_u64(*request_id);
+                    }
+                    Err(message) => {
+                        buf.put_u8(TAG_REPLY_ERR);
+                        buf.put_u64(*request_id);
+                        buf.put_slice(message.as_bytes());
+                    }
+                }
+            }
+        }
+        buf.freeze()
+    }
+
+    fn decode(mut buf: BytesMut) -> Result<Frame> {
+        fn take_u64(buf: &mut BytesMut) -> Result<u64> {
+            if buf.remaining() < 8 {
+                bail!("Truncated cluster frame");
+            }
+            Ok(buf.get_u64())
+        }
+
+        if buf.is_empty() {
+            bail!("Empty cluster frame");
+        }
+        let frame = match buf.get_u8() {
+            TAG_JOIN => Frame::Join {
+                doc_id: Uuid::from_slice(&buf).context("Malformed document ID in cluster frame")?,
+            },
+            TAG_UPDATE => Frame::Update {
+                request_id: take_u64(&mut buf)?,
+                origin: take_u64(&mut buf)?,
+                author: {
+                    if buf.remaining() < 16 {
+                        bail!("Truncated cluster frame");
+                    }
+                    Some(Uuid::from_slice(&buf.split_to(16)).expect("16 bytes make a UUID")).filter(|author| !author.is_nil())
+                },
+                role: match buf.has_remaining().then(|| buf.get_u8()) {
+                    Some(ROLE_EDITOR) => DocumentRole::Editor,
+                    Some(ROLE_OWNER) => DocumentRole::Owner,
+                    Some(role) => bail!("Unknown document role {} in cluster frame", role),
+                    None => bail!("Truncated cluster frame"),
+                },
+                data: buf.to_vec(),
+            },
+            TAG_SNAPSHOT => Frame::Snapshot { request_id: take_u64(&mut buf)? },
+            TAG_CHANGE_PROTECTION => Frame::ChangeProtection {
+                request_id: take_u64(&mut buf)?,
+                change: serde_json::from_slice(&buf).context("Malformed protection change in cluster frame")?,
+            },
+            TAG_BROADCAST => Frame::Broadcast { origin: take_u64(&mut buf)?, data: buf.to_vec() },
+            TAG_SAVE_STATUS => Frame::SaveStatus(
+                serde_json::from_slice(&buf).context("Malformed save status in cluster frame")?,
+            ),
+            TAG_ANNOTATIONS => Frame::Annotations(
+                serde_json::from_slice(&buf).context("Malformed annotations in cluster frame")?,
+            ),
+            TAG_PRESENCE => Frame::Presence {
+                origin: take_u64(&mut buf)?,
+                update: serde_json::from_slice(&buf).context("Malformed presence update in cluster frame")?,
+            },
+            TAG_PRESENCE_CHANGE => Frame::PresenceChange(
+                serde_json::from_slice(&buf).context("Malformed presence change in cluster frame")?,
+            ),
+            TAG_PARTICIPANTS => Frame::Participants { request_id: take_u64(&mut buf)? },
+            TAG_NOTIFY => Frame::Notify {
+                request_id: take_u64(&mut buf)?,
+                notice: serde_json::from_slice(&buf).context("Malformed room notice in cluster frame")?,
+            },
+            TAG_NOTICE => Frame::Notice(serde_json::from_slice(&buf).context("Malformed room notice in cluster frame")?),
+            TAG_REPLY_OK => Frame::Reply { request_id: take_u64(&mut buf)?, result: Ok(Some(buf.to_vec())) },
+            TAG_REPLY_EMPTY => Frame::Reply { request_id: take_u64(&mut buf)?, result: Ok(None) },
+            TAG_REPLY_ERR => Frame::Reply {
+                request_id: take_u64(&mut buf)?,
+                result: Err(String::from_utf8_lossy(&buf).into_owned()),
+            },
+            tag => bail!("Unknown cluster frame type {}", tag),
+        };
+        Ok(frame)
+    }
+}
+
+fn framed(stream: TcpStream) -> Framed<TcpStream, LengthDelimitedCodec> {
+    let codec = LengthDelimitedCodec::builder().max_frame_length(MAX_FRAME_LENGTH).new_codec();
+    Framed::new(stream, codec)
+}
+
+/// Accepts relay connections from other nodes and attaches them to local rooms.
+pub async fn serve(listen_addr: SocketAddr, rooms: Arc<RoomRegistry>) -> Result<()> {
+    let listener = TcpListener::bind(listen_addr)
+        .await
+        .context(format!("Failed to bind cluster listener on {}", listen_addr))?;
+    info!("Cluster relay listening on {}", listener.local_addr()?);
+    serve_listener(listener, rooms).await
+}
+
+async fn serve_listener(listener: TcpListener, rooms: Arc<RoomRegistry>) -> Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let rooms = rooms.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_relay(stream, rooms).await {
+                info!("Cluster relay from {} ended: {:#}", peer_addr, e);
+            }
+        });
+    }
+}
+
+async fn serve_relay(stream: TcpStream, rooms: Arc<RoomRegistry>) -> Result<()> {
+    let (mut sink, mut stream) = framed(stream).split();
+    let doc_id = match stream.next().await {
+        Some(frame) => match Frame::decode(frame?)? {
+            Frame::Join { doc_id } => doc_id,
+            other => bail!("Expected a join frame, got {:?}", other),
+        },
+        None => return Ok(()),
+    };
+    if rooms.is_draining() {
+        // Rooms are moving off this node; the relay should find the document's next host.
+        let closing = Frame::Notice(RoomNotice::Closing(CloseCode::ShuttingDown));
+        sink.send(closing.encode()).await?;
+        return Ok(());
+    }
+    let (room, mut events) = rooms.join_local(doc_id);
+
+    // Replies and broadcasts share the socket, so funnel both through one writer.
+    let (outgoing, mut outgoing_rx) = mpsc::channel::<Frame>(256);
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = outgoing_rx.recv().await {
+            if sink.send(frame.encode()).await.is_err() {
+                break;
+            }
+        }
+    });
+    let forward_outgoing = outgoing.clone();
+    let forwarder = tokio::spawn(async move {
+        loop {
+            let frame = match events.recv().await {
+                Ok(RoomEvent::Update { origin, data }) => Frame::Broadcast { origin, data: data.to_vec() },
+                Ok(RoomEvent::SaveStatus(status)) => Frame::SaveStatus(status),
+                Ok(RoomEvent::Annotations(annotations)) => Frame::Annotations((*annotations).clone()),
+                Ok(RoomEvent::Presence(change)) => Frame::PresenceChange(change),
+                Ok(RoomEvent::Notice(notice)) => Frame::Notice(notice),
+                // The relay can't resynchronise on its own; dropping it makes its clients reconnect.
+                Err(_) => break,
+            };
+            if forward_outgoing.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Connections on the relaying node that shared their presence, withdrawn if the relay drops.
+    let mut present = HashSet::new();
+    let result = relay_commands(&mut stream, &room, &outgoing, doc_id, &mut present).await;
+    for origin in present {
+        let _ = room.update_presence(origin, PresenceUpdate::Leave).await;
+    }
+    forwarder.abort();
+    writer.abort();
+    result
+}
+
+async fn relay_commands(
+    stream: &mut SplitStream<Framed<TcpStream, LengthDelimitedCodec>>,
+    room: &RoomHandle,
+    outgoing: &mpsc::Sender<Frame>,
+    doc_id: Uuid,
+    present: &mut HashSet<u64>,
+) -> Result<()> {
+    while let Some(frame) = stream.next().await {
+        let reply = match Frame::decode(frame?)? {
+            Frame::Update { request_id, origin, author, role, data } => Frame::Reply {
+                request_id,
+                result: room.submit_update(origin, author, role, data).await.map(|_| None).map_err(reply_error),
+            },
+            Frame::ChangeProtection { request_id, change } => Frame::Reply {
+                request_id,
+                result: room
+                    .change_protection(change)
+                    .await
+                    .map(|ranges| Some(serde_json::to_vec(&ranges).expect("protected ranges are always serializable")))
+                    .map_err(reply_error),
+            },
+            Frame::Snapshot { request_id } => Frame::Reply {
+                request_id,
+                result: room.snapshot().await.map_err(|e| e.to_string()),
+            },
+            Frame::Participants { request_id } => Frame::Reply {
+                request_id,
+                result: room
+                    .participants()
+                    .await
+                    .map(|participants| Some(serde_json::to_vec(&participants).expect("participants are always serializable")))
+                    .map_err(|e| e.to_string()),
+            },
+            Frame::Notify { request_id, notice } => Frame::Reply {
+                request_id,
+                result: room.notify(notice).await.map(|_| None).map_err(|e| e.to_string()),
+            },
+            Frame::Presence { origin, update } => {
+                match update {
+                    PresenceUpdate::Set { .. } => present.insert(origin),
+                    PresenceUpdate::Leave => present.remove(&origin),
+                    PresenceUpdate::Heartbeat => false,
+                };
+                room.update_presence(origin, update).await?;
+                continue;
+            }
+            other => bail!("Unexpected frame on relay for document {}: {:?}", doc_id, other),
+        };
+        if outgoing.send(reply).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Rejections are sent by key so the relay can hand clients a typed, localisable error.
+fn reply_error(e: anyhow::Error) -> String {
+    match e.downcast_ref::<UpdateRejection>() {
+        Some(rejection) => rejection.message_key().to_string(),
+        None => e.to_string(),
+    }
+}
+
+/// Stands in for a room owned by another node: forwards commands to the owner and
+/// re-broadcasts its events to local connections.
+pub(crate) struct RemoteRoom {
+    pub(crate) doc_id: Uuid,
+    pub(crate) owner: ClusterPeer,
+    // Shared with the registry, so that documents are routed elsewhere once the owner drains.
+    pub(crate) released: Arc<Releases>,
+    pub(crate) events: broadcast::Sender<RoomEvent>,
+    // Local connections are held to their message limits here rather than by the owner.
+    pub(crate) senders: SenderLimits,
+}
+
+// Commands forwarded to the owner, awaiting its reply.
+enum PendingReply {
+    Update(oneshot::Sender<Result<()>>),
+    Snapshot(oneshot::Sender<Result<Option<Vec<u8>>>>),
+    ChangeProtection(oneshot::Sender<Result<Vec<ProtectedRange>>>),
+    Participants(oneshot::Sender<Result<Vec<Participant>>>),
+    Notify(oneshot::Sender<Result<()>>),
+}
+
+impl RemoteRoom {
+    pub(crate) async fn run(mut self, mut commands: mpsc::Receiver<RoomCommand>, rooms: RoomMap) {
+        if let Err(e) = self.relay(&mut commands, &rooms).await {
+            warn!("Relay to {} for document {} failed: {:#}", self.owner.node_id, self.doc_id, e);
+        }
+        // Dropping `self.events` disconnects local clients so they reconnect and retry.
+        rooms.remove(self.doc_id, &self.events);
+    }
+
+    async fn relay(&mut self, commands: &mut mpsc::Receiver<RoomCommand>, rooms: &RoomMap) -> Result<()> {
+        let stream = TcpStream::connect(self.owner.addr)
+            .await
+            .context(format!("Failed to connect to cluster peer {} at {}", self.owner.node_id, self.owner.addr))?;
+        let mut framed = framed(stream);
+        framed.send(Frame::Join { doc_id: self.doc_id }.encode()).await?;
+
+        let mut pending: HashMap<u64, PendingReply> = HashMap::new();
+        let mut next_request_id = 0u64;
+        let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                Some(command) = commands.recv() => {
+                    next_request_id += 1;
+                    let request_id = next_request_id;
+                    let frame = match command {
+                        RoomCommand::Update { origin, author, role, class, data, reply } => {
+                            let limited = class.map(|class| self.senders.check(origin, class, data.len(), Instant::now()));
+                            if let Some(Err(rejection)) = limited {
+                                let _ = reply.send(Err(rejection.into()));
+                                continue;
+                            }
+                            pending.insert(request_id, PendingReply::Update(reply));
+                            Frame::Update { request_id, origin, author, role, data }
+                        }
+                        RoomCommand::Snapshot { reply } => {
+                            pending.insert(request_id, PendingReply::Snapshot(reply));
+                            Frame::Snapshot { request_id }
+                        }
+                        RoomCommand::ChangeProtection { change, reply } => {
+                            pending.insert(request_id, PendingReply::ChangeProtection(reply));
+                            Frame::ChangeProtection { request_id, change }
+                        }
+                        RoomCommand::Presence { origin, update } => Frame::Presence { origin, update },
+                        RoomCommand::Participants { reply } => {
+                            pending.insert(request_id, PendingReply::Participants(reply));
+                            Frame::Participants { request_id }
+                        }
+                        RoomCommand::Notify { notice, reply } => {
+                            pending.insert(request_id, PendingReply::Notify(reply));
+                            Frame::Notify { request_id, notice }
+                        }
+                        // Only rooms hosted on this node take part in broadcaster relaying.
+                        RoomCommand::Relayed(_) => continue,
+                        // The owner drains its own rooms; local connections just need warning.
+                        RoomCommand::Migrate => {
+                            let _ = self.events.send(RoomEvent::Notice(RoomNotice::Migrating));
+                            continue;
+                        }
+                    };
+                    framed.send(frame.encode()).await?;
+                },
+                frame = framed.next() => {
+                    let Some(frame) = frame else {
+                        bail!("Owner closed the relay connection");
+                    };
+                    match Frame::decode(frame?)? {
+                        Frame::Broadcast { origin, data } => {
+                            let _ = self.events.send(RoomEvent::Update { origin, data: Arc::new(data) });
+                        }
+                        Frame::SaveStatus(status) => {
+                            let _ = self.events.send(RoomEvent::SaveStatus(status));
+                        }
+                        Frame::Annotations(annotations) => {
+                            let _ = self.events.send(RoomEvent::Annotations(Arc::new(annotations)));
+                        }
+                        Frame::PresenceChange(change) => {
+                            let _ = self.events.send(RoomEvent::Presence(change));
+                        }
+                        // The owner has saved and handed the room over; whoever joins next
+                        // should reach the document's next host rather than this relay.
+                        Frame::Notice(notice @ RoomNotice::Closing(CloseCode::ShuttingDown)) => {
+                            self.released.release(&self.owner.node_id, Instant::now() + RELEASE_PERIOD);
+                            let _ = self.events.send(RoomEvent::Notice(notice));
+                            return Ok(());
+                        }
+                        Frame::Notice(notice) => {
+                            let _ = self.events.send(RoomEvent::Notice(notice));
+                        }
+                        Frame::Reply { request_id, result } => {
+                            let result = result.map_err(|message| match UpdateRejection::from_message_key(&message) {
+                                Some(rejection) => anyhow::Error::new(rejection),
+                                None => anyhow!(message),
+                            });
+                            match pending.remove(&request_id) {
+                                Some(PendingReply::Update(reply)) | Some(PendingReply::Notify(reply)) => {
+                                    let _ = reply.send(result.map(|_| ()));
+                                }
+                                Some(PendingReply::Snapshot(reply)) => {
+                                    let _ = reply.send(result);
+                                }
+                                Some(PendingReply::ChangeProtection(reply)) => {
+                                    let ranges = result.and_then(|body| {
+                                        serde_json::from_slice(&body.unwrap_or_default())
+                                            .context("Malformed protected ranges from owner")
+                                    });
+                                    let _ = reply.send(ranges);
+                                }
+                                Some(PendingReply::Participants(reply)) => {
+                                    let participants = result.and_then(|body| {
+                                        serde_json::from_slice(&body.unwrap_or_default())
+                                            .context("Malformed participants from owner")
+                                    });
+                                    let _ = reply.send(participants);
+                                }
+                                None => {}
+                            }
+                        }
+                        other => bail!("Unexpected frame from owner: {:?}", other),
+                    }
+                },
+                _ = idle_check.tick() => {
+                    if rooms.remove_if_idle(self.doc_id, &self.events) {
+                        return Ok(());
+                    }
+                    self.senders.prune(Instant::now());
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consistency::CausalityToken;
+    use crate::content_analysis::Annotation;
+    use crate::db::Manager as DbManager;
+    use crate::document_room::WriteBufferConfig;
+    use crate::document_service::DocumentService;
+    use crate::presence::Presence;
+    use chrono::DateTime;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[tokio::test]
+    async fn test_updates_relay_through_owner() -> Result<()> {
+        let manager = DbManager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?;
+        let doc_service = Arc::new(DocumentService::new(Arc::new(manager)).await?);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let owner_addr = listener.local_addr()?;
+        let owner_rooms = Arc::new(RoomRegistry::new(doc_service.clone(), WriteBufferConfig::default()));
+        tokio::spawn(serve_listener(listener, owner_rooms.clone()));
+
+        // The relay node's own address is never dialled; we pick a document the owner node owns.
+        let cluster = ClusterConfig {
+            node_id: "relay".to_string(),
+            listen_addr: "127.0.0.1:0".parse()?,
+            peers: vec![
+                ClusterPeer { node_id: "relay".to_string(), addr: "127.0.0.1:1".parse()? },
+                ClusterPeer { node_id: "owner".to_string(), addr: owner_addr },
+            ],
+        };
+        let metadata = loop {
+            let metadata = doc_service.create_document("Test Document for Relays").await?;
+            if cluster.owner(metadata.id).node_id == "owner" {
+                break metadata;
+            }
+        };
+        let relay_rooms = RoomRegistry::new(doc_service.clone(), WriteBufferConfig::default())
+            .with_cluster(Arc::new(cluster));
+
+        let (owner_room, mut owner_events) = owner_rooms.join_local(metadata.id);
+        let (relay_room, mut relay_events) = relay_rooms.join(metadata.id);
+
+        relay_room.submit_update(7, None, DocumentRole::Editor, vec![1, 2, 3]).await?;
+        match owner_events.recv().await? {
+            RoomEvent::Update { origin, data } => assert_eq!((origin, data.to_vec()), (7, vec![1, 2, 3])),
+            other => panic!("Expected an update on the owner, got {:?}", other),
+        }
+        assert_eq!(relay_room.snapshot().await?, Some(vec![1, 2, 3]));
+
+        owner_room.submit_update(8, None, DocumentRole::Editor, vec![4, 5]).await?;
+        loop {
+            // The relay also sees its own update echoed back before the owner's.
+            if let RoomEvent::Update { origin: 8, data } = relay_events.recv().await? {
+                assert_eq!(data.to_vec(), vec![4, 5]);
+                break;
+            }
+        }
+
+        let presence = Presence { display_name: "Ada".to_string(), color: None, cursor: Some(1), selection: None, region: None };
+        relay_room.update_presence(7, PresenceUpdate::Set { presence: presence.clone() }).await?;
+        assert_eq!(relay_room.participants().await?, vec![Participant { connection_id: 7, presence }]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_owner_hands_rooms_over_on_migrate() -> Result<()> {
+        let manager = DbManager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?;
+        let doc_service = Arc::new(DocumentService::new(Arc::new(manager)).await?);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let owner_addr = listener.local_addr()?;
+        let owner_rooms = Arc::new(RoomRegistry::new(doc_service.clone(), WriteBufferConfig::default()));
+        tokio::spawn(serve_listener(listener, owner_rooms.clone()));
+
+        let cluster = ClusterConfig {
+            node_id: "relay".to_string(),
+            listen_addr: "127.0.0.1:0".parse()?,
+            peers: vec![
+                ClusterPeer { node_id: "relay".to_string(), addr: "127.0.0.1:1".parse()? },
+                ClusterPeer { node_id: "owner".to_string(), addr: owner_addr },
+            ],
+        };
+        let metadata = loop {
+            let metadata = doc_service.create_document("Test Document for Migration").await?;
+            if cluster.owner(metadata.id).node_id == "owner" {
+                break metadata;
+            }
+        };
+        let relay_rooms = RoomRegistry::new(doc_service.clone(), WriteBufferConfig::default())
+            .with_cluster(Arc::new(cluster));
+        let (relay_room, mut relay_events) = relay_rooms.join(metadata.id);
+        relay_room.submit_update(7, None, DocumentRole::Editor, vec![1, 2, 3]).await?;
+
+        assert_eq!(owner_rooms.migrate(Duration::from_secs(5)).await, 0);
+        assert!(owner_rooms.is_draining());
+        let mut notices = Vec::new();
+        while notices.len() < 2 {
+            if let RoomEvent::Notice(notice) = relay_events.recv().await? {
+                notices.push(notice);
+            }
+        }
+        assert_eq!(notices, vec![RoomNotice::Migrating, RoomNotice::Closing(CloseCode::ShuttingDown)]);
+
+        // Once released, the relaying node hosts the document itself, from saved content.
+        assert!(relay_rooms.remote_owner(metadata.id).is_none());
+        let (room, _events) = relay_rooms.join(metadata.id);
+        assert_eq!(room.snapshot().await?, Some(vec![1, 2, 3]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_frames_round_trip() -> Result<()> {
+        let frames = vec![
+            Frame::Join { doc_id: Uuid::new_v4() },
+            Frame::Update { request_id: 1, origin: 2, author: Some(Uuid::new_v4()), role: DocumentRole::Owner, data: vec![1, 2, 3] },
+            Frame::Update { request_id: 1, origin: 2, author: None, role: DocumentRole::Editor, data: vec![] },
+            Frame::Snapshot { request_id: 3 },
+            Frame::ChangeProtection {
+                request_id: 4,
+                change: ProtectionChange::Protect { start: 0, end: 6, editors: vec![DocumentRole::Owner] },
+            },
+            Frame::Broadcast { origin: 4, data: vec![] },
+            Frame::SaveStatus(SaveStatus::Unsaved { pending_updates: 5 }),
+            Frame::Annotations(AnnotationSet {
+                version: CausalityToken::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()),
+                annotations: vec![Annotation {
+                    start: 0,
+                    end: 3,
+                    kind: "spelling".to_string(),
+                    message: "Possible typo".to_string(),
+                    suggestion: None,
+                }],
+            }),
+            Frame::Presence { origin: 9, update: PresenceUpdate::Heartbeat },
+            Frame::PresenceChange(PresenceChange::Left { connection_id: 9 }),
+            Frame::Participants { request_id: 5 },
+            Frame::Notify { request_id: 5, notice: RoomNotice::Closing(CloseCode::DocumentDeleted) },
+            Frame::Notice(RoomNotice::AccessChanged),
+            Frame::Notice(RoomNotice::Migrating),
+            Frame::Notice(RoomNotice::Expired),
+            Frame::Reply { request_id: 6, result: Ok(Some(vec![9])) },
+            Frame::Reply { request_id: 7, result: Ok(None) },
+            Frame::Reply { request_id: 8, result: Err("Buffer full".to_string()) },
+        ];
+        for frame in frames {
+            assert_eq!(Frame::decode(BytesMut::from(&frame.encode()[..]))?, frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_frames_are_rejected() {
+        assert!(Frame::decode(BytesMut::new()).is_err());
+        assert!(Frame::decode(BytesMut::from(&[99u8][..])).is_err());
+        assert!(Frame::decode(BytesMut::from(&[TAG_UPDATE, 0, 0][..])).is_err());
+        assert!(Frame::decode(BytesMut::from(&[TAG_JOIN, 1, 2, 3][..])).is_err());
+    }
+
+    #[test]
+    fn test_ownership_is_stable_and_spread() -> Result<()> {
+        let config = ClusterConfig {
+            node_id: "a".to_string(),
+            listen_addr: "127.0.0.1:4000".parse()?,
+            peers: ClusterConfig::parse_peers("a=127.0.0.1:4000,b=127.0.0.1:4001,c=127.0.0.1:4002")?,
+        };
+        let mut owned_by = HashMap::new();
+        for _ in 0..300 {
+            let doc_id = Uuid::new_v4();
+            let owner = config.owner(doc_id);
+            assert_eq!(owner, config.owner(doc_id));
+            *owned_by.entry(owner.node_id.clone()).or_insert(0) += 1;
+        }
+        assert_eq!(owned_by.len(), 3, "Every node should own some documents");
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::blob_store;
+use crate::document_service::DocumentService;
+use anyhow::{Context, Result};
+use chrono::{TimeDelta, Utc};
+use object_store::path::Path;
+use object_store::{ObjectStore, PutPayload};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const COLD_STORAGE_URL_ENV: &str = "COLLABORATE_COLD_STORAGE_URL";
+const COLD_AFTER_DAYS_ENV: &str = "COLLABORATE_COLD_AFTER_DAYS";
+const COLD_MIN_BYTES_ENV: &str = "COLLABORATE_COLD_MIN_BYTES";
+const EXPIRED_COLD_AFTER_DAYS_ENV: &str = "COLLABORATE_EXPIRED_COLD_AFTER_DAYS";
+const OFFLOAD_BATCH_SIZE: i64 = 64;
+
+/// When documents are considered cold and where their content is moved to.
+#[derive(Clone, Debug)]
+pub struct ColdStorageConfig {
+    /// Object storage URL; see `blob_store::open`.
+    pub url: String,
+    /// Documents neither opened nor modified for this long are offloaded.
+    pub cold_after: TimeDelta,
+    /// Documents smaller than this stay in the database; offloading them saves little.
+    pub min_bytes: usize,
+    /// Documents that expired this long ago are offloaded whatever their size, unless
+    /// opened since; they can no longer change.
+    pub expired_cold_after: TimeDelta,
+    pub interval: Duration,
+}
+
+impl ColdStorageConfig {
+    /// Returns `None` when `COLLABORATE_COLD_STORAGE_URL` is unset, i.e. offloading is disabled.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(url) = env::var(COLD_STORAGE_URL_ENV) else {
+            return Ok(None);
+        };
+        let cold_after_days = match env::var(COLD_AFTER_DAYS_ENV) {
+            Ok(days) => days.parse().context(format!("{} must be a number of days", COLD_AFTER_DAYS_ENV))?,
+            Err(_) => 90,
+        };
+        let min_bytes = match env::var(COLD_MIN_BYTES_ENV) {
+            Ok(bytes) => bytes.parse().context(format!("{} must be a number of bytes", COLD_MIN_BYTES_ENV))?,
+            Err(_) => 64 * 1024,
+        };
+        let expired_cold_after_days = match env::var(EXPIRED_COLD_AFTER_DAYS_ENV) {
+            Ok(days) => days.parse().context(format!("{} must be a number of days", EXPIRED_COLD_AFTER_DAYS_ENV))?,
+            Err(_) => 7,
+        };
+        Ok(Some(ColdStorageConfig {
+            url,
+            cold_after: TimeDelta::days(cold_after_days),
+            min_bytes,
+            expired_cold_after: TimeDelta::days(expired_cold_after_days),
+            interval: Duration::from_secs(60 * 60),
+        }))
+    }
+}
+
+/// Holds the content of offloaded documents. The database keeps a stub row pointing at the object.
+pub struct ColdStorage {
+    store: Arc<dyn ObjectStore>,
+    root: Path,
+}
+
+impl ColdStorage {
+    pub fn new(store: Arc<dyn ObjectStore>, root: Path) -> Self {
+        ColdStorage { store, root }
+    }
+
+    pub fn from_config(config: &ColdStorageConfig) -> Result<Self> {
+        let (store, root) = blob_store::open(&config.url)?;
+        Ok(ColdStorage::new(store, root))
+    }
+
+    /// Uploads a document's content, returning the key to record in its stub row.
+    pub(crate) async fn put(&self, doc_id: Uuid, data: Vec<u8>) -> Result<String> {
+        // A fresh key per offload means a stale stub can never point at newer content.
+        let location = self.root.child("cold").child(doc_id.to_string()).child(format!("{}.bin", Uuid::new_v4()));
+        self.store
+            .put(&location, PutPayload::from(data))
+            .await
+            .context(format!("Failed to offload content of document ID {}", doc_id))?;
+        Ok(location.to_string())
+    }
+
+    pub(crate) async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let location = Path::from(key);
+        let data = self.store
+            .get(&location)
+            .await
+            .context(format!("Failed to fetch offloaded content {}", key))?
+            .bytes()
+            .await?;
+        Ok(data.to_vec())
+    }
+
+    pub(crate) async fn delete(&self, key: &str) -> Result<()> {
+        self.store
+            .delete(&Path::from(key))
+            .await
+            .context(format!("Failed to delete offloaded content {}", key))
+    }
+}
+
+/// Periodically moves the content of cold documents out of the database.
+pub async fn run_offloader(doc_service: Arc<DocumentService>, config: ColdStorageConfig) {
+    let mut ticker = tokio::time::interval(config.interval);
+    loop {
+        ticker.tick().await;
+        match offload_cold_documents(&doc_service, &config).await {
+            Ok(0) => {}
+            Ok(offloaded) => info!("Offloaded {} cold documents to object storage", offloaded),
+            Err(e) => warn!("Cold document offloading failed; will retry: {:#}", e),
+        }
+    }
+}
+
+async fn offload_cold_documents(doc_service: &DocumentService, config: &ColdStorageConfig) -> Result<usize> {
+    let cold_before = Utc::now() - config.cold_after;
+    let expired_before = Utc::now() - config.expired_cold_after;
+    let mut offloaded = 0;
+    loop {
+        let candidates = doc_service.find_cold_documents(cold_before, expired_before, config.min_bytes, OFFLOAD_BATCH_SIZE).await?;
+        let mut progressed = false;
+        for doc_id in &candidates {
+            if doc_service.offload_document(*doc_id).await? {
+                offloaded += 1;
+                progressed = true;
+            }
+        }
+        // Stop once a batch comes back short, or if every candidate raced with a writer.
+        if (candidates.len() as i64) < OFFLOAD_BATCH_SIZE || !progressed {
+            return Ok(offloaded);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Folds the document update log back into snapshots, so reads stay cheap and the log
+//! does not grow without bound.
+
+use crate::document_service::DocumentService;
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Documents are compacted once this many updates have built up on their snapshot.
+pub const COMPACT_MIN_UPDATES: i64 = 32;
+const COMPACT_BATCH_SIZE: i64 = 100;
+
+/// Periodically folds logged updates into document snapshots.
+pub async fn run_compactor(doc_service: Arc<DocumentService>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match compact_documents(&doc_service).await {
+            Ok(0) => {}
+            Ok(compacted) => info!("Compacted the update logs of {} documents", compacted),
+            Err(e) => warn!("Document compaction failed; will retry: {:#}", e),
+        }
+    }
+}
+
+async fn compact_documents(doc_service: &DocumentService) -> Result<usize> {
+    let mut compacted = 0;
+    loop {
+        let candidates = doc_service.find_documents_to_compact(COMPACT_MIN_UPDATES, COMPACT_BATCH_SIZE).await?;
+        let mut progressed = false;
+        for doc_id in &candidates {
+            if doc_service.compact_document(*doc_id).await? {
+                compacted += 1;
+                progressed = true;
+            }
+        }
+        // Stop once a batch comes back short, or if every candidate raced with a writer.
+        if (candidates.len() as i64) < COMPACT_BATCH_SIZE || !progressed {
+            return Ok(compacted);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! zstd compression of document snapshots at rest. Snapshots are compressed before they are
+//! sealed (see `encryption`), and kept compressed only if that makes them smaller. Compressed
+//! snapshots start with a marker ending in a format byte; snapshots saved before compression
+//! have none and are read as they are until they are next written. Logged updates are small
+//! and saved versions are sized by what they store, so both are left alone.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const DEFAULT_LEVEL: i32 = 3;
+/// Starts everything compressed, ahead of a zstd frame. The last byte is the format.
+const COMPRESSED_PREFIX: &[u8] = b"\x00ZSTD\x01";
+
+/// How new snapshots are compressed, with a tally of what that has saved on this node.
+#[derive(Debug, Default)]
+pub struct Compression {
+    /// `None` stores new snapshots as they are; compressed ones can still be read.
+    level: Option<i32>,
+    original_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+/// Snapshot bytes this node has written since it started, before and after compression.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct CompressionStats {
+    pub original_bytes: u64,
+    pub stored_bytes: u64,
+    /// `original_bytes` per stored byte; `None` until something is written.
+    pub ratio: Option<f64>,
+}
+
+impl Compression {
+    /// Compresses at `level`, from 1 (fastest) to 22 (smallest).
+    pub fn new(level: i32) -> Self {
+        Compression { level: Some(level), ..Compression::default() }
+    }
+
+    /// Leaves new snapshots uncompressed.
+    pub fn disabled() -> Self {
+        Compression::default()
+    }
+
+    pub fn level(&self) -> Option<i32> {
+        self.level
+    }
+
+    /// `content` as it is to be stored. Content that happens to start with the marker is
+    /// compressed even when compression is off, so it cannot be mistaken for compressed content.
+    pub(crate) fn compress(&self, content: Vec<u8>) -> Result<Vec<u8>> {
+        let original_len = content.len();
+        let ambiguous = is_compressed(&content);
+        let stored = match self.level.or(ambiguous.then_some(DEFAULT_LEVEL)) {
+            Some(level) => {
+                let compressed = zstd::encode_all(content.as_slice(), level).context("Failed to compress content")?;
+                if ambiguous || COMPRESSED_PREFIX.len() + compressed.len() < original_len {
+                    [COMPRESSED_PREFIX, &compressed].concat()
+                } else {
+                    content
+                }
+            }
+            None => content,
+        };
+        self.original_bytes.fetch_add(original_len as u64, Ordering::Relaxed);
+        self.stored_bytes.fetch_add(stored.len() as u64, Ordering::Relaxed);
+        Ok(stored)
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        let original_bytes = self.original_bytes.load(Ordering::Relaxed);
+        let stored_bytes = self.stored_bytes.load(Ordering::Relaxed);
+        let ratio = (stored_bytes > 0).then(|| original_bytes as f64 / stored_bytes as f64);
+        CompressionStats { original_bytes, stored_bytes, ratio }
+    }
+}
+
+/// The content `stored`, decompressed if it was compressed.
+pub(crate) fn decompress(stored: Vec<u8>) -> Result<Vec<u8>> {
+    match stored.strip_prefix(COMPRESSED_PREFIX) {
+        Some(compressed) => zstd::decode_all(compressed).context("Failed to decompress content"),
+        None => Ok(stored),
+    }
+}
+
+/// Whether `stored` was compressed.
+pub(crate) fn is_compressed(stored: &[u8]) -> bool {
+    stored.starts_with(COMPRESSED_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_round_trips() -> Result<()> {
+        let compression = Compression::new(DEFAULT_LEVEL);
+        let large = b"the quick brown fox ".repeat(100);
+        let stored = compression.compress(large.clone())?;
+        assert!(is_compressed(&stored) && stored.len() < large.len());
+        assert_eq!(decompress(stored)?, large);
+
+        // Content that would not shrink, and snapshots saved before compression, stay as they are.
+        assert_eq!(compression.compress(b"tiny".to_vec())?, b"tiny");
+        assert_eq!(decompress(b"tiny".to_vec())?, b"tiny");
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_that_looks_compressed_is_always_compressed() -> Result<()> {
+        let lookalike = [COMPRESSED_PREFIX, b"not really"].concat();
+        let stored = Compression::disabled().compress(lookalike.clone())?;
+        assert_ne!(stored, lookalike);
+        assert_eq!(decompress(stored)?, lookalike);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats_track_the_ratio() -> Result<()> {
+        let compression = Compression::new(DEFAULT_LEVEL);
+        assert_eq!(compression.stats().ratio, None);
+        let stored = compression.compress(vec![0; 10_000])?;
+        let stats = compression.stats();
+        assert_eq!((stats.original_bytes, stats.stored_bytes), (10_000, stored.len() as u64));
+        assert!(stats.ratio.is_some_and(|ratio| ratio > 10.0));
+        Ok(())
+    }
+}
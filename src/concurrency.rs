@@ -0,0 +1,251 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Caps on how many expensive requests a node serves at once. Exports, imports and merges
+//! each have their own budget; requests over it queue for a slot rather than fail, so a
+//! burst of exports waits behind itself instead of starving the runtime and database pool
+//! that interactive saves share. How long requests queue is reported with the node's metrics.
+
+use anyhow::{Context, Result};
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Serialize;
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+const EXPORT_CONCURRENCY_ENV: &str = "COLLABORATE_EXPORT_CONCURRENCY";
+const IMPORT_CONCURRENCY_ENV: &str = "COLLABORATE_IMPORT_CONCURRENCY";
+const MERGE_CONCURRENCY_ENV: &str = "COLLABORATE_MERGE_CONCURRENCY";
+
+/// The kinds of request with a budget of their own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteClass {
+    /// Rendering a document for download.
+    Export,
+    /// Creating a document from an uploaded file.
+    Import,
+    /// Catching an offline client up on many documents at once.
+    Merge,
+}
+
+impl fmt::Display for RouteClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RouteClass::Export => "export",
+            RouteClass::Import => "import",
+            RouteClass::Merge => "merge",
+        })
+    }
+}
+
+/// How many requests of each class a node serves at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConcurrencyConfig {
+    pub exports: usize,
+    pub imports: usize,
+    pub merges: usize,
+}
+
+impl ConcurrencyConfig {
+    /// Reads `COLLABORATE_EXPORT_CONCURRENCY`, `COLLABORATE_IMPORT_CONCURRENCY` and
+    /// `COLLABORATE_MERGE_CONCURRENCY`, keeping the default for any that is unset.
+    pub fn from_env() -> Result<Self> {
+        let defaults = ConcurrencyConfig::default();
+        Ok(ConcurrencyConfig {
+            exports: limit_from_env(EXPORT_CONCURRENCY_ENV, defaults.exports)?,
+            imports: limit_from_env(IMPORT_CONCURRENCY_ENV, defaults.imports)?,
+            merges: limit_from_env(MERGE_CONCURRENCY_ENV, defaults.merges)?,
+        })
+    }
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        ConcurrencyConfig { exports: 4, imports: 2, merges: 8 }
+    }
+}
+
+fn limit_from_env(name: &str, default: usize) -> Result<usize> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .ok()
+            .filter(|limit| *limit > 0)
+            .context(format!("{} must be a positive number of requests", name)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// One class's slots, and how long requests have queued for them.
+#[derive(Debug)]
+pub struct RouteBudget {
+    class: RouteClass,
+    limit: usize,
+    slots: Arc<Semaphore>,
+    waiting: AtomicUsize,
+    admitted: AtomicU64,
+    total_wait_us: AtomicU64,
+    max_wait_us: AtomicU64,
+}
+
+impl RouteBudget {
+    pub fn new(class: RouteClass, limit: usize) -> Self {
+        RouteBudget {
+            class,
+            limit,
+            slots: Arc::new(Semaphore::new(limit)),
+            waiting: AtomicUsize::new(0),
+            admitted: AtomicU64::new(0),
+            total_wait_us: AtomicU64::new(0),
+            max_wait_us: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits for a free slot, which is held until the returned permit is dropped.
+    pub async fn admit(&self) -> OwnedSemaphorePermit {
+        let started = Instant::now();
+        let permit = {
+            let _waiting = Waiting::new(&self.waiting);
+            self.slots.clone().acquire_owned().await.expect("budget semaphores are never closed")
+        };
+        let waited = u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.admitted.fetch_add(1, Ordering::Relaxed);
+        self.total_wait_us.fetch_add(waited, Ordering::Relaxed);
+        self.max_wait_us.fetch_max(waited, Ordering::Relaxed);
+        permit
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        let admitted = self.admitted.load(Ordering::Relaxed);
+        let total_wait_ms = self.total_wait_us.load(Ordering::Relaxed) as f64 / 1000.0;
+        QueueSnapshot {
+            class: self.class,
+            limit: self.limit,
+            in_flight: self.limit - self.slots.available_permits(),
+            waiting: self.waiting.load(Ordering::Relaxed),
+            admitted,
+            mean_wait_ms: if admitted == 0 { 0.0 } else { total_wait_ms / admitted as f64 },
+            max_wait_ms: self.max_wait_us.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+/// Counts a request as queued until dropped, including when its client gives up waiting.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(waiting: &'a AtomicUsize) -> Self {
+        waiting.fetch_add(1, Ordering::Relaxed);
+        Waiting(waiting)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A class's queue as of now. Waits are totalled since the node started.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QueueSnapshot {
+    pub class: RouteClass,
+    pub limit: usize,
+    pub in_flight: usize,
+    /// Requests queued for a slot.
+    pub waiting: usize,
+    /// Requests given a slot so far.
+    pub admitted: u64,
+    pub mean_wait_ms: f64,
+    pub max_wait_ms: f64,
+}
+
+/// The budgets of every class.
+#[derive(Debug)]
+pub struct ConcurrencyLimits {
+    exports: Arc<RouteBudget>,
+    imports: Arc<RouteBudget>,
+    merges: Arc<RouteBudget>,
+}
+
+impl ConcurrencyLimits {
+    pub fn new(config: ConcurrencyConfig) -> Self {
+        ConcurrencyLimits {
+            exports: Arc::new(RouteBudget::new(RouteClass::Export, config.exports)),
+            imports: Arc::new(RouteBudget::new(RouteClass::Import, config.imports)),
+            merges: Arc::new(RouteBudget::new(RouteClass::Merge, config.merges)),
+        }
+    }
+
+    pub fn budget(&self, class: RouteClass) -> Arc<RouteBudget> {
+        match class {
+            RouteClass::Export => self.exports.clone(),
+            RouteClass::Import => self.imports.clone(),
+            RouteClass::Merge => self.merges.clone(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<QueueSnapshot> {
+        [&self.exports, &self.imports, &self.merges].iter().map(|budget| budget.snapshot()).collect()
+    }
+}
+
+/// Middleware holding each request to its route's budget.
+pub async fn enforce(State(budget): State<Arc<RouteBudget>>, request: Request, next: Next) -> Response {
+    let _slot = budget.admit().await;
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_requests_over_budget_queue_until_a_slot_frees() {
+        let budget = Arc::new(RouteBudget::new(RouteClass::Export, 1));
+        let first = budget.admit().await;
+
+        let queued = tokio::spawn({
+            let budget = budget.clone();
+            async move {
+                let _slot = budget.admit().await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let snapshot = budget.snapshot();
+        assert_eq!((snapshot.in_flight, snapshot.waiting, snapshot.admitted), (1, 1, 1));
+
+        drop(first);
+        queued.await.unwrap();
+        let snapshot = budget.snapshot();
+        assert_eq!((snapshot.in_flight, snapshot.waiting, snapshot.admitted), (0, 0, 2));
+        assert!(snapshot.max_wait_ms >= 20.0);
+        assert!(snapshot.mean_wait_ms >= 10.0 && snapshot.mean_wait_ms <= snapshot.max_wait_ms);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_requests_stop_counting_as_queued() {
+        let budget = RouteBudget::new(RouteClass::Merge, 1);
+        let _slot = budget.admit().await;
+        assert!(tokio::time::timeout(Duration::from_millis(10), budget.admit()).await.is_err());
+        assert_eq!((budget.snapshot().waiting, budget.snapshot().admitted), (0, 1));
+    }
+}
@@ -0,0 +1,486 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+//! Where to find the database, where to listen, how eagerly to save documents, how much
+//! each WebSocket may send, how large documents may grow and how hard to compress them,
+//! read from a TOML file and overridden
+//! by environment variables. The file is the one given with `--config` or
+//! `COLLABORATE_CONFIG`, or `collaborate.toml` in the working directory if that exists; every setting has a default, so neither is required.
+//!
+//! ```toml
+//! region = "eu-west1"
+//!
+//! [database]
+//! endpoints = ["eu-west1=root@db-west:26257", "us-east1=root@db-east:26257"]
+//! name = "collaborate_app"
+//! max_connections = 10
+//!
+//! [server]
+//! listen = "0.0.0.0:3000"
+//!
+//! [autosave]
+//! min_delay_ms = 0
+//! max_delay_ms = 5000
+//! large_document_bytes = 1048576
+//! busy_updates_per_second = 2.0
+//!
+//! [message_limits]
+//! guest_max_frame_bytes = 1048576
+//! guest_updates_per_second = 10.0
+//! guest_burst = 30
+//! editor_max_frame_bytes = 16777216
+//! editor_updates_per_second = 30.0
+//! editor_burst = 120
+//! warnings = 3
+//!
+//! [upload_limits]
+//! max_update_bytes = 25165824
+//! max_document_bytes = 67108864
+//!
+//! [compression]
+//! enabled = true
+//! level = 3
+//! ```
+
+use crate::compression;
+use crate::db::RetryPolicy;
+use crate::document_room::{AutosavePolicy, MessageLimit, MessageLimits};
+use crate::region::{RegionConfig, RegionalEndpoint};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const CONFIG_PATH_ENV: &str = "COLLABORATE_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "collaborate.toml";
+const REGION_ENV: &str = "COLLABORATE_REGION";
+const DB_ENDPOINTS_ENV: &str = "COLLABORATE_DB_ENDPOINTS";
+const DB_NAME_ENV: &str = "COLLABORATE_DB_NAME";
+const DB_MAX_CONNECTIONS_ENV: &str = "COLLABORATE_DB_MAX_CONNECTIONS";
+const DB_CONNECT_ATTEMPTS_ENV: &str = "COLLABORATE_DB_CONNECT_ATTEMPTS";
+const LISTEN_ENV: &str = "COLLABORATE_LISTEN";
+
+const DEFAULT_DB_ENDPOINT: &str = "root@localhost:26257";
+const DEFAULT_DB_NAME: &str = "collaborate_app";
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+pub const DEFAULT_LISTEN_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 3000);
+/// Room for the largest update an editor may send over a WebSocket, base64-encoded in JSON.
+pub const DEFAULT_MAX_UPDATE_BYTES: usize = 24 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabaseConfig {
+    /// The application database, created on first connection if missing.
+    pub name: String,
+    pub max_connections: u32,
+    /// How long to keep trying to connect at startup.
+    pub connect_retry: RetryPolicy,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerConfig {
+    /// Where the HTTP API listens.
+    pub listen_addr: SocketAddr,
+}
+
+/// How large uploaded content may be.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UploadLimits {
+    /// The largest request body `PUT /api/documents/:id/content` accepts.
+    pub max_update_bytes: usize,
+    /// The largest a document's content may grow; `None` for no limit. See
+    /// `DocumentService::with_max_document_bytes`.
+    pub max_document_bytes: Option<usize>,
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        UploadLimits { max_update_bytes: DEFAULT_MAX_UPDATE_BYTES, max_document_bytes: None }
+    }
+}
+
+/// Settings needed to connect to the database and start serving.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AppConfig {
+    /// This instance's region and the database endpoints it may use.
+    pub region: RegionConfig,
+    pub database: DatabaseConfig,
+    pub server: ServerConfig,
+    /// How long document rooms hold content before saving it.
+    pub autosave: AutosavePolicy,
+    /// What each document WebSocket may send.
+    pub message_limits: MessageLimits,
+    pub upload_limits: UploadLimits,
+    /// The zstd level document snapshots are compressed at; `None` leaves them uncompressed.
+    pub compression_level: Option<i32>,
+}
+
+// The file's layout; everything is optional so a file need only mention what it changes.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    region: Option<String>,
+    #[serde(default)]
+    database: DatabaseSection,
+    #[serde(default)]
+    server: ServerSection,
+    #[serde(default)]
+    autosave: AutosaveSection,
+    #[serde(default)]
+    message_limits: MessageLimitsSection,
+    #[serde(default)]
+    upload_limits: UploadLimitsSection,
+    #[serde(default)]
+    compression: CompressionSection,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DatabaseSection {
+    /// Each `base-uri` or `region=base-uri`, as in `COLLABORATE_DB_ENDPOINTS`.
+    endpoints: Option<Vec<String>>,
+    name: Option<String>,
+    max_connections: Option<u32>,
+    connect_attempts: Option<u32>,
+    connect_initial_delay_ms: Option<u64>,
+    connect_max_delay_ms: Option<u64>,
+    connect_jitter: Option<f64>,
+}
+
+impl DatabaseSection {
+    fn connect_retry(&self, attempts: Option<u32>) -> Result<RetryPolicy> {
+        let defaults = RetryPolicy::default();
+        let policy = RetryPolicy {
+            max_attempts: attempts.or(self.connect_attempts).unwrap_or(defaults.max_attempts),
+            initial_delay: self.connect_initial_delay_ms.map_or(defaults.initial_delay, Duration::from_millis),
+            max_delay: self.connect_max_delay_ms.map_or(defaults.max_delay, Duration::from_millis),
+            jitter: self.connect_jitter.unwrap_or(defaults.jitter),
+        };
+        if policy.max_attempts == 0 || policy.max_delay < policy.initial_delay || !(0.0..=1.0).contains(&policy.jitter) {
+            return Err(anyhow!(
+                "database.connect_attempts must be at least 1, connect_max_delay_ms at least connect_initial_delay_ms, and connect_jitter between 0 and 1"
+            ));
+        }
+        Ok(policy)
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServerSection {
+    listen: Option<SocketAddr>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AutosaveSection {
+    min_delay_ms: Option<u64>,
+    max_delay_ms: Option<u64>,
+    large_document_bytes: Option<usize>,
+    busy_updates_per_second: Option<f64>,
+}
+
+impl AutosaveSection {
+    fn policy(self) -> Result<AutosavePolicy> {
+        let defaults = AutosavePolicy::default();
+        let policy = AutosavePolicy {
+            min_delay: self.min_delay_ms.map_or(defaults.min_delay, Duration::from_millis),
+            max_delay: self.max_delay_ms.map_or(defaults.max_delay, Duration::from_millis),
+            large_document_bytes: self.large_document_bytes.unwrap_or(defaults.large_document_bytes),
+            busy_updates_per_second: self.busy_updates_per_second.unwrap_or(defaults.busy_updates_per_second),
+        };
+        if policy.max_delay < policy.min_delay
+            || policy.large_document_bytes == 0
+            || !policy.busy_updates_per_second.is_finite()
+            || policy.busy_updates_per_second <= 0.0
+        {
+            return Err(anyhow!(
+                "autosave.max_delay_ms must be at least min_delay_ms, and large_document_bytes and busy_updates_per_second must be positive"
+            ));
+        }
+        Ok(policy)
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MessageLimitsSection {
+    guest_max_frame_bytes: Option<usize>,
+    guest_updates_per_second: Option<f64>,
+    guest_burst: Option<u32>,
+    editor_max_frame_bytes: Option<usize>,
+    editor_updates_per_second: Option<f64>,
+    editor_burst: Option<u32>,
+    warnings: Option<u32>,
+}
+
+impl MessageLimitsSection {
+    fn limits(self) -> Result<MessageLimits> {
+        let defaults = MessageLimits::default();
+        let limits = MessageLimits {
+            guest: MessageLimit {
+                max_frame_bytes: self.guest_max_frame_bytes.unwrap_or(defaults.guest.max_frame_bytes),
+                updates_per_second: self.guest_updates_per_second.unwrap_or(defaults.guest.updates_per_second),
+                burst: self.guest_burst.unwrap_or(defaults.guest.burst),
+            },
+            editor: MessageLimit {
+                max_frame_bytes: self.editor_max_frame_bytes.unwrap_or(defaults.editor.max_frame_bytes),
+                updates_per_second: self.editor_updates_per_second.unwrap_or(defaults.editor.updates_per_second),
+                burst: self.editor_burst.unwrap_or(defaults.editor.burst),
+            },
+            warnings: self.warnings.unwrap_or(defaults.warnings),
+        };
+        let valid = |limit: &MessageLimit| {
+            limit.max_frame_bytes > 0 && limit.burst > 0 && limit.updates_per_second.is_finite() && limit.updates_per_second > 0.0
+        };
+        if !valid(&limits.guest) || !valid(&limits.editor) {
+            return Err(anyhow!("message_limits frame sizes, update rates and bursts must be positive"));
+        }
+        Ok(limits)
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UploadLimitsSection {
+    max_update_bytes: Option<usize>,
+    max_document_bytes: Option<usize>,
+}
+
+impl UploadLimitsSection {
+    fn limits(self) -> Result<UploadLimits> {
+        let limits = UploadLimits {
+            max_update_bytes: self.max_update_bytes.unwrap_or(DEFAULT_MAX_UPDATE_BYTES),
+            max_document_bytes: self.max_document_bytes,
+        };
+        if limits.max_update_bytes == 0 || limits.max_document_bytes == Some(0) {
+            return Err(anyhow!("upload_limits.max_update_bytes and max_document_bytes must be positive"));
+        }
+        Ok(limits)
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompressionSection {
+    enabled: Option<bool>,
+    level: Option<i32>,
+}
+
+impl CompressionSection {
+    fn level(self) -> Result<Option<i32>> {
+        let level = self.level.unwrap_or(compression::DEFAULT_LEVEL);
+        if !(1..=22).contains(&level) {
+            return Err(anyhow!("compression.level must be between 1 and 22"));
+        }
+        Ok(self.enabled.unwrap_or(true).then_some(level))
+    }
+}
+
+impl AppConfig {
+    /// Reads the config file, if any, then applies `COLLABORATE_REGION`,
+    /// `COLLABORATE_DB_ENDPOINTS`, `COLLABORATE_DB_NAME`, `COLLABORATE_DB_MAX_CONNECTIONS`,
+    /// `COLLABORATE_DB_CONNECT_ATTEMPTS` and `COLLABORATE_LISTEN` on top.
+    pub fn load() -> Result<Self> {
+        Self::load_from(env::var(CONFIG_PATH_ENV).ok().as_deref().map(Path::new))
+    }
+
+    /// Like [`AppConfig::load`], but reads the config file at `path` rather than the one
+    /// `COLLABORATE_CONFIG` names.
+    pub fn load_from(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => Some(path),
+            None => Some(Path::new(DEFAULT_CONFIG_PATH)).filter(|path| path.exists()),
+        };
+        let file = match path {
+            Some(path) => Some(fs::read_to_string(path).context(format!("Failed to read config file {}", path.display()))?),
+            None => None,
+        };
+        Self::from_sources(file.as_deref(), |name| env::var(name).ok())
+    }
+
+    /// Builds the configuration from a file's contents and an environment lookup.
+    pub fn from_sources(file: Option<&str>, env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let file: ConfigFile = match file {
+            Some(contents) => toml::from_str(contents).context("Invalid config file")?,
+            None => ConfigFile::default(),
+        };
+
+        let region = env(REGION_ENV).or(file.region).filter(|region| !region.is_empty());
+        let connect_attempts = match env(DB_CONNECT_ATTEMPTS_ENV) {
+            Some(value) => Some(parse_env(DB_CONNECT_ATTEMPTS_ENV, &value)?),
+            None => None,
+        };
+        let connect_retry = file.database.connect_retry(connect_attempts)?;
+        let db_endpoints = match env(DB_ENDPOINTS_ENV) {
+            Some(spec) => RegionConfig::parse_endpoints(&spec).context(format!("Invalid {}", DB_ENDPOINTS_ENV))?,
+            None => match file.database.endpoints {
+                Some(entries) if entries.is_empty() => {
+                    return Err(anyhow!("database.endpoints in the config file must list at least one endpoint"));
+                }
+                Some(entries) => entries.iter().map(String::as_str).map(RegionalEndpoint::parse).collect(),
+                None => vec![RegionalEndpoint::parse(DEFAULT_DB_ENDPOINT)],
+            },
+        };
+        let database = DatabaseConfig {
+            name: env(DB_NAME_ENV).or(file.database.name).unwrap_or_else(|| DEFAULT_DB_NAME.to_string()),
+            max_connections: match env(DB_MAX_CONNECTIONS_ENV) {
+                Some(value) => parse_env(DB_MAX_CONNECTIONS_ENV, &value)?,
+                None => file.database.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS),
+            },
+            connect_retry,
+        };
+        if database.name.is_empty() || database.max_connections == 0 {
+            return Err(anyhow!("The database name must be set and max_connections must be at least 1"));
+        }
+        let server = ServerConfig {
+            listen_addr: match env(LISTEN_ENV) {
+                Some(value) => parse_env(LISTEN_ENV, &value)?,
+                None => file.server.listen.unwrap_or(DEFAULT_LISTEN_ADDR),
+            },
+        };
+        let autosave = file.autosave.policy()?;
+        let message_limits = file.message_limits.limits()?;
+        let upload_limits = file.upload_limits.limits()?;
+        let compression_level = file.compression.level()?;
+        Ok(AppConfig {
+            region: RegionConfig { region, db_endpoints },
+            database,
+            server,
+            autosave,
+            message_limits,
+            upload_limits,
+            compression_level,
+        })
+    }
+}
+
+fn parse_env<T: FromStr>(name: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| anyhow!("Invalid {}: {}", name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_defaults_apply_without_file_or_environment() -> Result<()> {
+        let config = AppConfig::from_sources(None, lookup(&[]))?;
+        assert_eq!(config.region.region, None);
+        assert_eq!(config.region.db_endpoints, vec![RegionalEndpoint::parse(DEFAULT_DB_ENDPOINT)]);
+        assert_eq!(
+            config.database,
+            DatabaseConfig { name: "collaborate_app".to_string(), max_connections: 10, connect_retry: RetryPolicy::default() }
+        );
+        assert_eq!(config.server.listen_addr, DEFAULT_LISTEN_ADDR);
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_overrides_file() -> Result<()> {
+        let file = r#"
+            region = "eu-west1"
+
+            [database]
+            endpoints = ["eu-west1=root@west:26257", "root@anywhere:26257"]
+            name = "from_file"
+
+            [server]
+            listen = "0.0.0.0:4000"
+        "#;
+        let config = AppConfig::from_sources(Some(file), lookup(&[("COLLABORATE_DB_NAME", "from_env")]))?;
+        assert_eq!(config.region.region.as_deref(), Some("eu-west1"));
+        assert_eq!(config.region.db_endpoints.len(), 2);
+        assert_eq!(config.database.name, "from_env");
+        assert_eq!(config.database.max_connections, DEFAULT_MAX_CONNECTIONS);
+        assert_eq!(config.server.listen_addr, "0.0.0.0:4000".parse()?);
+
+        let config = AppConfig::from_sources(
+            Some(file),
+            lookup(&[("COLLABORATE_DB_ENDPOINTS", "root@env:26257"), ("COLLABORATE_LISTEN", "127.0.0.1:5000")]),
+        )?;
+        assert_eq!(config.region.db_endpoints, vec![RegionalEndpoint::parse("root@env:26257")]);
+        assert_eq!(config.server.listen_addr, "127.0.0.1:5000".parse()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_autosave_policy_is_read_from_file() -> Result<()> {
+        assert_eq!(AppConfig::from_sources(None, lookup(&[]))?.autosave, AutosavePolicy::default());
+
+        let file = "[autosave]\nmin_delay_ms = 100\nmax_delay_ms = 30000";
+        let autosave = AppConfig::from_sources(Some(file), lookup(&[]))?.autosave;
+        assert_eq!(autosave.min_delay, Duration::from_millis(100));
+        assert_eq!(autosave.max_delay, Duration::from_secs(30));
+        assert_eq!(autosave.large_document_bytes, AutosavePolicy::default().large_document_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        assert!(AppConfig::from_sources(Some("[databse]\nname = \"typo\""), lookup(&[])).is_err());
+        assert!(AppConfig::from_sources(Some("[database]\nendpoints = []"), lookup(&[])).is_err());
+        assert!(AppConfig::from_sources(None, lookup(&[("COLLABORATE_LISTEN", "port 80")])).is_err());
+        assert!(AppConfig::from_sources(None, lookup(&[("COLLABORATE_DB_MAX_CONNECTIONS", "0")])).is_err());
+        assert!(AppConfig::from_sources(Some("[autosave]\nmin_delay_ms = 2000\nmax_delay_ms = 1000"), lookup(&[])).is_err());
+        assert!(AppConfig::from_sources(Some("[autosave]\nbusy_updates_per_second = 0.0"), lookup(&[])).is_err());
+        assert!(AppConfig::from_sources(Some("[message_limits]\nguest_burst = 0"), lookup(&[])).is_err());
+        assert!(AppConfig::from_sources(Some("[database]\nconnect_jitter = 1.5"), lookup(&[])).is_err());
+        assert!(AppConfig::from_sources(None, lookup(&[("COLLABORATE_DB_CONNECT_ATTEMPTS", "0")])).is_err());
+        assert!(AppConfig::from_sources(Some("[upload_limits]\nmax_document_bytes = 0"), lookup(&[])).is_err());
+        assert!(AppConfig::from_sources(Some("[compression]\nlevel = 23"), lookup(&[])).is_err());
+    }
+
+    #[test]
+    fn test_upload_limits_are_read_from_file() -> Result<()> {
+        assert_eq!(AppConfig::from_sources(None, lookup(&[]))?.upload_limits, UploadLimits::default());
+
+        let file = "[upload_limits]\nmax_document_bytes = 1048576";
+        let limits = AppConfig::from_sources(Some(file), lookup(&[]))?.upload_limits;
+        assert_eq!(limits, UploadLimits { max_update_bytes: DEFAULT_MAX_UPDATE_BYTES, max_document_bytes: Some(1024 * 1024) });
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_is_on_unless_disabled() -> Result<()> {
+        assert_eq!(AppConfig::from_sources(None, lookup(&[]))?.compression_level, Some(compression::DEFAULT_LEVEL));
+        assert_eq!(AppConfig::from_sources(Some("[compression]\nlevel = 19"), lookup(&[]))?.compression_level, Some(19));
+        assert_eq!(AppConfig::from_sources(Some("[compression]\nenabled = false"), lookup(&[]))?.compression_level, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_retry_is_read_from_file_and_environment() -> Result<()> {
+        let file = "[database]\nconnect_attempts = 30\nconnect_max_delay_ms = 5000\nconnect_jitter = 0.0";
+        let retry = AppConfig::from_sources(Some(file), lookup(&[]))?.database.connect_retry;
+        assert_eq!(retry.max_attempts, 30);
+        assert_eq!(retry.max_delay, Duration::from_secs(5));
+        assert_eq!(retry.jitter, 0.0);
+        assert_eq!(retry.initial_delay, RetryPolicy::default().initial_delay);
+
+        let retry = AppConfig::from_sources(Some(file), lookup(&[("COLLABORATE_DB_CONNECT_ATTEMPTS", "1")]))?.database.connect_retry;
+        assert_eq!(retry.max_attempts, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::PgPool;
+use std::fmt;
+use std::str::FromStr;
+
+// Versioned prefix so the token format can change without confusing old clients.
+const TOKEN_PREFIX: &str = "ct1.";
+
+/// Opaque token handed to clients after a write. Echoing it on a later read
+/// guarantees the read observes that write (read-your-writes), even when the
+/// read would otherwise be served by a lagging replica.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CausalityToken {
+    /// No earlier than the write's commit timestamp, on the cluster's clock; see `commit_time`.
+    written_at: DateTime<Utc>,
+}
+
+impl CausalityToken {
+    pub fn new(written_at: DateTime<Utc>) -> Self {
+        CausalityToken { written_at }
+    }
+
+    pub fn written_at(&self) -> DateTime<Utc> {
+        self.written_at
+    }
+}
+
+impl fmt::Display for CausalityToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{:x}", TOKEN_PREFIX, self.written_at.timestamp_millis())
+    }
+}
+
+impl FromStr for CausalityToken {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let encoded = s
+            .strip_prefix(TOKEN_PREFIX)
+            .ok_or_else(|| anyhow!("Unrecognized causality token: {}", s))?;
+        let millis = i64::from_str_radix(encoded, 16)
+            .context(format!("Malformed causality token: {}", s))?;
+        let written_at = DateTime::from_timestamp_millis(millis)
+            .ok_or_else(|| anyhow!("Causality token out of range: {}", s))?;
+        Ok(CausalityToken { written_at })
+    }
+}
+
+impl Serialize for CausalityToken {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CausalityToken {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        encoded.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The freshness a read requires.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadConsistency {
+    /// Read the latest committed state from the leaseholder.
+    #[default]
+    Strong,
+    /// Read from the nearest replica at `follower_read_timestamp()`, a few seconds stale.
+    Follower,
+    /// Read from the nearest replica unless it could predate the write the token was issued for,
+    /// in which case the read is redirected to the leaseholder.
+    AtLeast(CausalityToken),
+}
+
+impl ReadConsistency {
+    /// Collapses `AtLeast` into either `Strong` or `Follower` depending on whether a
+    /// follower read at `follower_read_at` is guaranteed to observe the token's write.
+    pub fn resolve(self, follower_read_at: DateTime<Utc>) -> ReadConsistency {
+        match self {
+            ReadConsistency::AtLeast(token) if token.written_at() > follower_read_at => ReadConsistency::Strong,
+            ReadConsistency::AtLeast(_) => ReadConsistency::Follower,
+            other => other,
+        }
+    }
+
+    /// Resolves `AtLeast` against the cluster's own follower read timestamp, so that
+    /// neither this server's clock nor how long the write took to commit can skew it.
+    /// Follower reads made afterwards are at least that fresh.
+    pub(crate) async fn resolve_in(self, pool: &PgPool) -> Result<ReadConsistency> {
+        match self {
+            ReadConsistency::AtLeast(_) => {
+                let follower_read_at: DateTime<Utc> = sqlx::query_scalar("SELECT follower_read_timestamp()")
+                    .fetch_one(pool)
+                    .await
+                    .context("Failed to query the follower read timestamp")?;
+                Ok(self.resolve(follower_read_at))
+            }
+            other => Ok(other),
+        }
+    }
+
+    /// The `AS OF SYSTEM TIME` clause to append after a query's `FROM` table for this
+    /// consistency. `AtLeast` reads from the leaseholder until it is resolved.
+    pub(crate) fn as_of_clause(self) -> &'static str {
+        match self {
+            ReadConsistency::Follower => " AS OF SYSTEM TIME follower_read_timestamp()",
+            _ => "",
+        }
+    }
+}
+
+/// The time `tx` commits at on the cluster's clock, rounded up to the millisecond.
+/// Call it last: once read, the commit timestamp is fixed, and a later statement that
+/// would have had to move it fails the transaction instead.
+pub(crate) async fn commit_time(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>) -> Result<DateTime<Utc>> {
+    let millis: i64 = sqlx::query_scalar("SELECT ceil(cluster_logical_timestamp() / 1000000)::INT8")
+        .fetch_one(&mut **tx)
+        .await
+        .context("Failed to query the commit timestamp")?;
+    DateTime::from_timestamp_millis(millis).ok_or_else(|| anyhow!("Commit timestamp out of range: {}", millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    #[test]
+    fn test_token_round_trip() -> Result<()> {
+        let written_at = DateTime::from_timestamp_millis(1_750_000_000_123).unwrap();
+        let token = CausalityToken::new(written_at);
+
+        let parsed: CausalityToken = token.to_string().parse()?;
+        assert_eq!(parsed, token);
+        assert_eq!(parsed.written_at(), written_at);
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_rejects_garbage() {
+        assert!("".parse::<CausalityToken>().is_err());
+        assert!("ct1.".parse::<CausalityToken>().is_err());
+        assert!("ct1.not-hex".parse::<CausalityToken>().is_err());
+        assert!("ct0.1f".parse::<CausalityToken>().is_err());
+    }
+
+    #[test]
+    fn test_tokens_newer_than_follower_reads_redirect_to_leaseholder() {
+        let follower_read_at = DateTime::from_timestamp_micros(1_750_000_000_123_456).unwrap();
+        let just_after = CausalityToken::new(follower_read_at + TimeDelta::microseconds(1));
+        let just_before = CausalityToken::new(follower_read_at - TimeDelta::microseconds(1));
+
+        assert_eq!(ReadConsistency::AtLeast(just_after).resolve(follower_read_at), ReadConsistency::Strong);
+        assert_eq!(ReadConsistency::AtLeast(just_before).resolve(follower_read_at), ReadConsistency::Follower);
+        assert_eq!(
+            ReadConsistency::AtLeast(CausalityToken::new(follower_read_at)).resolve(follower_read_at),
+            ReadConsistency::Follower,
+            "A follower read sees writes committed at its own timestamp"
+        );
+        assert_eq!(ReadConsistency::Follower.resolve(follower_read_at), ReadConsistency::Follower);
+    }
+}
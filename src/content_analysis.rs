@@ -0,0 +1,215 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Spell-check, grammar and lint services see plain text only: content is reduced to
+//! text by a `TextExtractor`, sent to an `Analyzer` along with the language detected in
+//! it, and the annotations it returns are broadcast to everyone in the document's room.
+
+use crate::consistency::CausalityToken;
+use crate::language;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+const ANALYZER_URL_ENV: &str = "COLLABORATE_ANALYZER_URL";
+const ANALYZER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A finding anchored to a range of the extracted text, in Unicode scalar values.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    pub start: usize,
+    pub end: usize,
+    /// Free-form category from the analyzer, e.g. "spelling" or "style".
+    pub kind: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suggestion: Option<String>,
+}
+
+/// The annotations for one saved version of a document.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AnnotationSet {
+    pub version: CausalityToken,
+    pub annotations: Vec<Annotation>,
+}
+
+/// Turns stored document content into the text analyzers see.
+pub trait TextExtractor: Send + Sync {
+    /// `None` means the content has no analysable text.
+    fn extract(&self, content: &[u8]) -> Option<String>;
+}
+
+/// Treats content as UTF-8 text. Stands in until content is decoded as a CRDT.
+pub struct Utf8Extractor;
+
+impl TextExtractor for Utf8Extractor {
+    fn extract(&self, content: &[u8]) -> Option<String> {
+        std::str::from_utf8(content).ok().filter(|text| !text.is_empty()).map(str::to_string)
+    }
+}
+
+/// A spell-check or linting service.
+#[async_trait]
+pub trait Analyzer: Send + Sync {
+    /// `language` is the text's language, e.g. "en", to pick a dictionary by; `None`
+    /// when there is too little text to tell.
+    async fn analyze(&self, text: &str, language: Option<&str>) -> Result<Vec<Annotation>>;
+}
+
+#[derive(Serialize)]
+struct AnalysisRequest<'a> {
+    text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct AnalysisResponse {
+    annotations: Vec<Annotation>,
+}
+
+/// Posts `{"text": ..., "language": ...}` to a URL and expects `{"annotations": [...]}`
+/// back. `language` is left out when it is unknown.
+pub struct HttpAnalyzer {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpAnalyzer {
+    pub fn new(url: String) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(ANALYZER_TIMEOUT)
+            .build()
+            .context("Failed to build analyzer HTTP client")?;
+        Ok(HttpAnalyzer { client, url })
+    }
+
+    /// Returns `None` when `COLLABORATE_ANALYZER_URL` is unset, i.e. analysis is disabled.
+    pub fn from_env() -> Result<Option<Self>> {
+        match env::var(ANALYZER_URL_ENV) {
+            Ok(url) => Ok(Some(HttpAnalyzer::new(url)?)),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl Analyzer for HttpAnalyzer {
+    async fn analyze(&self, text: &str, language: Option<&str>) -> Result<Vec<Annotation>> {
+        let response: AnalysisResponse = self.client
+            .post(&self.url)
+            .json(&AnalysisRequest { text, language })
+            .send()
+            .await
+            .context(format!("Failed to reach analyzer at {}", self.url))?
+            .error_for_status()
+            .context("Analyzer returned an error")?
+            .json()
+            .await
+            .context("Analyzer returned a malformed response")?;
+        Ok(response.annotations)
+    }
+}
+
+/// An extractor paired with the analyzer its text goes to.
+#[derive(Clone)]
+pub struct ContentAnalysis {
+    extractor: Arc<dyn TextExtractor>,
+    analyzer: Arc<dyn Analyzer>,
+}
+
+impl ContentAnalysis {
+    pub fn new(extractor: Arc<dyn TextExtractor>, analyzer: Arc<dyn Analyzer>) -> Self {
+        ContentAnalysis { extractor, analyzer }
+    }
+
+    /// Annotations for a version of a document's content. Annotations whose range falls
+    /// outside the text are dropped so clients can trust the anchors. The language is
+    /// detected in the text analyzed rather than read from the document's metadata, which
+    /// only follows snapshots.
+    pub async fn analyze(&self, content: &[u8]) -> Result<Vec<Annotation>> {
+        let Some(text) = self.extractor.extract(content) else {
+            return Ok(Vec::new());
+        };
+        let length = text.chars().count();
+        let mut annotations = self.analyzer.analyze(&text, language::detect_text(&text)).await?;
+        annotations.retain(|annotation| annotation.start <= annotation.end && annotation.end <= length);
+        Ok(annotations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flags every occurrence of "teh".
+    struct TypoAnalyzer;
+
+    #[async_trait]
+    impl Analyzer for TypoAnalyzer {
+        async fn analyze(&self, text: &str, _language: Option<&str>) -> Result<Vec<Annotation>> {
+            let chars: Vec<char> = text.chars().collect();
+            let mut annotations: Vec<Annotation> = chars
+                .windows(3)
+                .enumerate()
+                .filter(|(_, window)| window.iter().collect::<String>() == "teh")
+                .map(|(start, _)| Annotation {
+                    start,
+                    end: start + 3,
+                    kind: "spelling".to_string(),
+                    message: "Possible typo".to_string(),
+                    suggestion: Some("the".to_string()),
+                })
+                .collect();
+            // A misbehaving analyzer pointing past the end of the text.
+            annotations.push(Annotation { start: 0, end: chars.len() + 1, kind: "bogus".to_string(), message: String::new(), suggestion: None });
+            Ok(annotations)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_annotations_are_anchored_to_text() -> Result<()> {
+        let analysis = ContentAnalysis::new(Arc::new(Utf8Extractor), Arc::new(TypoAnalyzer));
+        let annotations = analysis.analyze("Über teh hill".as_bytes()).await?;
+        assert_eq!(annotations.len(), 1);
+        assert_eq!((annotations[0].start, annotations[0].end), (5, 8));
+
+        assert!(analysis.analyze(&[0xff, 0xfe]).await?.is_empty());
+        Ok(())
+    }
+
+    /// Reports the language it was given.
+    struct LanguageAnalyzer;
+
+    #[async_trait]
+    impl Analyzer for LanguageAnalyzer {
+        async fn analyze(&self, _text: &str, language: Option<&str>) -> Result<Vec<Annotation>> {
+            let message = language.unwrap_or("unknown").to_string();
+            Ok(vec![Annotation { start: 0, end: 0, kind: "language".to_string(), message, suggestion: None }])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_analyzers_are_told_the_language() -> Result<()> {
+        let analysis = ContentAnalysis::new(Arc::new(Utf8Extractor), Arc::new(LanguageAnalyzer));
+        let annotations = analysis.analyze("Nous avons relu le document et corrigé les fautes d'orthographe.".as_bytes()).await?;
+        assert_eq!(annotations[0].message, "fr");
+        let annotations = analysis.analyze(b"ok").await?;
+        assert_eq!(annotations[0].message, "unknown");
+        Ok(())
+    }
+}
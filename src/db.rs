@@ -1,8 +1,69 @@
 use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
 use sqlx::{Executor, PgPool};
 use std::sync::Arc;
+use std::future::Future;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use crate::config::{self, AppConfig};
+use tracing::{info, warn};
+
+/// How persistently to connect at startup, when the database may still be coming up.
+/// Attempts after the first wait twice as long as the one before, from `initial_delay`
+/// up to `max_delay`, each scaled by a random factor within `jitter` of 1 so that many
+/// nodes restarting together do not retry in lockstep.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Including the first; 1 gives up after a single failure.
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Between 0 and 1.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before retrying after `attempt` failed attempts.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.initial_delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))).min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return backoff;
+        }
+        backoff.mul_f64(rand::random_range(1.0 - jitter..=1.0 + jitter))
+    }
+
+    /// Runs `connect` until it succeeds or `max_attempts` have failed, returning the last error.
+    async fn retry<T, F: Future<Output = Result<T>>>(&self, mut connect: impl FnMut() -> F) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match connect().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt >= self.max_attempts => {
+                    return Err(e.context(format!("Giving up on the database after {} attempts", attempt)));
+                }
+                Err(e) => {
+                    let delay = self.delay(attempt);
+                    warn!("Database not reachable (attempt {} of {}), retrying in {:?}: {:#}", attempt, self.max_attempts, delay, e);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Manager {
@@ -15,11 +76,22 @@ impl Manager {
     ///
     /// # Arguments
     /// * `base_uri` - The base URI to connect to CockroachDB, typically pointing to a default
-    ///                database like `defaultdb` or `postgres`. This connection is used to
-    ///                create the application-specific database if it doesn't exist.
-    ///                Example for your Docker setup: "postgres://root@localhost:26257/defaultdb?sslmode=disable"
+    ///   database like `defaultdb` or `postgres`. This connection is used to
+    ///   create the application-specific database if it doesn't exist.
+    ///   Example for your Docker setup: "root@localhost:26257"
     /// * `app_db_name` - The name of the application-specific database to use or create (e.g., "collaborate_app").
+    ///
+    /// Retries as the default [`RetryPolicy`] allows if the database is not up yet.
     pub async fn new(base_uri: &str, app_db_name: &str) -> Result<Self> {
+        Manager::new_with_retry(base_uri, app_db_name, &RetryPolicy::default()).await
+    }
+
+    /// Like [`Manager::new`], retrying as `retry` allows.
+    pub async fn new_with_retry(base_uri: &str, app_db_name: &str, retry: &RetryPolicy) -> Result<Self> {
+        retry.retry(|| Manager::connect(base_uri, app_db_name, config::DEFAULT_MAX_CONNECTIONS)).await
+    }
+
+    async fn connect(base_uri: &str, app_db_name: &str, max_connections: u32) -> Result<Self> {
         // 1. Connect to the base URI (e.g., pointing to defaultdb) to be able to create the app_db_name
         let initial_pool_options = PgPoolOptions::new()
             .max_connections(5)
@@ -38,7 +110,7 @@ impl Manager {
             .await
             .context(format!("Failed to create database: {}", app_db_name))?;
         
-        println!("Successfully ensured database '{}' exists.", app_db_name);
+        info!("Successfully ensured database '{}' exists.", app_db_name);
         
         // Close the initial pool as we'll create a new one specifically for the application database.
         initial_pool.close().await;
@@ -51,24 +123,143 @@ impl Manager {
         app_conn_options = app_conn_options.database(app_db_name);
         
         // 4. Connect to the application-specific database with a new pool.
+        //    Connections are checked before being handed out, so those broken while the
+        //    database was away are replaced rather than failing the query that gets them.
         let app_pool_options = PgPoolOptions::new()
-            .max_connections(10) // Configure based on your application's needs
-            .acquire_timeout(std::time::Duration::from_secs(10));
+            .max_connections(max_connections)
+            .acquire_timeout(std::time::Duration::from_secs(10))
+            .test_before_acquire(true);
 
         let app_pool = app_pool_options
             .connect_with(app_conn_options.clone()) // PgConnectOptions implements Clone
             .await
             .context(format!("Failed to connect to CockroachDB application database: {}", app_db_name))?;
 
-        println!("Successfully connected to CockroachDB database '{}'", app_db_name);
+        info!("Successfully connected to CockroachDB database '{}'", app_db_name);
         
         Ok(Manager { pool: Arc::new(app_pool) })
     }
 
+    /// Connects to the first reachable configured endpoint, trying those in this
+    /// instance's region first. Each attempt tries every endpoint; attempts are retried as
+    /// `database.connect_retry` allows.
+    pub async fn from_config(config: &AppConfig) -> Result<Self> {
+        config.database.connect_retry.retry(|| Manager::connect_any(config)).await
+    }
+
+    async fn connect_any(config: &AppConfig) -> Result<Self> {
+        let mut last_err = None;
+        for endpoint in config.region.preferred_endpoints() {
+            let base_uri = &endpoint.base_uri;
+            match Manager::connect(base_uri, &config.database.name, config.database.max_connections).await {
+                Ok(manager) => return Ok(manager),
+                Err(e) => {
+                    warn!("Failed to connect via {}: {:#}", base_uri, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("No database endpoints configured")))
+    }
+
+    /// Pings the database every `interval`, logging when it stops answering and when it
+    /// answers again. The pool replaces broken connections by itself; this makes the outage
+    /// visible rather than leaving it to be inferred from failing requests.
+    pub async fn run_monitor(self: Arc<Self>, interval: Duration) {
+        let mut reachable = true;
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            match self.ping().await {
+                Ok(_) if !reachable => {
+                    info!("Reconnected to the database");
+                    reachable = true;
+                }
+                Ok(_) => {}
+                Err(e) if reachable => {
+                    warn!("Lost connection to the database: {:#}", e);
+                    reachable = false;
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
     /// Example method to check the connection by executing a simple query.
     pub async fn check_connection(&self) -> Result<()> {
         sqlx::query("SELECT 1").execute(&*self.pool).await?;
-        println!("Connection check to CockroachDB successful.");
+        info!("Connection check to CockroachDB successful.");
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Waits for checked-out connections to be returned, then closes them all.
+    pub async fn close(&self) {
+        self.pool.close().await;
+        info!("Closed database connections.");
+    }
+
+    /// Round-trip time of a trivial query.
+    pub async fn ping(&self) -> Result<Duration> {
+        let started = Instant::now();
+        sqlx::query("SELECT 1").execute(&*self.pool).await?;
+        Ok(started.elapsed())
+    }
+
+    /// The database's clock and the round-trip time of reading it, so callers can place the
+    /// reading at the midpoint of the round trip.
+    pub async fn clock(&self) -> Result<(DateTime<Utc>, Duration)> {
+        let started = Instant::now();
+        let now: DateTime<Utc> = sqlx::query_scalar("SELECT now()").fetch_one(&*self.pool).await?;
+        Ok((now, started.elapsed()))
+    }
+
+    /// Time to begin, read in and commit a serializable transaction.
+    pub async fn transaction_latency(&self) -> Result<Duration> {
+        let started = Instant::now();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE").execute(&mut *tx).await?;
+        sqlx::query("SELECT 1").execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(started.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delays_back_off_with_jitter() {
+        let policy = RetryPolicy { jitter: 0.0, ..RetryPolicy::default() };
+        assert_eq!(policy.delay(1), Duration::from_millis(500));
+        assert_eq!(policy.delay(3), Duration::from_secs(2));
+        assert_eq!(policy.delay(100), Duration::from_secs(30));
+
+        let policy = RetryPolicy::default();
+        for _ in 0..100 {
+            let delay = policy.delay(2);
+            assert!(delay >= Duration::from_millis(800) && delay <= Duration::from_millis(1200), "{:?}", delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connecting_gives_up_after_max_attempts() {
+        let policy = RetryPolicy { max_attempts: 3, initial_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1), jitter: 0.5 };
+        let mut attempts = 0;
+        let result: Result<()> = policy.retry(|| {
+            attempts += 1;
+            async { Err(anyhow::anyhow!("connection refused")) }
+        }).await;
+        assert!(format!("{:#}", result.unwrap_err()).contains("after 3 attempts"));
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let result = policy.retry(|| {
+            attempts += 1;
+            let attempt = attempts;
+            async move { if attempt < 2 { Err(anyhow::anyhow!("not yet")) } else { Ok(attempt) } }
+        }).await;
+        assert_eq!(result.unwrap(), 2);
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Content deltas: the one contiguous span an update replaced, so a small edit to a large
+//! document can be stored without rewriting all of it.
+
+use anyhow::{bail, Result};
+
+/// Where `old` and `new` differ: `old[start..old_end]` became `new[start..new_end]`.
+/// `None` if they are identical.
+pub(crate) fn changed_span(old: &[u8], new: &[u8]) -> Option<(usize, usize, usize)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    if prefix == old.len() && prefix == new.len() {
+        return None;
+    }
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    Some((prefix, old.len() - suffix, new.len() - suffix))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContentDelta {
+    pub start: usize,
+    /// Bytes removed at `start`.
+    pub delete_len: usize,
+    /// Bytes inserted at `start` in their place.
+    pub insert: Vec<u8>,
+}
+
+impl ContentDelta {
+    /// The delta turning `old` into `new`; empty if they are identical.
+    pub fn between(old: &[u8], new: &[u8]) -> Self {
+        match changed_span(old, new) {
+            Some((start, old_end, new_end)) => ContentDelta {
+                start,
+                delete_len: old_end - start,
+                insert: new[start..new_end].to_vec(),
+            },
+            None => ContentDelta { start: new.len(), delete_len: 0, insert: Vec::new() },
+        }
+    }
+
+    pub fn apply(&self, content: &mut Vec<u8>) -> Result<()> {
+        let end = self.start + self.delete_len;
+        if end > content.len() {
+            bail!("Delta replaces bytes {}..{} of content {} bytes long", self.start, end, content.len());
+        }
+        content.splice(self.start..end, self.insert.iter().copied());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deltas_round_trip() {
+        let cases: [(&[u8], &[u8]); 5] = [
+            (b"hello world", b"hello brave world"),
+            (b"hello world", b"hello"),
+            (b"", b"new"),
+            (b"abcabc", b"abc"),
+            (b"same", b"same"),
+        ];
+        for (old, new) in cases {
+            let delta = ContentDelta::between(old, new);
+            let mut content = old.to_vec();
+            delta.apply(&mut content).unwrap();
+            assert_eq!(content, new);
+        }
+        assert_eq!(ContentDelta::between(b"hello world", b"hello brave world").insert, b"brave ");
+        assert!(ContentDelta { start: 3, delete_len: 2, insert: Vec::new() }.apply(&mut b"abcd".to_vec()).is_err());
+    }
+}
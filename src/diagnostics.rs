@@ -0,0 +1,237 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Self-diagnostics for support escalations. `GET /admin/diagnostics` runs every check
+//! below against the node's dependencies and grades each pass, warn or fail, so the
+//! report can be attached to a ticket as is.
+//!
+//! Documents, locks and conditional writes all live in the SQL database, so its
+//! serializable transactions are checked where a store with lightweight transactions
+//! would have those checked instead.
+
+use crate::attachments::AttachmentService;
+use crate::concurrency::ConcurrencyLimits;
+use crate::db::Manager;
+use crate::document_room::RoomRegistry;
+use crate::email::Mailer;
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long any one check may take before it fails.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const DATABASE_WARN: Duration = Duration::from_millis(100);
+const TRANSACTION_WARN: Duration = Duration::from_millis(250);
+const BLOB_STORE_WARN: Duration = Duration::from_secs(1);
+/// Clocks further apart than this make token expiry and retention windows unreliable.
+const CLOCK_SKEW_WARN: Duration = Duration::from_millis(500);
+const CLOCK_SKEW_FAIL: Duration = Duration::from_secs(30);
+
+/// Ordered so the worst of several is the greatest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    /// What was measured or what went wrong.
+    pub detail: String,
+    pub latency_ms: Option<f64>,
+}
+
+impl CheckResult {
+    fn new(name: &'static str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        CheckResult { name, status, detail: detail.into(), latency_ms: None }
+    }
+
+    fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency_ms = Some(latency.as_secs_f64() * 1000.0);
+        self
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DiagnosticsReport {
+    pub at: DateTime<Utc>,
+    pub region: Option<String>,
+    /// The worst status of any check.
+    pub status: CheckStatus,
+    pub checks: Vec<CheckResult>,
+}
+
+impl DiagnosticsReport {
+    fn new(region: Option<String>, checks: Vec<CheckResult>) -> Self {
+        let status = checks.iter().map(|check| check.status).max().unwrap_or(CheckStatus::Pass);
+        DiagnosticsReport { at: Utc::now(), region, status, checks }
+    }
+}
+
+/// Runs the checks against this node's dependencies.
+pub struct Diagnostics {
+    db_manager: Arc<Manager>,
+    rooms: Arc<RoomRegistry>,
+    attachments: Option<Arc<AttachmentService>>,
+    mailer: Option<Arc<dyn Mailer>>,
+}
+
+impl Diagnostics {
+    pub fn new(db_manager: Arc<Manager>, rooms: Arc<RoomRegistry>) -> Self {
+        Diagnostics { db_manager, rooms, attachments: None, mailer: None }
+    }
+
+    pub fn with_attachments(mut self, attachments: Arc<AttachmentService>) -> Self {
+        self.attachments = Some(attachments);
+        self
+    }
+
+    pub fn with_mailer(mut self, mailer: Arc<dyn Mailer>) -> Self {
+        self.mailer = Some(mailer);
+        self
+    }
+
+    /// Runs every check concurrently. `concurrency` is the server's, for queue depth.
+    pub async fn run(&self, region: Option<String>, concurrency: &ConcurrencyLimits) -> DiagnosticsReport {
+        let (database, transaction, clock, blob_store, email) = tokio::join!(
+            self.check_database(),
+            self.check_transaction(),
+            self.check_clock(),
+            self.check_blob_store(),
+            self.check_email(),
+        );
+        let queues = self.check_queues(concurrency);
+        DiagnosticsReport::new(region, vec![database, transaction, clock, blob_store, email, queues])
+    }
+
+    async fn check_database(&self) -> CheckResult {
+        match timed(self.db_manager.ping()).await {
+            Ok(latency) => grade_latency("database", latency, DATABASE_WARN),
+            Err(e) => CheckResult::new("database", CheckStatus::Fail, format!("{:#}", e)),
+        }
+    }
+
+    async fn check_transaction(&self) -> CheckResult {
+        match timed(self.db_manager.transaction_latency()).await {
+            Ok(latency) => grade_latency("transaction", latency, TRANSACTION_WARN),
+            Err(e) => CheckResult::new("transaction", CheckStatus::Fail, format!("{:#}", e)),
+        }
+    }
+
+    async fn check_clock(&self) -> CheckResult {
+        let sent = Utc::now();
+        match timed(self.db_manager.clock()).await {
+            Ok((db_now, round_trip)) => {
+                let midpoint = sent + TimeDelta::from_std(round_trip / 2).unwrap_or_default();
+                grade_skew(db_now - midpoint).with_latency(round_trip)
+            }
+            Err(e) => CheckResult::new("clock_skew", CheckStatus::Fail, format!("{:#}", e)),
+        }
+    }
+
+    async fn check_blob_store(&self) -> CheckResult {
+        let Some(attachments) = &self.attachments else {
+            return CheckResult::new("blob_store", CheckStatus::Pass, "attachments are disabled");
+        };
+        match timed(attachments.probe_store()).await {
+            Ok(latency) => grade_latency("blob_store", latency, BLOB_STORE_WARN),
+            Err(e) => CheckResult::new("blob_store", CheckStatus::Fail, format!("{:#}", e)),
+        }
+    }
+
+    async fn check_email(&self) -> CheckResult {
+        let Some(mailer) = &self.mailer else {
+            return CheckResult::new("email", CheckStatus::Pass, "email is disabled");
+        };
+        let started = std::time::Instant::now();
+        match timed(mailer.test_connection()).await {
+            Ok(()) => CheckResult::new("email", CheckStatus::Pass, "transport reachable").with_latency(started.elapsed()),
+            Err(e) => CheckResult::new("email", CheckStatus::Fail, format!("{:#}", e)),
+        }
+    }
+
+    fn check_queues(&self, concurrency: &ConcurrencyLimits) -> CheckResult {
+        let queues = concurrency.snapshot();
+        let backed_up: Vec<String> = queues.iter()
+            .filter(|queue| queue.waiting >= queue.limit)
+            .map(|queue| format!("{} ({} waiting for {} slots)", queue.class, queue.waiting, queue.limit))
+            .collect();
+        let waiting: usize = queues.iter().map(|queue| queue.waiting).sum();
+        let pending_updates = self.rooms.stats().pending_updates();
+        let detail = format!("{} requests queued, {} updates waiting to be saved", waiting, pending_updates);
+        if backed_up.is_empty() {
+            CheckResult::new("queue_depth", CheckStatus::Pass, detail)
+        } else {
+            CheckResult::new("queue_depth", CheckStatus::Warn, format!("{}; backed up: {}", detail, backed_up.join(", ")))
+        }
+    }
+}
+
+/// Runs a check, failing it if it takes longer than `CHECK_TIMEOUT`.
+async fn timed<T>(check: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out after {:?}", CHECK_TIMEOUT)))
+}
+
+fn grade_latency(name: &'static str, latency: Duration, warn: Duration) -> CheckResult {
+    let status = if latency >= warn { CheckStatus::Warn } else { CheckStatus::Pass };
+    CheckResult::new(name, status, format!("{:.1} ms", latency.as_secs_f64() * 1000.0)).with_latency(latency)
+}
+
+/// `skew` is the database's clock minus this node's.
+fn grade_skew(skew: TimeDelta) -> CheckResult {
+    let magnitude = skew.abs().to_std().unwrap_or(Duration::MAX);
+    let status = if magnitude >= CLOCK_SKEW_FAIL {
+        CheckStatus::Fail
+    } else if magnitude >= CLOCK_SKEW_WARN {
+        CheckStatus::Warn
+    } else {
+        CheckStatus::Pass
+    };
+    let direction = if skew < TimeDelta::zero() { "behind" } else { "ahead of" };
+    CheckResult::new("clock_skew", status, format!("database clock is {} ms {} this node's", magnitude.as_millis(), direction))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_takes_the_worst_status() {
+        let report = DiagnosticsReport::new(None, vec![
+            grade_latency("database", Duration::from_millis(5), DATABASE_WARN),
+            grade_latency("blob_store", Duration::from_secs(2), BLOB_STORE_WARN),
+        ]);
+        assert_eq!(report.checks[0].status, CheckStatus::Pass);
+        assert_eq!(report.status, CheckStatus::Warn);
+        assert_eq!(DiagnosticsReport::new(None, Vec::new()).status, CheckStatus::Pass);
+    }
+
+    #[test]
+    fn test_clock_skew_is_graded_either_way() {
+        assert_eq!(grade_skew(TimeDelta::milliseconds(20)).status, CheckStatus::Pass);
+        assert_eq!(grade_skew(TimeDelta::milliseconds(-800)).status, CheckStatus::Warn);
+        let far = grade_skew(TimeDelta::minutes(-2));
+        assert_eq!(far.status, CheckStatus::Fail);
+        assert_eq!(far.detail, "database clock is 120000 ms behind this node's");
+    }
+}
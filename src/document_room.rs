@@ -0,0 +1,1633 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::broadcaster::{Broadcaster, Relay, RelayedEvent};
+use crate::close_code::CloseCode;
+use crate::cluster::{ClusterConfig, ClusterPeer, Releases, RemoteRoom};
+use crate::consistency::CausalityToken;
+use crate::content_analysis::{AnnotationSet, ContentAnalysis};
+use crate::document_service::{CommentChange, DocumentService, DocumentTooLarge};
+use crate::hooks::HookRejection;
+use crate::presence::{self, Participant, PresenceChange, PresenceMap, PresenceUpdate};
+use crate::protected_ranges::{self, ProtectedRange, ProtectionChange};
+use crate::storage_quota::QuotaExceeded;
+use crate::user_service::DocumentRole;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+const COMMAND_QUEUE_CAPACITY: usize = 256;
+const EVENT_QUEUE_CAPACITY: usize = 256;
+const PRESENCE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often `RoomRegistry::flush` checks whether rooms have saved everything.
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Roughly how far back a room's update rate looks.
+const UPDATE_RATE_WINDOW: Duration = Duration::from_secs(10);
+/// A connection's message limit violations are forgotten after this long without another.
+const VIOLATION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Bounds on the unsaved state a room may hold while persistence is failing.
+#[derive(Clone, Debug)]
+pub struct WriteBufferConfig {
+    /// Maximum number of updates queued for persistence.
+    pub max_pending_updates: usize,
+    /// Maximum total size of the updates queued for persistence.
+    pub max_pending_bytes: usize,
+    /// How long a room keeps accepting updates after persistence starts failing.
+    pub max_outage: Duration,
+    /// How often a failing room retries persisting its queue.
+    pub retry_interval: Duration,
+}
+
+impl Default for WriteBufferConfig {
+    fn default() -> Self {
+        WriteBufferConfig {
+            max_pending_updates: 1024,
+            max_pending_bytes: 16 * 1024 * 1024,
+            max_outage: Duration::from_secs(60),
+            retry_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// How long a room holds new content before saving it. Each room picks a delay between
+/// `min_delay` and `max_delay` from its content size and recent update rate: small or quiet
+/// documents are saved promptly, while large documents under heavy editing wait so that
+/// one write covers many updates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutosavePolicy {
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// Content size at and beyond which a document waits the full `max_delay` when busy.
+    pub large_document_bytes: usize,
+    /// Update rate at and beyond which a document waits the full `max_delay` when large.
+    pub busy_updates_per_second: f64,
+}
+
+impl Default for AutosavePolicy {
+    fn default() -> Self {
+        AutosavePolicy {
+            min_delay: Duration::ZERO,
+            max_delay: Duration::from_secs(5),
+            large_document_bytes: 1024 * 1024,
+            busy_updates_per_second: 2.0,
+        }
+    }
+}
+
+impl AutosavePolicy {
+    /// The delay for a document of `content_bytes` receiving `updates_per_second`. A save
+    /// only waits long when it is both expensive and likely to be superseded soon.
+    pub fn delay(&self, content_bytes: usize, updates_per_second: f64) -> Duration {
+        let size = (content_bytes as f64 / self.large_document_bytes.max(1) as f64).min(1.0);
+        let activity = (updates_per_second / self.busy_updates_per_second.max(f64::MIN_POSITIVE)).clamp(0.0, 1.0);
+        self.min_delay + self.max_delay.saturating_sub(self.min_delay).mul_f64(size * activity)
+    }
+}
+
+/// Updates per second, decaying exponentially over `UPDATE_RATE_WINDOW`.
+#[derive(Default)]
+struct UpdateRate {
+    rate: f64,
+    last: Option<Instant>,
+}
+
+impl UpdateRate {
+    fn record(&mut self, now: Instant) {
+        self.rate = self.at(now) + 1.0 / UPDATE_RATE_WINDOW.as_secs_f64();
+        self.last = Some(now);
+    }
+
+    fn at(&self, now: Instant) -> f64 {
+        match self.last {
+            Some(last) => {
+                let elapsed = now.saturating_duration_since(last).as_secs_f64();
+                self.rate * (-elapsed / UPDATE_RATE_WINDOW.as_secs_f64()).exp()
+            }
+            None => 0.0,
+        }
+    }
+}
+
+/// Which per-connection message limits apply to a WebSocket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrafficClass {
+    /// Anonymous visitors and guests.
+    Guest,
+    /// Signed-in users, and the bots and apps acting for them.
+    Editor,
+}
+
+/// How much a single connection may send: updates no larger than `max_frame_bytes`, at
+/// `updates_per_second` sustained with bursts of up to `burst` updates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MessageLimit {
+    pub max_frame_bytes: usize,
+    pub updates_per_second: f64,
+    pub burst: u32,
+}
+
+/// Per-connection message limits by traffic class. An update over its connection's limit
+/// is refused with a warning; the connection is disconnected once it has been warned
+/// `warnings` times within `VIOLATION_WINDOW` of each other.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MessageLimits {
+    pub guest: MessageLimit,
+    pub editor: MessageLimit,
+    pub warnings: u32,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        MessageLimits {
+            guest: MessageLimit { max_frame_bytes: 1024 * 1024, updates_per_second: 10.0, burst: 30 },
+            editor: MessageLimit { max_frame_bytes: 16 * 1024 * 1024, updates_per_second: 30.0, burst: 120 },
+            warnings: 3,
+        }
+    }
+}
+
+impl MessageLimits {
+    /// The largest update any traffic class may send.
+    pub fn max_frame_bytes(&self) -> usize {
+        self.guest.max_frame_bytes.max(self.editor.max_frame_bytes)
+    }
+
+    fn limit(&self, class: TrafficClass) -> &MessageLimit {
+        match class {
+            TrafficClass::Guest => &self.guest,
+            TrafficClass::Editor => &self.editor,
+        }
+    }
+}
+
+/// Each connection's standing against its message limit, kept by the actor it sends to.
+pub(crate) struct SenderLimits {
+    limits: MessageLimits,
+    senders: HashMap<u64, SenderState>,
+}
+
+struct SenderState {
+    class: TrafficClass,
+    // A token bucket holding up to `burst` updates, refilled at `updates_per_second`.
+    tokens: f64,
+    refilled: Instant,
+    violations: u32,
+    last_violation: Option<Instant>,
+}
+
+impl SenderLimits {
+    pub(crate) fn new(limits: MessageLimits) -> Self {
+        SenderLimits { limits, senders: HashMap::new() }
+    }
+
+    /// Counts an update of `bytes` from the connection identified by `origin` against its
+    /// limit. Fails with `RateLimited` or `FrameTooLarge` as a warning, or with
+    /// `LimitExceeded` once the connection has run out of warnings.
+    pub(crate) fn check(&mut self, origin: u64, class: TrafficClass, bytes: usize, now: Instant) -> Result<(), UpdateRejection> {
+        let limit = *self.limits.limit(class);
+        let sender = self.senders.entry(origin).or_insert_with(|| SenderState {
+            class,
+            tokens: limit.burst as f64,
+            refilled: now,
+            violations: 0,
+            last_violation: None,
+        });
+        let elapsed = now.saturating_duration_since(sender.refilled).as_secs_f64();
+        sender.tokens = (sender.tokens + elapsed * limit.updates_per_second).min(limit.burst as f64);
+        sender.refilled = now;
+        let violation = if bytes > limit.max_frame_bytes {
+            UpdateRejection::FrameTooLarge
+        } else if sender.tokens < 1.0 {
+            UpdateRejection::RateLimited
+        } else {
+            sender.tokens -= 1.0;
+            return Ok(());
+        };
+        if sender.last_violation.is_some_and(|last| now.saturating_duration_since(last) > VIOLATION_WINDOW) {
+            sender.violations = 0;
+        }
+        sender.violations += 1;
+        sender.last_violation = Some(now);
+        if sender.violations > self.limits.warnings {
+            return Err(UpdateRejection::LimitExceeded);
+        }
+        Err(violation)
+    }
+
+    /// Forgets connections that would start afresh anyway, including ones that have left.
+    pub(crate) fn prune(&mut self, now: Instant) {
+        let limits = &self.limits;
+        self.senders.retain(|_, sender| {
+            let limit = limits.limit(sender.class);
+            let refill = Duration::from_secs_f64(limit.burst as f64 / limit.updates_per_second.max(f64::MIN_POSITIVE));
+            let idle = now.saturating_duration_since(sender.refilled);
+            idle < refill.max(VIOLATION_WINDOW)
+        });
+    }
+}
+
+/// Whether everything broadcast in a room has been persisted.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SaveStatus {
+    Saved,
+    Unsaved { pending_updates: usize },
+    /// A save hook refused an update, or its owner is out of storage; it was dropped rather
+    /// than retried.
+    Rejected { reason: String },
+}
+
+#[derive(Clone, Debug)]
+pub enum RoomEvent {
+    /// Content submitted by the connection identified by `origin`.
+    Update { origin: u64, data: Arc<Vec<u8>> },
+    SaveStatus(SaveStatus),
+    /// Findings from the content analysis service for the latest saved version.
+    Annotations(Arc<AnnotationSet>),
+    /// A participant joined, moved their cursor or left.
+    Presence(PresenceChange),
+    Notice(RoomNotice),
+}
+
+/// Something every connection to a room must act on, wherever in the cluster it is.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "notice", content = "code", rename_all = "snake_case")]
+pub enum RoomNotice {
+    /// Connections should close with this code.
+    Closing(CloseCode),
+    /// Who may access the document changed; connections should check theirs again.
+    AccessChanged,
+    /// Connections that may read the document should pass this on to their client.
+    Comment(CommentChange),
+    /// The node hosting the room is shutting down and handing it over; connections should
+    /// tell their client to expect a `Closing(ShuttingDown)` and reconnect.
+    Migrating,
+    /// The document's time ran out and it takes no more edits.
+    Expired,
+}
+
+/// Why a room refused an update.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpdateRejection {
+    /// The unsaved update queue hit its size cap.
+    BufferFull,
+    /// Persistence has been failing for longer than the configured window.
+    OutageTooLong,
+    /// The update edits a protected range the submitter's role may not edit.
+    ProtectedRange,
+    /// A range to protect does not lie within the document.
+    InvalidRange,
+    /// The connection is sending updates faster than its traffic class allows.
+    RateLimited,
+    /// The update is larger than the connection's traffic class allows.
+    FrameTooLarge,
+    /// The connection kept exceeding its message limits after being warned, and should
+    /// be disconnected.
+    LimitExceeded,
+    /// The room is being handed to another node; the update should be sent again there.
+    Migrating,
+    /// The document was created to stop taking edits at a time that has passed.
+    Expired,
+    /// The update would make the document larger than the configured limit; see
+    /// `DocumentService::with_max_document_bytes`.
+    DocumentTooLarge,
+}
+
+impl fmt::Display for UpdateRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateRejection::BufferFull => write!(f, "Too many unsaved updates; try again once the document is saved"),
+            UpdateRejection::OutageTooLong => write!(f, "Document storage is unavailable; updates are not being accepted"),
+            UpdateRejection::ProtectedRange => write!(f, "Update modifies a protected range"),
+            UpdateRejection::InvalidRange => write!(f, "Protected range is empty or outside the document"),
+            UpdateRejection::RateLimited => write!(f, "Too many updates; slow down"),
+            UpdateRejection::FrameTooLarge => write!(f, "Update is larger than this connection may send"),
+            UpdateRejection::LimitExceeded => write!(f, "Message limits exceeded too often"),
+            UpdateRejection::Migrating => write!(f, "The document is moving to another server; send the update again after reconnecting"),
+            UpdateRejection::Expired => write!(f, "The document has expired and is read-only"),
+            UpdateRejection::DocumentTooLarge => write!(f, "Update would make the document larger than allowed"),
+        }
+    }
+}
+
+impl std::error::Error for UpdateRejection {}
+
+impl UpdateRejection {
+    /// The i18n message key shown to users.
+    pub fn message_key(&self) -> &'static str {
+        match self {
+            UpdateRejection::BufferFull => "update-buffer-full",
+            UpdateRejection::OutageTooLong => "update-storage-unavailable",
+            UpdateRejection::ProtectedRange => "update-protected-range",
+            UpdateRejection::InvalidRange => "invalid-protected-range",
+            UpdateRejection::RateLimited => "update-rate-limited",
+            UpdateRejection::FrameTooLarge => "update-too-large",
+            UpdateRejection::LimitExceeded => "message-limit-exceeded",
+            UpdateRejection::Migrating => "update-server-migrating",
+            UpdateRejection::Expired => "update-document-expired",
+            UpdateRejection::DocumentTooLarge => "update-document-too-large",
+        }
+    }
+
+    pub fn from_message_key(key: &str) -> Option<Self> {
+        match key {
+            "update-buffer-full" => Some(UpdateRejection::BufferFull),
+            "update-storage-unavailable" => Some(UpdateRejection::OutageTooLong),
+            "update-protected-range" => Some(UpdateRejection::ProtectedRange),
+            "invalid-protected-range" => Some(UpdateRejection::InvalidRange),
+            "update-rate-limited" => Some(UpdateRejection::RateLimited),
+            "update-too-large" => Some(UpdateRejection::FrameTooLarge),
+            "message-limit-exceeded" => Some(UpdateRejection::LimitExceeded),
+            "update-server-migrating" => Some(UpdateRejection::Migrating),
+            "update-document-expired" => Some(UpdateRejection::Expired),
+            "update-document-too-large" => Some(UpdateRejection::DocumentTooLarge),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) enum RoomCommand {
+    Update {
+        origin: u64,
+        /// Who the update is attributed to; see `blame`.
+        author: Option<Uuid>,
+        role: DocumentRole,
+        /// Set for updates from a WebSocket, which are held to the class's message limits.
+        class: Option<TrafficClass>,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Snapshot {
+        reply: oneshot::Sender<Result<Option<Vec<u8>>>>,
+    },
+    ChangeProtection {
+        change: ProtectionChange,
+        reply: oneshot::Sender<Result<Vec<ProtectedRange>>>,
+    },
+    Presence {
+        origin: u64,
+        update: PresenceUpdate,
+    },
+    Participants {
+        reply: oneshot::Sender<Result<Vec<Participant>>>,
+    },
+    Notify {
+        notice: RoomNotice,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Broadcast by the room for the same document on another node.
+    Relayed(RelayedEvent),
+    /// This node is shutting down: stop taking updates, save what is queued and tell
+    /// connections to expect the room to move.
+    Migrate,
+}
+
+/// A connection's handle on a document room.
+#[derive(Clone)]
+pub struct RoomHandle {
+    commands: mpsc::Sender<RoomCommand>,
+    events: broadcast::Sender<RoomEvent>,
+}
+
+impl RoomHandle {
+    /// Broadcasts an update by `author` to the room and queues it for persistence. Fails
+    /// with `UpdateRejection::ProtectedRange` if it edits a range `role` may not edit.
+    pub async fn submit_update(&self, origin: u64, author: Option<Uuid>, role: DocumentRole, data: Vec<u8>) -> Result<()> {
+        self.submit(origin, author, role, None, data).await
+    }
+
+    /// Like `submit_update`, for an update from a WebSocket, which is first counted against
+    /// the message limits of `class`. Fails with `UpdateRejection::RateLimited` or
+    /// `FrameTooLarge` as a warning, and with `LimitExceeded` once the connection should go.
+    pub async fn submit_frame(
+        &self,
+        origin: u64,
+        author: Option<Uuid>,
+        role: DocumentRole,
+        class: TrafficClass,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        self.submit(origin, author, role, Some(class), data).await
+    }
+
+    async fn submit(&self, origin: u64, author: Option<Uuid>, role: DocumentRole, class: Option<TrafficClass>, data: Vec<u8>) -> Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(RoomCommand::Update { origin, author, role, class, data, reply })
+            .await
+            .map_err(|_| anyhow!("Document room has shut down"))?;
+        response.await.map_err(|_| anyhow!("Document room has shut down"))??;
+        Ok(())
+    }
+
+    /// The latest content of the document, including updates not yet persisted.
+    pub async fn snapshot(&self) -> Result<Option<Vec<u8>>> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(RoomCommand::Snapshot { reply })
+            .await
+            .map_err(|_| anyhow!("Document room has shut down"))?;
+        response.await.map_err(|_| anyhow!("Document room has shut down"))?
+    }
+
+    /// Locks or unlocks part of the document, returning the resulting protected ranges.
+    /// Offsets refer to the latest content, as returned by `snapshot`.
+    pub async fn change_protection(&self, change: ProtectionChange) -> Result<Vec<ProtectedRange>> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(RoomCommand::ChangeProtection { change, reply })
+            .await
+            .map_err(|_| anyhow!("Document room has shut down"))?;
+        response.await.map_err(|_| anyhow!("Document room has shut down"))?
+    }
+
+    /// Shares, refreshes or withdraws the presence of the connection identified by `origin`.
+    pub async fn update_presence(&self, origin: u64, update: PresenceUpdate) -> Result<()> {
+        self.commands
+            .send(RoomCommand::Presence { origin, update })
+            .await
+            .map_err(|_| anyhow!("Document room has shut down"))
+    }
+
+    /// Passes a notice to every connection to the room.
+    pub async fn notify(&self, notice: RoomNotice) -> Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(RoomCommand::Notify { notice, reply })
+            .await
+            .map_err(|_| anyhow!("Document room has shut down"))?;
+        response.await.map_err(|_| anyhow!("Document room has shut down"))?
+    }
+
+    /// The connections currently present in the room.
+    pub async fn participants(&self) -> Result<Vec<Participant>> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(RoomCommand::Participants { reply })
+            .await
+            .map_err(|_| anyhow!("Document room has shut down"))?;
+        response.await.map_err(|_| anyhow!("Document room has shut down"))?
+    }
+}
+
+/// The rooms with a running actor, shared between the registry and the actors themselves.
+#[derive(Clone, Default)]
+pub(crate) struct RoomMap(Arc<Mutex<HashMap<Uuid, RoomHandle>>>);
+
+impl RoomMap {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, RoomHandle>> {
+        self.0.lock().expect("room registry lock poisoned")
+    }
+
+    /// Unregisters a room if nobody is connected to it; returns whether its actor should exit.
+    pub(crate) fn remove_if_idle(&self, doc_id: Uuid, events: &broadcast::Sender<RoomEvent>) -> bool {
+        let mut rooms = self.lock();
+        if events.receiver_count() > 0 {
+            return false;
+        }
+        if rooms.get(&doc_id).is_some_and(|handle| handle.events.same_channel(events)) {
+            rooms.remove(&doc_id);
+        }
+        true
+    }
+
+    /// Passes an event from another node to this node's room for the document, if one is open.
+    pub(crate) fn deliver(&self, doc_id: Uuid, event: RelayedEvent) {
+        let Some(handle) = self.lock().get(&doc_id).cloned() else {
+            return;
+        };
+        if handle.commands.try_send(RoomCommand::Relayed(event)).is_err() {
+            warn!("Dropped a relayed event for busy document ID {}", doc_id);
+        }
+    }
+
+    /// Unregisters a room whose actor is exiting regardless of connections.
+    pub(crate) fn remove(&self, doc_id: Uuid, events: &broadcast::Sender<RoomEvent>) {
+        let mut rooms = self.lock();
+        if rooms.get(&doc_id).is_some_and(|handle| handle.events.same_channel(events)) {
+            rooms.remove(&doc_id);
+        }
+    }
+}
+
+/// Totals across every room on this node, for monitoring.
+#[derive(Debug, Default)]
+pub struct RoomStats {
+    pending_updates: AtomicUsize,
+    pending_bytes: AtomicUsize,
+    unsaved_documents: AtomicUsize,
+}
+
+impl RoomStats {
+    /// Updates accepted but not yet persisted.
+    pub fn pending_updates(&self) -> usize {
+        self.pending_updates.load(Ordering::Relaxed)
+    }
+
+    pub fn pending_bytes(&self) -> usize {
+        self.pending_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Rooms holding updates not yet persisted.
+    pub fn unsaved_documents(&self) -> usize {
+        self.unsaved_documents.load(Ordering::Relaxed)
+    }
+}
+
+/// Owns one actor per open document. Actors are spawned on first join and exit
+/// once every connection has left and their queue has been persisted.
+/// In a cluster, documents owned by another node get a relay actor instead.
+pub struct RoomRegistry {
+    rooms: RoomMap,
+    doc_service: Arc<DocumentService>,
+    config: WriteBufferConfig,
+    autosave: AutosavePolicy,
+    cluster: Option<Arc<ClusterConfig>>,
+    // Peers that handed their rooms over while draining, routed around for a while.
+    released: Arc<Releases>,
+    // Set once this node starts handing its rooms over; see `migrate`.
+    draining: AtomicBool,
+    relay: Option<Arc<Relay>>,
+    analysis: Option<ContentAnalysis>,
+    message_limits: MessageLimits,
+    stats: Arc<RoomStats>,
+    next_connection_id: AtomicU64,
+}
+
+impl RoomRegistry {
+    pub fn new(doc_service: Arc<DocumentService>, config: WriteBufferConfig) -> Self {
+        // Connection IDs travel between nodes as update origins, so start from a random
+        // base to keep them unique across the cluster.
+        let base = Uuid::new_v4().as_u64_pair().0 & 0xffff_ffff_0000_0000;
+        RoomRegistry {
+            rooms: RoomMap::default(),
+            doc_service,
+            config,
+            autosave: AutosavePolicy::default(),
+            cluster: None,
+            released: Arc::default(),
+            draining: AtomicBool::new(false),
+            relay: None,
+            analysis: None,
+            message_limits: MessageLimits::default(),
+            stats: Arc::default(),
+            next_connection_id: AtomicU64::new(base),
+        }
+    }
+
+    /// Replaces the default policy for how long rooms hold content before saving it.
+    pub fn with_autosave(mut self, autosave: AutosavePolicy) -> Self {
+        self.autosave = autosave;
+        self
+    }
+
+    /// Replaces the default limits on what each WebSocket may send.
+    pub fn with_message_limits(mut self, message_limits: MessageLimits) -> Self {
+        self.message_limits = message_limits;
+        self
+    }
+
+    pub fn message_limits(&self) -> &MessageLimits {
+        &self.message_limits
+    }
+
+    /// Routes rooms for documents owned by other nodes through those nodes.
+    pub fn with_cluster(mut self, cluster: Arc<ClusterConfig>) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Shares what rooms broadcast with the rooms for the same documents on other nodes, as
+    /// an alternative to `with_cluster`. Must be called within a Tokio runtime.
+    pub fn with_broadcaster(mut self, broadcaster: Arc<dyn Broadcaster>) -> Self {
+        self.relay = Some(Relay::start(broadcaster, self.rooms.clone()));
+        self
+    }
+
+    /// Sends each saved version of a document to a content analysis service.
+    pub fn with_content_analysis(mut self, analysis: ContentAnalysis) -> Self {
+        self.analysis = Some(analysis);
+        self
+    }
+
+    /// A process-unique identifier for a connection, used to tag the updates it submits.
+    pub fn next_connection_id(&self) -> u64 {
+        self.next_connection_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Joins a document's room, starting its actor (or a relay to its owner) if necessary.
+    pub fn join(&self, doc_id: Uuid) -> (RoomHandle, broadcast::Receiver<RoomEvent>) {
+        match self.remote_owner(doc_id) {
+            Some(owner) => {
+                let owner = owner.clone();
+                self.join_with(doc_id, |registry| registry.spawn_remote_room(doc_id, owner))
+            }
+            None => self.join_local(doc_id),
+        }
+    }
+
+    /// The node hosting a document's room, unless it is this one.
+    pub(crate) fn remote_owner(&self, doc_id: Uuid) -> Option<&ClusterPeer> {
+        let cluster = self.cluster.as_ref()?;
+        let owner = cluster.current_owner(doc_id, &self.released);
+        (!cluster.is_local(owner)).then_some(owner)
+    }
+
+    /// Joins the room hosted on this node, regardless of cluster ownership.
+    pub(crate) fn join_local(&self, doc_id: Uuid) -> (RoomHandle, broadcast::Receiver<RoomEvent>) {
+        self.join_with(doc_id, |registry| registry.spawn_room(doc_id))
+    }
+
+    fn join_with(
+        &self,
+        doc_id: Uuid,
+        spawn: impl FnOnce(&Self) -> RoomHandle,
+    ) -> (RoomHandle, broadcast::Receiver<RoomEvent>) {
+        // Subscribing under the lock ensures an idle actor can't exit between lookup and subscribe.
+        let mut rooms = self.rooms.lock();
+        let handle = rooms.entry(doc_id).or_insert_with(|| spawn(self)).clone();
+        let events = handle.events.subscribe();
+        (handle, events)
+    }
+
+    /// Passes a notice to everyone connected to a document. Does nothing if no room for it
+    /// is open on this node, unless another node owns it and may have one.
+    pub async fn notify(&self, doc_id: Uuid, notice: RoomNotice) -> Result<()> {
+        let room = if self.remote_owner(doc_id).is_some() {
+            // The relay stays up while we hold its events, i.e. until the owner has the notice.
+            Some(self.join(doc_id))
+        } else {
+            self.rooms.lock().get(&doc_id).map(|handle| (handle.clone(), handle.events.subscribe()))
+        };
+        match room {
+            Some((room, _events)) => room.notify(notice).await,
+            None => {
+                // Other nodes may have a room open even though this one doesn't.
+                if let Some(relay) = &self.relay {
+                    relay.publish(doc_id, RelayedEvent::Notice(notice));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Number of rooms with a running actor or relay.
+    pub fn open_rooms(&self) -> usize {
+        self.rooms.lock().len()
+    }
+
+    pub fn stats(&self) -> &RoomStats {
+        &self.stats
+    }
+
+    /// Waits up to `timeout` for rooms on this node to persist the updates they hold, e.g.
+    /// before shutting down. Returns how many are still unsaved.
+    pub async fn flush(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        while self.stats.pending_updates() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(FLUSH_POLL_INTERVAL).await;
+        }
+        self.stats.pending_updates()
+    }
+
+    /// Whether this node has started handing its rooms over; see `migrate`.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Hands this node's rooms over before it shuts down, so clients move without losing
+    /// edits. Every connection is first told the room is migrating, and rooms hosted here
+    /// stop taking updates and save what they hold at once. Only once that is done, or
+    /// `timeout` passes, are connections closed to reconnect, including those relayed from
+    /// other nodes, which route this node's documents elsewhere from then on. Returns how
+    /// many updates are still unsaved.
+    pub async fn migrate(&self, timeout: Duration) -> usize {
+        self.draining.store(true, Ordering::Relaxed);
+        let rooms: Vec<RoomHandle> = self.rooms.lock().values().cloned().collect();
+        for room in &rooms {
+            let _ = room.commands.send(RoomCommand::Migrate).await;
+        }
+        let unsaved = self.flush(timeout).await;
+        for room in &rooms {
+            let _ = room.events.send(RoomEvent::Notice(RoomNotice::Closing(CloseCode::ShuttingDown)));
+        }
+        info!("Handed over {} document rooms", rooms.len());
+        unsaved
+    }
+
+    fn spawn_remote_room(&self, doc_id: Uuid, owner: ClusterPeer) -> RoomHandle {
+        let (commands, command_rx) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+        let (events, _) = broadcast::channel(EVENT_QUEUE_CAPACITY);
+        let relay = RemoteRoom {
+            doc_id,
+            owner,
+            released: self.released.clone(),
+            events: events.clone(),
+            senders: SenderLimits::new(self.message_limits.clone()),
+        };
+        tokio::spawn(relay.run(command_rx, self.rooms.clone()));
+        RoomHandle { commands, events }
+    }
+
+    fn spawn_room(&self, doc_id: Uuid) -> RoomHandle {
+        let (commands, command_rx) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+        let (events, _) = broadcast::channel(EVENT_QUEUE_CAPACITY);
+        let room = DocumentRoom {
+            doc_id,
+            doc_service: self.doc_service.clone(),
+            config: self.config.clone(),
+            autosave: self.autosave.clone(),
+            update_rate: UpdateRate::default(),
+            rooms: self.rooms.clone(),
+            relay: self.relay.clone(),
+            events: events.clone(),
+            pending: VecDeque::new(),
+            pending_bytes: 0,
+            stats: self.stats.clone(),
+            failing_since: None,
+            analysis: self.analysis.clone(),
+            unanalyzed: None,
+            protected: None,
+            latest: None,
+            persisted: None,
+            relayed: None,
+            presence: PresenceMap::new(presence::PRESENCE_TIMEOUT),
+            senders: SenderLimits::new(self.message_limits.clone()),
+            migrating: false,
+            expires_at: None,
+        };
+        tokio::spawn(room.run(command_rx));
+        RoomHandle { commands, events }
+    }
+}
+
+struct DocumentRoom {
+    doc_id: Uuid,
+    doc_service: Arc<DocumentService>,
+    config: WriteBufferConfig,
+    autosave: AutosavePolicy,
+    update_rate: UpdateRate,
+    rooms: RoomMap,
+    relay: Option<Arc<Relay>>,
+    events: broadcast::Sender<RoomEvent>,
+    // Updates broadcast to clients but not yet persisted, oldest first.
+    pending: VecDeque<PendingWrite>,
+    pending_bytes: usize,
+    stats: Arc<RoomStats>,
+    // Set when the first persistence failure of an outage is observed.
+    failing_since: Option<Instant>,
+    analysis: Option<ContentAnalysis>,
+    // The latest saved version not yet sent for analysis. Intermediate versions are skipped.
+    unanalyzed: Option<(CausalityToken, Vec<u8>)>,
+    // Protected ranges as of the latest content; loaded on first use.
+    protected: Option<Vec<ProtectedRange>>,
+    // The latest content, kept only while there are protected ranges to check updates against.
+    latest: Option<Vec<u8>>,
+    // The content as of the last write, so the next one need only store what changed.
+    // Unknown until the room's first write, which loads it.
+    persisted: Option<Vec<u8>>,
+    // Content from another node newer than anything queued here; that node saves it.
+    relayed: Option<Vec<u8>>,
+    presence: PresenceMap,
+    senders: SenderLimits,
+    // Set once the room starts moving to another node; it takes no more updates.
+    migrating: bool,
+    // When the document stops taking updates, if it was created to; loaded on opening.
+    expires_at: Option<DateTime<Utc>>,
+}
+
+struct PendingWrite {
+    data: Vec<u8>,
+    // Set when the protected ranges moved with this version and must be saved alongside it.
+    protected: Option<Arc<Vec<ProtectedRange>>>,
+    author: Option<Uuid>,
+}
+
+impl DocumentRoom {
+    async fn run(mut self, mut commands: mpsc::Receiver<RoomCommand>) {
+        if let Err(e) = self.doc_service.mark_document_opened(self.doc_id).await {
+            warn!("Failed to record opening of document ID {}: {:#}", self.doc_id, e);
+        }
+        match self.doc_service.document_expiry(self.doc_id).await {
+            Ok(expires_at) => self.expires_at = expires_at,
+            Err(e) => warn!("Failed to load expiry of document ID {}: {:#}", self.doc_id, e),
+        }
+        // Connections are told once, when it passes, even if that was before the room opened.
+        let mut expire_at = self.expires_at.map(|at| Instant::now() + (at - Utc::now()).to_std().unwrap_or_default());
+        let mut retry = tokio::time::interval(self.config.retry_interval);
+        // Persistence runs in its own task so a slow or unreachable database never
+        // stalls broadcasting. At most one write is in flight at a time to preserve order.
+        let mut in_flight: Option<JoinHandle<Result<CausalityToken>>> = None;
+        // Likewise at most one analysis runs at a time.
+        let mut analyzing: Option<JoinHandle<Result<AnnotationSet>>> = None;
+        let mut presence_check = tokio::time::interval(PRESENCE_CHECK_INTERVAL);
+        // When the queue is next due to be written. Only set while no write is in flight.
+        let mut save_at: Option<Instant> = None;
+
+        loop {
+            tokio::select! {
+                Some(command) = commands.recv() => match command {
+                    RoomCommand::Update { origin, author, role, class, data, reply } => {
+                        let limited = class.map(|class| self.senders.check(origin, class, data.len(), Instant::now()));
+                        if let Some(Err(rejection)) = limited {
+                            let _ = reply.send(Err(rejection.into()));
+                            continue;
+                        }
+                        let _ = reply.send(self.accept_update(origin, author, role, data).await);
+                        if in_flight.is_none() && self.failing_since.is_none() && save_at.is_none() {
+                            save_at = self.next_save();
+                        }
+                    }
+                    RoomCommand::Snapshot { reply } => {
+                        let _ = reply.send(self.snapshot().await);
+                    }
+                    RoomCommand::ChangeProtection { change, reply } => {
+                        let _ = reply.send(self.change_protection(change).await);
+                    }
+                    RoomCommand::Presence { origin, update } => {
+                        if let Some(change) = self.presence.apply(origin, update, Instant::now()) {
+                            self.broadcast_presence(change);
+                        }
+                    }
+                    RoomCommand::Participants { reply } => {
+                        let _ = reply.send(Ok(self.presence.participants()));
+                    }
+                    RoomCommand::Notify { notice, reply } => {
+                        self.relay(RelayedEvent::Notice(notice.clone()));
+                        self.apply_notice(notice);
+                        let _ = reply.send(Ok(()));
+                    }
+                    RoomCommand::Relayed(event) => self.apply_relayed(event, in_flight.is_some()),
+                    RoomCommand::Migrate => {
+                        self.migrating = true;
+                        let _ = self.events.send(RoomEvent::Notice(RoomNotice::Migrating));
+                        // Save now rather than when autosave would have.
+                        if in_flight.is_none() && !self.pending.is_empty() {
+                            save_at = Some(Instant::now());
+                        }
+                    }
+                },
+                result = async { in_flight.as_mut().unwrap().await }, if in_flight.is_some() => {
+                    in_flight = None;
+                    match result {
+                        Ok(Ok(version)) => {
+                            self.complete_write(version);
+                            save_at = self.next_save();
+                            if analyzing.is_none() {
+                                analyzing = self.start_analysis();
+                            }
+                        }
+                        // Retrying a refused write would only be refused again.
+                        Ok(Err(e)) if e.is::<HookRejection>() || e.is::<QuotaExceeded>() || e.is::<DocumentTooLarge>() => {
+                            self.reject_write(e);
+                            save_at = self.next_save();
+                        }
+                        Ok(Err(e)) => self.fail_write(e),
+                        Err(e) => self.fail_write(e.into()),
+                    }
+                },
+                result = async { analyzing.as_mut().unwrap().await }, if analyzing.is_some() => {
+                    match result {
+                        Ok(Ok(annotations)) => {
+                            let _ = self.events.send(RoomEvent::Annotations(Arc::new(annotations)));
+                        }
+                        Ok(Err(e)) => warn!("Content analysis failed for document ID {}: {:#}", self.doc_id, e),
+                        Err(e) => warn!("Content analysis failed for document ID {}: {:#}", self.doc_id, e),
+                    }
+                    analyzing = self.start_analysis();
+                },
+                _ = tokio::time::sleep_until(save_at.unwrap_or_else(Instant::now).into()), if save_at.is_some() => {
+                    save_at = None;
+                    in_flight = self.start_write();
+                },
+                _ = tokio::time::sleep_until(expire_at.unwrap_or_else(Instant::now).into()), if expire_at.is_some() => {
+                    expire_at = None;
+                    info!("Document ID {} expired; it is read-only from now on", self.doc_id);
+                    let _ = self.events.send(RoomEvent::Notice(RoomNotice::Expired));
+                    // Save what came in before the deadline now rather than when autosave would have.
+                    if in_flight.is_none() && !self.pending.is_empty() {
+                        save_at = Some(Instant::now());
+                    }
+                },
+                _ = presence_check.tick() => {
+                    for change in self.presence.expire(Instant::now()) {
+                        self.broadcast_presence(change);
+                    }
+                    self.senders.prune(Instant::now());
+                },
+                _ = retry.tick() => {
+                    if in_flight.is_some() || save_at.is_some() {
+                        continue;
+                    }
+                    if !self.pending.is_empty() {
+                        in_flight = self.start_write();
+                    } else if self.try_close() {
+                        break;
+                    }
+                },
+            }
+        }
+        info!("Closed room for document ID: {}", self.doc_id);
+    }
+
+    async fn accept_update(&mut self, origin: u64, author: Option<Uuid>, role: DocumentRole, data: Vec<u8>) -> Result<()> {
+        if self.migrating {
+            return Err(UpdateRejection::Migrating.into());
+        }
+        if self.expires_at.is_some_and(|at| at <= Utc::now()) {
+            return Err(UpdateRejection::Expired.into());
+        }
+        if self.failing_since.is_some_and(|since| since.elapsed() > self.config.max_outage) {
+            return Err(UpdateRejection::OutageTooLong.into());
+        }
+        // Updates carry the whole content, so one over the limit could never be saved.
+        if self.doc_service.max_document_bytes().is_some_and(|max_bytes| data.len() > max_bytes) {
+            return Err(UpdateRejection::DocumentTooLarge.into());
+        }
+        if self.pending.len() >= self.config.max_pending_updates
+            || self.pending_bytes + data.len() > self.config.max_pending_bytes
+        {
+            return Err(UpdateRejection::BufferFull.into());
+        }
+        let protected = self.check_protected_ranges(role, &data).await?;
+
+        let _ = self.events.send(RoomEvent::Update { origin, data: Arc::new(data.clone()) });
+        self.relay(RelayedEvent::Update { origin, data: data.clone() });
+        self.relayed = None;
+        self.stats.pending_updates.fetch_add(1, Ordering::Relaxed);
+        self.stats.pending_bytes.fetch_add(data.len(), Ordering::Relaxed);
+        if self.pending.is_empty() {
+            self.stats.unsaved_documents.fetch_add(1, Ordering::Relaxed);
+        }
+        self.pending_bytes += data.len();
+        self.pending.push_back(PendingWrite { data, protected, author });
+        self.update_rate.record(Instant::now());
+        if self.failing_since.is_some() {
+            self.broadcast_status();
+        }
+        Ok(())
+    }
+
+    fn relay(&self, event: RelayedEvent) {
+        if let Some(relay) = &self.relay {
+            relay.publish(self.doc_id, event);
+        }
+    }
+
+    fn broadcast_presence(&self, change: PresenceChange) {
+        let _ = self.events.send(RoomEvent::Presence(change.clone()));
+        self.relay(RelayedEvent::Presence(change));
+    }
+
+    fn apply_notice(&mut self, notice: RoomNotice) {
+        if notice == RoomNotice::Closing(CloseCode::DocumentDeleted) {
+            self.discard_pending();
+        }
+        let _ = self.events.send(RoomEvent::Notice(notice));
+    }
+
+    /// Passes on what the room for this document on another node broadcast. An update there
+    /// supersedes whatever is queued here, except a write already under way.
+    fn apply_relayed(&mut self, event: RelayedEvent, writing: bool) {
+        match event {
+            RelayedEvent::Update { origin, data } => {
+                while self.pending.len() > usize::from(writing) {
+                    self.pop_latest_pending();
+                }
+                // Ranges may have moved on either node; reload them when next needed.
+                self.protected = None;
+                self.latest = None;
+                let _ = self.events.send(RoomEvent::Update { origin, data: Arc::new(data.clone()) });
+                self.relayed = Some(data);
+            }
+            RelayedEvent::Presence(change) => {
+                let _ = self.events.send(RoomEvent::Presence(change));
+            }
+            RelayedEvent::Notice(notice) => self.apply_notice(notice),
+        }
+    }
+
+    async fn protected_ranges(&mut self) -> Result<&mut Vec<ProtectedRange>> {
+        if self.protected.is_none() {
+            self.protected = Some(self.doc_service.list_protected_ranges(self.doc_id).await?);
+        }
+        Ok(self.protected.as_mut().expect("protected ranges were just loaded"))
+    }
+
+    /// Refuses an update that edits a range `role` may not edit. Returns the moved
+    /// ranges if the update shifted them.
+    async fn check_protected_ranges(&mut self, role: DocumentRole, data: &[u8]) -> Result<Option<Arc<Vec<ProtectedRange>>>> {
+        if self.protected_ranges().await?.is_empty() {
+            self.latest = None;
+            return Ok(None);
+        }
+        let previous = match self.latest.take() {
+            Some(latest) => latest,
+            None => self.snapshot().await?.unwrap_or_default(),
+        };
+        let ranges = self.protected.as_mut().expect("protected ranges are loaded");
+        match protected_ranges::apply_update(ranges, role, &previous, data) {
+            Ok(moved) => {
+                self.latest = Some(data.to_vec());
+                Ok(moved.map(|moved| {
+                    *ranges = moved.clone();
+                    Arc::new(moved)
+                }))
+            }
+            Err(violation) => {
+                warn!("Refused update to document ID {}: {}", self.doc_id, violation);
+                self.latest = Some(previous);
+                Err(UpdateRejection::ProtectedRange.into())
+            }
+        }
+    }
+
+    async fn change_protection(&mut self, change: ProtectionChange) -> Result<Vec<ProtectedRange>> {
+        let mut ranges = self.protected_ranges().await?.clone();
+        match change {
+            ProtectionChange::Protect { start, end, editors } => {
+                let len = self.snapshot().await?.map_or(0, |content| content.len());
+                if start >= end || end > len {
+                    return Err(UpdateRejection::InvalidRange.into());
+                }
+                ranges.push(ProtectedRange::new(start, end, editors));
+                ranges.sort_by_key(|range| range.start);
+            }
+            ProtectionChange::Unprotect { id } => ranges.retain(|range| range.id != id),
+        }
+        // Offsets are relative to the latest content, so they are saved with it.
+        match self.pending.back_mut() {
+            Some(latest) => latest.protected = Some(Arc::new(ranges.clone())),
+            None => self.doc_service.save_protected_ranges(self.doc_id, &ranges).await?,
+        }
+        self.protected = Some(ranges.clone());
+        Ok(ranges)
+    }
+
+    /// When to write the queue, as the autosave policy sees fit for this document right now.
+    fn next_save(&self) -> Option<Instant> {
+        let latest = self.pending.back()?;
+        let now = Instant::now();
+        Some(now + self.autosave.delay(latest.data.len(), self.update_rate.at(now)))
+    }
+
+    fn start_write(&mut self) -> Option<JoinHandle<Result<CausalityToken>>> {
+        self.coalesce_pending();
+        let pending = self.pending.front()?;
+        let data = pending.data.clone();
+        let protected = pending.protected.clone();
+        let author = pending.author;
+        let persisted = self.persisted.clone();
+        let doc_service = self.doc_service.clone();
+        let doc_id = self.doc_id;
+        Some(tokio::spawn(async move {
+            // Saving only the change keeps the attribution of everything else.
+            let previous = match persisted {
+                Some(previous) => previous,
+                None => doc_service.get_document_content(doc_id).await?.map(|content| content.crdt_data).unwrap_or_default(),
+            };
+            let version = doc_service.append_document_update(doc_id, &previous, data, author).await?;
+            if let Some(protected) = protected {
+                doc_service.save_protected_ranges(doc_id, &protected).await?;
+            }
+            Ok(version)
+        }))
+    }
+
+    /// Folds the oldest updates by one author into the latest of them. Each update carries
+    /// the whole content, so only that needs writing, along with the latest ranges moved on
+    /// the way to it. Updates by different authors are written separately to keep each
+    /// one's edits attributed to them.
+    fn coalesce_pending(&mut self) {
+        while self.pending.len() > 1 && self.pending[0].author == self.pending[1].author {
+            let moved = self.pending.front().and_then(|pending| pending.protected.clone());
+            self.pop_pending();
+            if let Some(moved) = moved {
+                self.pending.front_mut().expect("a later update is queued").protected.get_or_insert(moved);
+            }
+        }
+    }
+
+    fn pop_pending(&mut self) -> Option<Vec<u8>> {
+        let PendingWrite { data, .. } = self.pending.pop_front()?;
+        self.release_pending(&data);
+        Some(data)
+    }
+
+    fn pop_latest_pending(&mut self) -> Option<Vec<u8>> {
+        let PendingWrite { data, .. } = self.pending.pop_back()?;
+        self.release_pending(&data);
+        Some(data)
+    }
+
+    fn release_pending(&mut self, data: &[u8]) {
+        self.pending_bytes -= data.len();
+        self.stats.pending_updates.fetch_sub(1, Ordering::Relaxed);
+        self.stats.pending_bytes.fetch_sub(data.len(), Ordering::Relaxed);
+        if self.pending.is_empty() {
+            self.stats.unsaved_documents.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Drops unsaved updates that can no longer be saved, e.g. because the document is gone.
+    fn discard_pending(&mut self) {
+        let discarded = self.pending.len();
+        while self.pop_pending().is_some() {}
+        self.protected = None;
+        self.latest = None;
+        if discarded > 0 {
+            warn!("Discarded {} unsaved updates to document ID {}", discarded, self.doc_id);
+        }
+    }
+
+    fn complete_write(&mut self, version: CausalityToken) {
+        if let Some(data) = self.pop_pending() {
+            if self.analysis.is_some() {
+                self.unanalyzed = Some((version, data.clone()));
+            }
+            self.persisted = Some(data);
+        }
+        if self.pending.is_empty() && self.failing_since.take().is_some() {
+            info!("Persistence recovered for document ID: {}", self.doc_id);
+            self.broadcast_status();
+        }
+    }
+
+    fn start_analysis(&mut self) -> Option<JoinHandle<Result<AnnotationSet>>> {
+        let analysis = self.analysis.clone()?;
+        let (version, content) = self.unanalyzed.take()?;
+        Some(tokio::spawn(async move {
+            let annotations = analysis.analyze(&content).await?;
+            Ok(AnnotationSet { version, annotations })
+        }))
+    }
+
+    fn reject_write(&mut self, rejection: anyhow::Error) {
+        warn!("Dropped update to document ID {}: {}", self.doc_id, rejection);
+        let moved = self.pending.front().and_then(|pending| pending.protected.clone());
+        self.pop_pending();
+        // Range moves must still reach the database with a later version, or be forgotten.
+        if let Some(moved) = moved {
+            match self.pending.front_mut() {
+                Some(next) => {
+                    next.protected.get_or_insert(moved);
+                }
+                None => {
+                    self.protected = None;
+                    self.latest = None;
+                }
+            }
+        }
+        let _ = self.events.send(RoomEvent::SaveStatus(SaveStatus::Rejected { reason: rejection.to_string() }));
+    }
+
+    fn fail_write(&mut self, e: anyhow::Error) {
+        if self.failing_since.is_none() {
+            error!("Persistence failing for document ID {}; buffering updates: {:#}", self.doc_id, e);
+            self.failing_since = Some(Instant::now());
+        }
+        self.broadcast_status();
+    }
+
+    fn broadcast_status(&self) {
+        let status = if self.pending.is_empty() {
+            SaveStatus::Saved
+        } else {
+            SaveStatus::Unsaved { pending_updates: self.pending.len() }
+        };
+        let _ = self.events.send(RoomEvent::SaveStatus(status));
+    }
+
+    async fn snapshot(&self) -> Result<Option<Vec<u8>>> {
+        if let Some(relayed) = &self.relayed {
+            return Ok(Some(relayed.clone()));
+        }
+        if let Some(latest) = self.pending.back() {
+            return Ok(Some(latest.data.clone()));
+        }
+        let content = self.doc_service.get_document_content(self.doc_id).await?;
+        Ok(content.map(|content| content.crdt_data))
+    }
+
+    /// Removes the room from the registry if nobody is connected.
+    fn try_close(&self) -> bool {
+        self.rooms.remove_if_idle(self.doc_id, &self.events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Manager as DbManager;
+    use crate::content_analysis::{Analyzer, Annotation, Utf8Extractor};
+    use crate::hooks::{Hook, HookRegistry, SaveEvent};
+    use crate::presence::Presence;
+    use anyhow::{bail, Context};
+    use async_trait::async_trait;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    async fn get_test_registry(config: WriteBufferConfig) -> Result<(Arc<DocumentService>, RoomRegistry)> {
+        let manager = DbManager::new(COCKROACH_BASE_URI, TEST_DB_NAME)
+            .await
+            .context(format!("Failed to initialize DbManager for test database '{}'", TEST_DB_NAME))?;
+        let doc_service = Arc::new(DocumentService::new(Arc::new(manager)).await?);
+        Ok((doc_service.clone(), RoomRegistry::new(doc_service, config)))
+    }
+
+    #[tokio::test]
+    async fn test_updates_are_broadcast_and_persisted() -> Result<()> {
+        let (doc_service, registry) = get_test_registry(WriteBufferConfig::default()).await?;
+        let metadata = doc_service.create_document("Test Document for Rooms").await?;
+
+        let (room, mut events) = registry.join(metadata.id);
+        room.submit_update(1, None, DocumentRole::Editor, vec![1, 2, 3]).await?;
+
+        match events.recv().await? {
+            RoomEvent::Update { origin, data } => {
+                assert_eq!(origin, 1);
+                assert_eq!(*data, vec![1, 2, 3]);
+            }
+            other => panic!("Expected an update event, got {:?}", other),
+        }
+        assert_eq!(room.snapshot().await?, Some(vec![1, 2, 3]));
+
+        // Persistence happens in the background; give it a moment.
+        for _ in 0..50 {
+            let content = doc_service.get_document_content(metadata.id).await?.unwrap();
+            if content.crdt_data == vec![1, 2, 3] {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Update was never persisted");
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_held_to_message_limits() -> Result<()> {
+        let (doc_service, registry) = get_test_registry(WriteBufferConfig::default()).await?;
+        let limits = MessageLimits {
+            guest: MessageLimit { max_frame_bytes: 4, updates_per_second: 0.01, burst: 2 },
+            warnings: 2,
+            ..MessageLimits::default()
+        };
+        let registry = registry.with_message_limits(limits);
+        let metadata = doc_service.create_document("Test Document for Message Limits").await?;
+        let (room, _events) = registry.join(metadata.id);
+        let rejection = |result: Result<()>| result.unwrap_err().downcast_ref::<UpdateRejection>().copied();
+
+        room.submit_frame(1, None, DocumentRole::Editor, TrafficClass::Guest, vec![1]).await?;
+        room.submit_frame(1, None, DocumentRole::Editor, TrafficClass::Guest, vec![2]).await?;
+        let limited = room.submit_frame(1, None, DocumentRole::Editor, TrafficClass::Guest, vec![3]).await;
+        assert_eq!(rejection(limited), Some(UpdateRejection::RateLimited));
+        // Limits are per connection and per class; updates outside a WebSocket have none.
+        room.submit_frame(2, None, DocumentRole::Editor, TrafficClass::Guest, vec![4]).await?;
+        room.submit_frame(3, None, DocumentRole::Editor, TrafficClass::Editor, vec![0; 64]).await?;
+        room.submit_update(1, None, DocumentRole::Editor, vec![0; 64]).await?;
+        let oversized = room.submit_frame(2, None, DocumentRole::Editor, TrafficClass::Guest, vec![0; 5]).await;
+        assert_eq!(rejection(oversized), Some(UpdateRejection::FrameTooLarge));
+
+        // Once warned as often as allowed, the next violation means disconnecting.
+        let limited = room.submit_frame(1, None, DocumentRole::Editor, TrafficClass::Guest, vec![5]).await;
+        assert_eq!(rejection(limited), Some(UpdateRejection::RateLimited));
+        let exceeded = room.submit_frame(1, None, DocumentRole::Editor, TrafficClass::Guest, vec![6]).await;
+        assert_eq!(rejection(exceeded), Some(UpdateRejection::LimitExceeded));
+        assert_eq!(room.snapshot().await?, Some(vec![0; 64]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_full_buffer_rejects_updates() -> Result<()> {
+        let config = WriteBufferConfig { max_pending_bytes: 4, ..WriteBufferConfig::default() };
+        let (doc_service, registry) = get_test_registry(config).await?;
+        let metadata = doc_service.create_document("Test Document for Room Limits").await?;
+
+        let (room, _events) = registry.join(metadata.id);
+        let err = room.submit_update(1, None, DocumentRole::Editor, vec![0; 5]).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<UpdateRejection>(), Some(&UpdateRejection::BufferFull));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_documents_turn_read_only() -> Result<()> {
+        let (doc_service, registry) = get_test_registry(WriteBufferConfig::default()).await?;
+        let expires_at = Utc::now() + chrono::TimeDelta::milliseconds(500);
+        let metadata = doc_service.create_document_with("Test Document for Expiry", None, None, Some(expires_at)).await?;
+        assert_eq!(doc_service.document_expiry(metadata.id).await?.map(|at| at.timestamp_millis()), Some(expires_at.timestamp_millis()));
+
+        let (room, mut events) = registry.join(metadata.id);
+        room.submit_update(1, None, DocumentRole::Editor, b"answer".to_vec()).await?;
+        let notice = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let RoomEvent::Notice(notice) = events.recv().await? {
+                    return Ok::<_, anyhow::Error>(notice);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(notice, RoomNotice::Expired);
+        let err = room.submit_update(1, None, DocumentRole::Owner, b"late answer".to_vec()).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<UpdateRejection>(), Some(&UpdateRejection::Expired));
+        registry.flush(Duration::from_secs(5)).await;
+        assert_eq!(room.snapshot().await?, Some(b"answer".to_vec()));
+
+        // Expired documents are archived whatever their size once they have not been opened for a while.
+        let long_ago = Utc::now() - chrono::TimeDelta::days(10_000);
+        assert!(doc_service.find_cold_documents(long_ago, Utc::now(), i64::MAX as usize, i64::MAX).await?.contains(&metadata.id));
+        assert!(!doc_service.find_cold_documents(long_ago, expires_at, i64::MAX as usize, i64::MAX).await?.contains(&metadata.id));
+        Ok(())
+    }
+
+    struct RejectMarkedContent;
+
+    #[async_trait]
+    impl Hook for RejectMarkedContent {
+        fn name(&self) -> &str {
+            "reject-marked"
+        }
+
+        async fn pre_save(&self, event: &SaveEvent<'_>) -> Result<()> {
+            if event.content.first() == Some(&0xff) {
+                bail!("marked content");
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hook_rejections_are_dropped_not_retried() -> Result<()> {
+        let manager = DbManager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?;
+        let mut hooks = HookRegistry::new();
+        hooks.register(Arc::new(RejectMarkedContent));
+        let doc_service = Arc::new(DocumentService::new(Arc::new(manager)).await?.with_hooks(Arc::new(hooks)));
+        let registry = RoomRegistry::new(doc_service.clone(), WriteBufferConfig::default());
+        let metadata = doc_service.create_document("Test Document for Save Hooks").await?;
+
+        let (room, mut events) = registry.join(metadata.id);
+        room.submit_update(1, None, DocumentRole::Editor, vec![0xff, 1]).await?;
+        loop {
+            if let RoomEvent::SaveStatus(status) = events.recv().await? {
+                assert_eq!(status, SaveStatus::Rejected { reason: "Rejected by reject-marked: marked content".to_string() });
+                break;
+            }
+        }
+
+        // The room keeps saving later updates.
+        room.submit_update(1, None, DocumentRole::Editor, vec![1, 2]).await?;
+        for _ in 0..50 {
+            if doc_service.get_document_content(metadata.id).await?.unwrap().crdt_data == vec![1, 2] {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Update after a rejection was never persisted");
+    }
+
+    /// Flags the whole text as one annotation.
+    struct WholeTextAnalyzer;
+
+    #[async_trait]
+    impl Analyzer for WholeTextAnalyzer {
+        async fn analyze(&self, text: &str, _language: Option<&str>) -> Result<Vec<Annotation>> {
+            Ok(vec![Annotation {
+                start: 0,
+                end: text.chars().count(),
+                kind: "style".to_string(),
+                message: text.to_string(),
+                suggestion: None,
+            }])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_saved_versions_are_analyzed() -> Result<()> {
+        let (doc_service, registry) = get_test_registry(WriteBufferConfig::default()).await?;
+        let registry = registry.with_content_analysis(ContentAnalysis::new(Arc::new(Utf8Extractor), Arc::new(WholeTextAnalyzer)));
+        let metadata = doc_service.create_document("Test Document for Analysis").await?;
+
+        let (room, mut events) = registry.join(metadata.id);
+        room.submit_update(1, None, DocumentRole::Editor, b"hello".to_vec()).await?;
+        let annotations = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let RoomEvent::Annotations(annotations) = events.recv().await? {
+                    return Ok::<_, anyhow::Error>(annotations);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(annotations.annotations.len(), 1);
+        assert_eq!(annotations.annotations[0].message, "hello");
+        assert_eq!(Some(annotations.version), doc_service.get_document_metadata(metadata.id).await?.map(|m| m.causality_token()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_protected_ranges_are_enforced() -> Result<()> {
+        let (doc_service, registry) = get_test_registry(WriteBufferConfig::default()).await?;
+        let metadata = doc_service.create_document("Test Document for Protected Ranges").await?;
+        let (room, _events) = registry.join(metadata.id);
+        room.submit_update(1, None, DocumentRole::Owner, b"Title\nbody".to_vec()).await?;
+
+        let ranges = room
+            .change_protection(ProtectionChange::Protect { start: 0, end: 6, editors: vec![DocumentRole::Owner] })
+            .await?;
+        let err = room
+            .change_protection(ProtectionChange::Protect { start: 4, end: 40, editors: vec![] })
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<UpdateRejection>(), Some(&UpdateRejection::InvalidRange));
+
+        let err = room.submit_update(2, None, DocumentRole::Editor, b"Tytle\nbody".to_vec()).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<UpdateRejection>(), Some(&UpdateRejection::ProtectedRange));
+        room.submit_update(2, None, DocumentRole::Editor, b"Title\nbody text".to_vec()).await?;
+        room.submit_update(1, None, DocumentRole::Owner, b"Title 2\nbody text".to_vec()).await?;
+        assert_eq!(room.snapshot().await?, Some(b"Title 2\nbody text".to_vec()));
+
+        // The moved range is saved along with the content that moved it.
+        for _ in 0..50 {
+            let saved = doc_service.list_protected_ranges(metadata.id).await?;
+            if saved.first().map(|range| (range.start, range.end)) == Some((0, 8)) {
+                assert_eq!(saved[0].id, ranges[0].id);
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Moved protected range was never saved");
+    }
+
+    #[tokio::test]
+    async fn test_presence_is_shared_with_the_room() -> Result<()> {
+        let (doc_service, registry) = get_test_registry(WriteBufferConfig::default()).await?;
+        let metadata = doc_service.create_document("Test Document for Presence").await?;
+        let (room, mut events) = registry.join(metadata.id);
+
+        let presence = Presence { display_name: "Ada".to_string(), color: None, cursor: Some(3), selection: None, region: None };
+        room.update_presence(1, PresenceUpdate::Set { presence: presence.clone() }).await?;
+        room.update_presence(1, PresenceUpdate::Heartbeat).await?;
+        assert_eq!(room.participants().await?, vec![Participant { connection_id: 1, presence: presence.clone() }]);
+        room.update_presence(1, PresenceUpdate::Leave).await?;
+
+        let mut changes = Vec::new();
+        while changes.len() < 2 {
+            if let RoomEvent::Presence(change) = events.recv().await? {
+                changes.push(change);
+            }
+        }
+        assert_eq!(
+            changes,
+            vec![PresenceChange::Joined { connection_id: 1, presence }, PresenceChange::Left { connection_id: 1 }]
+        );
+        assert!(room.participants().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_notices_reach_open_rooms_only() -> Result<()> {
+        let (doc_service, registry) = get_test_registry(WriteBufferConfig::default()).await?;
+        let metadata = doc_service.create_document("Test Document for Notices").await?;
+
+        // Nobody is connected, so there is nothing to tell.
+        registry.notify(metadata.id, RoomNotice::AccessChanged).await?;
+        assert_eq!(registry.open_rooms(), 0);
+
+        let (_room, mut events) = registry.join(metadata.id);
+        let deleted = RoomNotice::Closing(CloseCode::DocumentDeleted);
+        registry.notify(metadata.id, deleted.clone()).await?;
+        loop {
+            if let RoomEvent::Notice(notice) = events.recv().await? {
+                assert_eq!(notice, deleted);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// A message bus within the process, standing in for Redis.
+    struct LocalBus(broadcast::Sender<Vec<u8>>);
+
+    #[async_trait]
+    impl Broadcaster for LocalBus {
+        async fn publish(&self, _doc_id: Uuid, payload: Vec<u8>) -> Result<()> {
+            let _ = self.0.send(payload);
+            Ok(())
+        }
+
+        async fn subscribe(&self) -> Result<futures::stream::BoxStream<'static, Vec<u8>>> {
+            let payloads = self.0.subscribe();
+            Ok(Box::pin(futures::stream::unfold(payloads, |mut payloads| async move {
+                payloads.recv().await.ok().map(|payload| (payload, payloads))
+            })))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_are_relayed_between_nodes() -> Result<()> {
+        let (doc_service, first) = get_test_registry(WriteBufferConfig::default()).await?;
+        let bus: Arc<dyn Broadcaster> = Arc::new(LocalBus(broadcast::channel(16).0));
+        let first = first.with_broadcaster(bus.clone());
+        let second = RoomRegistry::new(doc_service.clone(), WriteBufferConfig::default()).with_broadcaster(bus);
+        let metadata = doc_service.create_document("Test Document for Relaying").await?;
+        let (room, _events) = first.join(metadata.id);
+        let (other_room, mut other_events) = second.join(metadata.id);
+        // Let both nodes subscribe before anything is published.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        room.submit_update(1, None, DocumentRole::Editor, b"from the first node".to_vec()).await?;
+        let presence = Presence { display_name: "Ada".to_string(), color: None, cursor: Some(3), selection: None, region: None };
+        room.update_presence(1, PresenceUpdate::Set { presence: presence.clone() }).await?;
+        let deleted = RoomNotice::Closing(CloseCode::DocumentDeleted);
+        first.notify(metadata.id, deleted.clone()).await?;
+
+        let mut relayed = Vec::new();
+        while relayed.len() < 3 {
+            match tokio::time::timeout(Duration::from_secs(5), other_events.recv()).await?? {
+                RoomEvent::Update { origin, data } => relayed.push(format!("update {} {}", origin, String::from_utf8_lossy(&data))),
+                RoomEvent::Presence(change) => {
+                    assert_eq!(change, PresenceChange::Joined { connection_id: 1, presence: presence.clone() });
+                    relayed.push("presence".to_string());
+                }
+                RoomEvent::Notice(notice) => relayed.push(format!("notice {:?}", notice)),
+                _ => {}
+            }
+        }
+        assert_eq!(relayed, ["update 1 from the first node", "presence", "notice Closing(DocumentDeleted)"]);
+        assert_eq!(other_room.snapshot().await?, Some(b"from the first node".to_vec()));
+        assert!(other_room.participants().await?.is_empty(), "Rooms list only their own participants");
+        Ok(())
+    }
+
+    #[test]
+    fn test_autosave_delay_grows_with_size_and_activity() {
+        let policy = AutosavePolicy::default();
+        let large = policy.large_document_bytes;
+        assert!(policy.delay(100, 50.0) < Duration::from_millis(1));
+        assert_eq!(policy.delay(large * 10, 0.0), policy.min_delay);
+        assert_eq!(policy.delay(large * 10, 50.0), policy.max_delay);
+        assert_eq!(policy.delay(large / 2, policy.busy_updates_per_second), policy.max_delay / 2);
+
+        let start = Instant::now();
+        let mut rate = UpdateRate::default();
+        for i in 0..100 {
+            rate.record(start + Duration::from_millis(100 * i));
+        }
+        let busy = rate.at(start + Duration::from_secs(10));
+        assert!(busy > 5.0, "rate was {}", busy);
+        assert!(rate.at(start + Duration::from_secs(70)) < busy / 100.0);
+    }
+
+    #[tokio::test]
+    async fn test_autosave_delay_coalesces_updates() -> Result<()> {
+        let (doc_service, registry) = get_test_registry(WriteBufferConfig::default()).await?;
+        let autosave = AutosavePolicy { min_delay: Duration::from_millis(300), max_delay: Duration::from_millis(300), ..AutosavePolicy::default() };
+        let registry = registry.with_autosave(autosave);
+        let metadata = doc_service.create_document("Test Document for Autosave").await?;
+        let (room, _events) = registry.join(metadata.id);
+        for content in [b"one", b"two", b"six"] {
+            room.submit_update(1, None, DocumentRole::Owner, content.to_vec()).await?;
+        }
+
+        // Nothing is written until the delay is up, and then only the latest content.
+        assert_eq!(registry.stats().pending_updates(), 3);
+        assert_eq!(registry.stats().unsaved_documents(), 1);
+        assert!(doc_service.get_document_content(metadata.id).await?.is_none_or(|content| content.crdt_data.is_empty()));
+        assert_eq!(registry.flush(Duration::from_secs(5)).await, 0);
+        assert_eq!(registry.stats().unsaved_documents(), 0);
+        assert_eq!(doc_service.get_document_content(metadata.id).await?.unwrap().crdt_data, b"six");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flush_waits_for_pending_writes() -> Result<()> {
+        let (doc_service, registry) = get_test_registry(WriteBufferConfig::default()).await?;
+        let metadata = doc_service.create_document("Test Document for Flushing").await?;
+        let (room, _events) = registry.join(metadata.id);
+        room.submit_update(1, None, DocumentRole::Owner, b"saved before exit".to_vec()).await?;
+
+        assert_eq!(registry.flush(Duration::from_secs(5)).await, 0);
+        let content = doc_service.get_document_content(metadata.id).await?.unwrap();
+        assert_eq!(content.crdt_data, b"saved before exit");
+        Ok(())
+    }
+}
@@ -12,13 +12,48 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::blame::Attribution;
+use crate::cold_storage::ColdStorage;
+use crate::compression::{self, Compression, CompressionStats};
+use crate::consistency::{self, CausalityToken, ReadConsistency};
 use crate::db::Manager; // Assuming db::Manager is your CockroachDB manager
-use anyhow::{Context, Result}; // Use anyhow::Result for convenience
-use chrono::{DateTime, Utc}; // Needed for Utc::now() and DateTime<Utc>
+use crate::delta::ContentDelta;
+use crate::encryption::{self, Encryption, EncryptionError};
+use crate::events::{DocumentEvent, EventBus};
+use crate::fingerprint::{self, Fingerprint};
+use crate::hooks::{CommentEvent, HookRegistry, SaveEvent};
+use crate::integrity::{self, Integrity};
+use crate::language;
+use crate::migrations::{self, Component, Migration};
+use crate::outbox::{self, LoggedEvent};
+use crate::preview;
+use crate::protected_ranges::ProtectedRange;
+use crate::references::{self, DocumentLink};
+use crate::search;
+use crate::storage_quota::{self, StorageUsage};
+use anyhow::{anyhow, Context, Result}; // Use anyhow::Result for convenience
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use chrono::{DateTime, TimeDelta, Utc}; // Needed for Utc::now() and DateTime<Utc>
+use serde::{Deserialize, Serialize};
 use sqlx::{Row, FromRow, Executor}; // For deriving FromRow for sqlx
+use sqlx::types::Json;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, instrument, warn};
 use uuid::Uuid;
 
+pub const MAX_DOCUMENT_NAME_LENGTH: usize = 200;
+pub const MAX_COMMENT_LENGTH: usize = 10_000;
+/// Longest encoded relative position a comment may be anchored at.
+pub const MAX_COMMENT_ANCHOR_LENGTH: usize = 1024;
+/// Rows fetched by a duplicate lookup before similarity is checked. Band matches
+/// include unrelated documents, so this bounds how many are examined.
+const DUPLICATE_CANDIDATE_LIMIT: i64 = 200;
+/// How many alternatives a `NameTaken` error suggests.
+const NAME_SUGGESTIONS: usize = 3;
+
 // Helper trait and implementation for truncating DateTime<Utc> to milliseconds
 trait TruncateToMillis {
     fn trunc_to_millis(self) -> Self;
@@ -38,6 +73,15 @@ pub struct DocumentMetadata {
     pub name: String,
     pub created_at: DateTime<Utc>, // Changed to DateTime<Utc>
     pub updated_at: DateTime<Utc>, // Changed to DateTime<Utc>
+    /// Predominant language of the content as of its last snapshot; see `language::detect`.
+    pub language: Option<String>,
+}
+
+impl DocumentMetadata {
+    /// Token a client can echo on later reads to observe this version of the document.
+    pub fn causality_token(&self) -> CausalityToken {
+        CausalityToken::new(self.updated_at)
+    }
 }
 
 #[derive(Clone, Debug, FromRow, PartialEq)] // Changed to sqlx::FromRow
@@ -47,53 +91,799 @@ pub struct DocumentContent {
     pub updated_at: DateTime<Utc>, // Changed to DateTime<Utc>
 }
 
+/// A saved earlier state of a document. Listings leave out the content itself.
+#[derive(Clone, Debug, FromRow, PartialEq, Serialize)]
+pub struct DocumentVersion {
+    pub document_id: Uuid,
+    /// Numbered from 1 per document.
+    pub version: i64,
+    /// The document's name when the version was saved.
+    pub name: String,
+    /// Content length in bytes.
+    pub size: i64,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A comment on a document. Comments sit beside the content rather than in it, so
+/// callers who may not edit a document can still comment on it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Comment {
+    pub id: Uuid,
+    pub document_id: Uuid,
+    /// The first comment in the thread, which has its own ID here.
+    pub thread_id: Uuid,
+    /// Where the thread is anchored; only ever set on its first comment.
+    pub anchor: Option<CommentAnchor>,
+    /// The account or guest that wrote it.
+    pub author: Option<Uuid>,
+    pub body: String,
+    /// Shared by every comment in the thread.
+    pub resolved: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Where in the content a thread is anchored: a position, or a range between two, as
+/// encoded by the client's CRDT. Relative positions follow the text they point at through
+/// later edits, so the server stores them as given and leaves resolving them to clients.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CommentAnchor {
+    pub start: String,
+    #[serde(default)]
+    pub end: Option<String>,
+}
+
+#[derive(FromRow)]
+struct CommentRow {
+    id: Uuid,
+    document_id: Uuid,
+    thread_id: Uuid,
+    anchor_start: Option<String>,
+    anchor_end: Option<String>,
+    author: Option<Uuid>,
+    body: String,
+    resolved: bool,
+    created_at: DateTime<Utc>,
+}
+
+impl From<CommentRow> for Comment {
+    fn from(row: CommentRow) -> Self {
+        Comment {
+            id: row.id,
+            document_id: row.document_id,
+            thread_id: row.thread_id,
+            anchor: row.anchor_start.map(|start| CommentAnchor { start, end: row.anchor_end }),
+            author: row.author,
+            body: row.body,
+            resolved: row.resolved,
+            created_at: row.created_at,
+        }
+    }
+}
+
+const COMMENT_COLUMNS: &str = "id, document_id, thread_id, anchor_start, anchor_end, author, body, resolved, created_at";
+
+/// Which thread a new comment goes in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommentThread {
+    Start { anchor: Option<CommentAnchor> },
+    /// Replies in the thread of the given comment.
+    Reply { to: Uuid },
+}
+
+/// What changed in a document's comments, as passed on to everyone connected to it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum CommentChange {
+    Added { comment: Comment },
+    Resolved { thread_id: Uuid, resolved: bool },
+    /// Deleting a thread's first comment deletes the whole thread.
+    Deleted { id: Uuid, thread_id: Uuid },
+}
+
+/// How many documents there are, for server statistics.
+#[derive(Clone, Debug, FromRow, PartialEq, Serialize)]
+pub struct DocumentCounts {
+    /// Including those in the trash.
+    pub total: i64,
+    pub trashed: i64,
+}
+
+/// A document in the trash.
+#[derive(Clone, Debug, FromRow, PartialEq)]
+pub struct TrashedDocument {
+    #[sqlx(flatten)]
+    pub metadata: DocumentMetadata,
+    pub deleted_at: DateTime<Utc>,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Document {
     pub metadata: DocumentMetadata,
     pub content: Option<DocumentContent>,
 }
 
+/// An existing document that looks like a copy of some content.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateCandidate {
+    pub metadata: DocumentMetadata,
+    /// The content is byte-for-byte identical.
+    pub exact: bool,
+    /// From 0.0 to 1.0; see `fingerprint::similarity`.
+    pub similarity: f64,
+}
+
+/// Orders for listing documents.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentSort {
+    /// Most recently changed first.
+    #[default]
+    UpdatedDesc,
+    UpdatedAsc,
+    CreatedDesc,
+    CreatedAsc,
+    /// Alphabetical by name.
+    Name,
+}
+
+impl DocumentSort {
+    // Column, direction, and the comparison that selects rows after a cursor.
+    fn order(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            DocumentSort::UpdatedDesc => ("updated_at", "DESC", "<"),
+            DocumentSort::UpdatedAsc => ("updated_at", "ASC", ">"),
+            DocumentSort::CreatedDesc => ("created_at", "DESC", "<"),
+            DocumentSort::CreatedAsc => ("created_at", "ASC", ">"),
+            DocumentSort::Name => ("name", "ASC", ">"),
+        }
+    }
+}
+
+impl FromStr for DocumentSort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "updated_desc" => Ok(DocumentSort::UpdatedDesc),
+            "updated_asc" => Ok(DocumentSort::UpdatedAsc),
+            "created_desc" => Ok(DocumentSort::CreatedDesc),
+            "created_asc" => Ok(DocumentSort::CreatedAsc),
+            "name" => Ok(DocumentSort::Name),
+            other => Err(anyhow!("Unknown document sort '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CursorKey {
+    Time(DateTime<Utc>),
+    Name(String),
+}
+
+/// Where a page of a document listing ended. Clients treat its encoded form as opaque.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DocumentCursor {
+    sort: DocumentSort,
+    key: CursorKey,
+    id: Uuid,
+}
+
+impl DocumentCursor {
+    fn after(document: &DocumentMetadata, sort: DocumentSort) -> Self {
+        let key = match sort {
+            DocumentSort::UpdatedDesc | DocumentSort::UpdatedAsc => CursorKey::Time(document.updated_at),
+            DocumentSort::CreatedDesc | DocumentSort::CreatedAsc => CursorKey::Time(document.created_at),
+            DocumentSort::Name => CursorKey::Name(document.name.clone()),
+        };
+        DocumentCursor { sort, key, id: document.id }
+    }
+
+    pub fn encode(&self) -> String {
+        BASE64_URL.encode(serde_json::to_vec(self).expect("cursors are always serializable"))
+    }
+
+    /// Fails with `InvalidCursor` if `encoded` is not a cursor this service produced.
+    pub fn decode(encoded: &str) -> Result<Self> {
+        let bytes = BASE64_URL.decode(encoded).map_err(|_| anyhow!(InvalidCursor))?;
+        let cursor: DocumentCursor = serde_json::from_slice(&bytes).map_err(|_| anyhow!(InvalidCursor))?;
+        let key_matches_sort = match cursor.key {
+            CursorKey::Time(_) => cursor.sort != DocumentSort::Name,
+            CursorKey::Name(_) => cursor.sort == DocumentSort::Name,
+        };
+        if !key_matches_sort {
+            return Err(anyhow!(InvalidCursor));
+        }
+        Ok(cursor)
+    }
+}
+
+/// A listing cursor was malformed or belongs to a different sort order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InvalidCursor;
+
+impl std::fmt::Display for InvalidCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid document listing cursor")
+    }
+}
+
+impl std::error::Error for InvalidCursor {}
+
+/// Another document in the same folder already has this name. A document's folder is the
+/// one it was moved into (see `FolderService`), or else its workspace or, outside
+/// workspaces, its owner's personal documents; names are compared ignoring case and
+/// surrounding whitespace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameTaken {
+    pub name: String,
+    /// Free names to offer instead, best first.
+    pub suggestions: Vec<String>,
+}
+
+impl std::fmt::Display for NameTaken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "A document named '{}' already exists in this folder", self.name)
+    }
+}
+
+impl std::error::Error for NameTaken {}
+
+/// Returned by writes that would make a document larger than `with_max_document_bytes`
+/// allows. Nothing is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DocumentTooLarge {
+    pub size_bytes: usize,
+    pub max_bytes: usize,
+}
+
+impl std::fmt::Display for DocumentTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Document of {} bytes exceeds the limit of {} bytes", self.size_bytes, self.max_bytes)
+    }
+}
+
+impl std::error::Error for DocumentTooLarge {}
+
+/// How long a deletion awaiting approval stays open unless configured otherwise.
+pub const DEFAULT_DELETION_APPROVAL_WINDOW: TimeDelta = TimeDelta::days(3);
+
+/// A request to delete a document in a folder marked as needing a second approval; see
+/// `DocumentService::request_deletion`. Kept once decided, as a record of who did what.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeletionRequest {
+    pub id: Uuid,
+    pub document_id: Uuid,
+    pub requested_by: Option<Uuid>,
+    pub requested_at: DateTime<Utc>,
+    /// Unless decided by then, the request lapses and the document stays.
+    pub expires_at: DateTime<Utc>,
+    pub status: DeletionStatus,
+    pub decided_by: Option<Uuid>,
+    pub decided_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionStatus {
+    Pending,
+    /// The document went to the trash.
+    Approved,
+    Rejected,
+    Expired,
+}
+
+impl DeletionStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeletionStatus::Pending => "pending",
+            DeletionStatus::Approved => "approved",
+            DeletionStatus::Rejected => "rejected",
+            DeletionStatus::Expired => "expired",
+        }
+    }
+}
+
+impl FromStr for DeletionStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pending" => Ok(DeletionStatus::Pending),
+            "approved" => Ok(DeletionStatus::Approved),
+            "rejected" => Ok(DeletionStatus::Rejected),
+            "expired" => Ok(DeletionStatus::Expired),
+            other => Err(anyhow!("Unknown deletion request status '{}'", other)),
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct DeletionRequestRow {
+    id: Uuid,
+    document_id: Uuid,
+    requested_by: Option<Uuid>,
+    requested_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    status: String,
+    decided_by: Option<Uuid>,
+    decided_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<DeletionRequestRow> for DeletionRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(row: DeletionRequestRow) -> Result<Self> {
+        Ok(DeletionRequest {
+            id: row.id,
+            document_id: row.document_id,
+            requested_by: row.requested_by,
+            requested_at: row.requested_at,
+            expires_at: row.expires_at,
+            status: row.status.parse()?,
+            decided_by: row.decided_by,
+            decided_at: row.decided_at,
+        })
+    }
+}
+
+const DELETION_REQUEST_COLUMNS: &str = "id, document_id, requested_by, requested_at, expires_at, status, decided_by, decided_at";
+
+/// What asking to delete a document did.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Deletion {
+    /// It went straight to the trash.
+    Trashed,
+    /// It now awaits another owner's approval.
+    Requested(DeletionRequest),
+    /// It already awaited approval; asking again changes nothing.
+    Pending(DeletionRequest),
+}
+
+/// The answer to a pending deletion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionDecision {
+    Approve,
+    /// Also how whoever asked withdraws the request.
+    Reject,
+}
+
+/// Whoever asked to delete a document cannot also approve it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelfApproval;
+
+impl std::fmt::Display for SelfApproval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "A deletion must be approved by someone other than whoever requested it")
+    }
+}
+
+impl std::error::Error for SelfApproval {}
+
+/// Narrows a listing; fields left `None` do not.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DocumentFilter<'a> {
+    /// Documents owned by this user.
+    pub owner: Option<Uuid>,
+    /// Only these documents, for callers with scoped access.
+    pub restrict_to: Option<&'a [Uuid]>,
+    /// Only documents with neither an owner nor a workspace, which anyone may use, and
+    /// these, for callers without an account.
+    pub public_and: Option<&'a [Uuid]>,
+    /// Documents detected to be in this language; see `language::detect`.
+    pub language: Option<&'a str>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DocumentPage {
+    pub documents: Vec<DocumentMetadata>,
+    /// Pass back to fetch the next page; `None` on the last page.
+    pub next_cursor: Option<DocumentCursor>,
+}
+
+#[derive(FromRow)]
+struct DuplicateRow {
+    id: Uuid,
+    name: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    language: Option<String>,
+    checksum: Option<String>,
+    simhash: Option<i64>,
+}
+
 #[derive(Clone)]
 pub struct DocumentService {
     db_manager: Arc<Manager>,
+    cold_storage: Option<Arc<ColdStorage>>,
+    encryption: Option<Arc<Encryption>>,
+    compression: Arc<Compression>,
+    hooks: Arc<HookRegistry>,
+    events: EventBus,
+    storage_quota: Option<i64>,
+    max_document_bytes: Option<usize>,
+    deletion_approval_window: TimeDelta,
 }
 
-impl DocumentService {
-    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
-        let service = DocumentService { db_manager };
-        service.initialize_schema().await?;
-        Ok(service)
-    }
-
-    async fn initialize_schema(&self) -> Result<()> {
-        self.db_manager.pool
-            .execute(
+pub(crate) const SCHEMA: Component = Component {
+    name: "documents",
+    migrations: &[
+        Migration {
+            version: 1,
+            description: "baseline",
+            up: &[
                 "CREATE TABLE IF NOT EXISTS documents_metadata (
                     id UUID PRIMARY KEY,
                     name TEXT,
                     created_at TIMESTAMPTZ NOT NULL,
                     updated_at TIMESTAMPTZ NOT NULL
                 )",
-            )
-            .await
-            .context("Failed to create documents_metadata table")?;
-
-        self.db_manager.pool
-            .execute(
                 "CREATE TABLE IF NOT EXISTS documents_content (
                     document_id UUID PRIMARY KEY,
                     crdt_data BYTEA,
                     updated_at TIMESTAMPTZ NOT NULL,
                     FOREIGN KEY (document_id) REFERENCES documents_metadata(id) ON DELETE CASCADE
                 )",
+                "CREATE TABLE IF NOT EXISTS document_updates (
+                    document_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                    seq BIGINT NOT NULL,
+                    start_offset BIGINT NOT NULL,
+                    delete_len BIGINT NOT NULL,
+                    data BYTEA NOT NULL,
+                    checksum TEXT NOT NULL,
+                    created_at TIMESTAMPTZ NOT NULL,
+                    PRIMARY KEY (document_id, seq)
+                )",
+                "CREATE TABLE IF NOT EXISTS document_protected_ranges (
+                    id UUID PRIMARY KEY,
+                    document_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                    start_offset BIGINT NOT NULL,
+                    end_offset BIGINT NOT NULL,
+                    editors TEXT[] NOT NULL
+                )",
+                "CREATE TABLE IF NOT EXISTS document_versions (
+                    document_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                    version BIGINT NOT NULL,
+                    name TEXT NOT NULL,
+                    crdt_data BYTEA NOT NULL,
+                    checksum TEXT NOT NULL,
+                    created_by UUID,
+                    created_at TIMESTAMPTZ NOT NULL,
+                    PRIMARY KEY (document_id, version)
+                )",
+                "CREATE TABLE IF NOT EXISTS document_comments (
+                    id UUID PRIMARY KEY,
+                    document_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                    author UUID,
+                    body TEXT NOT NULL,
+                    created_at TIMESTAMPTZ NOT NULL
+                )",
+                "CREATE INDEX IF NOT EXISTS document_comments_by_document ON document_comments (document_id, created_at)",
+                // Columns added after the initial schema.
+                "ALTER TABLE documents_metadata ADD COLUMN IF NOT EXISTS last_opened_at TIMESTAMPTZ",
+                "ALTER TABLE documents_metadata ADD COLUMN IF NOT EXISTS seq BIGINT NOT NULL DEFAULT 0",
+                "ALTER TABLE documents_content ADD COLUMN IF NOT EXISTS offloaded_to TEXT",
+                "ALTER TABLE documents_content ADD COLUMN IF NOT EXISTS checksum TEXT",
+                "ALTER TABLE documents_content ADD COLUMN IF NOT EXISTS simhash BIGINT",
+                "ALTER TABLE documents_content ADD COLUMN IF NOT EXISTS simhash_bands INT[]",
+                "ALTER TABLE documents_content ADD COLUMN IF NOT EXISTS preview_html TEXT",
+                // Updates in document_updates up to this sequence number are folded into crdt_data.
+                "ALTER TABLE documents_content ADD COLUMN IF NOT EXISTS snapshot_seq BIGINT NOT NULL DEFAULT 0",
+                "ALTER TABLE documents_metadata ADD COLUMN IF NOT EXISTS owner_id UUID",
+                "ALTER TABLE documents_metadata ADD COLUMN IF NOT EXISTS workspace_id UUID",
+                // Set while the document is in the trash; purged for good once it has been there long enough.
+                "ALTER TABLE documents_metadata ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ",
+                "ALTER TABLE documents_metadata ADD COLUMN IF NOT EXISTS parent_folder_id UUID",
+                // Keyset pagination in `list_documents` walks these in either direction.
+                "CREATE INDEX IF NOT EXISTS documents_metadata_updated_at ON documents_metadata (updated_at, id)",
+                "CREATE INDEX IF NOT EXISTS documents_metadata_owner ON documents_metadata (owner_id, updated_at, id)",
+                // Folder listings look documents up by the folder they are in; see `NameTaken`.
+                "CREATE INDEX IF NOT EXISTS documents_metadata_folder
+                 ON documents_metadata ((COALESCE(parent_folder_id, workspace_id, owner_id)))",
+                // Claims each name in a folder; see `NameTaken`. Documents outside any folder have no entry.
+                "CREATE TABLE IF NOT EXISTS documents_by_name (
+                    folder_id UUID NOT NULL,
+                    name_key TEXT NOT NULL,
+                    document_id UUID NOT NULL UNIQUE REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                    PRIMARY KEY (folder_id, name_key)
+                )",
+                // Index documents created before names were unique. Of existing duplicates only the
+                // oldest claims the name; the others keep theirs until renamed.
+                "INSERT INTO documents_by_name (folder_id, name_key, document_id)
+                 SELECT COALESCE(m.parent_folder_id, m.workspace_id, m.owner_id), lower(btrim(m.name)), m.id
+                 FROM documents_metadata m
+                 WHERE COALESCE(m.parent_folder_id, m.workspace_id, m.owner_id) IS NOT NULL AND m.name IS NOT NULL
+                   AND NOT EXISTS (SELECT 1 FROM documents_by_name n WHERE n.document_id = m.id)
+                 ORDER BY m.created_at
+                 ON CONFLICT DO NOTHING",
+            ],
+            down: &[
+                "DROP TABLE IF EXISTS documents_by_name",
+                "DROP TABLE IF EXISTS document_comments",
+                "DROP TABLE IF EXISTS document_versions",
+                "DROP TABLE IF EXISTS document_protected_ranges",
+                "DROP TABLE IF EXISTS document_updates",
+                "DROP TABLE IF EXISTS documents_content",
+                "DROP TABLE IF EXISTS documents_metadata",
+            ],
+        },
+        Migration {
+            version: 2,
+            description: "detected document language",
+            up: &[
+                // Filled in as each document's next snapshot is saved.
+                "ALTER TABLE documents_metadata ADD COLUMN IF NOT EXISTS language TEXT",
+                "CREATE INDEX IF NOT EXISTS documents_metadata_language ON documents_metadata (language, updated_at, id)",
+            ],
+            down: &[
+                "DROP INDEX IF EXISTS documents_metadata_language",
+                "ALTER TABLE documents_metadata DROP COLUMN IF EXISTS language",
+            ],
+        },
+        Migration {
+            version: 3,
+            description: "comment threads",
+            up: &[
+                // Comments made before threads each start their own.
+                "ALTER TABLE document_comments ADD COLUMN IF NOT EXISTS thread_id UUID",
+                "UPDATE document_comments SET thread_id = id WHERE thread_id IS NULL",
+                "ALTER TABLE document_comments ALTER COLUMN thread_id SET NOT NULL",
+                "ALTER TABLE document_comments ADD COLUMN IF NOT EXISTS anchor_start TEXT",
+                "ALTER TABLE document_comments ADD COLUMN IF NOT EXISTS anchor_end TEXT",
+                "ALTER TABLE document_comments ADD COLUMN IF NOT EXISTS resolved BOOLEAN NOT NULL DEFAULT FALSE",
+                "CREATE INDEX IF NOT EXISTS document_comments_by_thread ON document_comments (thread_id)",
+            ],
+            down: &[
+                "DROP INDEX IF EXISTS document_comments_by_thread",
+                "ALTER TABLE document_comments DROP COLUMN IF EXISTS resolved",
+                "ALTER TABLE document_comments DROP COLUMN IF EXISTS anchor_end",
+                "ALTER TABLE document_comments DROP COLUMN IF EXISTS anchor_start",
+                "ALTER TABLE document_comments DROP COLUMN IF EXISTS thread_id",
+            ],
+        },
+        Migration {
+            version: 4,
+            description: "deletion approvals",
+            up: &[
+                "CREATE TABLE IF NOT EXISTS document_deletion_requests (
+                    id UUID PRIMARY KEY,
+                    document_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                    requested_by UUID,
+                    requested_at TIMESTAMPTZ NOT NULL,
+                    expires_at TIMESTAMPTZ NOT NULL,
+                    status TEXT NOT NULL,
+                    decided_by UUID,
+                    decided_at TIMESTAMPTZ
+                )",
+                "CREATE INDEX IF NOT EXISTS document_deletion_requests_by_document
+                 ON document_deletion_requests (document_id, requested_at)",
+                // At most one request per document awaits a decision.
+                "CREATE UNIQUE INDEX IF NOT EXISTS document_deletion_requests_pending
+                 ON document_deletion_requests (document_id) WHERE status = 'pending'",
+            ],
+            down: &[
+                "DROP TABLE IF EXISTS document_deletion_requests",
+            ],
+        },
+        Migration {
+            version: 5,
+            description: "edit attribution",
+            up: &[
+                // Updates logged before authors were recorded have none.
+                "ALTER TABLE document_updates ADD COLUMN IF NOT EXISTS author UUID",
+                // Who wrote the content as of the snapshot taken at `seq`; see `blame`.
+                "CREATE TABLE IF NOT EXISTS document_blame (
+                    document_id UUID PRIMARY KEY REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                    seq BIGINT NOT NULL,
+                    runs JSONB NOT NULL
+                )",
+            ],
+            down: &[
+                "DROP TABLE IF EXISTS document_blame",
+                "ALTER TABLE document_updates DROP COLUMN IF EXISTS author",
+            ],
+        },
+        Migration {
+            version: 6,
+            description: "expiring documents",
+            up: &[
+                // When the document stops taking edits; see `create_document_with`.
+                "ALTER TABLE documents_metadata ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ",
+            ],
+            down: &[
+                "ALTER TABLE documents_metadata DROP COLUMN IF EXISTS expires_at",
+            ],
+        },
+        Migration {
+            version: 7,
+            description: "plaintext update and version sizes",
+            up: &[
+                // Sealed data is longer than what it holds, so sizes are kept alongside.
+                "ALTER TABLE document_updates ADD COLUMN IF NOT EXISTS insert_len BIGINT",
+                "ALTER TABLE document_versions ADD COLUMN IF NOT EXISTS size BIGINT",
+                // Sealed data carries its prefix, a nonce and a tag: 36 bytes in all.
+                "UPDATE document_updates SET insert_len = octet_length(data)
+                     - CASE WHEN substring(data FROM 1 FOR 8) = decode('005345414c454401', 'hex') THEN 36 ELSE 0 END
+                 WHERE insert_len IS NULL",
+                "UPDATE document_versions SET size = octet_length(crdt_data)
+                     - CASE WHEN substring(crdt_data FROM 1 FOR 8) = decode('005345414c454401', 'hex') THEN 36 ELSE 0 END
+                 WHERE size IS NULL",
+            ],
+            down: &[
+                "ALTER TABLE document_versions DROP COLUMN IF EXISTS size",
+                "ALTER TABLE document_updates DROP COLUMN IF EXISTS insert_len",
+            ],
+        },
+    ],
+};
+
+/// An update's place in the log, without its content; see `DocumentService::blame`.
+#[derive(FromRow)]
+struct AttributedUpdate {
+    seq: i64,
+    start_offset: i64,
+    delete_len: i64,
+    insert_len: i64,
+    author: Option<Uuid>,
+    created_at: DateTime<Utc>,
+}
+
+/// A snapshot's size, with the attribution cached for it if any.
+#[derive(FromRow)]
+struct SnapshotAttribution {
+    snapshot_seq: i64,
+    snapshot_bytes: i64,
+    cached_seq: Option<i64>,
+    cached: Option<Json<Attribution>>,
+}
+
+impl DocumentService {
+    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
+        let service = DocumentService {
+            db_manager,
+            cold_storage: None,
+            encryption: None,
+            compression: Arc::new(Compression::disabled()),
+            hooks: Arc::new(HookRegistry::new()),
+            events: EventBus::default(),
+            storage_quota: None,
+            max_document_bytes: None,
+            deletion_approval_window: DEFAULT_DELETION_APPROVAL_WINDOW,
+        };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    /// Enables offloading cold document content to object storage, and rehydrating it on access.
+    pub fn with_cold_storage(mut self, cold_storage: Arc<ColdStorage>) -> Self {
+        self.cold_storage = Some(cold_storage);
+        self
+    }
+
+    /// Encrypts the content of documents whose organization has registered a key; see `encryption`.
+    pub fn with_encryption(mut self, encryption: Arc<Encryption>) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Compresses snapshots before storing them; see `compression`.
+    pub fn with_compression(mut self, compression: Arc<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Runs the given hooks around every content save and comment.
+    pub fn with_hooks(mut self, hooks: Arc<HookRegistry>) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Caps what each user's documents may store, in bytes; see `storage_quota`. Writes that
+    /// would exceed it fail with `QuotaExceeded`.
+    pub fn with_storage_quota(mut self, quota_bytes: i64) -> Self {
+        self.storage_quota = Some(quota_bytes);
+        self
+    }
+
+    /// Caps the size of each document's content, in bytes. Writes of anything larger fail
+    /// with `DocumentTooLarge` before they reach the database.
+    pub fn with_max_document_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_document_bytes = Some(max_bytes);
+        self
+    }
+
+    /// How long deletions awaiting approval stay open; see `request_deletion`.
+    pub fn with_deletion_approval_window(mut self, window: TimeDelta) -> Self {
+        self.deletion_approval_window = window;
+        self
+    }
+
+    /// `None` when documents may be any size; see `with_max_document_bytes`.
+    pub fn max_document_bytes(&self) -> Option<usize> {
+        self.max_document_bytes
+    }
+
+    /// Fails with `DocumentTooLarge` if content of `size_bytes` is over the limit.
+    fn check_document_size(&self, size_bytes: usize) -> Result<()> {
+        match self.max_document_bytes {
+            Some(max_bytes) if size_bytes > max_bytes => Err(DocumentTooLarge { size_bytes, max_bytes }.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// `None` unless a KMS is configured; see `with_encryption`.
+    pub fn encryption(&self) -> Option<&Arc<Encryption>> {
+        self.encryption.as_ref()
+    }
+
+    /// How much snapshot compression has saved so far, for monitoring.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compression.stats()
+    }
+
+    /// Where saves and deletions are announced once committed.
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Round-trip time to the database, for monitoring.
+    #[instrument(skip_all)]
+    pub async fn database_latency(&self) -> Result<Duration> {
+        self.db_manager.ping().await
+    }
+
+    #[instrument(skip_all)]
+    pub async fn count_documents(&self) -> Result<DocumentCounts> {
+        sqlx::query_as(
+                "SELECT COUNT(*) AS total, COUNT(*) FILTER (WHERE deleted_at IS NOT NULL) AS trashed FROM documents_metadata"
             )
+            .fetch_one(&*self.db_manager.pool)
             .await
-            .context("Failed to create documents_content table")?;
-        println!("Document service schema initialized.");
+            .context("Failed to count documents")
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        migrations::apply(&self.db_manager.pool, &storage_quota::SCHEMA).await?;
+        migrations::apply(&self.db_manager.pool, &outbox::SCHEMA).await?;
+        migrations::apply(&self.db_manager.pool, &references::SCHEMA).await?;
+        info!("Document service schema initialized.");
         Ok(())
     }
 
+    #[instrument(skip_all)]
     pub async fn create_document(&self, name: &str) -> Result<DocumentMetadata> {
+        self.create_document_for(name, None).await
+    }
+
+    /// Creates a document owned by `owner_id`, if given.
+    #[instrument(skip_all, fields(owner_id = ?owner_id))]
+    pub async fn create_document_for(&self, name: &str, owner_id: Option<Uuid>) -> Result<DocumentMetadata> {
+        self.create_document_in(name, owner_id, None).await
+    }
+
+    /// Creates a document in a workspace, whose default policy then governs access to it.
+    pub async fn create_document_in(
+        &self,
+        name: &str,
+        owner_id: Option<Uuid>,
+        workspace_id: Option<Uuid>,
+    ) -> Result<DocumentMetadata> {
+        self.create_document_with(name, owner_id, workspace_id, None).await
+    }
+
+    /// Creates a document that, if `expires_at` is given, becomes read-only at that time;
+    /// see `document_expiry`.
+    #[instrument(skip_all, fields(owner_id = ?owner_id, workspace_id = ?workspace_id))]
+    pub async fn create_document_with(
+        &self,
+        name: &str,
+        owner_id: Option<Uuid>,
+        workspace_id: Option<Uuid>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<DocumentMetadata> {
         let id = Uuid::new_v4();
         let now = Utc::now().trunc_to_millis();
         let metadata = DocumentMetadata {
@@ -101,112 +891,1679 @@ impl DocumentService {
             name: name.to_string(),
             created_at: now,
             updated_at: now,
+            language: None,
         };
 
-        self.db_manager.pool
-            .execute(sqlx::query(
-                    "INSERT INTO documents_metadata (id, name, created_at, updated_at) VALUES ($1, $2, $3, $4)"
-                )
-                .bind(metadata.id)
-                .bind(&metadata.name)
-                .bind(metadata.created_at)
-                .bind(metadata.updated_at)
-            ).await
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        sqlx::query(
+                "INSERT INTO documents_metadata (id, name, created_at, updated_at, owner_id, workspace_id, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)"
+            )
+            .bind(metadata.id)
+            .bind(&metadata.name)
+            .bind(metadata.created_at)
+            .bind(metadata.updated_at)
+            .bind(owner_id)
+            .bind(workspace_id)
+            .bind(expires_at)
+            .execute(&mut *tx)
+            .await
             .context(format!("Failed to insert document metadata for ID {}", id))?;
-        
+        if let Some(folder_id) = workspace_id.or(owner_id)
+            && !claim_name(&mut tx, folder_id, name, id).await?
+        {
+            tx.rollback().await.ok();
+            return Err(anyhow!(self.name_taken(folder_id, name).await?));
+        }
+        let metadata = DocumentMetadata { updated_at: stamp_updated_at(&mut tx, id).await?, ..metadata };
+        tx.commit().await.context(format!("Failed to commit creation of document ID {}", id))?;
+
         // Optionally, create an initial empty content entry
         self.update_document_content(id, Vec::new()).await.ok(); // Best effort for initial empty content
 
-        println!("Created document '{}' with ID: {}", name, id);
-        Ok(metadata)
+        info!("Created document '{}' with ID: {}", name, id);
+        Ok(metadata)
+    }
+
+    /// When the document stops taking edits, if it was created to. Its room enforces this.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn document_expiry(&self, doc_id: Uuid) -> Result<Option<DateTime<Utc>>> {
+        let expires_at: Option<Option<DateTime<Utc>>> = sqlx::query_scalar("SELECT expires_at FROM documents_metadata WHERE id = $1")
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query expiry of document ID {}", doc_id))?;
+        Ok(expires_at.flatten())
+    }
+
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn get_document_metadata(&self, doc_id: Uuid) -> Result<Option<DocumentMetadata>> {
+        self.get_document_metadata_with(doc_id, ReadConsistency::Strong).await
+    }
+
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn get_document_metadata_with(&self, doc_id: Uuid, consistency: ReadConsistency) -> Result<Option<DocumentMetadata>> {
+        let consistency = consistency.resolve_in(&self.db_manager.pool).await?;
+        let row_opt = sqlx::query(&format!(
+                "SELECT id, name, created_at, updated_at, language FROM documents_metadata{} WHERE id = $1 AND deleted_at IS NULL",
+                consistency.as_of_clause()
+            ))
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query document metadata for ID {}", doc_id))?;
+
+        match row_opt {
+            Some(row) => {
+            // Manually map the row to DocumentMetadata
+            // try_get can be used for fallible conversions, or get for infallible ones if types are exact.
+                let metadata = DocumentMetadata {
+                    id: row.try_get("id").context("Failed to get 'id' from row")?, // UUIDs don't need truncation
+                    name: row.try_get("name").context("Failed to get 'name' from row")?, // String doesn't need truncation
+                    created_at: row.try_get::<DateTime<Utc>, _>("created_at").context("Failed to get 'created_at' from row")?.trunc_to_millis(),
+                    updated_at: row.try_get::<DateTime<Utc>, _>("updated_at").context("Failed to get 'updated_at' from row")?.trunc_to_millis(),
+                    language: row.try_get("language").context("Failed to get 'language' from row")?,
+                };
+                Ok(Some(metadata))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces a document's content with a new snapshot, returning a token for
+    /// read-your-writes reads. Prefer `append_document_update` for edits to content the
+    /// caller already holds, which stores only what changed. Fails with `DocumentTooLarge` if
+    /// the content is over the size limit, `QuotaExceeded` if the owner has no room for it,
+    /// or `HookRejection` if a pre-save hook refuses it.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn update_document_content(&self, doc_id: Uuid, content_data: Vec<u8>) -> Result<CausalityToken> {
+        self.check_document_size(content_data.len())?;
+        self.write_snapshot(doc_id, content_data, self.storage_quota, None).await
+    }
+
+    /// Replaces a document's content with a new snapshot, attributing what changed to
+    /// `author` and keeping the attribution of the rest.
+    async fn rewrite_document_content(&self, doc_id: Uuid, content_data: Vec<u8>, author: Option<Uuid>) -> Result<CausalityToken> {
+        self.check_document_size(content_data.len())?;
+        let attribution = match self.load_document_content(doc_id, ReadConsistency::Strong).await? {
+            // Attribution that does not match the content is dropped rather than holding up the save.
+            Some((current, _, seq)) => self.attribution_at(doc_id, seq).await?.and_then(|mut attribution| {
+                let delta = ContentDelta::between(&current.crdt_data, &content_data);
+                attribution.apply(delta.start, delta.delete_len, delta.insert.len(), author, Utc::now()).ok().map(|_| attribution)
+            }),
+            None => Some(Attribution::written(content_data.len(), author, Some(Utc::now()))),
+        };
+        self.write_snapshot(doc_id, content_data, self.storage_quota, attribution.as_ref()).await
+    }
+
+    /// Saves `content_data` as the document's snapshot, with who wrote it if known.
+    async fn write_snapshot(
+        &self,
+        doc_id: Uuid,
+        content_data: Vec<u8>,
+        quota: Option<i64>,
+        attribution: Option<&Attribution>,
+    ) -> Result<CausalityToken> {
+        self.hooks.pre_save(&SaveEvent { doc_id, content: &content_data }).await?;
+        let now = Utc::now().trunc_to_millis(); // Truncate to millisecond precision
+        let fingerprint = Fingerprint::of(&content_data);
+        let stored = self.seal_snapshot(doc_id, content_data.clone()).await?;
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+
+        // Update metadata's updated_at timestamp and advance its change sequence
+        let language = language::detect(&content_data);
+        let seq: i64 = sqlx::query_scalar(
+                "UPDATE documents_metadata SET seq = seq + 1, language = $1 WHERE id = $2 RETURNING seq"
+            )
+            .bind(language)
+            .bind(doc_id)
+            .fetch_one(&mut *tx)
+            .await
+            .context(format!("Failed to update metadata timestamp for ID {}", doc_id))?;
+        let replaced_bytes = self.snapshot_bytes(&mut tx, doc_id).await?;
+
+        // Upsert content
+        sqlx::query(
+                "INSERT INTO documents_content (document_id, crdt_data, updated_at, checksum, simhash, simhash_bands, preview_html, snapshot_seq, snapshot_bytes)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                 ON CONFLICT (document_id) DO UPDATE
+                 SET crdt_data = EXCLUDED.crdt_data,
+                     updated_at = EXCLUDED.updated_at,
+                     checksum = EXCLUDED.checksum,
+                     simhash = EXCLUDED.simhash,
+                     simhash_bands = EXCLUDED.simhash_bands,
+                     preview_html = EXCLUDED.preview_html,
+                     snapshot_seq = EXCLUDED.snapshot_seq,
+                     snapshot_bytes = EXCLUDED.snapshot_bytes,
+                     offloaded_to = NULL"
+            )
+            .bind(doc_id)
+            .bind(&stored) // Vec<u8> for BYTEA
+            .bind(now)
+            .bind(&fingerprint.checksum)
+            .bind(fingerprint.simhash.map(|simhash| simhash as i64))
+            .bind(fingerprint.bands())
+            .bind(preview::render(&content_data, language))
+            .bind(seq)
+            .bind(content_data.len() as i64)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to update document content for ID {}", doc_id))?;
+
+        // The snapshot supersedes any updates not yet folded into the previous one.
+        let superseded_bytes = self.clear_updates(&mut tx, doc_id, seq).await?;
+        self.save_attribution(&mut tx, doc_id, seq, attribution).await?;
+        references::record(&mut tx, doc_id, &content_data).await?;
+        storage_quota::charge(&mut tx, doc_id, content_data.len() as i64 - replaced_bytes - superseded_bytes, quota).await?;
+        outbox::record(&mut tx, DocumentEvent::Changed { doc_id }).await?;
+        let updated_at = stamp_updated_at(&mut tx, doc_id).await?;
+        tx.commit().await.context(format!("Failed to commit content of document ID {}", doc_id))?;
+
+        debug!("Updated content for document ID: {}", doc_id);
+        self.hooks.post_save(&SaveEvent { doc_id, content: &content_data }).await;
+        self.events.publish(DocumentEvent::Changed { doc_id });
+        Ok(CausalityToken::new(updated_at))
+    }
+
+    /// Saves `content`, an edit of `previous` by `author`, by appending only the changed
+    /// span to the document's update log. Falls back to a new snapshot for large rewrites,
+    /// or if `previous` is not the document's current content.
+    #[instrument(skip_all, fields(doc_id = %doc_id, author = ?author))]
+    pub async fn append_document_update(
+        &self,
+        doc_id: Uuid,
+        previous: &[u8],
+        content: Vec<u8>,
+        author: Option<Uuid>,
+    ) -> Result<CausalityToken> {
+        self.check_document_size(content.len())?;
+        let delta = ContentDelta::between(previous, &content);
+        if delta.insert.len() >= content.len() / 2 {
+            return self.rewrite_document_content(doc_id, content, author).await;
+        }
+        self.hooks.pre_save(&SaveEvent { doc_id, content: &content }).await?;
+        let now = Utc::now().trunc_to_millis();
+        let fingerprint = Fingerprint::of(&content);
+        let stored = self.seal(doc_id, delta.insert.clone()).await?;
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+
+        // The checksum of the current content is that of the last update, or of the snapshot
+        // if there is none. Locking the row keeps other writers out until we commit.
+        let current: Option<(Option<String>,)> = sqlx::query_as(
+                "SELECT COALESCE(
+                     (SELECT u.checksum FROM document_updates u
+                      WHERE u.document_id = c.document_id AND u.seq > c.snapshot_seq ORDER BY u.seq DESC LIMIT 1),
+                     c.checksum)
+                 FROM documents_content c WHERE c.document_id = $1 FOR UPDATE"
+            )
+            .bind(doc_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .context(format!("Failed to query current checksum of document ID {}", doc_id))?;
+        if current.and_then(|(checksum,)| checksum) != Some(integrity::checksum(previous)) {
+            tx.rollback().await.ok();
+            return self.rewrite_document_content(doc_id, content, author).await;
+        }
+
+        // Updates leave the language as of the last snapshot.
+        let (seq, language): (i64, Option<String>) = sqlx::query_as(
+                "UPDATE documents_metadata SET seq = seq + 1 WHERE id = $1 RETURNING seq, language"
+            )
+            .bind(doc_id)
+            .fetch_one(&mut *tx)
+            .await
+            .context(format!("Failed to update metadata timestamp for ID {}", doc_id))?;
+        sqlx::query(
+                "INSERT INTO document_updates (document_id, seq, start_offset, delete_len, data, insert_len, checksum, created_at, author)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"
+            )
+            .bind(doc_id)
+            .bind(seq)
+            .bind(delta.start as i64)
+            .bind(delta.delete_len as i64)
+            .bind(&stored)
+            .bind(delta.insert.len() as i64)
+            .bind(&fingerprint.checksum)
+            .bind(now)
+            .bind(author)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to append update to document ID {}", doc_id))?;
+        storage_quota::charge(&mut tx, doc_id, delta.insert.len() as i64, self.storage_quota).await?;
+        // Everything derived from the content describes the latest version; only crdt_data
+        // and its checksum wait for compaction.
+        sqlx::query(
+                "UPDATE documents_content SET updated_at = $1, simhash = $2, simhash_bands = $3, preview_html = $4
+                 WHERE document_id = $5"
+            )
+            .bind(now)
+            .bind(fingerprint.simhash.map(|simhash| simhash as i64))
+            .bind(fingerprint.bands())
+            .bind(preview::render(&content, language.as_deref()))
+            .bind(doc_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to update content metadata for ID {}", doc_id))?;
+        outbox::record(&mut tx, DocumentEvent::Changed { doc_id }).await?;
+        let updated_at = stamp_updated_at(&mut tx, doc_id).await?;
+        tx.commit().await.context(format!("Failed to commit update to document ID {}", doc_id))?;
+
+        self.hooks.post_save(&SaveEvent { doc_id, content: &content }).await;
+        self.events.publish(DocumentEvent::Changed { doc_id });
+        Ok(CausalityToken::new(updated_at))
+    }
+
+    /// Documents with at least `min_updates` updates not yet folded into their snapshot.
+    #[instrument(skip_all)]
+    pub async fn find_documents_to_compact(&self, min_updates: i64, limit: i64) -> Result<Vec<Uuid>> {
+        sqlx::query_scalar(
+                "SELECT document_id FROM document_updates GROUP BY document_id HAVING count(*) >= $1 LIMIT $2"
+            )
+            .bind(min_updates)
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to query documents to compact")
+    }
+
+    /// Folds a document's logged updates into its snapshot. Returns false if there was
+    /// nothing to fold or the snapshot was replaced concurrently.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn compact_document(&self, doc_id: Uuid) -> Result<bool> {
+        // Loading also rehydrates offloaded content, so the snapshot is in the database.
+        let Some((content, snapshot_seq, latest_seq)) = self.load_document_content(doc_id, ReadConsistency::Strong).await? else {
+            return Ok(false);
+        };
+        if latest_seq == snapshot_seq {
+            return Ok(false);
+        }
+        let checksum = integrity::checksum(&content.crdt_data);
+        // The updates are about to go, so keep who wrote what with the snapshot.
+        let attribution = self.attribution_at(doc_id, latest_seq).await?;
+        let stored = self.seal_snapshot(doc_id, content.crdt_data.clone()).await?;
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let replaced_bytes = self.snapshot_bytes(&mut tx, doc_id).await?;
+        let result = sqlx::query(
+                "UPDATE documents_content SET crdt_data = $1, checksum = $2, snapshot_seq = $3, snapshot_bytes = $4
+                 WHERE document_id = $5 AND snapshot_seq = $6 AND offloaded_to IS NULL"
+            )
+            .bind(&stored)
+            .bind(&checksum)
+            .bind(latest_seq)
+            .bind(content.crdt_data.len() as i64)
+            .bind(doc_id)
+            .bind(snapshot_seq)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to compact document ID {}", doc_id))?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        // The language follows snapshots; edits since the last one are taken in now.
+        sqlx::query("UPDATE documents_metadata SET language = $1 WHERE id = $2")
+            .bind(language::detect(&content.crdt_data))
+            .bind(doc_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to update language of document ID {}", doc_id))?;
+        let compacted_bytes = self.clear_updates(&mut tx, doc_id, latest_seq).await?;
+        self.save_attribution(&mut tx, doc_id, latest_seq, attribution.as_ref()).await?;
+        references::record(&mut tx, doc_id, &content.crdt_data).await?;
+        // Folding updates in seldom grows a document, and is never the owner's doing.
+        storage_quota::charge(&mut tx, doc_id, content.crdt_data.len() as i64 - replaced_bytes - compacted_bytes, None).await?;
+        tx.commit().await.context(format!("Failed to commit compaction of document ID {}", doc_id))?;
+        info!("Compacted {} updates into document ID {}", latest_seq - snapshot_seq, doc_id);
+        Ok(true)
+    }
+
+    /// Size of the document's current snapshot, wherever it is stored.
+    async fn snapshot_bytes(&self, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, doc_id: Uuid) -> Result<i64> {
+        let bytes: Option<Option<i64>> = sqlx::query_scalar("SELECT snapshot_bytes FROM documents_content WHERE document_id = $1")
+            .bind(doc_id)
+            .fetch_optional(&mut **tx)
+            .await
+            .context(format!("Failed to query snapshot size of document ID {}", doc_id))?;
+        Ok(bytes.flatten().unwrap_or(0))
+    }
+
+    /// Deletes the document's logged updates up to `seq`, returning how many bytes they were charged for.
+    async fn clear_updates(&self, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, doc_id: Uuid, seq: i64) -> Result<i64> {
+        sqlx::query_scalar(
+                "WITH cleared AS (DELETE FROM document_updates WHERE document_id = $1 AND seq <= $2 RETURNING insert_len AS bytes)
+                 SELECT COALESCE(sum(bytes), 0)::BIGINT FROM cleared"
+            )
+            .bind(doc_id)
+            .bind(seq)
+            .fetch_one(&mut **tx)
+            .await
+            .context(format!("Failed to clear superseded updates for ID {}", doc_id))
+    }
+
+    /// Caches who wrote the snapshot taken at `seq`, or forgets it if that is not known.
+    async fn save_attribution(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        doc_id: Uuid,
+        seq: i64,
+        attribution: Option<&Attribution>,
+    ) -> Result<()> {
+        let query = match attribution {
+            Some(attribution) => sqlx::query(
+                    "INSERT INTO document_blame (document_id, seq, runs) VALUES ($1, $2, $3)
+                     ON CONFLICT (document_id) DO UPDATE SET seq = EXCLUDED.seq, runs = EXCLUDED.runs"
+                )
+                .bind(doc_id)
+                .bind(seq)
+                .bind(Json(attribution)),
+            None => sqlx::query("DELETE FROM document_blame WHERE document_id = $1").bind(doc_id),
+        };
+        query.execute(&mut **tx).await.context(format!("Failed to save attribution of document ID {}", doc_id))?;
+        Ok(())
+    }
+
+    /// Who wrote each byte of the document's saved content; see `blame`. `None` if there is
+    /// no such document.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn blame(&self, doc_id: Uuid) -> Result<Option<Attribution>> {
+        self.attribution_at(doc_id, i64::MAX).await
+    }
+
+    /// Who wrote the content as of update `seq`: the attribution cached with the snapshot,
+    /// followed through the updates logged since.
+    async fn attribution_at(&self, doc_id: Uuid, seq: i64) -> Result<Option<Attribution>> {
+        // The log first, as in `load_document_content`.
+        let updates: Vec<AttributedUpdate> = sqlx::query_as(
+                "SELECT seq, start_offset, delete_len, insert_len, author, created_at
+                 FROM document_updates WHERE document_id = $1 AND seq <= $2 ORDER BY seq"
+            )
+            .bind(doc_id)
+            .bind(seq)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query updates to document ID {}", doc_id))?;
+        let row: Option<SnapshotAttribution> = sqlx::query_as(
+                "SELECT c.snapshot_seq, COALESCE(c.snapshot_bytes, octet_length(c.crdt_data), 0)::BIGINT AS snapshot_bytes,
+                        b.seq AS cached_seq, b.runs AS cached
+                 FROM documents_content c LEFT JOIN document_blame b ON b.document_id = c.document_id
+                 WHERE c.document_id = $1"
+            )
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query attribution of document ID {}", doc_id))?;
+        let Some(SnapshotAttribution { snapshot_seq, snapshot_bytes, cached_seq, cached }) = row else {
+            return Ok(None);
+        };
+        let mut attribution = match cached {
+            Some(Json(cached)) if cached_seq == Some(snapshot_seq) && cached.len() == snapshot_bytes as usize => cached,
+            _ => Attribution::unknown(snapshot_bytes as usize),
+        };
+        for update in updates.into_iter().filter(|update| update.seq > snapshot_seq) {
+            let (start, delete_len, insert_len) = (update.start_offset as usize, update.delete_len as usize, update.insert_len as usize);
+            let len = attribution.len();
+            if let Err(e) = attribution.apply(start, delete_len, insert_len, update.author, update.created_at) {
+                warn!("Update {} to document ID {} cannot be attributed: {:#}", update.seq, doc_id, e);
+                attribution = Attribution::unknown((len + insert_len).saturating_sub(delete_len));
+            }
+        }
+        Ok(Some(attribution))
+    }
+
+    /// The document's cached preview; see `preview::render`. Previews of content saved before
+    /// previews existed are rendered on first request. `None` if there is no such document
+    /// or its content is not text.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn get_document_preview(&self, doc_id: Uuid) -> Result<Option<String>> {
+        let row: Option<(Option<String>,)> = sqlx::query_as("SELECT preview_html FROM documents_content WHERE document_id = $1")
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query preview for document ID {}", doc_id))?;
+        match row {
+            None => Ok(None),
+            Some((Some(html),)) => Ok(Some(html)),
+            Some((None,)) => {
+                let Some(content) = self.get_document_content(doc_id).await? else {
+                    return Ok(None);
+                };
+                let Some(html) = preview::render(&content.crdt_data, language::detect(&content.crdt_data)) else {
+                    return Ok(None);
+                };
+                // Only fill in the gap; a concurrent save has already stored a newer preview.
+                sqlx::query("UPDATE documents_content SET preview_html = $1 WHERE document_id = $2 AND updated_at = $3 AND preview_html IS NULL")
+                    .bind(&html)
+                    .bind(doc_id)
+                    .bind(content.updated_at)
+                    .execute(&*self.db_manager.pool)
+                    .await
+                    .context(format!("Failed to cache preview for document ID {}", doc_id))?;
+                Ok(Some(html))
+            }
+        }
+    }
+
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn get_document_content(&self, doc_id: Uuid) -> Result<Option<DocumentContent>> {
+        self.get_document_content_with(doc_id, ReadConsistency::Strong).await
+    }
+
+    /// Fails with `ChecksumMismatch` if the stored content is corrupt.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn get_document_content_with(&self, doc_id: Uuid, consistency: ReadConsistency) -> Result<Option<DocumentContent>> {
+        Ok(self.load_document_content(doc_id, consistency).await?.map(|(content, _, _)| content))
+    }
+
+    /// The document's content with its logged updates applied, the sequence number its
+    /// snapshot was taken at, and that of the last update applied.
+    async fn load_document_content(&self, doc_id: Uuid, consistency: ReadConsistency) -> Result<Option<(DocumentContent, i64, i64)>> {
+        let consistency = consistency.resolve_in(&self.db_manager.pool).await?;
+        // Read the log first: a snapshot written meanwhile only supersedes updates, so at worst
+        // the content is a little behind rather than missing a change in the middle.
+        let updates: Vec<(i64, i64, i64, Vec<u8>, String)> = sqlx::query_as(&format!(
+                "SELECT seq, start_offset, delete_len, data, checksum FROM document_updates{} WHERE document_id = $1 ORDER BY seq",
+                consistency.as_of_clause()
+            ))
+            .bind(doc_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query updates to document ID {}", doc_id))?;
+        let row_opt = sqlx::query(&format!(
+                "SELECT document_id, crdt_data, offloaded_to, checksum, snapshot_seq, updated_at FROM documents_content{} WHERE document_id = $1",
+                consistency.as_of_clause()
+            ))
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query document content for ID {}", doc_id))?;
+        match row_opt {
+            Some(row) => {
+                let checksum: Option<String> = row.try_get("checksum").context("Failed to get 'checksum' from row")?;
+                // Offloaded documents keep only a stub row; pull their content back in on first access.
+                let mut crdt_data = match row.try_get::<Option<String>, _>("offloaded_to").context("Failed to get 'offloaded_to' from row")? {
+                    Some(key) => self.rehydrate_document(doc_id, &key, checksum.as_deref()).await?,
+                    None => {
+                        let stored = row.try_get::<Option<Vec<u8>>, _>("crdt_data").context("Failed to get 'crdt_data' from row")?.unwrap_or_default();
+                        let crdt_data = self.open_snapshot(doc_id, stored).await?;
+                        integrity::verify(format_args!("document ID {}", doc_id), &crdt_data, checksum.as_deref())?;
+                        crdt_data
+                    }
+                };
+                let snapshot_seq: i64 = row.try_get("snapshot_seq").context("Failed to get 'snapshot_seq' from row")?;
+                let mut applied_seq = snapshot_seq;
+                let mut expected_checksum = None;
+                for (seq, start, delete_len, data, checksum) in updates.into_iter().filter(|(seq, ..)| *seq > snapshot_seq) {
+                    let delta = ContentDelta { start: start as usize, delete_len: delete_len as usize, insert: self.open(doc_id, data).await? };
+                    delta.apply(&mut crdt_data).context(format!("Failed to apply update {} to document ID {}", seq, doc_id))?;
+                    applied_seq = seq;
+                    expected_checksum = Some(checksum);
+                }
+                if let Some(checksum) = expected_checksum {
+                    integrity::verify(format_args!("document ID {} (update {})", doc_id, applied_seq), &crdt_data, Some(&checksum))?;
+                }
+                let content = DocumentContent {
+                    document_id: row.try_get("document_id").context("Failed to get 'document_id' from row")?, // UUID
+                    crdt_data,
+                    updated_at: row.try_get::<DateTime<Utc>, _>("updated_at").context("Failed to get 'updated_at' from row")?.trunc_to_millis(),
+                };
+                Ok(Some((content, snapshot_seq, applied_seq)))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// An earlier version of the document's content, found by its checksum among the
+    /// snapshot and the updates logged since. `None` if the log no longer reaches back that
+    /// far, e.g. after compaction, or the document is in cold storage.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn logged_content(&self, doc_id: Uuid, checksum: &str) -> Result<Option<Vec<u8>>> {
+        // The log first, as in `load_document_content`.
+        let updates: Vec<(i64, i64, i64, Vec<u8>, String)> = sqlx::query_as(
+                "SELECT seq, start_offset, delete_len, data, checksum FROM document_updates WHERE document_id = $1 ORDER BY seq"
+            )
+            .bind(doc_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query updates to document ID {}", doc_id))?;
+        let row: Option<(Option<Vec<u8>>, Option<String>, i64)> = sqlx::query_as(
+                "SELECT crdt_data, checksum, snapshot_seq FROM documents_content WHERE document_id = $1 AND offloaded_to IS NULL"
+            )
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query document content for ID {}", doc_id))?;
+        let Some((crdt_data, snapshot_checksum, snapshot_seq)) = row else {
+            return Ok(None);
+        };
+        let mut content = self.open_snapshot(doc_id, crdt_data.unwrap_or_default()).await?;
+        integrity::verify(format_args!("document ID {}", doc_id), &content, snapshot_checksum.as_deref())?;
+        if integrity::checksum(&content) == checksum {
+            return Ok(Some(content));
+        }
+        for (seq, start, delete_len, data, update_checksum) in updates.into_iter().filter(|(seq, ..)| *seq > snapshot_seq) {
+            let delta = ContentDelta { start: start as usize, delete_len: delete_len as usize, insert: self.open(doc_id, data).await? };
+            delta.apply(&mut content).context(format!("Failed to apply update {} to document ID {}", seq, doc_id))?;
+            if update_checksum == checksum {
+                integrity::verify(format_args!("document ID {} (update {})", doc_id, seq), &content, Some(&update_checksum))?;
+                return Ok(Some(content));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Records that a document was opened, which keeps it out of cold storage.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn mark_document_opened(&self, doc_id: Uuid) -> Result<()> {
+        self.db_manager.pool
+            .execute(sqlx::query("UPDATE documents_metadata SET last_opened_at = $1 WHERE id = $2")
+                .bind(Utc::now().trunc_to_millis())
+                .bind(doc_id)
+            )
+            .await
+            .context(format!("Failed to record opening of document ID {}", doc_id))?;
+        Ok(())
+    }
+
+    /// Documents whose content is still in the database that were neither opened nor
+    /// modified since `cold_before`, or, whatever their size, that expired before
+    /// `expired_before` and were not opened since.
+    #[instrument(skip_all)]
+    pub async fn find_cold_documents(&self, cold_before: DateTime<Utc>, expired_before: DateTime<Utc>, min_bytes: usize, limit: i64) -> Result<Vec<Uuid>> {
+        let rows = sqlx::query(
+                "SELECT c.document_id FROM documents_content c
+                 JOIN documents_metadata m ON m.id = c.document_id
+                 WHERE c.offloaded_to IS NULL
+                   AND ((octet_length(c.crdt_data) >= $1
+                         AND m.updated_at < $2
+                         AND (m.last_opened_at IS NULL OR m.last_opened_at < $2))
+                        OR (m.expires_at < $3 AND (m.last_opened_at IS NULL OR m.last_opened_at < $3)))
+                 LIMIT $4"
+            )
+            .bind(min_bytes as i64)
+            .bind(cold_before)
+            .bind(expired_before)
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to query cold documents")?;
+        rows.iter()
+            .map(|row| row.try_get("document_id").context("Failed to get 'document_id' from row"))
+            .collect()
+    }
+
+    /// Moves a document's content to cold storage, leaving a stub row behind.
+    /// Returns false if there was nothing to offload or the document changed concurrently.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn offload_document(&self, doc_id: Uuid) -> Result<bool> {
+        let cold_storage = self.cold_storage.as_ref()
+            .context("Cold storage is not configured")?;
+        let row_opt = sqlx::query(
+                "SELECT crdt_data, updated_at FROM documents_content WHERE document_id = $1 AND offloaded_to IS NULL"
+            )
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query document content for ID {}", doc_id))?;
+        let Some(row) = row_opt else {
+            return Ok(false);
+        };
+        let crdt_data: Vec<u8> = row.try_get::<Option<Vec<u8>>, _>("crdt_data").context("Failed to get 'crdt_data' from row")?.unwrap_or_default();
+        let updated_at: DateTime<Utc> = row.try_get("updated_at").context("Failed to get 'updated_at' from row")?;
+
+        let key = cold_storage.put(doc_id, crdt_data).await?;
+        // Only stub the row if nobody wrote to the document while we were uploading.
+        let result = self.db_manager.pool
+            .execute(sqlx::query(
+                "UPDATE documents_content SET crdt_data = NULL, offloaded_to = $1
+                 WHERE document_id = $2 AND updated_at = $3 AND offloaded_to IS NULL"
+                )
+                .bind(&key)
+                .bind(doc_id)
+                .bind(updated_at)
+            )
+            .await
+            .context(format!("Failed to stub offloaded document ID {}", doc_id))?;
+        if result.rows_affected() == 0 {
+            cold_storage.delete(&key).await.ok();
+            return Ok(false);
+        }
+        info!("Offloaded content of document ID {} to {}", doc_id, key);
+        Ok(true)
+    }
+
+    fn cold_storage_for(&self, doc_id: Uuid) -> Result<&ColdStorage> {
+        self.cold_storage.as_deref()
+            .context(format!("Document ID {} is offloaded but cold storage is not configured", doc_id))
+    }
+
+    /// `content` as it is to be stored; see `Encryption::seal`.
+    async fn seal(&self, doc_id: Uuid, content: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.encryption {
+            Some(encryption) => encryption.seal(doc_id, content).await,
+            None => Ok(content),
+        }
+    }
+
+    /// Content as it was before it was stored; see `Encryption::open`.
+    pub(crate) async fn open(&self, doc_id: Uuid, stored: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.encryption {
+            Some(encryption) => encryption.open(doc_id, stored).await,
+            None if encryption::is_sealed(&stored) => {
+                Err(anyhow!("Document ID {} is encrypted but no KMS is configured", doc_id))
+            }
+            None => Ok(stored),
+        }
+    }
+
+    /// A snapshot as it is to be stored: compressed, then sealed.
+    async fn seal_snapshot(&self, doc_id: Uuid, content: Vec<u8>) -> Result<Vec<u8>> {
+        let compressed = self.compression.compress(content)?;
+        self.seal(doc_id, compressed).await
+    }
+
+    /// A snapshot as it was before it was stored; see `seal_snapshot`.
+    async fn open_snapshot(&self, doc_id: Uuid, stored: Vec<u8>) -> Result<Vec<u8>> {
+        let compressed = self.open(doc_id, stored).await?;
+        compression::decompress(compressed).context(format!("Failed to read snapshot of document ID {}", doc_id))
+    }
+
+    async fn rehydrate_document(&self, doc_id: Uuid, key: &str, checksum: Option<&str>) -> Result<Vec<u8>> {
+        let cold_storage = self.cold_storage_for(doc_id)?;
+        let stored = cold_storage.get(key).await?;
+        let crdt_data = self.open_snapshot(doc_id, stored.clone()).await?;
+        // Never write a corrupt object back over the stub; the row still points at the evidence.
+        integrity::verify(format_args!("document ID {} (offloaded to {})", doc_id, key), &crdt_data, checksum)?;
+        let result = self.db_manager.pool
+            .execute(sqlx::query(
+                "UPDATE documents_content SET crdt_data = $1, offloaded_to = NULL
+                 WHERE document_id = $2 AND offloaded_to = $3"
+                )
+                .bind(&stored)
+                .bind(doc_id)
+                .bind(key)
+            )
+            .await
+            .context(format!("Failed to rehydrate document ID {}", doc_id))?;
+        // If another reader won the race the object is already gone; either way the data is good.
+        if result.rows_affected() > 0 {
+            cold_storage.delete(key).await.ok();
+            info!("Rehydrated content of document ID {} from {}", doc_id, key);
+        }
+        Ok(crdt_data)
+    }
+
+    /// Metadata of every document modified at or after `since`, oldest change first.
+    #[instrument(skip_all)]
+    pub async fn list_documents_updated_since(&self, since: DateTime<Utc>) -> Result<Vec<DocumentMetadata>> {
+        let rows = sqlx::query(
+                "SELECT id, name, created_at, updated_at, language FROM documents_metadata
+                 WHERE updated_at >= $1 ORDER BY updated_at"
+            )
+            .bind(since)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list documents updated since {}", since))?;
+        rows.iter()
+            .map(|row| Ok(DocumentMetadata {
+                id: row.try_get("id").context("Failed to get 'id' from row")?,
+                name: row.try_get("name").context("Failed to get 'name' from row")?,
+                created_at: row.try_get::<DateTime<Utc>, _>("created_at").context("Failed to get 'created_at' from row")?.trunc_to_millis(),
+                updated_at: row.try_get::<DateTime<Utc>, _>("updated_at").context("Failed to get 'updated_at' from row")?.trunc_to_millis(),
+                language: row.try_get("language").context("Failed to get 'language' from row")?,
+            }))
+            .collect()
+    }
+
+    /// Metadata and change sequence number of each of `ids` that still exists. The sequence
+    /// number grows with every change to the document, so clients can tell what they missed.
+    #[instrument(skip_all)]
+    pub async fn get_document_sequences(&self, ids: &[Uuid], consistency: ReadConsistency) -> Result<Vec<(DocumentMetadata, i64)>> {
+        let consistency = consistency.resolve_in(&self.db_manager.pool).await?;
+        let rows = sqlx::query(&format!(
+                "SELECT id, name, created_at, updated_at, language, seq FROM documents_metadata{} WHERE id = ANY($1) AND deleted_at IS NULL",
+                consistency.as_of_clause()
+            ))
+            .bind(ids)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to query document sequence numbers")?;
+        rows.iter()
+            .map(|row| Ok((
+                DocumentMetadata {
+                    id: row.try_get("id").context("Failed to get 'id' from row")?,
+                    name: row.try_get("name").context("Failed to get 'name' from row")?,
+                    created_at: row.try_get::<DateTime<Utc>, _>("created_at").context("Failed to get 'created_at' from row")?.trunc_to_millis(),
+                    updated_at: row.try_get::<DateTime<Utc>, _>("updated_at").context("Failed to get 'updated_at' from row")?.trunc_to_millis(),
+                    language: row.try_get("language").context("Failed to get 'language' from row")?,
+                },
+                row.try_get("seq").context("Failed to get 'seq' from row")?,
+            )))
+            .collect()
+    }
+
+    /// Documents whose name contains `query`, case-insensitively, most recently updated first.
+    /// `restrict_to` limits the search to the given documents, for callers with scoped access.
+    #[instrument(skip_all)]
+    pub async fn search_documents_by_name(
+        &self,
+        query: &str,
+        restrict_to: Option<&[Uuid]>,
+        limit: i64,
+        consistency: ReadConsistency,
+    ) -> Result<Vec<DocumentMetadata>> {
+        let consistency = consistency.resolve_in(&self.db_manager.pool).await?;
+        let rows = sqlx::query(&format!(
+                "SELECT id, name, created_at, updated_at, language FROM documents_metadata{}
+                 WHERE name ILIKE $1 AND ($2::UUID[] IS NULL OR id = ANY($2)) AND deleted_at IS NULL
+                 ORDER BY updated_at DESC LIMIT $3",
+                consistency.as_of_clause()
+            ))
+            .bind(search::like_pattern(query))
+            .bind(restrict_to)
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to search documents for '{}'", query))?;
+        rows.iter()
+            .map(|row| Ok(DocumentMetadata {
+                id: row.try_get("id").context("Failed to get 'id' from row")?,
+                name: row.try_get("name").context("Failed to get 'name' from row")?,
+                created_at: row.try_get::<DateTime<Utc>, _>("created_at").context("Failed to get 'created_at' from row")?.trunc_to_millis(),
+                updated_at: row.try_get::<DateTime<Utc>, _>("updated_at").context("Failed to get 'updated_at' from row")?.trunc_to_millis(),
+                language: row.try_get("language").context("Failed to get 'language' from row")?,
+            }))
+            .collect()
+    }
+
+    /// One page of the documents `filter` lets through, in `sort` order, continuing after
+    /// `cursor` if given.
+    #[instrument(skip_all, fields(owner = ?filter.owner))]
+    pub async fn list_documents(
+        &self,
+        filter: &DocumentFilter<'_>,
+        cursor: Option<&DocumentCursor>,
+        limit: usize,
+        sort: DocumentSort,
+        consistency: ReadConsistency,
+    ) -> Result<DocumentPage> {
+        if cursor.is_some_and(|cursor| cursor.sort != sort) {
+            return Err(anyhow!(InvalidCursor));
+        }
+        let consistency = consistency.resolve_in(&self.db_manager.pool).await?;
+        let (column, direction, comparison) = sort.order();
+        let mut sql = format!(
+            "SELECT id, name, created_at, updated_at, language FROM documents_metadata{}
+             WHERE ($1::UUID IS NULL OR owner_id = $1) AND ($2::UUID[] IS NULL OR id = ANY($2))
+               AND ($4::TEXT IS NULL OR language = $4) AND deleted_at IS NULL
+               AND ($5::UUID[] IS NULL OR (owner_id IS NULL AND workspace_id IS NULL) OR id = ANY($5))",
+            consistency.as_of_clause()
+        );
+        if cursor.is_some() {
+            sql.push_str(&format!(" AND ({}, id) {} ($6, $7)", column, comparison));
+        }
+        sql.push_str(&format!(" ORDER BY {} {}, id {} LIMIT $3", column, direction, direction));
+
+        // One extra row tells us whether there is another page.
+        let mut query = sqlx::query_as::<_, DocumentMetadata>(&sql)
+            .bind(filter.owner)
+            .bind(filter.restrict_to)
+            .bind(limit as i64 + 1)
+            .bind(filter.language)
+            .bind(filter.public_and);
+        if let Some(cursor) = cursor {
+            query = match &cursor.key {
+                CursorKey::Time(time) => query.bind(*time),
+                CursorKey::Name(name) => query.bind(name.clone()),
+            };
+            query = query.bind(cursor.id);
+        }
+        let mut documents = query
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to list documents")?;
+        let next_cursor = if documents.len() > limit {
+            documents.truncate(limit);
+            documents.last().map(|last| DocumentCursor::after(last, sort))
+        } else {
+            None
+        };
+        for document in &mut documents {
+            document.created_at = document.created_at.trunc_to_millis();
+            document.updated_at = document.updated_at.trunc_to_millis();
+        }
+        Ok(DocumentPage { documents, next_cursor })
+    }
+
+    /// Documents whose content is identical or nearly identical to content with `fingerprint`,
+    /// most similar first. `restrict_to` limits the search to those documents.
+    #[instrument(skip_all)]
+    pub async fn find_duplicates(
+        &self,
+        fingerprint: &Fingerprint,
+        restrict_to: Option<&[Uuid]>,
+        limit: usize,
+    ) -> Result<Vec<DuplicateCandidate>> {
+        let rows: Vec<DuplicateRow> = sqlx::query_as(
+                "SELECT m.id, m.name, m.created_at, m.updated_at, m.language, c.checksum, c.simhash
+                 FROM documents_content c JOIN documents_metadata m ON m.id = c.document_id
+                 WHERE (c.checksum = $1 OR c.simhash_bands && $2) AND ($3::UUID[] IS NULL OR m.id = ANY($3))
+                   AND m.deleted_at IS NULL
+                 ORDER BY c.checksum = $1 DESC LIMIT $4"
+            )
+            .bind(&fingerprint.checksum)
+            .bind(fingerprint.bands().unwrap_or_default())
+            .bind(restrict_to)
+            .bind(DUPLICATE_CANDIDATE_LIMIT)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to look up duplicate documents")?;
+        let mut duplicates: Vec<DuplicateCandidate> = rows
+            .into_iter()
+            .filter_map(|row| {
+                let exact = row.checksum.as_deref() == Some(fingerprint.checksum.as_str());
+                let similarity = match (fingerprint.simhash, row.simhash) {
+                    _ if exact => 1.0,
+                    (Some(a), Some(b)) if fingerprint::is_near_duplicate(a, b as u64) => fingerprint::similarity(a, b as u64),
+                    _ => return None,
+                };
+                let metadata = DocumentMetadata {
+                    id: row.id,
+                    name: row.name,
+                    created_at: row.created_at.trunc_to_millis(),
+                    updated_at: row.updated_at.trunc_to_millis(),
+                    language: row.language,
+                };
+                Some(DuplicateCandidate { metadata, exact, similarity })
+            })
+            .collect();
+        duplicates.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        duplicates.truncate(limit);
+        Ok(duplicates)
+    }
+
+    /// The document's protected ranges, in document order.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn list_protected_ranges(&self, doc_id: Uuid) -> Result<Vec<ProtectedRange>> {
+        let rows = sqlx::query(
+                "SELECT id, start_offset, end_offset, editors FROM document_protected_ranges
+                 WHERE document_id = $1 ORDER BY start_offset"
+            )
+            .bind(doc_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query protected ranges of document ID {}", doc_id))?;
+        rows.iter()
+            .map(|row| Ok(ProtectedRange {
+                id: row.try_get("id").context("Failed to get 'id' from row")?,
+                start: row.try_get::<i64, _>("start_offset").context("Failed to get 'start_offset' from row")? as usize,
+                end: row.try_get::<i64, _>("end_offset").context("Failed to get 'end_offset' from row")? as usize,
+                editors: row.try_get::<Vec<String>, _>("editors").context("Failed to get 'editors' from row")?
+                    .iter()
+                    .map(|role| role.parse())
+                    .collect::<Result<_>>()?,
+            }))
+            .collect()
+    }
+
+    /// Replaces the document's protected ranges. Offsets are relative to its latest saved content.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn save_protected_ranges(&self, doc_id: Uuid, ranges: &[ProtectedRange]) -> Result<()> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        sqlx::query("DELETE FROM document_protected_ranges WHERE document_id = $1")
+            .bind(doc_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to clear protected ranges of document ID {}", doc_id))?;
+        for range in ranges {
+            sqlx::query(
+                    "INSERT INTO document_protected_ranges (id, document_id, start_offset, end_offset, editors)
+                     VALUES ($1, $2, $3, $4, $5)"
+                )
+                .bind(range.id)
+                .bind(doc_id)
+                .bind(range.start as i64)
+                .bind(range.end as i64)
+                .bind(range.editors.iter().map(|role| role.as_str()).collect::<Vec<_>>())
+                .execute(&mut *tx)
+                .await
+                .context(format!("Failed to save protected range {} of document ID {}", range.id, doc_id))?;
+        }
+        tx.commit().await.context(format!("Failed to commit protected ranges of document ID {}", doc_id))?;
+        Ok(())
+    }
+
+    /// Up to `limit` document IDs in ID order, starting after `after`, for walking every document.
+    #[instrument(skip_all, fields(after = ?after))]
+    pub async fn list_document_ids(&self, after: Option<Uuid>, limit: i64) -> Result<Vec<Uuid>> {
+        let rows = sqlx::query(
+                "SELECT id FROM documents_metadata WHERE ($1::UUID IS NULL OR id > $1) ORDER BY id LIMIT $2"
+            )
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to list document IDs")?;
+        rows.iter()
+            .map(|row| row.try_get("id").context("Failed to get 'id' from row"))
+            .collect()
+    }
+
+    /// Compares a document's stored content against its checksum without changing anything;
+    /// offloaded content is read from cold storage but left there. `None` if there is no content.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn check_document_integrity(&self, doc_id: Uuid) -> Result<Option<Integrity>> {
+        let row_opt = sqlx::query(
+                "SELECT crdt_data, offloaded_to, checksum FROM documents_content WHERE document_id = $1"
+            )
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query document content for ID {}", doc_id))?;
+        let Some(row) = row_opt else {
+            return Ok(None);
+        };
+        let Some(checksum) = row.try_get::<Option<String>, _>("checksum").context("Failed to get 'checksum' from row")? else {
+            return Ok(Some(Integrity::Unverified));
+        };
+        let stored = match row.try_get::<Option<String>, _>("offloaded_to").context("Failed to get 'offloaded_to' from row")? {
+            Some(key) => self.cold_storage_for(doc_id)?.get(&key).await?,
+            None => row.try_get::<Option<Vec<u8>>, _>("crdt_data").context("Failed to get 'crdt_data' from row")?.unwrap_or_default(),
+        };
+        // Content whose data key was revoked can no longer be checked.
+        let crdt_data = match self.open_snapshot(doc_id, stored).await {
+            Err(e) if e.downcast_ref() == Some(&EncryptionError::Unreadable) => return Ok(Some(Integrity::Unverified)),
+            opened => opened?,
+        };
+        Ok(Some(match integrity::verify(format_args!("document ID {}", doc_id), &crdt_data, Some(&checksum)) {
+            Ok(()) => Integrity::Intact,
+            Err(mismatch) => Integrity::Corrupted(mismatch),
+        }))
+    }
+
+    /// Saves the document's current state as a new version. `None` if there is no such document.
+    #[instrument(skip_all, fields(doc_id = %doc_id, created_by = ?created_by))]
+    pub async fn create_version(&self, doc_id: Uuid, created_by: Option<Uuid>) -> Result<Option<DocumentVersion>> {
+        let crdt_data = self.get_document_content(doc_id).await?.map(|content| content.crdt_data).unwrap_or_default();
+        self.save_version(doc_id, &crdt_data, created_by).await
+    }
+
+    /// Saves `crdt_data` as a new version of the document, for content that is newer than what
+    /// is stored, such as a room's. `None` if there is no such document.
+    #[instrument(skip_all, fields(doc_id = %doc_id, created_by = ?created_by))]
+    pub async fn save_version(&self, doc_id: Uuid, crdt_data: &[u8], created_by: Option<Uuid>) -> Result<Option<DocumentVersion>> {
+        let Some(metadata) = self.get_document_metadata(doc_id).await? else {
+            return Ok(None);
+        };
+        let stored = self.seal(doc_id, crdt_data.to_vec()).await?;
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let version: DocumentVersion = sqlx::query_as(
+                "INSERT INTO document_versions (document_id, version, name, crdt_data, size, checksum, created_by, created_at)
+                 SELECT $1, COALESCE(max(version), 0) + 1, $2, $3, $4, $5, $6, $7 FROM document_versions WHERE document_id = $1
+                 RETURNING document_id, version, name, size, created_by, created_at"
+            )
+            .bind(doc_id)
+            .bind(&metadata.name)
+            .bind(&stored)
+            .bind(crdt_data.len() as i64)
+            .bind(integrity::checksum(crdt_data))
+            .bind(created_by)
+            .bind(Utc::now().trunc_to_millis())
+            .fetch_one(&mut *tx)
+            .await
+            .context(format!("Failed to save a version of document ID {}", doc_id))?;
+        storage_quota::charge(&mut tx, doc_id, version.size, self.storage_quota).await?;
+        tx.commit().await.context(format!("Failed to commit version of document ID {}", doc_id))?;
+        info!("Saved version {} of document ID {}", version.version, doc_id);
+        Ok(Some(version))
+    }
+
+    /// The document's saved versions, newest first.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn list_versions(&self, doc_id: Uuid) -> Result<Vec<DocumentVersion>> {
+        sqlx::query_as(
+                "SELECT document_id, version, name, size, created_by, created_at
+                 FROM document_versions WHERE document_id = $1 ORDER BY version DESC"
+            )
+            .bind(doc_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list versions of document ID {}", doc_id))
+    }
+
+    /// Adds a comment to a document. `None` if there is no such document, or no comment to
+    /// reply to on it; fails with `HookRejection` if a hook refuses the comment. Replies take
+    /// on whether their thread is resolved.
+    #[instrument(skip_all, fields(doc_id = %doc_id, author = ?author))]
+    pub async fn add_comment(&self, doc_id: Uuid, author: Option<Uuid>, thread: CommentThread, body: &str) -> Result<Option<Comment>> {
+        self.hooks.on_comment(&CommentEvent { doc_id, author, body }).await?;
+        let (sql, anchor, reply_to) = match thread {
+            CommentThread::Start { anchor } => (
+                format!(
+                    "INSERT INTO document_comments ({COMMENT_COLUMNS})
+                     SELECT $1, $2, $1, $3, $4, $5, $6, FALSE, $7
+                     WHERE EXISTS (SELECT 1 FROM documents_metadata WHERE id = $2 AND deleted_at IS NULL)
+                     RETURNING {COMMENT_COLUMNS}"
+                ),
+                anchor,
+                None,
+            ),
+            CommentThread::Reply { to } => (
+                format!(
+                    "INSERT INTO document_comments ({COMMENT_COLUMNS})
+                     SELECT $1, $2, c.thread_id, $3, $4, $5, $6, c.resolved, $7
+                     FROM document_comments c JOIN documents_metadata m ON m.id = c.document_id AND m.deleted_at IS NULL
+                     WHERE c.id = $8 AND c.document_id = $2
+                     RETURNING {COMMENT_COLUMNS}"
+                ),
+                None,
+                Some(to),
+            ),
+        };
+        let (anchor_start, anchor_end) = anchor.map_or((None, None), |anchor| (Some(anchor.start), anchor.end));
+        let mut query = sqlx::query_as(&sql)
+            .bind(Uuid::new_v4())
+            .bind(doc_id)
+            .bind(anchor_start)
+            .bind(anchor_end)
+            .bind(author)
+            .bind(body)
+            .bind(Utc::now().trunc_to_millis());
+        if let Some(to) = reply_to {
+            query = query.bind(to);
+        }
+        let row: Option<CommentRow> = query
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to add a comment to document ID {}", doc_id))?;
+        Ok(row.map(Comment::from))
+    }
+
+    /// The document's comments, oldest first.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn list_comments(&self, doc_id: Uuid) -> Result<Vec<Comment>> {
+        let rows: Vec<CommentRow> = sqlx::query_as(&format!(
+                "SELECT {COMMENT_COLUMNS} FROM document_comments WHERE document_id = $1 ORDER BY created_at, id"
+            ))
+            .bind(doc_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list comments on document ID {}", doc_id))?;
+        Ok(rows.into_iter().map(Comment::from).collect())
+    }
+
+    /// Every comment `author` wrote, on any document, oldest first.
+    #[instrument(skip_all, fields(author = %author))]
+    pub async fn comments_by(&self, author: Uuid) -> Result<Vec<Comment>> {
+        let rows: Vec<CommentRow> = sqlx::query_as(&format!(
+                "SELECT {COMMENT_COLUMNS} FROM document_comments WHERE author = $1 ORDER BY created_at, id"
+            ))
+            .bind(author)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list comments by user ID {}", author))?;
+        Ok(rows.into_iter().map(Comment::from).collect())
+    }
+
+    #[instrument(skip_all, fields(doc_id = %doc_id, comment_id = %comment_id))]
+    pub async fn get_comment(&self, doc_id: Uuid, comment_id: Uuid) -> Result<Option<Comment>> {
+        let row: Option<CommentRow> = sqlx::query_as(&format!(
+                "SELECT {COMMENT_COLUMNS} FROM document_comments WHERE id = $1 AND document_id = $2"
+            ))
+            .bind(comment_id)
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to get comment ID {}", comment_id))?;
+        Ok(row.map(Comment::from))
+    }
+
+    /// Resolves or reopens the thread the given comment is in. Returns the thread's ID, or
+    /// `None` if there is no such comment on the document.
+    #[instrument(skip_all, fields(doc_id = %doc_id, comment_id = %comment_id))]
+    pub async fn resolve_thread(&self, doc_id: Uuid, comment_id: Uuid, resolved: bool) -> Result<Option<Uuid>> {
+        let threads: Vec<Uuid> = sqlx::query_scalar(
+                "UPDATE document_comments SET resolved = $3
+                 WHERE document_id = $1 AND thread_id = (SELECT thread_id FROM document_comments WHERE id = $2 AND document_id = $1)
+                 RETURNING thread_id"
+            )
+            .bind(doc_id)
+            .bind(comment_id)
+            .bind(resolved)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to resolve the thread of comment ID {}", comment_id))?;
+        Ok(threads.into_iter().next())
+    }
+
+    /// Deletes a comment, along with its replies if it starts a thread. `false` if there is no
+    /// such comment on the document.
+    #[instrument(skip_all, fields(doc_id = %doc_id, comment_id = %comment_id))]
+    pub async fn delete_comment(&self, doc_id: Uuid, comment_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM document_comments WHERE document_id = $1 AND (id = $2 OR thread_id = $2)")
+            .bind(doc_id)
+            .bind(comment_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to delete comment ID {}", comment_id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The content saved with a version. Fails with `ChecksumMismatch` if it is corrupt.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn get_version_content(&self, doc_id: Uuid, version: i64) -> Result<Option<Vec<u8>>> {
+        let row: Option<(Vec<u8>, String)> = sqlx::query_as(
+                "SELECT crdt_data, checksum FROM document_versions WHERE document_id = $1 AND version = $2"
+            )
+            .bind(doc_id)
+            .bind(version)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query version {} of document ID {}", version, doc_id))?;
+        let Some((stored, checksum)) = row else {
+            return Ok(None);
+        };
+        let crdt_data = self.open(doc_id, stored).await?;
+        integrity::verify(format_args!("version {} of document ID {}", version, doc_id), &crdt_data, Some(&checksum))?;
+        Ok(Some(crdt_data))
+    }
+
+    /// Rolls a document back to a saved version, first saving its current state as a
+    /// version of its own so the restore can be undone. `None` if there is no such version.
+    /// This writes to the database directly; documents open in a room should be restored
+    /// through the room instead.
+    #[instrument(skip_all, fields(doc_id = %doc_id, restored_by = ?restored_by))]
+    pub async fn restore_version(&self, doc_id: Uuid, version: i64, restored_by: Option<Uuid>) -> Result<Option<CausalityToken>> {
+        let Some(crdt_data) = self.get_version_content(doc_id, version).await? else {
+            return Ok(None);
+        };
+        self.create_version(doc_id, restored_by).await?;
+        let token = self.rewrite_document_content(doc_id, crdt_data, restored_by).await?;
+        info!("Restored document ID {} to version {}", doc_id, version);
+        Ok(Some(token))
+    }
+
+    /// Restores a document's content from a backup, recreating its metadata row if the
+    /// document no longer exists and taking it out of the trash if it is there. The restore
+    /// is recorded as a new version. Restores are an administrator's call, so neither the
+    /// owner's quota nor the document size limit applies.
+    #[instrument(skip_all, fields(doc_id = %metadata.id))]
+    pub async fn restore_document(&self, metadata: &DocumentMetadata, content_data: Vec<u8>) -> Result<CausalityToken> {
+        self.db_manager.pool
+            .execute(sqlx::query(
+                    "INSERT INTO documents_metadata (id, name, created_at, updated_at) VALUES ($1, $2, $3, $4)
+                     ON CONFLICT (id) DO UPDATE SET deleted_at = NULL"
+                )
+                .bind(metadata.id)
+                .bind(&metadata.name)
+                .bind(metadata.created_at)
+                .bind(metadata.updated_at)
+            ).await
+            .context(format!("Failed to recreate document metadata for ID {}", metadata.id))?;
+        self.write_snapshot(metadata.id, content_data, None, None).await
+    }
+
+    /// Renames a document. Counts as a change, so watchers and syncing clients pick it up.
+    /// Fails with `NameTaken` if another document in its folder has the name.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn rename_document(&self, doc_id: Uuid, name: &str) -> Result<Option<DocumentMetadata>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let folder_id: Option<Option<Uuid>> = sqlx::query_scalar(
+                "SELECT COALESCE(parent_folder_id, workspace_id, owner_id) FROM documents_metadata
+                 WHERE id = $1 AND deleted_at IS NULL FOR UPDATE"
+            )
+            .bind(doc_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .context(format!("Failed to query folder of document ID {}", doc_id))?;
+        let Some(folder_id) = folder_id else {
+            return Ok(None);
+        };
+        if let Some(folder_id) = folder_id {
+            sqlx::query("DELETE FROM documents_by_name WHERE document_id = $1")
+                .bind(doc_id)
+                .execute(&mut *tx)
+                .await
+                .context(format!("Failed to release name of document ID {}", doc_id))?;
+            if !claim_name(&mut tx, folder_id, name, doc_id).await? {
+                tx.rollback().await.ok();
+                return Err(anyhow!(self.name_taken(folder_id, name).await?));
+            }
+        }
+        let metadata: Option<DocumentMetadata> = sqlx::query_as(
+                "UPDATE documents_metadata SET name = $1, seq = seq + 1 WHERE id = $2
+                 RETURNING id, name, created_at, updated_at, language"
+            )
+            .bind(name)
+            .bind(doc_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .context(format!("Failed to rename document ID {}", doc_id))?;
+        let metadata = match metadata {
+            Some(metadata) => Some(DocumentMetadata { updated_at: stamp_updated_at(&mut tx, doc_id).await?, ..metadata }),
+            None => None,
+        };
+        tx.commit().await.context(format!("Failed to commit rename of document ID {}", doc_id))?;
+        if metadata.is_some() {
+            info!("Renamed document ID {} to '{}'", doc_id, name);
+        }
+        Ok(metadata)
+    }
+
+    /// A `NameTaken` error for `name`, suggesting numbered variants that are still free.
+    pub(crate) async fn name_taken(&self, folder_id: Uuid, name: &str) -> Result<NameTaken> {
+        // "Plan (2)" suggests "Plan (3)" rather than "Plan (2) (2)".
+        let base = match name.trim_end().strip_suffix(')').and_then(|rest| rest.rsplit_once(" (")) {
+            Some((base, number)) if number.parse::<u32>().is_ok() => base,
+            _ => name.trim(),
+        };
+        let mut suggestions = Vec::new();
+        let mut next = 2;
+        while suggestions.len() < NAME_SUGGESTIONS {
+            let candidates: Vec<String> = (next..next + 10).map(|n| format!("{} ({})", base, n)).collect();
+            next += 10;
+            let taken: Vec<String> = sqlx::query_scalar(
+                    "SELECT name_key FROM documents_by_name
+                     WHERE folder_id = $1 AND name_key IN (SELECT lower(btrim(c)) FROM unnest($2::TEXT[]) AS c)"
+                )
+                .bind(folder_id)
+                .bind(&candidates)
+                .fetch_all(&*self.db_manager.pool)
+                .await
+                .context(format!("Failed to query names taken in folder {}", folder_id))?;
+            suggestions.extend(
+                candidates.into_iter()
+                    .filter(|candidate| !taken.contains(&candidate.to_lowercase()))
+                    .take(NAME_SUGGESTIONS - suggestions.len()),
+            );
+        }
+        Ok(NameTaken { name: name.to_string(), suggestions })
+    }
+
+    /// Moves a document to the trash, from which `restore_from_trash` brings it back until
+    /// `purge_deleted` removes it for good. Trashed documents are left out of every lookup
+    /// and listing except `list_trash`, and give up their name so another document can take
+    /// it. Returns false if there was no such document outside the trash.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn delete_document(&self, doc_id: Uuid) -> Result<bool> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        if !trash(&mut tx, doc_id).await? {
+            return Ok(false);
+        }
+        tx.commit().await.context(format!("Failed to commit trashing of document ID {}", doc_id))?;
+        info!("Moved document ID {} to the trash", doc_id);
+        self.events.publish(DocumentEvent::Deleted { doc_id });
+        Ok(true)
+    }
+
+    /// Deletes a document the way `requested_by` asked to: straight away with
+    /// `delete_document`, unless a folder it is in is marked as needing a second approval.
+    /// Then the deletion waits for another owner to decide on it with `decide_deletion`, and
+    /// lapses if nobody does within the approval window. `None` if there was no such document
+    /// outside the trash.
+    #[instrument(skip_all, fields(doc_id = %doc_id, requested_by = ?requested_by))]
+    pub async fn request_deletion(&self, doc_id: Uuid, requested_by: Option<Uuid>) -> Result<Option<Deletion>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        // Walk up from the document's folder. Transactions are serializable, so a folder
+        // cannot be marked between this check and the deletion.
+        let needs_approval: bool = sqlx::query_scalar(
+                "WITH RECURSIVE ancestors (id, parent_id, protected) AS (
+                    SELECT f.id, f.parent_id, f.deletion_requires_approval
+                    FROM folders f JOIN documents_metadata d ON f.id = d.parent_folder_id
+                    WHERE d.id = $1
+                    UNION ALL
+                    SELECT f.id, f.parent_id, f.deletion_requires_approval FROM folders f JOIN ancestors a ON f.id = a.parent_id
+                 )
+                 SELECT EXISTS (SELECT 1 FROM ancestors WHERE protected)"
+            )
+            .bind(doc_id)
+            .fetch_one(&mut *tx)
+            .await
+            .context(format!("Failed to check whether deleting document ID {} needs approval", doc_id))?;
+        if !needs_approval {
+            if !trash(&mut tx, doc_id).await? {
+                return Ok(None);
+            }
+            tx.commit().await.context(format!("Failed to commit trashing of document ID {}", doc_id))?;
+            info!("Moved document ID {} to the trash", doc_id);
+            self.events.publish(DocumentEvent::Deleted { doc_id });
+            return Ok(Some(Deletion::Trashed));
+        }
+
+        let now = Utc::now().trunc_to_millis();
+        let pending: Option<DeletionRequestRow> = sqlx::query_as(&format!(
+                "SELECT {DELETION_REQUEST_COLUMNS} FROM document_deletion_requests
+                 WHERE document_id = $1 AND status = 'pending' AND expires_at > $2"
+            ))
+            .bind(doc_id)
+            .bind(now)
+            .fetch_optional(&mut *tx)
+            .await
+            .context(format!("Failed to look up pending deletion of document ID {}", doc_id))?;
+        if let Some(pending) = pending {
+            return Ok(Some(Deletion::Pending(pending.try_into()?)));
+        }
+        // One that lapsed but has not been swept yet would hold the pending slot.
+        let lapsed = expire_pending(&mut tx, Some(doc_id), now).await?;
+        let row: Option<DeletionRequestRow> = sqlx::query_as(&format!(
+                "INSERT INTO document_deletion_requests ({DELETION_REQUEST_COLUMNS})
+                 SELECT $1, $2, $3, $4, $5, 'pending', NULL, NULL
+                 WHERE EXISTS (SELECT 1 FROM documents_metadata WHERE id = $2 AND deleted_at IS NULL)
+                 RETURNING {DELETION_REQUEST_COLUMNS}"
+            ))
+            .bind(Uuid::new_v4())
+            .bind(doc_id)
+            .bind(requested_by)
+            .bind(now)
+            .bind(now + self.deletion_approval_window)
+            .fetch_optional(&mut *tx)
+            .await
+            .context(format!("Failed to request deletion of document ID {}", doc_id))?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        outbox::record(&mut tx, DocumentEvent::DeletionRequested { doc_id }).await?;
+        tx.commit().await.context(format!("Failed to commit deletion request for document ID {}", doc_id))?;
+        info!("Deletion of document ID {} awaits approval", doc_id);
+        for doc_id in lapsed {
+            self.events.publish(DocumentEvent::DeletionExpired { doc_id });
+        }
+        self.events.publish(DocumentEvent::DeletionRequested { doc_id });
+        Ok(Some(Deletion::Requested(row.try_into()?)))
     }
 
-    pub async fn get_document_metadata(&self, doc_id: Uuid) -> Result<Option<DocumentMetadata>> {
-        let row_opt = sqlx::query(
-                "SELECT id, name, created_at, updated_at FROM documents_metadata WHERE id = $1"
+    /// Approves or rejects a pending deletion, trashing the document on approval. `None` if
+    /// there is no such request awaiting a decision; fails with `SelfApproval` if whoever
+    /// asked tries to approve it.
+    #[instrument(skip_all, fields(doc_id = %doc_id, request_id = %request_id, decided_by = ?decided_by))]
+    pub async fn decide_deletion(
+        &self,
+        doc_id: Uuid,
+        request_id: Uuid,
+        decided_by: Option<Uuid>,
+        decision: DeletionDecision,
+    ) -> Result<Option<DeletionRequest>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let requested_by: Option<Option<Uuid>> = sqlx::query_scalar(
+                "SELECT requested_by FROM document_deletion_requests
+                 WHERE id = $1 AND document_id = $2 AND status = 'pending' AND expires_at > $3"
             )
+            .bind(request_id)
             .bind(doc_id)
-            .fetch_optional(&*self.db_manager.pool)
+            .bind(Utc::now())
+            .fetch_optional(&mut *tx)
             .await
-            .context(format!("Failed to query document metadata for ID {}", doc_id))?;
-
-        match row_opt {
-            Some(row) => {
-            // Manually map the row to DocumentMetadata
-            // try_get can be used for fallible conversions, or get for infallible ones if types are exact.
-                let metadata = DocumentMetadata {
-                    id: row.try_get("id").context("Failed to get 'id' from row")?, // UUIDs don't need truncation
-                    name: row.try_get("name").context("Failed to get 'name' from row")?, // String doesn't need truncation
-                    created_at: row.try_get::<DateTime<Utc>, _>("created_at").context("Failed to get 'created_at' from row")?.trunc_to_millis(),
-                    updated_at: row.try_get::<DateTime<Utc>, _>("updated_at").context("Failed to get 'updated_at' from row")?.trunc_to_millis(),
-                };
-                Ok(Some(metadata))
-            },
-            None => Ok(None),
+            .context(format!("Failed to look up deletion request ID {}", request_id))?;
+        let Some(requested_by) = requested_by else {
+            return Ok(None);
+        };
+        if decision == DeletionDecision::Approve && requested_by.is_some() && requested_by == decided_by {
+            return Err(SelfApproval.into());
+        }
+        let status = match decision {
+            DeletionDecision::Approve => DeletionStatus::Approved,
+            DeletionDecision::Reject => DeletionStatus::Rejected,
+        };
+        let row: DeletionRequestRow = sqlx::query_as(&format!(
+                "UPDATE document_deletion_requests SET status = $2, decided_by = $3, decided_at = $4
+                 WHERE id = $1 RETURNING {DELETION_REQUEST_COLUMNS}"
+            ))
+            .bind(request_id)
+            .bind(status.as_str())
+            .bind(decided_by)
+            .bind(Utc::now().trunc_to_millis())
+            .fetch_one(&mut *tx)
+            .await
+            .context(format!("Failed to decide deletion request ID {}", request_id))?;
+        let mut events = Vec::new();
+        match decision {
+            DeletionDecision::Approve => {
+                events.push(DocumentEvent::DeletionApproved { doc_id });
+                outbox::record(&mut tx, DocumentEvent::DeletionApproved { doc_id }).await?;
+                // Already in the trash some other way is as good as approved.
+                if trash(&mut tx, doc_id).await? {
+                    events.push(DocumentEvent::Deleted { doc_id });
+                }
+            }
+            DeletionDecision::Reject => {
+                events.push(DocumentEvent::DeletionRejected { doc_id });
+                outbox::record(&mut tx, DocumentEvent::DeletionRejected { doc_id }).await?;
+            }
         }
+        tx.commit().await.context(format!("Failed to commit decision on deletion request ID {}", request_id))?;
+        info!("Deletion request ID {} for document ID {} is {}", request_id, doc_id, status.as_str());
+        for event in events {
+            self.events.publish(event);
+        }
+        row.try_into().map(Some)
     }
 
+    /// Every deletion requested for the document, pending or decided, most recent first.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn deletion_requests(&self, doc_id: Uuid) -> Result<Vec<DeletionRequest>> {
+        let rows: Vec<DeletionRequestRow> = sqlx::query_as(&format!(
+                "SELECT {DELETION_REQUEST_COLUMNS} FROM document_deletion_requests
+                 WHERE document_id = $1 ORDER BY requested_at DESC, id"
+            ))
+            .bind(doc_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list deletion requests for document ID {}", doc_id))?;
+        rows.into_iter().map(DeletionRequest::try_from).collect()
+    }
 
-    pub async fn update_document_content(&self, doc_id: Uuid, content_data: Vec<u8>) -> Result<()> {
-        let now = Utc::now().trunc_to_millis(); // Truncate to millisecond precision
+    /// Marks deletions nobody decided on in time as expired. Returns how many there were.
+    #[instrument(skip_all)]
+    pub async fn expire_deletion_requests(&self) -> Result<usize> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let expired = expire_pending(&mut tx, None, Utc::now()).await?;
+        tx.commit().await.context("Failed to commit expiry of deletion requests")?;
+        for doc_id in &expired {
+            self.events.publish(DocumentEvent::DeletionExpired { doc_id: *doc_id });
+        }
+        Ok(expired.len())
+    }
 
-        // Upsert content
-        self.db_manager.pool
-            .execute(sqlx::query(
-                "INSERT INTO documents_content (document_id, crdt_data, updated_at)
-                 VALUES ($1, $2, $3)
-                 ON CONFLICT (document_id) DO UPDATE
-                 SET crdt_data = EXCLUDED.crdt_data,
-                     updated_at = EXCLUDED.updated_at"
-                )
-                .bind(doc_id)
-                .bind(content_data) // Vec<u8> for BYTEA
-                .bind(now)
+    /// Documents in `owner_id`'s trash, most recently trashed first.
+    #[instrument(skip_all, fields(owner_id = %owner_id))]
+    pub async fn list_trash(&self, owner_id: Uuid) -> Result<Vec<TrashedDocument>> {
+        let rows: Vec<TrashedDocument> = sqlx::query_as(
+                "SELECT id, name, created_at, updated_at, language, deleted_at FROM documents_metadata
+                 WHERE owner_id = $1 AND deleted_at IS NOT NULL ORDER BY deleted_at DESC, id"
             )
+            .bind(owner_id)
+            .fetch_all(&*self.db_manager.pool)
             .await
-            .context(format!("Failed to update document content for ID {}", doc_id))?;
+            .context(format!("Failed to list the trash of user ID {}", owner_id))?;
+        Ok(rows
+            .into_iter()
+            .map(|mut row| {
+                row.metadata.created_at = row.metadata.created_at.trunc_to_millis();
+                row.metadata.updated_at = row.metadata.updated_at.trunc_to_millis();
+                row
+            })
+            .collect())
+    }
 
-        // Update metadata's updated_at timestamp
-        self.db_manager.pool
-            .execute(sqlx::query(
-                "UPDATE documents_metadata SET updated_at = $1 WHERE id = $2"
-                )
-                .bind(now)
-                .bind(doc_id)
+    /// Takes a document out of the trash. If another document has taken its name in the
+    /// meantime, it comes back under the first free numbered variant, e.g. "Plan (2)".
+    /// `None` if the document is not in the trash.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn restore_from_trash(&self, doc_id: Uuid) -> Result<Option<DocumentMetadata>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let row: Option<(String, Option<Uuid>)> = sqlx::query_as(
+                "SELECT name, COALESCE(parent_folder_id, workspace_id, owner_id) FROM documents_metadata
+                 WHERE id = $1 AND deleted_at IS NOT NULL FOR UPDATE"
             )
+            .bind(doc_id)
+            .fetch_optional(&mut *tx)
             .await
-            .context(format!("Failed to update metadata timestamp for ID {}", doc_id))?;
-        
-        println!("Updated content for document ID: {}", doc_id);
-        Ok(())
+            .context(format!("Failed to query trashed document ID {}", doc_id))?;
+        let Some((mut name, folder_id)) = row else {
+            return Ok(None);
+        };
+        if let Some(folder_id) = folder_id
+            && !claim_name(&mut tx, folder_id, &name, doc_id).await?
+        {
+            let taken = self.name_taken(folder_id, &name).await?;
+            match taken.suggestions.first() {
+                Some(suggestion) if claim_name(&mut tx, folder_id, suggestion, doc_id).await? => name = suggestion.clone(),
+                _ => {
+                    tx.rollback().await.ok();
+                    return Err(anyhow!(taken));
+                }
+            }
+        }
+        // Restoring counts as a change, so syncing clients and the search index pick it up again.
+        let metadata: DocumentMetadata = sqlx::query_as(
+                "UPDATE documents_metadata SET deleted_at = NULL, name = $1, seq = seq + 1 WHERE id = $2
+                 RETURNING id, name, created_at, updated_at, language"
+            )
+            .bind(&name)
+            .bind(doc_id)
+            .fetch_one(&mut *tx)
+            .await
+            .context(format!("Failed to restore document ID {} from the trash", doc_id))?;
+        outbox::record(&mut tx, DocumentEvent::Changed { doc_id }).await?;
+        let metadata = DocumentMetadata { updated_at: stamp_updated_at(&mut tx, doc_id).await?, ..metadata };
+        tx.commit().await.context(format!("Failed to commit restore of document ID {} from the trash", doc_id))?;
+        info!("Restored document ID {} from the trash as '{}'", doc_id, name);
+        self.events.publish(DocumentEvent::Changed { doc_id });
+        Ok(Some(metadata))
     }
 
-    pub async fn get_document_content(&self, doc_id: Uuid) -> Result<Option<DocumentContent>> {
-        let row_opt = sqlx::query(
-                "SELECT document_id, crdt_data, updated_at FROM documents_content WHERE document_id = $1"
+    /// Permanently deletes up to `limit` documents trashed before `deleted_before`, with
+    /// everything stored with them, including content offloaded to cold storage. Returns
+    /// the IDs of the documents purged.
+    #[instrument(skip_all)]
+    pub async fn purge_deleted(&self, deleted_before: DateTime<Utc>, limit: i64) -> Result<Vec<Uuid>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let purged: Vec<(Uuid, Option<String>)> = sqlx::query_as(
+                "SELECT m.id, c.offloaded_to FROM documents_metadata m
+                 LEFT JOIN documents_content c ON c.document_id = m.id
+                 WHERE m.deleted_at < $1 ORDER BY m.deleted_at LIMIT $2 FOR UPDATE OF m"
             )
-            .bind(doc_id)
-            .fetch_optional(&*self.db_manager.pool)
+            .bind(deleted_before)
+            .bind(limit)
+            .fetch_all(&mut *tx)
             .await
-            .context(format!("Failed to query document content for ID {}", doc_id))?;
-        match row_opt {
-            Some(row) => {
-                let content = DocumentContent {
-                    document_id: row.try_get("document_id").context("Failed to get 'document_id' from row")?, // UUID
-                    crdt_data: row.try_get("crdt_data").context("Failed to get 'crdt_data' from row")?,       // Vec<u8>
-                    updated_at: row.try_get::<DateTime<Utc>, _>("updated_at").context("Failed to get 'updated_at' from row")?.trunc_to_millis(),
-                };
-                Ok(Some(content))
-            },
-            None => Ok(None),
+            .context("Failed to query trashed documents to purge")?;
+        self.purge(tx, purged).await
+    }
+
+    /// Deletes for good the documents `owner_id` owns outside any workspace, trashed or not,
+    /// as when the account is deleted. Workspace documents stay with the workspace; see
+    /// `forget_user`. Returns the IDs of the purged documents.
+    #[instrument(skip_all, fields(owner_id = %owner_id))]
+    pub async fn purge_personal_documents(&self, owner_id: Uuid) -> Result<Vec<Uuid>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let purged: Vec<(Uuid, Option<String>)> = sqlx::query_as(
+                "SELECT m.id, c.offloaded_to FROM documents_metadata m
+                 LEFT JOIN documents_content c ON c.document_id = m.id
+                 WHERE m.owner_id = $1 AND m.workspace_id IS NULL FOR UPDATE OF m"
+            )
+            .bind(owner_id)
+            .fetch_all(&mut *tx)
+            .await
+            .context(format!("Failed to query personal documents of user ID {}", owner_id))?;
+        self.purge(tx, purged).await
+    }
+
+    /// Deletes the locked documents in `purged`, with where their content was offloaded.
+    async fn purge(&self, mut tx: sqlx::Transaction<'_, sqlx::Postgres>, purged: Vec<(Uuid, Option<String>)>) -> Result<Vec<Uuid>> {
+        let ids: Vec<Uuid> = purged.iter().map(|(doc_id, _)| *doc_id).collect();
+        storage_quota::release(&mut tx, &ids).await?;
+        sqlx::query("DELETE FROM documents_metadata WHERE id = ANY($1)")
+            .bind(&ids)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to purge documents")?;
+        tx.commit().await.context("Failed to commit purge of documents")?;
+        // The rows are gone either way; an object left behind only costs storage.
+        for (doc_id, offloaded_to) in &purged {
+            if let (Some(cold_storage), Some(key)) = (&self.cold_storage, offloaded_to)
+                && let Err(e) = cold_storage.delete(key).await
+            {
+                warn!("Failed to delete offloaded content of purged document ID {} at {}: {:#}", doc_id, key, e);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Drops every record of `user_id` from the documents that outlive their account:
+    /// workspace documents it owned are left without an owner, and what it wrote, commented
+    /// or saved as a version is kept without an author. Cached attributions naming it are
+    /// forgotten; the rest of those documents' attribution goes with them.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn forget_user(&self, user_id: Uuid) -> Result<()> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let statements = [
+            "UPDATE documents_metadata SET owner_id = NULL WHERE owner_id = $1",
+            "DELETE FROM storage_usage WHERE owner_id = $1",
+            "UPDATE document_comments SET author = NULL WHERE author = $1",
+            "UPDATE document_updates SET author = NULL WHERE author = $1",
+            "UPDATE document_versions SET created_by = NULL WHERE created_by = $1",
+            "UPDATE document_deletion_requests SET requested_by = NULL WHERE requested_by = $1",
+            "UPDATE document_deletion_requests SET decided_by = NULL WHERE decided_by = $1",
+        ];
+        for statement in statements {
+            sqlx::query(statement)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+                .context(format!("Failed to forget user ID {}", user_id))?;
         }
+        sqlx::query("DELETE FROM document_blame WHERE runs @> $1")
+            .bind(Json(serde_json::json!([{ "author": user_id }])))
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to forget attribution to user ID {}", user_id))?;
+        tx.commit().await.context(format!("Failed to commit forgetting user ID {}", user_id))?;
+        Ok(())
+    }
+
+    /// Up to `limit` logged document events from `from_seq` on; see `outbox`.
+    #[instrument(skip_all, fields(from_seq = from_seq))]
+    pub async fn replay_events(&self, from_seq: i64, limit: i64) -> Result<Vec<LoggedEvent>> {
+        outbox::replay(&self.db_manager.pool, from_seq, Utc::now() - outbox::SETTLE_TIME, limit).await
+    }
+
+    /// What the documents `owner_id` owns store, against their quota.
+    #[instrument(skip_all, fields(owner_id = %owner_id))]
+    pub async fn storage_usage(&self, owner_id: Uuid) -> Result<StorageUsage> {
+        let used_bytes = storage_quota::used_bytes(&self.db_manager.pool, owner_id).await?;
+        Ok(StorageUsage { used_bytes, quota_bytes: self.storage_quota })
+    }
+
+    /// Documents outside the trash that link to `doc_id`.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn backlinks(&self, doc_id: Uuid) -> Result<Vec<DocumentMetadata>> {
+        references::backlinks(&self.db_manager.pool, doc_id).await
     }
 
+    /// What `doc_id` linked to as of its last snapshot, and which of those links are broken.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn links(&self, doc_id: Uuid) -> Result<Vec<DocumentLink>> {
+        references::outgoing(&self.db_manager.pool, doc_id).await
+    }
+
+    /// What documents with an owner store altogether, as counted toward quotas.
+    #[instrument(skip_all)]
+    pub async fn total_storage_bytes(&self) -> Result<i64> {
+        storage_quota::total_bytes(&self.db_manager.pool).await
+    }
+
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
     pub async fn get_document(&self, doc_id: Uuid) -> Result<Option<Document>> {
-        let metadata_opt = self.get_document_metadata(doc_id).await?;
+        self.get_document_with(doc_id, ReadConsistency::Strong).await
+    }
+
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn get_document_with(&self, doc_id: Uuid, consistency: ReadConsistency) -> Result<Option<Document>> {
+        // Resolve once so metadata and content are read at the same consistency.
+        let consistency = consistency.resolve_in(&self.db_manager.pool).await?;
+        let metadata_opt = self.get_document_metadata_with(doc_id, consistency).await?;
         match metadata_opt {
             Some(metadata) => {
-                let content_opt = self.get_document_content(doc_id).await?;
+                let content_opt = self.get_document_content_with(doc_id, consistency).await?;
                 Ok(Some(Document {
                     metadata,
                     content: content_opt,
@@ -217,6 +2574,76 @@ impl DocumentService {
     }
 }
 
+/// Stamps a document changed in `tx` with the time `tx` commits at, so that its causality
+/// tokens follow the cluster's clock rather than ours. Run it last; see `consistency::commit_time`.
+async fn stamp_updated_at(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, doc_id: Uuid) -> Result<DateTime<Utc>> {
+    let updated_at = consistency::commit_time(tx).await?;
+    sqlx::query("UPDATE documents_metadata SET updated_at = $1 WHERE id = $2")
+        .bind(updated_at)
+        .bind(doc_id)
+        .execute(&mut **tx)
+        .await
+        .context(format!("Failed to stamp update time of document ID {}", doc_id))?;
+    Ok(updated_at)
+}
+
+/// Claims `name` in a folder for a document. Returns false if another document has it.
+pub(crate) async fn claim_name(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, folder_id: Uuid, name: &str, doc_id: Uuid) -> Result<bool> {
+    let result = sqlx::query(
+            "INSERT INTO documents_by_name (folder_id, name_key, document_id) VALUES ($1, lower(btrim($2)), $3)
+             ON CONFLICT (folder_id, name_key) DO NOTHING"
+        )
+        .bind(folder_id)
+        .bind(name)
+        .bind(doc_id)
+        .execute(&mut **tx)
+        .await
+        .context(format!("Failed to claim name of document ID {}", doc_id))?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Moves a document to the trash as part of `tx`; see `DocumentService::delete_document`.
+/// Publish `DocumentEvent::Deleted` once `tx` commits. False if there was no such document
+/// outside the trash.
+async fn trash(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, doc_id: Uuid) -> Result<bool> {
+    let result = sqlx::query("UPDATE documents_metadata SET deleted_at = $1 WHERE id = $2 AND deleted_at IS NULL")
+        .bind(Utc::now().trunc_to_millis())
+        .bind(doc_id)
+        .execute(&mut **tx)
+        .await
+        .context(format!("Failed to move document ID {} to the trash", doc_id))?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+    sqlx::query("DELETE FROM documents_by_name WHERE document_id = $1")
+        .bind(doc_id)
+        .execute(&mut **tx)
+        .await
+        .context(format!("Failed to release name of document ID {}", doc_id))?;
+    outbox::record(tx, DocumentEvent::Deleted { doc_id }).await?;
+    Ok(true)
+}
+
+/// Expires the pending deletions that lapsed by `now`, of one document or all of them, as
+/// part of `tx`. Returns their documents; publish `DocumentEvent::DeletionExpired` for each
+/// once `tx` commits.
+async fn expire_pending(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, doc_id: Option<Uuid>, now: DateTime<Utc>) -> Result<Vec<Uuid>> {
+    let expired: Vec<Uuid> = sqlx::query_scalar(
+            "UPDATE document_deletion_requests SET status = 'expired'
+             WHERE status = 'pending' AND expires_at <= $1 AND ($2::UUID IS NULL OR document_id = $2)
+             RETURNING document_id"
+        )
+        .bind(now)
+        .bind(doc_id)
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to expire deletion requests")?;
+    for doc_id in &expired {
+        outbox::record(tx, DocumentEvent::DeletionExpired { doc_id: *doc_id }).await?;
+    }
+    Ok(expired)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -301,6 +2728,21 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_documents_are_held_to_the_size_limit() -> Result<()> {
+        let doc_service = get_test_document_service().await?.with_max_document_bytes(10);
+        let doc = doc_service.create_document("Test Document for the Size Limit").await?;
+        doc_service.update_document_content(doc.id, vec![b'a'; 10]).await?;
+
+        // Neither a new snapshot nor an appended update may take the document past the limit.
+        let refused = doc_service.update_document_content(doc.id, vec![b'b'; 11]).await.unwrap_err();
+        assert_eq!(refused.downcast_ref::<DocumentTooLarge>(), Some(&DocumentTooLarge { size_bytes: 11, max_bytes: 10 }));
+        let refused = doc_service.append_document_update(doc.id, &[b'a'; 10], vec![b'a'; 11], None).await.unwrap_err();
+        assert!(refused.is::<DocumentTooLarge>());
+        assert_eq!(doc_service.get_document_content(doc.id).await?.unwrap().crdt_data, vec![b'a'; 10]);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_get_full_document() -> Result<()> {
         let doc_service = get_test_document_service().await
@@ -324,6 +2766,82 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_read_your_writes_with_causality_token() -> Result<()> {
+        let doc_service = get_test_document_service().await
+            .expect("Failed to initialize test document service");
+
+        let metadata = doc_service.create_document("Test Document for Causality").await?;
+        let content_data = vec![7, 8, 9];
+        let token = doc_service.update_document_content(metadata.id, content_data.clone()).await?;
+        assert!(token >= metadata.causality_token(), "Later writes should yield later tokens");
+
+        // A freshly issued token is always inside the follower lag window, so this read
+        // is redirected to the leaseholder and must observe the write.
+        let document = doc_service
+            .get_document_with(metadata.id, ReadConsistency::AtLeast(token))
+            .await?
+            .expect("Document should exist");
+        assert_eq!(document.content.unwrap().crdt_data, content_data);
+        assert!(document.metadata.causality_token() >= token);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_follower_reads_observe_writes_as_soon_as_tokens_allow_them() -> Result<()> {
+        let doc_service = get_test_document_service().await
+            .expect("Failed to initialize test document service");
+
+        let metadata = doc_service.create_document("Test Document for Follower Reads").await?;
+        let content_data = vec![4, 5, 6];
+        let token = doc_service.update_document_content(metadata.id, content_data.clone()).await?;
+
+        // Catch the first moment the follower read timestamp reaches the write, when its
+        // token stops redirecting reads to the leaseholder.
+        let token_consistency = ReadConsistency::AtLeast(token);
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while token_consistency.resolve_in(&doc_service.db_manager.pool).await? != ReadConsistency::Follower {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+        let document = doc_service
+            .get_document_with(metadata.id, token_consistency)
+            .await?
+            .expect("Document should exist");
+        assert_eq!(document.content.unwrap().crdt_data, content_data);
+        assert_eq!(document.metadata.causality_token(), token);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_offloaded_documents_rehydrate_on_access() -> Result<()> {
+        let cold_storage = Arc::new(ColdStorage::new(
+            Arc::new(object_store::memory::InMemory::new()),
+            object_store::path::Path::from("test"),
+        ));
+        let doc_service = get_test_document_service().await
+            .expect("Failed to initialize test document service")
+            .with_cold_storage(cold_storage);
+
+        let metadata = doc_service.create_document("Test Document for Cold Storage").await?;
+        let content_data = vec![42; 1024];
+        doc_service.update_document_content(metadata.id, content_data.clone()).await?;
+
+        assert!(doc_service.offload_document(metadata.id).await?, "Document should be offloaded");
+        assert!(!doc_service.offload_document(metadata.id).await?, "Offloading twice should be a no-op");
+
+        let content = doc_service.get_document_content(metadata.id).await?.unwrap();
+        assert_eq!(content.crdt_data, content_data);
+        // Rehydration is permanent, so the document can be offloaded again.
+        assert!(doc_service.offload_document(metadata.id).await?);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_get_non_existent_document() -> Result<()> {
         let doc_service = get_test_document_service().await
@@ -337,4 +2855,446 @@ mod tests {
         
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_previews_follow_saved_content() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let doc = doc_service.create_document("Test Document for Previews").await?;
+
+        doc_service.update_document_content(doc.id, b"Agenda\nBudget".to_vec()).await?;
+        let expected = preview::render(b"Agenda\nBudget", None);
+        assert_eq!(doc_service.get_document_preview(doc.id).await?, expected);
+
+        // Content saved before previews existed gets one on first request.
+        sqlx::query("UPDATE documents_content SET preview_html = NULL WHERE document_id = $1")
+            .bind(doc.id)
+            .execute(&*doc_service.db_manager.pool)
+            .await?;
+        assert_eq!(doc_service.get_document_preview(doc.id).await?, expected);
+
+        doc_service.update_document_content(doc.id, vec![0xff]).await?;
+        assert_eq!(doc_service.get_document_preview(doc.id).await?, None);
+        assert_eq!(doc_service.get_document_preview(Uuid::new_v4()).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_updates_are_logged_and_compacted() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let doc = doc_service.create_document("Test Document for the Update Log").await?;
+        let count_updates = async || -> Result<i64> {
+            Ok(sqlx::query_scalar("SELECT count(*) FROM document_updates WHERE document_id = $1")
+                .bind(doc.id)
+                .fetch_one(&*doc_service.db_manager.pool)
+                .await?)
+        };
+
+        let first = b"The quick brown fox jumps over the lazy dog".to_vec();
+        doc_service.update_document_content(doc.id, first.clone()).await?;
+        let second = b"The quick red fox jumps over the lazy dog".to_vec();
+        doc_service.append_document_update(doc.id, &first, second.clone(), None).await?;
+        let third = b"The quick red fox jumps over the sleepy dog".to_vec();
+        doc_service.append_document_update(doc.id, &second, third.clone(), None).await?;
+        assert_eq!(count_updates().await?, 2);
+        assert_eq!(doc_service.get_document_content(doc.id).await?.unwrap().crdt_data, third);
+        assert!(doc_service.find_documents_to_compact(2, 1000).await?.contains(&doc.id));
+        for version in [&first, &second, &third] {
+            assert_eq!(doc_service.logged_content(doc.id, &integrity::checksum(version)).await?.as_ref(), Some(version));
+        }
+        assert_eq!(doc_service.logged_content(doc.id, &integrity::checksum(b"never saved")).await?, None);
+
+        // An update against stale content is saved as a snapshot instead.
+        doc_service.append_document_update(doc.id, &first, second.clone(), None).await?;
+        assert_eq!(count_updates().await?, 0);
+        assert_eq!(doc_service.get_document_content(doc.id).await?.unwrap().crdt_data, second);
+
+        doc_service.append_document_update(doc.id, &second, third.clone(), None).await?;
+        assert!(doc_service.compact_document(doc.id).await?);
+        assert!(!doc_service.compact_document(doc.id).await?);
+        assert_eq!(count_updates().await?, 0);
+        assert_eq!(doc_service.logged_content(doc.id, &integrity::checksum(&second)).await?, None, "Compacted away");
+        assert_eq!(doc_service.get_document_content(doc.id).await?.unwrap().crdt_data, third);
+        assert_eq!(doc_service.check_document_integrity(doc.id).await?, Some(Integrity::Intact));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blame_follows_updates_through_compaction() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let doc = doc_service.create_document("Test Document for Blame").await?;
+        let (alice, bob) = (Some(Uuid::new_v4()), Some(Uuid::new_v4()));
+        let blame = async || -> Result<Vec<(usize, usize, Option<Uuid>)>> {
+            let attribution = doc_service.blame(doc.id).await?.expect("document should exist");
+            Ok(attribution.spans(0..usize::MAX).iter().map(|span| (span.start, span.end, span.author)).collect())
+        };
+
+        let first = b"The quick brown fox".to_vec();
+        doc_service.update_document_content(doc.id, first.clone()).await?;
+        let second = b"The quick red fox".to_vec();
+        doc_service.append_document_update(doc.id, &first, second.clone(), alice).await?;
+        let third = b"The quick red fox jumps".to_vec();
+        doc_service.append_document_update(doc.id, &second, third.clone(), bob).await?;
+        let expected = vec![(0, 10, None), (10, 13, alice), (13, 17, None), (17, 23, bob)];
+        assert_eq!(blame().await?, expected);
+
+        // Compaction discards the updates but not who made them.
+        assert!(doc_service.compact_document(doc.id).await?);
+        assert_eq!(blame().await?, expected);
+        let fourth = b"The very quick red fox jumps".to_vec();
+        doc_service.append_document_update(doc.id, &third, fourth.clone(), alice).await?;
+        assert_eq!(blame().await?, vec![(0, 4, None), (4, 9, alice), (9, 15, None), (15, 18, alice), (18, 22, None), (22, 28, bob)]);
+
+        // A rewrite large enough to be saved as a snapshot still only takes over what it changed.
+        let fifth = b"The very quick red fox jumps over the lazy dog and far away".to_vec();
+        doc_service.append_document_update(doc.id, &fourth, fifth.clone(), bob).await?;
+        assert_eq!(blame().await?, vec![(0, 4, None), (4, 9, alice), (9, 15, None), (15, 18, alice), (18, 22, None), (22, 59, bob)]);
+
+        // Content replaced without an author is no one's.
+        doc_service.update_document_content(doc.id, b"Gone".to_vec()).await?;
+        assert_eq!(blame().await?, vec![(0, 4, None)]);
+        assert_eq!(doc_service.blame(Uuid::new_v4()).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_names_are_unique_per_folder() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let (owner, other_owner) = (Some(Uuid::new_v4()), Some(Uuid::new_v4()));
+        let plan = doc_service.create_document_for("Plan", owner).await?;
+        fn taken<T: std::fmt::Debug>(result: Result<T>) -> NameTaken {
+            result.unwrap_err().downcast::<NameTaken>().unwrap()
+        }
+
+        let error = taken(doc_service.create_document_for(" plan ", owner).await);
+        assert_eq!(error.suggestions, ["plan (2)", "plan (3)", "plan (4)"]);
+        let second = doc_service.create_document_for("Plan (2)", owner).await?;
+        let error = taken(doc_service.create_document_for("Plan (2)", owner).await);
+        assert_eq!(error.suggestions, ["Plan (3)", "Plan (4)", "Plan (5)"]);
+        taken(doc_service.rename_document(second.id, "PLAN").await);
+
+        // Names are per folder, and only documents in one are checked.
+        doc_service.create_document_for("Plan", other_owner).await?;
+        doc_service.create_document("Plan").await?;
+        doc_service.create_document("Plan").await?;
+
+        // Renaming a document to its own name, or to a freed one, succeeds.
+        doc_service.rename_document(plan.id, "PLAN").await?;
+        doc_service.rename_document(plan.id, "Agenda").await?;
+        doc_service.rename_document(second.id, "Plan").await?;
+        assert!(doc_service.delete_document(second.id).await?);
+        doc_service.create_document_for("Plan", owner).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_versions_can_be_listed_and_restored() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let doc = doc_service.create_document("Test Document for Versions").await?;
+        let author = Some(Uuid::new_v4());
+
+        doc_service.update_document_content(doc.id, b"first draft".to_vec()).await?;
+        let first = doc_service.create_version(doc.id, author).await?.unwrap();
+        assert_eq!((first.version, first.size, first.created_by), (1, 11, author));
+        doc_service.update_document_content(doc.id, b"second draft".to_vec()).await?;
+        doc_service.create_version(doc.id, None).await?.unwrap();
+        let versions: Vec<i64> = doc_service.list_versions(doc.id).await?.iter().map(|version| version.version).collect();
+        assert_eq!(versions, [2, 1]);
+
+        doc_service.update_document_content(doc.id, b"third draft".to_vec()).await?;
+        assert!(doc_service.restore_version(doc.id, 1, author).await?.is_some());
+        assert_eq!(doc_service.get_document_content(doc.id).await?.unwrap().crdt_data, b"first draft");
+        // The state it replaced was saved first.
+        assert_eq!(doc_service.get_version_content(doc.id, 3).await?.unwrap(), b"third draft");
+
+        assert!(doc_service.restore_version(doc.id, 99, author).await?.is_none());
+        assert!(doc_service.create_version(Uuid::new_v4(), author).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_comments_are_listed_and_pass_through_hooks() -> Result<()> {
+        struct NoSpoilers;
+
+        #[async_trait::async_trait]
+        impl crate::hooks::Hook for NoSpoilers {
+            fn name(&self) -> &str {
+                "no-spoilers"
+            }
+
+            async fn on_comment(&self, event: &CommentEvent<'_>) -> Result<()> {
+                anyhow::ensure!(!event.body.contains("spoiler"), "no spoilers");
+                Ok(())
+            }
+        }
+
+        let mut hooks = HookRegistry::new();
+        hooks.register(Arc::new(NoSpoilers));
+        let doc_service = get_test_document_service().await?.with_hooks(Arc::new(hooks));
+        let doc = doc_service.create_document("Test Document for Comments").await?;
+        let author = Some(Uuid::new_v4());
+
+        let first = doc_service.add_comment(doc.id, author, CommentThread::Start { anchor: None }, "Looks good").await?.unwrap();
+        let second = doc_service.add_comment(doc.id, None, CommentThread::Reply { to: first.id }, "Agreed").await?.unwrap();
+        assert_eq!((first.document_id, first.author, first.body.as_str()), (doc.id, author, "Looks good"));
+        assert_eq!(doc_service.list_comments(doc.id).await?, [first, second]);
+
+        let err = doc_service.add_comment(doc.id, author, CommentThread::Start { anchor: None }, "spoiler: it was a dream").await.unwrap_err();
+        assert!(err.downcast_ref::<crate::hooks::HookRejection>().is_some());
+        assert!(doc_service.add_comment(Uuid::new_v4(), author, CommentThread::Start { anchor: None }, "Hello?").await?.is_none());
+        assert_eq!(doc_service.list_comments(doc.id).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_comment_threads_resolve_and_delete_together() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let doc = doc_service.create_document("Test Document for Comment Threads").await?;
+        let other = doc_service.create_document("Another Test Document for Comment Threads").await?;
+        let anchor = CommentAnchor { start: "AQID".to_string(), end: Some("BAUG".to_string()) };
+
+        let root = doc_service.add_comment(doc.id, None, CommentThread::Start { anchor: Some(anchor.clone()) }, "Reword this?").await?.unwrap();
+        assert_eq!((root.thread_id, root.anchor.as_ref(), root.resolved), (root.id, Some(&anchor), false));
+        let reply = doc_service.add_comment(doc.id, None, CommentThread::Reply { to: root.id }, "Done").await?.unwrap();
+        let nested = doc_service.add_comment(doc.id, None, CommentThread::Reply { to: reply.id }, "Thanks").await?.unwrap();
+        assert_eq!((reply.thread_id, reply.anchor, nested.thread_id), (root.id, None, root.id));
+        assert!(doc_service.add_comment(other.id, None, CommentThread::Reply { to: root.id }, "Wrong place").await?.is_none());
+
+        assert_eq!(doc_service.resolve_thread(doc.id, nested.id, true).await?, Some(root.id));
+        assert!(doc_service.list_comments(doc.id).await?.iter().all(|comment| comment.resolved));
+        let late = doc_service.add_comment(doc.id, None, CommentThread::Reply { to: root.id }, "One more thing").await?.unwrap();
+        assert!(late.resolved);
+        assert_eq!(doc_service.resolve_thread(other.id, root.id, false).await?, None);
+
+        assert!(doc_service.delete_comment(doc.id, reply.id).await?);
+        assert_eq!(doc_service.list_comments(doc.id).await?.len(), 3);
+        assert!(doc_service.delete_comment(doc.id, root.id).await?);
+        assert!(doc_service.list_comments(doc.id).await?.is_empty());
+        assert!(!doc_service.delete_comment(doc.id, root.id).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rename_and_delete_document() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let metadata = doc_service.create_document("Test Document for Renaming").await?;
+        let (_, seq) = doc_service.get_document_sequences(&[metadata.id], ReadConsistency::Strong).await?.remove(0);
+
+        let renamed = doc_service.rename_document(metadata.id, "Renamed Test Document").await?.unwrap();
+        assert_eq!(renamed.name, "Renamed Test Document");
+        assert_eq!(renamed.created_at, metadata.created_at);
+        let (_, renamed_seq) = doc_service.get_document_sequences(&[metadata.id], ReadConsistency::Strong).await?.remove(0);
+        assert_eq!(renamed_seq, seq + 1);
+
+        assert!(doc_service.delete_document(metadata.id).await?);
+        assert!(doc_service.get_document(metadata.id).await?.is_none());
+        assert!(!doc_service.delete_document(metadata.id).await?);
+        assert!(doc_service.rename_document(metadata.id, "Gone").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_trashed_documents_can_be_restored_until_purged() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let owner = Uuid::new_v4();
+        let doc = doc_service.create_document_for("Plan", Some(owner)).await?;
+        doc_service.update_document_content(doc.id, b"draft".to_vec()).await?;
+
+        assert!(doc_service.delete_document(doc.id).await?);
+        assert!(doc_service.get_document_metadata(doc.id).await?.is_none());
+        let mine = DocumentFilter { owner: Some(owner), ..Default::default() };
+        let page = doc_service.list_documents(&mine, None, 10, DocumentSort::default(), ReadConsistency::Strong).await?;
+        assert!(page.documents.is_empty());
+        let trash: Vec<Uuid> = doc_service.list_trash(owner).await?.iter().map(|trashed| trashed.metadata.id).collect();
+        assert_eq!(trash, [doc.id]);
+
+        // The trashed document gave up its name, so it comes back under another.
+        doc_service.create_document_for("Plan", Some(owner)).await?;
+        let restored = doc_service.restore_from_trash(doc.id).await?.unwrap();
+        assert_eq!(restored.name, "Plan (2)");
+        assert_eq!(doc_service.get_document_content(doc.id).await?.unwrap().crdt_data, b"draft");
+        assert!(doc_service.list_trash(owner).await?.is_empty());
+        assert!(doc_service.restore_from_trash(doc.id).await?.is_none());
+
+        // Purging takes only documents trashed before the cutoff.
+        assert!(doc_service.delete_document(doc.id).await?);
+        let purged = doc_service.purge_deleted(Utc::now() - chrono::TimeDelta::days(1), 1000).await?;
+        assert!(!purged.contains(&doc.id));
+        while !doc_service.purge_deleted(Utc::now() + chrono::TimeDelta::seconds(1), 1000).await?.is_empty() {}
+        assert!(doc_service.restore_from_trash(doc.id).await?.is_none());
+        assert!(doc_service.get_document_content(doc.id).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_documents_in_pages() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let owner = Uuid::new_v4();
+        let mut created = Vec::new();
+        for name in ["Charlie", "alpha", "Bravo", "Delta", "Echo"] {
+            created.push(doc_service.create_document_for(name, Some(owner)).await?);
+        }
+        doc_service.create_document("Not owned").await?;
+        let mine = DocumentFilter { owner: Some(owner), ..Default::default() };
+
+        let mut listed = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = doc_service
+                .list_documents(&mine, cursor.as_ref(), 2, DocumentSort::CreatedAsc, ReadConsistency::Strong)
+                .await?;
+            assert!(page.documents.len() <= 2);
+            listed.extend(page.documents);
+            // Cursors survive a round trip through their opaque form.
+            match page.next_cursor {
+                Some(next) => cursor = Some(DocumentCursor::decode(&next.encode())?),
+                None => break,
+            }
+        }
+        created.sort_by_key(|doc| (doc.created_at, doc.id));
+        let ids = |docs: &[DocumentMetadata]| docs.iter().map(|doc| doc.id).collect::<Vec<_>>();
+        assert_eq!(ids(&listed), ids(&created));
+
+        let by_name = doc_service
+            .list_documents(&mine, None, 10, DocumentSort::Name, ReadConsistency::Strong)
+            .await?;
+        let names: Vec<&str> = by_name.documents.iter().map(|doc| doc.name.as_str()).collect();
+        assert_eq!(names, ["Bravo", "Charlie", "Delta", "Echo", "alpha"]);
+        assert!(by_name.next_cursor.is_none());
+
+        let only = [created[3].id];
+        let restricted = doc_service
+            .list_documents(&DocumentFilter { restrict_to: Some(&only), ..Default::default() }, None, 10, DocumentSort::UpdatedDesc, ReadConsistency::Strong)
+            .await?;
+        assert_eq!(ids(&restricted.documents), [created[3].id]);
+
+        // Callers without an account see documents anyone may use, and only those they are let into.
+        let public = doc_service.create_document("Public").await?;
+        let candidates = [public.id, created[0].id, created[1].id];
+        let linked = [created[1].id];
+        let anonymous = doc_service
+            .list_documents(
+                &DocumentFilter { restrict_to: Some(&candidates), public_and: Some(&linked), ..Default::default() },
+                None,
+                10,
+                DocumentSort::CreatedAsc,
+                ReadConsistency::Strong,
+            )
+            .await?;
+        assert_eq!(ids(&anonymous.documents), [created[1].id, public.id]);
+
+        // A cursor only continues the listing it came from.
+        let first = doc_service
+            .list_documents(&mine, None, 1, DocumentSort::Name, ReadConsistency::Strong)
+            .await?;
+        let err = doc_service
+            .list_documents(&mine, first.next_cursor.as_ref(), 1, DocumentSort::UpdatedAsc, ReadConsistency::Strong)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<InvalidCursor>().is_some());
+        assert!(DocumentCursor::decode("not a cursor").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_language_is_detected_when_snapshots_are_saved() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let owner = Uuid::new_v4();
+        let doc = doc_service.create_document_for("Notes", Some(owner)).await?;
+        assert_eq!(doc_service.get_document_metadata(doc.id).await?.unwrap().language, None);
+
+        let english = "The committee will meet again next week to review the budget and the hiring plan.";
+        doc_service.update_document_content(doc.id, english.as_bytes().to_vec()).await?;
+        assert_eq!(doc_service.get_document_metadata(doc.id).await?.unwrap().language.as_deref(), Some("en"));
+        let listed = |language| {
+            let filter = DocumentFilter { owner: Some(owner), language: Some(language), ..Default::default() };
+            let doc_service = &doc_service;
+            async move {
+                let page = doc_service.list_documents(&filter, None, 10, DocumentSort::default(), ReadConsistency::Strong).await?;
+                Ok::<_, anyhow::Error>(page.documents.iter().map(|doc| doc.id).collect::<Vec<_>>())
+            }
+        };
+        assert_eq!(listed("en").await?, [doc.id]);
+        assert!(listed("de").await?.is_empty());
+
+        let german = "Der Ausschuss trifft sich nächste Woche erneut, um den Haushalt und die Einstellungen zu prüfen.";
+        doc_service.update_document_content(doc.id, german.as_bytes().to_vec()).await?;
+        assert_eq!(listed("de").await?, [doc.id]);
+        assert!(listed("en").await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search_documents_by_name() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let marker = Uuid::new_v4().simple().to_string();
+        let visible = doc_service.create_document(&format!("Roadmap {} 100%", marker)).await?;
+        doc_service.create_document(&format!("roadmap {} draft", marker)).await?;
+
+        let found = doc_service.search_documents_by_name(&marker.to_uppercase(), None, 10, ReadConsistency::Strong).await?;
+        assert_eq!(found.len(), 2);
+        let found = doc_service
+            .search_documents_by_name(&marker, Some(&[visible.id]), 10, ReadConsistency::Strong)
+            .await?;
+        assert_eq!(found.iter().map(|doc| doc.id).collect::<Vec<_>>(), vec![visible.id]);
+        // Wildcards in the query match literally.
+        let found = doc_service.search_documents_by_name(&format!("{} 100%", marker), None, 10, ReadConsistency::Strong).await?;
+        assert_eq!(found.iter().map(|doc| doc.id).collect::<Vec<_>>(), vec![visible.id]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_snapshots_are_compressed_at_rest() -> Result<()> {
+        let compression = Arc::new(Compression::new(compression::DEFAULT_LEVEL));
+        let doc_service = get_test_document_service().await?.with_compression(compression);
+        let metadata = doc_service.create_document("Test Document for Compression").await?;
+        let content = b"all work and no play ".repeat(500);
+        doc_service.update_document_content(metadata.id, content.clone()).await?;
+        let stored: Vec<u8> = sqlx::query_scalar("SELECT crdt_data FROM documents_content WHERE document_id = $1")
+            .bind(metadata.id)
+            .fetch_one(&*doc_service.db_manager.pool)
+            .await?;
+        assert!(compression::is_compressed(&stored) && stored.len() < content.len());
+        assert_eq!(doc_service.get_document_content(metadata.id).await?.unwrap().crdt_data, content);
+        assert_eq!(doc_service.check_document_integrity(metadata.id).await?, Some(Integrity::Intact));
+        assert!(doc_service.compression_stats().ratio.is_some_and(|ratio| ratio > 1.0));
+
+        // Snapshots saved before compression are read as they are.
+        sqlx::query("UPDATE documents_content SET crdt_data = $1 WHERE document_id = $2")
+            .bind(&content)
+            .bind(metadata.id)
+            .execute(&*doc_service.db_manager.pool)
+            .await?;
+        assert_eq!(doc_service.get_document_content(metadata.id).await?.unwrap().crdt_data, content);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_content_is_detected() -> Result<()> {
+        let doc_service = get_test_document_service().await?;
+        let metadata = doc_service.create_document("Test Document for Integrity").await?;
+        doc_service.update_document_content(metadata.id, vec![1, 2, 3]).await?;
+        assert_eq!(doc_service.check_document_integrity(metadata.id).await?, Some(Integrity::Intact));
+
+        // Corrupt the blob behind the service's back.
+        sqlx::query("UPDATE documents_content SET crdt_data = $1 WHERE document_id = $2")
+            .bind(vec![1u8, 2, 4])
+            .bind(metadata.id)
+            .execute(&*doc_service.db_manager.pool)
+            .await?;
+        assert!(matches!(
+            doc_service.check_document_integrity(metadata.id).await?,
+            Some(Integrity::Corrupted(_))
+        ));
+        let err = doc_service.get_document_content(metadata.id).await.unwrap_err();
+        assert!(err.is::<integrity::ChecksumMismatch>());
+
+        // Rows written before checksums existed are still readable.
+        sqlx::query("UPDATE documents_content SET checksum = NULL WHERE document_id = $1")
+            .bind(metadata.id)
+            .execute(&*doc_service.db_manager.pool)
+            .await?;
+        assert_eq!(doc_service.check_document_integrity(metadata.id).await?, Some(Integrity::Unverified));
+        assert_eq!(doc_service.get_document_content(metadata.id).await?.unwrap().crdt_data, vec![1, 2, 4]);
+        Ok(())
+    }
+}
@@ -0,0 +1,499 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Email digests: users who opt in get one email a day or a week, at a fixed local time,
+//! covering their unread notifications and changes to documents they own.
+
+use crate::db::Manager;
+use crate::email::{Email, Mailer};
+use crate::i18n::{self, Locale};
+use crate::migrations::{self, Component, Migration};
+use crate::preview::escape_html;
+use crate::watch::DOCUMENT_DIGEST;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Datelike, Days, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow};
+use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+const PUBLIC_URL_ENV: &str = "COLLABORATE_PUBLIC_URL";
+/// Digests go out at this local hour, daily or on Mondays.
+const SEND_HOUR: u32 = 8;
+const MAX_DIGEST_NOTIFICATIONS: i64 = 50;
+const MAX_DIGEST_DOCUMENTS: i64 = 20;
+
+/// The server's public base URL, for links in emails. Reads `COLLABORATE_PUBLIC_URL`.
+pub fn public_url_from_env() -> Option<String> {
+    env::var(PUBLIC_URL_ENV).ok().map(|url| url.trim_end_matches('/').to_string())
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DigestFrequency {
+    #[default]
+    Off,
+    Daily,
+    Weekly,
+}
+
+impl DigestFrequency {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DigestFrequency::Off => "off",
+            DigestFrequency::Daily => "daily",
+            DigestFrequency::Weekly => "weekly",
+        }
+    }
+}
+
+impl FromStr for DigestFrequency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(DigestFrequency::Off),
+            "daily" => Ok(DigestFrequency::Daily),
+            "weekly" => Ok(DigestFrequency::Weekly),
+            other => Err(anyhow!("Unknown digest frequency '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DigestPreferences {
+    pub frequency: DigestFrequency,
+    /// IANA name of the timezone digests are scheduled in.
+    pub timezone: String,
+    /// Language tag of the locale digests are written in.
+    pub locale: String,
+}
+
+impl Default for DigestPreferences {
+    fn default() -> Self {
+        DigestPreferences {
+            frequency: DigestFrequency::Off,
+            timezone: "UTC".to_string(),
+            locale: Locale::FALLBACK.tag().to_string(),
+        }
+    }
+}
+
+/// The most recent scheduled send time at or before `now`: today's (or yesterday's) send
+/// hour for daily digests, or the latest Monday's for weekly ones.
+fn period_start(frequency: DigestFrequency, tz: Tz, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let local = now.with_timezone(&tz);
+    let mut day = local.date_naive();
+    if local.hour() < SEND_HOUR {
+        day = day.pred_opt()?;
+    }
+    match frequency {
+        DigestFrequency::Off => return None,
+        DigestFrequency::Daily => {}
+        DigestFrequency::Weekly => day = day - Days::new(day.weekday().num_days_from_monday().into()),
+    }
+    let send_at = day.and_hms_opt(SEND_HOUR, 0, 0)?;
+    // If a DST change skips the send hour, send at the same wall-clock time in UTC instead.
+    let start = tz.from_local_datetime(&send_at).earliest().unwrap_or_else(|| Utc.from_utc_datetime(&send_at).with_timezone(&tz));
+    Some(start.with_timezone(&Utc))
+}
+
+#[derive(FromRow)]
+struct Subscriber {
+    user_id: Uuid,
+    email: String,
+    frequency: String,
+    timezone: String,
+    locale: String,
+    unsubscribe_token: String,
+    last_sent_at: Option<DateTime<Utc>>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct DigestNotification {
+    kind: String,
+    payload: Value,
+}
+
+#[derive(FromRow)]
+struct ChangedDocument {
+    id: Uuid,
+    name: String,
+}
+
+#[derive(Clone)]
+pub struct EmailDigestService {
+    db_manager: Arc<Manager>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "email_digest",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS digest_preferences (
+                user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+                frequency TEXT NOT NULL,
+                timezone TEXT NOT NULL,
+                locale TEXT NOT NULL,
+                unsubscribe_token TEXT NOT NULL UNIQUE,
+                last_sent_at TIMESTAMPTZ,
+                updated_at TIMESTAMPTZ NOT NULL
+            )",
+        ],
+        down: &[
+            "DROP TABLE IF EXISTS digest_preferences",
+        ],
+    }],
+};
+
+impl EmailDigestService {
+    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
+        let service = EmailDigestService { db_manager };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("Email digest schema initialized.");
+        Ok(())
+    }
+
+    /// A user's digest preferences; digests are off until the user opts in.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn preferences(&self, user_id: Uuid) -> Result<DigestPreferences> {
+        let row: Option<(String, String, String)> = sqlx::query_as(
+                "SELECT frequency, timezone, locale FROM digest_preferences WHERE user_id = $1"
+            )
+            .bind(user_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to get digest preferences for user ID {}", user_id))?;
+        match row {
+            Some((frequency, timezone, locale)) => Ok(DigestPreferences { frequency: frequency.parse()?, timezone, locale }),
+            None => Ok(DigestPreferences::default()),
+        }
+    }
+
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn set_preferences(&self, user_id: Uuid, frequency: DigestFrequency, timezone: Tz, locale: Locale) -> Result<DigestPreferences> {
+        let mut token = [0u8; 32];
+        rand::rng().fill_bytes(&mut token);
+        sqlx::query(
+                "INSERT INTO digest_preferences (user_id, frequency, timezone, locale, unsubscribe_token, updated_at)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT (user_id) DO UPDATE
+                 SET frequency = excluded.frequency, timezone = excluded.timezone, locale = excluded.locale,
+                     updated_at = excluded.updated_at"
+            )
+            .bind(user_id)
+            .bind(frequency.as_str())
+            .bind(timezone.name())
+            .bind(locale.tag())
+            .bind(hex::encode(token))
+            .bind(Utc::now())
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to set digest preferences for user ID {}", user_id))?;
+        Ok(DigestPreferences { frequency, timezone: timezone.name().to_string(), locale: locale.tag().to_string() })
+    }
+
+    /// Turns digests off for whoever was sent `token`. Returns false for unknown tokens.
+    #[instrument(skip_all)]
+    pub async fn unsubscribe(&self, token: &str) -> Result<bool> {
+        let result = sqlx::query("UPDATE digest_preferences SET frequency = $1, updated_at = $2 WHERE unsubscribe_token = $3")
+            .bind(DigestFrequency::Off.as_str())
+            .bind(Utc::now())
+            .bind(token)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context("Failed to unsubscribe from digests")?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Sends every digest that has come due. Returns the number of emails sent.
+    #[instrument(skip_all)]
+    pub async fn send_due_digests(&self, mailer: &dyn Mailer, public_url: &str, now: DateTime<Utc>) -> Result<usize> {
+        let subscribers: Vec<Subscriber> = sqlx::query_as(
+                "SELECT p.user_id, u.email, p.frequency, p.timezone, p.locale, p.unsubscribe_token, p.last_sent_at, p.updated_at
+                 FROM digest_preferences p JOIN users u ON u.id = p.user_id
+                 WHERE p.frequency <> 'off' AND u.email IS NOT NULL AND u.anonymized_at IS NULL"
+            )
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to find digest subscribers")?;
+        let mut sent = 0;
+        for subscriber in subscribers {
+            let tz = subscriber.timezone.parse::<Tz>().unwrap_or(Tz::UTC);
+            let Some(start) = period_start(subscriber.frequency.parse()?, tz, now) else {
+                continue;
+            };
+            if subscriber.last_sent_at.is_some_and(|last_sent_at| last_sent_at >= start) {
+                continue;
+            }
+            match self.send_digest(mailer, public_url, &subscriber, now).await {
+                Ok(true) => sent += 1,
+                Ok(false) => {}
+                Err(e) => warn!("Email digest for user ID {} failed; will retry: {:#}", subscriber.user_id, e),
+            }
+        }
+        Ok(sent)
+    }
+
+    // Claims the digest, then sends it unless there is nothing to report. Returns whether an email went out.
+    async fn send_digest(&self, mailer: &dyn Mailer, public_url: &str, subscriber: &Subscriber, now: DateTime<Utc>) -> Result<bool> {
+        // The conditional update keeps two nodes from sending the same digest.
+        let claimed = sqlx::query(
+                "UPDATE digest_preferences SET last_sent_at = $1 WHERE user_id = $2 AND last_sent_at IS NOT DISTINCT FROM $3"
+            )
+            .bind(now)
+            .bind(subscriber.user_id)
+            .bind(subscriber.last_sent_at)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to claim email digest for user ID {}", subscriber.user_id))?;
+        if claimed.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        let since = subscriber.last_sent_at.unwrap_or(subscriber.updated_at);
+        let notifications: Vec<DigestNotification> = sqlx::query_as(
+                "SELECT kind, payload FROM notifications
+                 WHERE user_id = $1 AND read_at IS NULL AND created_at > $2 ORDER BY created_at LIMIT $3"
+            )
+            .bind(subscriber.user_id)
+            .bind(since)
+            .bind(MAX_DIGEST_NOTIFICATIONS)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to gather notifications for user ID {}", subscriber.user_id))?;
+        let documents: Vec<ChangedDocument> = sqlx::query_as(
+                "SELECT id, name FROM documents_metadata
+                 WHERE owner_id = $1 AND updated_at > $2 AND deleted_at IS NULL ORDER BY updated_at DESC LIMIT $3"
+            )
+            .bind(subscriber.user_id)
+            .bind(since)
+            .bind(MAX_DIGEST_DOCUMENTS)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to gather document activity for user ID {}", subscriber.user_id))?;
+        if notifications.is_empty() && documents.is_empty() {
+            return Ok(false);
+        }
+
+        let locale = Locale::from_tag(&subscriber.locale).unwrap_or(Locale::FALLBACK);
+        let email = render_digest(locale, public_url, subscriber, &notifications, &documents);
+        if let Err(e) = mailer.send(&email).await {
+            // Release the claim so the next run retries.
+            sqlx::query("UPDATE digest_preferences SET last_sent_at = $1 WHERE user_id = $2 AND last_sent_at = $3")
+                .bind(subscriber.last_sent_at)
+                .bind(subscriber.user_id)
+                .bind(now)
+                .execute(&*self.db_manager.pool)
+                .await
+                .context(format!("Failed to release email digest for user ID {}", subscriber.user_id))?;
+            return Err(e);
+        }
+        Ok(true)
+    }
+}
+
+// An item in a digest, shared by the text and HTML parts.
+struct DigestLine {
+    text: String,
+    link: Option<String>,
+}
+
+fn document_link(public_url: &str, doc_id: impl std::fmt::Display) -> Option<String> {
+    Some(format!("{}/app/documents/{}", public_url, doc_id))
+}
+
+fn watched_lines(locale: Locale, public_url: &str, notifications: &[DigestNotification]) -> Vec<DigestLine> {
+    let mut lines = Vec::new();
+    for notification in notifications {
+        if notification.kind != DOCUMENT_DIGEST {
+            lines.push(DigestLine { text: i18n::message(locale, "digest-email-other-notification", &[]), link: None });
+            continue;
+        }
+        let changed = notification.payload["documents"].as_array().map(Vec::as_slice).unwrap_or_default();
+        for document in changed {
+            let name = document["name"].as_str().unwrap_or_default().to_string();
+            let changes = document["changes"].as_i64().unwrap_or_default().to_string();
+            lines.push(DigestLine {
+                text: i18n::message(locale, "digest-email-watched-document", &[("name", name), ("changes", changes)]),
+                link: document["id"].as_str().and_then(|id| document_link(public_url, id)),
+            });
+        }
+    }
+    lines
+}
+
+fn render_digest(
+    locale: Locale,
+    public_url: &str,
+    subscriber: &Subscriber,
+    notifications: &[DigestNotification],
+    documents: &[ChangedDocument],
+) -> Email {
+    let unsubscribe_url = format!("{}/api/digests/unsubscribe?token={}", public_url, subscriber.unsubscribe_token);
+    let owned = documents
+        .iter()
+        .map(|document| DigestLine { text: document.name.clone(), link: document_link(public_url, document.id) })
+        .collect();
+    let sections = [
+        ("digest-email-watched-heading", watched_lines(locale, public_url, notifications)),
+        ("digest-email-owned-heading", owned),
+    ];
+
+    let mut text = String::new();
+    let mut html = String::from("<html><body>");
+    for (heading, lines) in sections.iter().filter(|(_, lines)| !lines.is_empty()) {
+        let heading = i18n::message(locale, heading, &[]);
+        text.push_str(&format!("{}\n\n", heading));
+        html.push_str(&format!("<h2>{}</h2><ul>", escape_html(&heading)));
+        for line in lines {
+            match &line.link {
+                Some(link) => {
+                    text.push_str(&format!("- {} <{}>\n", line.text, link));
+                    html.push_str(&format!("<li><a href=\"{}\">{}</a></li>", escape_html(link), escape_html(&line.text)));
+                }
+                None => {
+                    text.push_str(&format!("- {}\n", line.text));
+                    html.push_str(&format!("<li>{}</li>", escape_html(&line.text)));
+                }
+            }
+        }
+        text.push('\n');
+        html.push_str("</ul>");
+    }
+    let unsubscribe = i18n::message(locale, "digest-email-unsubscribe", &[]);
+    text.push_str(&format!("{}: {}\n", unsubscribe, unsubscribe_url));
+    html.push_str(&format!("<p><a href=\"{}\">{}</a></p></body></html>", escape_html(&unsubscribe_url), escape_html(&unsubscribe)));
+
+    Email {
+        to: subscriber.email.clone(),
+        subject: i18n::message(locale, "digest-email-subject", &[]),
+        text,
+        html,
+        unsubscribe_url: Some(unsubscribe_url),
+    }
+}
+
+/// Periodically sends the digests that have come due.
+pub async fn run_email_digests(service: Arc<EmailDigestService>, mailer: Arc<dyn Mailer>, public_url: String, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match service.send_due_digests(mailer.as_ref(), &public_url, Utc::now()).await {
+            Ok(0) => {}
+            Ok(sent) => info!("Sent {} email digests", sent),
+            Err(e) => warn!("Sending email digests failed; will retry: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_service::DocumentService;
+    use crate::user_service::UserService;
+    use crate::watch::WatchService;
+    use async_trait::async_trait;
+    use chrono::TimeDelta;
+    use std::sync::Mutex;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[derive(Default)]
+    struct RecordingMailer {
+        sent: Mutex<Vec<Email>>,
+    }
+
+    #[async_trait]
+    impl Mailer for RecordingMailer {
+        async fn send(&self, email: &Email) -> Result<()> {
+            self.sent.lock().unwrap().push(email.clone());
+            Ok(())
+        }
+    }
+
+    impl RecordingMailer {
+        fn sent_to(&self, to: &str) -> Vec<Email> {
+            self.sent.lock().unwrap().iter().filter(|email| email.to == to).cloned().collect()
+        }
+    }
+
+    #[test]
+    fn test_digests_are_scheduled_in_local_time() {
+        let berlin: Tz = "Europe/Berlin".parse().unwrap();
+        let utc = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        // Wednesday 07:30 in Berlin (CEST): before the send hour, so yesterday's slot.
+        let now = utc("2025-06-11T05:30:00Z");
+        assert_eq!(period_start(DigestFrequency::Daily, berlin, now), Some(utc("2025-06-10T06:00:00Z")));
+        assert_eq!(period_start(DigestFrequency::Weekly, berlin, now), Some(utc("2025-06-09T06:00:00Z")));
+        assert_eq!(period_start(DigestFrequency::Daily, berlin, utc("2025-06-11T06:00:00Z")), Some(utc("2025-06-11T06:00:00Z")));
+        assert_eq!(period_start(DigestFrequency::Off, berlin, now), None);
+    }
+
+    #[tokio::test]
+    async fn test_digest_emails() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let users = UserService::new(manager.clone()).await?;
+        let watches = WatchService::new(manager.clone()).await?;
+        let digests = EmailDigestService::new(manager).await?;
+        let address = format!("digest-{}@example.com", Uuid::new_v4());
+        let user = users.register_user("Digest Reader", &address, "correct horse battery").await?;
+        let de = Locale::from_tag("de").unwrap();
+        digests.set_preferences(user.id, DigestFrequency::Daily, "Europe/Berlin".parse().unwrap(), de).await?;
+
+        let watched = doc_service.create_document("Test Document for Email Digests").await?;
+        watches.watch(user.id, watched.id).await?;
+        doc_service.update_document_content(watched.id, b"news".to_vec()).await?;
+        watches.send_digests().await?;
+        let owned = doc_service.create_document_for("Owned Document for Email Digests", Some(user.id)).await?;
+
+        let mailer = RecordingMailer::default();
+        let now = Utc::now();
+        digests.send_due_digests(&mailer, "https://collab.example.com", now).await?;
+        let sent = mailer.sent_to(&address);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].subject, i18n::message(de, "digest-email-subject", &[]));
+        assert!(sent[0].text.contains("Test Document for Email Digests (Änderungen: 1)"));
+        assert!(sent[0].html.contains(&format!("https://collab.example.com/app/documents/{}", owned.id)));
+
+        // Nothing more until tomorrow, and nothing to say then.
+        digests.send_due_digests(&mailer, "https://collab.example.com", now).await?;
+        digests.send_due_digests(&mailer, "https://collab.example.com", now + TimeDelta::days(1)).await?;
+        assert_eq!(mailer.sent_to(&address).len(), 1);
+
+        let unsubscribe_url = sent[0].unsubscribe_url.clone().unwrap();
+        let token = unsubscribe_url.split("token=").nth(1).unwrap();
+        assert!(digests.unsubscribe(token).await?);
+        assert_eq!(digests.preferences(user.id).await?.frequency, DigestFrequency::Off);
+        assert!(!digests.unsubscribe("not-a-token").await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,550 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Bring-your-own-key encryption for organizations. Once an organization registers a key it
+//! holds in its own key management service (KMS), the content of its documents is encrypted
+//! at rest: each document gets a random data key, stored only wrapped by the organization's
+//! key, that seals its snapshots, logged updates and versions with AES-256-GCM. The KMS is
+//! reached through a `KeyManager`; `HttpKeyManager` talks to one over HTTP.
+//!
+//! Rotating the organization's key rewraps every data key under the new one without touching
+//! content. Revoking it deletes the wrapped data keys, so nothing sealed with them can be read
+//! again by anyone, along with the previews and search terms derived from the content.
+//! Unwrapped data keys are cached for a few minutes, so other servers may go on reading
+//! content for that long after a revocation. Content saved before its organization had a key
+//! stays as it is until it is next written.
+
+use crate::db::Manager;
+use crate::migrations::{self, Component, Migration};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+const KMS_URL_ENV: &str = "COLLABORATE_KMS_URL";
+const KMS_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long an unwrapped data key is used before the KMS is asked for it again.
+const DATA_KEY_CACHE_TIME: Duration = Duration::from_secs(5 * 60);
+const DATA_KEY_BYTES: usize = 32;
+const REWRAP_BATCH_SIZE: i64 = 100;
+/// Starts everything sealed, ahead of the nonce and the ciphertext.
+const SEALED_PREFIX: &[u8] = b"\x00SEALED\x01";
+
+/// Wraps and unwraps data keys with keys held in a KMS, identified by the KMS's own IDs.
+#[async_trait]
+pub trait KeyManager: Send + Sync {
+    async fn wrap(&self, key_id: &str, data_key: &[u8]) -> Result<Vec<u8>>;
+    async fn unwrap(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>>;
+}
+
+#[derive(Serialize)]
+struct KmsRequest<'a> {
+    key_id: &'a str,
+    /// Base64.
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct KmsResponse {
+    /// Base64.
+    data: String,
+}
+
+/// Posts `{"key_id": ..., "data": ...}` to `<url>/wrap` or `<url>/unwrap`, with the data in
+/// base64, and expects `{"data": ...}` back, wrapped or unwrapped.
+pub struct HttpKeyManager {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpKeyManager {
+    pub fn new(url: String) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(KMS_TIMEOUT)
+            .build()
+            .context("Failed to build KMS HTTP client")?;
+        Ok(HttpKeyManager { client, url: url.trim_end_matches('/').to_string() })
+    }
+
+    /// Returns `None` when `COLLABORATE_KMS_URL` is unset.
+    pub fn from_env() -> Result<Option<Self>> {
+        match env::var(KMS_URL_ENV) {
+            Ok(url) => Ok(Some(HttpKeyManager::new(url)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn call(&self, operation: &str, key_id: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let url = format!("{}/{}", self.url, operation);
+        let response: KmsResponse = self.client
+            .post(&url)
+            .json(&KmsRequest { key_id, data: BASE64.encode(data) })
+            .send()
+            .await
+            .context(format!("Failed to reach KMS at {}", url))?
+            .error_for_status()
+            .context(format!("KMS refused to {} with key {}", operation, key_id))?
+            .json()
+            .await
+            .context("KMS returned a malformed response")?;
+        BASE64.decode(response.data).context("KMS returned malformed data")
+    }
+}
+
+#[async_trait]
+impl KeyManager for HttpKeyManager {
+    async fn wrap(&self, key_id: &str, data_key: &[u8]) -> Result<Vec<u8>> {
+        self.call("wrap", key_id, data_key).await
+    }
+
+    async fn unwrap(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+        self.call("unwrap", key_id, wrapped).await
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncryptionError {
+    /// The KMS would not wrap and unwrap a data key with the key given.
+    KeyUnusable,
+    /// The organization revoked its key, so its documents take no new content.
+    KeyRevoked,
+    /// The content was sealed with a data key that no longer exists.
+    Unreadable,
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionError::KeyUnusable => write!(f, "The KMS cannot use the key"),
+            EncryptionError::KeyRevoked => write!(f, "The organization's key has been revoked"),
+            EncryptionError::Unreadable => write!(f, "The content's data key has been revoked"),
+        }
+    }
+}
+
+impl std::error::Error for EncryptionError {}
+
+/// The key an organization's documents are encrypted with.
+#[derive(Clone, Debug, FromRow, PartialEq, Serialize)]
+pub struct OrganizationKey {
+    pub organization_id: Uuid,
+    /// The key's ID in the organization's KMS.
+    pub key_id: String,
+    /// Counts registrations and rotations, from 1.
+    pub version: i64,
+    pub created_at: DateTime<Utc>,
+    pub rotated_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "encryption",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS organization_keys (
+                organization_id UUID PRIMARY KEY,
+                key_id TEXT NOT NULL,
+                version BIGINT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                rotated_at TIMESTAMPTZ,
+                revoked_at TIMESTAMPTZ
+            )",
+            // Each data key records the organization key it is wrapped with, so rotation can
+            // rewrap them a batch at a time.
+            "CREATE TABLE IF NOT EXISTS document_keys (
+                document_id UUID PRIMARY KEY REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                organization_id UUID NOT NULL,
+                key_id TEXT NOT NULL,
+                key_version BIGINT NOT NULL,
+                wrapped_key BYTEA NOT NULL
+            )",
+            "CREATE INDEX IF NOT EXISTS document_keys_by_organization ON document_keys (organization_id, key_version)",
+        ],
+        down: &["DROP TABLE IF EXISTS document_keys", "DROP TABLE IF EXISTS organization_keys"],
+    }],
+};
+
+const KEY_COLUMNS: &str = "organization_id, key_id, version, created_at, rotated_at, revoked_at";
+
+/// The documents in an organization's workspaces; binds the organization as `$1`.
+const ORGANIZATION_DOCUMENTS: &str =
+    "SELECT m.id FROM documents_metadata m JOIN workspaces w ON w.id = m.workspace_id WHERE w.organization_id = $1";
+
+pub struct Encryption {
+    db_manager: Arc<Manager>,
+    key_manager: Arc<dyn KeyManager>,
+    rng: SystemRandom,
+    /// Unwrapped data keys by document, with when they were unwrapped.
+    data_keys: Mutex<HashMap<Uuid, (Instant, [u8; DATA_KEY_BYTES])>>,
+}
+
+impl Encryption {
+    pub async fn new(db_manager: Arc<Manager>, key_manager: Arc<dyn KeyManager>) -> Result<Self> {
+        migrations::apply(&db_manager.pool, &SCHEMA).await?;
+        info!("Encryption schema initialized.");
+        Ok(Encryption { db_manager, key_manager, rng: SystemRandom::new(), data_keys: Mutex::default() })
+    }
+
+    #[instrument(skip_all, fields(organization_id = %organization_id))]
+    pub async fn organization_key(&self, organization_id: Uuid) -> Result<Option<OrganizationKey>> {
+        sqlx::query_as(&format!("SELECT {KEY_COLUMNS} FROM organization_keys WHERE organization_id = $1"))
+            .bind(organization_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query key of organization ID {}", organization_id))
+    }
+
+    /// Encrypts the organization's documents with `key_id` from now on. If it already had a
+    /// key, every data key wrapped with that one is rewrapped with the new key; registering
+    /// the same ID again rewraps them with whatever the KMS now holds under it. Content
+    /// sealed under a revoked key stays unreadable.
+    #[instrument(skip_all, fields(organization_id = %organization_id))]
+    pub async fn set_key(&self, organization_id: Uuid, key_id: &str) -> Result<OrganizationKey> {
+        // Check the KMS can actually use the key before anything is wrapped with it.
+        let probe = self.generate_data_key()?;
+        let wrapped = self.key_manager.wrap(key_id, &probe).await.map_err(|_| EncryptionError::KeyUnusable)?;
+        if self.key_manager.unwrap(key_id, &wrapped).await.ok().as_deref() != Some(&probe[..]) {
+            return Err(EncryptionError::KeyUnusable.into());
+        }
+        let key: OrganizationKey = sqlx::query_as(&format!(
+                "INSERT INTO organization_keys (organization_id, key_id, version, created_at)
+                 VALUES ($1, $2, 1, $3)
+                 ON CONFLICT (organization_id) DO UPDATE
+                 SET key_id = EXCLUDED.key_id, version = organization_keys.version + 1,
+                     rotated_at = EXCLUDED.created_at, revoked_at = NULL
+                 RETURNING {KEY_COLUMNS}"
+            ))
+            .bind(organization_id)
+            .bind(key_id)
+            .bind(Utc::now())
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to save key of organization ID {}", organization_id))?;
+        let rewrapped = self.rewrap(&key).await?;
+        info!("Organization ID {} now encrypts with key version {}; rewrapped {} data keys", organization_id, key.version, rewrapped);
+        Ok(key)
+    }
+
+    /// Rewraps the organization's data keys that predate `key`, returning how many there were.
+    async fn rewrap(&self, key: &OrganizationKey) -> Result<u64> {
+        let mut rewrapped = 0;
+        loop {
+            let batch: Vec<(Uuid, String, i64, Vec<u8>)> = sqlx::query_as(
+                    "SELECT document_id, key_id, key_version, wrapped_key FROM document_keys
+                     WHERE organization_id = $1 AND key_version < $2 LIMIT $3"
+                )
+                .bind(key.organization_id)
+                .bind(key.version)
+                .bind(REWRAP_BATCH_SIZE)
+                .fetch_all(&*self.db_manager.pool)
+                .await
+                .context(format!("Failed to query data keys of organization ID {}", key.organization_id))?;
+            if batch.is_empty() {
+                return Ok(rewrapped);
+            }
+            for (doc_id, old_key_id, old_version, wrapped) in batch {
+                let data_key = self.key_manager.unwrap(&old_key_id, &wrapped).await
+                    .context(format!("Failed to unwrap data key of document ID {}", doc_id))?;
+                let wrapped = self.key_manager.wrap(&key.key_id, &data_key).await
+                    .context(format!("Failed to rewrap data key of document ID {}", doc_id))?;
+                // Skipped if a concurrent rotation got there first.
+                sqlx::query(
+                        "UPDATE document_keys SET key_id = $1, key_version = $2, wrapped_key = $3
+                         WHERE document_id = $4 AND key_version = $5"
+                    )
+                    .bind(&key.key_id)
+                    .bind(key.version)
+                    .bind(&wrapped)
+                    .bind(doc_id)
+                    .bind(old_version)
+                    .execute(&*self.db_manager.pool)
+                    .await
+                    .context(format!("Failed to rewrap data key of document ID {}", doc_id))?;
+                rewrapped += 1;
+            }
+        }
+    }
+
+    /// Makes the organization's encrypted content unreadable for good by deleting its data
+    /// keys. Its documents take no new content until it registers a key again. `None` if the
+    /// organization has no key in use, otherwise the documents whose data keys were deleted.
+    #[instrument(skip_all, fields(organization_id = %organization_id))]
+    pub async fn revoke(&self, organization_id: Uuid) -> Result<Option<Vec<Uuid>>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let revoked = sqlx::query("UPDATE organization_keys SET revoked_at = $1 WHERE organization_id = $2 AND revoked_at IS NULL")
+            .bind(Utc::now())
+            .bind(organization_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to revoke key of organization ID {}", organization_id))?;
+        if revoked.rows_affected() == 0 {
+            return Ok(None);
+        }
+        let unreadable: Vec<Uuid> = sqlx::query_scalar("DELETE FROM document_keys WHERE organization_id = $1 RETURNING document_id")
+            .bind(organization_id)
+            .fetch_all(&mut *tx)
+            .await
+            .context(format!("Failed to delete data keys of organization ID {}", organization_id))?;
+        // What was derived from the content would otherwise outlive it. The search index
+        // keeps its record of the documents as indexed, so it does not try them again.
+        sqlx::query(&format!("UPDATE documents_content SET preview_html = NULL WHERE document_id IN ({ORGANIZATION_DOCUMENTS})"))
+            .bind(organization_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to clear previews of organization ID {}", organization_id))?;
+        sqlx::query(&format!("DELETE FROM document_search_terms WHERE document_id IN ({ORGANIZATION_DOCUMENTS})"))
+            .bind(organization_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to clear search terms of organization ID {}", organization_id))?;
+        tx.commit().await.context(format!("Failed to commit revocation of organization ID {}", organization_id))?;
+        self.data_keys.lock().expect("data key cache poisoned").clear();
+        info!("Revoked key of organization ID {}; deleted {} data keys", organization_id, unreadable.len());
+        Ok(Some(unreadable))
+    }
+
+    /// `content` as it should be stored for the document: sealed if it belongs to an
+    /// organization with a key, as it is otherwise.
+    pub(crate) async fn seal(&self, doc_id: Uuid, content: Vec<u8>) -> Result<Vec<u8>> {
+        let key: Option<OrganizationKey> = sqlx::query_as(
+                "SELECT k.organization_id, k.key_id, k.version, k.created_at, k.rotated_at, k.revoked_at
+                 FROM documents_metadata m
+                 JOIN workspaces w ON w.id = m.workspace_id
+                 JOIN organization_keys k ON k.organization_id = w.organization_id
+                 WHERE m.id = $1"
+            )
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query encryption key of document ID {}", doc_id))?;
+        let Some(key) = key else {
+            return Ok(content);
+        };
+        if key.revoked_at.is_some() {
+            return Err(EncryptionError::KeyRevoked.into());
+        }
+        let data_key = match self.data_key(doc_id).await? {
+            Some(data_key) => data_key,
+            None => self.create_data_key(doc_id, &key).await?,
+        };
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| anyhow!("Failed to generate a nonce"))?;
+        let mut sealed = content;
+        aead_key(&data_key)
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(doc_id.as_bytes()), &mut sealed)
+            .map_err(|_| anyhow!("Failed to encrypt content of document ID {}", doc_id))?;
+        Ok([SEALED_PREFIX, &nonce, &sealed].concat())
+    }
+
+    /// The content `stored` for the document, unsealed if it was sealed.
+    pub(crate) async fn open(&self, doc_id: Uuid, stored: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(sealed) = stored.strip_prefix(SEALED_PREFIX) else {
+            return Ok(stored);
+        };
+        let data_key = self.data_key(doc_id).await?.ok_or(EncryptionError::Unreadable)?;
+        let (nonce, ciphertext) = sealed.split_at_checked(NONCE_LEN)
+            .context(format!("Sealed content of document ID {} is truncated", doc_id))?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce).expect("nonce has the right length");
+        let mut content = ciphertext.to_vec();
+        let len = aead_key(&data_key)
+            .open_in_place(nonce, Aad::from(doc_id.as_bytes()), &mut content)
+            .map_err(|_| anyhow!("Failed to decrypt content of document ID {}", doc_id))?
+            .len();
+        content.truncate(len);
+        Ok(content)
+    }
+
+    /// The document's data key, if it has one.
+    async fn data_key(&self, doc_id: Uuid) -> Result<Option<[u8; DATA_KEY_BYTES]>> {
+        if let Some((unwrapped_at, data_key)) = self.data_keys.lock().expect("data key cache poisoned").get(&doc_id)
+            && unwrapped_at.elapsed() < DATA_KEY_CACHE_TIME
+        {
+            return Ok(Some(*data_key));
+        }
+        let row: Option<(String, Vec<u8>)> = sqlx::query_as("SELECT key_id, wrapped_key FROM document_keys WHERE document_id = $1")
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query data key of document ID {}", doc_id))?;
+        let Some((key_id, wrapped)) = row else {
+            return Ok(None);
+        };
+        let data_key: [u8; DATA_KEY_BYTES] = self.key_manager.unwrap(&key_id, &wrapped).await
+            .context(format!("Failed to unwrap data key of document ID {}", doc_id))?
+            .try_into()
+            .map_err(|_| anyhow!("KMS unwrapped a data key of the wrong length for document ID {}", doc_id))?;
+        self.data_keys.lock().expect("data key cache poisoned").insert(doc_id, (Instant::now(), data_key));
+        Ok(Some(data_key))
+    }
+
+    /// Gives the document a data key wrapped with `key`, or takes the one a concurrent
+    /// writer gave it first.
+    async fn create_data_key(&self, doc_id: Uuid, key: &OrganizationKey) -> Result<[u8; DATA_KEY_BYTES]> {
+        let data_key = self.generate_data_key()?;
+        let wrapped = self.key_manager.wrap(&key.key_id, &data_key).await
+            .context(format!("Failed to wrap data key of document ID {}", doc_id))?;
+        let created = sqlx::query(
+                "INSERT INTO document_keys (document_id, organization_id, key_id, key_version, wrapped_key)
+                 VALUES ($1, $2, $3, $4, $5) ON CONFLICT (document_id) DO NOTHING"
+            )
+            .bind(doc_id)
+            .bind(key.organization_id)
+            .bind(&key.key_id)
+            .bind(key.version)
+            .bind(&wrapped)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to save data key of document ID {}", doc_id))?;
+        if created.rows_affected() == 0 {
+            return self.data_key(doc_id).await?.context(format!("Data key of document ID {} vanished", doc_id));
+        }
+        self.data_keys.lock().expect("data key cache poisoned").insert(doc_id, (Instant::now(), data_key));
+        Ok(data_key)
+    }
+
+    fn generate_data_key(&self) -> Result<[u8; DATA_KEY_BYTES]> {
+        let mut data_key = [0; DATA_KEY_BYTES];
+        self.rng.fill(&mut data_key).map_err(|_| anyhow!("Failed to generate a data key"))?;
+        Ok(data_key)
+    }
+}
+
+/// Whether `stored` was sealed, and so needs a data key to read.
+pub(crate) fn is_sealed(stored: &[u8]) -> bool {
+    stored.starts_with(SEALED_PREFIX)
+}
+
+fn aead_key(data_key: &[u8; DATA_KEY_BYTES]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, data_key).expect("data keys are AES-256 keys"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_service::DocumentService;
+    use crate::organizations::OrganizationService;
+    use crate::permissions::PermissionService;
+    use crate::user_service::UserService;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    /// Wraps by prefixing the key ID, and refuses keys whose ID starts with `bad`.
+    struct TestKms;
+
+    #[async_trait]
+    impl KeyManager for TestKms {
+        async fn wrap(&self, key_id: &str, data_key: &[u8]) -> Result<Vec<u8>> {
+            if key_id.starts_with("bad") {
+                return Err(anyhow!("no such key"));
+            }
+            Ok([key_id.as_bytes(), b":", data_key].concat())
+        }
+
+        async fn unwrap(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+            wrapped.strip_prefix(format!("{}:", key_id).as_bytes()).map(<[u8]>::to_vec).context("wrapped with another key")
+        }
+    }
+
+    async fn stored_snapshot(manager: &Manager, doc_id: Uuid) -> Result<Vec<u8>> {
+        Ok(sqlx::query_scalar("SELECT crdt_data FROM documents_content WHERE document_id = $1")
+            .bind(doc_id)
+            .fetch_one(&*manager.pool)
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn test_organization_keys_seal_rotate_and_revoke() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let users = UserService::new(manager.clone()).await?;
+        let permissions = PermissionService::new(manager.clone()).await?;
+        let organizations = OrganizationService::new(manager.clone()).await?;
+        let encryption = Arc::new(Encryption::new(manager.clone(), Arc::new(TestKms)).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?.with_encryption(encryption.clone());
+        let founder = users.register_user("Founder", &format!("founder-{}@example.com", Uuid::new_v4()), "correct horse battery").await?;
+        let organization = organizations.create_organization("Enterprise", founder.id).await?;
+        let workspace = permissions.create_workspace_in("Legal", founder.id, Some(organization.id)).await?;
+        let doc = doc_service.create_document_in("Contract", Some(founder.id), Some(workspace.id)).await?;
+        let personal = doc_service.create_document_in("Diary", Some(founder.id), None).await?;
+
+        // Until the organization registers a key, content is stored as it is.
+        doc_service.update_document_content(doc.id, b"draft".to_vec()).await?;
+        assert_eq!(stored_snapshot(&manager, doc.id).await?, b"draft");
+        let refused = encryption.set_key(organization.id, "bad-key").await.unwrap_err();
+        assert_eq!(refused.downcast_ref(), Some(&EncryptionError::KeyUnusable));
+        assert_eq!(encryption.organization_key(organization.id).await?, None);
+
+        let key = encryption.set_key(organization.id, "key-1").await?;
+        assert_eq!((key.key_id.as_str(), key.version), ("key-1", 1));
+        doc_service.update_document_content(doc.id, b"signed contract".to_vec()).await?;
+        doc_service.append_document_update(doc.id, b"signed contract", b"signed contract, v2".to_vec(), None).await?;
+        doc_service.create_version(doc.id, None).await?;
+        doc_service.update_document_content(personal.id, b"dear diary".to_vec()).await?;
+        let stored = stored_snapshot(&manager, doc.id).await?;
+        assert!(is_sealed(&stored) && !stored.windows(6).any(|window| window == b"signed"));
+        assert_eq!(stored_snapshot(&manager, personal.id).await?, b"dear diary");
+        assert_eq!(doc_service.get_document_content(doc.id).await?.unwrap().crdt_data, b"signed contract, v2");
+        assert_eq!(doc_service.get_version_content(doc.id, 1).await?.unwrap(), b"signed contract, v2");
+
+        // Sizes are those of the content, not of what sealing makes of it.
+        let content_len = b"signed contract, v2".len();
+        assert_eq!(doc_service.list_versions(doc.id).await?[0].size, content_len as i64);
+        assert_eq!(doc_service.blame(doc.id).await?.unwrap().len(), content_len);
+        let used_bytes = doc_service.storage_usage(founder.id).await?.used_bytes;
+        assert!(doc_service.compact_document(doc.id).await?);
+        assert_eq!(doc_service.storage_usage(founder.id).await?.used_bytes, used_bytes);
+
+        // Rotating rewraps the data key; a server without it cached unwraps it with the new key.
+        let key = encryption.set_key(organization.id, "key-2").await?;
+        assert_eq!((key.key_id.as_str(), key.version, key.rotated_at.is_some()), ("key-2", 2, true));
+        let rewrapped: String = sqlx::query_scalar("SELECT key_id FROM document_keys WHERE document_id = $1")
+            .bind(doc.id)
+            .fetch_one(&*manager.pool)
+            .await?;
+        assert_eq!(rewrapped, "key-2");
+        let other_server = Encryption::new(manager.clone(), Arc::new(TestKms)).await?;
+        assert_eq!(other_server.open(doc.id, stored).await?, b"signed contract");
+
+        // Revoking leaves the organization's content unreadable and unwritable.
+        assert_eq!(encryption.revoke(organization.id).await?, Some(vec![doc.id]));
+        assert_eq!(encryption.revoke(organization.id).await?, None);
+        let unreadable = doc_service.get_document_content(doc.id).await.unwrap_err();
+        assert_eq!(unreadable.downcast_ref(), Some(&EncryptionError::Unreadable));
+        let unwritable = doc_service.update_document_content(doc.id, b"amended".to_vec()).await.unwrap_err();
+        assert_eq!(unwritable.downcast_ref(), Some(&EncryptionError::KeyRevoked));
+        assert_eq!(doc_service.get_document_content(personal.id).await?.unwrap().crdt_data, b"dear diary");
+
+        // A new key takes new content; what was sealed before stays unreadable.
+        assert_eq!(encryption.set_key(organization.id, "key-3").await?.version, 3);
+        assert!(doc_service.get_version_content(doc.id, 1).await.is_err());
+        doc_service.update_document_content(doc.id, b"fresh start".to_vec()).await?;
+        assert_eq!(doc_service.get_document_content(doc.id).await?.unwrap().crdt_data, b"fresh start");
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+//! In-process notifications of document changes, for work that should follow a save
+//! without slowing it down. Publishing never blocks; a consumer that falls behind misses
+//! events and must catch up from the database, so consumers keep their own progress there.
+//! Every event is also logged durably; see `outbox`. What consumers outside the process
+//! receive is versioned in `schema`.
+
+pub mod schema;
+
+use schema::EventSchema;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+const EVENT_BUS_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DocumentEvent {
+    /// New content was saved.
+    Changed { doc_id: Uuid },
+    Deleted { doc_id: Uuid },
+    /// Deleting the document needs another owner's approval, which was asked for.
+    DeletionRequested { doc_id: Uuid },
+    /// Followed by `Deleted` unless the document was already in the trash.
+    DeletionApproved { doc_id: Uuid },
+    DeletionRejected { doc_id: Uuid },
+    /// Nobody decided on the deletion in time, so the document stays.
+    DeletionExpired { doc_id: Uuid },
+}
+
+impl DocumentEvent {
+    pub fn doc_id(&self) -> Uuid {
+        match *self {
+            DocumentEvent::Changed { doc_id }
+            | DocumentEvent::Deleted { doc_id }
+            | DocumentEvent::DeletionRequested { doc_id }
+            | DocumentEvent::DeletionApproved { doc_id }
+            | DocumentEvent::DeletionRejected { doc_id }
+            | DocumentEvent::DeletionExpired { doc_id } => doc_id,
+        }
+    }
+
+    pub fn schema(&self) -> &'static EventSchema {
+        match self {
+            DocumentEvent::Changed { .. } => &schema::DOCUMENT_CHANGED,
+            DocumentEvent::Deleted { .. } => &schema::DOCUMENT_DELETED,
+            DocumentEvent::DeletionRequested { .. } => &schema::DOCUMENT_DELETION_REQUESTED,
+            DocumentEvent::DeletionApproved { .. } => &schema::DOCUMENT_DELETION_APPROVED,
+            DocumentEvent::DeletionRejected { .. } => &schema::DOCUMENT_DELETION_REJECTED,
+            DocumentEvent::DeletionExpired { .. } => &schema::DOCUMENT_DELETION_EXPIRED,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<DocumentEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus { sender: broadcast::channel(EVENT_BUS_CAPACITY).0 }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: DocumentEvent) {
+        // Nobody listening is fine: consumers catch up when they start.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DocumentEvent> {
+        self.sender.subscribe()
+    }
+}
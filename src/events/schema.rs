@@ -0,0 +1,190 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+//! The events delivered outside the process, through webhooks and the replayable document
+//! event log, and the version of each one's payload.
+//!
+//! Every payload carries its `schema_version`. Adding a field is compatible and keeps the
+//! version; removing, renaming or retyping one, or changing what a value means, is not
+//! and takes a new version. Consumers should ignore fields they do not know. The tests
+//! pin the shape of each registered version, so changing a payload without bumping its
+//! version fails them.
+
+use crate::attachments::Attachment;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// A kind of event and the current version of its payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+pub struct EventSchema {
+    /// Dotted event name, e.g. `attachment.infected`.
+    pub name: &'static str,
+    pub version: u32,
+}
+
+pub const DOCUMENT_CHANGED: EventSchema = EventSchema { name: "document.changed", version: 1 };
+pub const DOCUMENT_DELETED: EventSchema = EventSchema { name: "document.deleted", version: 1 };
+pub const DOCUMENT_DELETION_REQUESTED: EventSchema = EventSchema { name: "document.deletion_requested", version: 1 };
+pub const DOCUMENT_DELETION_APPROVED: EventSchema = EventSchema { name: "document.deletion_approved", version: 1 };
+pub const DOCUMENT_DELETION_REJECTED: EventSchema = EventSchema { name: "document.deletion_rejected", version: 1 };
+pub const DOCUMENT_DELETION_EXPIRED: EventSchema = EventSchema { name: "document.deletion_expired", version: 1 };
+pub const ATTACHMENT_INFECTED: EventSchema = EventSchema { name: "attachment.infected", version: 1 };
+pub const RESOURCE_THRESHOLD_CROSSED: EventSchema = EventSchema { name: "resource.threshold_crossed", version: 1 };
+pub const RESOURCE_THRESHOLD_CLEARED: EventSchema = EventSchema { name: "resource.threshold_cleared", version: 1 };
+
+/// Every event emitted, for consumers to discover and for checking names are unique.
+pub const REGISTRY: &[EventSchema] = &[
+    DOCUMENT_CHANGED,
+    DOCUMENT_DELETED,
+    DOCUMENT_DELETION_REQUESTED,
+    DOCUMENT_DELETION_APPROVED,
+    DOCUMENT_DELETION_REJECTED,
+    DOCUMENT_DELETION_EXPIRED,
+    ATTACHMENT_INFECTED,
+    RESOURCE_THRESHOLD_CROSSED,
+    RESOURCE_THRESHOLD_CLEARED,
+];
+
+/// The registered event called `name`.
+pub fn lookup(name: &str) -> Option<&'static EventSchema> {
+    REGISTRY.iter().find(|schema| schema.name == name)
+}
+
+/// How a webhook delivers an event; `data` is the event's payload.
+#[derive(Serialize)]
+pub struct WebhookEnvelope<'a, T> {
+    pub event: &'a str,
+    pub schema_version: u32,
+    pub occurred_at: DateTime<Utc>,
+    pub data: T,
+}
+
+impl<'a, T> WebhookEnvelope<'a, T> {
+    pub fn new(schema: &'a EventSchema, data: T) -> Self {
+        WebhookEnvelope { event: schema.name, schema_version: schema.version, occurred_at: Utc::now(), data }
+    }
+}
+
+/// The payload of `attachment.infected`.
+#[derive(Serialize)]
+pub struct AttachmentInfected<'a> {
+    pub attachment: &'a Attachment,
+    /// Which scanner found it.
+    pub scanner: &'a str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::{Alert, Resource};
+    use crate::attachments::ScanStatus;
+    use crate::events::DocumentEvent;
+    use crate::outbox::LoggedEvent;
+    use serde_json::{json, Value};
+    use std::collections::HashSet;
+    use uuid::Uuid;
+
+    fn at() -> DateTime<Utc> {
+        "2025-01-02T03:04:05Z".parse().unwrap()
+    }
+
+    fn envelope(schema: &EventSchema, data: impl Serialize) -> Value {
+        let mut envelope = WebhookEnvelope::new(schema, data);
+        envelope.occurred_at = at();
+        serde_json::to_value(envelope).unwrap()
+    }
+
+    // The pinned shape of each registered version. A change that fails here either needs a
+    // new version, with its shape added alongside the old, or is a compatible addition.
+    fn fixtures() -> Vec<(EventSchema, Value, Value)> {
+        let doc_id = Uuid::nil();
+        let logged = |event: DocumentEvent| {
+            serde_json::to_value(LoggedEvent { seq: 7, schema_version: event.schema().version, event, occurred_at: at() }).unwrap()
+        };
+        let document = |kind: &str| json!({ "seq": 7, "schema_version": 1, "type": kind, "doc_id": doc_id, "occurred_at": "2025-01-02T03:04:05Z" });
+        let attachment = Attachment {
+            id: doc_id,
+            document_id: doc_id,
+            file_name: "invoice.pdf".to_string(),
+            content_type: "application/pdf".to_string(),
+            size: 1024,
+            scan_status: ScanStatus::Infected,
+            scan_signature: Some("Eicar-Test-Signature".to_string()),
+            uploaded_by: None,
+            created_at: at(),
+            scanned_at: Some(at()),
+        };
+        let alert = |firing| Alert { resource: Resource::Storage, usage: 90, threshold: 100, firing };
+        let webhook = |event: &str, data: Value| json!({ "event": event, "schema_version": 1, "occurred_at": "2025-01-02T03:04:05Z", "data": data });
+        let alert_data = |firing| json!({ "resource": "storage", "usage": 90, "threshold": 100, "firing": firing });
+        vec![
+            (DOCUMENT_CHANGED, logged(DocumentEvent::Changed { doc_id }), document("changed")),
+            (DOCUMENT_DELETED, logged(DocumentEvent::Deleted { doc_id }), document("deleted")),
+            (DOCUMENT_DELETION_REQUESTED, logged(DocumentEvent::DeletionRequested { doc_id }), document("deletion_requested")),
+            (DOCUMENT_DELETION_APPROVED, logged(DocumentEvent::DeletionApproved { doc_id }), document("deletion_approved")),
+            (DOCUMENT_DELETION_REJECTED, logged(DocumentEvent::DeletionRejected { doc_id }), document("deletion_rejected")),
+            (DOCUMENT_DELETION_EXPIRED, logged(DocumentEvent::DeletionExpired { doc_id }), document("deletion_expired")),
+            (
+                ATTACHMENT_INFECTED,
+                envelope(&ATTACHMENT_INFECTED, AttachmentInfected { attachment: &attachment, scanner: "clamav" }),
+                webhook("attachment.infected", json!({
+                    "attachment": {
+                        "id": doc_id,
+                        "document_id": doc_id,
+                        "file_name": "invoice.pdf",
+                        "content_type": "application/pdf",
+                        "size": 1024,
+                        "scan_status": "infected",
+                        "scan_signature": "Eicar-Test-Signature",
+                        "uploaded_by": null,
+                        "created_at": "2025-01-02T03:04:05Z",
+                        "scanned_at": "2025-01-02T03:04:05Z",
+                    },
+                    "scanner": "clamav",
+                })),
+            ),
+            (
+                RESOURCE_THRESHOLD_CROSSED,
+                envelope(alert(true).schema(), alert(true)),
+                webhook("resource.threshold_crossed", alert_data(true)),
+            ),
+            (
+                RESOURCE_THRESHOLD_CLEARED,
+                envelope(alert(false).schema(), alert(false)),
+                webhook("resource.threshold_cleared", alert_data(false)),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_payloads_match_their_registered_versions() {
+        let fixtures = fixtures();
+        for (schema, actual, expected) in &fixtures {
+            assert_eq!(actual, expected, "{} v{} changed shape", schema.name, schema.version);
+        }
+        let pinned: HashSet<EventSchema> = fixtures.iter().map(|(schema, _, _)| *schema).collect();
+        let registered: HashSet<EventSchema> = REGISTRY.iter().copied().collect();
+        assert_eq!(pinned, registered, "every registered version needs a pinned payload");
+    }
+
+    #[test]
+    fn test_registry() {
+        let names: HashSet<&str> = REGISTRY.iter().map(|schema| schema.name).collect();
+        assert_eq!(names.len(), REGISTRY.len());
+        assert!(REGISTRY.iter().all(|schema| schema.version >= 1));
+        assert_eq!(lookup("attachment.infected"), Some(&ATTACHMENT_INFECTED));
+        assert_eq!(lookup("attachment.quarantined"), None);
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Documents as standalone HTML files for download. Content is treated as UTF-8 text
+//! until it is decoded as a CRDT: blank lines separate paragraphs and lines starting
+//! with `#` are headings, one level per `#`.
+//!
+//! The accessible profile (`?profile=a11y`) is meant for screen readers: the page
+//! carries the document's language, content sits in a `main` landmark under a single
+//! `h1`, heading levels never skip, and images are listed with alt text, or with a
+//! prompt to add a description where the attachment has none.
+
+use serde::Deserialize;
+
+use crate::attachments::Attachment;
+use crate::document_service::DocumentMetadata;
+use crate::i18n::{self, Locale};
+use crate::preview::escape_html;
+
+/// How an export is laid out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum ExportProfile {
+    /// The content as written, with attachments linked by name.
+    #[default]
+    #[serde(rename = "standard")]
+    Standard,
+    /// Semantically structured HTML for assistive technology.
+    #[serde(rename = "a11y")]
+    Accessible,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Block<'a> {
+    Heading(usize, &'a str),
+    Paragraph(Vec<&'a str>),
+}
+
+fn blocks(text: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    let mut paragraph = Vec::new();
+    for line in text.lines().map(str::trim) {
+        let level = line.chars().take_while(|c| *c == '#').count();
+        let heading = line[level..].strip_prefix(' ').filter(|_| (1..=6).contains(&level));
+        if line.is_empty() || heading.is_some() {
+            if !paragraph.is_empty() {
+                blocks.push(Block::Paragraph(std::mem::take(&mut paragraph)));
+            }
+        } else {
+            paragraph.push(line);
+        }
+        if let Some(heading) = heading {
+            blocks.push(Block::Heading(level, heading.trim()));
+        }
+    }
+    if !paragraph.is_empty() {
+        blocks.push(Block::Paragraph(paragraph));
+    }
+    blocks
+}
+
+/// Renders a document as a complete HTML page. Only attachments that may be downloaded
+/// are included. Returns `None` when the content is not text.
+pub fn render(
+    metadata: &DocumentMetadata,
+    content: &[u8],
+    attachments: &[Attachment],
+    profile: ExportProfile,
+    locale: Locale,
+) -> Option<String> {
+    let text = std::str::from_utf8(content).ok()?;
+    let accessible = profile == ExportProfile::Accessible;
+    let title = escape_html(&metadata.name);
+    let mut html = String::from("<!DOCTYPE html>\n<html");
+    if accessible && let Some(language) = &metadata.language {
+        html.push_str(&format!(" lang=\"{}\"", escape_html(language)));
+    }
+    html.push_str(&format!(">\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n", title));
+    if accessible {
+        html.push_str("<main>\n");
+    }
+    html.push_str(&format!("<h1>{}</h1>\n", title));
+    // Written levels of the headings enclosing the current one; in the accessible profile
+    // a heading's level is its depth below the title, so levels never skip.
+    let mut outline: Vec<usize> = Vec::new();
+    for block in blocks(text) {
+        match block {
+            Block::Heading(written, heading) => {
+                let level = if accessible {
+                    while outline.last().is_some_and(|enclosing| *enclosing >= written) {
+                        outline.pop();
+                    }
+                    outline.push(written);
+                    (outline.len() + 1).min(6)
+                } else {
+                    written
+                };
+                html.push_str(&format!("<h{level}>{}</h{level}>\n", escape_html(heading)));
+            }
+            Block::Paragraph(lines) => {
+                let lines: Vec<String> = lines.into_iter().map(escape_html).collect();
+                html.push_str(&format!("<p>{}</p>\n", lines.join("<br>\n")));
+            }
+        }
+    }
+    let attachments: Vec<&Attachment> = attachments.iter().filter(|a| a.scan_status.is_downloadable()).collect();
+    if !attachments.is_empty() {
+        let heading = escape_html(&i18n::message(locale, "export-attachments", &[]));
+        if accessible {
+            html.push_str(&format!("<section aria-labelledby=\"attachments\">\n<h2 id=\"attachments\">{}</h2>\n", heading));
+        } else {
+            html.push_str(&format!("<h2>{}</h2>\n", heading));
+        }
+        html.push_str("<ul>\n");
+        for attachment in attachments {
+            let href = format!("/api/attachments/{}/content", attachment.id);
+            let file_name = escape_html(&attachment.file_name);
+            if accessible && attachment.content_type.starts_with("image/") {
+                // Attachments have no description of their own yet, so the alt text says
+                // so rather than leaving screen readers to read out the file name.
+                let alt = i18n::message(locale, "export-image-undescribed", &[("file", attachment.file_name.clone())]);
+                html.push_str(&format!(
+                    "<li><figure><img src=\"{}\" alt=\"{}\"><figcaption><a href=\"{}\">{}</a></figcaption></figure></li>\n",
+                    href, escape_html(&alt), href, file_name,
+                ));
+            } else if accessible {
+                html.push_str(&format!(
+                    "<li><a href=\"{}\">{} <span>({})</span></a></li>\n",
+                    href, file_name, escape_html(&attachment.content_type),
+                ));
+            } else {
+                html.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", href, file_name));
+            }
+        }
+        html.push_str("</ul>\n");
+        if accessible {
+            html.push_str("</section>\n");
+        }
+    }
+    if accessible {
+        html.push_str("</main>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    Some(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachments::ScanStatus;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    fn metadata() -> DocumentMetadata {
+        DocumentMetadata {
+            id: Uuid::new_v4(),
+            name: "Plan & budget".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            language: Some("en".to_string()),
+        }
+    }
+
+    fn attachment(file_name: &str, content_type: &str, scan_status: ScanStatus) -> Attachment {
+        Attachment {
+            id: Uuid::new_v4(),
+            document_id: Uuid::new_v4(),
+            file_name: file_name.to_string(),
+            content_type: content_type.to_string(),
+            size: 1,
+            scan_status,
+            scan_signature: None,
+            uploaded_by: None,
+            created_at: Utc::now(),
+            scanned_at: None,
+        }
+    }
+
+    #[test]
+    fn test_blocks() {
+        assert_eq!(
+            blocks("# Title\nfirst\nsecond\n\n#hashtag\n### Deep"),
+            vec![
+                Block::Heading(1, "Title"),
+                Block::Paragraph(vec!["first", "second"]),
+                Block::Paragraph(vec!["#hashtag"]),
+                Block::Heading(3, "Deep"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_accessible_export() {
+        let content = "# Goals\nShip <it>\n### Detail\n## Risks";
+        let attachments = [
+            attachment("chart.png", "image/png", ScanStatus::Clean),
+            attachment("notes.pdf", "application/pdf", ScanStatus::Unscanned),
+            attachment("bad.exe", "application/octet-stream", ScanStatus::Infected),
+        ];
+        let html = render(&metadata(), content.as_bytes(), &attachments, ExportProfile::Accessible, Locale::FALLBACK).unwrap();
+        assert!(html.contains("<html lang=\"en\">"));
+        assert!(html.contains("<main>\n<h1>Plan &amp; budget</h1>\n<h2>Goals</h2>\n<p>Ship &lt;it&gt;</p>\n<h3>Detail</h3>\n<h3>Risks</h3>"));
+        assert!(html.contains("alt=\"chart.png (image, no description yet)\""));
+        assert!(html.contains("notes.pdf <span>(application/pdf)</span>"));
+        assert!(!html.contains("bad.exe"));
+
+        let html = render(&metadata(), content.as_bytes(), &attachments, ExportProfile::Standard, Locale::FALLBACK).unwrap();
+        assert!(html.contains("<html>"));
+        assert!(html.contains("<h1>Goals</h1>\n<p>Ship &lt;it&gt;</p>\n<h3>Detail</h3>"));
+        assert!(!html.contains("<img"));
+    }
+}
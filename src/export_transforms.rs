@@ -0,0 +1,437 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Transformations a workspace applies to exports of its documents, such as adding a
+//! letterhead or redacting account numbers. None of them runs code on this server:
+//! redactions are regular expressions, matched in linear time and limited in size;
+//! letterheads are text templates; anything more is left to a webhook the page is posted
+//! to, which is only reached at public addresses and has a deadline and a size limit.
+//!
+//! Redactions apply to the document's text before it is rendered, letterheads and
+//! webhooks to the rendered page afterwards, each in the order they are listed.
+
+use crate::db::Manager;
+use crate::document_service::DocumentMetadata;
+use crate::migrations::{self, Component, Migration};
+use crate::preview::escape_html;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use regex_automata::meta::Regex;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::FromRow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, instrument};
+use url::Url;
+use uuid::Uuid;
+
+pub const MAX_TRANSFORMS: usize = 10;
+pub const MAX_PATTERN_LENGTH: usize = 1000;
+pub const MAX_TEMPLATE_LENGTH: usize = 2000;
+/// Compiled size of a redaction pattern, which keeps matching cheap.
+const PATTERN_SIZE_LIMIT: usize = 1 << 20;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_WEBHOOK_RESPONSE_BYTES: usize = 10 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Transform {
+    /// Replaces every match of `pattern` in the text with `replacement`, taken literally.
+    Redact { pattern: String, replacement: String },
+    /// Lines of text above and below the content, in which `{{title}}`, `{{workspace}}`
+    /// and `{{date}}` are filled in.
+    Letterhead { header: String, footer: String },
+    /// Posts `{"document_id": ..., "name": ..., "html": ...}` to an HTTPS URL and expects
+    /// `{"html": ...}` back.
+    Webhook { url: String },
+}
+
+impl Transform {
+    /// The i18n key of what is wrong with the transformation, if anything.
+    pub fn problem(&self) -> Option<&'static str> {
+        match self {
+            Transform::Redact { pattern, replacement } => {
+                if pattern.chars().count() > MAX_PATTERN_LENGTH || replacement.chars().count() > MAX_TEMPLATE_LENGTH {
+                    Some("export-transform-too-long")
+                } else if compile(pattern).is_err() {
+                    Some("invalid-redaction-pattern")
+                } else {
+                    None
+                }
+            }
+            Transform::Letterhead { header, footer } => {
+                (header.chars().count() > MAX_TEMPLATE_LENGTH || footer.chars().count() > MAX_TEMPLATE_LENGTH)
+                    .then_some("export-transform-too-long")
+            }
+            Transform::Webhook { url } => {
+                let valid = Url::parse(url).is_ok_and(|url| url.scheme() == "https" && url.host().is_some());
+                (!valid).then_some("invalid-export-webhook-url")
+            }
+        }
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+    Regex::builder()
+        .configure(Regex::config().nfa_size_limit(Some(PATTERN_SIZE_LIMIT)))
+        .build(pattern)
+        .context("Invalid redaction pattern")
+}
+
+/// A workspace's export transformations.
+#[derive(Clone, Debug, FromRow, PartialEq, Serialize)]
+pub struct WorkspaceTransforms {
+    pub workspace_id: Uuid,
+    #[sqlx(json)]
+    pub transforms: Vec<Transform>,
+    pub updated_by: Option<Uuid>,
+    pub updated_at: DateTime<Utc>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "export_transforms",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS export_transforms (
+                workspace_id UUID PRIMARY KEY REFERENCES workspaces(id) ON DELETE CASCADE,
+                transforms JSONB NOT NULL,
+                updated_by UUID,
+                updated_at TIMESTAMPTZ NOT NULL
+            )",
+        ],
+        down: &["DROP TABLE IF EXISTS export_transforms"],
+    }],
+};
+
+pub struct ExportTransforms {
+    db_manager: Arc<Manager>,
+}
+
+impl ExportTransforms {
+    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
+        migrations::apply(&db_manager.pool, &SCHEMA).await?;
+        info!("Export transforms schema initialized.");
+        Ok(ExportTransforms { db_manager })
+    }
+
+    #[instrument(skip_all, fields(workspace_id = %workspace_id))]
+    pub async fn for_workspace(&self, workspace_id: Uuid) -> Result<Option<WorkspaceTransforms>> {
+        sqlx::query_as("SELECT workspace_id, transforms, updated_by, updated_at FROM export_transforms WHERE workspace_id = $1")
+            .bind(workspace_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query export transforms of workspace ID {}", workspace_id))
+    }
+
+    /// Replaces the workspace's transformations. Check each with `Transform::problem` first.
+    #[instrument(skip_all, fields(workspace_id = %workspace_id))]
+    pub async fn set(&self, workspace_id: Uuid, transforms: &[Transform], updated_by: Uuid) -> Result<WorkspaceTransforms> {
+        sqlx::query_as(
+                "INSERT INTO export_transforms (workspace_id, transforms, updated_by, updated_at) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (workspace_id) DO UPDATE
+                 SET transforms = EXCLUDED.transforms, updated_by = EXCLUDED.updated_by, updated_at = EXCLUDED.updated_at
+                 RETURNING workspace_id, transforms, updated_by, updated_at"
+            )
+            .bind(workspace_id)
+            .bind(Json(transforms))
+            .bind(updated_by)
+            .bind(Utc::now())
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to save export transforms of workspace ID {}", workspace_id))
+    }
+
+    /// Returns false if the workspace had none.
+    #[instrument(skip_all, fields(workspace_id = %workspace_id))]
+    pub async fn clear(&self, workspace_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM export_transforms WHERE workspace_id = $1")
+            .bind(workspace_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to clear export transforms of workspace ID {}", workspace_id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// What applies to exports of the document; `None` if its workspace has nothing set,
+    /// or it is in no workspace.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn for_document(&self, doc_id: Uuid) -> Result<Option<ExportPipeline>> {
+        let row: Option<(String, Json<Vec<Transform>>)> = sqlx::query_as(
+                "SELECT w.name, t.transforms FROM documents_metadata m
+                 JOIN workspaces w ON w.id = m.workspace_id
+                 JOIN export_transforms t ON t.workspace_id = w.id
+                 WHERE m.id = $1"
+            )
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query export transforms of document ID {}", doc_id))?;
+        Ok(row.map(|(workspace, Json(transforms))| ExportPipeline { workspace, transforms }))
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookPage<'a> {
+    document_id: Uuid,
+    name: &'a str,
+    html: &'a str,
+}
+
+#[derive(Deserialize)]
+struct WebhookReply {
+    html: String,
+}
+
+/// The transformations that apply to a document's export, and its workspace's name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportPipeline {
+    pub workspace: String,
+    pub transforms: Vec<Transform>,
+}
+
+impl ExportPipeline {
+    /// Applies the redactions to text content; anything else is left for `export::render`
+    /// to turn down.
+    pub fn redact(&self, content: Vec<u8>) -> Result<Vec<u8>> {
+        let mut text = match String::from_utf8(content) {
+            Ok(text) => text,
+            Err(e) => return Ok(e.into_bytes()),
+        };
+        for transform in &self.transforms {
+            if let Transform::Redact { pattern, replacement } = transform {
+                let regex = compile(pattern)?;
+                let mut redacted = String::with_capacity(text.len());
+                let mut last = 0;
+                for found in regex.find_iter(&text) {
+                    redacted.push_str(&text[last..found.start()]);
+                    redacted.push_str(replacement);
+                    last = found.end();
+                }
+                redacted.push_str(&text[last..]);
+                text = redacted;
+            }
+        }
+        Ok(text.into_bytes())
+    }
+
+    /// Applies the letterheads and webhooks to the rendered page.
+    pub async fn finish(&self, metadata: &DocumentMetadata, mut html: String) -> Result<String> {
+        for transform in &self.transforms {
+            match transform {
+                Transform::Redact { .. } => {}
+                Transform::Letterhead { header, footer } => {
+                    if !header.is_empty() {
+                        let header = format!("<header>{}</header>\n", self.fill(header, metadata));
+                        let at = html.find("<body>\n").map_or(0, |at| at + "<body>\n".len());
+                        html.insert_str(at, &header);
+                    }
+                    if !footer.is_empty() {
+                        let footer = format!("<footer>{}</footer>\n", self.fill(footer, metadata));
+                        let at = html.rfind("</body>").unwrap_or(html.len());
+                        html.insert_str(at, &footer);
+                    }
+                }
+                Transform::Webhook { url } => {
+                    html = post(url, &WebhookPage { document_id: metadata.id, name: &metadata.name, html: &html }).await?;
+                }
+            }
+        }
+        Ok(html)
+    }
+
+    /// `template` with its placeholders filled in, as HTML lines.
+    fn fill(&self, template: &str, metadata: &DocumentMetadata) -> String {
+        let date = Utc::now().format("%Y-%m-%d").to_string();
+        template
+            .lines()
+            .map(|line| {
+                let line = line.replace("{{title}}", &metadata.name).replace("{{workspace}}", &self.workspace).replace("{{date}}", &date);
+                escape_html(&line)
+            })
+            .collect::<Vec<_>>()
+            .join("<br>\n")
+    }
+}
+
+/// Posts the page to a webhook at a public address, pinned to the address checked so a
+/// second lookup cannot point the request elsewhere, and without following redirects.
+async fn post(url: &str, page: &WebhookPage<'_>) -> Result<String> {
+    let parsed = Url::parse(url).context("Invalid export webhook URL")?;
+    let host = parsed.host_str().context("Export webhook URL has no host")?;
+    let port = parsed.port_or_known_default().context("Export webhook URL has no port")?;
+    let addresses: Vec<_> = tokio::net::lookup_host((host, port)).await
+        .context(format!("Failed to resolve export webhook host {}", host))?
+        .collect();
+    if addresses.is_empty() || !addresses.iter().all(|address| is_public(address.ip())) {
+        bail!("Export webhook host {} is not at a public address", host);
+    }
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(host, addresses[0])
+        .build()
+        .context("Failed to build export webhook HTTP client")?;
+    let mut response = client.post(url).json(page).send().await
+        .context(format!("Failed to reach export webhook at {}", url))?
+        .error_for_status()
+        .context("Export webhook returned an error")?;
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.context("Failed to read export webhook response")? {
+        if body.len() + chunk.len() > MAX_WEBHOOK_RESPONSE_BYTES {
+            bail!("Export webhook response is larger than {} bytes", MAX_WEBHOOK_RESPONSE_BYTES);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let reply: WebhookReply = serde_json::from_slice(&body).context("Export webhook returned a malformed response")?;
+    Ok(reply.html)
+}
+
+/// Whether `ip` is reachable on the internet, rather than on this host or a private network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || a == 0
+        || a >= 240
+        // Carrier-grade NAT and benchmarking ranges.
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (18..20).contains(&b)))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local, link-local and documentation ranges.
+        || first & 0xfe00 == 0xfc00
+        || first & 0xffc0 == 0xfe80
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_service::DocumentService;
+    use crate::permissions::PermissionService;
+    use crate::user_service::UserService;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    fn metadata() -> DocumentMetadata {
+        DocumentMetadata {
+            id: Uuid::new_v4(),
+            name: "Q3 <report>".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            language: None,
+        }
+    }
+
+    #[test]
+    fn test_problems() {
+        let redact = |pattern: &str| Transform::Redact { pattern: pattern.to_string(), replacement: "█".to_string() };
+        assert_eq!(redact(r"\b\d{4}-\d{4}\b").problem(), None);
+        assert_eq!(redact("(unclosed").problem(), Some("invalid-redaction-pattern"));
+        assert_eq!(redact(&"a".repeat(MAX_PATTERN_LENGTH + 1)).problem(), Some("export-transform-too-long"));
+        // Patterns that would compile to something huge are turned down rather than matched slowly.
+        assert_eq!(redact(r"\w{1000}\w{1000}").problem(), Some("invalid-redaction-pattern"));
+        let webhook = |url: &str| Transform::Webhook { url: url.to_string() };
+        assert_eq!(webhook("https://transform.example.com/letterhead").problem(), None);
+        assert_eq!(webhook("http://transform.example.com/").problem(), Some("invalid-export-webhook-url"));
+        assert_eq!(webhook("not a url").problem(), Some("invalid-export-webhook-url"));
+    }
+
+    #[tokio::test]
+    async fn test_pipeline() -> Result<()> {
+        let pipeline = ExportPipeline {
+            workspace: "Finance".to_string(),
+            transforms: vec![
+                Transform::Letterhead { header: "ACME Corp\n{{title}} ({{workspace}})".to_string(), footer: String::new() },
+                Transform::Redact { pattern: r"\d{4}-\d{4}".to_string(), replacement: "[redacted]".to_string() },
+                Transform::Letterhead { header: String::new(), footer: "Confidential".to_string() },
+            ],
+        };
+        assert_eq!(pipeline.redact(b"Card 1234-5678, PIN 42".to_vec())?, b"Card [redacted], PIN 42");
+        assert_eq!(pipeline.redact(vec![0xff, 0xfe])?, vec![0xff, 0xfe]);
+        let html = pipeline.finish(&metadata(), "<html>\n<body>\n<p>Text</p>\n</body>\n</html>\n".to_string()).await?;
+        assert_eq!(
+            html,
+            "<html>\n<body>\n<header>ACME Corp<br>\nQ3 &lt;report&gt; (Finance)</header>\n<p>Text</p>\n<footer>Confidential</footer>\n</body>\n</html>\n"
+        );
+
+        // Webhooks are refused before anything is sent to a private address.
+        let private = ExportPipeline { workspace: String::new(), transforms: vec![Transform::Webhook { url: "https://127.0.0.1/".to_string() }] };
+        assert!(private.finish(&metadata(), String::new()).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_public_addresses() {
+        for private in ["10.1.2.3", "127.0.0.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:192.168.0.1"] {
+            assert!(!is_public(private.parse().unwrap()), "{}", private);
+        }
+        for public in ["93.184.216.34", "2606:4700::1111", "::ffff:8.8.8.8"] {
+            assert!(is_public(public.parse().unwrap()), "{}", public);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transforms_follow_the_workspace() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let users = UserService::new(manager.clone()).await?;
+        let permissions = PermissionService::new(manager.clone()).await?;
+        let export_transforms = ExportTransforms::new(manager).await?;
+        let admin = users.register_user("Admin", &format!("admin-{}@example.com", Uuid::new_v4()), "correct horse battery").await?;
+        let workspace = permissions.create_workspace("Legal", admin.id).await?;
+        let doc = doc_service.create_document_in("Brief", Some(admin.id), Some(workspace.id)).await?;
+        let personal = doc_service.create_document_in("Notes", Some(admin.id), None).await?;
+        assert_eq!(export_transforms.for_document(doc.id).await?, None);
+
+        let transforms = vec![Transform::Letterhead { header: "{{workspace}}".to_string(), footer: String::new() }];
+        let saved = export_transforms.set(workspace.id, &transforms, admin.id).await?;
+        assert_eq!((saved.transforms.clone(), saved.updated_by), (transforms.clone(), Some(admin.id)));
+        assert_eq!(export_transforms.for_workspace(workspace.id).await?, Some(saved));
+        assert_eq!(
+            export_transforms.for_document(doc.id).await?,
+            Some(ExportPipeline { workspace: "Legal".to_string(), transforms })
+        );
+        assert_eq!(export_transforms.for_document(personal.id).await?, None);
+        assert!(export_transforms.clear(workspace.id).await?);
+        assert!(!export_transforms.clear(workspace.id).await?);
+        assert_eq!(export_transforms.for_document(doc.id).await?, None);
+        Ok(())
+    }
+}
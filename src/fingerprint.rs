@@ -0,0 +1,118 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Content fingerprints for spotting duplicate documents. Identical content shares a
+//! checksum; near-identical text shares most of a SimHash, a 64-bit hash in which similar
+//! texts differ in few bits.
+
+use crate::integrity;
+
+/// SimHashes at most this many bits apart are treated as near-duplicates.
+pub const NEAR_DUPLICATE_DISTANCE: u32 = 5;
+/// Words per shingle; a shingle is the unit of text being compared.
+const SHINGLE_WORDS: usize = 3;
+/// The SimHash is split into this many bands for lookup. Hashes within
+/// `NEAR_DUPLICATE_DISTANCE` bits always agree on at least one band.
+const BANDS: u32 = NEAR_DUPLICATE_DISTANCE + 1;
+const BAND_BITS: u32 = 64_u32.div_ceil(BANDS);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fingerprint {
+    pub checksum: String,
+    /// `None` when the content has no words to compare.
+    pub simhash: Option<u64>,
+}
+
+impl Fingerprint {
+    /// Fingerprints stored content. Content is treated as UTF-8 text until it is decoded as a CRDT.
+    pub fn of(content: &[u8]) -> Self {
+        Fingerprint {
+            checksum: integrity::checksum(content),
+            simhash: std::str::from_utf8(content).ok().and_then(simhash),
+        }
+    }
+
+    /// The SimHash's bands, each tagged with its position so bands only match their counterparts.
+    pub fn bands(&self) -> Option<Vec<i32>> {
+        let simhash = self.simhash?;
+        Some(
+            (0..BANDS)
+                .map(|band| {
+                    let value = (simhash >> (band * BAND_BITS)) & ((1 << BAND_BITS) - 1);
+                    (band << BAND_BITS | value as u32) as i32
+                })
+                .collect(),
+        )
+    }
+}
+
+/// How similar two SimHashes are, from 0.0 to 1.0.
+pub fn similarity(a: u64, b: u64) -> f64 {
+    1.0 - f64::from((a ^ b).count_ones()) / 64.0
+}
+
+pub fn is_near_duplicate(a: u64, b: u64) -> bool {
+    (a ^ b).count_ones() <= NEAR_DUPLICATE_DISTANCE
+}
+
+/// SimHash over word shingles, ignoring case and punctuation.
+fn simhash(text: &str) -> Option<u64> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.is_empty() {
+        return None;
+    }
+    let mut votes = [0i64; 64];
+    for shingle in words.windows(SHINGLE_WORDS.min(words.len())) {
+        let hash = blake3::hash(shingle.join(" ").as_bytes());
+        let hash = u64::from_le_bytes(hash.as_bytes()[..8].try_into().expect("blake3 hashes are 32 bytes"));
+        for (bit, vote) in votes.iter_mut().enumerate() {
+            *vote += if hash >> bit & 1 == 1 { 1 } else { -1 };
+        }
+    }
+    Some(votes.iter().enumerate().fold(0, |simhash, (bit, vote)| if *vote > 0 { simhash | 1 << bit } else { simhash }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "Quarterly planning notes. The team agreed to ship the offline editor before the \
+        end of the quarter, to move the sync service onto the new cluster, and to review every \
+        open accessibility issue in the web client before the next release goes out to customers.";
+
+    #[test]
+    fn test_fingerprints() {
+        let original = Fingerprint::of(TEXT.as_bytes());
+        let reformatted = Fingerprint::of(TEXT.to_uppercase().replace(',', "").as_bytes());
+        assert_ne!(original.checksum, reformatted.checksum);
+        assert_eq!(original.simhash, reformatted.simhash, "Case and punctuation are ignored");
+
+        let edited = Fingerprint::of(TEXT.replace("customers", "users").as_bytes());
+        let (a, b) = (original.simhash.unwrap(), edited.simhash.unwrap());
+        assert!(is_near_duplicate(a, b), "{} bits apart", (a ^ b).count_ones());
+        let shared = original.bands().unwrap().iter().filter(|band| edited.bands().unwrap().contains(band)).count();
+        assert!(shared > 0);
+
+        let unrelated = Fingerprint::of(b"Minutes of the garden committee: compost bins, new hedges and a rota for watering.");
+        assert!(!is_near_duplicate(a, unrelated.simhash.unwrap()));
+        assert!(similarity(a, unrelated.simhash.unwrap()) < similarity(a, b));
+
+        assert_eq!(Fingerprint::of(b"").simhash, None);
+        assert_eq!(Fingerprint::of(&[0xff, 0xfe]).simhash, None);
+    }
+}
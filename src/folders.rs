@@ -0,0 +1,552 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Folders that organize documents into a tree.
+//!
+//! Every folder belongs to a root: a workspace or, outside workspaces, one person's
+//! documents. So do the subfolders and documents in it, and nothing moves between roots.
+//! Wherever a folder ID is expected, the root's own ID stands for the top of its tree, as
+//! in `documents_by_name`; see `document_service::NameTaken`.
+
+use crate::db::Manager;
+use crate::document_service::{self, DocumentMetadata, DocumentService};
+use crate::events::DocumentEvent;
+use crate::migrations::{self, Component, Migration};
+use crate::outbox;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{FromRow};
+use std::sync::Arc;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+pub const MAX_FOLDER_NAME_LENGTH: usize = document_service::MAX_DOCUMENT_NAME_LENGTH;
+
+const FOLDER_COLUMNS: &str = "id, name, parent_id, owner_id, workspace_id, deletion_requires_approval, created_at, updated_at";
+
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+pub struct Folder {
+    pub id: Uuid,
+    pub name: String,
+    /// `None` at the top of the root.
+    pub parent_id: Option<Uuid>,
+    /// Set for personal folders.
+    pub owner_id: Option<Uuid>,
+    /// Set for folders in a workspace.
+    pub workspace_id: Option<Uuid>,
+    /// Documents in this folder or any inside it are only deleted once a second owner
+    /// approves; see `DocumentService::request_deletion`.
+    pub deletion_requires_approval: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// What is directly inside a folder, each sorted by name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FolderContents {
+    pub folders: Vec<Folder>,
+    pub documents: Vec<DocumentMetadata>,
+}
+
+/// Why a folder or document could not go where it was asked to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FolderRejection {
+    /// Another folder in the same place has the name, ignoring case and surrounding whitespace.
+    NameTaken,
+    /// A folder cannot move into itself or any folder inside it.
+    Cycle,
+    /// The destination belongs to a different root, or does not exist.
+    DifferentRoot,
+}
+
+impl std::fmt::Display for FolderRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FolderRejection::NameTaken => write!(f, "A folder with this name already exists here"),
+            FolderRejection::Cycle => write!(f, "A folder cannot be moved into itself"),
+            FolderRejection::DifferentRoot => write!(f, "The destination folder belongs elsewhere"),
+        }
+    }
+}
+
+impl std::error::Error for FolderRejection {}
+
+/// Maps a violation of the unique name index to `FolderRejection::NameTaken`.
+fn name_taken_or(e: sqlx::Error, context: String) -> anyhow::Error {
+    match e {
+        sqlx::Error::Database(e) if e.is_unique_violation() => FolderRejection::NameTaken.into(),
+        e => anyhow!(e).context(context),
+    }
+}
+
+pub struct FolderService {
+    db_manager: Arc<Manager>,
+    doc_service: Arc<DocumentService>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "folders",
+    migrations: &[
+        Migration {
+            version: 1,
+            description: "baseline",
+            up: &[
+                "CREATE TABLE IF NOT EXISTS folders (
+                    id UUID PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    parent_id UUID REFERENCES folders(id) ON DELETE CASCADE,
+                    owner_id UUID,
+                    workspace_id UUID,
+                    created_at TIMESTAMPTZ NOT NULL,
+                    updated_at TIMESTAMPTZ NOT NULL,
+                    CHECK (owner_id IS NOT NULL OR workspace_id IS NOT NULL)
+                )",
+                // Names are unique among the folders in one place, which also serves folder listings.
+                "CREATE UNIQUE INDEX IF NOT EXISTS folders_by_name
+                 ON folders ((COALESCE(parent_id, workspace_id, owner_id)), (lower(btrim(name))))",
+            ],
+            down: &[
+                "DROP TABLE IF EXISTS folders",
+            ],
+        },
+        Migration {
+            version: 2,
+            description: "deletion approval",
+            up: &[
+                "ALTER TABLE folders ADD COLUMN IF NOT EXISTS deletion_requires_approval BOOLEAN NOT NULL DEFAULT FALSE",
+            ],
+            down: &[
+                "ALTER TABLE folders DROP COLUMN IF EXISTS deletion_requires_approval",
+            ],
+        },
+    ],
+};
+
+impl FolderService {
+    pub async fn new(db_manager: Arc<Manager>, doc_service: Arc<DocumentService>) -> Result<Self> {
+        let service = FolderService { db_manager, doc_service };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("Folder schema initialized.");
+        Ok(())
+    }
+
+    /// Creates a folder in a workspace or, without one, in `owner_id`'s personal documents,
+    /// at the top or inside `parent_id`. Fails with `FolderRejection` if the parent is
+    /// elsewhere or the name is taken there.
+    #[instrument(skip_all, fields(parent_id = ?parent_id))]
+    pub async fn create_folder(
+        &self,
+        name: &str,
+        owner_id: Option<Uuid>,
+        workspace_id: Option<Uuid>,
+        parent_id: Option<Uuid>,
+    ) -> Result<Folder> {
+        let owner_id = if workspace_id.is_some() { None } else { owner_id };
+        let Some(root_id) = workspace_id.or(owner_id) else {
+            bail!("A folder needs an owner or a workspace");
+        };
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        if let Some(parent_id) = parent_id
+            && self.root_of(&mut tx, parent_id).await? != Some(root_id)
+        {
+            return Err(FolderRejection::DifferentRoot.into());
+        }
+        let now = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap_or_default();
+        let folder: Folder = sqlx::query_as(&format!(
+                "INSERT INTO folders (id, name, parent_id, owner_id, workspace_id, created_at, updated_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $6) RETURNING {}",
+                FOLDER_COLUMNS,
+            ))
+            .bind(Uuid::new_v4())
+            .bind(name)
+            .bind(parent_id)
+            .bind(owner_id)
+            .bind(workspace_id)
+            .bind(now)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| name_taken_or(e, format!("Failed to create folder '{}'", name)))?;
+        tx.commit().await.context("Failed to commit folder creation")?;
+        info!("Created folder '{}' with ID: {}", name, folder.id);
+        Ok(folder)
+    }
+
+    #[instrument(skip_all, fields(folder_id = %folder_id))]
+    pub async fn get_folder(&self, folder_id: Uuid) -> Result<Option<Folder>> {
+        sqlx::query_as(&format!("SELECT {} FROM folders WHERE id = $1", FOLDER_COLUMNS))
+            .bind(folder_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to get folder ID {}", folder_id))
+    }
+
+    /// Fails with `FolderRejection::NameTaken` if a folder beside it has the name.
+    #[instrument(skip_all, fields(folder_id = %folder_id))]
+    pub async fn rename_folder(&self, folder_id: Uuid, name: &str) -> Result<Option<Folder>> {
+        sqlx::query_as(&format!(
+                "UPDATE folders SET name = $2, updated_at = $3 WHERE id = $1 RETURNING {}",
+                FOLDER_COLUMNS,
+            ))
+            .bind(folder_id)
+            .bind(name)
+            .bind(Utc::now())
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .map_err(|e| name_taken_or(e, format!("Failed to rename folder ID {}", folder_id)))
+    }
+
+    /// Marks a folder as needing a second approval to delete the documents in it, or clears
+    /// the mark. Deletions already awaiting approval stay that way.
+    #[instrument(skip_all, fields(folder_id = %folder_id, required = required))]
+    pub async fn set_deletion_approval(&self, folder_id: Uuid, required: bool) -> Result<Option<Folder>> {
+        sqlx::query_as(&format!(
+                "UPDATE folders SET deletion_requires_approval = $2, updated_at = $3 WHERE id = $1 RETURNING {}",
+                FOLDER_COLUMNS,
+            ))
+            .bind(folder_id)
+            .bind(required)
+            .bind(Utc::now())
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to set deletion approval of folder ID {}", folder_id))
+    }
+
+    /// Whether deleting the folder would take documents that need approval to delete with
+    /// it: it, a folder it is in or a folder inside it is marked.
+    #[instrument(skip_all, fields(folder_id = %folder_id))]
+    pub async fn deletion_protected(&self, folder_id: Uuid) -> Result<bool> {
+        sqlx::query_scalar(
+                "WITH RECURSIVE ancestors (id, parent_id, protected) AS (
+                    SELECT id, parent_id, deletion_requires_approval FROM folders WHERE id = $1
+                    UNION ALL
+                    SELECT f.id, f.parent_id, f.deletion_requires_approval FROM folders f JOIN ancestors a ON f.id = a.parent_id
+                 ),
+                 subtree (id, protected) AS (
+                    SELECT id, deletion_requires_approval FROM folders WHERE id = $1
+                    UNION ALL
+                    SELECT f.id, f.deletion_requires_approval FROM folders f JOIN subtree s ON f.parent_id = s.id
+                 )
+                 SELECT EXISTS (SELECT 1 FROM ancestors WHERE protected) OR EXISTS (SELECT 1 FROM subtree WHERE protected)"
+            )
+            .bind(folder_id)
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to check deletion approval around folder ID {}", folder_id))
+    }
+
+    /// Moves a folder, with everything in it, into `parent_id` or to the top of its root.
+    /// Fails with `FolderRejection` if that would put it inside itself, the parent is
+    /// elsewhere, or the name is taken there.
+    #[instrument(skip_all, fields(folder_id = %folder_id, parent_id = ?parent_id))]
+    pub async fn move_folder(&self, folder_id: Uuid, parent_id: Option<Uuid>) -> Result<Option<Folder>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let Some(root_id) = self.root_of(&mut tx, folder_id).await? else {
+            return Ok(None);
+        };
+        if let Some(parent_id) = parent_id {
+            if self.root_of(&mut tx, parent_id).await? != Some(root_id) {
+                return Err(FolderRejection::DifferentRoot.into());
+            }
+            // Walk up from the new parent. Transactions are serializable, so two moves
+            // cannot each pass this check and together make a cycle.
+            let cycle: bool = sqlx::query_scalar(
+                    "WITH RECURSIVE ancestors (id, parent_id) AS (
+                        SELECT id, parent_id FROM folders WHERE id = $1
+                        UNION ALL
+                        SELECT f.id, f.parent_id FROM folders f JOIN ancestors a ON f.id = a.parent_id
+                     )
+                     SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = $2)"
+                )
+                .bind(parent_id)
+                .bind(folder_id)
+                .fetch_one(&mut *tx)
+                .await
+                .context(format!("Failed to check ancestors of folder ID {}", parent_id))?;
+            if cycle {
+                return Err(FolderRejection::Cycle.into());
+            }
+        }
+        let folder: Folder = sqlx::query_as(&format!(
+                "UPDATE folders SET parent_id = $2, updated_at = $3 WHERE id = $1 RETURNING {}",
+                FOLDER_COLUMNS,
+            ))
+            .bind(folder_id)
+            .bind(parent_id)
+            .bind(Utc::now())
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| name_taken_or(e, format!("Failed to move folder ID {}", folder_id)))?;
+        tx.commit().await.context(format!("Failed to commit move of folder ID {}", folder_id))?;
+        Ok(Some(folder))
+    }
+
+    /// Deletes a folder and every folder inside it, moving their documents to the trash.
+    /// Documents restored from there come back at the top of their root. Returns the
+    /// trashed documents, or `None` if there was no such folder.
+    #[instrument(skip_all, fields(folder_id = %folder_id))]
+    pub async fn delete_folder(&self, folder_id: Uuid) -> Result<Option<Vec<Uuid>>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let subtree: Vec<Uuid> = sqlx::query_scalar(
+                "WITH RECURSIVE subtree (id) AS (
+                    SELECT id FROM folders WHERE id = $1
+                    UNION ALL
+                    SELECT f.id FROM folders f JOIN subtree s ON f.parent_id = s.id
+                 )
+                 SELECT id FROM subtree"
+            )
+            .bind(folder_id)
+            .fetch_all(&mut *tx)
+            .await
+            .context(format!("Failed to list folders inside folder ID {}", folder_id))?;
+        if subtree.is_empty() {
+            return Ok(None);
+        }
+        let trashed: Vec<Uuid> = sqlx::query_scalar(
+                "UPDATE documents_metadata SET deleted_at = $2
+                 WHERE parent_folder_id = ANY($1) AND deleted_at IS NULL RETURNING id"
+            )
+            .bind(&subtree)
+            .bind(Utc::now())
+            .fetch_all(&mut *tx)
+            .await
+            .context(format!("Failed to trash documents in folder ID {}", folder_id))?;
+        sqlx::query("DELETE FROM documents_by_name WHERE document_id = ANY($1)")
+            .bind(&trashed)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to release names of documents in folder ID {}", folder_id))?;
+        // Includes documents that were already in the trash.
+        sqlx::query("UPDATE documents_metadata SET parent_folder_id = NULL WHERE parent_folder_id = ANY($1)")
+            .bind(&subtree)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to take documents out of folder ID {}", folder_id))?;
+        // Subfolders go with it.
+        sqlx::query("DELETE FROM folders WHERE id = $1")
+            .bind(folder_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to delete folder ID {}", folder_id))?;
+        for doc_id in &trashed {
+            outbox::record(&mut tx, DocumentEvent::Deleted { doc_id: *doc_id }).await?;
+        }
+        tx.commit().await.context(format!("Failed to commit deletion of folder ID {}", folder_id))?;
+        info!("Deleted folder ID {} with {} subfolders and {} documents", folder_id, subtree.len() - 1, trashed.len());
+        for doc_id in &trashed {
+            self.doc_service.events().publish(DocumentEvent::Deleted { doc_id: *doc_id });
+        }
+        Ok(Some(trashed))
+    }
+
+    /// Moves a document into `folder_id` or to the top of its root. Fails with
+    /// `FolderRejection::DifferentRoot` if the folder is elsewhere, or `NameTaken` if a
+    /// document there has its name. Returns false if there was no such document.
+    #[instrument(skip_all, fields(doc_id = %doc_id, folder_id = ?folder_id))]
+    pub async fn move_document(&self, doc_id: Uuid, folder_id: Option<Uuid>) -> Result<bool> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let row: Option<(String, Option<Uuid>)> = sqlx::query_as(
+                "SELECT name, COALESCE(workspace_id, owner_id) FROM documents_metadata
+                 WHERE id = $1 AND deleted_at IS NULL FOR UPDATE"
+            )
+            .bind(doc_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .context(format!("Failed to query document ID {}", doc_id))?;
+        let Some((name, root_id)) = row else {
+            return Ok(false);
+        };
+        // Documents without an owner or a workspace have no root to organize them in.
+        let Some(root_id) = root_id else {
+            return Err(FolderRejection::DifferentRoot.into());
+        };
+        if let Some(folder_id) = folder_id
+            && self.root_of(&mut tx, folder_id).await? != Some(root_id)
+        {
+            return Err(FolderRejection::DifferentRoot.into());
+        }
+        sqlx::query("DELETE FROM documents_by_name WHERE document_id = $1")
+            .bind(doc_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to release name of document ID {}", doc_id))?;
+        let destination = folder_id.unwrap_or(root_id);
+        if !document_service::claim_name(&mut tx, destination, &name, doc_id).await? {
+            tx.rollback().await.ok();
+            return Err(anyhow!(self.doc_service.name_taken(destination, &name).await?));
+        }
+        sqlx::query("UPDATE documents_metadata SET parent_folder_id = $2 WHERE id = $1")
+            .bind(doc_id)
+            .bind(folder_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to move document ID {}", doc_id))?;
+        tx.commit().await.context(format!("Failed to commit move of document ID {}", doc_id))?;
+        Ok(true)
+    }
+
+    /// The folders and documents directly inside a folder, or at the top of a root.
+    #[instrument(skip_all, fields(folder_id = %folder_id))]
+    pub async fn children(&self, folder_id: Uuid) -> Result<FolderContents> {
+        let folders = sqlx::query_as(&format!(
+                "SELECT {} FROM folders WHERE COALESCE(parent_id, workspace_id, owner_id) = $1
+                 ORDER BY lower(btrim(name)), id",
+                FOLDER_COLUMNS,
+            ))
+            .bind(folder_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list folders in folder ID {}", folder_id))?;
+        let documents = sqlx::query_as(
+                "SELECT id, name, created_at, updated_at, language FROM documents_metadata
+                 WHERE COALESCE(parent_folder_id, workspace_id, owner_id) = $1 AND deleted_at IS NULL
+                 ORDER BY lower(btrim(name)), id"
+            )
+            .bind(folder_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list documents in folder ID {}", folder_id))?;
+        Ok(FolderContents { folders, documents })
+    }
+
+    /// The workspace or owner a folder belongs to.
+    async fn root_of(&self, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, folder_id: Uuid) -> Result<Option<Uuid>> {
+        sqlx::query_scalar("SELECT COALESCE(workspace_id, owner_id) FROM folders WHERE id = $1")
+            .bind(folder_id)
+            .fetch_optional(&mut **tx)
+            .await
+            .context(format!("Failed to look up the root of folder ID {}", folder_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    fn rejection(result: Result<impl std::fmt::Debug>) -> Option<FolderRejection> {
+        result.unwrap_err().downcast_ref::<FolderRejection>().copied()
+    }
+
+    #[tokio::test]
+    async fn test_folder_tree() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = Arc::new(DocumentService::new(manager.clone()).await?);
+        let folders = FolderService::new(manager, doc_service.clone()).await?;
+        let owner = Uuid::new_v4();
+
+        let projects = folders.create_folder("Projects", Some(owner), None, None).await?;
+        let archive = folders.create_folder("Archive", Some(owner), None, Some(projects.id)).await?;
+        let old = folders.create_folder("Old", Some(owner), None, Some(archive.id)).await?;
+        assert_eq!(rejection(folders.create_folder(" projects ", Some(owner), None, None).await), Some(FolderRejection::NameTaken));
+        assert_eq!(
+            rejection(folders.create_folder("Elsewhere", Some(Uuid::new_v4()), None, Some(projects.id)).await),
+            Some(FolderRejection::DifferentRoot),
+        );
+
+        // No folder can end up inside itself.
+        assert_eq!(rejection(folders.move_folder(projects.id, Some(old.id)).await), Some(FolderRejection::Cycle));
+        assert_eq!(rejection(folders.move_folder(projects.id, Some(projects.id)).await), Some(FolderRejection::Cycle));
+        let moved = folders.move_folder(old.id, None).await?.expect("folder should exist");
+        assert_eq!(moved.parent_id, None);
+        assert_eq!(folders.rename_folder(old.id, "Older").await?.map(|folder| folder.name), Some("Older".to_string()));
+
+        // Names are unique per folder, so a document can share its name with one elsewhere.
+        let plan = doc_service.create_document_for("Plan", Some(owner)).await?;
+        let other_plan = doc_service.create_document_for("Other plan", Some(owner)).await?;
+        assert!(folders.move_document(plan.id, Some(archive.id)).await?);
+        doc_service.rename_document(other_plan.id, "Plan").await?.expect("the name should be free at the top");
+        let blocked = folders.move_document(other_plan.id, Some(archive.id)).await.unwrap_err();
+        assert!(blocked.downcast_ref::<document_service::NameTaken>().is_some());
+
+        let top = folders.children(owner).await?;
+        assert_eq!(top.folders.iter().map(|folder| folder.name.as_str()).collect::<Vec<_>>(), ["Older", "Projects"]);
+        assert_eq!(top.documents.iter().map(|doc| doc.id).collect::<Vec<_>>(), [other_plan.id]);
+        assert_eq!(folders.children(archive.id).await?.documents.iter().map(|doc| doc.id).collect::<Vec<_>>(), [plan.id]);
+
+        // Deleting a folder takes its subfolders with it and trashes their documents, which
+        // come back at the top of the root.
+        assert_eq!(folders.delete_folder(projects.id).await?, Some(vec![plan.id]));
+        assert!(folders.get_folder(archive.id).await?.is_none());
+        assert!(folders.delete_folder(projects.id).await?.is_none());
+        assert!(doc_service.get_document_metadata(plan.id).await?.is_none());
+        let restored = doc_service.restore_from_trash(plan.id).await?.expect("document should be in the trash");
+        assert_eq!(restored.name, "Plan (2)");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deleting_from_marked_folders_needs_a_second_approval() -> Result<()> {
+        use crate::document_service::{Deletion, DeletionDecision, DeletionStatus, SelfApproval};
+
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = Arc::new(DocumentService::new(manager.clone()).await?);
+        let lapsing = DocumentService::new(manager.clone()).await?.with_deletion_approval_window(chrono::TimeDelta::zero());
+        let folders = FolderService::new(manager, doc_service.clone()).await?;
+        let (owner, approver) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let contracts = folders.create_folder("Contracts", Some(owner), None, None).await?;
+        let signed = folders.create_folder("Signed", Some(owner), None, Some(contracts.id)).await?;
+        let marked = folders.set_deletion_approval(contracts.id, true).await?.expect("folder should exist");
+        assert!(marked.deletion_requires_approval);
+        assert!(folders.deletion_protected(signed.id).await?);
+
+        // Marks apply to everything inside, and asking twice finds the same request.
+        let lease = doc_service.create_document_for("Lease", Some(owner)).await?;
+        assert!(folders.move_document(lease.id, Some(signed.id)).await?);
+        let Some(Deletion::Requested(request)) = doc_service.request_deletion(lease.id, Some(owner)).await? else {
+            panic!("deletion should await approval");
+        };
+        assert_eq!((request.status, request.requested_by), (DeletionStatus::Pending, Some(owner)));
+        assert_eq!(doc_service.request_deletion(lease.id, Some(owner)).await?, Some(Deletion::Pending(request.clone())));
+        assert!(doc_service.get_document_metadata(lease.id).await?.is_some());
+
+        let err = doc_service.decide_deletion(lease.id, request.id, Some(owner), DeletionDecision::Approve).await.unwrap_err();
+        assert!(err.downcast_ref::<SelfApproval>().is_some());
+        let approved = doc_service.decide_deletion(lease.id, request.id, Some(approver), DeletionDecision::Approve).await?
+            .expect("request should be pending");
+        assert_eq!((approved.status, approved.decided_by), (DeletionStatus::Approved, Some(approver)));
+        assert!(doc_service.get_document_metadata(lease.id).await?.is_none());
+        assert!(doc_service.decide_deletion(lease.id, request.id, Some(approver), DeletionDecision::Reject).await?.is_none());
+
+        // Rejected requests leave the document, and lapsed ones cannot be approved.
+        let deed = doc_service.create_document_for("Deed", Some(owner)).await?;
+        assert!(folders.move_document(deed.id, Some(contracts.id)).await?);
+        let Some(Deletion::Requested(rejected)) = doc_service.request_deletion(deed.id, Some(owner)).await? else {
+            panic!("deletion should await approval");
+        };
+        doc_service.decide_deletion(deed.id, rejected.id, Some(owner), DeletionDecision::Reject).await?.expect("request should be pending");
+        let Some(Deletion::Requested(lapsed)) = lapsing.request_deletion(deed.id, Some(owner)).await? else {
+            panic!("deletion should await approval");
+        };
+        assert!(doc_service.decide_deletion(deed.id, lapsed.id, Some(approver), DeletionDecision::Approve).await?.is_none());
+        assert!(doc_service.expire_deletion_requests().await? >= 1);
+        let history = doc_service.deletion_requests(deed.id).await?;
+        assert_eq!(history.len(), 2);
+        assert!([DeletionStatus::Expired, DeletionStatus::Rejected].iter().all(|status| history.iter().any(|request| request.status == *status)));
+        assert!(doc_service.get_document_metadata(deed.id).await?.is_some());
+
+        // Unmarked folders delete straight away.
+        folders.set_deletion_approval(contracts.id, false).await?;
+        assert_eq!(doc_service.request_deletion(deed.id, Some(owner)).await?, Some(Deletion::Trashed));
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Serves the web client under `/app`, either from a directory or, with the
+//! `embed-frontend` feature, from files compiled into the binary. Unknown paths
+//! fall back to `index.html` so the client's router can handle them.
+
+use anyhow::{bail, Result};
+use axum::extract::{Path as UrlPath, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::get;
+use axum::Router;
+use std::borrow::Cow;
+use std::env;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+const FRONTEND_DIR_ENV: &str = "COLLABORATE_FRONTEND_DIR";
+const INDEX: &str = "index.html";
+
+#[cfg(feature = "embed-frontend")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "frontend/dist"]
+struct EmbeddedFrontend;
+
+/// Where the web client's files come from.
+pub enum Frontend {
+    Directory(PathBuf),
+    #[cfg(feature = "embed-frontend")]
+    Embedded,
+}
+
+struct Asset {
+    data: Cow<'static, [u8]>,
+    etag: String,
+}
+
+impl Frontend {
+    /// Serves from `COLLABORATE_FRONTEND_DIR` when set, otherwise from the embedded
+    /// files if this build has them. `None` means no frontend is served.
+    pub fn from_env() -> Result<Option<Self>> {
+        if let Ok(dir) = env::var(FRONTEND_DIR_ENV) {
+            let dir = PathBuf::from(dir);
+            if !dir.join(INDEX).is_file() {
+                bail!("{} is set to {}, which has no {}", FRONTEND_DIR_ENV, dir.display(), INDEX);
+            }
+            return Ok(Some(Frontend::Directory(dir)));
+        }
+        #[cfg(feature = "embed-frontend")]
+        return Ok(Some(Frontend::Embedded));
+        #[cfg(not(feature = "embed-frontend"))]
+        Ok(None)
+    }
+
+    async fn load(&self, path: &str) -> Option<Asset> {
+        // Only plain relative paths; anything that could escape the root is treated as missing.
+        if !Path::new(path).components().all(|component| matches!(component, Component::Normal(_))) {
+            return None;
+        }
+        match self {
+            Frontend::Directory(root) => {
+                let file = root.join(path);
+                let metadata = tokio::fs::metadata(&file).await.ok().filter(|metadata| metadata.is_file())?;
+                let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+                let data = tokio::fs::read(&file).await.ok()?;
+                Some(Asset { data: Cow::Owned(data), etag: format!("\"{:x}-{:x}\"", metadata.len(), modified) })
+            }
+            #[cfg(feature = "embed-frontend")]
+            Frontend::Embedded => {
+                let file = EmbeddedFrontend::get(path)?;
+                let etag = format!("\"{}\"", hex::encode(file.metadata.sha256_hash()));
+                Some(Asset { data: file.data, etag })
+            }
+        }
+    }
+}
+
+/// `index.html` must be revalidated so clients pick up new releases; bundler output under
+/// `assets/` is content-hashed and can be cached forever.
+fn cache_control(path: &str) -> &'static str {
+    if path == INDEX {
+        "no-cache"
+    } else if path.starts_with("assets/") {
+        "public, max-age=31536000, immutable"
+    } else {
+        "public, max-age=3600"
+    }
+}
+
+/// Client-side routes look like `/app/documents/42`; files have an extension.
+fn is_client_route(path: &str) -> bool {
+    !path.rsplit('/').next().unwrap_or_default().contains('.')
+}
+
+pub fn routes<S: Clone + Send + Sync + 'static>(frontend: Arc<Frontend>) -> Router<S> {
+    Router::new()
+        // Relative asset URLs in index.html need the trailing slash.
+        .route("/app", get(|| async { Redirect::permanent("/app/") }))
+        .route("/app/", get(serve_index))
+        .route("/app/*path", get(serve_path))
+        .with_state(frontend)
+}
+
+async fn serve_index(State(frontend): State<Arc<Frontend>>, headers: HeaderMap) -> Response {
+    serve(&frontend, INDEX, &headers).await
+}
+
+async fn serve_path(
+    State(frontend): State<Arc<Frontend>>,
+    UrlPath(path): UrlPath<String>,
+    headers: HeaderMap,
+) -> Response {
+    serve(&frontend, &path, &headers).await
+}
+
+async fn serve(frontend: &Frontend, path: &str, headers: &HeaderMap) -> Response {
+    let (path, asset) = match frontend.load(path).await {
+        Some(asset) => (path, asset),
+        None if is_client_route(path) => match frontend.load(INDEX).await {
+            Some(asset) => (INDEX, asset),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        },
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let mut response = if headers.get(IF_NONE_MATCH).is_some_and(|tag| tag.as_bytes() == asset.etag.as_bytes()) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let mime = mime_guess::from_path(path).first_or_octet_stream();
+        let mut response = asset.data.into_owned().into_response();
+        if let Ok(content_type) = HeaderValue::from_str(mime.as_ref()) {
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        response
+    };
+    let response_headers = response.headers_mut();
+    response_headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control(path)));
+    if let Ok(etag) = HeaderValue::from_str(&asset.etag) {
+        response_headers.insert(ETAG, etag);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_directory_serving_with_spa_fallback() -> Result<()> {
+        let root = env::temp_dir().join(format!("collaborate-frontend-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(root.join("assets")).await?;
+        tokio::fs::write(root.join(INDEX), "<html></html>").await?;
+        tokio::fs::write(root.join("assets/app.3f2a.js"), "console.log(1)").await?;
+        let frontend = Frontend::Directory(root.clone());
+        let headers = HeaderMap::new();
+
+        let script = serve(&frontend, "assets/app.3f2a.js", &headers).await;
+        assert_eq!(script.status(), StatusCode::OK);
+        assert_eq!(script.headers()[CONTENT_TYPE], "text/javascript");
+        assert_eq!(script.headers()[CACHE_CONTROL], "public, max-age=31536000, immutable");
+
+        let route = serve(&frontend, "documents/42", &headers).await;
+        assert_eq!(route.status(), StatusCode::OK);
+        assert_eq!(route.headers()[CACHE_CONTROL], "no-cache");
+
+        assert_eq!(serve(&frontend, "assets/missing.js", &headers).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(serve(&frontend, "../secrets.txt", &headers).await.status(), StatusCode::NOT_FOUND);
+
+        let mut conditional = HeaderMap::new();
+        conditional.insert(IF_NONE_MATCH, route.headers()[ETAG].clone());
+        assert_eq!(serve(&frontend, INDEX, &conditional).await.status(), StatusCode::NOT_MODIFIED);
+
+        tokio::fs::remove_dir_all(root).await?;
+        Ok(())
+    }
+}
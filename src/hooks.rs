@@ -0,0 +1,197 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Extension points for deployment-specific policy. Implement `Hook`, register it on a
+//! `HookRegistry`, and hand the registry to the services at startup.
+
+use async_trait::async_trait;
+use std::fmt;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// A document's content about to be, or just, persisted.
+pub struct SaveEvent<'a> {
+    pub doc_id: Uuid,
+    pub content: &'a [u8],
+}
+
+/// A document being shared with someone.
+pub struct ShareEvent<'a> {
+    pub doc_id: Uuid,
+    pub shared_by: Option<Uuid>,
+    /// Who or what the document is shared with, e.g. an email address or a link token.
+    pub grantee: &'a str,
+}
+
+/// A comment being added to a document.
+pub struct CommentEvent<'a> {
+    pub doc_id: Uuid,
+    pub author: Option<Uuid>,
+    pub body: &'a str,
+}
+
+/// Custom policy invoked by the services. `pre_save`, `on_share` and `on_comment` can veto
+/// the operation by returning an error, whose message is shown to the user; `post_save`
+/// only observes. Every method defaults to allowing the operation.
+#[async_trait]
+pub trait Hook: Send + Sync {
+    /// Identifies the hook in logs and rejection messages.
+    fn name(&self) -> &str;
+
+    async fn pre_save(&self, _event: &SaveEvent<'_>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn post_save(&self, _event: &SaveEvent<'_>) {}
+
+    async fn on_share(&self, _event: &ShareEvent<'_>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn on_comment(&self, _event: &CommentEvent<'_>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// A hook refused an operation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HookRejection {
+    pub hook: String,
+    pub reason: String,
+}
+
+impl fmt::Display for HookRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Rejected by {}: {}", self.hook, self.reason)
+    }
+}
+
+impl std::error::Error for HookRejection {}
+
+/// Hooks run in registration order; the first rejection stops the operation.
+#[derive(Clone, Default)]
+pub struct HookRegistry {
+    hooks: Vec<Arc<dyn Hook>>,
+}
+
+impl HookRegistry {
+    pub fn new() -> Self {
+        HookRegistry::default()
+    }
+
+    pub fn register(&mut self, hook: Arc<dyn Hook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub async fn pre_save(&self, event: &SaveEvent<'_>) -> Result<(), HookRejection> {
+        for hook in &self.hooks {
+            hook.pre_save(event).await.map_err(|e| rejection(hook.as_ref(), e))?;
+        }
+        Ok(())
+    }
+
+    pub async fn post_save(&self, event: &SaveEvent<'_>) {
+        for hook in &self.hooks {
+            hook.post_save(event).await;
+        }
+    }
+
+    pub async fn on_share(&self, event: &ShareEvent<'_>) -> Result<(), HookRejection> {
+        for hook in &self.hooks {
+            hook.on_share(event).await.map_err(|e| rejection(hook.as_ref(), e))?;
+        }
+        Ok(())
+    }
+
+    pub async fn on_comment(&self, event: &CommentEvent<'_>) -> Result<(), HookRejection> {
+        for hook in &self.hooks {
+            hook.on_comment(event).await.map_err(|e| rejection(hook.as_ref(), e))?;
+        }
+        Ok(())
+    }
+}
+
+fn rejection(hook: &dyn Hook, reason: anyhow::Error) -> HookRejection {
+    HookRejection { hook: hook.name().to_string(), reason: reason.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct BannedWord(&'static str);
+
+    #[async_trait]
+    impl Hook for BannedWord {
+        fn name(&self) -> &str {
+            "banned-word"
+        }
+
+        async fn pre_save(&self, event: &SaveEvent<'_>) -> anyhow::Result<()> {
+            if event.content.windows(self.0.len()).any(|window| window == self.0.as_bytes()) {
+                bail!("content contains '{}'", self.0);
+            }
+            Ok(())
+        }
+
+        async fn on_comment(&self, event: &CommentEvent<'_>) -> anyhow::Result<()> {
+            if event.body.contains(self.0) {
+                bail!("comment contains '{}'", self.0);
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct SaveCounter(AtomicUsize);
+
+    #[async_trait]
+    impl Hook for SaveCounter {
+        fn name(&self) -> &str {
+            "save-counter"
+        }
+
+        async fn post_save(&self, _event: &SaveEvent<'_>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_first_rejection_wins() {
+        let counter = Arc::new(SaveCounter::default());
+        let mut registry = HookRegistry::new();
+        registry.register(Arc::new(BannedWord("secret")));
+        registry.register(counter.clone());
+
+        let doc_id = Uuid::new_v4();
+        let clean = SaveEvent { doc_id, content: b"hello" };
+        assert!(registry.pre_save(&clean).await.is_ok());
+        registry.post_save(&clean).await;
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        let rejection = registry.pre_save(&SaveEvent { doc_id, content: b"top secret" }).await.unwrap_err();
+        assert_eq!(rejection.hook, "banned-word");
+
+        let comment = CommentEvent { doc_id, author: None, body: "the secret is out" };
+        assert!(registry.on_comment(&comment).await.is_err());
+        let share = ShareEvent { doc_id, shared_by: None, grantee: "someone@example.com" };
+        assert!(registry.on_share(&share).await.is_ok());
+    }
+}
@@ -13,44 +13,4058 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        rejection::{BytesRejection, FormRejection, JsonRejection, QueryRejection},
+        DefaultBodyLimit,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Form, Path, Query, State,
     },
-    response::{Html, IntoResponse},
-    routing::get,
-    Router,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    middleware,
+    routing::{delete, get, post, put},
+    Json, Router,
 };
+use axum::body::Bytes;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, FixedOffset, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tokio::net::TcpListener; // Import TcpListener
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use std::collections::HashSet;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use crate::document_service::DocumentService; // Import DocumentService
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use anyhow::Context;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn, Instrument, Span};
+use uuid::Uuid;
+use crate::analytics::{AnalyticsService, ClientEventBatch};
+use crate::api_error::{self, ApiError};
+use crate::api_keys::{self, ApiKey, ApiKeyGrant, ApiKeyService};
+use crate::archive_import::{self, ArchiveImports, ExportSource, ImportDestination, ImportJob};
+use crate::attachments::{Attachment, AttachmentService, Quarantined, ScanStatus};
+use crate::audit::{AuditAction, AuditEvent, AuditFilter, AuditLog, AuditRecord, RequestOrigin};
+use crate::auth::oauth::{FederatedLogin, PendingLogin, PENDING_LOGIN_COOKIE, PENDING_LOGIN_LIFETIME};
+use crate::auth::tokens::{self, bearer_token, AccessTokens, AuthenticatedUser, Authenticator, Credential, TokenConfig};
+use crate::blame::BlameSpan;
+use crate::close_code::CloseCode;
+use crate::concurrency::{self, ConcurrencyConfig, ConcurrencyLimits, RouteClass};
+use crate::config::{self, AppConfig};
+use crate::content_analysis::AnnotationSet;
+use crate::email_digest::{DigestFrequency, DigestPreferences, EmailDigestService};
+use crate::encryption::{Encryption, OrganizationKey};
+use crate::delta::ContentDelta;
+use crate::diagnostics::{Diagnostics, DiagnosticsReport};
+use crate::document_room::{RoomEvent, RoomHandle, RoomNotice, RoomRegistry, SaveStatus, TrafficClass, UpdateRejection};
+use crate::export::{self, ExportProfile};
+use crate::export_transforms::{self, ExportTransforms, Transform, WorkspaceTransforms};
+use crate::folders::{self, Folder, FolderRejection, FolderService};
+use crate::frontend::{self, Frontend};
+use crate::hooks::HookRejection;
+use crate::i18n::{self, Locale};
+use crate::import::{self, ImportRequest, ImportResponse};
+use crate::integrity;
+use crate::language;
+use crate::metrics::{self, ConnectionCounter, MetricsSnapshot};
+use crate::moderation::{LogEntry, ModerationService, Publication, Review};
+use crate::personal_data::PersonalDataService;
+use crate::oauth::{self, Consent, Delegation, OAuthClient, OAuthScope, OAuthService};
+use crate::organizations::{
+    Invite, IssuedInvite, Membership, Organization, OrganizationMember, OrganizationRejection, OrganizationRole,
+    OrganizationService, Team,
+};
+use crate::outbox::{self, LoggedEvent};
+use crate::permissions::{
+    AccessibleDocument, DefaultPolicy, DocumentPermission, DocumentSelection, IssuedShareLink, MemberRole, PermissionService,
+    PolicyRole, ShareLink, Workspace,
+};
+use crate::presence::{Participant, Presence, PresenceChange, PresenceUpdate};
+use crate::preview;
+use crate::protected_ranges::{ProtectedRange, ProtectionChange};
+use crate::protocol::{self, SyncMessage};
+use crate::rate_limit::{self, RateLimit, RateLimitBackend, RateLimiter};
+use crate::references::DocumentLink;
+use crate::search::{self, QuickSearchResults, ResultKind, SearchResult};
+use crate::search_index::SearchIndex;
+use crate::storage_quota::StorageUsage;
+use crate::sync::{self, SyncRequest, SyncResponse};
+use crate::telemetry;
+use crate::timezone::RenderTimezone;
+use crate::user_service::{
+    self, AuthenticationError, BotPrincipal, DocumentAccess, DocumentRole, FederatedLoginError, Guest, IssuedSession, RegistrationError, SessionInfo,
+    SessionKind, User, UserCounts, UserRole, UserService,
+};
+use crate::validation::{self, FieldError, FieldErrors, Valid, Validate};
+use crate::watch::{Notification, WatchService, DELETION_DECIDED, DELETION_REQUESTED};
+use crate::consistency::{CausalityToken, ReadConsistency};
+use crate::document_service::{self, Comment, CommentAnchor, CommentChange, CommentThread, Deletion, DeletionDecision, DeletionRequest, DeletionStatus, DocumentCounts, DocumentCursor, DocumentFilter, DocumentMetadata, DocumentService, DocumentSort, DocumentVersion, InvalidCursor, NameTaken, SelfApproval, TrashedDocument}; // Import DocumentService
+
+// Shared application state (if needed, e.g., for broadcasting messages)
+#[derive(Clone)]
+struct AppState {
+    doc_service: Arc<DocumentService>,
+    users: Arc<UserService>,
+    analytics: Arc<AnalyticsService>,
+    rooms: Arc<RoomRegistry>,
+    watches: Arc<WatchService>,
+    /// `None` when attachments are disabled.
+    attachments: Option<Arc<AttachmentService>>,
+    permissions: Arc<PermissionService>,
+    digests: Arc<EmailDigestService>,
+    search_index: Arc<SearchIndex>,
+    folders: Arc<FolderService>,
+    organizations: Arc<OrganizationService>,
+    archive_imports: Arc<ArchiveImports>,
+    oauth: Arc<OAuthService>,
+    api_keys: Arc<ApiKeyService>,
+    moderation: Arc<ModerationService>,
+    personal_data: Arc<PersonalDataService>,
+    audit: Arc<AuditLog>,
+    encryption: Option<Arc<Encryption>>,
+    export_transforms: Arc<ExportTransforms>,
+    federated_login: Arc<FederatedLogin>,
+    tokens: Arc<AccessTokens>,
+    connections: Arc<ConnectionCounter>,
+    concurrency: Arc<ConcurrencyLimits>,
+    diagnostics: Arc<Diagnostics>,
+    drain: Arc<DrainState>,
+    /// Becomes true when the server starts shutting down.
+    shutdown: watch::Receiver<bool>,
+    region: Option<String>,
+    admin_token: Option<String>,
+}
+
+/// Deployment settings for the HTTP server.
+#[derive(Clone)]
+pub struct ServerSettings {
+    pub listen_addr: SocketAddr,
+    /// Reported to clients by `/api/instance`.
+    pub region: Option<String>,
+    pub rate_limit: RateLimit,
+    pub rate_limit_backend: RateLimitBackend,
+    /// Slots for exports, imports and merges.
+    pub concurrency: ConcurrencyConfig,
+    /// Bearer token for `/admin` endpoints; they are disabled when unset.
+    pub admin_token: Option<String>,
+    pub tokens: TokenConfig,
+    /// The web client to serve at `/app`, if any.
+    pub frontend: Option<Arc<Frontend>>,
+    /// The largest request body a content update may have.
+    pub max_update_bytes: usize,
+}
+
+impl ServerSettings {
+    /// Takes the listen address, region and update size limit from `config`, and reads
+    /// `COLLABORATE_RATE_LIMIT_PER_MINUTE`, `COLLABORATE_RATE_LIMIT_BACKEND`,
+    /// `COLLABORATE_ADMIN_TOKEN`, the concurrency limits and the token settings.
+    pub fn from_env(config: &AppConfig) -> anyhow::Result<Self> {
+        Ok(ServerSettings {
+            listen_addr: config.server.listen_addr,
+            region: config.region.region.clone(),
+            rate_limit: RateLimit::from_env()?,
+            rate_limit_backend: RateLimitBackend::from_env()?,
+            concurrency: ConcurrencyConfig::from_env()?,
+            admin_token: std::env::var(ADMIN_TOKEN_ENV).ok().filter(|token| !token.is_empty()),
+            tokens: TokenConfig::from_env()?,
+            frontend: Frontend::from_env()?.map(Arc::new),
+            max_update_bytes: config.upload_limits.max_update_bytes,
+        })
+    }
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            listen_addr: config::DEFAULT_LISTEN_ADDR,
+            region: None,
+            rate_limit: RateLimit::default(),
+            rate_limit_backend: RateLimitBackend::default(),
+            concurrency: ConcurrencyConfig::default(),
+            admin_token: None,
+            tokens: TokenConfig::default(),
+            frontend: None,
+            max_update_bytes: config::DEFAULT_MAX_UPDATE_BYTES,
+        }
+    }
+}
+
+const ADMIN_TOKEN_ENV: &str = "COLLABORATE_ADMIN_TOKEN";
+/// Returned on document writes; echoed on reads to see at least that write.
+const CAUSALITY_TOKEN_HEADER: &str = "x-causality-token";
+/// Carries the secret of a share link; see `PermissionService::create_share_link`.
+const SHARE_LINK_HEADER: &str = "x-share-link";
+
+/// Room for the version and type ahead of an update in the framed WebSocket protocol.
+const FRAME_HEADER_BYTES: usize = 2;
+
+/// Documents per page of `GET /api/documents`, unless `?limit=` asks otherwise.
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+/// How often document WebSockets check that their session and access are still valid.
+const ACCESS_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How many unread notifications `GET /notifications` returns.
+const NOTIFICATIONS_PAGE_SIZE: i64 = 50;
+/// How long shutdown waits for rooms to save their updates, then for WebSockets to close.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(20);
+/// Clients told to migrate reconnect at a random point within this long, not all at once.
+const MIGRATE_RECONNECT_SPREAD: Duration = Duration::from_secs(5);
+/// Holds a guest's token; see `put_guest_handler`.
+const GUEST_COOKIE: &str = "collaborate_guest";
+const GUEST_COOKIE_MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+/// Holds the device secret a magic link was requested with; see `request_magic_link_handler`.
+const MAGIC_LINK_COOKIE: &str = "collaborate_magic_link";
+
+/// How far this node is in handing its rooms over before it stops, whether asked to by
+/// `/admin/drain` or by a shutdown signal.
+#[derive(Default)]
+struct DrainState {
+    started_at: OnceLock<DateTime<Utc>>,
+    /// Set once rooms have saved, or given up saving, and closed their connections.
+    handed_over: AtomicBool,
+}
+
+impl DrainState {
+    /// Marks the drain as started; false if it already was.
+    fn start(&self) -> bool {
+        self.started_at.set(Utc::now()).is_ok()
+    }
+
+    fn is_draining(&self) -> bool {
+        self.started_at.get().is_some()
+    }
+
+    fn handed_over(&self) -> bool {
+        self.handed_over.load(Ordering::Relaxed)
+    }
+}
+
+/// What a drain still waits for; see `drain_handler`.
+#[derive(Serialize)]
+struct DrainProgress {
+    draining: bool,
+    started_at: Option<DateTime<Utc>>,
+    /// Whether rooms have saved and told their clients to reconnect elsewhere.
+    handed_over: bool,
+    /// Document WebSockets still open.
+    connections: usize,
+    open_rooms: usize,
+    /// Documents with updates accepted but not yet saved, and how many updates.
+    unsaved_documents: usize,
+    unsaved_updates: usize,
+    /// Whether the node can be stopped without losing edits or cutting clients off.
+    complete: bool,
+}
+
+impl DrainProgress {
+    fn of(state: &AppState) -> Self {
+        let handed_over = state.drain.handed_over();
+        let connections = state.connections.current();
+        DrainProgress {
+            draining: state.drain.is_draining(),
+            started_at: state.drain.started_at.get().copied(),
+            handed_over,
+            connections,
+            open_rooms: state.rooms.open_rooms(),
+            unsaved_documents: state.rooms.stats().unsaved_documents(),
+            unsaved_updates: state.rooms.stats().pending_updates(),
+            complete: handed_over && connections == 0,
+        }
+    }
+}
+
+/// Identifies the instance a client is talking to. Clients compare `server_time`
+/// against their own clock around the request to estimate latency to this region.
+#[derive(Serialize)]
+struct InstanceInfo {
+    region: Option<String>,
+    /// Rendered in `timezone` when the client passed `?tz=`, otherwise UTC.
+    server_time: DateTime<FixedOffset>,
+    timezone: Option<&'static str>,
+}
+
+#[derive(Deserialize)]
+struct QuickSearchQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+    language: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EventStreamQuery {
+    #[serde(default)]
+    from_seq: i64,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct EventStreamResponse {
+    events: Vec<LoggedEvent>,
+    /// Where to continue from; unchanged when there was nothing new.
+    next_seq: i64,
+}
+
+#[derive(Deserialize)]
+struct MetricsQuery {
+    interval_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ListDocumentsQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+    sort: Option<DocumentSort>,
+    language: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    profile: ExportProfile,
+}
+
+#[derive(Serialize)]
+struct DocumentListResponse {
+    documents: Vec<DocumentResponse>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DocumentNameRequest {
+    name: String,
+}
+
+impl Validate for DocumentNameRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            "name",
+            validation::length(&self.name, 1, document_service::MAX_DOCUMENT_NAME_LENGTH),
+            FieldError::new("invalid-document-name").with_arg("max", document_service::MAX_DOCUMENT_NAME_LENGTH),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateDocumentRequest {
+    name: String,
+    /// Creates the document in this workspace, under its default policy. The caller must be a member.
+    workspace_id: Option<Uuid>,
+    /// Makes the document read-only from this time on, as for an interview scratchpad.
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Validate for CreateDocumentRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors
+            .check(
+                "name",
+                validation::length(&self.name, 1, document_service::MAX_DOCUMENT_NAME_LENGTH),
+                FieldError::new("invalid-document-name").with_arg("max", document_service::MAX_DOCUMENT_NAME_LENGTH),
+            )
+            .check(
+                "expires_at",
+                self.expires_at.is_none_or(|expires_at| expires_at > Utc::now()),
+                FieldError::new("invalid-document-expiry"),
+            );
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateFolderRequest {
+    name: String,
+    /// Creates the folder inside this one; otherwise at the top of the workspace, or of the
+    /// caller's personal documents.
+    parent_id: Option<Uuid>,
+    workspace_id: Option<Uuid>,
+}
+
+impl Validate for CreateFolderRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_folder_name(&self.name, errors);
+    }
+}
+
+#[derive(Deserialize)]
+struct FolderNameRequest {
+    name: String,
+}
+
+impl Validate for FolderNameRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_folder_name(&self.name, errors);
+    }
+}
+
+fn check_folder_name(name: &str, errors: &mut FieldErrors) {
+    errors.check(
+        "name",
+        validation::length(name.trim(), 1, folders::MAX_FOLDER_NAME_LENGTH),
+        FieldError::new("invalid-folder-name").with_arg("max", folders::MAX_FOLDER_NAME_LENGTH),
+    );
+}
+
+#[derive(Deserialize)]
+struct MoveFolderRequest {
+    /// `null` moves the folder to the top of its workspace or personal documents.
+    parent_id: Option<Uuid>,
+}
+
+impl Validate for MoveFolderRequest {}
+
+#[derive(Deserialize)]
+struct DeletionApprovalRequest {
+    required: bool,
+}
+
+impl Validate for DeletionApprovalRequest {}
+
+#[derive(Deserialize)]
+struct DeletionDecisionRequest {
+    decision: DeletionDecision,
+}
+
+impl Validate for DeletionDecisionRequest {}
+
+#[derive(Deserialize)]
+struct MoveDocumentRequest {
+    /// `null` moves the document to the top of its workspace or personal documents.
+    folder_id: Option<Uuid>,
+}
+
+impl Validate for MoveDocumentRequest {}
+
+#[derive(Deserialize)]
+struct DigestPreferencesRequest {
+    frequency: DigestFrequency,
+    /// IANA timezone name; digests go out in the morning there.
+    timezone: String,
+}
+
+impl Validate for DigestPreferencesRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            "timezone",
+            validation::is_timezone(&self.timezone),
+            FieldError::new("invalid-timezone").with_arg("timezone", &self.timezone),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyEmailQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct WorkspaceRequest {
+    name: String,
+    /// Creates the workspace for this organization. The caller must be one of its admins.
+    #[serde(default)]
+    organization_id: Option<Uuid>,
+}
+
+impl Validate for WorkspaceRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            "name",
+            validation::length(&self.name, 1, document_service::MAX_DOCUMENT_NAME_LENGTH),
+            FieldError::new("invalid-workspace-name").with_arg("max", document_service::MAX_DOCUMENT_NAME_LENGTH),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct MemberRequest {
+    role: MemberRole,
+}
+
+impl Validate for MemberRequest {}
+
+#[derive(Deserialize)]
+struct OrganizationRequest {
+    name: String,
+}
+
+impl Validate for OrganizationRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            "name",
+            validation::length(&self.name, 1, document_service::MAX_DOCUMENT_NAME_LENGTH),
+            FieldError::new("invalid-organization-name").with_arg("max", document_service::MAX_DOCUMENT_NAME_LENGTH),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct OrganizationRoleRequest {
+    role: OrganizationRole,
+}
+
+impl Validate for OrganizationRoleRequest {}
+
+#[derive(Deserialize)]
+struct InviteRequest {
+    email: String,
+    role: OrganizationRole,
+}
+
+impl Validate for InviteRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check("email", validation::is_email(self.email.trim()), FieldError::new("invalid-email"));
+    }
+}
+
+#[derive(Deserialize)]
+struct AcceptInviteRequest {
+    secret: String,
+}
+
+impl Validate for AcceptInviteRequest {}
+
+#[derive(Deserialize)]
+struct TeamRequest {
+    name: String,
+}
+
+impl Validate for TeamRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            "name",
+            validation::length(&self.name, 1, document_service::MAX_DOCUMENT_NAME_LENGTH),
+            FieldError::new("invalid-team-name").with_arg("max", document_service::MAX_DOCUMENT_NAME_LENGTH),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct EncryptionKeyRequest {
+    /// The key's ID in the organization's KMS.
+    key_id: String,
+}
+
+impl Validate for EncryptionKeyRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            "key_id",
+            validation::length(&self.key_id, 1, MAX_KEY_ID_LENGTH),
+            FieldError::new("invalid-encryption-key-id").with_arg("max", MAX_KEY_ID_LENGTH),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct ShareRequest {
+    user_id: Uuid,
+    role: PolicyRole,
+}
+
+impl Validate for ShareRequest {}
+
+#[derive(Deserialize)]
+struct ShareLinkRequest {
+    role: PolicyRole,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Validate for ShareLinkRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            "expires_at",
+            self.expires_at.is_none_or(|expires_at| expires_at > Utc::now()),
+            FieldError::new("invalid-share-link-expiry"),
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct ShareLinkResponse {
+    #[serde(flatten)]
+    link: IssuedShareLink,
+    /// Where to send whoever the link is for: the web client, carrying the secret.
+    url: String,
+    /// Set when moderation flagged the document: the review the link stays up pending.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moderation_review: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ModerationDecision {
+    Approve,
+    Reject,
+}
+
+#[derive(Deserialize)]
+struct ModerationDecisionRequest {
+    decision: ModerationDecision,
+    note: Option<String>,
+}
+
+impl Validate for ModerationDecisionRequest {}
+
+#[derive(Deserialize)]
+struct DocumentSocketQuery {
+    share_link: Option<String>,
+    #[serde(default)]
+    mode: SocketMode,
+}
+
+/// What a document WebSocket is for.
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SocketMode {
+    /// Syncing and editing the document.
+    #[default]
+    Edit,
+    /// Watching who is in the document and where their cursors are, for dashboards. The
+    /// document's content is never loaded or sent, and the connection cannot edit or
+    /// appear in the room itself.
+    Presence,
+}
+
+/// Documents at most one bulk share or unshare may list.
+const MAX_BULK_DOCUMENTS: usize = 1000;
+/// Longest KMS key ID accepted; cloud KMS ARNs and resource names fit well within it.
+const MAX_KEY_ID_LENGTH: usize = 512;
+
+#[derive(Deserialize)]
+struct BulkShareRequest {
+    user_id: Uuid,
+    role: PolicyRole,
+    #[serde(flatten)]
+    selection: DocumentSelection,
+}
+
+impl Validate for BulkShareRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        validate_selection(&self.selection, errors);
+    }
+}
+
+#[derive(Deserialize)]
+struct BulkUnshareRequest {
+    user_id: Uuid,
+    #[serde(flatten)]
+    selection: DocumentSelection,
+}
+
+impl Validate for BulkUnshareRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        validate_selection(&self.selection, errors);
+    }
+}
+
+fn validate_selection(selection: &DocumentSelection, errors: &mut FieldErrors) {
+    if let DocumentSelection::Documents(ids) = selection {
+        errors.check(
+            "documents",
+            (1..=MAX_BULK_DOCUMENTS).contains(&ids.len()),
+            FieldError::new("invalid-bulk-documents").with_arg("max", MAX_BULK_DOCUMENTS),
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct BulkShareResponse {
+    shared: Vec<DocumentPermission>,
+    /// Listed documents that don't exist or that the caller may not share.
+    skipped: Vec<Uuid>,
+}
+
+#[derive(Serialize)]
+struct BulkUnshareResponse {
+    /// Documents that were shared with the user and no longer are.
+    unshared: Vec<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct DefaultPolicyRequest {
+    member_role: PolicyRole,
+}
+
+impl Validate for DefaultPolicyRequest {}
+
+#[derive(Deserialize)]
+struct ExportTransformsRequest {
+    /// Applied in order to every export from the workspace.
+    transforms: Vec<Transform>,
+}
+
+impl Validate for ExportTransformsRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            "transforms",
+            self.transforms.len() <= export_transforms::MAX_TRANSFORMS,
+            FieldError::new("too-many-export-transforms").with_arg("max", export_transforms::MAX_TRANSFORMS),
+        );
+        for (i, transform) in self.transforms.iter().enumerate() {
+            if let Some(key) = transform.problem() {
+                errors.add(format!("transforms[{}]", i), FieldError::new(key));
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DocumentContentRequest {
+    /// Base64 of the new content.
+    content: String,
+}
+
+impl Validate for DocumentContentRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check("content", BASE64.decode(&self.content).is_ok(), FieldError::new("invalid-document-content"));
+    }
+}
+
+#[derive(Deserialize)]
+struct CommentRequest {
+    body: String,
+    /// Starts a new thread when missing.
+    #[serde(default)]
+    reply_to: Option<Uuid>,
+    /// Only for a new thread; replies share their thread's anchor.
+    #[serde(default)]
+    anchor: Option<CommentAnchor>,
+}
+
+impl Validate for CommentRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            "body",
+            validation::length(self.body.trim(), 1, document_service::MAX_COMMENT_LENGTH),
+            FieldError::new("invalid-comment-body").with_arg("max", document_service::MAX_COMMENT_LENGTH),
+        );
+        if let Some(anchor) = &self.anchor {
+            let valid = |position: &str| (1..=document_service::MAX_COMMENT_ANCHOR_LENGTH).contains(&position.len());
+            errors.check(
+                "anchor",
+                self.reply_to.is_none() && valid(&anchor.start) && anchor.end.as_deref().is_none_or(valid),
+                FieldError::new("invalid-comment-anchor"),
+            );
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ResolveCommentRequest {
+    /// `false` reopens the thread.
+    resolved: bool,
+}
+
+impl Validate for ResolveCommentRequest {}
+
+#[derive(Serialize)]
+struct DocumentResponse {
+    id: Uuid,
+    name: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    /// Detected language of the content; `null` until there is enough text to tell.
+    language: Option<String>,
+    /// Base64 of the saved content; omitted when only metadata changed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    /// When the document stops taking edits; omitted from listings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct TrashedDocumentResponse {
+    #[serde(flatten)]
+    document: DocumentResponse,
+    deleted_at: DateTime<Utc>,
+}
+
+impl From<TrashedDocument> for TrashedDocumentResponse {
+    fn from(trashed: TrashedDocument) -> Self {
+        TrashedDocumentResponse { document: trashed.metadata.into(), deleted_at: trashed.deleted_at }
+    }
+}
+
+/// One entry of a folder listing.
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum FolderEntry {
+    Folder(Folder),
+    Document(DocumentResponse),
+}
+
+impl From<DocumentMetadata> for DocumentResponse {
+    fn from(metadata: DocumentMetadata) -> Self {
+        DocumentResponse {
+            id: metadata.id,
+            name: metadata.name,
+            created_at: metadata.created_at,
+            updated_at: metadata.updated_at,
+            language: metadata.language,
+            content: None,
+            expires_at: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RegisterRequest {
+    display_name: String,
+    email: String,
+    password: String,
+    /// The secret of an organization invite to the same address, to join on registering.
+    #[serde(default)]
+    invite: Option<String>,
+}
+
+impl Validate for RegisterRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors
+            .check(
+                "display_name",
+                validation::length(&self.display_name, 1, user_service::MAX_DISPLAY_NAME_LENGTH),
+                FieldError::new("invalid-display-name").with_arg("max", user_service::MAX_DISPLAY_NAME_LENGTH),
+            )
+            .check("email", validation::is_email(self.email.trim()), FieldError::new("invalid-email"))
+            .check(
+                "password",
+                self.password.chars().count() >= user_service::MIN_PASSWORD_LENGTH,
+                FieldError::new("weak-password").with_arg("min", user_service::MIN_PASSWORD_LENGTH),
+            );
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    email: String,
+    password: String,
+    /// Start a cookie session instead of handing out tokens.
+    #[serde(default)]
+    cookie: bool,
+}
+
+impl Validate for LoginRequest {}
+
+#[derive(Deserialize)]
+struct PasswordResetRequest {
+    email: String,
+}
+
+impl Validate for PasswordResetRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check("email", validation::is_email(self.email.trim()), FieldError::new("invalid-email"));
+    }
+}
+
+#[derive(Deserialize)]
+struct MagicLinkRequest {
+    email: String,
+}
+
+impl Validate for MagicLinkRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check("email", validation::is_email(self.email.trim()), FieldError::new("invalid-email"));
+    }
+}
+
+#[derive(Deserialize)]
+struct MagicLinkConfirmation {
+    token: String,
+    /// Start a cookie session instead of handing out tokens.
+    #[serde(default)]
+    cookie: bool,
+}
+
+impl Validate for MagicLinkConfirmation {}
+
+#[derive(Deserialize)]
+struct PasswordResetConfirmation {
+    token: String,
+    password: String,
+}
+
+impl Validate for PasswordResetConfirmation {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            "password",
+            self.password.chars().count() >= user_service::MIN_PASSWORD_LENGTH,
+            FieldError::new("weak-password").with_arg("min", user_service::MIN_PASSWORD_LENGTH),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct GuestRequest {
+    display_name: String,
+    color: String,
+}
+
+impl Validate for GuestRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors
+            .check(
+                "display_name",
+                validation::length(&self.display_name, 1, user_service::MAX_DISPLAY_NAME_LENGTH),
+                FieldError::new("invalid-display-name").with_arg("max", user_service::MAX_DISPLAY_NAME_LENGTH),
+            )
+            .check("color", validation::is_color(&self.color), FieldError::new("invalid-color"));
+    }
+}
+
+/// An account as its owner sees it.
+#[derive(Serialize)]
+struct UserProfile {
+    id: Uuid,
+    display_name: String,
+    email: Option<String>,
+    email_verified: bool,
+    created_at: DateTime<Utc>,
+    role: UserRole,
+}
+
+impl From<User> for UserProfile {
+    fn from(user: User) -> Self {
+        UserProfile {
+            id: user.id,
+            display_name: user.display_name,
+            email: user.email,
+            email_verified: user.email_verified,
+            created_at: user.created_at,
+            role: user.role,
+        }
+    }
+}
+
+/// An account as administrators see it.
+#[derive(Serialize)]
+struct ManagedUser {
+    #[serde(flatten)]
+    profile: UserProfile,
+    bot: bool,
+    anonymized: bool,
+    deactivated_at: Option<DateTime<Utc>>,
+    /// Deactivated by an administrator rather than for disuse.
+    suspended: bool,
+}
+
+impl From<User> for ManagedUser {
+    fn from(user: User) -> Self {
+        ManagedUser {
+            bot: user.is_bot(),
+            anonymized: user.is_anonymized(),
+            deactivated_at: user.deactivated_at,
+            suspended: user.suspended,
+            profile: UserProfile::from(user),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    action: Option<AuditAction>,
+    actor: Option<Uuid>,
+    /// The document, account, key or email the operation acted on.
+    target: Option<String>,
+    ip: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    /// The last event of the previous page.
+    before: Option<Uuid>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct AuditEventsResponse {
+    events: Vec<AuditEvent>,
+    /// Pass back as `?before=` for the next page; `None` on the last page.
+    next_before: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct ListUsersQuery {
+    /// The last account of the previous page.
+    after: Option<Uuid>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct UserListResponse {
+    users: Vec<ManagedUser>,
+    /// Pass back as `?after=` for the next page; `None` on the last page.
+    next_after: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct RoleRequest {
+    role: UserRole,
+}
+
+impl Validate for RoleRequest {}
+
+#[derive(Serialize)]
+struct ServerStats {
+    users: UserCounts,
+    documents: DocumentCounts,
+    /// Gauges of the node that answered.
+    node: MetricsSnapshot,
+}
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+impl Validate for RefreshRequest {}
+
+#[derive(Serialize)]
+struct SessionTokens {
+    /// Sent back as `Authorization: Bearer <token>` until it expires.
+    access_token: String,
+    access_token_expires_at: DateTime<Utc>,
+    /// Exchanged at `/api/auth/refresh` for new tokens; each refresh token works once.
+    refresh_token: String,
+    refresh_token_expires_at: DateTime<Utc>,
+    user: UserProfile,
+}
+
+/// The body of a cookie login; the secret itself only travels in the cookie.
+#[derive(Serialize)]
+struct CookieSessionResponse {
+    session_id: Uuid,
+    expires_at: DateTime<Utc>,
+    user: UserProfile,
+}
+
+#[derive(Serialize)]
+struct AccountDeletionResponse {
+    /// When the account goes for good, unless its owner signs in again before then.
+    delete_after: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    #[serde(flatten)]
+    session: SessionInfo,
+    /// Whether the request was made with this session.
+    current: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiKeyRequest {
+    name: String,
+    #[serde(default)]
+    read_only: bool,
+    /// Limits the key to these documents.
+    documents: Option<Vec<Uuid>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Validate for ApiKeyRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors
+            .check(
+                "name",
+                validation::length(&self.name, 1, api_keys::MAX_KEY_NAME_LENGTH),
+                FieldError::new("invalid-api-key-name").with_arg("max", api_keys::MAX_KEY_NAME_LENGTH),
+            )
+            .check(
+                "documents",
+                self.documents.as_ref().is_none_or(|documents| (1..=api_keys::MAX_KEY_DOCUMENTS).contains(&documents.len())),
+                FieldError::new("invalid-api-key-documents").with_arg("max", api_keys::MAX_KEY_DOCUMENTS),
+            )
+            .check(
+                "expires_at",
+                self.expires_at.is_none_or(|expires_at| expires_at > Utc::now()),
+                FieldError::new("invalid-api-key-expiry"),
+            );
+    }
+}
+
+#[derive(Serialize)]
+struct ApiKeyResponse {
+    #[serde(flatten)]
+    key: ApiKey,
+    /// Sent as `Authorization: Bearer <secret>`. Only ever shown here.
+    secret: String,
+}
+
+/// What a client asks for when it sends a user to `/app/oauth/authorize`; the web client
+/// passes it on unchanged.
+#[derive(Deserialize)]
+struct AuthorizationParams {
+    response_type: String,
+    client_id: Uuid,
+    redirect_uri: String,
+    scope: String,
+    state: Option<String>,
+    code_challenge: String,
+    code_challenge_method: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AuthorizationDecision {
+    #[serde(flatten)]
+    params: AuthorizationParams,
+    approve: bool,
+}
+
+impl Validate for AuthorizationDecision {}
+
+#[derive(Serialize)]
+struct AuthorizationPrompt {
+    client_id: Uuid,
+    client_name: String,
+    scopes: Vec<OAuthScope>,
+    /// Whether the user already granted every scope asked for, so the web client may
+    /// approve without asking again.
+    granted: bool,
+}
+
+#[derive(Serialize)]
+struct AuthorizationRedirect {
+    /// Where to send the user's browser next.
+    redirect_to: String,
+}
+
+/// A form-encoded token request, per RFC 6749 section 4.1.3.
+#[derive(Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    client_id: Uuid,
+    code_verifier: String,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    scope: String,
+}
+
+/// A form-encoded introspection or revocation request.
+#[derive(Deserialize)]
+struct TokenParam {
+    token: String,
+}
+
+/// Per RFC 7662; tokens that are not active get only `"active": false`.
+#[derive(Default, Serialize)]
+struct IntrospectionResponse {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct EventsAccepted {
+    accepted: usize,
+}
+
+/// Control frames sent as text on a document WebSocket; content travels as binary frames.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerFrame<'a> {
+    SaveStatus(&'a SaveStatus),
+    Annotations(&'a AnnotationSet),
+    ProtectedRanges { ranges: &'a [ProtectedRange] },
+    /// Everyone already present, sent once on connecting.
+    Participants { participants: &'a [Participant] },
+    Presence(&'a PresenceChange),
+    Comment(&'a CommentChange),
+    Error { code: &'static str, message: String },
+    /// The room is moving to another server. Clients should stop sending updates, and once
+    /// the socket closes reconnect after `reconnect_in_ms`, resume with sync-step-1 and
+    /// resend any edits not reflected in the content `checksum` names.
+    Migrate { checksum: Option<String>, reconnect_in_ms: u64 },
+    /// The document's time ran out; the server refuses edits from now on.
+    Expired { message: String },
+    /// Sent just before the server closes the socket with `close_code`.
+    Closing { code: &'static str, message: String, close_code: u16, reconnect: bool },
+}
+
+impl ServerFrame<'_> {
+    fn error(locale: Locale, key: &'static str) -> Self {
+        ServerFrame::Error { code: key, message: i18n::message(locale, key, &[]) }
+    }
+
+    fn error_with_args(locale: Locale, key: &'static str, args: &[(&str, String)]) -> Self {
+        ServerFrame::Error { code: key, message: i18n::message(locale, key, args) }
+    }
+
+    fn to_message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).expect("server frames are always serializable"))
+    }
+
+    /// The frame as an awareness message, for clients using the framed protocol.
+    fn to_awareness(&self) -> Message {
+        let json = serde_json::to_string(self).expect("server frames are always serializable");
+        Message::Binary(protocol::encode(&SyncMessage::Awareness(json)))
+    }
+}
+
+/// Control frames a client may send as text on a document WebSocket.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    /// Owners only; answered with the document's protected ranges.
+    ChangeProtection { change: ProtectionChange },
+    /// Shares the sender's cursor, selection and display name; not answered.
+    Presence(Presence),
+    /// Keeps the sender's presence from expiring; not answered.
+    Heartbeat,
+}
+
+/// The services behind the HTTP API.
+pub struct Services {
+    pub doc_service: Arc<DocumentService>,
+    pub users: Arc<UserService>,
+    pub analytics: Arc<AnalyticsService>,
+    pub rooms: Arc<RoomRegistry>,
+    pub watches: Arc<WatchService>,
+    /// `None` when attachments are disabled.
+    pub attachments: Option<Arc<AttachmentService>>,
+    pub permissions: Arc<PermissionService>,
+    pub digests: Arc<EmailDigestService>,
+    pub search_index: Arc<SearchIndex>,
+    pub folders: Arc<FolderService>,
+    pub organizations: Arc<OrganizationService>,
+    pub archive_imports: Arc<ArchiveImports>,
+    pub oauth: Arc<OAuthService>,
+    pub api_keys: Arc<ApiKeyService>,
+    /// Checks documents before share links make them public.
+    pub moderation: Arc<ModerationService>,
+    /// Exports and deletes accounts at their owners' request.
+    pub personal_data: Arc<PersonalDataService>,
+    /// Records security-sensitive operations.
+    pub audit: Arc<AuditLog>,
+    /// `None` unless a KMS is configured, leaving organizations unable to register keys.
+    pub encryption: Option<Arc<Encryption>>,
+    /// What workspaces do to their documents on export.
+    pub export_transforms: Arc<ExportTransforms>,
+    /// The identity providers people may sign in with.
+    pub federated_login: Arc<FederatedLogin>,
+    /// Built from `ServerSettings::rate_limit` and `rate_limit_backend`.
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Counts the document WebSockets the server opens.
+    pub connections: Arc<ConnectionCounter>,
+    /// Checks run by `/admin/diagnostics`.
+    pub diagnostics: Arc<Diagnostics>,
+}
+
+pub async fn run_server(services: Services, settings: ServerSettings) -> anyhow::Result<()> {
+    let upload_limit = services.attachments.as_ref().map_or(0, |attachments| attachments.max_bytes());
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let app_state = Arc::new(AppState {
+        doc_service: services.doc_service,
+        users: services.users,
+        analytics: services.analytics,
+        rooms: services.rooms,
+        watches: services.watches,
+        attachments: services.attachments,
+        permissions: services.permissions,
+        digests: services.digests,
+        search_index: services.search_index,
+        folders: services.folders,
+        organizations: services.organizations,
+        archive_imports: services.archive_imports,
+        oauth: services.oauth,
+        api_keys: services.api_keys,
+        moderation: services.moderation,
+        personal_data: services.personal_data,
+        audit: services.audit,
+        encryption: services.encryption,
+        export_transforms: services.export_transforms,
+        federated_login: services.federated_login,
+        tokens: Arc::new(AccessTokens::new(&settings.tokens)),
+        connections: services.connections,
+        concurrency: Arc::new(ConcurrencyLimits::new(settings.concurrency)),
+        diagnostics: services.diagnostics,
+        drain: Arc::default(),
+        shutdown: shutdown_rx,
+        region: settings.region,
+        admin_token: settings.admin_token,
+    });
+
+    let limiter = services.rate_limiter;
+    // Queued after the rate limit, so requests it refuses never take up a slot.
+    let budget = |class| middleware::from_fn_with_state(app_state.concurrency.budget(class), concurrency::enforce);
+    let api = Router::new()
+        .route("/api/instance", get(instance_handler))
+        .route("/events", post(events_handler))
+        .route("/search/quick", get(quick_search_handler))
+        .route("/api/search", get(search_handler))
+        .route("/sync", post(sync_handler).layer(budget(RouteClass::Merge)))
+        .route("/api/users/register", post(register_handler))
+        .route("/api/users/login", post(login_handler))
+        .route("/api/users/verify", get(verify_email_handler))
+        .route("/api/users/password-reset/request", post(request_password_reset_handler))
+        .route("/api/users/password-reset/confirm", post(confirm_password_reset_handler))
+        .route("/api/users/magic-link/request", post(request_magic_link_handler))
+        .route("/api/users/magic-link/confirm", post(confirm_magic_link_handler))
+        .route("/api/auth/:provider/login", get(federated_login_handler))
+        .route("/api/auth/:provider/callback", get(federated_callback_handler))
+        .route("/api/users/me", delete(delete_account_handler))
+        .route("/api/users/me/export", post(export_personal_data_handler))
+        .route("/api/users/me/verify-email", post(resend_verification_handler))
+        .route("/api/users/me/sessions", get(list_sessions_handler))
+        .route("/api/users/me/sessions/:id", delete(revoke_session_handler))
+        .route("/api/users/me/usage", get(storage_usage_handler))
+        .route("/api/auth/refresh", post(refresh_handler))
+        .route("/api/auth/logout", post(logout_handler))
+        .route("/api/oauth/authorize", get(authorization_prompt_handler).post(authorize_handler))
+        .route("/api/oauth/token", post(oauth_token_handler))
+        .route("/api/oauth/introspect", post(introspect_handler))
+        .route("/api/oauth/revoke", post(oauth_revoke_handler))
+        .route("/api/users/me/oauth-consents", get(list_consents_handler))
+        .route("/api/users/me/oauth-consents/:client_id", delete(revoke_consent_handler))
+        .route("/api/users/me/api-keys", get(list_api_keys_handler).post(create_api_key_handler))
+        .route("/api/users/me/api-keys/:id", delete(revoke_api_key_handler))
+        .route("/api/guest", get(get_guest_handler).put(put_guest_handler))
+        .route("/api/documents", get(list_documents_handler).post(create_document_handler))
+        .route("/api/documents/import", post(import_document_handler).layer(budget(RouteClass::Import)))
+        .route(
+            "/api/imports",
+            get(list_archive_imports_handler)
+                .post(start_archive_import_handler)
+                .layer(DefaultBodyLimit::max(archive_import::MAX_ARCHIVE_BYTES)),
+        )
+        .route("/api/imports/:id", get(get_archive_import_handler))
+        .route("/api/documents/trash", get(list_trash_handler))
+        .route("/api/documents/:id", get(get_document_handler).patch(rename_document_handler).delete(delete_document_handler))
+        .route(
+            "/api/documents/:id/content",
+            put(update_content_handler).layer(DefaultBodyLimit::max(settings.max_update_bytes)),
+        )
+        .route("/api/documents/:id/export", get(export_handler).layer(budget(RouteClass::Export)))
+        .route("/api/documents/:id/versions", get(list_versions_handler).post(create_version_handler))
+        .route("/api/documents/:id/restore", post(restore_from_trash_handler))
+        .route("/api/documents/:id/deletion-requests", get(list_deletion_requests_handler))
+        .route("/api/documents/:id/deletion-requests/:request_id", post(decide_deletion_handler))
+        .route("/api/documents/:id/move", post(move_document_handler))
+        .route("/api/documents/:id/restore/:version", post(restore_version_handler))
+        .route("/api/documents/:id/comments", get(list_comments_handler).post(add_comment_handler))
+        .route("/api/documents/:id/comments/:comment_id", delete(delete_comment_handler))
+        .route("/api/documents/:id/comments/:comment_id/resolve", post(resolve_comment_handler))
+        .route("/api/documents/:id/backlinks", get(list_backlinks_handler))
+        .route("/api/documents/:id/links", get(list_links_handler))
+        .route("/api/documents/:id/permissions", get(list_permissions_handler).post(share_document_handler))
+        .route("/api/documents/:id/permissions/:user_id", delete(unshare_document_handler))
+        .route("/api/documents/:id/share-links", get(list_share_links_handler).post(create_share_link_handler))
+        .route("/api/documents/:id/share-links/:link_id", delete(revoke_share_link_handler))
+        .route("/api/folders", post(create_folder_handler))
+        .route("/api/folders/:id", get(get_folder_handler).patch(rename_folder_handler).delete(delete_folder_handler))
+        .route("/api/folders/:id/move", post(move_folder_handler))
+        .route("/api/folders/:id/deletion-approval", put(set_deletion_approval_handler))
+        .route("/api/folders/:id/children", get(folder_children_handler))
+        .route("/api/permissions/share", post(bulk_share_handler))
+        .route("/api/permissions/unshare", post(bulk_unshare_handler))
+        .route("/api/users/:id/accessible-documents", get(accessible_documents_handler))
+        .route(
+            "/api/documents/:id/attachments",
+            get(list_attachments_handler)
+                .post(upload_attachment_handler)
+                .layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route("/api/attachments/:id", get(get_attachment_handler))
+        .route("/api/attachments/:id/content", get(download_attachment_handler))
+        .route("/api/users/me/digest", get(get_digest_handler).put(set_digest_handler))
+        .route("/api/digests/unsubscribe", get(unsubscribe_handler).post(unsubscribe_handler))
+        .route("/api/organizations", get(list_organizations_handler).post(create_organization_handler))
+        .route("/api/organizations/invites/accept", post(accept_invite_handler))
+        .route("/api/organizations/:id/members", get(list_organization_members_handler))
+        .route(
+            "/api/organizations/:id/members/:user_id",
+            put(set_organization_role_handler).delete(remove_organization_member_handler),
+        )
+        .route("/api/organizations/:id/invites", get(list_invites_handler).post(invite_handler))
+        .route("/api/organizations/:id/invites/:invite_id", delete(revoke_invite_handler))
+        .route("/api/organizations/:id/teams", get(list_teams_handler).post(create_team_handler))
+        .route("/api/organizations/:id/teams/:team_id", get(list_team_members_handler).delete(delete_team_handler))
+        .route(
+            "/api/organizations/:id/encryption-key",
+            get(get_encryption_key_handler).put(set_encryption_key_handler).delete(revoke_encryption_key_handler),
+        )
+        .route(
+            "/api/organizations/:id/teams/:team_id/members/:user_id",
+            put(add_team_member_handler).delete(remove_team_member_handler),
+        )
+        .route("/api/workspaces", post(create_workspace_handler))
+        .route("/api/workspaces/:id/teams/:team_id", put(add_team_to_workspace_handler))
+        .route("/api/workspaces/:id/members/:user_id", put(set_member_handler).delete(remove_member_handler))
+        .route(
+            "/api/workspaces/:id/default-policy",
+            get(get_default_policy_handler).put(set_default_policy_handler).delete(clear_default_policy_handler),
+        )
+        .route(
+            "/api/workspaces/:id/export-transforms",
+            get(get_export_transforms_handler).put(set_export_transforms_handler).delete(clear_export_transforms_handler),
+        )
+        .route("/documents/:id/preview", get(preview_handler))
+        .route("/documents/:id/blame", get(blame_handler))
+        .route("/documents/:id/watch", put(watch_handler).delete(unwatch_handler))
+        .route("/notifications", get(notifications_handler))
+        .nest("/api/admin", admin_routes())
+        .route_layer(middleware::from_fn_with_state(limiter, rate_limit::enforce));
+
+    let mut app = Router::new()
+        .route("/", get(root_handler))
+        .route("/ws", get(websocket_handler))
+        .route("/ws/documents/:id", get(document_socket_handler))
+        .route("/admin/ws/metrics", get(metrics_socket_handler))
+        .route("/admin/diagnostics", get(diagnostics_handler))
+        .route("/admin/drain", get(drain_progress_handler).post(drain_handler))
+        .route("/ready", get(readiness_handler))
+        .route("/admin/moderation/reviews", get(moderation_queue_handler))
+        .route("/admin/moderation/reviews/:id", post(moderation_decision_handler))
+        .route("/admin/moderation/documents/:id", get(moderation_log_handler))
+        .route("/events/stream", get(event_stream_handler))
+        .merge(api);
+    if let Some(frontend) = settings.frontend {
+        app = app.merge(frontend::routes(frontend));
+    }
+    let state = app_state.clone();
+    let authenticator = Arc::new(Authenticator {
+        tokens: app_state.tokens.clone(),
+        users: app_state.users.clone(),
+        api_keys: app_state.api_keys.clone(),
+    });
+    let app = app
+        .layer(middleware::from_fn_with_state(authenticator, tokens::authenticate))
+        .layer(middleware::from_fn(api_error::localize_errors))
+        .layer(middleware::from_fn(telemetry::trace_requests))
+        .with_state(app_state);
+
+    let listener = TcpListener::bind(settings.listen_addr)
+        .await
+        .context(format!("Failed to bind HTTP listener on {}", settings.listen_addr))?;
+    info!("HTTP server listening on {}", listener.local_addr()?); // Use listener.local_addr()
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            info!("Shutting down; handing over document rooms");
+            // Metrics streams see this and close themselves; document rooms migrate below.
+            let _ = shutdown_tx.send(true);
+        })
+        .await?;
+
+    // Upgraded WebSockets outlive their HTTP connections, so the server can finish first.
+    // Rooms save before their connections close, so clients reconnect to saved content.
+    if state.drain.start() {
+        hand_over_rooms(&state).await;
+    } else {
+        // `/admin/drain` got there first; let its handover finish.
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_FLUSH_TIMEOUT;
+        while !state.drain.handed_over() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+    let deadline = tokio::time::Instant::now() + SHUTDOWN_DRAIN_TIMEOUT;
+    while state.connections.current() > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+/// Saves what the rooms hold, then closes their connections for clients to reconnect
+/// elsewhere; see `RoomRegistry::migrate`.
+async fn hand_over_rooms(state: &AppState) {
+    match state.rooms.migrate(SHUTDOWN_FLUSH_TIMEOUT).await {
+        0 => info!("All document updates saved"),
+        unsaved => info!("Handed rooms over with {} document updates unsaved", unsaved),
+    }
+    state.drain.handed_over.store(true, Ordering::Relaxed);
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+async fn root_handler() -> Html<&'static str> {
+    Html("<h1>Hello, World!</h1><p><a href='/ws'>Connect to WebSocket</a> (use a WebSocket client)</p>\n")
+}
+
+async fn instance_handler(State(state): State<Arc<AppState>>, tz: RenderTimezone) -> Json<InstanceInfo> {
+    Json(InstanceInfo {
+        region: state.region.clone(),
+        server_time: tz.render(Utc::now()),
+        timezone: tz.name(),
+    })
+}
+
+async fn quick_search_handler(
+    State(state): State<Arc<AppState>>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    query: Result<Query<QuickSearchQuery>, QueryRejection>,
+) -> Result<Json<QuickSearchResults>, ApiError> {
+    let Query(query) = query.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-search-query"))?;
+    let caller = caller(&state, user, &headers).await?;
+    let visible_documents = caller.visible_documents();
+    let mut results = search::quick_search(
+        &state.doc_service,
+        &state.search_index,
+        &state.users,
+        &query.q,
+        visible_documents.as_deref(),
+        query.limit.unwrap_or(8),
+    )
+    .await;
+    let ids: Vec<Uuid> = results.results.iter()
+        .filter(|result| result.kind == ResultKind::Document)
+        .map(|result| result.id)
+        .collect();
+    let readable = caller.readable_documents(&state, &ids).await?;
+    results.results.retain(|result| result.kind != ResultKind::Document || readable.contains(&result.id));
+    results.facets.documents = results.facets.documents.saturating_sub(ids.len() - readable.len());
+    Ok(Json(results))
+}
+
+/// Documents whose name or content matches `q`, best matches first, among those the caller
+/// may read. `?language=` keeps only documents detected to be in that language.
+async fn search_handler(
+    State(state): State<Arc<AppState>>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    query: Result<Query<SearchQuery>, QueryRejection>,
+) -> Result<Json<Vec<SearchResult>>, ApiError> {
+    let Query(query) = query.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-search-query"))?;
+    let language = query.language.as_deref()
+        .map(|tag| language::normalize(tag).ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "invalid-search-query")))
+        .transpose()?;
+    let caller = caller(&state, user, &headers).await?;
+    let visible_documents = caller.visible_documents();
+    let consistency = read_consistency(&headers, ReadConsistency::Follower)?;
+    let mut results = search::search_documents(
+        &state.doc_service,
+        &state.search_index,
+        &query.q,
+        visible_documents.as_deref(),
+        language.as_deref(),
+        consistency,
+    )
+    .await?;
+    let ids: Vec<Uuid> = results.iter().map(|result| result.id).collect();
+    let readable = caller.readable_documents(&state, &ids).await?;
+    results.retain(|result| readable.contains(&result.id));
+    results.truncate(query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE));
+    Ok(Json(results))
+}
+
+/// Creates an account and emails a link to verify its address, in the request's language.
+async fn register_handler(
+    State(state): State<Arc<AppState>>,
+    locale: Locale,
+    Valid(request): Valid<RegisterRequest>,
+) -> Result<(StatusCode, Json<UserProfile>), ApiError> {
+    match state.users.register_user(&request.display_name, &request.email, &request.password).await {
+        Ok(user) => {
+            // The account is usable regardless; the user can ask for another link.
+            if let Err(e) = state.users.send_email_verification(user.id, locale).await {
+                warn!("Failed to send verification email to user ID {}: {:#}", user.id, e);
+            }
+            // Following an invite link proves the address as well as verifying it would.
+            if let Some(secret) = &request.invite
+                && let Err(e) = state.organizations.accept_invite(secret, user.id, user.email.as_deref()).await
+            {
+                warn!("Failed to accept invite for new user ID {}: {:#}", user.id, e);
+            }
+            Ok((StatusCode::CREATED, Json(user.into())))
+        }
+        Err(e) => Err(match e.downcast_ref::<RegistrationError>() {
+            Some(RegistrationError::EmailTaken) => ApiError::new(StatusCode::CONFLICT, "email-taken"),
+            Some(RegistrationError::WeakPassword) => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "weak-password")
+                .with_arg("min", user_service::MIN_PASSWORD_LENGTH),
+            Some(RegistrationError::InvalidDisplayName) => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid-display-name")
+                .with_arg("max", user_service::MAX_DISPLAY_NAME_LENGTH),
+            Some(RegistrationError::InvalidEmail) => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid-email"),
+            None => e.into(),
+        }),
+    }
+}
+
+/// Signs in, returning tokens or, when the request asks for one, setting a session cookie.
+async fn login_handler(
+    State(state): State<Arc<AppState>>,
+    origin: RequestOrigin,
+    Valid(request): Valid<LoginRequest>,
+) -> Result<Response, ApiError> {
+    let kind = if request.cookie { SessionKind::Cookie } else { SessionKind::Token };
+    let failed = |reason: &str| AuditRecord::new(AuditAction::LoginFailed)
+        .with_target(&request.email)
+        .with_detail(json!({ "method": "password", "reason": reason }));
+    // One error for unknown accounts and wrong passwords, so logins can't probe for accounts.
+    let session = match state.users.login_with(&request.email, &request.password, kind).await {
+        Ok(Some(session)) => session,
+        Ok(None) => {
+            state.audit.record(&origin, failed("invalid_credentials")).await;
+            return Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid-credentials"));
+        }
+        Err(e) => {
+            return Err(match e.downcast_ref::<AuthenticationError>() {
+                Some(AuthenticationError::AccountLocked { until }) => {
+                    state.audit.record(&origin, failed("account_locked")).await;
+                    let minutes = (*until - Utc::now()).num_minutes() + 1;
+                    ApiError::new(StatusCode::TOO_MANY_REQUESTS, "account-locked").with_arg("minutes", minutes)
+                }
+                None => ApiError::internal(e),
+            });
+        }
+    };
+    record_login(&state, &origin, &session, "password").await;
+    session_response(&state, session, kind, HeaderMap::new())
+}
+
+async fn record_login(state: &AppState, origin: &RequestOrigin, session: &IssuedSession, method: &str) {
+    let record = AuditRecord::new(AuditAction::Login)
+        .with_actor(session.user.id)
+        .with_detail(json!({ "method": method, "session_id": session.session_id }));
+    state.audit.record(origin, record).await;
+}
+
+/// Hands a new session to the client as tokens or as a cookie, along with `headers`.
+fn session_response(state: &AppState, session: IssuedSession, kind: SessionKind, mut headers: HeaderMap) -> Result<Response, ApiError> {
+    if kind == SessionKind::Token {
+        return Ok((headers, session_tokens(state, session)?).into_response());
+    }
+    if let Some(cookie) = tokens::session_cookie(&session.secret, session.expires_at - Utc::now()) {
+        headers.insert(header::SET_COOKIE, cookie);
+    }
+    let body = CookieSessionResponse { session_id: session.session_id, expires_at: session.expires_at, user: session.user.into() };
+    Ok((headers, Json(body)).into_response())
+}
+
+/// Bytes stored by the signed-in user's documents, and their quota.
+async fn storage_usage_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<StorageUsage>, ApiError> {
+    Ok(Json(state.doc_service.storage_usage(user.user_id).await?))
+}
+
+/// Everything the service holds about the signed-in user, as a JSON file to download.
+async fn export_personal_data_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    let account = state.users.get_user(user.user_id).await?.ok_or_else(|| ApiError::not_found("user-not-found"))?;
+    let export = state.personal_data.export(&account).await?;
+    let mut response = Json(export).into_response();
+    let disposition = format!("attachment; filename=\"collaborate-export-{}.json\"", account.id);
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        response.headers_mut().insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok(response)
+}
+
+/// Deactivates the signed-in user's account and schedules it for deletion. Signing in
+/// again before `delete_after` cancels the deletion. Only a session can do this, not an
+/// API key, and the session cookie is removed.
+async fn delete_account_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Json<AccountDeletionResponse>), ApiError> {
+    require_session(&user, "account-deletion-forbidden")?;
+    let delete_after = state.personal_data.request_deletion(user.user_id).await?
+        .ok_or_else(|| ApiError::new(StatusCode::FORBIDDEN, "account-deletion-forbidden"))?;
+    let mut response_headers = HeaderMap::new();
+    if let Some(secret) = tokens::cookie(&headers, tokens::SESSION_COOKIE)
+        && let Some(cookie) = tokens::session_cookie(secret, TimeDelta::zero())
+    {
+        response_headers.insert(header::SET_COOKIE, cookie);
+    }
+    Ok((StatusCode::ACCEPTED, response_headers, Json(AccountDeletionResponse { delete_after })))
+}
+
+/// The signed-in user's live sessions, newest first.
+async fn list_sessions_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<SessionResponse>>, ApiError> {
+    let sessions = state.users.list_sessions(user.user_id).await?;
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| SessionResponse { current: Some(session.id) == user.session_id(), session })
+            .collect(),
+    ))
+}
+
+/// Ends one of the signed-in user's sessions, e.g. on a lost device. Ending the current
+/// cookie session also removes the cookie, which makes this the way to log out of one.
+async fn revoke_session_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Path(session_id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap), ApiError> {
+    if !state.users.revoke_session(user.user_id, session_id).await? {
+        return Err(ApiError::not_found("session-not-found"));
+    }
+    let mut response_headers = HeaderMap::new();
+    if Some(session_id) == user.session_id()
+        && let Some(secret) = tokens::cookie(&headers, tokens::SESSION_COOKIE)
+        && let Some(cookie) = tokens::session_cookie(secret, TimeDelta::zero())
+    {
+        response_headers.insert(header::SET_COOKIE, cookie);
+    }
+    Ok((StatusCode::NO_CONTENT, response_headers))
+}
+
+async fn refresh_handler(
+    State(state): State<Arc<AppState>>,
+    Valid(request): Valid<RefreshRequest>,
+) -> Result<Json<SessionTokens>, ApiError> {
+    let session = state.users.refresh_session(&request.refresh_token).await?
+        .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "invalid-token"))?;
+    session_tokens(&state, session)
+}
+
+/// Ends the session; access tokens already issued for it lapse when they expire.
+async fn logout_handler(
+    State(state): State<Arc<AppState>>,
+    Valid(request): Valid<RefreshRequest>,
+) -> Result<StatusCode, ApiError> {
+    state.users.end_session(&request.refresh_token).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Refuses requests made with an API key rather than a session, failing with `message_key`.
+fn require_session(user: &AuthenticatedUser, message_key: &'static str) -> Result<(), ApiError> {
+    match user.session_id() {
+        Some(_) => Ok(()),
+        None => Err(ApiError::new(StatusCode::FORBIDDEN, message_key)),
+    }
+}
+
+/// Checks a client's authorization request before the user is asked about it.
+async fn check_authorization(state: &AppState, params: &AuthorizationParams) -> Result<(OAuthClient, Vec<OAuthScope>), ApiError> {
+    // Never redirect anywhere the client did not register.
+    let client = state.oauth.client(params.client_id).await?
+        .filter(|client| client.redirect_uris.contains(&params.redirect_uri))
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "oauth-unknown-client"))?;
+    if params.response_type != "code" {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "oauth-unsupported-response-type"));
+    }
+    if params.code_challenge_method.as_deref() != Some("S256") || !oauth::is_code_challenge(&params.code_challenge) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "oauth-pkce-required"));
+    }
+    let scopes = oauth::parse_scopes(&params.scope)
+        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "oauth-invalid-scope"))?;
+    Ok((client, scopes))
+}
+
+/// Describes a client's authorization request so the web client can ask the signed-in
+/// user to approve it.
+async fn authorization_prompt_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    query: Result<Query<AuthorizationParams>, QueryRejection>,
+) -> Result<Json<AuthorizationPrompt>, ApiError> {
+    let Query(params) = query.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "oauth-invalid-request"))?;
+    let (client, scopes) = check_authorization(&state, &params).await?;
+    let granted = state.oauth.granted_scopes(user.user_id, client.id).await?;
+    Ok(Json(AuthorizationPrompt {
+        client_id: client.id,
+        client_name: client.name,
+        granted: scopes.iter().all(|scope| granted.contains(scope)),
+        scopes,
+    }))
+}
+
+/// Records the signed-in user's answer to an authorization request and says where to send
+/// them: back to the client with a code, or with `access_denied`.
+async fn authorize_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Valid(decision): Valid<AuthorizationDecision>,
+) -> Result<Json<AuthorizationRedirect>, ApiError> {
+    // An access token granted with a key would outlive the key's revocation.
+    require_session(&user, "oauth-authorize-forbidden")?;
+    let params = &decision.params;
+    let (client, scopes) = check_authorization(&state, params).await?;
+    let answer = if decision.approve {
+        let code = state.oauth.authorize(user.user_id, client.id, &params.redirect_uri, &scopes, &params.code_challenge).await?;
+        ("code", code)
+    } else {
+        ("error", "access_denied".to_string())
+    };
+    let mut query = vec![(answer.0, answer.1.as_str())];
+    if let Some(client_state) = &params.state {
+        query.push(("state", client_state));
+    }
+    Ok(Json(AuthorizationRedirect { redirect_to: oauth::redirect_with(&params.redirect_uri, &query)? }))
+}
+
+/// Token responses must not be cached (RFC 6749 section 5.1).
+fn no_store_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers
+}
+
+/// Trades an authorization code and its PKCE verifier for an access token.
+async fn oauth_token_handler(
+    State(state): State<Arc<AppState>>,
+    form: Result<Form<TokenRequest>, FormRejection>,
+) -> Result<(HeaderMap, Json<TokenResponse>), ApiError> {
+    let Form(request) = form.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "oauth-invalid-request"))?;
+    if request.grant_type != "authorization_code" {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "oauth-unsupported-grant-type"));
+    }
+    let issued = state.oauth
+        .exchange_code(request.client_id, &request.code, &request.redirect_uri, &request.code_verifier)
+        .await?
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "oauth-invalid-grant"))?;
+    Ok((
+        no_store_headers(),
+        Json(TokenResponse {
+            access_token: issued.token,
+            token_type: "Bearer",
+            expires_in: (issued.expires_at - Utc::now()).num_seconds(),
+            scope: oauth::format_scopes(&issued.scopes),
+        }),
+    ))
+}
+
+/// Reports whether an access token is live and what it grants. Presenting the token is
+/// the only credential needed, so this reveals nothing its holder could not find out.
+async fn introspect_handler(
+    State(state): State<Arc<AppState>>,
+    form: Result<Form<TokenParam>, FormRejection>,
+) -> Result<(HeaderMap, Json<IntrospectionResponse>), ApiError> {
+    let Form(request) = form.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "oauth-invalid-request"))?;
+    let response = match state.oauth.authenticate(&request.token).await? {
+        Some(delegation) => IntrospectionResponse {
+            active: true,
+            scope: Some(oauth::format_scopes(&delegation.scopes)),
+            client_id: Some(delegation.client_id),
+            sub: Some(delegation.user_id),
+            exp: Some(delegation.expires_at.timestamp()),
+            token_type: Some("Bearer"),
+        },
+        None => IntrospectionResponse::default(),
+    };
+    Ok((no_store_headers(), Json(response)))
+}
+
+/// Revokes an access token. Unknown tokens succeed too, as RFC 7009 requires.
+async fn oauth_revoke_handler(
+    State(state): State<Arc<AppState>>,
+    form: Result<Form<TokenParam>, FormRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Form(request) = form.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "oauth-invalid-request"))?;
+    state.oauth.revoke_token(&request.token).await?;
+    Ok(StatusCode::OK)
+}
+
+/// The OAuth clients the signed-in user has granted access, most recent first.
+async fn list_consents_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<Consent>>, ApiError> {
+    Ok(Json(state.oauth.list_consents(user.user_id).await?))
+}
+
+/// Withdraws the signed-in user's consent for a client, which also revokes its tokens.
+async fn revoke_consent_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Path(client_id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    if !state.oauth.revoke_consent(user.user_id, client_id).await? {
+        return Err(ApiError::not_found("oauth-consent-not-found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The signed-in user's live API keys, newest first.
+async fn list_api_keys_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    Ok(Json(state.api_keys.list(user.user_id).await?))
+}
+
+/// Creates an API key for the signed-in user. Keys can't be used to create more keys, so a
+/// leaked key can't outlive its revocation.
+async fn create_api_key_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    origin: RequestOrigin,
+    Valid(request): Valid<ApiKeyRequest>,
+) -> Result<(StatusCode, Json<ApiKeyResponse>), ApiError> {
+    require_session(&user, "api-key-forbidden")?;
+    let issued = state
+        .api_keys
+        .create(user.user_id, &request.name, request.read_only, request.documents.as_deref(), request.expires_at)
+        .await?
+        .ok_or_else(|| ApiError::new(StatusCode::CONFLICT, "too-many-api-keys").with_arg("max", api_keys::MAX_KEYS_PER_USER))?;
+    let record = AuditRecord::new(AuditAction::ApiKeyCreated)
+        .with_actor(user.user_id)
+        .with_target(issued.key.id)
+        .with_detail(json!({ "read_only": request.read_only, "documents": request.documents, "expires_at": request.expires_at }));
+    state.audit.record(&origin, record).await;
+    Ok((StatusCode::CREATED, Json(ApiKeyResponse { key: issued.key, secret: issued.secret })))
+}
+
+/// Revokes one of the signed-in user's API keys; sockets opened with it are closed.
+async fn revoke_api_key_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    origin: RequestOrigin,
+    Path(key_id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    if !state.api_keys.revoke(user.user_id, key_id).await? {
+        return Err(ApiError::not_found("api-key-not-found"));
+    }
+    state.audit.record(&origin, AuditRecord::new(AuditAction::ApiKeyRevoked).with_actor(user.user_id).with_target(key_id)).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The guest identity the request's cookie belongs to.
+async fn get_guest_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Json<Guest>, ApiError> {
+    let guest = match guest_cookie(&headers) {
+        Some(secret) => state.users.authenticate_guest(secret).await?,
+        None => None,
+    };
+    guest.map(Json).ok_or_else(|| ApiError::not_found("guest-not-found"))
+}
+
+/// Picks the name and cursor colour a collaborator without an account appears under. The
+/// first call creates the guest and sets its cookie; later calls with the cookie change it.
+async fn put_guest_handler(
+    State(state): State<Arc<AppState>>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    Valid(request): Valid<GuestRequest>,
+) -> Result<(StatusCode, HeaderMap, Json<Guest>), ApiError> {
+    if user.is_some() {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "guest-signed-in"));
+    }
+    let current = match guest_cookie(&headers) {
+        Some(secret) => state.users.authenticate_guest(secret).await?.map(|guest| (guest, secret.to_string())),
+        None => None,
+    };
+    let (status, guest, secret) = match current {
+        Some((guest, secret)) => {
+            let guest = state.users.update_guest(guest.id, &request.display_name, &request.color).await?
+                .ok_or_else(|| ApiError::not_found("guest-not-found"))?;
+            (StatusCode::OK, guest, secret)
+        }
+        None => {
+            let issued = state.users.create_guest(&request.display_name, &request.color).await?;
+            (StatusCode::CREATED, issued.guest, issued.secret)
+        }
+    };
+    // Setting the cookie again each time keeps an active guest's identity from expiring.
+    let cookie = format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Lax",
+        GUEST_COOKIE,
+        secret,
+        GUEST_COOKIE_MAX_AGE.as_secs(),
+    );
+    let mut response_headers = HeaderMap::new();
+    if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+        response_headers.insert(header::SET_COOKIE, cookie);
+    }
+    Ok((status, response_headers, Json(guest)))
+}
+
+/// The guest token in the request's cookies, if any.
+fn guest_cookie(headers: &HeaderMap) -> Option<&str> {
+    tokens::cookie(headers, GUEST_COOKIE)
+}
+
+fn session_tokens(state: &AppState, session: IssuedSession) -> Result<Json<SessionTokens>, ApiError> {
+    let access = state.tokens.issue(session.user.id, session.session_id)?;
+    Ok(Json(SessionTokens {
+        access_token: access.token,
+        access_token_expires_at: access.expires_at,
+        refresh_token: session.secret,
+        refresh_token_expires_at: session.expires_at,
+        user: session.user.into(),
+    }))
+}
+
+async fn create_document_handler(
+    State(state): State<Arc<AppState>>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    Valid(request): Valid<CreateDocumentRequest>,
+) -> Result<(StatusCode, HeaderMap, Json<DocumentResponse>), ApiError> {
+    let caller = caller(&state, user, &headers).await?;
+    // Bot scopes name existing documents, so bots cannot create new ones; OAuth clients
+    // only read and comment.
+    if let Caller::Bot(_) | Caller::Delegated(_) = caller {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-create-forbidden"));
+    }
+    let name = request.name.trim();
+    if let Some(workspace_id) = request.workspace_id {
+        let member = match caller.owner() {
+            Some(user_id) => state.permissions.member_role(workspace_id, user_id).await?.is_some(),
+            None => false,
+        };
+        if !member {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "workspace-member-required"));
+        }
+    }
+    let metadata = state.doc_service.create_document_with(name, caller.owner(), request.workspace_id, request.expires_at).await
+        .map_err(name_taken_error)?;
+    let headers = causality_headers(metadata.causality_token());
+    Ok((StatusCode::CREATED, headers, Json(DocumentResponse { expires_at: request.expires_at, ..metadata.into() })))
+}
+
+/// A page of documents: the caller's own when signed in, those a bot's scopes name, or for
+/// anonymous callers those anyone may use and the one a share link opens. Pass `next_cursor` back as `?cursor=` for the next page,
+/// and `?language=` to list only documents detected to be in that language.
+async fn list_documents_handler(
+    State(state): State<Arc<AppState>>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    query: Result<Query<ListDocumentsQuery>, QueryRejection>,
+) -> Result<Json<DocumentListResponse>, ApiError> {
+    let Query(query) = query.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-listing-query"))?;
+    let language = query.language.as_deref()
+        .map(|tag| language::normalize(tag).ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "invalid-listing-query")))
+        .transpose()?;
+    let cursor = query.cursor.as_deref()
+        .map(DocumentCursor::decode)
+        .transpose()
+        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-cursor"))?;
+    let caller = caller(&state, user, &headers).await?;
+    let visible_documents = caller.visible_documents();
+    let public_documents = caller.public_documents();
+    // Listings tolerate slightly stale data unless the client asks to see its own write.
+    let consistency = read_consistency(&headers, ReadConsistency::Follower)?;
+    let page = state.doc_service
+        .list_documents(
+            &DocumentFilter {
+                owner: caller.owner(),
+                restrict_to: visible_documents.as_deref(),
+                public_and: public_documents.as_deref(),
+                language: language.as_deref(),
+            },
+            cursor.as_ref(),
+            query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+            query.sort.unwrap_or_default(),
+            consistency,
+        )
+        .await
+        .map_err(|e| match e.downcast_ref::<InvalidCursor>() {
+            Some(_) => ApiError::new(StatusCode::BAD_REQUEST, "invalid-cursor"),
+            None => e.into(),
+        })?;
+    Ok(Json(DocumentListResponse {
+        documents: page.documents.into_iter().map(DocumentResponse::from).collect(),
+        next_cursor: page.next_cursor.map(|cursor| cursor.encode()),
+    }))
+}
+
+/// Creates a document from uploaded content, reporting existing documents with the same
+/// or nearly the same content. With `"on_duplicate": "link"`, an identical existing
+/// document is returned instead of creating a copy.
+async fn import_document_handler(
+    State(state): State<Arc<AppState>>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    Valid(request): Valid<ImportRequest>,
+) -> Result<(StatusCode, Json<ImportResponse>), ApiError> {
+    let caller = caller(&state, user, &headers).await?;
+    if let Caller::Bot(_) | Caller::Delegated(_) = caller {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-create-forbidden"));
+    }
+    let name = request.name.trim();
+    let content = BASE64.decode(&request.content)
+        .map_err(|_| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid-document-content"))?;
+    let visible_documents = caller.visible_documents();
+    let mut duplicates = import::find_duplicates(&state.doc_service, &content, visible_documents.as_deref()).await?;
+    let ids: Vec<Uuid> = duplicates.iter().map(|candidate| candidate.metadata.id).collect();
+    let readable = caller.readable_documents(&state, &ids).await?;
+    duplicates.retain(|candidate| readable.contains(&candidate.metadata.id));
+    let response = import::import(&state.doc_service, name, content, caller.owner(), request.on_duplicate, duplicates).await?;
+    let status = if response.created { StatusCode::CREATED } else { StatusCode::OK };
+    Ok((status, Json(response)))
+}
+
+#[derive(Deserialize)]
+struct ArchiveImportQuery {
+    source: ExportSource,
+    workspace_id: Option<Uuid>,
+    /// Imports into this folder rather than the top of the workspace or personal documents.
+    folder_id: Option<Uuid>,
+}
+
+/// Starts importing a Confluence or Notion export sent as the raw ZIP body. The import
+/// runs in the background; poll `/api/imports/:id` for its progress and report.
+async fn start_archive_import_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    query: Result<Query<ArchiveImportQuery>, QueryRejection>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<ImportJob>), ApiError> {
+    let Query(query) = query.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-import-query"))?;
+    let workspace_id = match (query.folder_id, query.workspace_id) {
+        (Some(folder_id), _) => organizable_folder(&state, folder_id, user.user_id).await?.workspace_id,
+        (None, Some(workspace_id)) => {
+            if state.permissions.member_role(workspace_id, user.user_id).await?.is_none() {
+                return Err(ApiError::new(StatusCode::FORBIDDEN, "workspace-member-required"));
+            }
+            Some(workspace_id)
+        }
+        (None, None) => None,
+    };
+    if body.is_empty() {
+        return Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "import-archive-empty"));
+    }
+    let destination = ImportDestination { owner_id: user.user_id, workspace_id, folder_id: query.folder_id };
+    let budget = state.concurrency.budget(RouteClass::Import);
+    let job = state.archive_imports.start(query.source, body.to_vec(), destination, budget).await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// The signed-in user's recent imports, newest first.
+async fn list_archive_imports_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<ImportJob>>, ApiError> {
+    Ok(Json(state.archive_imports.jobs_of(user.user_id, 50).await?))
+}
+
+/// An import's progress, and once it has finished, what it created and skipped.
+async fn get_archive_import_handler(
+    State(state): State<Arc<AppState>>,
+    Path(job_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<Json<ImportJob>, ApiError> {
+    state.archive_imports.get(job_id).await?
+        .filter(|job| job.owner_id == user.user_id)
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("import-job-not-found"))
+}
+
+/// A document with its saved content. Send back a `X-Causality-Token` from an earlier
+/// write to be sure of seeing it.
+async fn get_document_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Json<DocumentResponse>, ApiError> {
+    let access = caller(&state, user, &headers).await?.document_access(&state, doc_id).await?;
+    if !access.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    let consistency = read_consistency(&headers, ReadConsistency::Strong)?;
+    let document = state.doc_service.get_document_with(doc_id, consistency).await?
+        .ok_or_else(|| ApiError::not_found("document-not-found"))?;
+    let content = document.content.map(|content| BASE64.encode(&content.crdt_data)).unwrap_or_default();
+    let expires_at = state.doc_service.document_expiry(doc_id).await?;
+    Ok(Json(DocumentResponse { content: Some(content), expires_at, ..document.metadata.into() }))
+}
+
+#[derive(Deserialize)]
+struct BlameQuery {
+    /// Byte offsets `start-end` into the saved content; the whole of it if left out.
+    range: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BlameResponse {
+    /// Length of the saved content.
+    length: usize,
+    spans: Vec<BlameSpan>,
+}
+
+/// Parses `start-end`, with `end` past the last byte wanted.
+fn parse_blame_range(range: &str) -> Option<Range<usize>> {
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    (start <= end).then_some(start..end)
+}
+
+/// Who wrote each part of the document's saved content, e.g. a paragraph given its
+/// offsets. Edits still waiting in the document's room are not included.
+async fn blame_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    query: Result<Query<BlameQuery>, QueryRejection>,
+) -> Result<Json<BlameResponse>, ApiError> {
+    let Query(query) = query.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-blame-range"))?;
+    let range = match query.range.as_deref() {
+        Some(range) => parse_blame_range(range).ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "invalid-blame-range"))?,
+        None => 0..usize::MAX,
+    };
+    if !caller(&state, user, &headers).await?.document_access(&state, doc_id).await?.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    let attribution = state.doc_service.blame(doc_id).await?
+        .ok_or_else(|| ApiError::not_found("document-not-found"))?;
+    Ok(Json(BlameResponse { length: attribution.len(), spans: attribution.spans(range) }))
+}
+
+/// A small HTML rendering of the document's opening lines, for listings.
+async fn preview_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if !caller(&state, user, &headers).await?.document_access(&state, doc_id).await?.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    let html = state.doc_service.get_document_preview(doc_id).await?
+        .ok_or_else(|| ApiError::not_found("preview-unavailable"))?;
+    let etag = format!("\"{}\"", integrity::checksum(html.as_bytes()));
+    let mut response = if headers.get(header::IF_NONE_MATCH).is_some_and(|tag| tag.as_bytes() == etag.as_bytes()) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        Html(html).into_response()
+    };
+    let response_headers = response.headers_mut();
+    // Previews change with every save, so clients must revalidate; the ETag keeps that cheap.
+    response_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("private, no-cache"));
+    response_headers.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static("default-src 'none'"));
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        response_headers.insert(header::ETAG, etag);
+    }
+    Ok(response)
+}
+
+/// The whole document as an HTML page to download. `?profile=a11y` lays it out for
+/// screen readers; see `export`.
+async fn export_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    locale: Locale,
+    query: Result<Query<ExportQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Query(query) = query.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-export-query"))?;
+    if !caller(&state, user, &headers).await?.document_access(&state, doc_id).await?.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    let document = state.doc_service.get_document_with(doc_id, ReadConsistency::Strong).await?
+        .ok_or_else(|| ApiError::not_found("document-not-found"))?;
+    let attachments = match &state.attachments {
+        Some(attachments) => attachments.list(doc_id).await?,
+        None => Vec::new(),
+    };
+    let mut content = document.content.map(|content| content.crdt_data).unwrap_or_default();
+    let pipeline = state.export_transforms.for_document(doc_id).await?;
+    if let Some(pipeline) = &pipeline {
+        content = pipeline.redact(content).map_err(|e| transform_error(doc_id, e))?;
+    }
+    let mut html = export::render(&document.metadata, &content, &attachments, query.profile, locale)
+        .ok_or_else(|| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "export-unavailable"))?;
+    if let Some(pipeline) = &pipeline {
+        html = pipeline.finish(&document.metadata, html).await.map_err(|e| transform_error(doc_id, e))?;
+    }
+    let mut response = Html(html).into_response();
+    let response_headers = response.headers_mut();
+    // Attachment images load from this origin; nothing else, and no script, may.
+    response_headers.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static("default-src 'none'; img-src 'self'"));
+    let file_name: String = document.metadata.name
+        .chars()
+        .filter(|c| (c.is_ascii_graphic() || *c == ' ') && !matches!(c, '"' | '\\'))
+        .collect();
+    if let Ok(disposition) = HeaderValue::from_str(&format!("attachment; filename=\"{}.html\"", file_name.trim())) {
+        response_headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok(response)
+}
+
+/// A workspace's transform failing fails the export rather than handing out an
+/// unredacted or unprocessed copy.
+fn transform_error(doc_id: Uuid, e: anyhow::Error) -> ApiError {
+    warn!(document_id = %doc_id, "Export transform failed: {:#}", e);
+    ApiError::new(StatusCode::BAD_GATEWAY, "export-transform-failed")
+}
+
+/// Replaces a document's content. The change goes through the document's room like an
+/// edit from a live connection, so connected editors receive it and it is saved with
+/// their edits; it is acknowledged before it is persisted.
+async fn update_content_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    Valid(request): Valid<DocumentContentRequest>,
+) -> Result<StatusCode, ApiError> {
+    let caller = caller(&state, user, &headers).await?;
+    let access = caller.document_access(&state, doc_id).await?;
+    if !access.write {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "update-forbidden"));
+    }
+    let content = BASE64.decode(&request.content)
+        .map_err(|_| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid-document-content"))?;
+    if state.doc_service.get_document_metadata(doc_id).await?.is_none() {
+        return Err(ApiError::not_found("document-not-found"));
+    }
+    let (room, _events) = state.rooms.join(doc_id);
+    let origin = state.rooms.next_connection_id();
+    room.submit_update(origin, caller.author(), access.role, content).await.map_err(|e| update_error(doc_id, e))?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Saved versions of a document, newest first.
+async fn list_versions_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<DocumentVersion>>, ApiError> {
+    if !caller(&state, user, &headers).await?.document_access(&state, doc_id).await?.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    if state.doc_service.get_document_metadata(doc_id).await?.is_none() {
+        return Err(ApiError::not_found("document-not-found"));
+    }
+    Ok(Json(state.doc_service.list_versions(doc_id).await?))
+}
+
+/// Saves the document's current state as a version.
+async fn create_version_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<DocumentVersion>), ApiError> {
+    let caller = caller(&state, user, &headers).await?;
+    if !caller.document_access(&state, doc_id).await?.write {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "update-forbidden"));
+    }
+    let version = state.doc_service.create_version(doc_id, caller.author()).await?
+        .ok_or_else(|| ApiError::not_found("document-not-found"))?;
+    Ok((StatusCode::CREATED, Json(version)))
+}
+
+/// Rolls a document back to a saved version. The current state is saved as a version first,
+/// and the restored content goes through the document's room like any other update, so
+/// connected editors see it.
+async fn restore_version_handler(
+    State(state): State<Arc<AppState>>,
+    Path((doc_id, version)): Path<(Uuid, i64)>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let caller = caller(&state, user, &headers).await?;
+    let access = caller.document_access(&state, doc_id).await?;
+    if !access.write {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "update-forbidden"));
+    }
+    let content = state.doc_service.get_version_content(doc_id, version).await?
+        .ok_or_else(|| ApiError::not_found("version-not-found"))?;
+    let (room, _events) = state.rooms.join(doc_id);
+    // The room's content includes updates that may not be persisted yet.
+    let current = room.snapshot().await.map_err(|e| update_error(doc_id, e))?.unwrap_or_default();
+    state.doc_service.save_version(doc_id, &current, caller.author()).await?
+        .ok_or_else(|| ApiError::not_found("document-not-found"))?;
+    let origin = state.rooms.next_connection_id();
+    room.submit_update(origin, caller.author(), access.role, content).await.map_err(|e| update_error(doc_id, e))?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn rename_document_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    Valid(request): Valid<DocumentNameRequest>,
+) -> Result<(HeaderMap, Json<DocumentResponse>), ApiError> {
+    require_owner(caller(&state, user, &headers).await?.document_access(&state, doc_id).await?)?;
+    let name = request.name.trim();
+    let metadata = state.doc_service.rename_document(doc_id, name).await
+        .map_err(name_taken_error)?
+        .ok_or_else(|| ApiError::not_found("document-not-found"))?;
+    Ok((causality_headers(metadata.causality_token()), Json(metadata.into())))
+}
+
+fn name_taken_error(e: anyhow::Error) -> ApiError {
+    match e.downcast_ref::<NameTaken>() {
+        Some(taken) => ApiError::new(StatusCode::CONFLICT, "document-name-taken")
+            .with_arg("name", &taken.name)
+            .with_arg("suggestions", taken.suggestions.join(", ")),
+        None => e.into(),
+    }
+}
+
+/// Moves a document to the trash. It can be restored until the trash is purged. In a folder
+/// marked as needing a second approval, the document's other owners are asked to approve
+/// instead, and the pending request is returned with 202 Accepted.
+async fn delete_document_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let caller = caller(&state, user, &headers).await?;
+    require_owner(caller.document_access(&state, doc_id).await?)?;
+    let request = match state.doc_service.request_deletion(doc_id, caller.author()).await? {
+        None => return Err(ApiError::not_found("document-not-found")),
+        Some(Deletion::Trashed) => {
+            notify_room(&state, doc_id, RoomNotice::Closing(CloseCode::DocumentDeleted)).await;
+            return Ok(StatusCode::NO_CONTENT.into_response());
+        }
+        Some(Deletion::Requested(request)) => {
+            let owners = state.permissions.document_owners(doc_id).await?;
+            let approvers: Vec<Uuid> = owners.into_iter().filter(|owner| Some(*owner) != request.requested_by).collect();
+            notify_deletion(&state, &approvers, DELETION_REQUESTED, &request).await;
+            request
+        }
+        Some(Deletion::Pending(request)) => request,
+    };
+    Ok((StatusCode::ACCEPTED, Json(request)).into_response())
+}
+
+/// Every deletion requested for a document, pending or decided, most recent first.
+async fn list_deletion_requests_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<DeletionRequest>>, ApiError> {
+    require_owner(caller(&state, user, &headers).await?.document_access(&state, doc_id).await?)?;
+    Ok(Json(state.doc_service.deletion_requests(doc_id).await?))
+}
+
+/// Approves or rejects a pending deletion. Any owner but whoever asked may approve; they
+/// may only withdraw it by rejecting.
+async fn decide_deletion_handler(
+    State(state): State<Arc<AppState>>,
+    Path((doc_id, request_id)): Path<(Uuid, Uuid)>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    Valid(request): Valid<DeletionDecisionRequest>,
+) -> Result<Json<DeletionRequest>, ApiError> {
+    let caller = caller(&state, user, &headers).await?;
+    require_owner(caller.document_access(&state, doc_id).await?)?;
+    let decided_by = caller.author();
+    let decided = match state.doc_service.decide_deletion(doc_id, request_id, decided_by, request.decision).await {
+        Ok(decided) => decided.ok_or_else(|| ApiError::not_found("deletion-request-not-found"))?,
+        Err(e) if e.downcast_ref::<SelfApproval>().is_some() => {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "deletion-self-approval"));
+        }
+        Err(e) => return Err(ApiError::internal(e)),
+    };
+    if decided.status == DeletionStatus::Approved {
+        notify_room(&state, doc_id, RoomNotice::Closing(CloseCode::DocumentDeleted)).await;
+    }
+    if let Some(requested_by) = decided.requested_by
+        && Some(requested_by) != decided_by
+    {
+        notify_deletion(&state, &[requested_by], DELETION_DECIDED, &decided).await;
+    }
+    Ok(Json(decided))
+}
+
+/// Tells users about a deletion request. It has already been made or decided, so failures
+/// are only logged.
+async fn notify_deletion(state: &AppState, user_ids: &[Uuid], kind: &str, request: &DeletionRequest) {
+    let payload = serde_json::to_value(request).expect("deletion requests are always serializable");
+    if let Err(e) = state.watches.notify(user_ids, kind, payload).await {
+        warn!("Failed to notify owners of document ID {} about deletion request ID {}: {:#}", request.document_id, request.id, e);
+    }
+}
+
+/// The caller's deleted documents that can still be restored, most recently deleted first.
+async fn list_trash_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<TrashedDocumentResponse>>, ApiError> {
+    let trash = state.doc_service.list_trash(user.user_id).await?;
+    Ok(Json(trash.into_iter().map(TrashedDocumentResponse::from).collect()))
+}
+
+/// Takes a document out of the trash, renaming it if its name was taken meanwhile.
+async fn restore_from_trash_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<(HeaderMap, Json<DocumentResponse>), ApiError> {
+    require_owner(caller(&state, user, &headers).await?.document_access(&state, doc_id).await?)?;
+    let metadata = state.doc_service.restore_from_trash(doc_id).await
+        .map_err(name_taken_error)?
+        .ok_or_else(|| ApiError::not_found("document-not-in-trash"))?;
+    Ok((causality_headers(metadata.causality_token()), Json(metadata.into())))
+}
+
+/// Moves a document into a folder, or to the top of its workspace or personal documents.
+async fn move_document_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    headers: HeaderMap,
+    Valid(request): Valid<MoveDocumentRequest>,
+) -> Result<StatusCode, ApiError> {
+    require_owner(caller(&state, Some(user), &headers).await?.document_access(&state, doc_id).await?)?;
+    if let Some(folder_id) = request.folder_id {
+        organizable_folder(&state, folder_id, user.user_id).await?;
+    }
+    if !state.folders.move_document(doc_id, request.folder_id).await.map_err(folder_error)? {
+        return Err(ApiError::not_found("document-not-found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Whether a user may organize the folders of a root: their own personal documents, or a
+/// workspace they belong to.
+async fn can_organize(state: &AppState, root_id: Uuid, user_id: Uuid) -> Result<bool, ApiError> {
+    Ok(root_id == user_id || state.permissions.member_role(root_id, user_id).await?.is_some())
+}
+
+/// A folder the user may organize; others are reported as not found.
+async fn organizable_folder(state: &AppState, folder_id: Uuid, user_id: Uuid) -> Result<Folder, ApiError> {
+    if let Some(folder) = state.folders.get_folder(folder_id).await?
+        && let Some(root_id) = folder.workspace_id.or(folder.owner_id)
+        && can_organize(state, root_id, user_id).await?
+    {
+        return Ok(folder);
+    }
+    Err(ApiError::not_found("folder-not-found"))
+}
+
+fn folder_error(e: anyhow::Error) -> ApiError {
+    match e.downcast_ref::<FolderRejection>() {
+        Some(FolderRejection::NameTaken) => ApiError::new(StatusCode::CONFLICT, "folder-name-taken"),
+        Some(FolderRejection::Cycle) => ApiError::new(StatusCode::CONFLICT, "folder-move-cycle"),
+        Some(FolderRejection::DifferentRoot) => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "folder-different-root"),
+        None => name_taken_error(e),
+    }
+}
+
+async fn create_folder_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<CreateFolderRequest>,
+) -> Result<(StatusCode, Json<Folder>), ApiError> {
+    let name = request.name.trim();
+    let (owner_id, workspace_id) = match (request.parent_id, request.workspace_id) {
+        (Some(parent_id), _) => {
+            let parent = organizable_folder(&state, parent_id, user.user_id).await?;
+            (parent.owner_id, parent.workspace_id)
+        }
+        (None, Some(workspace_id)) => {
+            if state.permissions.member_role(workspace_id, user.user_id).await?.is_none() {
+                return Err(ApiError::new(StatusCode::FORBIDDEN, "workspace-member-required"));
+            }
+            (None, Some(workspace_id))
+        }
+        (None, None) => (Some(user.user_id), None),
+    };
+    let folder = state.folders.create_folder(name, owner_id, workspace_id, request.parent_id).await
+        .map_err(folder_error)?;
+    Ok((StatusCode::CREATED, Json(folder)))
+}
+
+async fn get_folder_handler(
+    State(state): State<Arc<AppState>>,
+    Path(folder_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<Json<Folder>, ApiError> {
+    Ok(Json(organizable_folder(&state, folder_id, user.user_id).await?))
+}
+
+async fn rename_folder_handler(
+    State(state): State<Arc<AppState>>,
+    Path(folder_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<FolderNameRequest>,
+) -> Result<Json<Folder>, ApiError> {
+    organizable_folder(&state, folder_id, user.user_id).await?;
+    let folder = state.folders.rename_folder(folder_id, request.name.trim()).await
+        .map_err(folder_error)?
+        .ok_or_else(|| ApiError::not_found("folder-not-found"))?;
+    Ok(Json(folder))
+}
+
+/// Moves a folder, with everything in it, into another folder of the same workspace or
+/// personal documents, or to the top.
+async fn move_folder_handler(
+    State(state): State<Arc<AppState>>,
+    Path(folder_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<MoveFolderRequest>,
+) -> Result<Json<Folder>, ApiError> {
+    organizable_folder(&state, folder_id, user.user_id).await?;
+    if let Some(parent_id) = request.parent_id {
+        organizable_folder(&state, parent_id, user.user_id).await?;
+    }
+    let folder = state.folders.move_folder(folder_id, request.parent_id).await
+        .map_err(folder_error)?
+        .ok_or_else(|| ApiError::not_found("folder-not-found"))?;
+    Ok(Json(folder))
+}
+
+/// Deletes a folder and the folders inside it. Their documents go to the trash, from
+/// which they are restored at the top of the workspace or personal documents.
+async fn delete_folder_handler(
+    State(state): State<Arc<AppState>>,
+    Path(folder_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    organizable_folder(&state, folder_id, user.user_id).await?;
+    // Its documents would skip their approval; they have to be deleted one by one.
+    if state.folders.deletion_protected(folder_id).await? {
+        return Err(ApiError::new(StatusCode::CONFLICT, "folder-deletion-protected"));
+    }
+    let trashed = state.folders.delete_folder(folder_id).await?
+        .ok_or_else(|| ApiError::not_found("folder-not-found"))?;
+    for doc_id in trashed {
+        notify_room(&state, doc_id, RoomNotice::Closing(CloseCode::DocumentDeleted)).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Marks a folder as needing a second approval to delete documents in it, or clears the
+/// mark. Reserved for workspace admins, or the owner of personal folders.
+async fn set_deletion_approval_handler(
+    State(state): State<Arc<AppState>>,
+    Path(folder_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<DeletionApprovalRequest>,
+) -> Result<Json<Folder>, ApiError> {
+    let folder = organizable_folder(&state, folder_id, user.user_id).await?;
+    if let Some(workspace_id) = folder.workspace_id {
+        require_workspace_admin(&state, workspace_id, &user).await?;
+    }
+    let folder = state.folders.set_deletion_approval(folder_id, request.required).await?
+        .ok_or_else(|| ApiError::not_found("folder-not-found"))?;
+    Ok(Json(folder))
+}
+
+/// The folders and then the documents directly inside a folder, each sorted by name. A
+/// workspace's ID, or the caller's own user ID, lists the top of that tree.
+async fn folder_children_handler(
+    State(state): State<Arc<AppState>>,
+    Path(folder_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<FolderEntry>>, ApiError> {
+    let visible = match state.folders.get_folder(folder_id).await? {
+        Some(_) => organizable_folder(&state, folder_id, user.user_id).await.is_ok(),
+        None => can_organize(&state, folder_id, user.user_id).await?,
+    };
+    if !visible {
+        return Err(ApiError::not_found("folder-not-found"));
+    }
+    let contents = state.folders.children(folder_id).await?;
+    let folders = contents.folders.into_iter().map(FolderEntry::Folder);
+    let documents = contents.documents.into_iter().map(|metadata| FolderEntry::Document(metadata.into()));
+    Ok(Json(folders.chain(documents).collect()))
+}
+
+/// Tells a document's open sockets about a change made over HTTP. The change itself has
+/// already succeeded, so failures are only logged.
+async fn notify_room(state: &AppState, doc_id: Uuid, notice: RoomNotice) {
+    if let Err(e) = state.rooms.notify(doc_id, notice).await {
+        warn!("Failed to notify the room of document {}: {:#}", doc_id, e);
+    }
+}
+
+/// Comments on a document, oldest first.
+async fn list_comments_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Comment>>, ApiError> {
+    if !caller(&state, user, &headers).await?.document_access(&state, doc_id).await?.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    if state.doc_service.get_document_metadata(doc_id).await?.is_none() {
+        return Err(ApiError::not_found("document-not-found"));
+    }
+    Ok(Json(state.doc_service.list_comments(doc_id).await?))
+}
+
+/// Documents linking to this one, leaving out those the caller may not read.
+async fn list_backlinks_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<DocumentResponse>>, ApiError> {
+    let caller = caller(&state, user, &headers).await?;
+    if !caller.document_access(&state, doc_id).await?.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    if state.doc_service.get_document_metadata(doc_id).await?.is_none() {
+        return Err(ApiError::not_found("document-not-found"));
+    }
+    let mut backlinks = state.doc_service.backlinks(doc_id).await?;
+    let ids: Vec<Uuid> = backlinks.iter().map(|doc| doc.id).collect();
+    let readable = caller.readable_documents(&state, &ids).await?;
+    backlinks.retain(|doc| readable.contains(&doc.id));
+    Ok(Json(backlinks.into_iter().map(DocumentResponse::from).collect()))
+}
+
+/// Documents this one links to, with links to documents since deleted marked broken.
+async fn list_links_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<DocumentLink>>, ApiError> {
+    if !caller(&state, user, &headers).await?.document_access(&state, doc_id).await?.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    if state.doc_service.get_document_metadata(doc_id).await?.is_none() {
+        return Err(ApiError::not_found("document-not-found"));
+    }
+    Ok(Json(state.doc_service.links(doc_id).await?))
+}
+
+/// Comments on a document. Commenters may do this without being able to edit.
+async fn add_comment_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    Valid(request): Valid<CommentRequest>,
+) -> Result<(StatusCode, Json<Comment>), ApiError> {
+    let caller = caller(&state, user, &headers).await?;
+    if !caller.document_access(&state, doc_id).await?.comment {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "comment-forbidden"));
+    }
+    let (thread, missing) = match request.reply_to {
+        Some(to) => (CommentThread::Reply { to }, "comment-not-found"),
+        None => (CommentThread::Start { anchor: request.anchor }, "document-not-found"),
+    };
+    let comment = match state.doc_service.add_comment(doc_id, caller.author(), thread, request.body.trim()).await {
+        Ok(comment) => comment.ok_or_else(|| ApiError::not_found(missing))?,
+        Err(e) => match e.downcast::<HookRejection>() {
+            Ok(rejection) => {
+                return Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "comment-rejected").with_arg("reason", rejection.reason));
+            }
+            Err(e) => return Err(ApiError::internal(e)),
+        },
+    };
+    notify_room(&state, doc_id, RoomNotice::Comment(CommentChange::Added { comment: comment.clone() })).await;
+    Ok((StatusCode::CREATED, Json(comment)))
+}
+
+/// Resolves or reopens the thread a comment is in. Anyone who may comment may do this.
+async fn resolve_comment_handler(
+    State(state): State<Arc<AppState>>,
+    Path((doc_id, comment_id)): Path<(Uuid, Uuid)>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    Valid(request): Valid<ResolveCommentRequest>,
+) -> Result<StatusCode, ApiError> {
+    if !caller(&state, user, &headers).await?.document_access(&state, doc_id).await?.comment {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "comment-forbidden"));
+    }
+    let Some(thread_id) = state.doc_service.resolve_thread(doc_id, comment_id, request.resolved).await? else {
+        return Err(ApiError::not_found("comment-not-found"));
+    };
+    notify_room(&state, doc_id, RoomNotice::Comment(CommentChange::Resolved { thread_id, resolved: request.resolved })).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deletes a comment, and its thread if it is the first. Reserved for its author and the
+/// document's owners.
+async fn delete_comment_handler(
+    State(state): State<Arc<AppState>>,
+    Path((doc_id, comment_id)): Path<(Uuid, Uuid)>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let caller = caller(&state, user, &headers).await?;
+    let access = caller.document_access(&state, doc_id).await?;
+    if !access.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    let Some(comment) = state.doc_service.get_comment(doc_id, comment_id).await? else {
+        return Err(ApiError::not_found("comment-not-found"));
+    };
+    let author = comment.author.is_some() && comment.author == caller.author();
+    if !author && require_owner(access).is_err() {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "comment-delete-forbidden"));
+    }
+    if state.doc_service.delete_comment(doc_id, comment_id).await? {
+        notify_room(&state, doc_id, RoomNotice::Comment(CommentChange::Deleted { id: comment.id, thread_id: comment.thread_id })).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Renaming and deleting a document are reserved for its owners.
+fn require_owner(access: DocumentAccess) -> Result<(), ApiError> {
+    match access.role {
+        DocumentRole::Owner if access.write => Ok(()),
+        _ => Err(ApiError::new(StatusCode::FORBIDDEN, "document-owner-required")),
+    }
+}
+
+/// Reads at least as fresh as the request's `X-Causality-Token`, if it sent one.
+fn read_consistency(headers: &HeaderMap, default: ReadConsistency) -> Result<ReadConsistency, ApiError> {
+    match headers.get(CAUSALITY_TOKEN_HEADER) {
+        Some(token) => {
+            let token = token.to_str().ok().and_then(|token| token.parse::<CausalityToken>().ok())
+                .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "invalid-causality-token"))?;
+            Ok(ReadConsistency::AtLeast(token))
+        }
+        None => Ok(default),
+    }
+}
+
+fn causality_headers(token: CausalityToken) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(CAUSALITY_TOKEN_HEADER, token.to_string().parse().expect("causality tokens are valid header values"));
+    headers
+}
+
+/// Maps a room's refusal of an update to a response.
+fn update_error(doc_id: Uuid, e: anyhow::Error) -> ApiError {
+    match e.downcast_ref::<UpdateRejection>() {
+        Some(rejection @ (UpdateRejection::BufferFull | UpdateRejection::RateLimited | UpdateRejection::LimitExceeded)) => {
+            ApiError::new(StatusCode::TOO_MANY_REQUESTS, rejection.message_key())
+        }
+        Some(rejection @ (UpdateRejection::FrameTooLarge | UpdateRejection::DocumentTooLarge)) => {
+            ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, rejection.message_key())
+        }
+        Some(rejection @ (UpdateRejection::OutageTooLong | UpdateRejection::Migrating)) => {
+            ApiError::new(StatusCode::SERVICE_UNAVAILABLE, rejection.message_key())
+        }
+        Some(rejection @ (UpdateRejection::ProtectedRange | UpdateRejection::InvalidRange)) => {
+            ApiError::new(StatusCode::CONFLICT, rejection.message_key())
+        }
+        Some(rejection @ UpdateRejection::Expired) => ApiError::new(StatusCode::LOCKED, rejection.message_key()),
+        None => ApiError::internal(e.context(format!("Failed to apply change to document {}", doc_id))),
+    }
+}
+
+#[derive(Deserialize)]
+struct UploadQuery {
+    name: String,
+}
+
+fn attachment_service(state: &AppState) -> Result<&AttachmentService, ApiError> {
+    state.attachments.as_deref().ok_or_else(|| ApiError::not_found("attachments-disabled"))
+}
+
+/// Attaches the request body to a document as a file named by `?name=`. The attachment
+/// cannot be downloaded until it has been scanned clean, if a scanner is configured.
+async fn upload_attachment_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    query: Result<Query<UploadQuery>, QueryRejection>,
+    body: Result<Bytes, BytesRejection>,
+) -> Result<(StatusCode, Json<Attachment>), ApiError> {
+    let attachments = attachment_service(&state)?;
+    let invalid_name = || {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid-attachment-name")
+            .with_arg("max", document_service::MAX_DOCUMENT_NAME_LENGTH)
+    };
+    let Query(query) = query.map_err(|_| invalid_name())?;
+    let caller = caller(&state, user, &headers).await?;
+    if !caller.document_access(&state, doc_id).await?.write {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "update-forbidden"));
+    }
+    let file_name = query.name.trim();
+    if file_name.is_empty() || file_name.chars().count() > document_service::MAX_DOCUMENT_NAME_LENGTH {
+        return Err(invalid_name());
+    }
+    let body = body.map_err(|_| {
+        ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "attachment-too-large").with_arg("max", attachments.max_bytes())
+    })?;
+    if state.doc_service.get_document_metadata(doc_id).await?.is_none() {
+        return Err(ApiError::not_found("document-not-found"));
+    }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| mime_guess::from_path(file_name).first_or_octet_stream().to_string());
+    let attachment = attachments.upload(doc_id, file_name, &content_type, body.to_vec(), caller.owner()).await?;
+    Ok((StatusCode::CREATED, Json(attachment)))
+}
+
+/// A document's attachments with their scan status, including quarantined ones.
+async fn list_attachments_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Attachment>>, ApiError> {
+    let attachments = attachment_service(&state)?;
+    if !caller(&state, user, &headers).await?.document_access(&state, doc_id).await?.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    Ok(Json(attachments.list(doc_id).await?))
+}
+
+// Looks up an attachment the caller may read through its document.
+async fn readable_attachment(
+    state: &AppState,
+    id: Uuid,
+    user: Option<AuthenticatedUser>,
+    headers: &HeaderMap,
+) -> Result<Attachment, ApiError> {
+    let attachment = attachment_service(state)?.get(id).await?
+        .ok_or_else(|| ApiError::not_found("attachment-not-found"))?;
+    if !caller(state, user, headers).await?.document_access(state, attachment.document_id).await?.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    Ok(attachment)
+}
+
+async fn get_attachment_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Json<Attachment>, ApiError> {
+    Ok(Json(readable_attachment(&state, id, user, &headers).await?))
+}
+
+/// An attachment's content, always served as a download. Quarantined attachments are refused.
+async fn download_attachment_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    readable_attachment(&state, id, user, &headers).await?;
+    let (attachment, data) = match attachment_service(&state)?.download(id).await {
+        Ok(Some(download)) => download,
+        Ok(None) => return Err(ApiError::not_found("attachment-not-found")),
+        Err(e) => {
+            return Err(match e.downcast_ref::<Quarantined>() {
+                Some(Quarantined(ScanStatus::Infected)) => ApiError::new(StatusCode::FORBIDDEN, "attachment-infected"),
+                Some(_) => ApiError::new(StatusCode::LOCKED, "attachment-scan-pending"),
+                None => ApiError::internal(e),
+            });
+        }
+    };
+    let mut response = data.into_response();
+    let response_headers = response.headers_mut();
+    if let Ok(content_type) = HeaderValue::from_str(&attachment.content_type) {
+        response_headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    // Uploaded content is never rendered inline, so it can't run script on this origin.
+    let file_name: String = attachment.file_name
+        .chars()
+        .filter(|c| (c.is_ascii_graphic() || *c == ' ') && !matches!(c, '"' | '\\'))
+        .collect();
+    if let Ok(disposition) = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", file_name)) {
+        response_headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    response_headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    Ok(response)
+}
+
+async fn get_digest_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<DigestPreferences>, ApiError> {
+    Ok(Json(state.digests.preferences(user.user_id).await?))
+}
+
+/// Opts in to or out of email digests. Digests are written in the request's language.
+async fn set_digest_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    locale: Locale,
+    Valid(request): Valid<DigestPreferencesRequest>,
+) -> Result<Json<DigestPreferences>, ApiError> {
+    let timezone = request.timezone.parse()
+        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-timezone").with_arg("timezone", &request.timezone))?;
+    Ok(Json(state.digests.set_preferences(user.user_id, request.frequency, timezone, locale).await?))
+}
+
+/// Emails a password reset link, in the request's language. Answers the same whether or
+/// not an account uses the address, so it can't be used to probe for accounts.
+async fn request_password_reset_handler(
+    State(state): State<Arc<AppState>>,
+    locale: Locale,
+    Valid(request): Valid<PasswordResetRequest>,
+) -> Result<StatusCode, ApiError> {
+    if !state.users.sends_email() {
+        return Err(ApiError::not_found("email-disabled"));
+    }
+    // A failure would only ever show for addresses with an account.
+    if let Err(e) = state.users.request_password_reset(&request.email, locale).await {
+        warn!("Failed to send password reset email: {:#}", e);
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Emails a link that signs in without a password, in the request's language. The link
+/// only works in the browser that asked for it, which keeps a secret for it in a cookie.
+/// Answers the same whether or not an account uses the address.
+async fn request_magic_link_handler(
+    State(state): State<Arc<AppState>>,
+    locale: Locale,
+    Valid(request): Valid<MagicLinkRequest>,
+) -> Result<(StatusCode, HeaderMap), ApiError> {
+    if !state.users.magic_links_enabled() {
+        return Err(ApiError::not_found("magic-links-disabled"));
+    }
+    let device = user_service::magic_link_device_secret();
+    // A failure would only ever show for addresses with an account.
+    if let Err(e) = state.users.request_magic_link(&request.email, &device, locale).await {
+        warn!("Failed to send magic link email: {:#}", e);
+    }
+    let mut headers = HeaderMap::new();
+    if let Some(cookie) = magic_link_cookie(&device, user_service::MAGIC_LINK_LIFETIME) {
+        headers.insert(header::SET_COOKIE, cookie);
+    }
+    Ok((StatusCode::ACCEPTED, headers))
+}
+
+/// Signs in with the token from a magic link, like `login_handler` does with a password.
+async fn confirm_magic_link_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    origin: RequestOrigin,
+    Valid(request): Valid<MagicLinkConfirmation>,
+) -> Result<Response, ApiError> {
+    if !state.users.magic_links_enabled() {
+        return Err(ApiError::not_found("magic-links-disabled"));
+    }
+    let device = tokens::cookie(&headers, MAGIC_LINK_COOKIE).unwrap_or_default();
+    let kind = if request.cookie { SessionKind::Cookie } else { SessionKind::Token };
+    let Some(session) = state.users.login_with_magic_link(&request.token, device, kind).await? else {
+        let failed = AuditRecord::new(AuditAction::LoginFailed).with_detail(json!({ "method": "magic_link", "reason": "invalid_link" }));
+        state.audit.record(&origin, failed).await;
+        return Err(ApiError::not_found("invalid-magic-link"));
+    };
+    record_login(&state, &origin, &session, "magic_link").await;
+    let mut response_headers = HeaderMap::new();
+    if let Some(cookie) = magic_link_cookie(device, TimeDelta::zero()) {
+        response_headers.insert(header::SET_COOKIE, cookie);
+    }
+    session_response(&state, session, kind, response_headers)
+}
+
+/// A `Set-Cookie` value holding a magic link's device secret, or removing it when `max_age`
+/// is not positive. Only sent to the magic link endpoints.
+fn magic_link_cookie(device: &str, max_age: TimeDelta) -> Option<HeaderValue> {
+    let cookie = format!(
+        "{}={}; Max-Age={}; Path=/api/users/magic-link; Secure; HttpOnly; SameSite=Lax",
+        MAGIC_LINK_COOKIE,
+        device,
+        max_age.num_seconds().max(0),
+    );
+    HeaderValue::from_str(&cookie).ok()
+}
+
+#[derive(Deserialize)]
+struct FederatedCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    /// Set instead of `code` when the provider did not sign anyone in, e.g. they declined.
+    error: Option<String>,
+}
+
+/// Sends the browser to the identity provider to sign in, keeping what the callback needs
+/// to finish in a cookie.
+async fn federated_login_handler(
+    State(state): State<Arc<AppState>>,
+    Path(provider): Path<String>,
+) -> Result<(HeaderMap, Redirect), ApiError> {
+    let provider = state.federated_login.provider(&provider).ok_or_else(|| ApiError::not_found("login-provider-not-found"))?;
+    let (url, pending) = state.federated_login.start(provider);
+    let mut headers = HeaderMap::new();
+    if let Some(cookie) = pending.cookie(&provider.name, PENDING_LOGIN_LIFETIME) {
+        headers.insert(header::SET_COOKIE, cookie);
+    }
+    Ok((headers, Redirect::to(url.as_str())))
+}
+
+/// Where the identity provider sends the browser back. Signs in with a cookie session and
+/// continues to the web client. Someone already signed in links the identity to their
+/// account instead.
+async fn federated_callback_handler(
+    State(state): State<Arc<AppState>>,
+    Path(provider): Path<String>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    origin: RequestOrigin,
+    query: Result<Query<FederatedCallbackQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let provider = state.federated_login.provider(&provider).ok_or_else(|| ApiError::not_found("login-provider-not-found"))?;
+    let Query(query) = query.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-login-callback"))?;
+    if query.error.is_some() {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "login-declined"));
+    }
+    let (Some(code), Some(returned_state)) = (query.code, query.state) else {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid-login-callback"));
+    };
+    // Without the cookie, the login was started elsewhere or too long ago.
+    let pending = tokens::cookie(&headers, PENDING_LOGIN_COOKIE)
+        .and_then(PendingLogin::parse)
+        .filter(|pending| pending.state == returned_state)
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "login-expired"))?;
+    let identity = state.federated_login.finish(provider, &pending, &code).await.map_err(|e| {
+        warn!("Failed to finish login with {}: {:#}", provider.name, e);
+        ApiError::new(StatusCode::BAD_GATEWAY, "login-provider-failed")
+    })?;
+    let link_to = user.map(|user| user.user_id);
+    let session = match state.users.login_with_identity(&identity, link_to, SessionKind::Cookie).await {
+        Ok(Some(session)) => session,
+        Ok(None) => {
+            let failed = AuditRecord::new(AuditAction::LoginFailed)
+                .with_detail(json!({ "method": provider.name, "reason": "account_unavailable" }));
+            state.audit.record(&origin, failed).await;
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "account-unavailable"));
+        }
+        Err(e) => {
+            return Err(match e.downcast_ref::<FederatedLoginError>() {
+                Some(FederatedLoginError::LinkedElsewhere) => ApiError::new(StatusCode::CONFLICT, "identity-linked-elsewhere"),
+                Some(FederatedLoginError::EmailTaken) => ApiError::new(StatusCode::CONFLICT, "identity-email-taken"),
+                None => e.into(),
+            });
+        }
+    };
+    record_login(&state, &origin, &session, &provider.name).await;
+    let mut response_headers = HeaderMap::new();
+    if let Some(cookie) = pending.cookie(&provider.name, TimeDelta::zero()) {
+        response_headers.append(header::SET_COOKIE, cookie);
+    }
+    if let Some(cookie) = tokens::session_cookie(&session.secret, session.expires_at - Utc::now()) {
+        response_headers.append(header::SET_COOKIE, cookie);
+    }
+    Ok((response_headers, Redirect::to("/app")).into_response())
+}
+
+/// Sets a new password with the token from a reset link, signing the account out everywhere.
+async fn confirm_password_reset_handler(
+    State(state): State<Arc<AppState>>,
+    origin: RequestOrigin,
+    Valid(request): Valid<PasswordResetConfirmation>,
+) -> Result<StatusCode, ApiError> {
+    match state.users.reset_password(&request.token, &request.password).await? {
+        Some(user_id) => {
+            let record = AuditRecord::new(AuditAction::PasswordChanged).with_actor(user_id).with_target(user_id);
+            state.audit.record(&origin, record).await;
+            Ok(StatusCode::NO_CONTENT)
+        }
+        None => Err(ApiError::not_found("invalid-password-reset-link")),
+    }
+}
+
+/// The link in verification emails.
+async fn verify_email_handler(
+    State(state): State<Arc<AppState>>,
+    locale: Locale,
+    query: Result<Query<VerifyEmailQuery>, QueryRejection>,
+) -> Result<Html<String>, ApiError> {
+    let Query(query) = query.map_err(|_| ApiError::not_found("invalid-verification-link"))?;
+    let Some(user_id) = state.users.verify_email(&query.token).await? else {
+        return Err(ApiError::not_found("invalid-verification-link"));
+    };
+    // Invites sent to the address before the account existed become memberships now.
+    if let Some(email) = state.users.get_user(user_id).await?.and_then(|user| user.email)
+        && let Err(e) = state.organizations.accept_invites_for(user_id, &email).await
+    {
+        warn!("Failed to accept pending invites for user ID {}: {:#}", user_id, e);
+    }
+    let message = i18n::message(locale, "email-verified", &[]);
+    Ok(Html(format!("<!DOCTYPE html><html><body><p>{}</p></body></html>", preview::escape_html(&message))))
+}
+
+/// Sends the signed-in user another verification link, e.g. after the first one expired.
+async fn resend_verification_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    locale: Locale,
+) -> Result<StatusCode, ApiError> {
+    if !state.users.sends_email() {
+        return Err(ApiError::not_found("email-disabled"));
+    }
+    if !state.users.send_email_verification(user.user_id, locale).await? {
+        return Err(ApiError::new(StatusCode::CONFLICT, "email-already-verified"));
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// The link in every digest email. Also accepts one-click `POST`s from mail clients.
+async fn unsubscribe_handler(
+    State(state): State<Arc<AppState>>,
+    locale: Locale,
+    query: Result<Query<UnsubscribeQuery>, QueryRejection>,
+) -> Result<Html<String>, ApiError> {
+    let Query(query) = query.map_err(|_| ApiError::not_found("invalid-unsubscribe-link"))?;
+    if !state.digests.unsubscribe(&query.token).await? {
+        return Err(ApiError::not_found("invalid-unsubscribe-link"));
+    }
+    let message = i18n::message(locale, "digest-unsubscribed", &[]);
+    Ok(Html(format!("<!DOCTYPE html><html><body><p>{}</p></body></html>", preview::escape_html(&message))))
+}
+
+/// Creates a workspace with the signed-in user as its admin, for an organization they
+/// administer if one is given.
+async fn create_workspace_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<WorkspaceRequest>,
+) -> Result<(StatusCode, Json<Workspace>), ApiError> {
+    if let Some(organization_id) = request.organization_id {
+        require_organization_role(&state, organization_id, &user, OrganizationRole::Admin).await?;
+    }
+    let name = request.name.trim();
+    let workspace = state.permissions.create_workspace_in(name, user.user_id, request.organization_id).await?;
+    Ok((StatusCode::CREATED, Json(workspace)))
+}
+
+/// Adds everyone on a team to a workspace. Reserved for admins of both the workspace and
+/// the team's organization.
+async fn add_team_to_workspace_handler(
+    State(state): State<Arc<AppState>>,
+    Path((workspace_id, team_id)): Path<(Uuid, Uuid)>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<MemberRequest>,
+) -> Result<StatusCode, ApiError> {
+    require_workspace_admin(&state, workspace_id, &user).await?;
+    let team = state.organizations.get_team(team_id).await?.ok_or_else(|| ApiError::not_found("team-not-found"))?;
+    require_organization_role(&state, team.organization_id, &user, OrganizationRole::Admin).await?;
+    state.organizations.add_team_to_workspace(team.id, workspace_id, request.role).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Creates an organization with the signed-in user as its owner.
+async fn create_organization_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<OrganizationRequest>,
+) -> Result<(StatusCode, Json<Organization>), ApiError> {
+    let organization = state.organizations.create_organization(request.name.trim(), user.user_id).await?;
+    Ok((StatusCode::CREATED, Json(organization)))
+}
+
+/// The organizations the signed-in user belongs to.
+async fn list_organizations_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<Membership>>, ApiError> {
+    Ok(Json(state.organizations.organizations_of(user.user_id).await?))
+}
+
+/// Fails unless the user has at least `role` in the organization, returning their role.
+/// Non-members are told the organization does not exist.
+async fn require_organization_role(
+    state: &AppState,
+    organization_id: Uuid,
+    user: &AuthenticatedUser,
+    role: OrganizationRole,
+) -> Result<OrganizationRole, ApiError> {
+    match state.organizations.member_role(organization_id, user.user_id).await? {
+        Some(held) if held >= role => Ok(held),
+        Some(_) => Err(ApiError::new(StatusCode::FORBIDDEN, match role {
+            OrganizationRole::Owner => "organization-owner-required",
+            _ => "organization-admin-required",
+        })),
+        None => Err(ApiError::not_found("organization-not-found")),
+    }
+}
+
+fn organization_error(e: anyhow::Error) -> ApiError {
+    match e.downcast_ref::<OrganizationRejection>() {
+        Some(OrganizationRejection::LastOwner) => ApiError::new(StatusCode::CONFLICT, "organization-last-owner"),
+        Some(OrganizationRejection::InviteForSomeoneElse) => ApiError::new(StatusCode::FORBIDDEN, "invite-for-someone-else"),
+        Some(OrganizationRejection::NotAMember) => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "team-member-not-in-organization"),
+        Some(OrganizationRejection::TeamNameTaken) => ApiError::new(StatusCode::CONFLICT, "team-name-taken"),
+        None => e.into(),
+    }
+}
+
+/// Visible to every member.
+async fn list_organization_members_handler(
+    State(state): State<Arc<AppState>>,
+    Path(organization_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<OrganizationMember>>, ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Member).await?;
+    Ok(Json(state.organizations.members(organization_id).await?))
+}
+
+/// Changes a member's role. Admins manage members; making, changing or removing admins
+/// and owners is left to owners.
+async fn set_organization_role_handler(
+    State(state): State<Arc<AppState>>,
+    Path((organization_id, member_id)): Path<(Uuid, Uuid)>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<OrganizationRoleRequest>,
+) -> Result<StatusCode, ApiError> {
+    let current = state.organizations.member_role(organization_id, member_id).await?;
+    let needed = if request.role.manages_members() || current.is_some_and(|role| role.manages_members()) {
+        OrganizationRole::Owner
+    } else {
+        OrganizationRole::Admin
+    };
+    require_organization_role(&state, organization_id, &user, needed).await?;
+    if !state.organizations.set_role(organization_id, member_id, request.role).await.map_err(organization_error)? {
+        return Err(ApiError::not_found("organization-member-not-found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Members may leave on their own; otherwise removing someone takes the same rights as
+/// changing their role.
+async fn remove_organization_member_handler(
+    State(state): State<Arc<AppState>>,
+    Path((organization_id, member_id)): Path<(Uuid, Uuid)>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    if member_id != user.user_id {
+        let current = state.organizations.member_role(organization_id, member_id).await?;
+        let needed = if current.is_some_and(|role| role.manages_members()) { OrganizationRole::Owner } else { OrganizationRole::Admin };
+        require_organization_role(&state, organization_id, &user, needed).await?;
+    }
+    if !state.organizations.remove_member(organization_id, member_id).await.map_err(organization_error)? {
+        return Err(ApiError::not_found("organization-member-not-found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Invites someone by email. Only owners can invite admins and owners. The secret is
+/// returned once, for the caller to pass on.
+async fn invite_handler(
+    State(state): State<Arc<AppState>>,
+    Path(organization_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    locale: Locale,
+    Valid(request): Valid<InviteRequest>,
+) -> Result<(StatusCode, Json<IssuedInvite>), ApiError> {
+    let needed = if request.role.manages_members() { OrganizationRole::Owner } else { OrganizationRole::Admin };
+    require_organization_role(&state, organization_id, &user, needed).await?;
+    let invite = state.organizations.invite(organization_id, &request.email, request.role, user.user_id, locale).await?;
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+async fn list_invites_handler(
+    State(state): State<Arc<AppState>>,
+    Path(organization_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<Invite>>, ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Admin).await?;
+    Ok(Json(state.organizations.pending_invites(organization_id).await?))
+}
+
+async fn revoke_invite_handler(
+    State(state): State<Arc<AppState>>,
+    Path((organization_id, invite_id)): Path<(Uuid, Uuid)>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Admin).await?;
+    if !state.organizations.revoke_invite(organization_id, invite_id).await? {
+        return Err(ApiError::not_found("invite-not-found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Joins an organization with an invite sent to the signed-in user's email address.
+async fn accept_invite_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<AcceptInviteRequest>,
+) -> Result<Json<OrganizationMember>, ApiError> {
+    let email = state.users.get_user(user.user_id).await?.and_then(|account| account.email);
+    let member = state.organizations.accept_invite(request.secret.trim(), user.user_id, email.as_deref()).await
+        .map_err(organization_error)?
+        .ok_or_else(|| ApiError::not_found("invite-not-found"))?;
+    Ok(Json(member))
+}
 
-// Shared application state (if needed, e.g., for broadcasting messages)
-#[derive(Clone)]
-struct AppState {
-    doc_service: Arc<DocumentService>,
+async fn create_team_handler(
+    State(state): State<Arc<AppState>>,
+    Path(organization_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<TeamRequest>,
+) -> Result<(StatusCode, Json<Team>), ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Admin).await?;
+    let team = state.organizations.create_team(organization_id, request.name.trim()).await.map_err(organization_error)?;
+    Ok((StatusCode::CREATED, Json(team)))
 }
 
-pub async fn run_server(doc_service: Arc<DocumentService>) -> anyhow::Result<()> {
-    let app_state = Arc::new(AppState {
-        doc_service,
-    });
+async fn list_teams_handler(
+    State(state): State<Arc<AppState>>,
+    Path(organization_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<Team>>, ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Member).await?;
+    Ok(Json(state.organizations.teams(organization_id).await?))
+}
 
-    let app = Router::new()
-        .route("/", get(root_handler))
-        .route("/ws", get(websocket_handler))
-        .with_state(app_state);
+/// The key the organization's documents are encrypted with, if it registered one.
+async fn get_encryption_key_handler(
+    State(state): State<Arc<AppState>>,
+    Path(organization_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<Json<OrganizationKey>, ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Admin).await?;
+    let encryption = encryption(&state)?;
+    let key = encryption.organization_key(organization_id).await?
+        .ok_or_else(|| ApiError::not_found("encryption-key-not-found"))?;
+    Ok(Json(key))
+}
+
+/// Registers the organization's key, or rotates to a new one.
+async fn set_encryption_key_handler(
+    State(state): State<Arc<AppState>>,
+    Path(organization_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    origin: RequestOrigin,
+    Valid(request): Valid<EncryptionKeyRequest>,
+) -> Result<Json<OrganizationKey>, ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Admin).await?;
+    let key = encryption(&state)?.set_key(organization_id, &request.key_id).await?;
+    let record = AuditRecord::new(AuditAction::EncryptionKeySet)
+        .with_actor(user.user_id)
+        .with_target(organization_id)
+        .with_detail(json!({ "key_id": key.key_id, "version": key.version }));
+    state.audit.record(&origin, record).await;
+    Ok(Json(key))
+}
+
+/// Revokes the organization's key, leaving its encrypted content unreadable for good.
+/// Only owners may do this.
+async fn revoke_encryption_key_handler(
+    State(state): State<Arc<AppState>>,
+    Path(organization_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    origin: RequestOrigin,
+) -> Result<StatusCode, ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Owner).await?;
+    let unreadable = encryption(&state)?.revoke(organization_id).await?
+        .ok_or_else(|| ApiError::not_found("encryption-key-not-found"))?;
+    let record = AuditRecord::new(AuditAction::EncryptionKeyRevoked)
+        .with_actor(user.user_id)
+        .with_target(organization_id)
+        .with_detail(json!({ "data_keys_deleted": unreadable.len() }));
+    state.audit.record(&origin, record).await;
+    for doc_id in unreadable {
+        notify_room(&state, doc_id, RoomNotice::Closing(CloseCode::DocumentUnreadable)).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn encryption(state: &AppState) -> Result<&Encryption, ApiError> {
+    state.encryption.as_deref().ok_or_else(|| ApiError::not_found("encryption-disabled"))
+}
+
+/// The team, if it belongs to the organization.
+async fn organization_team(state: &AppState, organization_id: Uuid, team_id: Uuid) -> Result<Team, ApiError> {
+    state.organizations.get_team(team_id).await?
+        .filter(|team| team.organization_id == organization_id)
+        .ok_or_else(|| ApiError::not_found("team-not-found"))
+}
+
+/// The IDs of the team's members.
+async fn list_team_members_handler(
+    State(state): State<Arc<AppState>>,
+    Path((organization_id, team_id)): Path<(Uuid, Uuid)>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<Uuid>>, ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Member).await?;
+    let team = organization_team(&state, organization_id, team_id).await?;
+    Ok(Json(state.organizations.team_members(team.id).await?))
+}
+
+async fn delete_team_handler(
+    State(state): State<Arc<AppState>>,
+    Path((organization_id, team_id)): Path<(Uuid, Uuid)>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Admin).await?;
+    let team = organization_team(&state, organization_id, team_id).await?;
+    state.organizations.delete_team(team.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn add_team_member_handler(
+    State(state): State<Arc<AppState>>,
+    Path((organization_id, team_id, member_id)): Path<(Uuid, Uuid, Uuid)>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Admin).await?;
+    let team = organization_team(&state, organization_id, team_id).await?;
+    state.organizations.add_to_team(&team, member_id).await.map_err(organization_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    let listener = TcpListener::bind(addr).await?;
-    println!("HTTP server listening on {}", listener.local_addr()?); // Use listener.local_addr()
-    axum::serve(listener, app.into_make_service()).await?;
+async fn remove_team_member_handler(
+    State(state): State<Arc<AppState>>,
+    Path((organization_id, team_id, member_id)): Path<(Uuid, Uuid, Uuid)>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    require_organization_role(&state, organization_id, &user, OrganizationRole::Admin).await?;
+    let team = organization_team(&state, organization_id, team_id).await?;
+    if !state.organizations.remove_from_team(team.id, member_id).await? {
+        return Err(ApiError::not_found("team-member-not-found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
 
+/// Sharing is managed by a document's owners, and so is seeing who it is shared with.
+async fn require_sharing_rights(state: &AppState, doc_id: Uuid, user: &AuthenticatedUser) -> Result<(), ApiError> {
+    require_owner(Caller::User(user.user_id).document_access(state, doc_id).await?)?;
+    if state.doc_service.get_document_metadata(doc_id).await?.is_none() {
+        return Err(ApiError::not_found("document-not-found"));
+    }
     Ok(())
 }
 
-async fn root_handler() -> Html<&'static str> {
-    Html("<h1>Hello, World!</h1><p><a href='/ws'>Connect to WebSocket</a> (use a WebSocket client)</p>\n")
+async fn list_permissions_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<DocumentPermission>>, ApiError> {
+    require_sharing_rights(&state, doc_id, &user).await?;
+    Ok(Json(state.permissions.document_permissions(doc_id).await?))
+}
+
+/// Shares a document with a user, or changes the role they were given.
+async fn share_document_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    origin: RequestOrigin,
+    Valid(request): Valid<ShareRequest>,
+) -> Result<Json<DocumentPermission>, ApiError> {
+    require_sharing_rights(&state, doc_id, &user).await?;
+    if state.users.get_user(request.user_id).await?.is_none() {
+        return Err(ApiError::not_found("user-not-found"));
+    }
+    let permission = state.permissions.share_document(doc_id, request.user_id, request.role, user.user_id).await?;
+    let record = AuditRecord::new(AuditAction::PermissionGranted)
+        .with_actor(user.user_id)
+        .with_target(doc_id)
+        .with_detail(json!({ "user_id": request.user_id, "role": request.role }));
+    state.audit.record(&origin, record).await;
+    notify_room(&state, doc_id, RoomNotice::AccessChanged).await;
+    Ok(Json(permission))
+}
+
+async fn unshare_document_handler(
+    State(state): State<Arc<AppState>>,
+    Path((doc_id, user_id)): Path<(Uuid, Uuid)>,
+    user: AuthenticatedUser,
+    origin: RequestOrigin,
+) -> Result<StatusCode, ApiError> {
+    require_sharing_rights(&state, doc_id, &user).await?;
+    state.permissions.unshare_document(doc_id, user_id).await?;
+    let record = AuditRecord::new(AuditAction::PermissionRevoked)
+        .with_actor(user.user_id)
+        .with_target(doc_id)
+        .with_detail(json!({ "user_id": user_id }));
+    state.audit.record(&origin, record).await;
+    notify_room(&state, doc_id, RoomNotice::AccessChanged).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_share_links_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<ShareLink>>, ApiError> {
+    require_sharing_rights(&state, doc_id, &user).await?;
+    Ok(Json(state.permissions.share_links(doc_id).await?))
+}
+
+/// Creates a link that lets anyone holding it open the document with the given role, until
+/// `expires_at` if set. Its secret is in the response and can't be retrieved again. The
+/// saved document is moderated first: if it is blocked no link is made until an
+/// administrator approves it.
+async fn create_share_link_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<ShareLinkRequest>,
+) -> Result<(StatusCode, Json<ShareLinkResponse>), ApiError> {
+    require_sharing_rights(&state, doc_id, &user).await?;
+    let moderation_review = if state.moderation.is_enabled() {
+        let metadata = state.doc_service.get_document_metadata(doc_id).await?
+            .ok_or_else(|| ApiError::not_found("document-not-found"))?;
+        let content = state.doc_service.get_document_content(doc_id).await?
+            .map(|content| content.crdt_data)
+            .unwrap_or_default();
+        match state.moderation.review_publication(doc_id, Some(user.user_id), &metadata.name, &content).await? {
+            Publication::Allowed => None,
+            Publication::Flagged(review) => Some(review.id),
+            Publication::Blocked(review) => {
+                return Err(ApiError::new(StatusCode::FORBIDDEN, "publication-blocked").with_arg("review", review.id));
+            }
+        }
+    } else {
+        None
+    };
+    let link = state.permissions.create_share_link(doc_id, request.role, request.expires_at, user.user_id).await?;
+    let url = format!("/app/documents/{}?share_link={}", doc_id, link.secret);
+    Ok((StatusCode::CREATED, Json(ShareLinkResponse { link, url, moderation_review })))
+}
+
+/// Revokes a share link; sockets opened with it are closed.
+async fn revoke_share_link_handler(
+    State(state): State<Arc<AppState>>,
+    Path((doc_id, link_id)): Path<(Uuid, Uuid)>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    require_sharing_rights(&state, doc_id, &user).await?;
+    if !state.permissions.revoke_share_link(doc_id, link_id).await? {
+        return Err(ApiError::not_found("share-link-not-found"));
+    }
+    notify_room(&state, doc_id, RoomNotice::AccessChanged).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Shares many documents with a user at once: those listed in `documents`, or every
+/// document in the folder `folder_id`. Only documents the caller owns or administers are
+/// shared.
+async fn bulk_share_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    origin: RequestOrigin,
+    Valid(request): Valid<BulkShareRequest>,
+) -> Result<Json<BulkShareResponse>, ApiError> {
+    if state.users.get_user(request.user_id).await?.is_none() {
+        return Err(ApiError::not_found("user-not-found"));
+    }
+    let shared = state.permissions.share_documents(&request.selection, request.user_id, request.role, user.user_id).await?;
+    let skipped = match &request.selection {
+        DocumentSelection::Documents(ids) => {
+            ids.iter().filter(|id| !shared.iter().any(|share| share.document_id == **id)).copied().collect()
+        }
+        DocumentSelection::FolderId(_) => Vec::new(),
+    };
+    for share in &shared {
+        let record = AuditRecord::new(AuditAction::PermissionGranted)
+            .with_actor(user.user_id)
+            .with_target(share.document_id)
+            .with_detail(json!({ "user_id": request.user_id, "role": request.role }));
+        state.audit.record(&origin, record).await;
+        notify_room(&state, share.document_id, RoomNotice::AccessChanged).await;
+    }
+    Ok(Json(BulkShareResponse { shared, skipped }))
+}
+
+/// The reverse of `bulk_share_handler`.
+async fn bulk_unshare_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    origin: RequestOrigin,
+    Valid(request): Valid<BulkUnshareRequest>,
+) -> Result<Json<BulkUnshareResponse>, ApiError> {
+    let unshared = state.permissions.unshare_documents(&request.selection, request.user_id, user.user_id).await?;
+    for doc_id in &unshared {
+        let record = AuditRecord::new(AuditAction::PermissionRevoked)
+            .with_actor(user.user_id)
+            .with_target(doc_id)
+            .with_detail(json!({ "user_id": request.user_id }));
+        state.audit.record(&origin, record).await;
+        notify_room(&state, *doc_id, RoomNotice::AccessChanged).await;
+    }
+    Ok(Json(BulkUnshareResponse { unshared }))
+}
+
+/// Every document a user can open. Users can list their own; listing anyone else's needs
+/// the admin token.
+async fn accessible_documents_handler(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<AccessibleDocument>>, ApiError> {
+    if user.is_none_or(|user| user.user_id != user_id) {
+        require_admin(&state, &headers)?;
+    }
+    Ok(Json(state.permissions.accessible_documents(user_id).await?))
+}
+
+/// Membership, policies and deletion approval are managed by workspace admins.
+async fn require_workspace_admin(state: &AppState, workspace_id: Uuid, user: &AuthenticatedUser) -> Result<(), ApiError> {
+    match state.permissions.member_role(workspace_id, user.user_id).await? {
+        Some(MemberRole::Admin) => Ok(()),
+        _ => Err(ApiError::new(StatusCode::FORBIDDEN, "workspace-admin-required")),
+    }
+}
+
+/// Adds a user to a workspace, or changes their role in it.
+async fn set_member_handler(
+    State(state): State<Arc<AppState>>,
+    Path((workspace_id, member_id)): Path<(Uuid, Uuid)>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<MemberRequest>,
+) -> Result<StatusCode, ApiError> {
+    require_workspace_admin(&state, workspace_id, &user).await?;
+    if state.users.get_user(member_id).await?.is_none() {
+        return Err(ApiError::not_found("user-not-found"));
+    }
+    state.permissions.set_member(workspace_id, member_id, request.role).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_member_handler(
+    State(state): State<Arc<AppState>>,
+    Path((workspace_id, member_id)): Path<(Uuid, Uuid)>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    require_workspace_admin(&state, workspace_id, &user).await?;
+    state.permissions.remove_member(workspace_id, member_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// What members get on the workspace's documents. Visible to all members.
+async fn get_default_policy_handler(
+    State(state): State<Arc<AppState>>,
+    Path(workspace_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<Json<DefaultPolicy>, ApiError> {
+    if state.permissions.member_role(workspace_id, user.user_id).await?.is_none() {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "workspace-member-required"));
+    }
+    let policy = state.permissions.default_policy(workspace_id).await?
+        .ok_or_else(|| ApiError::not_found("default-policy-not-found"))?;
+    Ok(Json(policy))
+}
+
+/// Sets the default policy; it applies to the workspace's existing documents as well as new ones.
+async fn set_default_policy_handler(
+    State(state): State<Arc<AppState>>,
+    Path(workspace_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<DefaultPolicyRequest>,
+) -> Result<Json<DefaultPolicy>, ApiError> {
+    require_workspace_admin(&state, workspace_id, &user).await?;
+    Ok(Json(state.permissions.set_default_policy(workspace_id, request.member_role, user.user_id).await?))
+}
+
+async fn clear_default_policy_handler(
+    State(state): State<Arc<AppState>>,
+    Path(workspace_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    require_workspace_admin(&state, workspace_id, &user).await?;
+    state.permissions.clear_default_policy(workspace_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The transforms applied to the workspace's exports. Patterns may themselves be sensitive,
+/// so only admins see them.
+async fn get_export_transforms_handler(
+    State(state): State<Arc<AppState>>,
+    Path(workspace_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<Json<WorkspaceTransforms>, ApiError> {
+    require_workspace_admin(&state, workspace_id, &user).await?;
+    let transforms = state.export_transforms.for_workspace(workspace_id).await?
+        .ok_or_else(|| ApiError::not_found("export-transforms-not-found"))?;
+    Ok(Json(transforms))
+}
+
+/// Replaces the workspace's transforms; they apply to the next export.
+async fn set_export_transforms_handler(
+    State(state): State<Arc<AppState>>,
+    Path(workspace_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    Valid(request): Valid<ExportTransformsRequest>,
+) -> Result<Json<WorkspaceTransforms>, ApiError> {
+    require_workspace_admin(&state, workspace_id, &user).await?;
+    Ok(Json(state.export_transforms.set(workspace_id, &request.transforms, user.user_id).await?))
+}
+
+async fn clear_export_transforms_handler(
+    State(state): State<Arc<AppState>>,
+    Path(workspace_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    require_workspace_admin(&state, workspace_id, &user).await?;
+    if !state.export_transforms.clear(workspace_id).await? {
+        return Err(ApiError::not_found("export-transforms-not-found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Subscribes the signed-in user to digests of changes to a document.
+async fn watch_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    if !Caller::User(user.user_id).document_access(&state, doc_id).await?.read {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+    if !state.watches.watch(user.user_id, doc_id).await? {
+        return Err(ApiError::not_found("document-not-found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unwatch_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<StatusCode, ApiError> {
+    state.watches.unwatch(user.user_id, doc_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn notifications_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<Notification>>, ApiError> {
+    Ok(Json(state.watches.unread_notifications(user.user_id, NOTIFICATIONS_PAGE_SIZE).await?))
+}
+
+/// Catches an offline client up on many documents in one round trip.
+async fn sync_handler(
+    State(state): State<Arc<AppState>>,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    Valid(request): Valid<SyncRequest>,
+) -> Result<Json<SyncResponse>, ApiError> {
+    let ids: Vec<Uuid> = request.documents.keys().copied().collect();
+    let readable: Vec<Uuid> = caller(&state, user, &headers).await?.readable_documents(&state, &ids).await?.into_iter().collect();
+    Ok(Json(sync::sync(&state.doc_service, &request, Some(&readable)).await?))
+}
+
+/// Who is making a request. Bots are limited to the documents their scopes name. Other
+/// callers are limited by ownership, sharing and workspace policy, except on documents with
+/// neither an owner nor a workspace, which anyone may use.
+enum Caller {
+    Anonymous,
+    /// Anonymous, but with a guest identity; guests have the same access as anyone else.
+    Guest(Guest),
+    User(Uuid),
+    Bot(BotPrincipal),
+    /// An OAuth client acting for a user, with no more access than the user granted it.
+    Delegated(Delegation),
+    /// A script using one of a user's API keys, with no more access than the key allows.
+    Keyed(ApiKeyGrant),
+    /// Anonymous, or a guest, holding a share link: what anyone may do, plus what the
+    /// link grants on its document.
+    Linked(ShareLink, Option<Guest>),
+}
+
+impl Caller {
+    /// Guests are held to stricter limits on what their WebSockets may send.
+    fn traffic_class(&self) -> TrafficClass {
+        match self {
+            Caller::Anonymous | Caller::Guest(_) | Caller::Linked(..) => TrafficClass::Guest,
+            Caller::User(_) | Caller::Bot(_) | Caller::Delegated(_) | Caller::Keyed(_) => TrafficClass::Editor,
+        }
+    }
+
+    async fn document_access(&self, state: &AppState, doc_id: Uuid) -> Result<DocumentAccess, ApiError> {
+        match self {
+            Caller::Bot(bot) => Ok(bot.document_access(doc_id)),
+            Caller::Delegated(delegation) => {
+                let access = state.permissions.document_access(Some(delegation.user_id), doc_id).await?;
+                Ok(delegation.limit(access.unwrap_or(DocumentAccess::FULL)))
+            }
+            Caller::Keyed(grant) => {
+                let access = state.permissions.document_access(Some(grant.user_id), doc_id).await?;
+                Ok(grant.limit(doc_id, access.unwrap_or(DocumentAccess::FULL)))
+            }
+            Caller::Linked(link, _) => {
+                let access = state.permissions.document_access(None, doc_id).await?;
+                Ok(link.extend(doc_id, access.unwrap_or(DocumentAccess::FULL)))
+            }
+            Caller::Anonymous | Caller::Guest(_) | Caller::User(_) => {
+                let access = state.permissions.document_access(self.owner(), doc_id).await?;
+                Ok(access.unwrap_or(DocumentAccess::FULL))
+            }
+        }
+    }
+
+    /// Those of `doc_ids` the caller may read, checked together.
+    async fn readable_documents(&self, state: &AppState, doc_ids: &[Uuid]) -> Result<HashSet<Uuid>, ApiError> {
+        let (user_id, delegation) = match self {
+            Caller::Bot(bot) => {
+                return Ok(doc_ids.iter().copied().filter(|doc_id| bot.document_access(*doc_id).read).collect());
+            }
+            Caller::Delegated(delegation) => (Some(delegation.user_id), Some(delegation)),
+            Caller::Anonymous | Caller::Guest(_) | Caller::User(_) | Caller::Keyed(_) | Caller::Linked(..) => (self.owner(), None),
+        };
+        let accesses = state.permissions.document_accesses(user_id, doc_ids).await?;
+        Ok(doc_ids
+            .iter()
+            .copied()
+            .filter(|doc_id| {
+                let access = accesses.get(doc_id).copied().unwrap_or(DocumentAccess::FULL);
+                let access = match self {
+                    Caller::Linked(link, _) => link.extend(*doc_id, access),
+                    Caller::Keyed(grant) => grant.limit(*doc_id, access),
+                    _ => access,
+                };
+                delegation.map_or(access, |delegation| delegation.limit(access)).read
+            })
+            .collect())
+    }
+
+    /// The account new documents created by the caller belong to.
+    fn owner(&self) -> Option<Uuid> {
+        match self {
+            Caller::User(user_id) => Some(*user_id),
+            Caller::Delegated(delegation) => Some(delegation.user_id),
+            Caller::Keyed(grant) => Some(grant.user_id),
+            Caller::Anonymous | Caller::Guest(_) | Caller::Bot(_) | Caller::Linked(..) => None,
+        }
+    }
+
+    /// Who versions the caller saves are attributed to: an account, or a guest.
+    fn author(&self) -> Option<Uuid> {
+        match self {
+            Caller::User(user_id) => Some(*user_id),
+            Caller::Delegated(delegation) => Some(delegation.user_id),
+            Caller::Keyed(grant) => Some(grant.user_id),
+            Caller::Guest(guest) | Caller::Linked(_, Some(guest)) => Some(guest.id),
+            Caller::Anonymous | Caller::Bot(_) | Caller::Linked(_, None) => None,
+        }
+    }
+
+    /// For callers without an account, the documents beyond those anyone may use that they
+    /// may read; `None` for callers limited by their account or scopes instead.
+    fn public_documents(&self) -> Option<Vec<Uuid>> {
+        match self {
+            Caller::Anonymous | Caller::Guest(_) => Some(Vec::new()),
+            Caller::Linked(link, _) => Some(vec![link.document_id]),
+            Caller::User(_) | Caller::Bot(_) | Caller::Delegated(_) | Caller::Keyed(_) => None,
+        }
+    }
+
+    /// Documents the caller may read, or `None` for no restriction.
+    fn visible_documents(&self) -> Option<Vec<Uuid>> {
+        match self {
+            Caller::Bot(bot) => Some(bot.visible_documents()),
+            Caller::Delegated(delegation) if !delegation.allows(OAuthScope::ReadDocuments) => Some(Vec::new()),
+            Caller::Keyed(grant) => grant.documents.clone(),
+            Caller::Anonymous | Caller::Guest(_) | Caller::User(_) | Caller::Delegated(_) | Caller::Linked(..) => None,
+        }
+    }
+}
+
+/// Identifies the caller from an access token or API key checked by `tokens::authenticate`,
+/// or else from a bot token, OAuth access token or API key limited to particular documents.
+/// Any other bearer credential is rejected. Without
+/// one, a guest cookie identifies a guest; an unknown one is ignored. A share link in
+/// `X-Share-Link` then adds to what the anonymous caller or guest may do, and one that is
+/// unknown, expired or revoked is rejected.
+async fn caller(state: &AppState, user: Option<AuthenticatedUser>, headers: &HeaderMap) -> Result<Caller, ApiError> {
+    let share_link = headers.get(SHARE_LINK_HEADER).and_then(|value| value.to_str().ok());
+    linked_caller(state, user, headers, share_link).await
+}
+
+/// `caller`, with the share link, if any, given separately; WebSockets opened from a
+/// browser can't send headers, so they pass it in the URL.
+async fn linked_caller(
+    state: &AppState,
+    user: Option<AuthenticatedUser>,
+    headers: &HeaderMap,
+    share_link: Option<&str>,
+) -> Result<Caller, ApiError> {
+    if let Some(user) = user {
+        return Ok(match user.credential {
+            Credential::Session(_) => Caller::User(user.user_id),
+            Credential::ApiKey { key_id, read_only } => {
+                Caller::Keyed(ApiKeyGrant { key_id, user_id: user.user_id, read_only, documents: None })
+            }
+        });
+    }
+    match bearer_token(headers) {
+        Some(token) => {
+            if let Some(bot) = state.users.authenticate_bot_token(token).await? {
+                return Ok(Caller::Bot(bot));
+            }
+            if let Some(grant) = state.api_keys.authenticate(token).await? {
+                return Ok(Caller::Keyed(grant));
+            }
+            match state.oauth.authenticate(token).await? {
+                Some(delegation) => Ok(Caller::Delegated(delegation)),
+                None => Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid-token")),
+            }
+        }
+        None => {
+            let guest = match guest_cookie(headers) {
+                Some(secret) => state.users.authenticate_guest(secret).await?,
+                None => None,
+            };
+            let Some(secret) = share_link else {
+                return Ok(guest.map_or(Caller::Anonymous, Caller::Guest));
+            };
+            match state.permissions.authenticate_share_link(secret).await? {
+                Some(link) => Ok(Caller::Linked(link, guest)),
+                None => Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid-share-link")),
+            }
+        }
+    }
+}
+
+/// Ingests a batch of client telemetry. Clients signalling `DNT` or `Sec-GPC` are
+/// acknowledged but nothing is stored.
+async fn events_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    batch: Result<Json<ClientEventBatch>, JsonRejection>,
+) -> Result<(StatusCode, Json<EventsAccepted>), ApiError> {
+    let Json(batch) = batch.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-request-body"))?;
+    batch.validate(Utc::now()).map_err(|invalid| match invalid.index {
+        Some(index) => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid-analytics-event")
+            .with_arg("index", index)
+            .with_arg("reason", invalid.reason),
+        None => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid-analytics-batch")
+            .with_arg("reason", invalid.reason),
+    })?;
+
+    let opted_out = ["dnt", "sec-gpc"].iter().any(|name| headers.get(*name).is_some_and(|value| value == "1"));
+    let accepted = if opted_out { 0 } else { state.analytics.record(&batch).await? };
+    Ok((StatusCode::ACCEPTED, Json(EventsAccepted { accepted })))
 }
 
 async fn websocket_handler(
@@ -61,15 +4075,831 @@ async fn websocket_handler(
 }
 
 async fn handle_socket(mut socket: WebSocket) {
-    println!("WebSocket client connected");
+    debug!("WebSocket client connected");
     while let Some(Ok(msg)) = socket.recv().await {
         if let Message::Text(text) = msg {
-            println!("Received WebSocket message: {}", text);
+            debug!("Received WebSocket message: {}", text);
             if socket.send(Message::Text(format!("You said: {}", text))).await.is_err() {
                 // Client disconnected
-                println!("WebSocket client disconnected");
+                debug!("WebSocket client disconnected");
+                break;
+            }
+        }
+    }
+}
+
+/// Loads the caller's account, refusing unless its role is at least `role`.
+async fn require_role(state: &AppState, user: &AuthenticatedUser, role: UserRole) -> Result<User, ApiError> {
+    match state.users.get_user(user.user_id).await? {
+        Some(account) if account.role >= role && !account.suspended => Ok(account),
+        _ => Err(ApiError::new(StatusCode::FORBIDDEN, "admin-forbidden")),
+    }
+}
+
+/// Loads an account an administrator means to manage. Administrators only manage accounts
+/// whose role is below their own, which also keeps them from locking themselves out.
+async fn managed_account(state: &AppState, admin: &User, user_id: Uuid) -> Result<User, ApiError> {
+    let account = state.users.get_user(user_id).await?
+        .ok_or_else(|| ApiError::not_found("user-not-found"))?;
+    if account.role >= admin.role {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "admin-outranked"));
+    }
+    Ok(account)
+}
+
+/// Checks the caller holds the deployment's admin token.
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(expected) = &state.admin_token else {
+        return Err(ApiError::not_found("admin-disabled"));
+    };
+    let presented = bearer_token(headers).unwrap_or_default();
+    // Compare without short-circuiting so response timing doesn't reveal a matching prefix.
+    let matches = presented.len() == expected.len()
+        && presented.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    if !matches {
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid-token"));
+    }
+    Ok(())
+}
+
+/// Replays the document event log from `?from_seq=`, for integrations backfilling their
+/// history. Requires the admin token.
+async fn event_stream_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    query: Result<Query<EventStreamQuery>, QueryRejection>,
+) -> Result<Json<EventStreamResponse>, ApiError> {
+    require_admin(&state, &headers)?;
+    let Query(query) = query.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-event-stream-query"))?;
+    let events = state.doc_service
+        .replay_events(query.from_seq, query.limit.unwrap_or(outbox::MAX_REPLAY_BATCH))
+        .await?;
+    let next_seq = events.last().map_or(query.from_seq, |event| event.seq + 1);
+    Ok(Json(EventStreamResponse { events, next_seq }))
+}
+
+/// Checks this node's database, clocks, blob store, email transport and queues, for
+/// attaching to support escalations. Requires the admin token.
+async fn diagnostics_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<DiagnosticsReport>, ApiError> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.diagnostics.run(state.region.clone(), &state.concurrency).await))
+}
+
+/// For load balancer health checks: fails once the node starts draining, so it is taken
+/// out of rotation before its connections move.
+async fn readiness_handler(State(state): State<Arc<AppState>>) -> StatusCode {
+    if state.drain.is_draining() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    }
+}
+
+/// Starts draining this node ahead of an orchestrated shutdown, and reports progress as
+/// `GET /admin/drain` does. Readiness fails from then on and new document WebSockets are
+/// refused; rooms save what they hold, then tell their clients to reconnect elsewhere.
+/// Draining is not undone short of a restart. Requires the admin token.
+async fn drain_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    origin: RequestOrigin,
+) -> Result<(StatusCode, Json<DrainProgress>), ApiError> {
+    require_admin(&state, &headers)?;
+    if state.drain.start() {
+        info!("Draining; handing over document rooms");
+        state.audit.record(&origin, AuditRecord::new(AuditAction::DrainStarted)).await;
+        let state = state.clone();
+        tokio::spawn(async move { hand_over_rooms(&state).await });
+    }
+    Ok((StatusCode::ACCEPTED, Json(DrainProgress::of(&state))))
+}
+
+/// How far draining has got, for orchestrators to wait on `complete` before stopping the
+/// node. Requires the admin token.
+async fn drain_progress_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<DrainProgress>, ApiError> {
+    require_admin(&state, &headers)?;
+    Ok(Json(DrainProgress::of(&state)))
+}
+
+/// Account administration, for signed-in admins and superadmins. Unlike `/admin`, which
+/// takes the server's admin token, these check the caller's role.
+fn admin_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/users", get(admin_list_users_handler))
+        .route("/users/:id/deactivate", post(admin_deactivate_user_handler))
+        .route("/users/:id/reactivate", post(admin_reactivate_user_handler))
+        .route("/users/:id/role", put(admin_set_role_handler))
+        .route("/stats", get(admin_stats_handler))
+        .route("/audit-events", get(admin_audit_events_handler))
+}
+
+/// Every account, in the order they were created.
+async fn admin_list_users_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    query: Result<Query<ListUsersQuery>, QueryRejection>,
+) -> Result<Json<UserListResponse>, ApiError> {
+    require_role(&state, &user, UserRole::Admin).await?;
+    let Query(query) = query.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-listing-query"))?;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let users = state.users.list_users(query.after, limit as i64).await?;
+    let next_after = users.last().filter(|_| users.len() == limit).map(|user| user.id);
+    Ok(Json(UserListResponse { users: users.into_iter().map(ManagedUser::from).collect(), next_after }))
+}
+
+/// The audit log, newest first.
+async fn admin_audit_events_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    query: Result<Query<AuditQuery>, QueryRejection>,
+) -> Result<Json<AuditEventsResponse>, ApiError> {
+    require_role(&state, &user, UserRole::Admin).await?;
+    let Query(query) = query.map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "invalid-listing-query"))?;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let filter = AuditFilter {
+        action: query.action,
+        actor: query.actor,
+        target: query.target,
+        ip: query.ip,
+        since: query.since,
+        until: query.until,
+        before: query.before,
+    };
+    let events = state.audit.events(&filter, limit as i64).await?;
+    let next_before = events.last().filter(|_| events.len() == limit).map(|event| event.id);
+    Ok(Json(AuditEventsResponse { events, next_before }))
+}
+
+/// Suspends an account and ends its sessions until an administrator reactivates it.
+async fn admin_deactivate_user_handler(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    origin: RequestOrigin,
+) -> Result<StatusCode, ApiError> {
+    let admin = require_role(&state, &user, UserRole::Admin).await?;
+    managed_account(&state, &admin, user_id).await?;
+    if state.users.deactivate_user(user_id).await? {
+        info!("User ID {} deactivated by {}", user_id, admin.id);
+        state.audit.record(&origin, AuditRecord::new(AuditAction::AccountSuspended).with_actor(admin.id).with_target(user_id)).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn admin_reactivate_user_handler(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    origin: RequestOrigin,
+) -> Result<StatusCode, ApiError> {
+    let admin = require_role(&state, &user, UserRole::Admin).await?;
+    managed_account(&state, &admin, user_id).await?;
+    if state.users.reactivate_user(user_id).await? {
+        info!("User ID {} reactivated by {}", user_id, admin.id);
+        state.audit.record(&origin, AuditRecord::new(AuditAction::AccountReactivated).with_actor(admin.id).with_target(user_id)).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Makes an account a user or an admin. Only superadmins may, and only the
+/// `set-role` command makes more superadmins.
+async fn admin_set_role_handler(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<Uuid>,
+    user: AuthenticatedUser,
+    origin: RequestOrigin,
+    Valid(request): Valid<RoleRequest>,
+) -> Result<Json<ManagedUser>, ApiError> {
+    let admin = require_role(&state, &user, UserRole::Superadmin).await?;
+    let account = managed_account(&state, &admin, user_id).await?;
+    if request.role >= admin.role {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "admin-outranked"));
+    }
+    state.users.set_role(user_id, request.role).await?;
+    info!("User ID {} made {} by {}", user_id, request.role.as_str(), admin.id);
+    let record = AuditRecord::new(AuditAction::RoleChanged)
+        .with_actor(admin.id)
+        .with_target(user_id)
+        .with_detail(json!({ "from": account.role, "to": request.role }));
+    state.audit.record(&origin, record).await;
+    Ok(Json(ManagedUser::from(User { role: request.role, ..account })))
+}
+
+/// Counts of accounts and documents, with the answering node's gauges.
+async fn admin_stats_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<ServerStats>, ApiError> {
+    require_role(&state, &user, UserRole::Admin).await?;
+    Ok(Json(ServerStats {
+        users: state.users.count_users().await?,
+        documents: state.doc_service.count_documents().await?,
+        node: MetricsSnapshot::collect(&state.connections, &state.rooms, &state.doc_service, &state.concurrency).await,
+    }))
+}
+
+/// Documents waiting for a moderation decision, oldest first.
+async fn moderation_queue_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Review>>, ApiError> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.moderation.pending_reviews().await?))
+}
+
+/// Approves or rejects a pending review. Approving lets the reviewed content be shared by
+/// link; rejecting revokes the document's share links.
+async fn moderation_decision_handler(
+    State(state): State<Arc<AppState>>,
+    Path(review_id): Path<Uuid>,
+    headers: HeaderMap,
+    origin: RequestOrigin,
+    Valid(request): Valid<ModerationDecisionRequest>,
+) -> Result<Json<Review>, ApiError> {
+    require_admin(&state, &headers)?;
+    let approve = matches!(request.decision, ModerationDecision::Approve);
+    let review = state.moderation.decide(review_id, approve, request.note.as_deref()).await?
+        .ok_or_else(|| ApiError::not_found("moderation-review-not-found"))?;
+    let record = AuditRecord::new(AuditAction::ModerationDecided)
+        .with_target(review.id)
+        .with_detail(json!({ "document_id": review.document_id, "approved": approve }));
+    state.audit.record(&origin, record).await;
+    if !approve {
+        let doc_id = review.document_id;
+        for link in state.permissions.share_links(doc_id).await? {
+            state.permissions.revoke_share_link(doc_id, link.id).await?;
+        }
+        notify_room(&state, doc_id, RoomNotice::AccessChanged).await;
+    }
+    Ok(Json(review))
+}
+
+/// Everything moderation decided about a document, oldest first.
+async fn moderation_log_handler(
+    State(state): State<Arc<AppState>>,
+    Path(doc_id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<LogEntry>>, ApiError> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.moderation.log_entries(doc_id).await?))
+}
+
+/// Streams this node's gauges as JSON text frames every `?interval_ms=` (default 1s).
+async fn metrics_socket_handler(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(query): Query<MetricsQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, ApiError> {
+    require_admin(&state, &headers)?;
+    let interval = metrics::metrics_interval(query.interval_ms);
+    Ok(ws.on_upgrade(move |socket| stream_metrics(socket, state, interval)))
+}
+
+async fn stream_metrics(mut socket: WebSocket, state: Arc<AppState>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut shutdown = state.shutdown.clone();
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                send_closing(&mut socket, Locale::FALLBACK, CloseCode::ShuttingDown).await;
                 break;
+            },
+            _ = ticker.tick() => {
+                let snapshot = MetricsSnapshot::collect(&state.connections, &state.rooms, &state.doc_service, &state.concurrency).await;
+                let text = serde_json::to_string(&snapshot).expect("metrics are always serializable");
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn document_socket_handler(
+    ws: WebSocketUpgrade,
+    Path(doc_id): Path<Uuid>,
+    locale: Locale,
+    user: Option<AuthenticatedUser>,
+    headers: HeaderMap,
+    Query(query): Query<DocumentSocketQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, ApiError> {
+    if state.drain.is_draining() {
+        return Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "server-draining"));
+    }
+    let session = user.and_then(|user| user.session_id());
+    let share_link = query.share_link.as_deref()
+        .or_else(|| headers.get(SHARE_LINK_HEADER).and_then(|value| value.to_str().ok()));
+    let caller = linked_caller(&state, user, &headers, share_link).await?;
+    let access = caller.document_access(&state, doc_id).await?;
+    // Cursor offsets point into the content, so watching presence needs read access.
+    if !access.any() || (query.mode == SocketMode::Presence && !access.read) {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "document-forbidden"));
+    }
+
+    match state.doc_service.get_document_metadata(doc_id).await {
+        Ok(Some(_)) => {
+            let client = SocketClient { caller, session, locale, access };
+            // The socket outlives the request, so it carries the request's span along.
+            let span = Span::current();
+            // Anything over every connection's frame limit is refused by the transport rather
+            // than read in full; the room refuses what is over the caller's own limit.
+            let max_message_bytes = state.rooms.message_limits().max_frame_bytes() + FRAME_HEADER_BYTES;
+            let ws = ws.protocols([protocol::SUBPROTOCOL]).max_message_size(max_message_bytes);
+            Ok(match query.mode {
+                SocketMode::Edit => ws.on_upgrade(move |socket| handle_document_socket(socket, state, doc_id, client).instrument(span)),
+                SocketMode::Presence => ws.on_upgrade(move |socket| handle_presence_socket(socket, state, doc_id, client).instrument(span)),
+            })
+        }
+        Ok(None) => Err(ApiError::not_found("document-not-found")),
+        Err(e) => Err(ApiError::internal(e.context(format!("Failed to look up document {} for WebSocket", doc_id)))),
+    }
+}
+
+/// Who is on the other end of a document WebSocket, as checked when it was opened.
+struct SocketClient {
+    caller: Caller,
+    /// The session of a signed-in user, rechecked while the socket is open.
+    session: Option<Uuid>,
+    locale: Locale,
+    access: DocumentAccess,
+}
+
+async fn handle_document_socket(mut socket: WebSocket, state: Arc<AppState>, doc_id: Uuid, client: SocketClient) {
+    let SocketClient { caller, session, locale, mut access } = client;
+    let _connection = state.connections.track();
+    let connection_id = state.rooms.next_connection_id();
+    let (room, mut events) = state.rooms.join(doc_id);
+    // Clients that negotiated the framed protocol get every binary frame wrapped.
+    let framed = socket.protocol().is_some();
+    debug!("WebSocket client {} joined document {}", connection_id, doc_id);
+
+    if access.read && !(send_snapshot(&mut socket, &room, locale, framed).await && send_participants(&mut socket, &room).await) {
+        return;
+    }
+
+    let mut recheck = tokio::time::interval(ACCESS_RECHECK_INTERVAL);
+    recheck.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    recheck.reset();
+    let mut close_code = None;
+    loop {
+        tokio::select! {
+            _ = recheck.tick() => match recheck_access(&state, &caller, session, doc_id, access).await {
+                Ok(current) => access = current,
+                Err(code) => {
+                    close_code = Some(code);
+                    break;
+                }
+            },
+            msg = socket.recv() => match unwrap_frame(framed, msg) {
+                Some(Ok(Frame::Sync(SyncMessage::SyncStep1 { checksum }))) if access.read => {
+                    if !send_sync_step_2(&mut socket, &state, &room, doc_id, locale, checksum).await {
+                        break;
+                    }
+                }
+                Some(Ok(Frame::Sync(SyncMessage::Ping))) => {
+                    if socket.send(Message::Binary(protocol::encode(&SyncMessage::Pong))).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Frame::Sync(SyncMessage::Pong))) => {}
+                Some(Ok(Frame::Sync(_))) | Some(Ok(Frame::Malformed)) => {
+                    close_code = Some(CloseCode::ProtocolError);
+                    break;
+                }
+                Some(Ok(Frame::Message(Message::Binary(_)))) if !access.write => {
+                    if socket.send(ServerFrame::error(locale, "update-forbidden").to_message()).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Frame::Message(Message::Binary(data)))) => {
+                    if let Err(e) = room.submit_frame(connection_id, caller.author(), access.role, caller.traffic_class(), data).await {
+                        if matches!(e.downcast_ref(), Some(UpdateRejection::LimitExceeded)) {
+                            close_code = Some(CloseCode::MessageLimit);
+                            break;
+                        }
+                        let frame = ServerFrame::error(locale, rejection_key(doc_id, &e));
+                        if socket.send(frame.to_message()).await.is_err() {
+                            break;
+                        }
+                        // The client applied its edit locally; send the content back so it reverts.
+                        let refused = matches!(
+                            e.downcast_ref(),
+                            Some(
+                                UpdateRejection::ProtectedRange
+                                    | UpdateRejection::RateLimited
+                                    | UpdateRejection::FrameTooLarge
+                                    | UpdateRejection::DocumentTooLarge
+                            )
+                        );
+                        if refused && access.read && !send_snapshot(&mut socket, &room, locale, framed).await {
+                            break;
+                        }
+                    }
+                }
+                Some(Ok(Frame::Message(Message::Text(text)))) => {
+                    let reply = match serde_json::from_str::<ClientFrame>(&text) {
+                        Ok(ClientFrame::ChangeProtection { .. }) if access.role != DocumentRole::Owner => {
+                            Some(ServerFrame::error(locale, "protection-forbidden").to_message())
+                        }
+                        Ok(ClientFrame::ChangeProtection { change }) => match room.change_protection(change).await {
+                            Ok(ranges) => Some(ServerFrame::ProtectedRanges { ranges: &ranges }.to_message()),
+                            Err(e) => Some(ServerFrame::error(locale, rejection_key(doc_id, &e)).to_message()),
+                        },
+                        Ok(ClientFrame::Presence(mut presence)) => {
+                            // Guests appear as they chose to, whichever device they are on.
+                            if let Caller::Guest(guest) | Caller::Linked(_, Some(guest)) = &caller {
+                                presence.display_name = guest.display_name.clone();
+                                presence.color = Some(guest.color.clone());
+                            }
+                            presence.display_name = presence.display_name.trim().to_string();
+                            presence.region = state.region.clone();
+                            if !validation::length(&presence.display_name, 1, user_service::MAX_DISPLAY_NAME_LENGTH) {
+                                let max = user_service::MAX_DISPLAY_NAME_LENGTH.to_string();
+                                Some(ServerFrame::error_with_args(locale, "invalid-display-name", &[("max", max)]).to_message())
+                            } else if !presence.color.as_deref().is_none_or(validation::is_color) {
+                                Some(ServerFrame::error(locale, "invalid-color").to_message())
+                            } else {
+                                let update = PresenceUpdate::Set { presence };
+                                room.update_presence(connection_id, update).await.err().map(|e| {
+                                    ServerFrame::error(locale, rejection_key(doc_id, &e)).to_message()
+                                })
+                            }
+                        }
+                        Ok(ClientFrame::Heartbeat) => room
+                            .update_presence(connection_id, PresenceUpdate::Heartbeat)
+                            .await
+                            .err()
+                            .map(|e| ServerFrame::error(locale, rejection_key(doc_id, &e)).to_message()),
+                        Err(_) => {
+                            close_code = Some(CloseCode::ProtocolError);
+                            break;
+                        }
+                    };
+                    if let Some(reply) = reply && socket.send(reply).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Frame::Message(Message::Close(_)))) | Some(Err(_)) | None => break,
+                Some(Ok(Frame::Message(_))) => {}
+            },
+            event = events.recv() => {
+                let sent = match event {
+                    Ok(RoomEvent::Update { origin, data }) if origin != connection_id && access.read => {
+                        socket.send(content_message(framed, data.to_vec(), SyncMessage::Update)).await.is_ok()
+                    }
+                    Ok(RoomEvent::Update { .. }) => true,
+                    Ok(RoomEvent::SaveStatus(status)) => {
+                        socket.send(ServerFrame::SaveStatus(&status).to_message()).await.is_ok()
+                    }
+                    // Annotations quote the document's text, so they need read access.
+                    Ok(RoomEvent::Annotations(annotations)) if access.read => {
+                        socket.send(ServerFrame::Annotations(&annotations).to_message()).await.is_ok()
+                    }
+                    Ok(RoomEvent::Annotations(_)) => true,
+                    // Cursor offsets point into the content, so they need read access too.
+                    Ok(RoomEvent::Presence(change)) if access.read && change.connection_id() != connection_id => {
+                        let frame = ServerFrame::Presence(&change);
+                        socket.send(if framed { frame.to_awareness() } else { frame.to_message() }).await.is_ok()
+                    }
+                    Ok(RoomEvent::Presence(_)) => true,
+                    Ok(RoomEvent::Notice(RoomNotice::Comment(change))) if access.read => {
+                        socket.send(ServerFrame::Comment(&change).to_message()).await.is_ok()
+                    }
+                    Ok(RoomEvent::Notice(RoomNotice::Comment(_))) => true,
+                    Ok(RoomEvent::Notice(RoomNotice::Closing(code))) => {
+                        close_code = Some(code);
+                        break;
+                    }
+                    // The room closes the socket with `ShuttingDown` once it has saved.
+                    Ok(RoomEvent::Notice(RoomNotice::Migrating)) => send_migrate(&mut socket, &room, access.read).await,
+                    // Edits would only be refused by the room now.
+                    Ok(RoomEvent::Notice(RoomNotice::Expired)) => {
+                        access.write = false;
+                        let frame = ServerFrame::Expired { message: i18n::message(locale, "document-expired", &[]) };
+                        socket.send(frame.to_message()).await.is_ok()
+                    }
+                    // Shares changed; check straight away rather than at the next recheck.
+                    Ok(RoomEvent::Notice(RoomNotice::AccessChanged)) => {
+                        match recheck_access(&state, &caller, session, doc_id, access).await {
+                            Ok(current) => access = current,
+                            Err(code) => {
+                                close_code = Some(code);
+                                break;
+                            }
+                        }
+                        true
+                    }
+                    // Missed some updates; the latest snapshot supersedes them.
+                    Err(RecvError::Lagged(_)) if access.read => send_snapshot(&mut socket, &room, locale, framed).await,
+                    Err(RecvError::Lagged(_)) => true,
+                    Err(RecvError::Closed) => false,
+                };
+                if !sent {
+                    break;
+                }
+            },
+        }
+    }
+    let _ = room.update_presence(connection_id, PresenceUpdate::Leave).await;
+    if let Some(code) = close_code {
+        send_closing(&mut socket, locale, code).await;
+    }
+    debug!("WebSocket client {} left document {}", connection_id, doc_id);
+}
+
+/// The loop for a `?mode=presence` socket: the participants on joining, then their changes.
+/// Nothing here asks the room for its content, so a room opened only by such sockets never
+/// loads it; content-bearing events are dropped rather than forwarded.
+async fn handle_presence_socket(mut socket: WebSocket, state: Arc<AppState>, doc_id: Uuid, client: SocketClient) {
+    let SocketClient { caller, session, locale, mut access } = client;
+    let _connection = state.connections.track();
+    let (room, mut events) = state.rooms.join(doc_id);
+    let framed = socket.protocol().is_some();
+    debug!("Presence-only WebSocket client joined document {}", doc_id);
+
+    if !send_participants(&mut socket, &room).await {
+        return;
+    }
+
+    let mut recheck = tokio::time::interval(ACCESS_RECHECK_INTERVAL);
+    recheck.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    recheck.reset();
+    let mut close_code = None;
+    loop {
+        tokio::select! {
+            _ = recheck.tick() => match recheck_access(&state, &caller, session, doc_id, access).await {
+                Ok(current) if current.read => access = current,
+                Ok(_) => {
+                    close_code = Some(CloseCode::Kicked);
+                    break;
+                }
+                Err(code) => {
+                    close_code = Some(code);
+                    break;
+                }
+            },
+            msg = socket.recv() => match unwrap_frame(framed, msg) {
+                Some(Ok(Frame::Sync(SyncMessage::Ping))) => {
+                    if socket.send(Message::Binary(protocol::encode(&SyncMessage::Pong))).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Frame::Sync(SyncMessage::Pong))) => {}
+                Some(Ok(Frame::Sync(_))) | Some(Ok(Frame::Malformed)) => {
+                    close_code = Some(CloseCode::ProtocolError);
+                    break;
+                }
+                Some(Ok(Frame::Message(Message::Binary(_) | Message::Text(_)))) => {
+                    if socket.send(ServerFrame::error(locale, "presence-only-connection").to_message()).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Frame::Message(Message::Close(_)))) | Some(Err(_)) | None => break,
+                Some(Ok(Frame::Message(_))) => {}
+            },
+            event = events.recv() => {
+                let sent = match event {
+                    Ok(RoomEvent::Presence(change)) => {
+                        let frame = ServerFrame::Presence(&change);
+                        socket.send(if framed { frame.to_awareness() } else { frame.to_message() }).await.is_ok()
+                    }
+                    Ok(RoomEvent::Notice(RoomNotice::Closing(code))) => {
+                        close_code = Some(code);
+                        break;
+                    }
+                    Ok(RoomEvent::Notice(RoomNotice::Migrating)) => send_migrate(&mut socket, &room, false).await,
+                    Ok(RoomEvent::Notice(RoomNotice::AccessChanged)) => {
+                        match recheck_access(&state, &caller, session, doc_id, access).await {
+                            Ok(current) if current.read => access = current,
+                            Ok(_) => {
+                                close_code = Some(CloseCode::Kicked);
+                                break;
+                            }
+                            Err(code) => {
+                                close_code = Some(code);
+                                break;
+                            }
+                        }
+                        true
+                    }
+                    Ok(_) => true,
+                    // Missed some changes; the current participants supersede them.
+                    Err(RecvError::Lagged(_)) => send_participants(&mut socket, &room).await,
+                    Err(RecvError::Closed) => false,
+                };
+                if !sent {
+                    break;
+                }
+            },
+        }
+    }
+    if let Some(code) = close_code {
+        send_closing(&mut socket, locale, code).await;
+    }
+    debug!("Presence-only WebSocket client left document {}", doc_id);
+}
+
+/// Tells the client its room is moving, with the content it can resume from if it may read it.
+async fn send_migrate(socket: &mut WebSocket, room: &RoomHandle, read: bool) -> bool {
+    let checksum = if read {
+        match room.snapshot().await {
+            Ok(data) => Some(integrity::checksum(&data.unwrap_or_default())),
+            Err(e) => {
+                warn!("Failed to load document snapshot for migration: {:#}", e);
+                None
             }
         }
+    } else {
+        None
+    };
+    let reconnect_in_ms = rand::random_range(0..=MIGRATE_RECONNECT_SPREAD.as_millis() as u64);
+    socket.send(ServerFrame::Migrate { checksum, reconnect_in_ms }.to_message()).await.is_ok()
+}
+
+/// Tells the client why the socket is closing, then closes it with the matching code.
+async fn send_closing(socket: &mut WebSocket, locale: Locale, code: CloseCode) {
+    let key = code.message_key();
+    let frame = ServerFrame::Closing {
+        code: key,
+        message: i18n::message(locale, key, &[]),
+        close_code: code.code(),
+        reconnect: code.reconnect(),
+    };
+    if socket.send(frame.to_message()).await.is_ok() {
+        let close = CloseFrame { code: code.code(), reason: key.into() };
+        let _ = socket.send(Message::Close(Some(close))).await;
+    }
+}
+
+/// Checks that an open socket's caller is still signed in and may still use the document,
+/// returning its current access. Failed lookups keep the access it had.
+async fn recheck_access(
+    state: &AppState,
+    caller: &Caller,
+    session: Option<Uuid>,
+    doc_id: Uuid,
+    current: DocumentAccess,
+) -> Result<DocumentAccess, CloseCode> {
+    let signed_in = match (caller, session) {
+        (Caller::Bot(bot), _) => state.users.bot_token_active(bot.token_id).await,
+        (Caller::Delegated(delegation), _) => state.oauth.token_active(delegation.token_id).await,
+        (Caller::Keyed(grant), _) => state.api_keys.key_active(grant.key_id).await,
+        (Caller::Linked(link, _), _) => state.permissions.share_link_active(link.id).await,
+        (_, Some(session_id)) => state.users.session_active(session_id).await,
+        (_, None) => Ok(true),
+    };
+    match signed_in {
+        Ok(true) => {}
+        Ok(false) => return Err(CloseCode::Unauthorized),
+        Err(e) => {
+            warn!("Failed to recheck the session on document {}: {:#}", doc_id, e);
+            return Ok(current);
+        }
+    }
+    // Internal errors were already logged when converted to an `ApiError`.
+    match caller.document_access(state, doc_id).await {
+        Ok(access) if access.any() => Ok(access),
+        Ok(_) => Err(CloseCode::Kicked),
+        Err(_) => Ok(current),
+    }
+}
+
+/// The message key for a room refusing a command; unexpected failures are logged.
+fn rejection_key(doc_id: Uuid, e: &anyhow::Error) -> &'static str {
+    match e.downcast_ref::<UpdateRejection>() {
+        Some(rejection) => rejection.message_key(),
+        None => {
+            warn!("Failed to apply change to document {}: {:#}", doc_id, e);
+            "update-failed"
+        }
+    }
+}
+
+/// Sends who is already in the room; returns false if the socket is no longer usable.
+async fn send_participants(socket: &mut WebSocket, room: &RoomHandle) -> bool {
+    match room.participants().await {
+        Ok(participants) => socket.send(ServerFrame::Participants { participants: &participants }.to_message()).await.is_ok(),
+        Err(e) => {
+            warn!("Failed to list document participants: {:#}", e);
+            false
+        }
+    }
+}
+
+/// Sends the room's current content; returns false if the socket is no longer usable.
+async fn send_snapshot(socket: &mut WebSocket, room: &RoomHandle, locale: Locale, framed: bool) -> bool {
+    match room.snapshot().await {
+        Ok(Some(data)) => {
+            let message = content_message(framed, data, |data| SyncMessage::SyncStep2(Some(data)));
+            socket.send(message).await.is_ok()
+        }
+        Ok(None) => true,
+        Err(e) => {
+            warn!("Failed to load document snapshot: {:#}", e);
+            let frame = ServerFrame::error(locale, "document-load-failed");
+            let _ = socket.send(frame.to_message()).await;
+            false
+        }
+    }
+}
+
+/// Answers a framed client's sync-step-1: with nothing if the client already has the
+/// content, with the edit since if its content is in the update log, and with the content
+/// otherwise. Returns false if the socket is no longer usable.
+async fn send_sync_step_2(
+    socket: &mut WebSocket,
+    state: &AppState,
+    room: &RoomHandle,
+    doc_id: Uuid,
+    locale: Locale,
+    checksum: Option<String>,
+) -> bool {
+    let current = match room.snapshot().await {
+        Ok(data) => data.unwrap_or_default(),
+        Err(e) => {
+            warn!("Failed to load document snapshot: {:#}", e);
+            let frame = ServerFrame::error(locale, "document-load-failed");
+            let _ = socket.send(frame.to_message()).await;
+            return false;
+        }
+    };
+    let reply = match checksum {
+        Some(checksum) if checksum == integrity::checksum(&current) => SyncMessage::SyncStep2(None),
+        Some(checksum) => match state.doc_service.logged_content(doc_id, &checksum).await {
+            Ok(Some(previous)) => SyncMessage::SyncDelta(ContentDelta::between(&previous, &current)),
+            Ok(None) => SyncMessage::SyncStep2(Some(current)),
+            Err(e) => {
+                warn!("Failed to look up the content a client resumed document {} from: {:#}", doc_id, e);
+                SyncMessage::SyncStep2(Some(current))
+            }
+        },
+        None => SyncMessage::SyncStep2(Some(current)),
+    };
+    socket.send(Message::Binary(protocol::encode(&reply))).await.is_ok()
+}
+
+/// A message from a document WebSocket, with framed binary frames decoded.
+enum Frame {
+    Message(Message),
+    Sync(SyncMessage),
+    Malformed,
+}
+
+/// Decodes a framed client's binary frames. Updates and awareness come back as the bare
+/// content and text frames that unframed clients send, so both are handled alike.
+fn unwrap_frame(framed: bool, msg: Option<Result<Message, axum::Error>>) -> Option<Result<Frame, axum::Error>> {
+    msg.map(|msg| {
+        msg.map(|msg| match msg {
+            Message::Binary(frame) if framed => match protocol::decode(&frame) {
+                Ok(SyncMessage::Update(data)) => Frame::Message(Message::Binary(data)),
+                Ok(SyncMessage::Awareness(json)) => Frame::Message(Message::Text(json)),
+                Ok(message) => Frame::Sync(message),
+                Err(e) => {
+                    debug!("Malformed WebSocket frame: {}", e);
+                    Frame::Malformed
+                }
+            },
+            msg => Frame::Message(msg),
+        })
+    })
+}
+
+/// Wraps content as `wrap` for framed clients; unframed clients get it bare.
+fn content_message(framed: bool, data: Vec<u8>, wrap: impl FnOnce(Vec<u8>) -> SyncMessage) -> Message {
+    if framed {
+        Message::Binary(protocol::encode(&wrap(data)))
+    } else {
+        Message::Binary(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_keys_cannot_act_for_a_session() {
+        let signed_in = AuthenticatedUser { user_id: Uuid::new_v4(), credential: Credential::Session(Uuid::new_v4()) };
+        assert!(require_session(&signed_in, "oauth-authorize-forbidden").is_ok());
+
+        let keyed = AuthenticatedUser {
+            user_id: signed_in.user_id,
+            credential: Credential::ApiKey { key_id: Uuid::new_v4(), read_only: false },
+        };
+        let refused = require_session(&keyed, "oauth-authorize-forbidden").unwrap_err();
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,176 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::header::ACCEPT_LANGUAGE;
+use axum::http::request::Parts;
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use std::convert::Infallible;
+use std::sync::OnceLock;
+use unic_langid::LanguageIdentifier;
+
+/// Message catalogs compiled into the binary. The first entry is the fallback.
+const CATALOGS: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.ftl")),
+    ("de", include_str!("../locales/de.ftl")),
+    ("fr", include_str!("../locales/fr.ftl")),
+];
+
+/// A locale we have a catalog for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Locale(usize);
+
+impl Locale {
+    pub const FALLBACK: Locale = Locale(0);
+
+    /// Picks the best supported locale from an `Accept-Language` header value,
+    /// honouring q-values and falling back from regional variants (`de-AT`) to the language.
+    pub fn negotiate(accept_language: &str) -> Locale {
+        let mut ranges: Vec<(f32, &str)> = accept_language
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.trim().split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse().ok())?;
+                (!tag.is_empty() && quality > 0.0).then_some((quality, tag))
+            })
+            .collect();
+        // Stable sort keeps the client's order among equal q-values.
+        ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        ranges
+            .iter()
+            .find_map(|(_, tag)| Locale::from_tag(tag))
+            .unwrap_or(Locale::FALLBACK)
+    }
+
+    /// Resolves a language tag (e.g. from a stored user preference) to a supported locale.
+    pub fn from_tag(tag: &str) -> Option<Locale> {
+        let language = tag.split(['-', '_']).next()?.to_ascii_lowercase();
+        CATALOGS.iter().position(|(code, _)| *code == language).map(Locale)
+    }
+
+    pub fn tag(&self) -> &'static str {
+        CATALOGS[self.0].0
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Locale {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .headers
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(Locale::FALLBACK, Locale::negotiate))
+    }
+}
+
+struct Translator {
+    bundles: Vec<FluentBundle<FluentResource>>,
+}
+
+impl Translator {
+    fn new() -> Self {
+        let bundles = CATALOGS
+            .iter()
+            .map(|(code, source)| {
+                let language: LanguageIdentifier = code.parse().expect("catalog codes are valid language tags");
+                let resource = FluentResource::try_new(source.to_string())
+                    .unwrap_or_else(|(_, errors)| panic!("Invalid message catalog '{}': {:?}", code, errors));
+                let mut bundle = FluentBundle::new_concurrent(vec![language]);
+                // Unicode isolation marks around arguments confuse plain-text clients and email.
+                bundle.set_use_isolating(false);
+                bundle
+                    .add_resource(resource)
+                    .unwrap_or_else(|errors| panic!("Duplicate messages in catalog '{}': {:?}", code, errors));
+                bundle
+            })
+            .collect();
+        Translator { bundles }
+    }
+
+    fn format(&self, locale: Locale, key: &str, args: &[(&str, String)]) -> Option<String> {
+        let bundle = &self.bundles[locale.0];
+        let pattern = bundle.get_message(key)?.value()?;
+        let mut fluent_args = FluentArgs::new();
+        for (name, value) in args {
+            fluent_args.set(*name, value.clone());
+        }
+        let mut errors = Vec::new();
+        let message = bundle.format_pattern(pattern, Some(&fluent_args), &mut errors);
+        Some(message.into_owned())
+    }
+}
+
+fn translator() -> &'static Translator {
+    static TRANSLATOR: OnceLock<Translator> = OnceLock::new();
+    TRANSLATOR.get_or_init(Translator::new)
+}
+
+/// Renders a user-facing message in the given locale, falling back to English
+/// and finally to the key itself if the message is missing.
+pub fn message(locale: Locale, key: &str, args: &[(&str, String)]) -> String {
+    let translator = translator();
+    translator
+        .format(locale, key, args)
+        .or_else(|| translator.format(Locale::FALLBACK, key, args))
+        .unwrap_or_else(|| key.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiation() {
+        assert_eq!(Locale::negotiate("fr-CA, en;q=0.8").tag(), "fr");
+        assert_eq!(Locale::negotiate("en;q=0.5, de-AT;q=0.9").tag(), "de");
+        assert_eq!(Locale::negotiate("ja, de;q=0.1").tag(), "de");
+        assert_eq!(Locale::negotiate("ja, zh").tag(), "en");
+        assert_eq!(Locale::negotiate("de;q=0, *").tag(), "en");
+        assert_eq!(Locale::negotiate("").tag(), "en");
+    }
+
+    #[test]
+    fn test_catalogs_define_every_fallback_message() {
+        let translator = translator();
+        let keys = CATALOGS[0].1
+            .lines()
+            .filter(|line| !line.starts_with(['#', ' ']))
+            .filter_map(|line| line.split_once(" = ").map(|(key, _)| key));
+        for key in keys {
+            for (index, (code, _)) in CATALOGS.iter().enumerate() {
+                assert!(
+                    translator.bundles[index].has_message(key),
+                    "Catalog '{}' is missing message '{}'",
+                    code,
+                    key
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing_messages_fall_back() {
+        assert_eq!(message(Locale::negotiate("de"), "document-not-found", &[]), "Dokument nicht gefunden.");
+        assert_eq!(message(Locale::FALLBACK, "no-such-message", &[]), "no-such-message");
+    }
+}
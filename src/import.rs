@@ -0,0 +1,220 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Importing documents. Before a document is created, its content is compared with
+//! existing documents so the importer learns about copies they may not know exist.
+
+use crate::document_service::{DocumentMetadata, DocumentService, DuplicateCandidate, NameTaken, MAX_DOCUMENT_NAME_LENGTH};
+use crate::fingerprint::Fingerprint;
+use crate::validation::{self, FieldError, FieldErrors, Validate};
+use anyhow::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// At most this many duplicates are reported per import.
+const MAX_REPORTED_DUPLICATES: usize = 10;
+
+/// What to do when the imported content already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnDuplicate {
+    /// Import anyway and report the duplicates.
+    #[default]
+    Warn,
+    /// Return an identical existing document instead of creating a copy. Near-identical
+    /// documents are only reported.
+    Link,
+}
+
+#[derive(Deserialize)]
+pub struct ImportRequest {
+    pub name: String,
+    /// Base64 of the content.
+    pub content: String,
+    #[serde(default)]
+    pub on_duplicate: OnDuplicate,
+}
+
+impl Validate for ImportRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors
+            .check(
+                "name",
+                validation::length(&self.name, 1, MAX_DOCUMENT_NAME_LENGTH),
+                FieldError::new("invalid-document-name").with_arg("max", MAX_DOCUMENT_NAME_LENGTH),
+            )
+            .check("content", BASE64.decode(&self.content).is_ok(), FieldError::new("invalid-document-content"));
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ImportedDocument {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<DocumentMetadata> for ImportedDocument {
+    fn from(metadata: DocumentMetadata) -> Self {
+        ImportedDocument {
+            id: metadata.id,
+            name: metadata.name,
+            created_at: metadata.created_at,
+            updated_at: metadata.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DuplicateMatch {
+    pub id: Uuid,
+    pub name: String,
+    pub exact: bool,
+    pub similarity: f64,
+}
+
+impl From<DuplicateCandidate> for DuplicateMatch {
+    fn from(candidate: DuplicateCandidate) -> Self {
+        DuplicateMatch {
+            id: candidate.metadata.id,
+            name: candidate.metadata.name,
+            exact: candidate.exact,
+            similarity: candidate.similarity,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ImportResponse {
+    /// The new document, or the existing one it was linked to.
+    pub document: ImportedDocument,
+    /// False when the import was linked to an existing document.
+    pub created: bool,
+    /// Existing documents with the same or nearly the same content, most similar first.
+    pub duplicates: Vec<DuplicateMatch>,
+}
+
+/// Existing documents with the same or nearly the same content as `content`, most similar
+/// first. Only documents in `visible_documents` are compared against, for callers with scoped
+/// access; the caller must still filter the candidates by what it may read.
+pub async fn find_duplicates(
+    doc_service: &DocumentService,
+    content: &[u8],
+    visible_documents: Option<&[Uuid]>,
+) -> Result<Vec<DuplicateCandidate>> {
+    // Every new document starts out empty, so empty content matches too much to be useful.
+    if content.is_empty() {
+        return Ok(Vec::new());
+    }
+    doc_service.find_duplicates(&Fingerprint::of(content), visible_documents, MAX_REPORTED_DUPLICATES).await
+}
+
+/// Creates a document with `content`, owned by `owner` if given, unless `on_duplicate` says
+/// to link to an identical one of `duplicates`, those of `find_duplicates` the caller may read.
+pub async fn import(
+    doc_service: &DocumentService,
+    name: &str,
+    content: Vec<u8>,
+    owner: Option<Uuid>,
+    on_duplicate: OnDuplicate,
+    duplicates: Vec<DuplicateCandidate>,
+) -> Result<ImportResponse> {
+    if on_duplicate == OnDuplicate::Link
+        && let Some(existing) = duplicates.iter().find(|candidate| candidate.exact)
+    {
+        return Ok(ImportResponse {
+            document: existing.metadata.clone().into(),
+            created: false,
+            duplicates: duplicates.into_iter().map(DuplicateMatch::from).collect(),
+        });
+    }
+
+    // An import should not fail over its file name, so it takes the first free variant.
+    let metadata = match doc_service.create_document_for(name, owner).await {
+        Err(e) => match e.downcast_ref::<NameTaken>().and_then(|taken| taken.suggestions.first()) {
+            Some(suggestion) => doc_service.create_document_for(suggestion, owner).await?,
+            None => return Err(e),
+        },
+        created => created?,
+    };
+    doc_service.update_document_content(metadata.id, content).await?;
+    let metadata = doc_service.get_document_metadata(metadata.id).await?.unwrap_or(metadata);
+    Ok(ImportResponse {
+        document: metadata.into(),
+        created: true,
+        duplicates: duplicates.into_iter().map(DuplicateMatch::from).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Manager;
+    use std::sync::Arc;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    async fn import_visible(
+        doc_service: &DocumentService,
+        name: &str,
+        content: Vec<u8>,
+        on_duplicate: OnDuplicate,
+        visible_documents: Option<&[Uuid]>,
+    ) -> Result<ImportResponse> {
+        let duplicates = find_duplicates(doc_service, &content, visible_documents).await?;
+        import(doc_service, name, content, None, on_duplicate, duplicates).await
+    }
+
+    #[tokio::test]
+    async fn test_import_reports_duplicates() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager).await?;
+        let marker = Uuid::new_v4().simple().to_string();
+        let text = format!(
+            "Release checklist {}: freeze the branch, run the migration rehearsal, update the \
+             changelog, tag the release, announce it on the mailing list and watch the error \
+             dashboards for the first hour after rollout.",
+            marker
+        );
+
+        let first = import_visible(&doc_service, "Checklist", text.clone().into_bytes(), OnDuplicate::Warn, None).await?;
+        assert!(first.created);
+
+        let copy = import_visible(&doc_service, "Checklist copy", text.clone().into_bytes(), OnDuplicate::Warn, None).await?;
+        assert!(copy.created);
+        assert!(copy.duplicates.iter().any(|duplicate| duplicate.id == first.document.id && duplicate.exact));
+
+        let linked = import_visible(&doc_service, "Checklist again", text.clone().into_bytes(), OnDuplicate::Link, None).await?;
+        assert!(!linked.created);
+        assert!([first.document.id, copy.document.id].contains(&linked.document.id));
+
+        // A small edit is still reported, but not linked to.
+        let notes = "Quarterly planning notes. The team agreed to ship the offline editor before the \
+            end of the quarter, to move the sync service onto the new cluster, and to review every \
+            open accessibility issue in the web client before the next release goes out to customers.";
+        let original = import_visible(&doc_service, "Notes", notes.as_bytes().to_vec(), OnDuplicate::Warn, None).await?;
+        let edited = notes.replace("customers", "users").into_bytes();
+        let near = import_visible(&doc_service, "Notes v2", edited, OnDuplicate::Link, Some(&[original.document.id])).await?;
+        assert!(near.created);
+        assert_eq!(near.duplicates.len(), 1);
+        assert!(!near.duplicates[0].exact);
+        assert!(near.duplicates[0].similarity < 1.0);
+        Ok(())
+    }
+}
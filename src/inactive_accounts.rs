@@ -0,0 +1,93 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+//! Deactivates accounts nobody has logged in to for a configurable number of months, after
+//! warning their owners by email. Deactivation ends the account's sessions; logging in
+//! again reactivates it. See `UserService::deactivate_inactive_accounts` for which accounts
+//! are spared.
+
+use crate::user_service::UserService;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Months, TimeDelta, Utc};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const DEACTIVATE_AFTER_MONTHS_ENV: &str = "COLLABORATE_DEACTIVATE_AFTER_MONTHS";
+const INACTIVITY_WARNING_DAYS_ENV: &str = "COLLABORATE_INACTIVITY_WARNING_DAYS";
+
+/// How long accounts may go unused and how much notice their owners get.
+#[derive(Clone, Debug)]
+pub struct InactivityConfig {
+    /// Accounts without a login for this many months are deactivated.
+    pub deactivate_after: Months,
+    /// How long before deactivation owners are warned. Deactivation waits until at least
+    /// this long after the warning, however late the warning went out.
+    pub warning_period: TimeDelta,
+    pub interval: Duration,
+}
+
+impl InactivityConfig {
+    /// Returns `None` when `COLLABORATE_DEACTIVATE_AFTER_MONTHS` is unset, i.e. accounts are
+    /// never deactivated. The notice period comes from `COLLABORATE_INACTIVITY_WARNING_DAYS`.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(months) = env::var(DEACTIVATE_AFTER_MONTHS_ENV) else {
+            return Ok(None);
+        };
+        let months: u32 = months.parse().context(format!("{} must be a number of months", DEACTIVATE_AFTER_MONTHS_ENV))?;
+        if months == 0 {
+            return Err(anyhow!("{} must be at least 1", DEACTIVATE_AFTER_MONTHS_ENV));
+        }
+        let warning_days = match env::var(INACTIVITY_WARNING_DAYS_ENV) {
+            Ok(days) => days.parse().context(format!("{} must be a number of days", INACTIVITY_WARNING_DAYS_ENV))?,
+            Err(_) => 14,
+        };
+        Ok(Some(InactivityConfig {
+            deactivate_after: Months::new(months),
+            warning_period: TimeDelta::days(warning_days),
+            interval: Duration::from_secs(60 * 60),
+        }))
+    }
+
+    /// Accounts unused since before this are deactivated once warned.
+    fn deactivation_cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now.checked_sub_months(self.deactivate_after).unwrap_or(DateTime::<Utc>::MIN_UTC)
+    }
+}
+
+/// Periodically warns the owners of unused accounts, then deactivates them.
+pub async fn run_sweeper(users: Arc<UserService>, config: InactivityConfig) {
+    let mut ticker = tokio::time::interval(config.interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = sweep_inactive_accounts(&users, &config, Utc::now()).await {
+            warn!("Inactive account sweep failed; will retry: {:#}", e);
+        }
+    }
+}
+
+async fn sweep_inactive_accounts(users: &UserService, config: &InactivityConfig, now: DateTime<Utc>) -> Result<()> {
+    let cutoff = config.deactivation_cutoff(now);
+    let warned = users.warn_inactive_accounts(cutoff + config.warning_period, now + config.warning_period, now).await?;
+    if warned > 0 {
+        info!("Warned {} inactive accounts of deactivation", warned);
+    }
+    let deactivated = users.deactivate_inactive_accounts(cutoff, now - config.warning_period, now).await?;
+    if !deactivated.is_empty() {
+        info!("Deactivated {} inactive accounts", deactivated.len());
+    }
+    Ok(())
+}
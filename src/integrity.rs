@@ -0,0 +1,82 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Content checksums, so corrupted document blobs are detected rather than served.
+
+use std::fmt;
+
+/// Hex-encoded BLAKE3 digest of a content blob.
+pub fn checksum(data: &[u8]) -> String {
+    blake3::hash(data).to_hex().to_string()
+}
+
+/// Checks `data` against a stored checksum. Content written before checksums
+/// were introduced has none and is accepted as-is.
+pub fn verify(subject: impl fmt::Display, data: &[u8], expected: Option<&str>) -> Result<(), ChecksumMismatch> {
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+    let actual = checksum(data);
+    if actual == expected {
+        return Ok(());
+    }
+    Err(ChecksumMismatch {
+        subject: subject.to_string(),
+        expected: expected.to_string(),
+        actual,
+    })
+}
+
+/// Stored content no longer matches the checksum recorded when it was written.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChecksumMismatch {
+    /// What was being read, e.g. "document ID ..." or a snapshot path.
+    pub subject: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Checksum mismatch for {}: expected {}, found {}", self.subject, self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Outcome of checking one document's stored content.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Integrity {
+    Intact,
+    /// Written before checksums were recorded, so there is nothing to compare against.
+    Unverified,
+    Corrupted(ChecksumMismatch),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let data = b"hello world";
+        let sum = checksum(data);
+        assert!(verify("test", data, Some(&sum)).is_ok());
+        assert!(verify("test", b"tampered", None).is_ok());
+
+        let mismatch = verify("test", b"hello w0rld", Some(&sum)).unwrap_err();
+        assert_eq!(mismatch.expected, sum);
+        assert_eq!(mismatch.actual, checksum(b"hello w0rld"));
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The predominant language of document text, detected when a snapshot is saved and kept
+//! on the document's metadata. Languages are BCP 47 primary language subtags ("en", "de"),
+//! the form spell-checkers and hyphenation dictionaries are looked up by.
+
+use unic_langid::LanguageIdentifier;
+
+/// Leading text examined; a few pages tell languages apart as well as the whole document.
+const SAMPLE_CHARS: usize = 10_000;
+/// Less text than this says too little about its language to be worth a guess.
+const MIN_LETTERS: usize = 20;
+
+/// ISO 639-3 codes of the detectable languages that have a shorter ISO 639-1 code.
+const TWO_LETTER_CODES: &[(&str, &str)] = &[
+    ("afr", "af"), ("aka", "ak"), ("amh", "am"), ("ara", "ar"), ("aze", "az"), ("bel", "be"),
+    ("ben", "bn"), ("bul", "bg"), ("cat", "ca"), ("ces", "cs"), ("cmn", "zh"), ("dan", "da"),
+    ("deu", "de"), ("ell", "el"), ("eng", "en"), ("epo", "eo"), ("est", "et"), ("fin", "fi"),
+    ("fra", "fr"), ("guj", "gu"), ("heb", "he"), ("hin", "hi"), ("hrv", "hr"), ("hun", "hu"),
+    ("hye", "hy"), ("ind", "id"), ("ita", "it"), ("jav", "jv"), ("jpn", "ja"), ("kan", "kn"),
+    ("kat", "ka"), ("khm", "km"), ("kor", "ko"), ("lat", "la"), ("lav", "lv"), ("lit", "lt"),
+    ("mal", "ml"), ("mar", "mr"), ("mkd", "mk"), ("mya", "my"), ("nep", "ne"), ("nld", "nl"),
+    ("nob", "nb"), ("ori", "or"), ("pan", "pa"), ("pes", "fa"), ("pol", "pl"), ("por", "pt"),
+    ("ron", "ro"), ("rus", "ru"), ("sin", "si"), ("slk", "sk"), ("slv", "sl"), ("sna", "sn"),
+    ("spa", "es"), ("srp", "sr"), ("swe", "sv"), ("tam", "ta"), ("tel", "te"), ("tgl", "tl"),
+    ("tha", "th"), ("tuk", "tk"), ("tur", "tr"), ("ukr", "uk"), ("urd", "ur"), ("uzb", "uz"),
+    ("vie", "vi"), ("yid", "yi"), ("zul", "zu"),
+];
+
+/// The language of `content`, treated as UTF-8 text until it is decoded as a CRDT.
+/// `None` when the content is not text, or too short or mixed to tell.
+pub fn detect(content: &[u8]) -> Option<&'static str> {
+    detect_text(std::str::from_utf8(content).ok()?)
+}
+
+/// The language of `text`; see `detect`.
+pub fn detect_text(text: &str) -> Option<&'static str> {
+    let sample = text.char_indices().nth(SAMPLE_CHARS).map_or(text, |(end, _)| &text[..end]);
+    if sample.chars().filter(|c| c.is_alphabetic()).count() < MIN_LETTERS {
+        return None;
+    }
+    let info = whatlang::detect(sample).filter(|info| info.is_reliable())?;
+    let code = info.lang().code();
+    Some(TWO_LETTER_CODES.iter().find(|(long, _)| *long == code).map_or(code, |(_, short)| short))
+}
+
+/// The primary language of a tag given by a client, e.g. "de" for "de-AT", to compare
+/// with detected languages. `None` if `tag` is not a language tag.
+pub fn normalize(tag: &str) -> Option<String> {
+    let tag: LanguageIdentifier = tag.parse().ok()?;
+    let language = tag.language.as_str();
+    (language != "und").then(|| language.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_languages_are_detected_from_enough_text() {
+        assert_eq!(detect(b"The committee will meet again next week to review the budget and the hiring plan."), Some("en"));
+        assert_eq!(
+            detect("Der schnelle braune Fuchs springt über den faulen Hund, während der Bauer von der Veranda aus zusieht.".as_bytes()),
+            Some("de")
+        );
+        assert_eq!(detect(b"Hello there"), None);
+        assert_eq!(detect(&[0xff, 0xfe]), None);
+    }
+
+    #[test]
+    fn test_tags_are_normalized_to_their_language() {
+        assert_eq!(normalize("de-AT").as_deref(), Some("de"));
+        assert_eq!(normalize("EN").as_deref(), Some("en"));
+        assert_eq!(normalize("not a tag"), None);
+        assert_eq!(normalize(""), None);
+    }
+}
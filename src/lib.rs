@@ -0,0 +1,85 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+pub mod alerts;
+pub mod analytics;
+pub mod api_error;
+pub mod api_keys;
+pub mod archive_import;
+pub mod attachments;
+pub mod audit;
+pub mod auth;
+pub mod blame;
+pub mod blob_store;
+pub mod broadcaster;
+pub mod cli;
+pub mod close_code;
+pub mod cluster;
+pub mod cold_storage;
+pub mod compaction;
+pub mod compression;
+pub mod concurrency;
+pub mod config;
+pub mod consistency;
+pub mod content_analysis;
+pub mod db;
+pub mod delta;
+pub mod diagnostics;
+pub mod document_room;
+pub mod document_service;
+pub mod email;
+pub mod email_digest;
+pub mod encryption;
+pub mod events;
+pub mod export;
+pub mod export_transforms;
+pub mod fingerprint;
+pub mod folders;
+pub mod frontend;
+pub mod hooks;
+pub mod http_server;
+pub mod i18n;
+pub mod import;
+pub mod inactive_accounts;
+pub mod integrity;
+pub mod language;
+pub mod metrics;
+pub mod migrations;
+pub mod moderation;
+pub mod oauth;
+pub mod organizations;
+pub mod outbox;
+pub mod password;
+pub mod permissions;
+pub mod personal_data;
+pub mod presence;
+pub mod preview;
+pub mod protected_ranges;
+pub mod protocol;
+pub mod rate_limit;
+pub mod references;
+pub mod region;
+pub mod scanning;
+pub mod search;
+pub mod search_index;
+pub mod snapshot_archive;
+pub mod storage_quota;
+pub mod sync;
+pub mod telemetry;
+pub mod timezone;
+pub mod trash;
+pub mod user_service;
+pub mod validation;
+pub mod watch;
+pub mod webhooks;
+pub mod workspace_archive;
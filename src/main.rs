@@ -11,31 +11,851 @@
 // GNU General Public License for more details.s
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
-mod db;
-mod document_service;
-mod http_server;
-
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use rand::RngCore;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::Arc;
-use db::Manager;
-use document_service::DocumentService;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use collaborate_core::alerts::{self, AlertConfig, ResourceMonitor};
+use collaborate_core::analytics::AnalyticsService;
+use collaborate_core::api_keys::ApiKeyService;
+use collaborate_core::archive_import::ArchiveImports;
+use collaborate_core::attachments::{self, AttachmentConfig, AttachmentService};
+use collaborate_core::audit::AuditLog;
+use collaborate_core::auth::oauth::{self as login, FederatedLogin};
+use collaborate_core::broadcaster::RedisBroadcaster;
+use collaborate_core::cli::{CommandError, Options, Outcome};
+use collaborate_core::cluster::{self, ClusterConfig};
+use collaborate_core::cold_storage::{self, ColdStorage, ColdStorageConfig};
+use collaborate_core::compaction;
+use collaborate_core::compression::Compression;
+use collaborate_core::config::{self, AppConfig};
+use collaborate_core::content_analysis::{ContentAnalysis, HttpAnalyzer, Utf8Extractor};
+use collaborate_core::db::Manager;
+use collaborate_core::diagnostics::Diagnostics;
+use collaborate_core::document_room::{RoomRegistry, WriteBufferConfig};
+use collaborate_core::document_service::DocumentService;
+use collaborate_core::email::{Mailer, SmtpMailer};
+use collaborate_core::email_digest::{self, EmailDigestService};
+use collaborate_core::encryption::{Encryption, HttpKeyManager};
+use collaborate_core::export_transforms::ExportTransforms;
+use collaborate_core::folders::FolderService;
+use collaborate_core::inactive_accounts::{self, InactivityConfig};
+use collaborate_core::metrics::ConnectionCounter;
+use collaborate_core::migrations::{self, Direction};
+use collaborate_core::moderation::{HttpClassifier, KeywordModerator, ModerationService};
+use collaborate_core::personal_data::{self, DeletionConfig, PersonalDataService};
+use collaborate_core::hooks::HookRegistry;
+use collaborate_core::http_server::{self, ServerSettings, Services};
+use collaborate_core::integrity::Integrity;
+use collaborate_core::oauth::{self, OAuthService};
+use collaborate_core::organizations::OrganizationService;
+use collaborate_core::permissions::PermissionService;
+use collaborate_core::rate_limit::{RateLimitBackend, RateLimiter};
+use collaborate_core::scanning::ClamdScanner;
+use collaborate_core::search_index::{self, SearchIndex};
+use collaborate_core::snapshot_archive::{self, SnapshotArchive, SnapshotArchiveConfig};
+use collaborate_core::storage_quota;
+use collaborate_core::telemetry;
+use collaborate_core::trash::{self, TrashConfig};
+use collaborate_core::user_service::{self, RegistrationError, Scope, UserRole, UserService};
+use collaborate_core::watch::{self, WatchService};
+use collaborate_core::webhooks::Webhooks;
+use collaborate_core::workspace_archive::{WorkspaceArchive, WorkspaceArchiver};
+
+/// Runs the collaboration server, or one of the maintenance commands below against the
+/// same database. Every command reads the same configuration as the server.
+#[derive(Parser)]
+#[command(name = "collaborate", version)]
+struct Cli {
+    #[command(flatten)]
+    options: Options,
+    /// Config file to read; without one, ./collaborate.toml is read if it exists.
+    #[arg(long, global = true, value_name = "FILE", env = config::CONFIG_PATH_ENV)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serves the HTTP API; the default when no command is given.
+    Serve,
+    /// Pulls a document back out of the snapshot archive, recreating it if it was deleted.
+    RestoreDocument {
+        document_id: Uuid,
+        /// Restore the newest snapshot taken at or before this RFC 3339 timestamp.
+        #[arg(long)]
+        at: Option<DateTime<Utc>>,
+    },
+    /// Creates an automation account and prints a token limited to the given scopes.
+    CreateBot {
+        name: String,
+        #[arg(long = "scope", required = true)]
+        scopes: Vec<Scope>,
+    },
+    /// Registers a third-party tool that may ask users for access.
+    CreateOauthClient {
+        name: String,
+        #[arg(long = "redirect-uri", required = true)]
+        redirect_uris: Vec<String>,
+    },
+    /// Creates a superadmin account and a workspace it administers, to bootstrap a fresh
+    /// installation.
+    CreateAdminUser {
+        email: String,
+        display_name: String,
+        /// Name of the workspace to create.
+        #[arg(long, default_value = "Administrators")]
+        workspace: String,
+        /// The account's password; one is generated and printed when unset.
+        #[arg(long, env = "COLLABORATE_ADMIN_PASSWORD", hide_env_values = true)]
+        password: Option<String>,
+    },
+    /// Gives an account a role: user, admin or superadmin.
+    SetRole { user_id: Uuid, role: UserRole },
+    /// Scrubs an account's personal data while keeping its ID.
+    AnonymizeUser { user_id: Uuid },
+    /// Checks every document's content against its checksum.
+    Verify {
+        /// Restore corrupt documents from their newest intact snapshot.
+        #[arg(long)]
+        restore: bool,
+    },
+    /// Indexes documents saved while no server was indexing them.
+    Reindex {
+        /// Empty the search index and index every document again.
+        #[arg(long)]
+        rebuild: bool,
+    },
+    /// Writes a workspace and everything in it to a self-contained archive file.
+    ExportWorkspace { workspace_id: Uuid, file: PathBuf },
+    /// Recreates an exported workspace under new IDs.
+    ImportWorkspace { file: PathBuf },
+    /// Applies pending schema migrations, or moves one component to a given version.
+    Migrate {
+        #[arg(requires = "version")]
+        component: Option<String>,
+        version: Option<i64>,
+    },
+    /// Reads the configuration and every setting taken from the environment, reporting
+    /// what is enabled and anything invalid, without connecting to anything.
+    CheckConfig,
+}
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    println!("Attempting to connect to database...");
-    let manager = Arc::new(Manager::new(
-        &"root@localhost:26257",
-        "collaborate_app"
-    ).await?);
+async fn main() -> ExitCode {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            // Help and version requests end here too, successfully.
+            let _ = e.print();
+            return ExitCode::from(e.exit_code() as u8);
+        }
+    };
+    let options = cli.options;
+    if let Err(e) = telemetry::init() {
+        return options.finish(Err(e));
+    }
+    let config = AppConfig::load_from(cli.config.as_deref());
+    let outcome = match cli.command.unwrap_or(Command::Serve) {
+        // Reports an invalid configuration rather than failing on it.
+        Command::CheckConfig => check_config(&options, config),
+        command => match config {
+            Ok(config) => run(&options, &config, command).await,
+            Err(e) => Err(e),
+        },
+    };
+    options.finish(outcome)
+}
+
+async fn run(options: &Options, config: &AppConfig, command: Command) -> Result<Outcome> {
+    match command {
+        Command::Serve if options.dry_run => Err(CommandError::usage("serve does not support --dry-run").into()),
+        Command::Serve => serve(config).await.map(|()| Outcome::success(json!({}))),
+        Command::RestoreDocument { document_id, at } => restore_document(options, config, document_id, at).await,
+        Command::CreateBot { name, scopes } => create_bot(options, config, &name, &scopes).await,
+        Command::CreateOauthClient { name, redirect_uris } => create_oauth_client(options, config, &name, &redirect_uris).await,
+        Command::CreateAdminUser { email, display_name, workspace, password } => {
+            create_admin_user(options, config, AdminAccount { email, display_name, workspace, password }).await
+        }
+        Command::SetRole { user_id, role } => set_role(options, config, user_id, role).await,
+        Command::AnonymizeUser { user_id } => anonymize_user(options, config, user_id).await,
+        Command::Verify { restore } => verify(options, config, restore).await,
+        Command::Reindex { rebuild } => reindex(options, config, rebuild).await,
+        Command::ExportWorkspace { workspace_id, file } => export_workspace(options, config, workspace_id, &file).await,
+        Command::ImportWorkspace { file } => import_workspace(options, config, &file).await,
+        Command::Migrate { component, version } => migrate(options, config, component.zip(version)).await,
+        Command::CheckConfig => check_config(options, Ok(config.clone())),
+    }
+}
+
+async fn connect(config: &AppConfig) -> Result<(Arc<Manager>, Arc<DocumentService>)> {
+    info!("Instance region: {}", config.region.region.as_deref().unwrap_or("unspecified"));
+
+    info!("Attempting to connect to database...");
+    let manager = Arc::new(Manager::from_config(config).await?);
 
     manager.check_connection().await?;
 
-    println!("Initializing DocumentService...");
-    let doc_service = Arc::new(DocumentService::new(manager.clone()).await?);
-    println!("DocumentService initialized.");
+    info!("Initializing DocumentService...");
+    let mut doc_service = DocumentService::new(manager.clone()).await?
+        .with_hooks(Arc::new(deployment_hooks()));
+    if let Some(level) = config.compression_level {
+        info!("Compressing document snapshots at zstd level {}", level);
+        doc_service = doc_service.with_compression(Arc::new(Compression::new(level)));
+    }
+    if let Some(cold_config) = ColdStorageConfig::from_env()? {
+        doc_service = doc_service.with_cold_storage(Arc::new(ColdStorage::from_config(&cold_config)?));
+    }
+    if let Some(quota_bytes) = storage_quota::quota_from_env()? {
+        info!("Limiting each user's documents to {} bytes of storage", quota_bytes);
+        doc_service = doc_service.with_storage_quota(quota_bytes);
+    }
+    if let Some(max_bytes) = config.upload_limits.max_document_bytes {
+        info!("Limiting each document to {} bytes of content", max_bytes);
+        doc_service = doc_service.with_max_document_bytes(max_bytes);
+    }
+    doc_service = doc_service.with_deletion_approval_window(TrashConfig::from_env()?.approval_window);
+    if let Some(key_manager) = HttpKeyManager::from_env()? {
+        info!("Organizations may encrypt their documents with keys from their own KMS");
+        doc_service = doc_service.with_encryption(Arc::new(Encryption::new(manager.clone(), Arc::new(key_manager)).await?));
+    }
+    let doc_service = Arc::new(doc_service);
+    info!("DocumentService initialized.");
+    Ok((manager, doc_service))
+}
+
+/// Policy hooks compiled into this build; register custom `Hook` implementations here.
+fn deployment_hooks() -> HookRegistry {
+    HookRegistry::new()
+}
+
+async fn serve(config: &AppConfig) -> Result<()> {
+    let (manager, doc_service) = connect(config).await?;
+    tokio::spawn(manager.clone().run_monitor(Duration::from_secs(15)));
+
+    if let Some(archive_config) = SnapshotArchiveConfig::from_env()? {
+        let archive = Arc::new(SnapshotArchive::from_config(&archive_config)?);
+        info!("Shipping document snapshots to {} every {:?}", archive_config.url, archive_config.interval);
+        tokio::spawn(snapshot_archive::run_shipper(doc_service.clone(), archive, archive_config.interval));
+    }
 
-    println!("Starting HTTP server...");
-    http_server::run_server(doc_service).await?; // Pass DocumentService to the HTTP server
+    if let Some(cold_config) = ColdStorageConfig::from_env()? {
+        info!("Offloading documents untouched for {} days to {}", cold_config.cold_after.num_days(), cold_config.url);
+        tokio::spawn(cold_storage::run_offloader(doc_service.clone(), cold_config));
+    }
+    tokio::spawn(compaction::run_compactor(doc_service.clone(), Duration::from_secs(60)));
+    let trash_config = TrashConfig::from_env()?;
+    info!("Purging documents from the trash after {} days", trash_config.retention.num_days());
+    tokio::spawn(trash::run_purger(doc_service.clone(), trash_config));
+    let search_index = Arc::new(SearchIndex::new(manager.clone(), doc_service.clone()).await?);
+    tokio::spawn(search_index::run_indexer(search_index.clone(), doc_service.events().clone()));
+
+    let mut rooms = RoomRegistry::new(doc_service.clone(), WriteBufferConfig::default())
+        .with_autosave(config.autosave.clone())
+        .with_message_limits(config.message_limits.clone());
+    if let Some(analyzer) = HttpAnalyzer::from_env()? {
+        info!("Sending saved document text to the analysis service");
+        rooms = rooms.with_content_analysis(ContentAnalysis::new(Arc::new(Utf8Extractor), Arc::new(analyzer)));
+    }
+    let cluster_config = ClusterConfig::from_env()?;
+    if let Some(cluster_config) = &cluster_config {
+        info!("Joining cluster as node '{}' with {} peers", cluster_config.node_id, cluster_config.peers.len());
+        rooms = rooms.with_cluster(Arc::new(cluster_config.clone()));
+    }
+    if let Some(broadcaster) = RedisBroadcaster::from_env()? {
+        if cluster_config.is_some() {
+            bail!("Relay rooms either through cluster peers or through Redis, not both");
+        }
+        info!("Relaying room broadcasts between nodes through Redis");
+        rooms = rooms.with_broadcaster(Arc::new(broadcaster));
+    }
+    let rooms = Arc::new(rooms);
+    if let Some(cluster_config) = cluster_config {
+        let rooms = rooms.clone();
+        tokio::spawn(async move {
+            if let Err(e) = cluster::serve(cluster_config.listen_addr, rooms).await {
+                error!("Cluster relay listener failed: {:#}", e);
+            }
+        });
+    }
+
+    let analytics = Arc::new(AnalyticsService::new(manager.clone(), AnalyticsService::sample_rate_from_env()?).await?);
+    let mailer = SmtpMailer::from_env()?.map(|mailer| Arc::new(mailer) as Arc<dyn Mailer>);
+    let public_url = email_digest::public_url_from_env();
+    let mut users = UserService::new(manager.clone()).await?;
+    match (&mailer, &public_url) {
+        (Some(mailer), Some(public_url)) => {
+            info!("Sending email digests and verification links");
+            users = users.with_mailer(mailer.clone(), public_url.clone());
+        }
+        (Some(_), None) => info!("COLLABORATE_PUBLIC_URL is unset, so email digests and verification are disabled"),
+        (None, _) => {}
+    }
+    if user_service::magic_links_from_env()? {
+        users = users.with_magic_links();
+        if users.magic_links_enabled() {
+            info!("Accounts may sign in with magic links");
+        } else {
+            warn!("Magic links need email to be configured, so they are disabled");
+        }
+    }
+    let users = Arc::new(users);
+    if let Some(inactivity_config) = InactivityConfig::from_env()? {
+        info!("Deactivating accounts unused for {:?} after {} days' notice", inactivity_config.deactivate_after, inactivity_config.warning_period.num_days());
+        tokio::spawn(inactive_accounts::run_sweeper(users.clone(), inactivity_config));
+    }
+    let permissions = Arc::new(PermissionService::new(manager.clone()).await?);
+    let folders = Arc::new(FolderService::new(manager.clone(), doc_service.clone()).await?);
+    let mut organizations = OrganizationService::new(manager.clone()).await?;
+    if let (Some(mailer), Some(public_url)) = (&mailer, &public_url) {
+        organizations = organizations.with_mailer(mailer.clone(), public_url.clone());
+    }
+    let organizations = Arc::new(organizations);
+    let oauth = Arc::new(OAuthService::new(manager.clone()).await?);
+    let api_keys = Arc::new(ApiKeyService::new(manager.clone()).await?);
+    let mut moderation = ModerationService::new(manager.clone()).await?;
+    if let Some(keywords) = KeywordModerator::from_env() {
+        info!("Moderating shared documents against keyword lists");
+        moderation = moderation.with_moderator(Arc::new(keywords));
+    }
+    if let Some(classifier) = HttpClassifier::from_env()? {
+        info!("Moderating shared documents with an external classifier");
+        moderation = moderation.with_moderator(Arc::new(classifier));
+    }
+    let deletion_config = DeletionConfig::from_env()?;
+    let personal_data = Arc::new(
+        PersonalDataService::new(manager.clone(), doc_service.clone()).await?.with_grace_period(deletion_config.grace_period),
+    );
+    info!("Deleting accounts {} days after their owners ask", deletion_config.grace_period.num_days());
+    tokio::spawn(personal_data::run_deleter(personal_data.clone(), deletion_config));
+    let audit = Arc::new(AuditLog::new(manager.clone()).await?);
+    let encryption = doc_service.encryption().cloned();
+    let export_transforms = Arc::new(ExportTransforms::new(manager.clone()).await?);
+    let mut login_providers = login::providers_from_env()?;
+    if !login_providers.is_empty() && public_url.is_none() {
+        info!("COLLABORATE_PUBLIC_URL is unset, so signing in with identity providers is disabled");
+        login_providers.clear();
+    }
+    for provider in &login_providers {
+        info!("Accounts may sign in with {}", provider.name);
+    }
+    let federated_login = Arc::new(FederatedLogin::new(login_providers, public_url.clone().unwrap_or_default())?);
+    let watches = Arc::new(WatchService::new(manager.clone()).await?);
+    let digest_interval = watch::digest_interval_from_env()?;
+    info!("Sending watch digests every {:?}", digest_interval);
+    tokio::spawn(watch::run_digester(watches.clone(), digest_interval));
+
+    let digests = Arc::new(EmailDigestService::new(manager.clone()).await?);
+    let connections = Arc::new(ConnectionCounter::default());
+    if let Some(alert_config) = AlertConfig::from_env()? {
+        info!("Alerting when usage crosses soft limits");
+        let mut monitor = ResourceMonitor::new(alert_config, doc_service.clone(), rooms.clone(), connections.clone());
+        if let Some(webhooks) = Webhooks::from_env()? {
+            monitor = monitor.with_webhooks(Arc::new(webhooks));
+        }
+        if let Some(mailer) = &mailer {
+            monitor = monitor.with_mailer(mailer.clone());
+        }
+        tokio::spawn(alerts::run_monitor(monitor));
+    }
+    if let (Some(mailer), Some(public_url)) = (mailer.clone(), public_url) {
+        tokio::spawn(email_digest::run_email_digests(digests.clone(), mailer, public_url, Duration::from_secs(5 * 60)));
+    }
+
+    let attachments = match AttachmentConfig::from_env()? {
+        Some(attachment_config) => {
+            info!("Storing attachments of up to {} bytes in {}", attachment_config.max_bytes, attachment_config.url);
+            let mut attachments = AttachmentService::from_config(manager.clone(), &attachment_config).await?;
+            if let Some(scanner) = ClamdScanner::from_env() {
+                info!("Scanning attachments with clamd; uploads are quarantined until scanned clean");
+                attachments = attachments.with_scanner(Arc::new(scanner));
+            }
+            if let Some(webhooks) = Webhooks::from_env()? {
+                attachments = attachments.with_webhooks(Arc::new(webhooks));
+            }
+            if let Some(quota_bytes) = storage_quota::quota_from_env()? {
+                attachments = attachments.with_storage_quota(quota_bytes);
+            }
+            let attachments = Arc::new(attachments);
+            tokio::spawn(attachments::run_rescanner(attachments.clone(), Duration::from_secs(60)));
+            Some(attachments)
+        }
+        None => None,
+    };
+
+    let mut archive_imports = ArchiveImports::new(manager.clone(), doc_service.clone(), folders.clone()).await?;
+    if let Some(attachments) = &attachments {
+        archive_imports = archive_imports.with_attachments(attachments.clone());
+    }
+
+    let mut diagnostics = Diagnostics::new(manager.clone(), rooms.clone());
+    if let Some(attachments) = &attachments {
+        diagnostics = diagnostics.with_attachments(attachments.clone());
+    }
+    if let Some(mailer) = mailer {
+        diagnostics = diagnostics.with_mailer(mailer);
+    }
+
+    info!("Starting HTTP server...");
+    let settings = ServerSettings::from_env(config)?;
+    let rate_limiter = match settings.rate_limit_backend {
+        RateLimitBackend::Local => RateLimiter::new(settings.rate_limit),
+        RateLimitBackend::Database => {
+            info!("Sharing rate limit counts between nodes through the database");
+            RateLimiter::shared(settings.rate_limit, manager.clone()).await?
+        }
+    };
+    let services = Services {
+        doc_service,
+        users,
+        analytics,
+        rooms,
+        watches,
+        attachments,
+        permissions,
+        digests,
+        search_index,
+        folders,
+        organizations,
+        archive_imports: Arc::new(archive_imports),
+        oauth,
+        api_keys,
+        moderation: Arc::new(moderation),
+        personal_data,
+        audit,
+        encryption,
+        export_transforms,
+        federated_login,
+        rate_limiter: Arc::new(rate_limiter),
+        connections,
+        diagnostics: Arc::new(diagnostics),
+    };
+    http_server::run_server(services, settings).await?;
+    manager.close().await;
 
     Ok(())
 }
+
+/// Pulls a document back out of the snapshot archive, recreating it if it was deleted.
+async fn restore_document(options: &Options, config: &AppConfig, doc_id: Uuid, at: Option<DateTime<Utc>>) -> Result<Outcome> {
+    let archive_config = SnapshotArchiveConfig::from_env()?
+        .ok_or_else(|| anyhow!("COLLABORATE_SNAPSHOT_URL must be set to restore documents"))?;
+    let archive = SnapshotArchive::from_config(&archive_config)?;
+    let (metadata, crdt_data) = archive.fetch(doc_id, at).await?
+        .ok_or_else(|| CommandError::not_found(format!("No archived snapshot found for document ID {}", doc_id)))?;
+    let result = json!({ "document_id": doc_id, "snapshot_taken_at": metadata.updated_at });
+    if options.dry_run {
+        options.say(format_args!("Would restore document {} from the snapshot taken at {}", doc_id, metadata.updated_at));
+        return Ok(Outcome::success(result));
+    }
+
+    let (_, doc_service) = connect(config).await?;
+    doc_service.restore_document(&metadata, crdt_data).await?;
+    options.say(format_args!("Restored document {} from the snapshot taken at {}", doc_id, metadata.updated_at));
+    Ok(Outcome::success(result))
+}
+
+/// Creates an automation account and prints a token limited to the given scopes.
+async fn create_bot(options: &Options, config: &AppConfig, name: &str, scopes: &[Scope]) -> Result<Outcome> {
+    let scope_names: Vec<String> = scopes.iter().map(Scope::to_string).collect();
+    if options.dry_run {
+        options.say(format_args!("Would create bot '{}' with scopes {}", name, scope_names.join(", ")));
+        return Ok(Outcome::success(json!({ "name": name, "scopes": scope_names })));
+    }
+
+    let (manager, _) = connect(config).await?;
+    let users = UserService::new(manager).await?;
+    let bot = users.create_bot(name, None).await?;
+    let token = users.issue_bot_token(bot.id, scopes, None).await?;
+    options.say(format_args!("Bot ID: {}", bot.id));
+    options.say(format_args!("Token (shown once): {}", token.secret));
+    Ok(Outcome::success(json!({ "name": name, "scopes": scope_names, "bot_id": bot.id, "token": token.secret })))
+}
+
+/// Registers a third-party tool that may ask users for access, returning its client ID.
+async fn create_oauth_client(options: &Options, config: &AppConfig, name: &str, redirect_uris: &[String]) -> Result<Outcome> {
+    for uri in redirect_uris {
+        oauth::check_redirect_uri(uri).map_err(|e| CommandError::usage(format!("{:#}", e)))?;
+    }
+    if options.dry_run {
+        options.say(format_args!("Would register OAuth client '{}' redirecting to {}", name, redirect_uris.join(", ")));
+        return Ok(Outcome::success(json!({ "name": name, "redirect_uris": redirect_uris })));
+    }
+
+    let (manager, _) = connect(config).await?;
+    // OAuth tables refer to users.
+    UserService::new(manager.clone()).await?;
+    let oauth = OAuthService::new(manager).await?;
+    let client = oauth.register_client(name, redirect_uris).await?;
+    options.say(format_args!("Client ID: {}", client.id));
+    Ok(Outcome::success(json!({ "name": name, "redirect_uris": redirect_uris, "client_id": client.id })))
+}
+
+/// What `create-admin-user` is asked to create.
+struct AdminAccount {
+    email: String,
+    display_name: String,
+    workspace: String,
+    password: Option<String>,
+}
+
+/// Creates a superadmin account and a workspace it administers, so a fresh installation has
+/// someone who can invite everyone else. Without a password, one is generated and printed once.
+async fn create_admin_user(options: &Options, config: &AppConfig, account: AdminAccount) -> Result<Outcome> {
+    let mut result = json!({
+        "email": account.email,
+        "display_name": account.display_name,
+        "workspace": account.workspace,
+    });
+    if options.dry_run {
+        options.say(format_args!("Would create account {} administering workspace '{}'", account.email, account.workspace));
+        return Ok(Outcome::success(result));
+    }
+
+    let generated = account.password.is_none();
+    let password = account.password.unwrap_or_else(|| {
+        let mut secret = [0u8; 18];
+        rand::rng().fill_bytes(&mut secret);
+        hex::encode(secret)
+    });
+    let (manager, _) = connect(config).await?;
+    let users = UserService::new(manager.clone()).await?;
+    let user = users.register_user(&account.display_name, &account.email, &password).await
+        .map_err(|e| match e.downcast_ref::<RegistrationError>() {
+            Some(rejection) => CommandError::usage(rejection.to_string()).into(),
+            None => e,
+        })?;
+    users.set_role(user.id, UserRole::Superadmin).await?;
+    let permissions = PermissionService::new(manager).await?;
+    let workspace = permissions.create_workspace(&account.workspace, user.id).await?;
+    options.say(format_args!("User ID: {}", user.id));
+    options.say(format_args!("Workspace ID: {}", workspace.id));
+    result["user_id"] = json!(user.id);
+    result["workspace_id"] = json!(workspace.id);
+    if generated {
+        options.say(format_args!("Password (shown once): {}", password));
+        result["password"] = json!(password);
+    }
+    Ok(Outcome::success(result))
+}
+
+/// Gives an account a role, which is how the first administrators beyond the one
+/// `create-admin-user` made come about.
+async fn set_role(options: &Options, config: &AppConfig, user_id: Uuid, role: UserRole) -> Result<Outcome> {
+    let (manager, _) = connect(config).await?;
+    let users = UserService::new(manager).await?;
+    if options.dry_run {
+        if users.get_user(user_id).await?.is_none() {
+            return Err(CommandError::not_found(format!("No account with ID {}", user_id)).into());
+        }
+        options.say(format_args!("Would make account {} {}", user_id, role.as_str()));
+    } else {
+        if !users.set_role(user_id, role).await? {
+            return Err(CommandError::not_found(format!("No account with ID {}", user_id)).into());
+        }
+        options.say(format_args!("Account {} is now {}", user_id, role.as_str()));
+    }
+    Ok(Outcome::success(json!({ "user_id": user_id, "role": role })))
+}
+
+/// Scrubs an account's personal data while keeping its ID, as an alternative to deleting it.
+async fn anonymize_user(options: &Options, config: &AppConfig, user_id: Uuid) -> Result<Outcome> {
+    let not_found = || CommandError::not_found(format!("No account with ID {} that is not already anonymized", user_id));
+
+    let (manager, _) = connect(config).await?;
+    let users = UserService::new(manager).await?;
+    if options.dry_run {
+        let anonymizable = users.get_user(user_id).await?.is_some_and(|user| !user.is_anonymized());
+        if !anonymizable {
+            return Err(not_found().into());
+        }
+        options.say(format_args!("Would anonymize account {}", user_id));
+    } else {
+        if !users.anonymize_user(user_id).await? {
+            return Err(not_found().into());
+        }
+        options.say(format_args!("Anonymized account {}", user_id));
+    }
+    Ok(Outcome::success(json!({ "user_id": user_id })))
+}
+
+/// Checks every document's content against its checksum, optionally restoring
+/// corrupt documents from their newest intact snapshot.
+async fn verify(options: &Options, config: &AppConfig, restore: bool) -> Result<Outcome> {
+    let archive = if restore {
+        let archive_config = SnapshotArchiveConfig::from_env()?
+            .ok_or_else(|| anyhow!("COLLABORATE_SNAPSHOT_URL must be set to restore documents"))?;
+        Some(SnapshotArchive::from_config(&archive_config)?)
+    } else {
+        None
+    };
+    let (_, doc_service) = connect(config).await?;
+
+    let (mut checked, mut unverified, mut unrestorable) = (0, 0, 0);
+    let (mut corrupted, mut unreadable) = (Vec::new(), Vec::new());
+    let mut after = None;
+    loop {
+        let ids = doc_service.list_document_ids(after, 500).await?;
+        let Some(last) = ids.last() else {
+            break;
+        };
+        after = Some(*last);
+        for doc_id in ids {
+            checked += 1;
+            match doc_service.check_document_integrity(doc_id).await {
+                Ok(None | Some(Integrity::Intact)) => {}
+                Ok(Some(Integrity::Unverified)) => unverified += 1,
+                Ok(Some(Integrity::Corrupted(mismatch))) => {
+                    options.say(format_args!("CORRUPT {}", mismatch));
+                    let mut restored_from = None;
+                    if let Some(archive) = &archive {
+                        match archive.fetch_latest_intact(doc_id).await? {
+                            Some((metadata, _)) if options.dry_run => {
+                                options.say(format_args!("Would restore document {} from the snapshot taken at {}", doc_id, metadata.updated_at));
+                                restored_from = Some(metadata.updated_at);
+                            }
+                            Some((metadata, crdt_data)) => {
+                                doc_service.restore_document(&metadata, crdt_data).await?;
+                                options.say(format_args!("Restored document {} from the snapshot taken at {}", doc_id, metadata.updated_at));
+                                restored_from = Some(metadata.updated_at);
+                            }
+                            None => {
+                                unrestorable += 1;
+                                options.say(format_args!("No intact snapshot of document {} to restore from", doc_id));
+                            }
+                        }
+                    }
+                    corrupted.push(json!({
+                        "document_id": doc_id,
+                        "error": mismatch.to_string(),
+                        "restored_from": restored_from,
+                    }));
+                }
+                Err(e) => {
+                    options.say(format_args!("UNREADABLE document {}: {:#}", doc_id, e));
+                    unreadable.push(json!({ "document_id": doc_id, "error": format!("{:#}", e) }));
+                }
+            }
+        }
+    }
+    options.say(format_args!(
+        "Checked {} documents: {} corrupt, {} unreadable, {} without a checksum",
+        checked, corrupted.len(), unreadable.len(), unverified
+    ));
+    let problem = if !corrupted.is_empty() && !restore {
+        Some(format!("Found {} corrupt documents; rerun with --restore to recover them from snapshots", corrupted.len()))
+    } else if unrestorable > 0 {
+        Some(format!("{} corrupt documents have no intact snapshot to restore from", unrestorable))
+    } else if !unreadable.is_empty() {
+        Some(format!("{} documents could not be checked", unreadable.len()))
+    } else {
+        None
+    };
+    let result = json!({
+        "checked": checked,
+        "without_checksum": unverified,
+        "corrupted": corrupted,
+        "unreadable": unreadable,
+    });
+    Ok(match problem {
+        Some(problem) => Outcome::problems_found(result, problem),
+        None => Outcome::success(result),
+    })
+}
+
+/// Indexes documents saved while no server was indexing them, or with `--rebuild`,
+/// empties the search index and indexes every document again.
+async fn reindex(options: &Options, config: &AppConfig, rebuild: bool) -> Result<Outcome> {
+    let (manager, doc_service) = connect(config).await?;
+    let index = SearchIndex::new(manager, doc_service).await?;
+    if options.dry_run {
+        let pending = index.count_unindexed(rebuild).await?;
+        options.say(format_args!("Would index {} documents", pending));
+        return Ok(Outcome::success(json!({ "rebuild": rebuild, "indexed": pending })));
+    }
+    let indexed = if rebuild { index.rebuild().await? } else { index.catch_up().await? };
+    options.say(format_args!("Indexed {} documents", indexed));
+    Ok(Outcome::success(json!({ "rebuild": rebuild, "indexed": indexed })))
+}
+
+/// Writes a workspace and everything in it to a self-contained archive file.
+async fn export_workspace(options: &Options, config: &AppConfig, workspace_id: Uuid, path: &Path) -> Result<Outcome> {
+    let (manager, doc_service) = connect(config).await?;
+    let archiver = workspace_archiver(manager, doc_service).await?;
+    let archive = archiver.export(workspace_id).await?
+        .ok_or_else(|| CommandError::not_found(format!("No workspace with ID {}", workspace_id)))?;
+    let result = json!({
+        "workspace_id": workspace_id,
+        "file": path,
+        "folders": archive.folders.len(),
+        "documents": archive.documents.len(),
+        "users": archive.users.len(),
+    });
+    if options.dry_run {
+        options.say(format_args!("Would export {} documents in {} folders to {}", archive.documents.len(), archive.folders.len(), path.display()));
+        return Ok(Outcome::success(result));
+    }
+    let encoded = serde_json::to_vec(&archive)?;
+    std::fs::write(path, encoded).with_context(|| format!("Failed to write {}", path.display()))?;
+    options.say(format_args!("Exported {} documents in {} folders to {}", archive.documents.len(), archive.folders.len(), path.display()));
+    Ok(Outcome::success(result))
+}
+
+/// Recreates an exported workspace under new IDs, matching its accounts to local ones by email.
+async fn import_workspace(options: &Options, config: &AppConfig, path: &Path) -> Result<Outcome> {
+    let encoded = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let archive: WorkspaceArchive = serde_json::from_slice(&encoded)
+        .map_err(|e| CommandError::usage(format!("{} is not a workspace archive: {}", path.display(), e)))?;
+    let (manager, doc_service) = connect(config).await?;
+    let archiver = workspace_archiver(manager, doc_service).await?;
+    let report = if options.dry_run {
+        archiver.plan_import(&archive).await?
+    } else {
+        archiver.import(&archive).await?
+    };
+    for user in &report.unmatched_users {
+        options.say(format_args!(
+            "No account here for {} ({}); their memberships and shares are skipped",
+            user.display_name, user.email.as_deref().unwrap_or("no email"),
+        ));
+    }
+    match report.workspace_id {
+        Some(workspace_id) => options.say(format_args!(
+            "Imported workspace '{}' as {} with {} documents, {} folders and {} members",
+            archive.workspace.name, workspace_id, report.documents, report.folders, report.members,
+        )),
+        None => options.say(format_args!(
+            "Would import workspace '{}' with {} documents, {} folders and {} members",
+            archive.workspace.name, report.documents, report.folders, report.members,
+        )),
+    }
+    Ok(Outcome::success(serde_json::to_value(&report)?))
+}
+
+async fn workspace_archiver(manager: Arc<Manager>, doc_service: Arc<DocumentService>) -> Result<WorkspaceArchiver> {
+    // Archives refer to users, workspaces and folders, so their tables must exist.
+    UserService::new(manager.clone()).await?;
+    PermissionService::new(manager.clone()).await?;
+    FolderService::new(manager.clone(), doc_service.clone()).await?;
+    Ok(WorkspaceArchiver::new(manager, doc_service))
+}
+
+/// Applies every component's pending migrations or, given a component and a version,
+/// moves just that component forward or back to it. Services are not started, so nothing
+/// else touches the schema meanwhile.
+async fn migrate(options: &Options, config: &AppConfig, target: Option<(String, i64)>) -> Result<Outcome> {
+    let target = match target {
+        Some((name, version)) => {
+            let component = migrations::component(&name)
+                .ok_or_else(|| CommandError::usage(format!("Unknown component '{}'", name)))?;
+            if !(0..=component.latest()).contains(&version) {
+                bail!(CommandError::usage(format!("{} has versions 0 to {}, not {}", name, component.latest(), version)));
+            }
+            Some((component, version))
+        }
+        None => None,
+    };
+    let manager = Manager::from_config(config).await?;
+    manager.check_connection().await?;
+
+    let steps = match target {
+        Some((component, version)) if options.dry_run => migrations::plan(&manager, component, version).await?,
+        Some((component, version)) => migrations::migrate_to(&manager, component, version).await?,
+        None if options.dry_run => migrations::plan_all(&manager).await?,
+        None => migrations::apply_all(&manager).await?,
+    };
+    for step in &steps {
+        let verb = match (step.direction, options.dry_run) {
+            (Direction::Up, true) => "Would apply",
+            (Direction::Up, false) => "Applied",
+            (Direction::Down, true) => "Would revert",
+            (Direction::Down, false) => "Reverted",
+        };
+        options.say(format_args!("{} {}", verb, step));
+    }
+    if steps.is_empty() {
+        options.say(format_args!("Nothing to migrate"));
+    }
+    let steps: Vec<_> = steps.iter()
+        .map(|step| json!({
+            "component": step.component,
+            "version": step.migration.version,
+            "description": step.migration.description,
+            "direction": match step.direction {
+                Direction::Up => "up",
+                Direction::Down => "down",
+            },
+        }))
+        .collect();
+    Ok(Outcome::success(json!({ "steps": steps })))
+}
+
+/// Reads the configuration and every setting the server takes from the environment,
+/// listing the optional features they enable and every setting that is invalid. Nothing
+/// is connected to, so it can run before the database is reachable.
+fn check_config(options: &Options, config: Result<AppConfig>) -> Result<Outcome> {
+    let mut enabled = Vec::new();
+    let mut problems = Vec::new();
+    let mut check = |feature: &'static str, setting: Result<bool>| match setting {
+        Ok(true) => enabled.push(feature),
+        Ok(false) => {}
+        Err(e) => problems.push(format!("{}: {:#}", feature, e)),
+    };
+    let summary = match &config {
+        Ok(config) => {
+            check("server", ServerSettings::from_env(config).map(|_| true));
+            json!({
+                "region": config.region.region,
+                "database": config.database.name,
+                "database_endpoints": config.region.db_endpoints.len(),
+                "listen": config.server.listen_addr.to_string(),
+            })
+        }
+        Err(_) => json!({}),
+    };
+    check("snapshot archive", SnapshotArchiveConfig::from_env().map(|archive| archive.is_some()));
+    check("cold storage", ColdStorageConfig::from_env().map(|cold| cold.is_some()));
+    check("storage quota", storage_quota::quota_from_env().map(|quota| quota.is_some()));
+    check("trash", TrashConfig::from_env().map(|_| true));
+    check("content analysis", HttpAnalyzer::from_env().map(|analyzer| analyzer.is_some()));
+    check("moderation classifier", HttpClassifier::from_env().map(|classifier| classifier.is_some()));
+    check("encryption", HttpKeyManager::from_env().map(|key_manager| key_manager.is_some()));
+    check("cluster", ClusterConfig::from_env().map(|cluster| cluster.is_some()));
+    check("redis relay", RedisBroadcaster::from_env().map(|broadcaster| broadcaster.is_some()));
+    check("analytics", AnalyticsService::sample_rate_from_env().map(|rate| rate > 0.0));
+    check("email", SmtpMailer::from_env().map(|mailer| mailer.is_some()));
+    check("magic links", user_service::magic_links_from_env());
+    check("inactive accounts", InactivityConfig::from_env().map(|inactivity| inactivity.is_some()));
+    check("account deletion", DeletionConfig::from_env().map(|_| true));
+    check("watch digests", watch::digest_interval_from_env().map(|_| true));
+    check("attachments", AttachmentConfig::from_env().map(|attachments| attachments.is_some()));
+    check("attachment scanning", Ok(ClamdScanner::from_env().is_some()));
+    check("webhooks", Webhooks::from_env().map(|webhooks| webhooks.is_some()));
+    check("resource alerts", AlertConfig::from_env().map(|alerts| alerts.is_some()));
+    if let Err(e) = config {
+        problems.insert(0, format!("{:#}", e));
+    }
+
+    if problems.is_empty() {
+        options.say(format_args!("Configuration is valid"));
+    }
+    for problem in &problems {
+        options.say(format_args!("INVALID {}", problem));
+    }
+    options.say(format_args!("Enabled: {}", enabled.join(", ")));
+    let mut result = summary;
+    result["enabled"] = json!(enabled);
+    result["problems"] = json!(problems);
+    Ok(match problems.len() {
+        0 => Outcome::success(result),
+        count => Outcome::problems_found(result, format!("{} settings are invalid", count)),
+    })
+}
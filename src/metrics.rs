@@ -0,0 +1,125 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::compression::CompressionStats;
+use crate::concurrency::{ConcurrencyLimits, QueueSnapshot};
+use crate::document_room::RoomRegistry;
+use crate::document_service::DocumentService;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_METRICS_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_METRICS_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counts open client WebSocket connections.
+#[derive(Debug, Default)]
+pub struct ConnectionCounter(AtomicUsize);
+
+impl ConnectionCounter {
+    /// Counts a connection until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>) -> ConnectionGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    pub fn current(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct ConnectionGuard(Arc<ConnectionCounter>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Live gauges for this node.
+#[derive(Clone, Debug, Serialize)]
+pub struct MetricsSnapshot {
+    pub at: DateTime<Utc>,
+    pub connections: usize,
+    pub open_rooms: usize,
+    /// Updates broadcast but not yet persisted, across all rooms.
+    pub pending_updates: usize,
+    pub pending_bytes: usize,
+    /// `None` when the database could not be reached.
+    pub db_latency_ms: Option<f64>,
+    /// Slots and queueing for exports, imports and merges.
+    pub request_queues: Vec<QueueSnapshot>,
+    /// How well this node's snapshots have compressed.
+    pub compression: CompressionStats,
+}
+
+impl MetricsSnapshot {
+    pub async fn collect(
+        connections: &ConnectionCounter,
+        rooms: &RoomRegistry,
+        doc_service: &DocumentService,
+        concurrency: &ConcurrencyLimits,
+    ) -> Self {
+        let db_latency_ms = match tokio::time::timeout(MAX_METRICS_INTERVAL, doc_service.database_latency()).await {
+            Ok(Ok(latency)) => Some(latency.as_secs_f64() * 1000.0),
+            _ => None,
+        };
+        MetricsSnapshot {
+            at: Utc::now(),
+            connections: connections.current(),
+            open_rooms: rooms.open_rooms(),
+            pending_updates: rooms.stats().pending_updates(),
+            pending_bytes: rooms.stats().pending_bytes(),
+            db_latency_ms,
+            request_queues: concurrency.snapshot(),
+            compression: doc_service.compression_stats(),
+        }
+    }
+}
+
+/// Clamps a requested streaming interval to something a node can sustain.
+pub fn metrics_interval(requested_ms: Option<u64>) -> Duration {
+    requested_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_METRICS_INTERVAL)
+        .clamp(MIN_METRICS_INTERVAL, MAX_METRICS_INTERVAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_guards_decrement() {
+        let counter = Arc::new(ConnectionCounter::default());
+        let first = counter.track();
+        let second = counter.track();
+        assert_eq!(counter.current(), 2);
+        drop(first);
+        assert_eq!(counter.current(), 1);
+        drop(second);
+        assert_eq!(counter.current(), 0);
+    }
+
+    #[test]
+    fn test_interval_is_clamped() {
+        assert_eq!(metrics_interval(None), DEFAULT_METRICS_INTERVAL);
+        assert_eq!(metrics_interval(Some(1)), MIN_METRICS_INTERVAL);
+        assert_eq!(metrics_interval(Some(5_000)), Duration::from_secs(5));
+        assert_eq!(metrics_interval(Some(u64::MAX)), MAX_METRICS_INTERVAL);
+    }
+}
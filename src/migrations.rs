@@ -0,0 +1,299 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Versioned schema migrations. Each component, i.e. a service and the tables it owns,
+//! lists its migrations in order; `apply` runs those a database has not seen yet and
+//! records them in `schema_migrations`. Services apply their own component when they are
+//! constructed, so starting the server brings the schema up to date, while
+//! `collaborate migrate` can also roll a component back.
+//!
+//! Up scripts must be safe to run twice (`IF NOT EXISTS`, backfills guarded by `WHERE`):
+//! nodes starting together may apply the same migration at once, and one interrupted
+//! partway is run again from the start. Schemas that predate migrations are recorded as
+//! each component's version 1, which creates only what is missing.
+
+use crate::db::Manager;
+use crate::{analytics, api_keys, archive_import, attachments, audit, document_service, email_digest, encryption, export_transforms, folders, moderation, oauth, organizations, outbox};
+use crate::{permissions, personal_data, rate_limit, references, search_index, storage_quota, user_service, watch};
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use sqlx::{Executor, PgPool};
+use std::fmt;
+use tracing::info;
+
+pub struct Migration {
+    /// Numbered from 1 per component.
+    pub version: i64,
+    pub description: &'static str,
+    pub up: &'static [&'static str],
+    /// Undoes `up`; data a migration backfilled or dropped is not brought back.
+    pub down: &'static [&'static str],
+}
+
+pub struct Component {
+    pub name: &'static str,
+    /// Oldest first.
+    pub migrations: &'static [Migration],
+}
+
+/// Every component, ordered so that each only refers to tables of those before it.
+pub const COMPONENTS: &[&Component] = &[
+    &user_service::SCHEMA,
+    &document_service::SCHEMA,
+    &storage_quota::SCHEMA,
+    &outbox::SCHEMA,
+    &references::SCHEMA,
+    &organizations::SCHEMA,
+    &encryption::SCHEMA,
+    &permissions::SCHEMA,
+    &folders::SCHEMA,
+    &search_index::SCHEMA,
+    &watch::SCHEMA,
+    &email_digest::SCHEMA,
+    &oauth::SCHEMA,
+    &api_keys::SCHEMA,
+    &moderation::SCHEMA,
+    &analytics::SCHEMA,
+    &attachments::SCHEMA,
+    &rate_limit::SCHEMA,
+    &archive_import::SCHEMA,
+    &personal_data::SCHEMA,
+    &audit::SCHEMA,
+    &export_transforms::SCHEMA,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// One migration to run, or roll back.
+pub struct Step {
+    pub component: &'static str,
+    pub migration: &'static Migration,
+    pub direction: Direction,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} version {} ({})", self.component, self.migration.version, self.migration.description)
+    }
+}
+
+impl Component {
+    /// The newest version this build knows about.
+    pub fn latest(&self) -> i64 {
+        self.migrations.last().map_or(0, |migration| migration.version)
+    }
+
+    /// The steps that take the database's copy of this component to `target`.
+    pub async fn plan(&'static self, pool: &PgPool, target: i64) -> Result<Vec<Step>> {
+        if target < 0 || target > self.latest() {
+            bail!("{} has no version {}; the latest is {}", self.name, target, self.latest());
+        }
+        let applied = applied_versions(pool, self.name).await?;
+        let mut steps: Vec<Step> = self.migrations.iter()
+            .filter(|migration| migration.version <= target && !applied.contains(&migration.version))
+            .map(|migration| Step { component: self.name, migration, direction: Direction::Up })
+            .collect();
+        for version in applied.iter().rev().filter(|version| **version > target) {
+            let Some(migration) = self.migrations.iter().find(|migration| migration.version == *version) else {
+                bail!("{} version {} was applied by a newer build and cannot be reverted by this one", self.name, version);
+            };
+            steps.push(Step { component: self.name, migration, direction: Direction::Down });
+        }
+        Ok(steps)
+    }
+}
+
+/// Brings a component up to its latest version. Versions applied by a newer build are left alone.
+pub async fn apply(pool: &PgPool, component: &'static Component) -> Result<()> {
+    initialize_schema(pool).await?;
+    let applied = applied_versions(pool, component.name).await?;
+    for migration in component.migrations.iter().filter(|migration| !applied.contains(&migration.version)) {
+        run(pool, &Step { component: component.name, migration, direction: Direction::Up }).await?;
+    }
+    Ok(())
+}
+
+/// The steps `apply_all` would run.
+pub async fn plan_all(db_manager: &Manager) -> Result<Vec<Step>> {
+    let mut steps = Vec::new();
+    for component in COMPONENTS {
+        steps.extend(plan(db_manager, component, component.latest()).await?);
+    }
+    Ok(steps)
+}
+
+/// Applies every component's pending migrations, returning what was run.
+pub async fn apply_all(db_manager: &Manager) -> Result<Vec<Step>> {
+    let steps = plan_all(db_manager).await?;
+    for step in &steps {
+        run(&db_manager.pool, step).await?;
+    }
+    Ok(steps)
+}
+
+/// The steps `migrate_to` would run.
+pub async fn plan(db_manager: &Manager, component: &'static Component, target: i64) -> Result<Vec<Step>> {
+    initialize_schema(&db_manager.pool).await?;
+    component.plan(&db_manager.pool, target).await
+}
+
+/// Moves a component to `target`, applying or reverting migrations as needed.
+pub async fn migrate_to(db_manager: &Manager, component: &'static Component, target: i64) -> Result<Vec<Step>> {
+    let steps = plan(db_manager, component, target).await?;
+    for step in &steps {
+        run(&db_manager.pool, step).await?;
+    }
+    Ok(steps)
+}
+
+pub fn component(name: &str) -> Option<&'static Component> {
+    COMPONENTS.iter().copied().find(|component| component.name == name)
+}
+
+async fn run(pool: &PgPool, step: &Step) -> Result<()> {
+    let migration = step.migration;
+    let (statements, verb, done) = match step.direction {
+        Direction::Up => (migration.up, "apply", "Applied"),
+        Direction::Down => (migration.down, "revert", "Reverted"),
+    };
+    for statement in statements {
+        pool.execute(*statement).await.context(format!("Failed to {} {}", verb, step))?;
+    }
+    match step.direction {
+        Direction::Up => {
+            sqlx::query(
+                    "INSERT INTO schema_migrations (component, version, description, applied_at) VALUES ($1, $2, $3, $4)
+                     ON CONFLICT (component, version) DO NOTHING"
+                )
+                .bind(step.component)
+                .bind(migration.version)
+                .bind(migration.description)
+                .bind(Utc::now())
+                .execute(pool)
+                .await
+        }
+        Direction::Down => {
+            sqlx::query("DELETE FROM schema_migrations WHERE component = $1 AND version = $2")
+                .bind(step.component)
+                .bind(migration.version)
+                .execute(pool)
+                .await
+        }
+    }
+    .context(format!("Failed to record migration {}", step))?;
+    info!("{} migration {}", done, step);
+    Ok(())
+}
+
+async fn initialize_schema(pool: &PgPool) -> Result<()> {
+    pool.execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                component TEXT NOT NULL,
+                version BIGINT NOT NULL,
+                description TEXT NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (component, version)
+            )",
+        )
+        .await
+        .context("Failed to create schema_migrations table")?;
+    Ok(())
+}
+
+async fn applied_versions(pool: &PgPool, component: &str) -> Result<Vec<i64>> {
+    sqlx::query_scalar("SELECT version FROM schema_migrations WHERE component = $1 ORDER BY version")
+        .bind(component)
+        .fetch_all(pool)
+        .await
+        .context(format!("Failed to list applied migrations of {}", component))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    static COMPONENT: Component = Component {
+        name: "migrations_test",
+        migrations: &[
+            Migration {
+                version: 1,
+                description: "create items",
+                up: &["CREATE TABLE IF NOT EXISTS migrations_test_items (id BIGINT PRIMARY KEY)"],
+                down: &["DROP TABLE IF EXISTS migrations_test_items"],
+            },
+            Migration {
+                version: 2,
+                description: "name items",
+                up: &["ALTER TABLE migrations_test_items ADD COLUMN IF NOT EXISTS name TEXT"],
+                down: &["ALTER TABLE migrations_test_items DROP COLUMN IF EXISTS name"],
+            },
+        ],
+    };
+
+    async fn column_exists(pool: &PgPool, table: &str, column: &str) -> Result<bool> {
+        Ok(sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM information_schema.columns WHERE table_name = $1 AND column_name = $2)"
+            )
+            .bind(table)
+            .bind(column)
+            .fetch_one(pool)
+            .await?)
+    }
+
+    #[test]
+    fn test_components_are_numbered_in_order() {
+        let mut names = std::collections::HashSet::new();
+        for component in COMPONENTS {
+            assert!(names.insert(component.name), "{} is listed twice", component.name);
+            for (i, migration) in component.migrations.iter().enumerate() {
+                assert_eq!(migration.version, i as i64 + 1, "{} is not numbered from 1 in order", component.name);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_components_migrate_up_and_down() -> Result<()> {
+        let manager = Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?;
+        migrate_to(&manager, &COMPONENT, 0).await?;
+        assert!(plan(&manager, &COMPONENT, 3).await.is_err());
+
+        apply(&manager.pool, &COMPONENT).await?;
+        assert!(column_exists(&manager.pool, "migrations_test_items", "name").await?);
+        assert!(plan(&manager, &COMPONENT, 2).await?.is_empty());
+        // Applying again finds nothing left to do.
+        apply(&manager.pool, &COMPONENT).await?;
+
+        let steps = migrate_to(&manager, &COMPONENT, 1).await?;
+        assert_eq!(steps.len(), 1);
+        assert_eq!((steps[0].migration.version, steps[0].direction), (2, Direction::Down));
+        assert!(!column_exists(&manager.pool, "migrations_test_items", "name").await?);
+        assert!(column_exists(&manager.pool, "migrations_test_items", "id").await?);
+
+        let steps = migrate_to(&manager, &COMPONENT, 0).await?;
+        assert_eq!(steps.iter().map(|step| step.migration.version).collect::<Vec<_>>(), vec![1]);
+        assert!(!column_exists(&manager.pool, "migrations_test_items", "id").await?);
+
+        let steps = migrate_to(&manager, &COMPONENT, 2).await?;
+        assert_eq!(steps.iter().map(|step| step.migration.version).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(column_exists(&manager.pool, "migrations_test_items", "name").await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,588 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Moderation of documents before they are made public. Share links are how a document is
+//! published: anyone holding one can open it without an account, so creating one first
+//! runs the document through every configured `Moderator`. `KeywordModerator` matches word
+//! lists and `HttpClassifier` asks an external classification service.
+//!
+//! A block stops the link from being created and a flag lets it through; either way the
+//! document joins the queue administrators review. Approving a blocked document lets the
+//! same content be published; rejecting a flagged one revokes its share links. A moderator
+//! that fails holds the document for review, as a block would. Every verdict and decision
+//! is recorded in the moderation log.
+
+use crate::content_analysis::{TextExtractor, Utf8Extractor};
+use crate::db::Manager;
+use crate::integrity;
+use crate::migrations::{self, Component, Migration};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+const BLOCKED_WORDS_ENV: &str = "COLLABORATE_MODERATION_BLOCKED_WORDS";
+const FLAGGED_WORDS_ENV: &str = "COLLABORATE_MODERATION_FLAGGED_WORDS";
+const CLASSIFIER_URL_ENV: &str = "COLLABORATE_MODERATION_CLASSIFIER_URL";
+const CLASSIFIER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Reviews listed per page of the queue.
+pub const REVIEW_PAGE_SIZE: i64 = 100;
+
+/// What a document about to be published looks like to moderators.
+pub struct Submission<'a> {
+    pub doc_id: Uuid,
+    pub title: &'a str,
+    pub text: &'a str,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "verdict", rename_all = "snake_case")]
+pub enum Verdict {
+    Allow,
+    /// Publish, but have someone look.
+    Flag { reason: String },
+    /// Don't publish unless someone approves it.
+    Block { reason: String },
+}
+
+#[async_trait]
+pub trait Moderator: Send + Sync {
+    /// Identifies the moderator in findings and logs.
+    fn name(&self) -> &str;
+
+    /// Fails if the submission could not be checked; it is then held for review.
+    async fn review(&self, submission: &Submission<'_>) -> Result<Verdict>;
+}
+
+/// Blocks or flags documents whose title or text contains any of its words or phrases,
+/// ignoring case and punctuation.
+pub struct KeywordModerator {
+    blocked: Vec<String>,
+    flagged: Vec<String>,
+}
+
+impl KeywordModerator {
+    pub fn new(blocked: &[&str], flagged: &[&str]) -> Self {
+        KeywordModerator { blocked: normalize_all(blocked.iter().copied()), flagged: normalize_all(flagged.iter().copied()) }
+    }
+
+    /// Reads comma-separated lists from `COLLABORATE_MODERATION_BLOCKED_WORDS` and
+    /// `COLLABORATE_MODERATION_FLAGGED_WORDS`. Returns `None` when both are unset.
+    pub fn from_env() -> Option<Self> {
+        let (blocked, flagged) = (env::var(BLOCKED_WORDS_ENV).ok(), env::var(FLAGGED_WORDS_ENV).ok());
+        if blocked.is_none() && flagged.is_none() {
+            return None;
+        }
+        let split = |list: Option<String>| normalize_all(list.as_deref().unwrap_or_default().split(','));
+        Some(KeywordModerator { blocked: split(blocked), flagged: split(flagged) })
+    }
+}
+
+fn normalize_all<'a>(phrases: impl Iterator<Item = &'a str>) -> Vec<String> {
+    phrases.map(normalize).filter(|phrase| !phrase.trim().is_empty()).collect()
+}
+
+// Lowercases and reduces everything but letters and digits to single spaces, padded so
+// phrases only match whole words.
+fn normalize(text: &str) -> String {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    format!(" {} ", words.join(" "))
+}
+
+#[async_trait]
+impl Moderator for KeywordModerator {
+    fn name(&self) -> &str {
+        "keywords"
+    }
+
+    async fn review(&self, submission: &Submission<'_>) -> Result<Verdict> {
+        let haystack = format!("{}{}", normalize(submission.title), normalize(submission.text));
+        let found = |phrases: &[String]| phrases.iter().find(|phrase| haystack.contains(phrase.as_str())).map(|phrase| phrase.trim().to_string());
+        Ok(match (found(&self.blocked), found(&self.flagged)) {
+            (Some(phrase), _) => Verdict::Block { reason: format!("contains '{}'", phrase) },
+            (None, Some(phrase)) => Verdict::Flag { reason: format!("contains '{}'", phrase) },
+            (None, None) => Verdict::Allow,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct ClassificationRequest<'a> {
+    title: &'a str,
+    text: &'a str,
+}
+
+/// Posts `{"title": ..., "text": ...}` to a URL and expects `{"verdict": "allow"}`, or
+/// `"flag"` or `"block"` with a `"reason"`, back.
+pub struct HttpClassifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpClassifier {
+    pub fn new(url: String) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(CLASSIFIER_TIMEOUT)
+            .build()
+            .context("Failed to build classifier HTTP client")?;
+        Ok(HttpClassifier { client, url })
+    }
+
+    /// Returns `None` when `COLLABORATE_MODERATION_CLASSIFIER_URL` is unset.
+    pub fn from_env() -> Result<Option<Self>> {
+        match env::var(CLASSIFIER_URL_ENV) {
+            Ok(url) => Ok(Some(HttpClassifier::new(url)?)),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl Moderator for HttpClassifier {
+    fn name(&self) -> &str {
+        "classifier"
+    }
+
+    async fn review(&self, submission: &Submission<'_>) -> Result<Verdict> {
+        self.client
+            .post(&self.url)
+            .json(&ClassificationRequest { title: submission.title, text: submission.text })
+            .send()
+            .await
+            .context(format!("Failed to reach classifier at {}", self.url))?
+            .error_for_status()
+            .context("Classifier returned an error")?
+            .json()
+            .await
+            .context("Classifier returned a malformed response")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl ReviewStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReviewStatus::Pending => "pending",
+            ReviewStatus::Approved => "approved",
+            ReviewStatus::Rejected => "rejected",
+        }
+    }
+}
+
+impl FromStr for ReviewStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pending" => Ok(ReviewStatus::Pending),
+            "approved" => Ok(ReviewStatus::Approved),
+            "rejected" => Ok(ReviewStatus::Rejected),
+            other => Err(anyhow!("Unknown review status '{}'", other)),
+        }
+    }
+}
+
+/// A document waiting for, or given, a reviewer's decision.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Review {
+    pub id: Uuid,
+    pub document_id: Uuid,
+    pub requested_by: Option<Uuid>,
+    /// Whether publication was stopped; otherwise it went ahead while flagged.
+    pub blocked: bool,
+    /// What each moderator found, as `<moderator>: <reason>`.
+    pub findings: Vec<String>,
+    pub status: ReviewStatus,
+    pub created_at: DateTime<Utc>,
+    pub decided_at: Option<DateTime<Utc>>,
+    pub note: Option<String>,
+}
+
+#[derive(FromRow)]
+struct ReviewRow {
+    id: Uuid,
+    document_id: Uuid,
+    requested_by: Option<Uuid>,
+    blocked: bool,
+    findings: Vec<String>,
+    status: String,
+    created_at: DateTime<Utc>,
+    decided_at: Option<DateTime<Utc>>,
+    note: Option<String>,
+}
+
+impl TryFrom<ReviewRow> for Review {
+    type Error = anyhow::Error;
+
+    fn try_from(row: ReviewRow) -> Result<Self> {
+        Ok(Review {
+            id: row.id,
+            document_id: row.document_id,
+            requested_by: row.requested_by,
+            blocked: row.blocked,
+            findings: row.findings,
+            status: row.status.parse()?,
+            created_at: row.created_at,
+            decided_at: row.decided_at,
+            note: row.note,
+        })
+    }
+}
+
+/// One entry of the moderation log.
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+pub struct LogEntry {
+    pub document_id: Uuid,
+    pub review_id: Option<Uuid>,
+    /// `allowed`, `flagged`, `blocked`, `approved` or `rejected`.
+    pub action: String,
+    /// Who asked to publish; `None` for decisions, which administrators make.
+    pub actor: Option<Uuid>,
+    pub detail: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The outcome of asking to publish a document.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Publication {
+    Allowed,
+    /// Published, with the review it awaits.
+    Flagged(Review),
+    /// Not published until the review is approved.
+    Blocked(Review),
+}
+
+const REVIEW_COLUMNS: &str = "id, document_id, requested_by, blocked, findings, status, created_at, decided_at, note";
+
+#[derive(Clone)]
+pub struct ModerationService {
+    db_manager: Arc<Manager>,
+    moderators: Vec<Arc<dyn Moderator>>,
+    extractor: Arc<dyn TextExtractor>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "moderation",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS moderation_reviews (
+                id UUID PRIMARY KEY,
+                document_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                requested_by UUID REFERENCES users(id) ON DELETE SET NULL,
+                content_checksum TEXT NOT NULL,
+                blocked BOOL NOT NULL,
+                findings TEXT[] NOT NULL,
+                status TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                decided_at TIMESTAMPTZ,
+                note TEXT
+            )",
+            "CREATE INDEX IF NOT EXISTS moderation_reviews_by_status ON moderation_reviews (status, created_at)",
+            "CREATE INDEX IF NOT EXISTS moderation_reviews_by_document ON moderation_reviews (document_id)",
+            // Outlives the documents it mentions, so it stays a complete record.
+            "CREATE TABLE IF NOT EXISTS moderation_log (
+                id UUID PRIMARY KEY,
+                document_id UUID NOT NULL,
+                review_id UUID,
+                action TEXT NOT NULL,
+                actor UUID,
+                detail TEXT,
+                created_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE INDEX IF NOT EXISTS moderation_log_by_document ON moderation_log (document_id, created_at)",
+        ],
+        down: &["DROP TABLE IF EXISTS moderation_log", "DROP TABLE IF EXISTS moderation_reviews"],
+    }],
+};
+
+impl ModerationService {
+    /// A service without moderators, which lets every document be published.
+    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
+        let service = ModerationService { db_manager, moderators: Vec::new(), extractor: Arc::new(Utf8Extractor) };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    /// Adds a moderator; they run in the order added.
+    pub fn with_moderator(mut self, moderator: Arc<dyn Moderator>) -> Self {
+        self.moderators.push(moderator);
+        self
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("Moderation schema initialized.");
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.moderators.is_empty()
+    }
+
+    /// Runs a document about to be published past every moderator, queueing it for review
+    /// if any of them blocks or flags it. Content an administrator already approved is let
+    /// through unchecked.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn review_publication(&self, doc_id: Uuid, requested_by: Option<Uuid>, title: &str, content: &[u8]) -> Result<Publication> {
+        if !self.is_enabled() {
+            return Ok(Publication::Allowed);
+        }
+        let checksum = integrity::checksum(content);
+        let approved: bool = sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM moderation_reviews
+                 WHERE document_id = $1 AND content_checksum = $2 AND status = 'approved')"
+            )
+            .bind(doc_id)
+            .bind(&checksum)
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to look up approvals of document ID {}", doc_id))?;
+        if approved {
+            self.log(doc_id, None, "allowed", requested_by, Some("approved before")).await?;
+            return Ok(Publication::Allowed);
+        }
+
+        let text = self.extractor.extract(content).unwrap_or_default();
+        let submission = Submission { doc_id, title, text: &text };
+        let (mut blocked, mut findings) = (false, Vec::new());
+        for moderator in &self.moderators {
+            match moderator.review(&submission).await {
+                Ok(Verdict::Allow) => {}
+                Ok(Verdict::Flag { reason }) => findings.push(format!("{}: {}", moderator.name(), reason)),
+                Ok(Verdict::Block { reason }) => {
+                    blocked = true;
+                    findings.push(format!("{}: {}", moderator.name(), reason));
+                }
+                Err(e) => {
+                    warn!("Moderator {} failed on document {}: {:#}", moderator.name(), doc_id, e);
+                    blocked = true;
+                    findings.push(format!("{}: could not check the document", moderator.name()));
+                }
+            }
+        }
+        if findings.is_empty() {
+            self.log(doc_id, None, "allowed", requested_by, None).await?;
+            return Ok(Publication::Allowed);
+        }
+
+        let review = Review {
+            id: Uuid::new_v4(),
+            document_id: doc_id,
+            requested_by,
+            blocked,
+            findings,
+            status: ReviewStatus::Pending,
+            created_at: DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap_or_default(),
+            decided_at: None,
+            note: None,
+        };
+        sqlx::query(
+                "INSERT INTO moderation_reviews (id, document_id, requested_by, content_checksum, blocked, findings, status, created_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"
+            )
+            .bind(review.id)
+            .bind(doc_id)
+            .bind(requested_by)
+            .bind(&checksum)
+            .bind(blocked)
+            .bind(&review.findings)
+            .bind(review.status.as_str())
+            .bind(review.created_at)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to queue document ID {} for review", doc_id))?;
+        let action = if blocked { "blocked" } else { "flagged" };
+        self.log(doc_id, Some(review.id), action, requested_by, Some(&review.findings.join("; "))).await?;
+        info!("Document {} {} for review {}", doc_id, action, review.id);
+        Ok(if blocked { Publication::Blocked(review) } else { Publication::Flagged(review) })
+    }
+
+    /// Reviews awaiting a decision, oldest first.
+    #[instrument(skip_all)]
+    pub async fn pending_reviews(&self) -> Result<Vec<Review>> {
+        let rows: Vec<ReviewRow> = sqlx::query_as(&format!(
+                "SELECT {} FROM moderation_reviews WHERE status = 'pending' ORDER BY created_at, id LIMIT $1",
+                REVIEW_COLUMNS,
+            ))
+            .bind(REVIEW_PAGE_SIZE)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to list pending reviews")?;
+        rows.into_iter().map(Review::try_from).collect()
+    }
+
+    /// Approves or rejects a pending review. Returns `None` if there is no such review or
+    /// it was already decided.
+    #[instrument(skip_all, fields(review_id = %review_id))]
+    pub async fn decide(&self, review_id: Uuid, approve: bool, note: Option<&str>) -> Result<Option<Review>> {
+        let status = if approve { ReviewStatus::Approved } else { ReviewStatus::Rejected };
+        let row: Option<ReviewRow> = sqlx::query_as(&format!(
+                "UPDATE moderation_reviews SET status = $1, decided_at = $2, note = $3
+                 WHERE id = $4 AND status = 'pending' RETURNING {}",
+                REVIEW_COLUMNS,
+            ))
+            .bind(status.as_str())
+            .bind(Utc::now())
+            .bind(note)
+            .bind(review_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to decide review {}", review_id))?;
+        let Some(review) = row.map(Review::try_from).transpose()? else {
+            return Ok(None);
+        };
+        self.log(review.document_id, Some(review.id), status.as_str(), None, note).await?;
+        info!("Review {} of document {} {}", review.id, review.document_id, status.as_str());
+        Ok(Some(review))
+    }
+
+    /// A document's moderation log, oldest first.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn log_entries(&self, doc_id: Uuid) -> Result<Vec<LogEntry>> {
+        sqlx::query_as(
+                "SELECT document_id, review_id, action, actor, detail, created_at FROM moderation_log
+                 WHERE document_id = $1 ORDER BY created_at, id"
+            )
+            .bind(doc_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to read the moderation log of document ID {}", doc_id))
+    }
+
+    async fn log(&self, doc_id: Uuid, review_id: Option<Uuid>, action: &str, actor: Option<Uuid>, detail: Option<&str>) -> Result<()> {
+        sqlx::query(
+                "INSERT INTO moderation_log (id, document_id, review_id, action, actor, detail, created_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)"
+            )
+            .bind(Uuid::new_v4())
+            .bind(doc_id)
+            .bind(review_id)
+            .bind(action)
+            .bind(actor)
+            .bind(detail)
+            .bind(Utc::now())
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to log moderation of document ID {}", doc_id))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_service::DocumentService;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    struct Unavailable;
+
+    #[async_trait]
+    impl Moderator for Unavailable {
+        fn name(&self) -> &str {
+            "unavailable"
+        }
+
+        async fn review(&self, _submission: &Submission<'_>) -> Result<Verdict> {
+            Err(anyhow!("classifier is down"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keyword_moderator_matches_whole_words() -> Result<()> {
+        let moderator = KeywordModerator::new(&["Bad Word", " "], &["spam"]);
+        let review = |title: &'static str, text: &'static str| {
+            let moderator = &moderator;
+            async move { moderator.review(&Submission { doc_id: Uuid::nil(), title, text }).await }
+        };
+        assert_eq!(review("Notes", "a BAD,  word here").await?, Verdict::Block { reason: "contains 'bad word'".to_string() });
+        assert_eq!(review("Spam!", "").await?, Verdict::Flag { reason: "contains 'spam'".to_string() });
+        assert_eq!(review("Notes", "badword and spammy").await?, Verdict::Allow);
+        Ok(())
+    }
+
+    #[test]
+    fn test_classifier_verdicts_parse() {
+        let verdict: Verdict = serde_json::from_str(r#"{"verdict": "block", "reason": "hate speech"}"#).unwrap();
+        assert_eq!(verdict, Verdict::Block { reason: "hate speech".to_string() });
+        assert_eq!(serde_json::from_str::<Verdict>(r#"{"verdict": "allow"}"#).unwrap(), Verdict::Allow);
+        assert!(serde_json::from_str::<Verdict>(r#"{"verdict": "flag"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_documents_wait_for_approval() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let moderation = ModerationService::new(manager.clone())
+            .await?
+            .with_moderator(Arc::new(KeywordModerator::new(&["forbidden"], &["dubious"])));
+        let doc = doc_service.create_document("Moderated Document").await?;
+
+        assert_eq!(moderation.review_publication(doc.id, None, &doc.name, b"all fine").await?, Publication::Allowed);
+        let Publication::Flagged(flagged) = moderation.review_publication(doc.id, None, &doc.name, b"dubious").await? else {
+            panic!("Expected the document to be flagged");
+        };
+        assert!(!flagged.blocked);
+        let Publication::Blocked(blocked) = moderation.review_publication(doc.id, None, &doc.name, b"forbidden").await? else {
+            panic!("Expected the document to be blocked");
+        };
+        assert_eq!(blocked.findings, vec!["keywords: contains 'forbidden'".to_string()]);
+        let pending: Vec<Uuid> = moderation.pending_reviews().await?.iter().map(|review| review.id).collect();
+        assert!(pending.contains(&flagged.id) && pending.contains(&blocked.id));
+
+        let approved = moderation.decide(blocked.id, true, Some("Quoted in a policy document")).await?.unwrap();
+        assert_eq!(approved.status, ReviewStatus::Approved);
+        assert!(moderation.decide(blocked.id, false, None).await?.is_none(), "Reviews are decided once");
+        assert_eq!(moderation.review_publication(doc.id, None, &doc.name, b"forbidden").await?, Publication::Allowed);
+        assert!(matches!(moderation.review_publication(doc.id, None, &doc.name, b"forbidden again").await?, Publication::Blocked(_)));
+
+        let actions: Vec<String> = moderation.log_entries(doc.id).await?.into_iter().map(|entry| entry.action).collect();
+        assert_eq!(actions, ["allowed", "flagged", "blocked", "approved", "allowed", "blocked"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failing_moderators_hold_documents() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let moderation = ModerationService::new(manager).await?.with_moderator(Arc::new(Unavailable));
+        let doc = doc_service.create_document("Unmoderated Document").await?;
+        let Publication::Blocked(review) = moderation.review_publication(doc.id, None, &doc.name, b"text").await? else {
+            panic!("Expected the document to be held");
+        };
+        assert_eq!(review.findings, vec!["unavailable: could not check the document".to_string()]);
+        Ok(())
+    }
+}
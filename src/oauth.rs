@@ -0,0 +1,618 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Lets third-party tools act for a user with limited access, as an OAuth 2.0
+//! authorization server.
+//!
+//! Only the authorization code grant with PKCE (`S256`) is supported, for public clients an
+//! administrator registers with fixed redirect URIs. A tool sends the user to the web client
+//! at `/app/oauth/authorize`, which shows what is asked for and records the user's consent.
+//! The tool then trades the code it is redirected back with for an opaque access token. Tokens
+//! are stored hashed, last an hour, and only ever narrow what the user could do themselves.
+
+use crate::db::Manager;
+use crate::migrations::{self, Component, Migration};
+use crate::user_service::{generate_secret, hash_token, DocumentAccess, DocumentRole};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{info, instrument};
+use url::Url;
+use uuid::Uuid;
+
+pub const ACCESS_TOKEN_PREFIX: &str = "coa_";
+const CODE_PREFIX: &str = "coc_";
+pub const ACCESS_TOKEN_LIFETIME: TimeDelta = TimeDelta::hours(1);
+const CODE_LIFETIME: TimeDelta = TimeDelta::minutes(10);
+
+/// Something a client may do for a user. Written as in the `scope` parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OAuthScope {
+    /// Read the documents the user can read.
+    ReadDocuments,
+    /// Comment on the documents the user can comment on.
+    WriteComments,
+}
+
+impl fmt::Display for OAuthScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OAuthScope::ReadDocuments => write!(f, "documents:read"),
+            OAuthScope::WriteComments => write!(f, "comments:write"),
+        }
+    }
+}
+
+impl FromStr for OAuthScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "documents:read" => Ok(OAuthScope::ReadDocuments),
+            "comments:write" => Ok(OAuthScope::WriteComments),
+            other => Err(anyhow!("Unknown scope '{}'", other)),
+        }
+    }
+}
+
+impl Serialize for OAuthScope {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Parses a space-separated `scope` parameter into a sorted set of at least one scope.
+pub fn parse_scopes(scope: &str) -> Result<Vec<OAuthScope>> {
+    let mut scopes = scope.split_whitespace().map(str::parse).collect::<Result<Vec<OAuthScope>>>()?;
+    if scopes.is_empty() {
+        bail!("At least one scope is required");
+    }
+    scopes.sort();
+    scopes.dedup();
+    Ok(scopes)
+}
+
+/// Formats scopes as a `scope` parameter.
+pub fn format_scopes(scopes: &[OAuthScope]) -> String {
+    scopes.iter().map(OAuthScope::to_string).collect::<Vec<_>>().join(" ")
+}
+
+fn parse_stored_scopes(scopes: &[String]) -> Result<Vec<OAuthScope>> {
+    scopes.iter().map(|scope| scope.parse()).collect()
+}
+
+/// Whether `challenge` is a PKCE `S256` challenge: a base64url SHA-256 digest.
+pub fn is_code_challenge(challenge: &str) -> bool {
+    challenge.len() == 43 && BASE64_URL.decode(challenge).is_ok_and(|digest| digest.len() == 32)
+}
+
+/// Whether `verifier` hashes to `challenge`, per RFC 7636. Verifiers are 43 to 128
+/// unreserved characters.
+fn verify_pkce(verifier: &str, challenge: &str) -> bool {
+    let well_formed = (43..=128).contains(&verifier.len())
+        && verifier.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._~".contains(&b));
+    well_formed && BASE64_URL.encode(Sha256::digest(verifier.as_bytes())) == challenge
+}
+
+/// Redirect URIs must be absolute, without a fragment, and use HTTPS unless they point
+/// at the client's own machine.
+pub fn check_redirect_uri(uri: &str) -> Result<()> {
+    let parsed = Url::parse(uri).context(format!("Invalid redirect URI: {}", uri))?;
+    if parsed.fragment().is_some() {
+        bail!("Redirect URI {} must not have a fragment", uri);
+    }
+    let loopback = matches!(parsed.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+    match parsed.scheme() {
+        "https" => Ok(()),
+        "http" if loopback => Ok(()),
+        _ => bail!("Redirect URI {} must use HTTPS", uri),
+    }
+}
+
+/// `redirect_uri` with `params` added to its query, for sending the user back to a client.
+pub fn redirect_with(redirect_uri: &str, params: &[(&str, &str)]) -> Result<String> {
+    let mut url = Url::parse(redirect_uri).context(format!("Invalid redirect URI: {}", redirect_uri))?;
+    url.query_pairs_mut().extend_pairs(params);
+    Ok(url.into())
+}
+
+/// A third-party tool allowed to ask users for access.
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+pub struct OAuthClient {
+    pub id: Uuid,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A user's standing permission for a client, shown so they can withdraw it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Consent {
+    pub client_id: Uuid,
+    pub client_name: String,
+    pub scopes: Vec<OAuthScope>,
+    pub granted_at: DateTime<Utc>,
+}
+
+/// A newly issued access token. `token` is shown once and never stored.
+pub struct IssuedAccessToken {
+    pub token: String,
+    pub scopes: Vec<OAuthScope>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A client acting for a user, authenticated by one of its access tokens.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delegation {
+    pub token_id: Uuid,
+    pub client_id: Uuid,
+    pub user_id: Uuid,
+    pub scopes: Vec<OAuthScope>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Delegation {
+    pub fn allows(&self, scope: OAuthScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// Narrows the user's own access to a document to what the client was granted.
+    /// Clients never edit documents or hold the owner's role.
+    pub fn limit(&self, access: DocumentAccess) -> DocumentAccess {
+        DocumentAccess {
+            read: access.read && self.allows(OAuthScope::ReadDocuments),
+            write: false,
+            comment: access.comment && self.allows(OAuthScope::WriteComments),
+            role: DocumentRole::Editor,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct CodeRow {
+    client_id: Uuid,
+    user_id: Uuid,
+    redirect_uri: String,
+    scopes: Vec<String>,
+    code_challenge: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct TokenRow {
+    id: Uuid,
+    client_id: Uuid,
+    user_id: Uuid,
+    scopes: Vec<String>,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct ConsentRow {
+    client_id: Uuid,
+    client_name: String,
+    scopes: Vec<String>,
+    granted_at: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct OAuthService {
+    db_manager: Arc<Manager>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "oauth",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS oauth_clients (
+                id UUID PRIMARY KEY,
+                name TEXT NOT NULL,
+                redirect_uris TEXT[] NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS oauth_consents (
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                client_id UUID NOT NULL REFERENCES oauth_clients(id) ON DELETE CASCADE,
+                scopes TEXT[] NOT NULL,
+                granted_at TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (user_id, client_id)
+            )",
+            "CREATE TABLE IF NOT EXISTS oauth_codes (
+                code_hash TEXT PRIMARY KEY,
+                client_id UUID NOT NULL REFERENCES oauth_clients(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                redirect_uri TEXT NOT NULL,
+                scopes TEXT[] NOT NULL,
+                code_challenge TEXT NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS oauth_tokens (
+                id UUID PRIMARY KEY,
+                token_hash TEXT NOT NULL UNIQUE,
+                client_id UUID NOT NULL REFERENCES oauth_clients(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                scopes TEXT[] NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL,
+                revoked_at TIMESTAMPTZ
+            )",
+            "CREATE INDEX IF NOT EXISTS oauth_tokens_by_grant ON oauth_tokens (user_id, client_id)",
+        ],
+        down: &[
+            "DROP TABLE IF EXISTS oauth_tokens",
+            "DROP TABLE IF EXISTS oauth_codes",
+            "DROP TABLE IF EXISTS oauth_consents",
+            "DROP TABLE IF EXISTS oauth_clients",
+        ],
+    }],
+};
+
+impl OAuthService {
+    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
+        let service = OAuthService { db_manager };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("OAuth schema initialized.");
+        Ok(())
+    }
+
+    /// Registers a client that may redirect users back to exactly `redirect_uris`.
+    #[instrument(skip_all)]
+    pub async fn register_client(&self, name: &str, redirect_uris: &[String]) -> Result<OAuthClient> {
+        if redirect_uris.is_empty() {
+            bail!("A client needs at least one redirect URI");
+        }
+        for uri in redirect_uris {
+            check_redirect_uri(uri)?;
+        }
+        let client = OAuthClient {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            redirect_uris: redirect_uris.to_vec(),
+            created_at: DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap_or_default(),
+        };
+        sqlx::query("INSERT INTO oauth_clients (id, name, redirect_uris, created_at) VALUES ($1, $2, $3, $4)")
+            .bind(client.id)
+            .bind(&client.name)
+            .bind(&client.redirect_uris)
+            .bind(client.created_at)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to register OAuth client '{}'", name))?;
+        info!("Registered OAuth client '{}' with ID: {}", name, client.id);
+        Ok(client)
+    }
+
+    #[instrument(skip_all, fields(client_id = %client_id))]
+    pub async fn client(&self, client_id: Uuid) -> Result<Option<OAuthClient>> {
+        sqlx::query_as("SELECT id, name, redirect_uris, created_at FROM oauth_clients WHERE id = $1")
+            .bind(client_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to get OAuth client {}", client_id))
+    }
+
+    /// The scopes a user has already granted a client, if any.
+    #[instrument(skip_all, fields(user_id = %user_id, client_id = %client_id))]
+    pub async fn granted_scopes(&self, user_id: Uuid, client_id: Uuid) -> Result<Vec<OAuthScope>> {
+        let scopes: Option<Vec<String>> = sqlx::query_scalar(
+                "SELECT scopes FROM oauth_consents WHERE user_id = $1 AND client_id = $2"
+            )
+            .bind(user_id)
+            .bind(client_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to get consent of user ID {} for client {}", user_id, client_id))?;
+        parse_stored_scopes(&scopes.unwrap_or_default())
+    }
+
+    /// Records that a user consents to `scopes` for a client, on top of anything granted
+    /// before, and issues a single-use code for them. The caller must have checked that
+    /// `redirect_uri` is one of the client's and that `code_challenge` is well formed.
+    #[instrument(skip_all, fields(user_id = %user_id, client_id = %client_id))]
+    pub async fn authorize(
+        &self,
+        user_id: Uuid,
+        client_id: Uuid,
+        redirect_uri: &str,
+        scopes: &[OAuthScope],
+        code_challenge: &str,
+    ) -> Result<String> {
+        let now = Utc::now();
+        let names: Vec<String> = scopes.iter().map(OAuthScope::to_string).collect();
+        let code = generate_secret(CODE_PREFIX);
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to begin transaction")?;
+        sqlx::query(
+                "INSERT INTO oauth_consents (user_id, client_id, scopes, granted_at) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (user_id, client_id) DO UPDATE
+                 SET scopes = ARRAY(SELECT DISTINCT unnest(array_cat(oauth_consents.scopes, excluded.scopes))),
+                     granted_at = excluded.granted_at"
+            )
+            .bind(user_id)
+            .bind(client_id)
+            .bind(&names)
+            .bind(now)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to record consent for client {}", client_id))?;
+        sqlx::query("DELETE FROM oauth_codes WHERE expires_at <= $1")
+            .bind(now)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to delete expired authorization codes")?;
+        sqlx::query(
+                "INSERT INTO oauth_codes (code_hash, client_id, user_id, redirect_uri, scopes, code_challenge, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)"
+            )
+            .bind(hash_token(&code))
+            .bind(client_id)
+            .bind(user_id)
+            .bind(redirect_uri)
+            .bind(&names)
+            .bind(code_challenge)
+            .bind(now + CODE_LIFETIME)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to issue authorization code for client {}", client_id))?;
+        tx.commit().await.context("Failed to commit authorization")?;
+        Ok(code)
+    }
+
+    /// Trades an authorization code for an access token. The code is used up even when
+    /// the exchange fails, so a leaked code cannot be retried with guessed verifiers.
+    #[instrument(skip_all, fields(client_id = %client_id))]
+    pub async fn exchange_code(
+        &self,
+        client_id: Uuid,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> Result<Option<IssuedAccessToken>> {
+        if !code.starts_with(CODE_PREFIX) {
+            return Ok(None);
+        }
+        let row: Option<CodeRow> = sqlx::query_as(
+                "DELETE FROM oauth_codes WHERE code_hash = $1
+                 RETURNING client_id, user_id, redirect_uri, scopes, code_challenge, expires_at"
+            )
+            .bind(hash_token(code))
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to redeem authorization code")?;
+        let now = Utc::now();
+        let Some(row) = row.filter(|row| {
+            row.client_id == client_id
+                && row.redirect_uri == redirect_uri
+                && row.expires_at > now
+                && verify_pkce(code_verifier, &row.code_challenge)
+        }) else {
+            return Ok(None);
+        };
+
+        let token = generate_secret(ACCESS_TOKEN_PREFIX);
+        let expires_at = now + ACCESS_TOKEN_LIFETIME;
+        sqlx::query(
+                "INSERT INTO oauth_tokens (id, token_hash, client_id, user_id, scopes, created_at, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)"
+            )
+            .bind(Uuid::new_v4())
+            .bind(hash_token(&token))
+            .bind(client_id)
+            .bind(row.user_id)
+            .bind(&row.scopes)
+            .bind(now)
+            .bind(expires_at)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to issue access token for client {}", client_id))?;
+        Ok(Some(IssuedAccessToken { token, scopes: parse_stored_scopes(&row.scopes)?, expires_at }))
+    }
+
+    /// Resolves a presented access token to the grant behind it, if the token is live and
+    /// its user can still sign in.
+    #[instrument(skip_all)]
+    pub async fn authenticate(&self, token: &str) -> Result<Option<Delegation>> {
+        if !token.starts_with(ACCESS_TOKEN_PREFIX) {
+            return Ok(None);
+        }
+        let row: Option<TokenRow> = sqlx::query_as(
+                "SELECT t.id, t.client_id, t.user_id, t.scopes, t.expires_at
+                 FROM oauth_tokens t JOIN users u ON u.id = t.user_id
+                 WHERE t.token_hash = $1 AND t.revoked_at IS NULL AND t.expires_at > $2
+                   AND u.deactivated_at IS NULL AND u.anonymized_at IS NULL"
+            )
+            .bind(hash_token(token))
+            .bind(Utc::now())
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to look up access token")?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Delegation {
+            token_id: row.id,
+            client_id: row.client_id,
+            user_id: row.user_id,
+            scopes: parse_stored_scopes(&row.scopes)?,
+            expires_at: row.expires_at,
+        }))
+    }
+
+    /// Whether an access token has neither been revoked nor expired.
+    #[instrument(skip_all, fields(token_id = %token_id))]
+    pub async fn token_active(&self, token_id: Uuid) -> Result<bool> {
+        sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM oauth_tokens WHERE id = $1 AND revoked_at IS NULL AND expires_at > $2)"
+            )
+            .bind(token_id)
+            .bind(Utc::now())
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to check access token {}", token_id))
+    }
+
+    /// Revokes an access token. Returns false for tokens that are unknown or already revoked.
+    #[instrument(skip_all)]
+    pub async fn revoke_token(&self, token: &str) -> Result<bool> {
+        let result = sqlx::query("UPDATE oauth_tokens SET revoked_at = $2 WHERE token_hash = $1 AND revoked_at IS NULL")
+            .bind(hash_token(token))
+            .bind(Utc::now())
+            .execute(&*self.db_manager.pool)
+            .await
+            .context("Failed to revoke access token")?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The clients a user has granted access, most recent first.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn list_consents(&self, user_id: Uuid) -> Result<Vec<Consent>> {
+        let rows: Vec<ConsentRow> = sqlx::query_as(
+                "SELECT c.client_id, k.name AS client_name, c.scopes, c.granted_at
+                 FROM oauth_consents c JOIN oauth_clients k ON k.id = c.client_id
+                 WHERE c.user_id = $1 ORDER BY c.granted_at DESC"
+            )
+            .bind(user_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list consents of user ID {}", user_id))?;
+        rows.into_iter()
+            .map(|row| {
+                Ok(Consent {
+                    client_id: row.client_id,
+                    client_name: row.client_name,
+                    scopes: parse_stored_scopes(&row.scopes)?,
+                    granted_at: row.granted_at,
+                })
+            })
+            .collect()
+    }
+
+    /// Withdraws a user's consent for a client, revoking its tokens and unused codes.
+    /// Returns false when there was no consent to withdraw.
+    #[instrument(skip_all, fields(user_id = %user_id, client_id = %client_id))]
+    pub async fn revoke_consent(&self, user_id: Uuid, client_id: Uuid) -> Result<bool> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to begin transaction")?;
+        let result = sqlx::query("DELETE FROM oauth_consents WHERE user_id = $1 AND client_id = $2")
+            .bind(user_id)
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to withdraw consent for client {}", client_id))?;
+        sqlx::query("DELETE FROM oauth_codes WHERE user_id = $1 AND client_id = $2")
+            .bind(user_id)
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to delete authorization codes for client {}", client_id))?;
+        sqlx::query(
+                "UPDATE oauth_tokens SET revoked_at = $3 WHERE user_id = $1 AND client_id = $2 AND revoked_at IS NULL"
+            )
+            .bind(user_id)
+            .bind(client_id)
+            .bind(Utc::now())
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to revoke access tokens for client {}", client_id))?;
+        tx.commit().await.context("Failed to commit consent withdrawal")?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_service::UserService;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+    // The example from RFC 7636, appendix B.
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    #[test]
+    fn test_scopes_and_pkce() {
+        let scopes = parse_scopes("comments:write documents:read comments:write").unwrap();
+        assert_eq!(scopes, vec![OAuthScope::ReadDocuments, OAuthScope::WriteComments]);
+        assert_eq!(format_scopes(&scopes), "documents:read comments:write");
+        assert!(parse_scopes("documents:write").is_err());
+        assert!(parse_scopes(" ").is_err());
+
+        assert!(is_code_challenge(CHALLENGE));
+        assert!(!is_code_challenge(&CHALLENGE[1..]));
+        assert!(!is_code_challenge("E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw+cM"));
+        assert!(verify_pkce(VERIFIER, CHALLENGE));
+        assert!(!verify_pkce(&VERIFIER[1..], CHALLENGE));
+
+        assert!(check_redirect_uri("https://tool.example.com/callback").is_ok());
+        assert!(check_redirect_uri("http://127.0.0.1:8080/callback").is_ok());
+        assert!(check_redirect_uri("http://tool.example.com/callback").is_err());
+        assert!(check_redirect_uri("https://tool.example.com/callback#token").is_err());
+        assert_eq!(
+            redirect_with("https://tool.example.com/callback?v=1", &[("code", "coc_1"), ("state", "a b")]).unwrap(),
+            "https://tool.example.com/callback?v=1&code=coc_1&state=a+b",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_authorization_code_flow() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let users = UserService::new(manager.clone()).await?;
+        let oauth = OAuthService::new(manager).await?;
+        let user = users.register_user("OAuth User", &format!("oauth-{}@example.com", Uuid::new_v4()), "correct horse battery").await?;
+        let redirect_uri = "https://tool.example.com/callback".to_string();
+        let client = oauth.register_client("Reading Tool", std::slice::from_ref(&redirect_uri)).await?;
+        assert_eq!(oauth.client(client.id).await?, Some(client.clone()));
+
+        // A wrong verifier uses the code up.
+        let scopes = [OAuthScope::ReadDocuments];
+        let code = oauth.authorize(user.id, client.id, &redirect_uri, &scopes, CHALLENGE).await?;
+        assert!(oauth.exchange_code(client.id, &code, &redirect_uri, &VERIFIER[1..]).await?.is_none());
+        assert!(oauth.exchange_code(client.id, &code, &redirect_uri, VERIFIER).await?.is_none());
+
+        let code = oauth.authorize(user.id, client.id, &redirect_uri, &scopes, CHALLENGE).await?;
+        let issued = oauth.exchange_code(client.id, &code, &redirect_uri, VERIFIER).await?.expect("code should redeem");
+        assert!(oauth.exchange_code(client.id, &code, &redirect_uri, VERIFIER).await?.is_none());
+        assert_eq!(issued.scopes, scopes);
+        let delegation = oauth.authenticate(&issued.token).await?.expect("token should authenticate");
+        assert_eq!((delegation.user_id, delegation.client_id), (user.id, client.id));
+        assert_eq!(
+            delegation.limit(DocumentAccess::FULL),
+            DocumentAccess { read: true, write: false, comment: false, role: DocumentRole::Editor },
+        );
+
+        // Consent accumulates, and withdrawing it revokes the client's tokens.
+        oauth.authorize(user.id, client.id, &redirect_uri, &[OAuthScope::WriteComments], CHALLENGE).await?;
+        assert_eq!(oauth.granted_scopes(user.id, client.id).await?.len(), 2);
+        assert_eq!(oauth.list_consents(user.id).await?[0].client_name, "Reading Tool");
+        assert!(oauth.revoke_consent(user.id, client.id).await?);
+        assert!(oauth.authenticate(&issued.token).await?.is_none());
+        assert!(!oauth.token_active(delegation.token_id).await?);
+        assert!(oauth.granted_scopes(user.id, client.id).await?.is_empty());
+        assert!(!oauth.revoke_consent(user.id, client.id).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,855 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Organizations model companies: people join one with a role, are grouped into teams,
+//! and the organization owns workspaces, and with them the workspaces' documents.
+//!
+//! An organization's owners and admins administer every one of its workspaces without
+//! being added to each (see `PermissionService`); members get only the workspaces they
+//! are added to. People join by accepting an invite sent to their email address, either by
+//! following its link or, for someone without an account yet, by verifying that address
+//! once they have registered. Leaving an organization also takes someone out of its teams
+//! and workspaces.
+
+use crate::db::Manager;
+use crate::email::{Email, Mailer};
+use crate::i18n::{self, Locale};
+use crate::migrations::{self, Component, Migration};
+use crate::permissions::MemberRole;
+use crate::preview::escape_html;
+use crate::user_service::{generate_secret, hash_token};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+const INVITE_PREFIX: &str = "coi_";
+/// How long an invite can be accepted for.
+pub const INVITE_LIFETIME: TimeDelta = TimeDelta::days(7);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrganizationRole {
+    Member,
+    /// Manages members, invites and teams, and administers every workspace of the organization.
+    Admin,
+    /// An admin who can also appoint and remove admins and owners. There is always at least one.
+    Owner,
+}
+
+impl OrganizationRole {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            OrganizationRole::Member => "member",
+            OrganizationRole::Admin => "admin",
+            OrganizationRole::Owner => "owner",
+        }
+    }
+
+    pub fn manages_members(&self) -> bool {
+        *self >= OrganizationRole::Admin
+    }
+}
+
+impl FromStr for OrganizationRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "member" => Ok(OrganizationRole::Member),
+            "admin" => Ok(OrganizationRole::Admin),
+            "owner" => Ok(OrganizationRole::Owner),
+            other => Err(anyhow!("Unknown organization role '{}'", other)),
+        }
+    }
+}
+
+/// Why a change to an organization's members or teams was refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrganizationRejection {
+    /// The change would leave the organization without an owner.
+    LastOwner,
+    /// The invite was sent to a different email address.
+    InviteForSomeoneElse,
+    /// Teams are made up of members of their organization.
+    NotAMember,
+    /// Another team in the organization has the name.
+    TeamNameTaken,
+}
+
+impl std::fmt::Display for OrganizationRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrganizationRejection::LastOwner => write!(f, "An organization must keep at least one owner"),
+            OrganizationRejection::InviteForSomeoneElse => write!(f, "The invite was sent to a different email address"),
+            OrganizationRejection::NotAMember => write!(f, "Only members of the organization can join its teams"),
+            OrganizationRejection::TeamNameTaken => write!(f, "A team with this name already exists"),
+        }
+    }
+}
+
+impl std::error::Error for OrganizationRejection {}
+
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+pub struct Organization {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OrganizationMember {
+    pub user_id: Uuid,
+    pub role: OrganizationRole,
+    pub joined_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct OrganizationMemberRow {
+    user_id: Uuid,
+    role: String,
+    joined_at: DateTime<Utc>,
+}
+
+impl TryFrom<OrganizationMemberRow> for OrganizationMember {
+    type Error = anyhow::Error;
+
+    fn try_from(row: OrganizationMemberRow) -> Result<Self> {
+        Ok(OrganizationMember { user_id: row.user_id, role: row.role.parse()?, joined_at: row.joined_at })
+    }
+}
+
+/// An organization someone belongs to, and their role in it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Membership {
+    #[serde(flatten)]
+    pub organization: Organization,
+    pub role: OrganizationRole,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+pub struct Team {
+    pub id: Uuid,
+    pub organization_id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An invitation to join an organization with a role. Only a hash of its secret is stored.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Invite {
+    pub id: Uuid,
+    pub organization_id: Uuid,
+    pub email: String,
+    pub role: OrganizationRole,
+    pub invited_by: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct InviteRow {
+    id: Uuid,
+    organization_id: Uuid,
+    email: String,
+    role: String,
+    invited_by: Uuid,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+impl TryFrom<InviteRow> for Invite {
+    type Error = anyhow::Error;
+
+    fn try_from(row: InviteRow) -> Result<Self> {
+        Ok(Invite {
+            id: row.id,
+            organization_id: row.organization_id,
+            email: row.email,
+            role: row.role.parse()?,
+            invited_by: row.invited_by,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+        })
+    }
+}
+
+const INVITE_COLUMNS: &str = "id, organization_id, email, role, invited_by, created_at, expires_at";
+
+/// A newly created invite with its secret, which is not stored and cannot be shown again.
+#[derive(Clone, Debug, Serialize)]
+pub struct IssuedInvite {
+    #[serde(flatten)]
+    pub invite: Invite,
+    pub secret: String,
+    /// Whether the invite link was emailed; if not, the secret has to be passed on by hand.
+    pub emailed: bool,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "organizations",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS organizations (
+                id UUID PRIMARY KEY,
+                name TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS organization_members (
+                organization_id UUID NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                role TEXT NOT NULL,
+                joined_at TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (organization_id, user_id)
+            )",
+            "CREATE INDEX IF NOT EXISTS organization_members_by_user ON organization_members (user_id)",
+            "CREATE TABLE IF NOT EXISTS teams (
+                id UUID PRIMARY KEY,
+                organization_id UUID NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+                name TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                UNIQUE (organization_id, name)
+            )",
+            "CREATE TABLE IF NOT EXISTS team_members (
+                team_id UUID NOT NULL REFERENCES teams(id) ON DELETE CASCADE,
+                organization_id UUID NOT NULL,
+                user_id UUID NOT NULL,
+                added_at TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (team_id, user_id),
+                FOREIGN KEY (organization_id, user_id)
+                    REFERENCES organization_members (organization_id, user_id) ON DELETE CASCADE
+            )",
+            "CREATE TABLE IF NOT EXISTS organization_invites (
+                id UUID PRIMARY KEY,
+                organization_id UUID NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+                email TEXT NOT NULL,
+                role TEXT NOT NULL,
+                token_hash TEXT NOT NULL UNIQUE,
+                invited_by UUID NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE INDEX IF NOT EXISTS organization_invites_by_organization ON organization_invites (organization_id, created_at)",
+        ],
+        down: &[
+            "DROP TABLE IF EXISTS organization_invites",
+            "DROP TABLE IF EXISTS team_members",
+            "DROP TABLE IF EXISTS teams",
+            "DROP TABLE IF EXISTS organization_members",
+            "DROP TABLE IF EXISTS organizations",
+        ],
+    }],
+};
+
+#[derive(Clone)]
+pub struct OrganizationService {
+    db_manager: Arc<Manager>,
+    /// Sends invite links, which point at `public_url`. Unset when email is disabled.
+    mailer: Option<Arc<dyn Mailer>>,
+    public_url: String,
+}
+
+impl OrganizationService {
+    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
+        let service = OrganizationService { db_manager, mailer: None, public_url: String::new() };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    /// Emails invite links, pointing at the server's `public_url`.
+    pub fn with_mailer(mut self, mailer: Arc<dyn Mailer>, public_url: String) -> Self {
+        self.mailer = Some(mailer);
+        self.public_url = public_url;
+        self
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("Organization schema initialized.");
+        Ok(())
+    }
+
+    /// Creates an organization with `creator` as its owner.
+    #[instrument(skip_all, fields(creator = %creator))]
+    pub async fn create_organization(&self, name: &str, creator: Uuid) -> Result<Organization> {
+        let organization = Organization { id: Uuid::new_v4(), name: name.to_string(), created_at: Utc::now() };
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        sqlx::query("INSERT INTO organizations (id, name, created_at) VALUES ($1, $2, $3)")
+            .bind(organization.id)
+            .bind(&organization.name)
+            .bind(organization.created_at)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to create organization '{}'", name))?;
+        add_member(&mut tx, organization.id, creator, OrganizationRole::Owner).await?;
+        tx.commit().await.context("Failed to commit organization")?;
+        Ok(organization)
+    }
+
+    pub async fn get_organization(&self, organization_id: Uuid) -> Result<Option<Organization>> {
+        sqlx::query_as("SELECT id, name, created_at FROM organizations WHERE id = $1")
+            .bind(organization_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to get organization ID {}", organization_id))
+    }
+
+    /// The organizations the user belongs to, with their role in each, oldest first.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn organizations_of(&self, user_id: Uuid) -> Result<Vec<Membership>> {
+        let rows: Vec<(Uuid, String, DateTime<Utc>, String)> = sqlx::query_as(
+                "SELECT o.id, o.name, o.created_at, m.role FROM organizations o
+                 JOIN organization_members m ON m.organization_id = o.id
+                 WHERE m.user_id = $1 ORDER BY o.created_at, o.id"
+            )
+            .bind(user_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list organizations of user ID {}", user_id))?;
+        rows.into_iter()
+            .map(|(id, name, created_at, role)| Ok(Membership { organization: Organization { id, name, created_at }, role: role.parse()? }))
+            .collect()
+    }
+
+    /// Members in the order they joined.
+    #[instrument(skip_all, fields(organization_id = %organization_id))]
+    pub async fn members(&self, organization_id: Uuid) -> Result<Vec<OrganizationMember>> {
+        let rows: Vec<OrganizationMemberRow> = sqlx::query_as(
+                "SELECT user_id, role, joined_at FROM organization_members
+                 WHERE organization_id = $1 ORDER BY joined_at, user_id"
+            )
+            .bind(organization_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list members of organization ID {}", organization_id))?;
+        rows.into_iter().map(OrganizationMember::try_from).collect()
+    }
+
+    /// The user's role in the organization, or `None` if they are not a member.
+    #[instrument(skip_all, fields(organization_id = %organization_id, user_id = %user_id))]
+    pub async fn member_role(&self, organization_id: Uuid, user_id: Uuid) -> Result<Option<OrganizationRole>> {
+        let role: Option<String> = sqlx::query_scalar(
+                "SELECT role FROM organization_members WHERE organization_id = $1 AND user_id = $2"
+            )
+            .bind(organization_id)
+            .bind(user_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to look up membership of organization ID {}", organization_id))?;
+        role.map(|role| role.parse()).transpose()
+    }
+
+    /// Changes a member's role. Returns false if they are not a member; fails with
+    /// `OrganizationRejection::LastOwner` if it would leave the organization without an owner.
+    #[instrument(skip_all, fields(organization_id = %organization_id, user_id = %user_id))]
+    pub async fn set_role(&self, organization_id: Uuid, user_id: Uuid, role: OrganizationRole) -> Result<bool> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let updated = sqlx::query("UPDATE organization_members SET role = $3 WHERE organization_id = $1 AND user_id = $2")
+            .bind(organization_id)
+            .bind(user_id)
+            .bind(role.as_str())
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to change the role of user ID {} in organization ID {}", user_id, organization_id))?;
+        if updated.rows_affected() == 0 {
+            return Ok(false);
+        }
+        require_owner_left(&mut tx, organization_id).await?;
+        tx.commit().await.context("Failed to commit role change")?;
+        Ok(true)
+    }
+
+    /// Takes a user out of the organization, its teams and its workspaces. Returns false if
+    /// they were not a member; fails with `OrganizationRejection::LastOwner` if they are its
+    /// only owner.
+    #[instrument(skip_all, fields(organization_id = %organization_id, user_id = %user_id))]
+    pub async fn remove_member(&self, organization_id: Uuid, user_id: Uuid) -> Result<bool> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let removed = sqlx::query("DELETE FROM organization_members WHERE organization_id = $1 AND user_id = $2")
+            .bind(organization_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to remove user ID {} from organization ID {}", user_id, organization_id))?;
+        if removed.rows_affected() == 0 {
+            return Ok(false);
+        }
+        require_owner_left(&mut tx, organization_id).await?;
+        sqlx::query(
+                "DELETE FROM workspace_members WHERE user_id = $2
+                 AND workspace_id IN (SELECT id FROM workspaces WHERE organization_id = $1)"
+            )
+            .bind(organization_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to remove user ID {} from the workspaces of organization ID {}", user_id, organization_id))?;
+        tx.commit().await.context("Failed to commit member removal")?;
+        Ok(true)
+    }
+
+    /// Invites whoever owns `email` to join with `role`, for `INVITE_LIFETIME`.
+    #[instrument(skip_all, fields(organization_id = %organization_id, invited_by = %invited_by))]
+    pub async fn invite(
+        &self,
+        organization_id: Uuid,
+        email: &str,
+        role: OrganizationRole,
+        invited_by: Uuid,
+        locale: Locale,
+    ) -> Result<IssuedInvite> {
+        let secret = generate_secret(INVITE_PREFIX);
+        let now = Utc::now();
+        let row: InviteRow = sqlx::query_as(&format!(
+                "INSERT INTO organization_invites (id, organization_id, email, role, token_hash, invited_by, created_at, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                 RETURNING {}",
+                INVITE_COLUMNS,
+            ))
+            .bind(Uuid::new_v4())
+            .bind(organization_id)
+            .bind(email.trim())
+            .bind(role.as_str())
+            .bind(hash_token(&secret))
+            .bind(invited_by)
+            .bind(now)
+            .bind(now + INVITE_LIFETIME)
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to invite {} to organization ID {}", email, organization_id))?;
+        let invite: Invite = row.try_into()?;
+        // The invite stands either way; whoever sent it can still pass the secret on.
+        let emailed = match &self.mailer {
+            Some(mailer) => match self.send_invite(mailer.as_ref(), &invite, &secret, locale).await {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Failed to email invite {}: {:#}", invite.id, e);
+                    false
+                }
+            },
+            None => false,
+        };
+        Ok(IssuedInvite { invite, secret, emailed })
+    }
+
+    async fn send_invite(&self, mailer: &dyn Mailer, invite: &Invite, secret: &str, locale: Locale) -> Result<()> {
+        let (organization, inviter): (String, String) = sqlx::query_as(
+                "SELECT o.name, u.display_name FROM organizations o, users u WHERE o.id = $1 AND u.id = $2"
+            )
+            .bind(invite.organization_id)
+            .bind(invite.invited_by)
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to look up invite {}", invite.id))?;
+        let args = [("organization", organization), ("inviter", inviter)];
+        let body = i18n::message(locale, "organization-invite-body", &args);
+        let action = i18n::message(locale, "organization-invite-action", &[]);
+        let link = format!("{}/app/accept-invite?token={}", self.public_url, secret);
+        mailer.send(&Email {
+            to: invite.email.clone(),
+            subject: i18n::message(locale, "organization-invite-subject", &args),
+            text: format!("{}\n\n{}: {}\n", body, action, link),
+            html: format!(
+                "<html><body><p>{}</p><p><a href=\"{}\">{}</a></p></body></html>",
+                escape_html(&body),
+                escape_html(&link),
+                escape_html(&action)
+            ),
+            unsubscribe_url: None,
+        }).await
+    }
+
+    /// Unexpired invites, oldest first.
+    #[instrument(skip_all, fields(organization_id = %organization_id))]
+    pub async fn pending_invites(&self, organization_id: Uuid) -> Result<Vec<Invite>> {
+        let rows: Vec<InviteRow> = sqlx::query_as(&format!(
+                "SELECT {} FROM organization_invites WHERE organization_id = $1 AND expires_at > $2
+                 ORDER BY created_at, id",
+                INVITE_COLUMNS,
+            ))
+            .bind(organization_id)
+            .bind(Utc::now())
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list invites of organization ID {}", organization_id))?;
+        rows.into_iter().map(Invite::try_from).collect()
+    }
+
+    /// Withdraws an invite. Returns false if the organization has no such invite.
+    #[instrument(skip_all, fields(organization_id = %organization_id, invite_id = %invite_id))]
+    pub async fn revoke_invite(&self, organization_id: Uuid, invite_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM organization_invites WHERE id = $1 AND organization_id = $2")
+            .bind(invite_id)
+            .bind(organization_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to revoke invite {}", invite_id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Joins the organization the invite with this secret is for, as `user_id`, whose email
+    /// is `email`. The invite is used up. `None` if there is no such unexpired invite; fails
+    /// with `OrganizationRejection::InviteForSomeoneElse` if it was sent to another address.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn accept_invite(&self, secret: &str, user_id: Uuid, email: Option<&str>) -> Result<Option<OrganizationMember>> {
+        if !secret.starts_with(INVITE_PREFIX) {
+            return Ok(None);
+        }
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let row: Option<InviteRow> = sqlx::query_as(&format!(
+                "SELECT {} FROM organization_invites WHERE token_hash = $1 AND expires_at > $2",
+                INVITE_COLUMNS,
+            ))
+            .bind(hash_token(secret))
+            .bind(Utc::now())
+            .fetch_optional(&mut *tx)
+            .await
+            .context("Failed to look up invite")?;
+        let Some(invite) = row.map(Invite::try_from).transpose()? else {
+            return Ok(None);
+        };
+        if !email.is_some_and(|email| email.trim().eq_ignore_ascii_case(&invite.email)) {
+            return Err(OrganizationRejection::InviteForSomeoneElse.into());
+        }
+        let member = redeem(&mut tx, &invite, user_id).await?;
+        tx.commit().await.context("Failed to commit invite acceptance")?;
+        Ok(Some(member))
+    }
+
+    /// Accepts every unexpired invite sent to `email` on behalf of `user_id`, once the address
+    /// has been shown to be theirs. Returns the organizations joined.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn accept_invites_for(&self, user_id: Uuid, email: &str) -> Result<Vec<Uuid>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let rows: Vec<InviteRow> = sqlx::query_as(&format!(
+                "SELECT {} FROM organization_invites WHERE lower(email) = lower($1) AND expires_at > $2
+                 ORDER BY created_at, id",
+                INVITE_COLUMNS,
+            ))
+            .bind(email.trim())
+            .bind(Utc::now())
+            .fetch_all(&mut *tx)
+            .await
+            .context("Failed to look up invites")?;
+        let mut joined = Vec::new();
+        for row in rows {
+            let invite = Invite::try_from(row)?;
+            redeem(&mut tx, &invite, user_id).await?;
+            if !joined.contains(&invite.organization_id) {
+                joined.push(invite.organization_id);
+            }
+        }
+        tx.commit().await.context("Failed to commit invite acceptance")?;
+        Ok(joined)
+    }
+
+    /// Creates a team. Fails with `OrganizationRejection::TeamNameTaken` if the organization
+    /// already has one with the name.
+    #[instrument(skip_all, fields(organization_id = %organization_id))]
+    pub async fn create_team(&self, organization_id: Uuid, name: &str) -> Result<Team> {
+        let team = Team { id: Uuid::new_v4(), organization_id, name: name.to_string(), created_at: Utc::now() };
+        sqlx::query("INSERT INTO teams (id, organization_id, name, created_at) VALUES ($1, $2, $3, $4)")
+            .bind(team.id)
+            .bind(organization_id)
+            .bind(&team.name)
+            .bind(team.created_at)
+            .execute(&*self.db_manager.pool)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(e) if e.is_unique_violation() => OrganizationRejection::TeamNameTaken.into(),
+                e => anyhow!(e).context(format!("Failed to create team '{}' in organization ID {}", name, organization_id)),
+            })?;
+        Ok(team)
+    }
+
+    /// The organization's teams by name.
+    #[instrument(skip_all, fields(organization_id = %organization_id))]
+    pub async fn teams(&self, organization_id: Uuid) -> Result<Vec<Team>> {
+        sqlx::query_as("SELECT id, organization_id, name, created_at FROM teams WHERE organization_id = $1 ORDER BY name, id")
+            .bind(organization_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list teams of organization ID {}", organization_id))
+    }
+
+    pub async fn get_team(&self, team_id: Uuid) -> Result<Option<Team>> {
+        sqlx::query_as("SELECT id, organization_id, name, created_at FROM teams WHERE id = $1")
+            .bind(team_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to get team ID {}", team_id))
+    }
+
+    /// Deletes a team; its members stay in the organization.
+    #[instrument(skip_all, fields(team_id = %team_id))]
+    pub async fn delete_team(&self, team_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM teams WHERE id = $1")
+            .bind(team_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to delete team ID {}", team_id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Adds a member of the team's organization to it. Fails with
+    /// `OrganizationRejection::NotAMember` for anyone else.
+    #[instrument(skip_all, fields(team_id = %team.id, user_id = %user_id))]
+    pub async fn add_to_team(&self, team: &Team, user_id: Uuid) -> Result<()> {
+        sqlx::query(
+                "INSERT INTO team_members (team_id, organization_id, user_id, added_at) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (team_id, user_id) DO NOTHING"
+            )
+            .bind(team.id)
+            .bind(team.organization_id)
+            .bind(user_id)
+            .bind(Utc::now())
+            .execute(&*self.db_manager.pool)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(e) if e.is_foreign_key_violation() => OrganizationRejection::NotAMember.into(),
+                e => anyhow!(e).context(format!("Failed to add user ID {} to team ID {}", user_id, team.id)),
+            })?;
+        Ok(())
+    }
+
+    /// Returns false if the user was not on the team.
+    #[instrument(skip_all, fields(team_id = %team_id, user_id = %user_id))]
+    pub async fn remove_from_team(&self, team_id: Uuid, user_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM team_members WHERE team_id = $1 AND user_id = $2")
+            .bind(team_id)
+            .bind(user_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to remove user ID {} from team ID {}", user_id, team_id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The team's members in the order they were added.
+    #[instrument(skip_all, fields(team_id = %team_id))]
+    pub async fn team_members(&self, team_id: Uuid) -> Result<Vec<Uuid>> {
+        sqlx::query_scalar("SELECT user_id FROM team_members WHERE team_id = $1 ORDER BY added_at, user_id")
+            .bind(team_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list members of team ID {}", team_id))
+    }
+
+    /// Adds every member of the team to a workspace with `role`, leaving anyone already in
+    /// it as they are. Returns how many were added. Later changes to the team do not carry
+    /// over to the workspace.
+    #[instrument(skip_all, fields(team_id = %team_id, workspace_id = %workspace_id))]
+    pub async fn add_team_to_workspace(&self, team_id: Uuid, workspace_id: Uuid, role: MemberRole) -> Result<u64> {
+        let result = sqlx::query(
+                "INSERT INTO workspace_members (workspace_id, user_id, role, created_at)
+                 SELECT $2, user_id, $3, $4 FROM team_members WHERE team_id = $1
+                 ON CONFLICT (workspace_id, user_id) DO NOTHING"
+            )
+            .bind(team_id)
+            .bind(workspace_id)
+            .bind(role.as_str())
+            .bind(Utc::now())
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to add team ID {} to workspace ID {}", team_id, workspace_id))?;
+        Ok(result.rows_affected())
+    }
+}
+
+/// Uses up `invite` to make `user_id` a member. Someone who is already a member keeps the
+/// stronger of the two roles.
+async fn redeem(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, invite: &Invite, user_id: Uuid) -> Result<OrganizationMember> {
+    sqlx::query("DELETE FROM organization_invites WHERE id = $1")
+        .bind(invite.id)
+        .execute(&mut **tx)
+        .await
+        .context(format!("Failed to use up invite {}", invite.id))?;
+    let current: Option<OrganizationMemberRow> = sqlx::query_as(
+            "SELECT user_id, role, joined_at FROM organization_members WHERE organization_id = $1 AND user_id = $2"
+        )
+        .bind(invite.organization_id)
+        .bind(user_id)
+        .fetch_optional(&mut **tx)
+        .await
+        .context(format!("Failed to look up membership of organization ID {}", invite.organization_id))?;
+    let member = match current.map(OrganizationMember::try_from).transpose()? {
+        Some(member) if member.role >= invite.role => member,
+        Some(member) => {
+            sqlx::query("UPDATE organization_members SET role = $3 WHERE organization_id = $1 AND user_id = $2")
+                .bind(invite.organization_id)
+                .bind(user_id)
+                .bind(invite.role.as_str())
+                .execute(&mut **tx)
+                .await
+                .context(format!("Failed to change the role of user ID {} in organization ID {}", user_id, invite.organization_id))?;
+            OrganizationMember { role: invite.role, ..member }
+        }
+        None => add_member(tx, invite.organization_id, user_id, invite.role).await?,
+    };
+    Ok(member)
+}
+
+async fn add_member(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    organization_id: Uuid,
+    user_id: Uuid,
+    role: OrganizationRole,
+) -> Result<OrganizationMember> {
+    let member = OrganizationMember { user_id, role, joined_at: Utc::now() };
+    sqlx::query("INSERT INTO organization_members (organization_id, user_id, role, joined_at) VALUES ($1, $2, $3, $4)")
+        .bind(organization_id)
+        .bind(user_id)
+        .bind(role.as_str())
+        .bind(member.joined_at)
+        .execute(&mut **tx)
+        .await
+        .context(format!("Failed to add user ID {} to organization ID {}", user_id, organization_id))?;
+    Ok(member)
+}
+
+/// Fails with `OrganizationRejection::LastOwner` once no owner is left, so the caller's
+/// transaction is rolled back.
+async fn require_owner_left(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, organization_id: Uuid) -> Result<()> {
+    let owners: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM organization_members WHERE organization_id = $1 AND role = $2")
+        .bind(organization_id)
+        .bind(OrganizationRole::Owner.as_str())
+        .fetch_one(&mut **tx)
+        .await
+        .context(format!("Failed to count owners of organization ID {}", organization_id))?;
+    if owners == 0 {
+        return Err(OrganizationRejection::LastOwner.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_service::DocumentService;
+    use crate::permissions::PermissionService;
+    use crate::user_service::{DocumentAccess, UserService};
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[tokio::test]
+    async fn test_organizations_administer_their_workspaces() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let users = UserService::new(manager.clone()).await?;
+        let permissions = PermissionService::new(manager.clone()).await?;
+        let organizations = OrganizationService::new(manager).await?;
+        let suffix = Uuid::new_v4();
+        let founder = users.register_user("Founder", &format!("founder-{}@example.com", suffix), "correct horse battery").await?;
+        let manager_email = format!("manager-{}@example.com", suffix);
+        let manager = users.register_user("Manager", &manager_email, "correct horse battery").await?;
+        let outsider = users.register_user("Outsider", &format!("outsider-{}@example.com", suffix), "correct horse battery").await?;
+
+        let organization = organizations.create_organization("Company", founder.id).await?;
+        let workspace = permissions.create_workspace_in("Engineering", founder.id, Some(organization.id)).await?;
+        let doc = doc_service.create_document_in("Test Document for Organizations", Some(founder.id), Some(workspace.id)).await?;
+
+        // Invites are for whoever they were sent to, and only work once.
+        let issued = organizations.invite(organization.id, &manager_email.to_uppercase(), OrganizationRole::Admin, founder.id, Locale::FALLBACK).await?;
+        let refused = organizations.accept_invite(&issued.secret, outsider.id, outsider.email.as_deref()).await.unwrap_err();
+        assert_eq!(refused.downcast_ref(), Some(&OrganizationRejection::InviteForSomeoneElse));
+        let joined = organizations.accept_invite(&issued.secret, manager.id, Some(&manager_email)).await?.unwrap();
+        assert_eq!(joined.role, OrganizationRole::Admin);
+        assert!(organizations.accept_invite(&issued.secret, manager.id, Some(&manager_email)).await?.is_none());
+        assert!(organizations.pending_invites(organization.id).await?.is_empty());
+
+        // Admins of the organization administer its workspaces without being added to them.
+        assert_eq!(permissions.member_role(workspace.id, manager.id).await?, Some(MemberRole::Admin));
+        assert_eq!(permissions.document_access(Some(manager.id), doc.id).await?, Some(DocumentAccess::FULL));
+        assert!(permissions.document_owners(doc.id).await?.contains(&manager.id));
+        organizations.set_role(organization.id, manager.id, OrganizationRole::Member).await?;
+        assert_eq!(permissions.member_role(workspace.id, manager.id).await?, None);
+        assert!(!permissions.document_access(Some(manager.id), doc.id).await?.unwrap().any());
+
+        // Teams hold members of the organization only, and can be added to workspaces.
+        let team = organizations.create_team(organization.id, "Platform").await?;
+        let taken = organizations.create_team(organization.id, "Platform").await.unwrap_err();
+        assert_eq!(taken.downcast_ref(), Some(&OrganizationRejection::TeamNameTaken));
+        organizations.add_to_team(&team, manager.id).await?;
+        let refused = organizations.add_to_team(&team, outsider.id).await.unwrap_err();
+        assert_eq!(refused.downcast_ref(), Some(&OrganizationRejection::NotAMember));
+        assert_eq!(organizations.team_members(team.id).await?, [manager.id]);
+        assert_eq!(organizations.add_team_to_workspace(team.id, workspace.id, MemberRole::Member).await?, 1);
+        assert_eq!(permissions.member_role(workspace.id, manager.id).await?, Some(MemberRole::Member));
+
+        // The last owner can neither leave nor step down.
+        let error = organizations.remove_member(organization.id, founder.id).await.unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&OrganizationRejection::LastOwner));
+        let error = organizations.set_role(organization.id, founder.id, OrganizationRole::Admin).await.unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&OrganizationRejection::LastOwner));
+        assert_eq!(organizations.member_role(organization.id, founder.id).await?, Some(OrganizationRole::Owner));
+
+        // Leaving takes someone out of the organization's teams and workspaces.
+        assert!(organizations.remove_member(organization.id, manager.id).await?);
+        assert!(organizations.team_members(team.id).await?.is_empty());
+        assert_eq!(permissions.member_role(workspace.id, manager.id).await?, None);
+        assert!(!organizations.remove_member(organization.id, manager.id).await?);
+        let memberships = organizations.organizations_of(founder.id).await?;
+        let memberships: Vec<(Uuid, OrganizationRole)> = memberships.iter().map(|m| (m.organization.id, m.role)).collect();
+        assert_eq!(memberships, [(organization.id, OrganizationRole::Owner)]);
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct RecordingMailer {
+        sent: std::sync::Mutex<Vec<Email>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Mailer for RecordingMailer {
+        async fn send(&self, email: &Email) -> Result<()> {
+            self.sent.lock().unwrap().push(email.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_emailed_invites_join_once_the_address_is_registered() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let users = UserService::new(manager.clone()).await?;
+        let mailer = Arc::new(RecordingMailer::default());
+        let organizations = OrganizationService::new(manager).await?
+            .with_mailer(mailer.clone(), "https://collaborate.example".to_string());
+        let suffix = Uuid::new_v4();
+        let founder = users.register_user("Founder", &format!("founder-{}@example.com", suffix), "correct horse battery").await?;
+        let organization = organizations.create_organization("Emailed Company", founder.id).await?;
+
+        // The invite goes out before the invitee has an account.
+        let email = format!("newcomer-{}@example.com", suffix);
+        let issued = organizations.invite(organization.id, &email, OrganizationRole::Member, founder.id, Locale::FALLBACK).await?;
+        assert!(issued.emailed);
+        let sent = mailer.sent.lock().unwrap().pop().expect("an invite email");
+        assert_eq!(sent.to, email);
+        assert!(sent.subject.contains("Emailed Company"));
+        let token = sent.text.split("?token=").nth(1).expect("an invite link").trim();
+        assert_eq!(token, issued.secret);
+
+        let newcomer = users.register_user("Newcomer", &email.to_uppercase(), "correct horse battery").await?;
+        assert_eq!(organizations.accept_invites_for(newcomer.id, &email.to_uppercase()).await?, [organization.id]);
+        assert_eq!(organizations.member_role(organization.id, newcomer.id).await?, Some(OrganizationRole::Member));
+        assert!(organizations.pending_invites(organization.id).await?.is_empty());
+        assert!(organizations.accept_invites_for(newcomer.id, &email).await?.is_empty());
+        Ok(())
+    }
+}
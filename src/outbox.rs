@@ -0,0 +1,168 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The durable log of document events. Each event published on the `EventBus` is also
+//! written here, in the same transaction as the change it describes, under a sequence
+//! number that never changes. Integrations added later replay the log from the start to
+//! backfill their history, then keep polling from where they stopped.
+//!
+//! Sequence numbers are handed out before commit, so a slow transaction can commit an
+//! event below one already visible. Events are therefore only replayed once they are
+//! `SETTLE_TIME` old, by which point the transaction that wrote them, and those of every
+//! event before them, have finished.
+
+use crate::events::DocumentEvent;
+use crate::migrations::{Component, Migration};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// How long an event waits before it is replayed; see the module documentation.
+pub const SETTLE_TIME: TimeDelta = TimeDelta::seconds(5);
+pub const MAX_REPLAY_BATCH: i64 = 1000;
+
+/// An event as recorded in the log.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LoggedEvent {
+    pub seq: i64,
+    /// The version of `event`'s payload; see `events::schema`.
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub event: DocumentEvent,
+    pub occurred_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct LoggedEventRow {
+    seq: i64,
+    kind: String,
+    document_id: Uuid,
+    occurred_at: DateTime<Utc>,
+}
+
+impl TryFrom<LoggedEventRow> for LoggedEvent {
+    type Error = anyhow::Error;
+
+    fn try_from(row: LoggedEventRow) -> Result<Self> {
+        let event = match row.kind.as_str() {
+            "changed" => DocumentEvent::Changed { doc_id: row.document_id },
+            "deleted" => DocumentEvent::Deleted { doc_id: row.document_id },
+            "deletion_requested" => DocumentEvent::DeletionRequested { doc_id: row.document_id },
+            "deletion_approved" => DocumentEvent::DeletionApproved { doc_id: row.document_id },
+            "deletion_rejected" => DocumentEvent::DeletionRejected { doc_id: row.document_id },
+            "deletion_expired" => DocumentEvent::DeletionExpired { doc_id: row.document_id },
+            other => return Err(anyhow!("Unknown document event kind '{}'", other)),
+        };
+        Ok(LoggedEvent { seq: row.seq, schema_version: event.schema().version, event, occurred_at: row.occurred_at })
+    }
+}
+
+/// The log. Entries outlive the documents they describe, so there is no foreign key.
+pub(crate) const SCHEMA: Component = Component {
+    name: "document_events",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE SEQUENCE IF NOT EXISTS document_event_seq",
+            "CREATE TABLE IF NOT EXISTS document_events (
+                seq BIGINT PRIMARY KEY DEFAULT nextval('document_event_seq'),
+                kind TEXT NOT NULL,
+                document_id UUID NOT NULL,
+                occurred_at TIMESTAMPTZ NOT NULL
+            )",
+        ],
+        down: &[
+            "DROP TABLE IF EXISTS document_events",
+            "DROP SEQUENCE IF EXISTS document_event_seq",
+        ],
+    }],
+};
+
+/// Logs `event` as part of `tx`; publish it on the bus once `tx` commits.
+pub(crate) async fn record(tx: &mut Transaction<'_, Postgres>, event: DocumentEvent) -> Result<()> {
+    let (kind, doc_id) = match event {
+        DocumentEvent::Changed { doc_id } => ("changed", doc_id),
+        DocumentEvent::Deleted { doc_id } => ("deleted", doc_id),
+        DocumentEvent::DeletionRequested { doc_id } => ("deletion_requested", doc_id),
+        DocumentEvent::DeletionApproved { doc_id } => ("deletion_approved", doc_id),
+        DocumentEvent::DeletionRejected { doc_id } => ("deletion_rejected", doc_id),
+        DocumentEvent::DeletionExpired { doc_id } => ("deletion_expired", doc_id),
+    };
+    // The time the sequence number was taken, not the transaction's start, which can be earlier.
+    sqlx::query("INSERT INTO document_events (kind, document_id, occurred_at) VALUES ($1, $2, clock_timestamp())")
+        .bind(kind)
+        .bind(doc_id)
+        .execute(&mut **tx)
+        .await
+        .context(format!("Failed to log {} event of document ID {}", kind, doc_id))?;
+    Ok(())
+}
+
+/// Up to `limit` events from `from_seq` on that occurred before `settled_before`, in
+/// sequence order.
+pub(crate) async fn replay(pool: &PgPool, from_seq: i64, settled_before: DateTime<Utc>, limit: i64) -> Result<Vec<LoggedEvent>> {
+    let rows: Vec<LoggedEventRow> = sqlx::query_as(
+            "SELECT seq, kind, document_id, occurred_at FROM document_events
+             WHERE seq >= $1 AND occurred_at < $2 ORDER BY seq LIMIT $3"
+        )
+        .bind(from_seq)
+        .bind(settled_before)
+        .bind(limit.clamp(1, MAX_REPLAY_BATCH))
+        .fetch_all(pool)
+        .await
+        .context(format!("Failed to replay document events from {}", from_seq))?;
+    rows.into_iter().map(LoggedEvent::try_from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Manager;
+    use crate::document_service::DocumentService;
+    use std::sync::Arc;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[tokio::test]
+    async fn test_changes_are_logged_in_order() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let start: i64 = sqlx::query_scalar("SELECT COALESCE(max(seq), 0) + 1 FROM document_events")
+            .fetch_one(&*manager.pool)
+            .await?;
+        let doc = doc_service.create_document("Logged").await?;
+        doc_service.update_document_content(doc.id, b"first".to_vec()).await?;
+        doc_service.append_document_update(doc.id, b"first", b"first!".to_vec(), None).await?;
+        doc_service.delete_document(doc.id).await?;
+
+        // Other tests log events concurrently, so look only at this document's.
+        let logged = replay(&manager.pool, start, Utc::now() + TimeDelta::seconds(1), MAX_REPLAY_BATCH).await?;
+        let ours: Vec<&LoggedEvent> = logged.iter().filter(|logged| logged.event.doc_id() == doc.id).collect();
+        let events: Vec<DocumentEvent> = ours.iter().map(|logged| logged.event).collect();
+        // Creating a document saves its empty content.
+        let changed = DocumentEvent::Changed { doc_id: doc.id };
+        assert_eq!(events, [changed, changed, changed, DocumentEvent::Deleted { doc_id: doc.id }]);
+        assert!(ours.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+
+        // Nothing is replayed before it settles, and replays start where asked.
+        assert!(replay(&manager.pool, start, Utc::now() - TimeDelta::minutes(1), MAX_REPLAY_BATCH).await?.is_empty());
+        let rest = replay(&manager.pool, ours[2].seq, Utc::now() + TimeDelta::seconds(1), MAX_REPLAY_BATCH).await?;
+        assert_eq!(rest.iter().filter(|logged| logged.event.doc_id() == doc.id).count(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Argon2id password hashing. Hashes are stored as PHC strings, which record the
+//! parameters they were made with, so hashes made with older parameters still verify
+//! and can be upgraded the next time their owner logs in.
+
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use std::sync::OnceLock;
+
+// OWASP's recommended minimum for Argon2id: 19 MiB of memory, two passes, one lane.
+const MEMORY_KIB: u32 = 19 * 1024;
+const ITERATIONS: u32 = 2;
+const PARALLELISM: u32 = 1;
+
+fn hasher() -> Argon2<'static> {
+    let params = Params::new(MEMORY_KIB, ITERATIONS, PARALLELISM, None).expect("password hashing parameters are valid");
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+/// Hashes a password with a fresh salt and the current parameters.
+pub fn hash(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    hasher()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    /// The password is right. `needs_rehash` is set when the stored hash was made with
+    /// parameters other than the current ones and should be replaced.
+    Valid { needs_rehash: bool },
+}
+
+/// Checks a password against a stored hash in constant time. Malformed hashes never match.
+pub fn verify(password: &str, stored: &str) -> Verification {
+    let Ok(parsed) = PasswordHash::new(stored) else {
+        return Verification::Invalid;
+    };
+    // Verifying with the parameters recorded in the hash lets older hashes still match.
+    if Argon2::default().verify_password(password.as_bytes(), &parsed).is_err() {
+        return Verification::Invalid;
+    }
+    Verification::Valid { needs_rehash: !is_current(&parsed) }
+}
+
+fn is_current(parsed: &PasswordHash<'_>) -> bool {
+    let Ok(params) = Params::try_from(parsed) else {
+        return false;
+    };
+    parsed.algorithm == Algorithm::Argon2id.ident()
+        && parsed.version == Some(Version::V0x13.into())
+        && params.m_cost() == MEMORY_KIB
+        && params.t_cost() == ITERATIONS
+        && params.p_cost() == PARALLELISM
+}
+
+/// Spends as long as a real verification, for when there is no account to check against,
+/// so response times don't reveal which accounts exist.
+pub fn verify_decoy(password: &str) {
+    static DECOY: OnceLock<String> = OnceLock::new();
+    let decoy = DECOY.get_or_init(|| hash("decoy password").expect("hashing a constant cannot fail"));
+    verify(password, decoy);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify() -> Result<()> {
+        let stored = hash("correct horse battery")?;
+        assert!(stored.starts_with("$argon2id$"));
+        assert_eq!(verify("correct horse battery", &stored), Verification::Valid { needs_rehash: false });
+        assert_eq!(verify("wrong horse battery", &stored), Verification::Invalid);
+        assert_eq!(verify("correct horse battery", "not a hash"), Verification::Invalid);
+        Ok(())
+    }
+
+    #[test]
+    fn test_outdated_hashes_verify_but_need_rehash() -> Result<()> {
+        let weak = Argon2::new(Algorithm::Argon2i, Version::V0x13, Params::new(8 * 1024, 1, 1, None).unwrap());
+        let salt = SaltString::generate(&mut OsRng);
+        let stored = weak.hash_password(b"correct horse battery", &salt).unwrap().to_string();
+        assert_eq!(verify("correct horse battery", &stored), Verification::Valid { needs_rehash: true });
+        Ok(())
+    }
+}
@@ -0,0 +1,987 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Workspaces, the policies that decide who may do what with their documents, and
+//! per-document sharing.
+//!
+//! A workspace's default policy is stored once and evaluated on every access check, so
+//! changing it changes access to every document in the workspace, existing ones included.
+//! Owners can also share a document with individual users, or with anyone holding a share
+//! link. Documents with neither an owner nor a workspace, such as those created
+//! anonymously, are not governed here.
+//!
+//! A workspace may belong to an organization, whose owners and admins then count as the
+//! workspace's admins in every check here without being added to it.
+
+use crate::db::Manager;
+use crate::migrations::{self, Component, Migration};
+use crate::organizations;
+use crate::user_service::{generate_secret, hash_token, DocumentAccess, DocumentRole};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberRole {
+    /// Manages membership and policies, and has full access to every document in the workspace.
+    Admin,
+    Member,
+}
+
+impl MemberRole {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            MemberRole::Admin => "admin",
+            MemberRole::Member => "member",
+        }
+    }
+}
+
+impl FromStr for MemberRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "admin" => Ok(MemberRole::Admin),
+            "member" => Ok(MemberRole::Member),
+            other => Err(anyhow!("Unknown workspace member role '{}'", other)),
+        }
+    }
+}
+
+/// What a policy or share grants on a document. Commenters can read and comment, but not edit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyRole {
+    Viewer,
+    Commenter,
+    Editor,
+}
+
+impl PolicyRole {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            PolicyRole::Viewer => "viewer",
+            PolicyRole::Commenter => "commenter",
+            PolicyRole::Editor => "editor",
+        }
+    }
+
+    pub fn access(&self) -> DocumentAccess {
+        DocumentAccess {
+            read: true,
+            write: *self == PolicyRole::Editor,
+            comment: *self != PolicyRole::Viewer,
+            role: DocumentRole::Editor,
+        }
+    }
+}
+
+impl FromStr for PolicyRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "viewer" => Ok(PolicyRole::Viewer),
+            "commenter" => Ok(PolicyRole::Commenter),
+            "editor" => Ok(PolicyRole::Editor),
+            other => Err(anyhow!("Unknown policy role '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+pub struct Workspace {
+    pub id: Uuid,
+    pub name: String,
+    pub organization_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Access every workspace member gets to the workspace's documents, unless they own them.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DefaultPolicy {
+    pub workspace_id: Uuid,
+    pub member_role: PolicyRole,
+    pub updated_by: Uuid,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct DefaultPolicyRow {
+    workspace_id: Uuid,
+    member_role: String,
+    updated_by: Uuid,
+    updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<DefaultPolicyRow> for DefaultPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(row: DefaultPolicyRow) -> Result<Self> {
+        Ok(DefaultPolicy {
+            workspace_id: row.workspace_id,
+            member_role: row.member_role.parse()?,
+            updated_by: row.updated_by,
+            updated_at: row.updated_at,
+        })
+    }
+}
+
+/// A document shared with one user.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DocumentPermission {
+    pub document_id: Uuid,
+    pub user_id: Uuid,
+    pub role: PolicyRole,
+    pub granted_by: Uuid,
+    pub granted_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct DocumentPermissionRow {
+    document_id: Uuid,
+    user_id: Uuid,
+    role: String,
+    granted_by: Uuid,
+    granted_at: DateTime<Utc>,
+}
+
+impl TryFrom<DocumentPermissionRow> for DocumentPermission {
+    type Error = anyhow::Error;
+
+    fn try_from(row: DocumentPermissionRow) -> Result<Self> {
+        Ok(DocumentPermission {
+            document_id: row.document_id,
+            user_id: row.user_id,
+            role: row.role.parse()?,
+            granted_by: row.granted_by,
+            granted_at: row.granted_at,
+        })
+    }
+}
+
+/// Which documents a bulk share or unshare applies to.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentSelection {
+    Documents(Vec<Uuid>),
+    /// Every document in a workspace, or in the personal documents of the user with this
+    /// ID, including those in folders there (see `FolderService`).
+    FolderId(Uuid),
+}
+
+impl DocumentSelection {
+    // Bound as $1 and $2 of a query that selects documents with `SELECTION_CONDITION`.
+    fn binds(&self) -> (&[Uuid], Option<Uuid>) {
+        match self {
+            DocumentSelection::Documents(ids) => (ids, None),
+            DocumentSelection::FolderId(folder_id) => (&[], Some(*folder_id)),
+        }
+    }
+}
+
+const SHARE_LINK_PREFIX: &str = "csl_";
+
+/// A link that lets whoever holds it open one document with a given role, without an
+/// account. Only a hash of its secret is stored.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ShareLink {
+    pub id: Uuid,
+    pub document_id: Uuid,
+    pub role: PolicyRole,
+    pub created_by: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(FromRow)]
+struct ShareLinkRow {
+    id: Uuid,
+    document_id: Uuid,
+    role: String,
+    created_by: Uuid,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<ShareLinkRow> for ShareLink {
+    type Error = anyhow::Error;
+
+    fn try_from(row: ShareLinkRow) -> Result<Self> {
+        Ok(ShareLink {
+            id: row.id,
+            document_id: row.document_id,
+            role: row.role.parse()?,
+            created_by: row.created_by,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+        })
+    }
+}
+
+impl ShareLink {
+    /// What the link's holder may do with `doc_id`, given `access`, what they may do without it.
+    pub fn extend(&self, doc_id: Uuid, access: DocumentAccess) -> DocumentAccess {
+        if doc_id == self.document_id {
+            extend(access, self.role)
+        } else {
+            access
+        }
+    }
+}
+
+/// A newly created share link with its secret, which is not stored and cannot be shown again.
+#[derive(Clone, Debug, Serialize)]
+pub struct IssuedShareLink {
+    #[serde(flatten)]
+    pub link: ShareLink,
+    pub secret: String,
+}
+
+// Workspace membership as every check sees it, standing in for `workspace_members`: the
+// owners and admins of a workspace's organization are its admins too. Someone with both
+// keeps the stronger role, as "admin" sorts before "member".
+const WORKSPACE_ROLES: &str = "(SELECT workspace_id, user_id, MIN(role) AS role FROM (
+        SELECT workspace_id, user_id, role FROM workspace_members
+        UNION ALL
+        SELECT w.id, o.user_id, 'admin' FROM workspaces w
+        JOIN organization_members o ON o.organization_id = w.organization_id AND o.role IN ('owner', 'admin')
+    ) roles GROUP BY workspace_id, user_id)";
+
+// Selects the documents `d` named by a `DocumentSelection`.
+const SELECTION_CONDITION: &str = "(d.id = ANY($1) OR COALESCE(d.workspace_id, d.owner_id) = $2)";
+
+/// A document a user can open, and what they can do with it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AccessibleDocument {
+    pub document_id: Uuid,
+    pub name: Option<String>,
+    pub write: bool,
+    pub role: DocumentRole,
+}
+
+// Everything an access check on one document needs, gathered in one query.
+#[derive(FromRow)]
+struct AccessRow {
+    owner_id: Option<Uuid>,
+    workspace_id: Option<Uuid>,
+    member_role: Option<String>,
+    policy_role: Option<String>,
+    shared_role: Option<String>,
+}
+
+impl AccessRow {
+    /// What `user_id` may do with the document, or `None` if it is not governed here.
+    fn access(&self, user_id: Option<Uuid>) -> Result<Option<DocumentAccess>> {
+        if self.workspace_id.is_none() && self.owner_id.is_none() {
+            return Ok(None);
+        }
+        if user_id.is_some() && self.owner_id == user_id {
+            return Ok(Some(DocumentAccess::FULL));
+        }
+        let access = match self.member_role.as_deref().map(MemberRole::from_str).transpose()? {
+            Some(MemberRole::Admin) => DocumentAccess::FULL,
+            Some(MemberRole::Member) => match &self.policy_role {
+                Some(role) => role.parse::<PolicyRole>()?.access(),
+                None => DocumentAccess::default(),
+            },
+            None => DocumentAccess::default(),
+        };
+        match &self.shared_role {
+            Some(role) => Ok(Some(extend(access, role.parse::<PolicyRole>()?))),
+            None => Ok(Some(access)),
+        }
+    }
+}
+
+// A share or share link can add to what the workspace grants, but never takes anything away.
+fn extend(access: DocumentAccess, role: PolicyRole) -> DocumentAccess {
+    let granted = role.access();
+    DocumentAccess {
+        read: access.read || granted.read,
+        write: access.write || granted.write,
+        comment: access.comment || granted.comment,
+        role: access.role,
+    }
+}
+
+#[derive(FromRow)]
+struct AccessibleRow {
+    document_id: Uuid,
+    name: Option<String>,
+    #[sqlx(flatten)]
+    access: AccessRow,
+}
+
+#[derive(Clone)]
+pub struct PermissionService {
+    db_manager: Arc<Manager>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "permissions",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS workspaces (
+                id UUID PRIMARY KEY,
+                name TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS workspace_members (
+                workspace_id UUID NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                role TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (workspace_id, user_id)
+            )",
+            "CREATE TABLE IF NOT EXISTS workspace_policies (
+                workspace_id UUID PRIMARY KEY REFERENCES workspaces(id) ON DELETE CASCADE,
+                member_role TEXT NOT NULL,
+                updated_by UUID NOT NULL,
+                updated_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS document_permissions (
+                document_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                role TEXT NOT NULL,
+                granted_by UUID NOT NULL,
+                granted_at TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (document_id, user_id)
+            )",
+        ],
+        down: &[
+            "DROP TABLE IF EXISTS document_permissions",
+            "DROP TABLE IF EXISTS workspace_policies",
+            "DROP TABLE IF EXISTS workspace_members",
+            "DROP TABLE IF EXISTS workspaces",
+        ],
+    }, Migration {
+        version: 2,
+        description: "share links",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS share_links (
+                id UUID PRIMARY KEY,
+                document_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                token_hash TEXT NOT NULL UNIQUE,
+                role TEXT NOT NULL,
+                created_by UUID NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                expires_at TIMESTAMPTZ
+            )",
+            "CREATE INDEX IF NOT EXISTS share_links_document ON share_links (document_id, created_at)",
+        ],
+        down: &["DROP TABLE IF EXISTS share_links"],
+    }, Migration {
+        version: 3,
+        description: "organizations",
+        up: &[
+            "ALTER TABLE workspaces ADD COLUMN IF NOT EXISTS organization_id UUID REFERENCES organizations(id) ON DELETE SET NULL",
+            "CREATE INDEX IF NOT EXISTS workspaces_by_organization ON workspaces (organization_id)",
+        ],
+        down: &[
+            "DROP INDEX IF EXISTS workspaces_by_organization",
+            "ALTER TABLE workspaces DROP COLUMN IF EXISTS organization_id",
+        ],
+    }],
+};
+
+impl PermissionService {
+    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
+        let service = PermissionService { db_manager };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &organizations::SCHEMA).await?;
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("Permission schema initialized.");
+        Ok(())
+    }
+
+    /// Creates a workspace with `creator` as its first admin.
+    pub async fn create_workspace(&self, name: &str, creator: Uuid) -> Result<Workspace> {
+        self.create_workspace_in(name, creator, None).await
+    }
+
+    /// Creates a workspace belonging to an organization, if given, with `creator` as its
+    /// first admin.
+    #[instrument(skip_all, fields(creator = %creator, organization_id = ?organization_id))]
+    pub async fn create_workspace_in(&self, name: &str, creator: Uuid, organization_id: Option<Uuid>) -> Result<Workspace> {
+        let workspace = Workspace { id: Uuid::new_v4(), name: name.to_string(), organization_id, created_at: Utc::now() };
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        sqlx::query("INSERT INTO workspaces (id, name, organization_id, created_at) VALUES ($1, $2, $3, $4)")
+            .bind(workspace.id)
+            .bind(&workspace.name)
+            .bind(workspace.organization_id)
+            .bind(workspace.created_at)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to create workspace '{}'", name))?;
+        sqlx::query("INSERT INTO workspace_members (workspace_id, user_id, role, created_at) VALUES ($1, $2, $3, $4)")
+            .bind(workspace.id)
+            .bind(creator)
+            .bind(MemberRole::Admin.as_str())
+            .bind(workspace.created_at)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to add user ID {} to workspace ID {}", creator, workspace.id))?;
+        tx.commit().await.context("Failed to commit workspace")?;
+        Ok(workspace)
+    }
+
+    /// Adds a member, or changes the role of an existing one.
+    #[instrument(skip_all, fields(workspace_id = %workspace_id, user_id = %user_id))]
+    pub async fn set_member(&self, workspace_id: Uuid, user_id: Uuid, role: MemberRole) -> Result<()> {
+        sqlx::query(
+                "INSERT INTO workspace_members (workspace_id, user_id, role, created_at) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (workspace_id, user_id) DO UPDATE SET role = excluded.role"
+            )
+            .bind(workspace_id)
+            .bind(user_id)
+            .bind(role.as_str())
+            .bind(Utc::now())
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to add user ID {} to workspace ID {}", user_id, workspace_id))?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(workspace_id = %workspace_id, user_id = %user_id))]
+    pub async fn remove_member(&self, workspace_id: Uuid, user_id: Uuid) -> Result<()> {
+        sqlx::query("DELETE FROM workspace_members WHERE workspace_id = $1 AND user_id = $2")
+            .bind(workspace_id)
+            .bind(user_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to remove user ID {} from workspace ID {}", user_id, workspace_id))?;
+        Ok(())
+    }
+
+    /// The user's role in the workspace, or `None` if they are not a member. Owners and
+    /// admins of the workspace's organization are admins.
+    #[instrument(skip_all, fields(workspace_id = %workspace_id, user_id = %user_id))]
+    pub async fn member_role(&self, workspace_id: Uuid, user_id: Uuid) -> Result<Option<MemberRole>> {
+        let role: Option<String> = sqlx::query_scalar(&format!("SELECT role FROM {} m WHERE workspace_id = $1 AND user_id = $2", WORKSPACE_ROLES))
+            .bind(workspace_id)
+            .bind(user_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to look up membership of workspace ID {}", workspace_id))?;
+        role.map(|role| role.parse()).transpose()
+    }
+
+    /// Everyone with full access to a document: its owner, or its workspace's admins.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn document_owners(&self, doc_id: Uuid) -> Result<Vec<Uuid>> {
+        sqlx::query_scalar(&format!(
+                "SELECT owner_id FROM documents_metadata WHERE id = $1 AND owner_id IS NOT NULL
+                 UNION
+                 SELECT m.user_id FROM documents_metadata d
+                 JOIN {} m ON m.workspace_id = d.workspace_id AND m.role = $2
+                 WHERE d.id = $1",
+                WORKSPACE_ROLES,
+            ))
+            .bind(doc_id)
+            .bind(MemberRole::Admin.as_str())
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list owners of document ID {}", doc_id))
+    }
+
+    #[instrument(skip_all, fields(workspace_id = %workspace_id))]
+    pub async fn default_policy(&self, workspace_id: Uuid) -> Result<Option<DefaultPolicy>> {
+        let row: Option<DefaultPolicyRow> = sqlx::query_as(
+                "SELECT workspace_id, member_role, updated_by, updated_at FROM workspace_policies WHERE workspace_id = $1"
+            )
+            .bind(workspace_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to get default policy of workspace ID {}", workspace_id))?;
+        row.map(DefaultPolicy::try_from).transpose()
+    }
+
+    /// Sets what members get on the workspace's documents.
+    #[instrument(skip_all, fields(workspace_id = %workspace_id, updated_by = %updated_by))]
+    pub async fn set_default_policy(&self, workspace_id: Uuid, member_role: PolicyRole, updated_by: Uuid) -> Result<DefaultPolicy> {
+        let row: DefaultPolicyRow = sqlx::query_as(
+                "INSERT INTO workspace_policies (workspace_id, member_role, updated_by, updated_at) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (workspace_id) DO UPDATE
+                 SET member_role = excluded.member_role, updated_by = excluded.updated_by, updated_at = excluded.updated_at
+                 RETURNING workspace_id, member_role, updated_by, updated_at"
+            )
+            .bind(workspace_id)
+            .bind(member_role.as_str())
+            .bind(updated_by)
+            .bind(Utc::now())
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to set default policy of workspace ID {}", workspace_id))?;
+        row.try_into()
+    }
+
+    /// Removes the default policy, leaving workspace documents to their owners and admins.
+    #[instrument(skip_all, fields(workspace_id = %workspace_id))]
+    pub async fn clear_default_policy(&self, workspace_id: Uuid) -> Result<()> {
+        sqlx::query("DELETE FROM workspace_policies WHERE workspace_id = $1")
+            .bind(workspace_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to clear default policy of workspace ID {}", workspace_id))?;
+        Ok(())
+    }
+
+    /// Shares a document with a user, or changes the role they were given.
+    #[instrument(skip_all, fields(doc_id = %doc_id, user_id = %user_id, granted_by = %granted_by))]
+    pub async fn share_document(&self, doc_id: Uuid, user_id: Uuid, role: PolicyRole, granted_by: Uuid) -> Result<DocumentPermission> {
+        let row: DocumentPermissionRow = sqlx::query_as(
+                "INSERT INTO document_permissions (document_id, user_id, role, granted_by, granted_at) VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (document_id, user_id) DO UPDATE
+                 SET role = excluded.role, granted_by = excluded.granted_by, granted_at = excluded.granted_at
+                 RETURNING document_id, user_id, role, granted_by, granted_at"
+            )
+            .bind(doc_id)
+            .bind(user_id)
+            .bind(role.as_str())
+            .bind(granted_by)
+            .bind(Utc::now())
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to share document ID {} with user ID {}", doc_id, user_id))?;
+        row.try_into()
+    }
+
+    /// Stops sharing a document with a user. Returns false if it was not shared with them.
+    #[instrument(skip_all, fields(doc_id = %doc_id, user_id = %user_id))]
+    pub async fn unshare_document(&self, doc_id: Uuid, user_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM document_permissions WHERE document_id = $1 AND user_id = $2")
+            .bind(doc_id)
+            .bind(user_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to unshare document ID {} with user ID {}", doc_id, user_id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Everyone the document is shared with, in the order it was shared.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn document_permissions(&self, doc_id: Uuid) -> Result<Vec<DocumentPermission>> {
+        let rows: Vec<DocumentPermissionRow> = sqlx::query_as(
+                "SELECT document_id, user_id, role, granted_by, granted_at FROM document_permissions
+                 WHERE document_id = $1 ORDER BY granted_at"
+            )
+            .bind(doc_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list permissions on document ID {}", doc_id))?;
+        rows.into_iter().map(DocumentPermission::try_from).collect()
+    }
+
+    /// Creates a link that grants `role` on a document to whoever presents its secret,
+    /// until `expires_at` if given.
+    #[instrument(skip_all, fields(doc_id = %doc_id, created_by = %created_by))]
+    pub async fn create_share_link(
+        &self,
+        doc_id: Uuid,
+        role: PolicyRole,
+        expires_at: Option<DateTime<Utc>>,
+        created_by: Uuid,
+    ) -> Result<IssuedShareLink> {
+        let secret = generate_secret(SHARE_LINK_PREFIX);
+        let row: ShareLinkRow = sqlx::query_as(
+                "INSERT INTO share_links (id, document_id, token_hash, role, created_by, created_at, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)
+                 RETURNING id, document_id, role, created_by, created_at, expires_at"
+            )
+            .bind(Uuid::new_v4())
+            .bind(doc_id)
+            .bind(hash_token(&secret))
+            .bind(role.as_str())
+            .bind(created_by)
+            .bind(Utc::now())
+            .bind(expires_at)
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to create a share link for document ID {}", doc_id))?;
+        Ok(IssuedShareLink { link: row.try_into()?, secret })
+    }
+
+    /// A document's share links, expired ones included, oldest first.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn share_links(&self, doc_id: Uuid) -> Result<Vec<ShareLink>> {
+        let rows: Vec<ShareLinkRow> = sqlx::query_as(
+                "SELECT id, document_id, role, created_by, created_at, expires_at FROM share_links
+                 WHERE document_id = $1 ORDER BY created_at, id"
+            )
+            .bind(doc_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list share links of document ID {}", doc_id))?;
+        rows.into_iter().map(ShareLink::try_from).collect()
+    }
+
+    /// Deletes a document's share link. Returns false if the document has no such link.
+    #[instrument(skip_all, fields(doc_id = %doc_id, link_id = %link_id))]
+    pub async fn revoke_share_link(&self, doc_id: Uuid, link_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM share_links WHERE id = $1 AND document_id = $2")
+            .bind(link_id)
+            .bind(doc_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to revoke share link {}", link_id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The unexpired share link with this secret, if any.
+    pub async fn authenticate_share_link(&self, secret: &str) -> Result<Option<ShareLink>> {
+        if !secret.starts_with(SHARE_LINK_PREFIX) {
+            return Ok(None);
+        }
+        let row: Option<ShareLinkRow> = sqlx::query_as(
+                "SELECT id, document_id, role, created_by, created_at, expires_at FROM share_links
+                 WHERE token_hash = $1 AND (expires_at IS NULL OR expires_at > $2)"
+            )
+            .bind(hash_token(secret))
+            .bind(Utc::now())
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to look up share link")?;
+        row.map(ShareLink::try_from).transpose()
+    }
+
+    /// Whether a share link still exists and has not expired.
+    pub async fn share_link_active(&self, link_id: Uuid) -> Result<bool> {
+        sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM share_links WHERE id = $1 AND (expires_at IS NULL OR expires_at > $2))"
+            )
+            .bind(link_id)
+            .bind(Utc::now())
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to check share link {}", link_id))
+    }
+
+    /// What `user_id` (`None` when anonymous) may do with a document, or `None` when the
+    /// document has neither an owner nor a workspace and so is not governed here.
+    #[instrument(skip_all, fields(user_id = ?user_id, doc_id = %doc_id))]
+    pub async fn document_access(&self, user_id: Option<Uuid>, doc_id: Uuid) -> Result<Option<DocumentAccess>> {
+        let row: Option<AccessRow> = sqlx::query_as(&format!(
+                "SELECT d.owner_id, d.workspace_id, m.role AS member_role, p.member_role AS policy_role, s.role AS shared_role
+                 FROM documents_metadata d
+                 LEFT JOIN {} m ON m.workspace_id = d.workspace_id AND m.user_id = $2
+                 LEFT JOIN workspace_policies p ON p.workspace_id = d.workspace_id
+                 LEFT JOIN document_permissions s ON s.document_id = d.id AND s.user_id = $2
+                 WHERE d.id = $1",
+                WORKSPACE_ROLES,
+            ))
+            .bind(doc_id)
+            .bind(user_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to evaluate access to document ID {}", doc_id))?;
+        match row {
+            Some(row) => row.access(user_id),
+            None => Ok(None),
+        }
+    }
+
+    /// `document_access` for each of `doc_ids` at once. Documents that are not governed here,
+    /// or do not exist, are left out.
+    #[instrument(skip_all, fields(user_id = ?user_id))]
+    pub async fn document_accesses(&self, user_id: Option<Uuid>, doc_ids: &[Uuid]) -> Result<HashMap<Uuid, DocumentAccess>> {
+        let rows: Vec<AccessibleRow> = sqlx::query_as(&format!(
+                "SELECT d.id AS document_id, d.name, d.owner_id, d.workspace_id,
+                        m.role AS member_role, p.member_role AS policy_role, s.role AS shared_role
+                 FROM documents_metadata d
+                 LEFT JOIN {} m ON m.workspace_id = d.workspace_id AND m.user_id = $2
+                 LEFT JOIN workspace_policies p ON p.workspace_id = d.workspace_id
+                 LEFT JOIN document_permissions s ON s.document_id = d.id AND s.user_id = $2
+                 WHERE d.id = ANY($1)",
+                WORKSPACE_ROLES,
+            ))
+            .bind(doc_ids)
+            .bind(user_id)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to evaluate access to documents")?;
+        let mut accesses = HashMap::with_capacity(rows.len());
+        for row in rows {
+            if let Some(access) = row.access.access(user_id)? {
+                accesses.insert(row.document_id, access);
+            }
+        }
+        Ok(accesses)
+    }
+
+    /// Shares every selected document that `granted_by` owns or administers with a user, in
+    /// one statement. Returns the resulting shares; other selected documents are skipped.
+    #[instrument(skip_all, fields(user_id = %user_id, granted_by = %granted_by))]
+    pub async fn share_documents(
+        &self,
+        selection: &DocumentSelection,
+        user_id: Uuid,
+        role: PolicyRole,
+        granted_by: Uuid,
+    ) -> Result<Vec<DocumentPermission>> {
+        let (ids, folder_id) = selection.binds();
+        let rows: Vec<DocumentPermissionRow> = sqlx::query_as(&format!(
+                "INSERT INTO document_permissions (document_id, user_id, role, granted_by, granted_at)
+                 SELECT d.id, $3, $4, $5, $6 FROM documents_metadata d
+                 LEFT JOIN {} m ON m.workspace_id = d.workspace_id AND m.user_id = $5
+                 WHERE {} AND (d.owner_id = $5 OR m.role = $7) AND d.deleted_at IS NULL
+                 ON CONFLICT (document_id, user_id) DO UPDATE
+                 SET role = excluded.role, granted_by = excluded.granted_by, granted_at = excluded.granted_at
+                 RETURNING document_id, user_id, role, granted_by, granted_at",
+                WORKSPACE_ROLES,
+                SELECTION_CONDITION,
+            ))
+            .bind(ids)
+            .bind(folder_id)
+            .bind(user_id)
+            .bind(role.as_str())
+            .bind(granted_by)
+            .bind(Utc::now())
+            .bind(MemberRole::Admin.as_str())
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to share documents with user ID {}", user_id))?;
+        rows.into_iter().map(DocumentPermission::try_from).collect()
+    }
+
+    /// Stops sharing every selected document that `revoked_by` owns or administers with a
+    /// user, in one statement. Returns the documents that were shared with them.
+    #[instrument(skip_all, fields(user_id = %user_id, revoked_by = %revoked_by))]
+    pub async fn unshare_documents(&self, selection: &DocumentSelection, user_id: Uuid, revoked_by: Uuid) -> Result<Vec<Uuid>> {
+        let (ids, folder_id) = selection.binds();
+        sqlx::query_scalar(&format!(
+                "DELETE FROM document_permissions s
+                 USING documents_metadata d
+                 LEFT JOIN {} m ON m.workspace_id = d.workspace_id AND m.user_id = $4
+                 WHERE s.document_id = d.id AND s.user_id = $3 AND {} AND (d.owner_id = $4 OR m.role = $5)
+                 RETURNING s.document_id",
+                WORKSPACE_ROLES,
+                SELECTION_CONDITION,
+            ))
+            .bind(ids)
+            .bind(folder_id)
+            .bind(user_id)
+            .bind(revoked_by)
+            .bind(MemberRole::Admin.as_str())
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to unshare documents with user ID {}", user_id))
+    }
+
+    /// Every document the user can open through ownership, workspace membership or a share,
+    /// most recently updated first. Documents with neither an owner nor a workspace are open
+    /// to everyone and not listed.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn accessible_documents(&self, user_id: Uuid) -> Result<Vec<AccessibleDocument>> {
+        let rows: Vec<AccessibleRow> = sqlx::query_as(&format!(
+                "SELECT d.id AS document_id, d.name, d.owner_id, d.workspace_id,
+                        m.role AS member_role, p.member_role AS policy_role, s.role AS shared_role
+                 FROM documents_metadata d
+                 LEFT JOIN {} m ON m.workspace_id = d.workspace_id AND m.user_id = $1
+                 LEFT JOIN workspace_policies p ON p.workspace_id = d.workspace_id
+                 LEFT JOIN document_permissions s ON s.document_id = d.id AND s.user_id = $1
+                 WHERE (d.owner_id = $1 OR m.role = $2 OR (m.role IS NOT NULL AND p.member_role IS NOT NULL) OR s.role IS NOT NULL)
+                   AND d.deleted_at IS NULL
+                 ORDER BY d.updated_at DESC, d.id",
+                WORKSPACE_ROLES,
+            ))
+            .bind(user_id)
+            .bind(MemberRole::Admin.as_str())
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list documents accessible to user ID {}", user_id))?;
+        let mut documents = Vec::with_capacity(rows.len());
+        for row in rows {
+            if let Some(access) = row.access.access(Some(user_id))?.filter(DocumentAccess::any) {
+                documents.push(AccessibleDocument { document_id: row.document_id, name: row.name, write: access.write, role: access.role });
+            }
+        }
+        Ok(documents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_service::DocumentService;
+    use crate::user_service::UserService;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[tokio::test]
+    async fn test_default_policy_governs_workspace_documents() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let users = UserService::new(manager.clone()).await?;
+        let permissions = PermissionService::new(manager).await?;
+        let suffix = Uuid::new_v4();
+        let admin = users.register_user("Admin", &format!("admin-{}@example.com", suffix), "correct horse battery").await?;
+        let member = users.register_user("Member", &format!("member-{}@example.com", suffix), "correct horse battery").await?;
+        let outsider = users.register_user("Outsider", &format!("outsider-{}@example.com", suffix), "correct horse battery").await?;
+
+        let workspace = permissions.create_workspace("Team", admin.id).await?;
+        permissions.set_member(workspace.id, member.id, MemberRole::Member).await?;
+        let doc = doc_service.create_document_in("Test Document for Policies", Some(member.id), Some(workspace.id)).await?;
+        let other = doc_service.create_document_in("Other Test Document for Policies", Some(admin.id), Some(workspace.id)).await?;
+        let personal = doc_service.create_document_for("Test Document for Policies", Some(member.id)).await?;
+
+        // Without a policy, only owners and admins get in.
+        assert_eq!(permissions.document_access(Some(member.id), doc.id).await?, Some(DocumentAccess::FULL));
+        assert_eq!(permissions.document_access(Some(admin.id), doc.id).await?, Some(DocumentAccess::FULL));
+        assert!(!permissions.document_access(Some(member.id), other.id).await?.unwrap().any());
+
+        // A policy applies to documents created before it as well as after.
+        permissions.set_default_policy(workspace.id, PolicyRole::Commenter, admin.id).await?;
+        let access = permissions.document_access(Some(member.id), other.id).await?.unwrap();
+        assert!(access.read && access.comment && !access.write);
+        permissions.set_default_policy(workspace.id, PolicyRole::Viewer, admin.id).await?;
+        let access = permissions.document_access(Some(member.id), other.id).await?.unwrap();
+        assert!(access.read && !access.comment && !access.write);
+        permissions.set_default_policy(workspace.id, PolicyRole::Editor, admin.id).await?;
+        assert!(permissions.document_access(Some(member.id), other.id).await?.unwrap().write);
+        assert_eq!(permissions.default_policy(workspace.id).await?.unwrap().member_role, PolicyRole::Editor);
+
+        assert!(!permissions.document_access(Some(outsider.id), other.id).await?.unwrap().any());
+        assert!(!permissions.document_access(None, other.id).await?.unwrap().any());
+        assert!(!permissions.document_access(Some(outsider.id), personal.id).await?.unwrap().any());
+
+        permissions.remove_member(workspace.id, member.id).await?;
+        assert!(!permissions.document_access(Some(member.id), other.id).await?.unwrap().any());
+        permissions.clear_default_policy(workspace.id).await?;
+        assert_eq!(permissions.default_policy(workspace.id).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shares_grant_access_to_single_documents() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let users = UserService::new(manager.clone()).await?;
+        let permissions = PermissionService::new(manager).await?;
+        let suffix = Uuid::new_v4();
+        let owner = users.register_user("Owner", &format!("owner-{}@example.com", suffix), "correct horse battery").await?;
+        let reader = users.register_user("Reader", &format!("reader-{}@example.com", suffix), "correct horse battery").await?;
+        let doc = doc_service.create_document_for("Test Document for Sharing", Some(owner.id)).await?;
+        let anonymous = doc_service.create_document("Test Document for Sharing").await?;
+
+        // Owned documents are private until shared; anonymous ones stay open.
+        assert_eq!(permissions.document_access(Some(owner.id), doc.id).await?, Some(DocumentAccess::FULL));
+        assert!(!permissions.document_access(Some(reader.id), doc.id).await?.unwrap().any());
+        assert!(!permissions.document_access(None, doc.id).await?.unwrap().any());
+        assert_eq!(permissions.document_access(Some(reader.id), anonymous.id).await?, None);
+
+        permissions.share_document(doc.id, reader.id, PolicyRole::Viewer, owner.id).await?;
+        let access = permissions.document_access(Some(reader.id), doc.id).await?.unwrap();
+        assert!(access.read && !access.write && access.role == DocumentRole::Editor);
+        permissions.share_document(doc.id, reader.id, PolicyRole::Editor, owner.id).await?;
+        let shares = permissions.document_permissions(doc.id).await?;
+        assert_eq!(shares.iter().map(|share| (share.user_id, share.role)).collect::<Vec<_>>(), [(reader.id, PolicyRole::Editor)]);
+        assert!(permissions.document_access(Some(reader.id), doc.id).await?.unwrap().write);
+
+        assert!(permissions.unshare_document(doc.id, reader.id).await?);
+        assert!(!permissions.unshare_document(doc.id, reader.id).await?);
+        assert!(!permissions.document_access(Some(reader.id), doc.id).await?.unwrap().any());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_share_links_grant_their_role_until_revoked() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let users = UserService::new(manager.clone()).await?;
+        let permissions = PermissionService::new(manager).await?;
+        let owner = users.register_user("Owner", &format!("owner-{}@example.com", Uuid::new_v4()), "correct horse battery").await?;
+        let doc = doc_service.create_document_for("Test Document for Share Links", Some(owner.id)).await?;
+        let other = doc_service.create_document_for("Other Test Document for Share Links", Some(owner.id)).await?;
+
+        let issued = permissions.create_share_link(doc.id, PolicyRole::Commenter, None, owner.id).await?;
+        let link = permissions.authenticate_share_link(&issued.secret).await?.unwrap();
+        assert_eq!(link, issued.link);
+        let without = permissions.document_access(None, doc.id).await?.unwrap();
+        let access = link.extend(doc.id, without);
+        assert!(access.read && access.comment && !access.write);
+        assert!(!link.extend(other.id, permissions.document_access(None, other.id).await?.unwrap()).any());
+        assert!(permissions.authenticate_share_link("csl_unknown").await?.is_none());
+        assert!(permissions.authenticate_share_link(&issued.secret.replace("csl_", "cbt_")).await?.is_none());
+
+        let expired = permissions.create_share_link(doc.id, PolicyRole::Editor, Some(Utc::now() - chrono::TimeDelta::minutes(1)), owner.id).await?;
+        assert!(permissions.authenticate_share_link(&expired.secret).await?.is_none());
+        assert!(!permissions.share_link_active(expired.link.id).await?);
+        let links = permissions.share_links(doc.id).await?;
+        assert_eq!(links.iter().map(|link| link.id).collect::<Vec<_>>(), [issued.link.id, expired.link.id]);
+
+        assert!(!permissions.revoke_share_link(other.id, link.id).await?);
+        assert!(permissions.revoke_share_link(doc.id, link.id).await?);
+        assert!(!permissions.share_link_active(link.id).await?);
+        assert!(permissions.authenticate_share_link(&issued.secret).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bulk_shares_cover_only_documents_the_granter_controls() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let users = UserService::new(manager.clone()).await?;
+        let permissions = PermissionService::new(manager).await?;
+        let suffix = Uuid::new_v4();
+        let owner = users.register_user("Owner", &format!("owner-{}@example.com", suffix), "correct horse battery").await?;
+        let reader = users.register_user("Reader", &format!("reader-{}@example.com", suffix), "correct horse battery").await?;
+        let stranger = users.register_user("Stranger", &format!("stranger-{}@example.com", suffix), "correct horse battery").await?;
+        let first = doc_service.create_document_for("First Bulk Test Document", Some(owner.id)).await?;
+        let second = doc_service.create_document_for("Second Bulk Test Document", Some(owner.id)).await?;
+        let foreign = doc_service.create_document_for("Foreign Bulk Test Document", Some(stranger.id)).await?;
+
+        // The owner's personal folder holds exactly their two documents.
+        let folder = DocumentSelection::FolderId(owner.id);
+        let shared = permissions.share_documents(&folder, reader.id, PolicyRole::Viewer, owner.id).await?;
+        let mut ids: Vec<Uuid> = shared.iter().map(|share| share.document_id).collect();
+        ids.sort();
+        let mut expected = vec![first.id, second.id];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        let listed = DocumentSelection::Documents(vec![first.id, foreign.id]);
+        let upgraded = permissions.share_documents(&listed, reader.id, PolicyRole::Editor, owner.id).await?;
+        assert_eq!(upgraded.iter().map(|share| (share.document_id, share.role)).collect::<Vec<_>>(), [(first.id, PolicyRole::Editor)]);
+
+        let accessible = permissions.accessible_documents(reader.id).await?;
+        let mut access: Vec<(Uuid, bool)> = accessible.iter().map(|doc| (doc.document_id, doc.write)).collect();
+        access.sort();
+        let mut expected = vec![(first.id, true), (second.id, false)];
+        expected.sort();
+        assert_eq!(access, expected);
+
+        // Strangers can't revoke shares on documents they don't control.
+        assert!(permissions.unshare_documents(&folder, reader.id, stranger.id).await?.is_empty());
+        assert_eq!(permissions.unshare_documents(&listed, reader.id, owner.id).await?, [first.id]);
+        let accessible = permissions.accessible_documents(reader.id).await?;
+        assert_eq!(accessible.iter().map(|doc| doc.document_id).collect::<Vec<_>>(), [second.id]);
+        Ok(())
+    }
+}
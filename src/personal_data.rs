@@ -0,0 +1,351 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Personal data requests. An account's owner can export everything the service holds
+//! about them, and delete the account. Deletion is staged: the account is deactivated at
+//! once and deleted for good after a grace period, set with
+//! `COLLABORATE_ACCOUNT_DELETION_GRACE_DAYS` (30 by default). Signing in again before then
+//! cancels it.
+//!
+//! Deleting an account purges the documents and folders it owns outside workspaces; its
+//! memberships, shares, sessions and keys go with the account row. Documents that outlive
+//! it keep what it wrote without naming it; see `DocumentService::forget_user`.
+
+use crate::db::Manager;
+use crate::document_service::{Comment, DocumentService};
+use crate::migrations::{self, Component, Migration};
+use crate::user_service::{User, UserRole};
+use crate::workspace_archive::{self, ArchivedDocument, DocumentRow, DOCUMENT_COLUMNS};
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+/// Bumped whenever the export layout changes incompatibly.
+pub const FORMAT_VERSION: u32 = 1;
+pub const DEFAULT_GRACE_PERIOD: TimeDelta = TimeDelta::days(30);
+
+const GRACE_DAYS_ENV: &str = "COLLABORATE_ACCOUNT_DELETION_GRACE_DAYS";
+const DELETION_BATCH_SIZE: i64 = 100;
+
+#[derive(Clone, Debug)]
+pub struct DeletionConfig {
+    /// How long a deleted account can still be recovered by signing in.
+    pub grace_period: TimeDelta,
+    pub interval: Duration,
+}
+
+impl DeletionConfig {
+    pub fn from_env() -> Result<Self> {
+        let grace_period = match env::var(GRACE_DAYS_ENV) {
+            Ok(days) => TimeDelta::days(days.parse().context(format!("{} must be a number of days", GRACE_DAYS_ENV))?),
+            Err(_) => DEFAULT_GRACE_PERIOD,
+        };
+        Ok(DeletionConfig { grace_period, interval: Duration::from_secs(60 * 60) })
+    }
+}
+
+/// Everything an account holds, as its owner downloads it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PersonalDataExport {
+    pub format_version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub profile: ExportedProfile,
+    /// Documents the account owns, in the layout of a workspace archive. Documents in the
+    /// trash are left out.
+    pub documents: Vec<ArchivedDocument>,
+    /// Every comment the account wrote, on its own documents or anyone else's.
+    pub comments: Vec<Comment>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ExportedProfile {
+    pub id: Uuid,
+    pub display_name: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub role: UserRole,
+    pub created_at: DateTime<Utc>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "personal_data",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            // A deletion stands while the account stays deactivated since `requested_at`.
+            "CREATE TABLE IF NOT EXISTS account_deletions (
+                user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+                requested_at TIMESTAMPTZ NOT NULL,
+                delete_after TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE INDEX IF NOT EXISTS account_deletions_due ON account_deletions (delete_after)",
+        ],
+        down: &["DROP TABLE IF EXISTS account_deletions"],
+    }],
+};
+
+pub struct PersonalDataService {
+    db_manager: Arc<Manager>,
+    doc_service: Arc<DocumentService>,
+    grace_period: TimeDelta,
+}
+
+impl PersonalDataService {
+    /// Expects the user, permission and folder schemas to be initialized.
+    pub async fn new(db_manager: Arc<Manager>, doc_service: Arc<DocumentService>) -> Result<Self> {
+        migrations::apply(&db_manager.pool, &SCHEMA).await?;
+        info!("Personal data schema initialized.");
+        Ok(PersonalDataService { db_manager, doc_service, grace_period: DEFAULT_GRACE_PERIOD })
+    }
+
+    /// Sets how long deleted accounts are kept before they are deleted for good.
+    pub fn with_grace_period(mut self, grace_period: TimeDelta) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Gathers everything `user` holds.
+    #[instrument(skip_all, fields(user_id = %user.id))]
+    pub async fn export(&self, user: &User) -> Result<PersonalDataExport> {
+        let pool = &*self.db_manager.pool;
+        let rows: Vec<DocumentRow> = sqlx::query_as(&format!(
+                "SELECT {DOCUMENT_COLUMNS} FROM documents_metadata
+                 WHERE owner_id = $1 AND deleted_at IS NULL ORDER BY created_at, id"
+            ))
+            .bind(user.id)
+            .fetch_all(pool)
+            .await
+            .context(format!("Failed to list documents of user ID {}", user.id))?;
+        let mut documents = Vec::with_capacity(rows.len());
+        for row in rows {
+            documents.push(workspace_archive::archive_document(pool, &self.doc_service, row).await?);
+        }
+        let comments = self.doc_service.comments_by(user.id).await?;
+        info!("Exported the data of user ID {} with {} documents", user.id, documents.len());
+        Ok(PersonalDataExport {
+            format_version: FORMAT_VERSION,
+            exported_at: Utc::now(),
+            profile: ExportedProfile {
+                id: user.id,
+                display_name: user.display_name.clone(),
+                email: user.email.clone(),
+                email_verified: user.email_verified,
+                role: user.role,
+                created_at: user.created_at,
+            },
+            documents,
+            comments,
+        })
+    }
+
+    /// Deactivates a person's account, ending its sessions, and schedules it for deletion
+    /// once the grace period is over. Returns when that will be, or `None` if the account
+    /// is a bot's or was already deactivated.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn request_deletion(&self, user_id: Uuid) -> Result<Option<DateTime<Utc>>> {
+        let now = Utc::now();
+        let delete_after = now + self.grace_period;
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let deactivated = sqlx::query(
+                "UPDATE users SET deactivated_at = $1
+                 WHERE id = $2 AND kind = 'human' AND deactivated_at IS NULL AND anonymized_at IS NULL"
+            )
+            .bind(now)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to deactivate user ID {}", user_id))?;
+        if deactivated.rows_affected() == 0 {
+            return Ok(None);
+        }
+        // Replaces any deletion the owner cancelled by signing in.
+        sqlx::query(
+                "INSERT INTO account_deletions (user_id, requested_at, delete_after) VALUES ($1, $2, $3)
+                 ON CONFLICT (user_id) DO UPDATE SET requested_at = excluded.requested_at, delete_after = excluded.delete_after"
+            )
+            .bind(user_id)
+            .bind(now)
+            .bind(delete_after)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to schedule deletion of user ID {}", user_id))?;
+        sqlx::query("UPDATE user_sessions SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL")
+            .bind(now)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to end sessions of user ID {}", user_id))?;
+        tx.commit().await.context(format!("Failed to commit deletion request of user ID {}", user_id))?;
+        info!("User ID {} asked for their account to be deleted after {}", user_id, delete_after);
+        Ok(Some(delete_after))
+    }
+
+    /// Deletes the accounts whose grace period was over by `now` and forgets deletions that
+    /// were cancelled. Returns the IDs of the deleted accounts.
+    #[instrument(skip_all)]
+    pub async fn delete_due_accounts(&self, now: DateTime<Utc>) -> Result<Vec<Uuid>> {
+        let pool = &*self.db_manager.pool;
+        sqlx::query(
+                "DELETE FROM account_deletions d WHERE NOT EXISTS (
+                     SELECT 1 FROM users u WHERE u.id = d.user_id AND u.deactivated_at = d.requested_at
+                 )"
+            )
+            .execute(pool)
+            .await
+            .context("Failed to forget cancelled account deletions")?;
+        let mut deleted = Vec::new();
+        loop {
+            let due: Vec<Uuid> = sqlx::query_scalar(
+                    "SELECT user_id FROM account_deletions WHERE delete_after <= $1 ORDER BY delete_after LIMIT $2"
+                )
+                .bind(now)
+                .bind(DELETION_BATCH_SIZE)
+                .fetch_all(pool)
+                .await
+                .context("Failed to query accounts due for deletion")?;
+            for &user_id in &due {
+                if self.delete_account(user_id).await? {
+                    deleted.push(user_id);
+                }
+            }
+            if (due.len() as i64) < DELETION_BATCH_SIZE {
+                return Ok(deleted);
+            }
+        }
+    }
+
+    /// Deletes an account for good unless its deletion was cancelled. Each step can be
+    /// repeated, so an account left halfway is finished on the next pass.
+    async fn delete_account(&self, user_id: Uuid) -> Result<bool> {
+        let pool = &*self.db_manager.pool;
+        // Suspended first, so that signing in can no longer cancel what is about to go.
+        let confirmed = sqlx::query(
+                "UPDATE users SET suspended = true
+                 WHERE id = $1 AND deactivated_at = (SELECT requested_at FROM account_deletions WHERE user_id = $1)"
+            )
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .context(format!("Failed to suspend user ID {} for deletion", user_id))?;
+        if confirmed.rows_affected() == 0 {
+            sqlx::query("DELETE FROM account_deletions WHERE user_id = $1")
+                .bind(user_id)
+                .execute(pool)
+                .await
+                .context(format!("Failed to forget cancelled deletion of user ID {}", user_id))?;
+            return Ok(false);
+        }
+        let purged = self.doc_service.purge_personal_documents(user_id).await?;
+        self.doc_service.forget_user(user_id).await?;
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
+        let statements = [
+            "DELETE FROM folders WHERE owner_id = $1 AND workspace_id IS NULL",
+            "UPDATE folders SET owner_id = NULL WHERE owner_id = $1",
+            "DELETE FROM users WHERE id = $1",
+        ];
+        for statement in statements {
+            sqlx::query(statement)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+                .context(format!("Failed to delete user ID {}", user_id))?;
+        }
+        tx.commit().await.context(format!("Failed to commit deletion of user ID {}", user_id))?;
+        info!("Deleted user ID {} and {} personal documents", user_id, purged.len());
+        Ok(true)
+    }
+}
+
+/// Periodically deletes accounts whose grace period is over.
+pub async fn run_deleter(service: Arc<PersonalDataService>, config: DeletionConfig) {
+    let mut ticker = tokio::time::interval(config.interval);
+    loop {
+        ticker.tick().await;
+        match service.delete_due_accounts(Utc::now()).await {
+            Ok(deleted) if deleted.is_empty() => {}
+            Ok(deleted) => info!("Deleted {} accounts at their owners' request", deleted.len()),
+            Err(e) => warn!("Deleting accounts failed; will retry: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_service::CommentThread;
+    use crate::folders::FolderService;
+    use crate::permissions::PermissionService;
+    use crate::user_service::UserService;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    struct Fixture {
+        users: UserService,
+        doc_service: Arc<DocumentService>,
+        personal_data: PersonalDataService,
+    }
+
+    async fn fixture() -> Result<Fixture> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let users = UserService::new(manager.clone()).await?;
+        let doc_service = Arc::new(DocumentService::new(manager.clone()).await?);
+        PermissionService::new(manager.clone()).await?;
+        FolderService::new(manager.clone(), doc_service.clone()).await?;
+        let personal_data = PersonalDataService::new(manager, doc_service.clone()).await?;
+        Ok(Fixture { users, doc_service, personal_data })
+    }
+
+    async fn register(users: &UserService, name: &str) -> Result<(User, String)> {
+        let email = format!("{}@example.com", Uuid::new_v4().simple());
+        Ok((users.register_user(name, &email, "correct horse battery").await?, email))
+    }
+
+    #[tokio::test]
+    async fn test_deleted_accounts_go_after_the_grace_period() -> Result<()> {
+        let Fixture { users, doc_service, personal_data } = fixture().await?;
+        let (user, email) = register(&users, "Leaving").await?;
+        let (other, _) = register(&users, "Staying").await?;
+        let diary = doc_service.create_document_for("Diary", Some(user.id)).await?;
+        let shared = doc_service.create_document_for("Shared", Some(other.id)).await?;
+        let comment = doc_service.add_comment(shared.id, Some(user.id), CommentThread::Start { anchor: None }, "Farewell").await?.unwrap();
+
+        let export = personal_data.export(&user).await?;
+        assert_eq!(export.profile.id, user.id);
+        assert_eq!(export.documents.iter().map(|doc| doc.id).collect::<Vec<_>>(), vec![diary.id]);
+        assert_eq!(export.comments, vec![comment.clone()]);
+
+        let delete_after = personal_data.request_deletion(user.id).await?.expect("deletion should be scheduled");
+        assert!(users.get_user(user.id).await?.unwrap().deactivated_at.is_some());
+        assert!(personal_data.request_deletion(user.id).await?.is_none(), "Already scheduled");
+        assert!(!personal_data.delete_due_accounts(delete_after - TimeDelta::seconds(1)).await?.contains(&user.id));
+        users.login(&email, "correct horse battery").await?.expect("signing in should reactivate the account");
+        assert!(!personal_data.delete_due_accounts(delete_after).await?.contains(&user.id), "Signing in cancels deletion");
+        assert!(doc_service.get_document(diary.id).await?.is_some());
+
+        let delete_after = personal_data.request_deletion(user.id).await?.expect("the account can be deleted again");
+        assert!(personal_data.delete_due_accounts(delete_after).await?.contains(&user.id));
+        assert!(users.get_user(user.id).await?.is_none());
+        assert!(doc_service.get_document(diary.id).await?.is_none());
+        assert_eq!(doc_service.get_comment(shared.id, comment.id).await?.unwrap().author, None);
+        assert!(users.get_user(other.id).await?.is_some());
+        Ok(())
+    }
+}
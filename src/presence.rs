@@ -0,0 +1,227 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+//! Who else is in a document room and where their cursor is.
+//!
+//! Presence is ephemeral: each room keeps it in memory only, keyed by connection. A
+//! connection appears once it first sends its presence and disappears when it leaves or
+//! stops sending heartbeats for `PRESENCE_TIMEOUT`; to reappear after timing out it sends
+//! its presence again. Offsets are bytes into the latest content, as with protected ranges.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a connection stays present without a presence update or heartbeat.
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A selected span; `head` is where the cursor is and may come before `anchor`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Selection {
+    pub anchor: usize,
+    pub head: usize,
+}
+
+/// What a connection shares with the other participants in a room.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Presence {
+    pub display_name: String,
+    /// Cursor colour as `#rrggbb`; clients pick one when this is missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(default)]
+    pub cursor: Option<usize>,
+    #[serde(default)]
+    pub selection: Option<Selection>,
+    /// Region of the server the participant is connected to, so clients can show how far
+    /// away collaborators are; see `/api/instance`. Set by the server, not the client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+}
+
+/// What a connection tells its room about its presence.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PresenceUpdate {
+    Set { presence: Presence },
+    /// Keeps the connection present without changing anything.
+    Heartbeat,
+    Leave,
+}
+
+/// A change to a room's participants, broadcast to everyone else in the room.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum PresenceChange {
+    Joined {
+        #[serde(with = "connection_id")]
+        connection_id: u64,
+        presence: Presence,
+    },
+    Updated {
+        #[serde(with = "connection_id")]
+        connection_id: u64,
+        presence: Presence,
+    },
+    Left {
+        #[serde(with = "connection_id")]
+        connection_id: u64,
+    },
+}
+
+impl PresenceChange {
+    pub fn connection_id(&self) -> u64 {
+        match self {
+            PresenceChange::Joined { connection_id, .. }
+            | PresenceChange::Updated { connection_id, .. }
+            | PresenceChange::Left { connection_id } => *connection_id,
+        }
+    }
+}
+
+/// A connection currently present in a room.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Participant {
+    #[serde(with = "connection_id")]
+    pub connection_id: u64,
+    pub presence: Presence,
+}
+
+// Connection IDs use all 64 bits, more than a JavaScript number holds exactly, so clients
+// see them as strings.
+mod connection_id {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(id)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A room's participants and when each was last heard from.
+pub(crate) struct PresenceMap {
+    timeout: Duration,
+    entries: HashMap<u64, (Presence, Instant)>,
+}
+
+impl PresenceMap {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        PresenceMap { timeout, entries: HashMap::new() }
+    }
+
+    /// Applies a connection's update; returns the change to broadcast, if any.
+    pub(crate) fn apply(&mut self, connection_id: u64, update: PresenceUpdate, now: Instant) -> Option<PresenceChange> {
+        match update {
+            PresenceUpdate::Set { presence } => match self.entries.insert(connection_id, (presence.clone(), now)) {
+                None => Some(PresenceChange::Joined { connection_id, presence }),
+                Some((previous, _)) if previous != presence => Some(PresenceChange::Updated { connection_id, presence }),
+                Some(_) => None,
+            },
+            PresenceUpdate::Heartbeat => {
+                if let Some((_, last_seen)) = self.entries.get_mut(&connection_id) {
+                    *last_seen = now;
+                }
+                None
+            }
+            PresenceUpdate::Leave => {
+                self.entries.remove(&connection_id).map(|_| PresenceChange::Left { connection_id })
+            }
+        }
+    }
+
+    /// Removes connections not heard from within the timeout, returning their departures.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<PresenceChange> {
+        let timeout = self.timeout;
+        let mut expired: Vec<u64> = self
+            .entries
+            .iter()
+            .filter(|(_, (_, last_seen))| now.saturating_duration_since(*last_seen) > timeout)
+            .map(|(connection_id, _)| *connection_id)
+            .collect();
+        expired.sort_unstable();
+        expired
+            .into_iter()
+            .map(|connection_id| {
+                self.entries.remove(&connection_id);
+                PresenceChange::Left { connection_id }
+            })
+            .collect()
+    }
+
+    /// Everyone present, in the order they were assigned connection IDs.
+    pub(crate) fn participants(&self) -> Vec<Participant> {
+        let mut participants: Vec<Participant> = self
+            .entries
+            .iter()
+            .map(|(connection_id, (presence, _))| Participant { connection_id: *connection_id, presence: presence.clone() })
+            .collect();
+        participants.sort_by_key(|participant| participant.connection_id);
+        participants
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presence(name: &str, cursor: usize) -> Presence {
+        Presence { display_name: name.to_string(), color: None, cursor: Some(cursor), selection: None, region: None }
+    }
+
+    #[test]
+    fn test_updates_report_joins_changes_and_leaves() {
+        let mut map = PresenceMap::new(PRESENCE_TIMEOUT);
+        let now = Instant::now();
+
+        let joined = map.apply(1, PresenceUpdate::Set { presence: presence("Ada", 0) }, now);
+        assert_eq!(joined, Some(PresenceChange::Joined { connection_id: 1, presence: presence("Ada", 0) }));
+        // Resending the same presence refreshes it without telling anyone.
+        assert_eq!(map.apply(1, PresenceUpdate::Set { presence: presence("Ada", 0) }, now), None);
+        let updated = map.apply(1, PresenceUpdate::Set { presence: presence("Ada", 4) }, now);
+        assert_eq!(updated, Some(PresenceChange::Updated { connection_id: 1, presence: presence("Ada", 4) }));
+        // Heartbeats from connections that never sent their presence are ignored.
+        assert_eq!(map.apply(2, PresenceUpdate::Heartbeat, now), None);
+        assert_eq!(map.participants(), vec![Participant { connection_id: 1, presence: presence("Ada", 4) }]);
+
+        assert_eq!(map.apply(1, PresenceUpdate::Leave, now), Some(PresenceChange::Left { connection_id: 1 }));
+        assert_eq!(map.apply(1, PresenceUpdate::Leave, now), None);
+        assert!(map.participants().is_empty());
+    }
+
+    #[test]
+    fn test_silent_connections_expire() {
+        let mut map = PresenceMap::new(Duration::from_secs(30));
+        let start = Instant::now();
+        map.apply(1, PresenceUpdate::Set { presence: presence("Ada", 0) }, start);
+        map.apply(2, PresenceUpdate::Set { presence: presence("Grace", 0) }, start);
+
+        map.apply(2, PresenceUpdate::Heartbeat, start + Duration::from_secs(20));
+        assert!(map.expire(start + Duration::from_secs(30)).is_empty());
+        assert_eq!(map.expire(start + Duration::from_secs(40)), vec![PresenceChange::Left { connection_id: 1 }]);
+        assert_eq!(map.participants().len(), 1);
+        assert_eq!(map.expire(start + Duration::from_secs(51)), vec![PresenceChange::Left { connection_id: 2 }]);
+    }
+
+    #[test]
+    fn test_connection_ids_serialize_as_strings() {
+        let change = PresenceChange::Left { connection_id: u64::MAX };
+        let json = serde_json::to_value(&change).unwrap();
+        assert_eq!(json, serde_json::json!({ "change": "left", "connection_id": "18446744073709551615" }));
+        assert_eq!(serde_json::from_value::<PresenceChange>(json).unwrap(), change);
+    }
+}
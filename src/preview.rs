@@ -0,0 +1,87 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Small HTML previews of document content for listings: the first few lines as text,
+//! rendered when content is saved so listing a document never means loading all of it.
+//! Previews carry the document's language as `lang`, which browsers need to hyphenate
+//! them (`hyphens: auto`) and to pick fonts and quotation marks.
+
+/// Lines of content a preview shows.
+const PREVIEW_LINES: usize = 5;
+/// Longer lines are cut short with an ellipsis.
+const PREVIEW_LINE_CHARS: usize = 120;
+
+/// Renders the opening lines of `content`, in `language` if known, as an HTML fragment.
+/// Returns `None` when the content is not text; content is treated as UTF-8 text until it
+/// is decoded as a CRDT.
+pub fn render(content: &[u8], language: Option<&str>) -> Option<String> {
+    let text = std::str::from_utf8(content).ok()?;
+    let mut html = String::from("<div class=\"document-preview\"");
+    if let Some(language) = language {
+        html.push_str(" lang=\"");
+        escape_into(&mut html, language.chars());
+        html.push('"');
+    }
+    html.push('>');
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()).take(PREVIEW_LINES) {
+        html.push_str("<p>");
+        let mut chars = line.chars();
+        escape_into(&mut html, chars.by_ref().take(PREVIEW_LINE_CHARS));
+        if chars.next().is_some() {
+            html.push('…');
+        }
+        html.push_str("</p>");
+    }
+    html.push_str("</div>");
+    Some(html)
+}
+
+/// Escapes text for inclusion in HTML.
+pub fn escape_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    escape_into(&mut html, text.chars());
+    html
+}
+
+fn escape_into(html: &mut String, text: impl Iterator<Item = char>) {
+    for c in text {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_preview() {
+        let content = "Title <script>\n\n  second & third  \n3\n4\n5\n6";
+        assert_eq!(
+            render(content.as_bytes(), None).unwrap(),
+            "<div class=\"document-preview\"><p>Title &lt;script&gt;</p><p>second &amp; third</p><p>3</p><p>4</p><p>5</p></div>"
+        );
+        let long = "x".repeat(PREVIEW_LINE_CHARS + 1);
+        assert_eq!(render(long.as_bytes(), None).unwrap(), format!("<div class=\"document-preview\"><p>{}…</p></div>", &long[1..]));
+        assert_eq!(render(b"", None).unwrap(), "<div class=\"document-preview\"></div>");
+        assert_eq!(render(&[0xff, 0xfe], None), None);
+        assert_eq!(render(b"Hallo", Some("de")).unwrap(), "<div class=\"document-preview\" lang=\"de\"><p>Hallo</p></div>");
+    }
+}
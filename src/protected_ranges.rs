@@ -0,0 +1,174 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Ranges of a document that only some roles may edit.
+//!
+//! Updates carry the whole document, so the server finds what an update changed by
+//! comparing it with the previous content: everything between the longest common
+//! prefix and suffix. Where that is ambiguous (an edit next to a protected range made
+//! of the same bytes) the change is attributed to the range, erring towards refusal.
+
+use crate::delta;
+use crate::user_service::DocumentRole;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+/// Bytes `start..end` of a document, editable only by `editors`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProtectedRange {
+    pub id: Uuid,
+    pub start: usize,
+    pub end: usize,
+    pub editors: Vec<DocumentRole>,
+}
+
+impl ProtectedRange {
+    pub fn new(start: usize, end: usize, editors: Vec<DocumentRole>) -> Self {
+        ProtectedRange { id: Uuid::new_v4(), start, end, editors }
+    }
+
+    pub fn allows(&self, role: DocumentRole) -> bool {
+        self.editors.contains(&role)
+    }
+}
+
+/// A request to lock or unlock part of a document.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ProtectionChange {
+    Protect { start: usize, end: usize, editors: Vec<DocumentRole> },
+    Unprotect { id: Uuid },
+}
+
+/// An update touched a range the submitting role may not edit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProtectedRangeViolation {
+    pub range_id: Uuid,
+}
+
+impl fmt::Display for ProtectedRangeViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Update modifies protected range {}", self.range_id)
+    }
+}
+
+impl std::error::Error for ProtectedRangeViolation {}
+
+/// The part of a document an update replaced: `old[start..old_end]` became `new[start..new_end]`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Change {
+    start: usize,
+    old_end: usize,
+    new_end: usize,
+}
+
+impl Change {
+    fn between(old: &[u8], new: &[u8]) -> Option<Change> {
+        let (start, old_end, new_end) = delta::changed_span(old, new)?;
+        Some(Change { start, old_end, new_end })
+    }
+
+    // An insertion exactly at either boundary leaves the range alone.
+    fn touches(&self, range: &ProtectedRange) -> bool {
+        self.start < range.end && self.old_end > range.start
+    }
+
+    fn shift(&self, range: &ProtectedRange) -> ProtectedRange {
+        let delta = |position: usize| position + self.new_end - self.old_end;
+        let start = if range.start >= self.old_end {
+            delta(range.start)
+        } else if range.start <= self.start {
+            range.start
+        } else {
+            self.start
+        };
+        let end = if range.end <= self.start {
+            range.end
+        } else if range.end >= self.old_end {
+            delta(range.end)
+        } else {
+            self.new_end
+        };
+        ProtectedRange { start, end, ..range.clone() }
+    }
+}
+
+/// Checks an update from `role` against the document's protected ranges. Returns the
+/// ranges moved to match the new content, or `None` if they are unchanged.
+pub fn apply_update(
+    ranges: &[ProtectedRange],
+    role: DocumentRole,
+    old: &[u8],
+    new: &[u8],
+) -> Result<Option<Vec<ProtectedRange>>, ProtectedRangeViolation> {
+    let Some(change) = Change::between(old, new) else {
+        return Ok(None);
+    };
+    if let Some(range) = ranges.iter().find(|range| change.touches(range) && !range.allows(role)) {
+        return Err(ProtectedRangeViolation { range_id: range.id });
+    }
+    let shifted: Vec<ProtectedRange> = ranges
+        .iter()
+        .map(|range| change.shift(range))
+        // A range whose content was deleted entirely protects nothing.
+        .filter(|range| range.start < range.end)
+        .collect();
+    Ok((shifted != ranges).then_some(shifted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners_only(start: usize, end: usize) -> ProtectedRange {
+        ProtectedRange::new(start, end, vec![DocumentRole::Owner])
+    }
+
+    #[test]
+    fn test_edits_inside_protected_ranges_need_the_role() {
+        let header = owners_only(0, 6);
+        let ranges = vec![header.clone()];
+        let old = b"Title\nbody";
+
+        // Editing the body is fine and leaves the header where it is.
+        assert_eq!(apply_update(&ranges, DocumentRole::Editor, old, b"Title\nbody text"), Ok(None));
+        assert_eq!(
+            apply_update(&ranges, DocumentRole::Editor, old, b"Tytle\nbody"),
+            Err(ProtectedRangeViolation { range_id: header.id })
+        );
+        assert_eq!(
+            apply_update(&ranges, DocumentRole::Editor, old, b"body"),
+            Err(ProtectedRangeViolation { range_id: header.id })
+        );
+        // Owners may edit it, and the range grows with the text.
+        let shifted = apply_update(&ranges, DocumentRole::Owner, old, b"Title 2\nbody").unwrap().unwrap();
+        assert_eq!((shifted[0].start, shifted[0].end), (0, 8));
+    }
+
+    #[test]
+    fn test_ranges_follow_edits_around_them() {
+        let footer = owners_only(5, 8);
+        let ranges = vec![footer.clone()];
+
+        // Inserting right before the range pushes it along rather than touching it.
+        let shifted = apply_update(&ranges, DocumentRole::Editor, b"body FTR", b"body text FTR").unwrap().unwrap();
+        assert_eq!((shifted[0].start, shifted[0].end), (10, 13));
+        // Appending right after it is allowed too.
+        assert_eq!(apply_update(&ranges, DocumentRole::Editor, b"body FTR", b"body FTR!"), Ok(None));
+
+        // Deleting all of a range's content removes it.
+        assert_eq!(apply_update(&ranges, DocumentRole::Owner, b"body FTR", b"body "), Ok(Some(vec![])));
+    }
+}
@@ -0,0 +1,191 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The framed binary protocol for document WebSockets, offered as the `collaborate.v1`
+//! subprotocol. Each binary frame is a version byte, a message type byte and the message's
+//! payload:
+//!
+//! | type | message     | payload                                                      |
+//! |------|-------------|--------------------------------------------------------------|
+//! | 0    | sync-step-1 | the checksum of the content the client holds, or nothing     |
+//! | 1    | sync-step-2 | the document's content, or nothing if the client is current  |
+//! | 2    | update      | the sender's full content after an edit                      |
+//! | 3    | awareness   | a presence or heartbeat frame as JSON, as in text frames     |
+//! | 4    | ping        | nothing                                                      |
+//! | 5    | pong        | nothing                                                      |
+//! | 6    | sync-delta  | the edit turning the client's content into the document's    |
+//!
+//! A client reconnecting with content the server can find in the document's update log is
+//! answered with sync-delta instead of sync-step-2. Its payload is the offset and length of
+//! the replaced bytes, each a big-endian `u64`, followed by the bytes that replace them.
+//!
+//! Clients that don't ask for the subprotocol keep the original framing, where binary
+//! frames are bare content and presence travels in text frames.
+
+use crate::delta::ContentDelta;
+use std::fmt;
+
+/// The WebSocket subprotocol clients request to use this framing.
+pub const SUBPROTOCOL: &str = "collaborate.v1";
+/// The only version this server speaks.
+pub const VERSION: u8 = 1;
+
+const SYNC_STEP_1: u8 = 0;
+const SYNC_STEP_2: u8 = 1;
+const UPDATE: u8 = 2;
+const AWARENESS: u8 = 3;
+const PING: u8 = 4;
+const PONG: u8 = 5;
+const SYNC_DELTA: u8 = 6;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncMessage {
+    /// Asks for the document's content unless the client's matches `checksum`.
+    SyncStep1 { checksum: Option<String> },
+    /// Answers sync-step-1; `None` when the client's content is already current.
+    SyncStep2(Option<Vec<u8>>),
+    Update(Vec<u8>),
+    Awareness(String),
+    Ping,
+    Pong,
+    /// Answers sync-step-1 when the client's content is an earlier version of the document's.
+    SyncDelta(ContentDelta),
+}
+
+/// Why a binary frame couldn't be decoded.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MalformedFrame {
+    /// The frame is too short to hold a version and message type.
+    Truncated,
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    /// The payload doesn't suit the message type.
+    InvalidPayload(&'static str),
+}
+
+impl fmt::Display for MalformedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MalformedFrame::Truncated => write!(f, "frame is missing its header"),
+            MalformedFrame::UnsupportedVersion(version) => write!(f, "unsupported protocol version {}", version),
+            MalformedFrame::UnknownType(kind) => write!(f, "unknown message type {}", kind),
+            MalformedFrame::InvalidPayload(reason) => write!(f, "invalid payload: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MalformedFrame {}
+
+/// Encodes `message` as a binary frame.
+pub fn encode(message: &SyncMessage) -> Vec<u8> {
+    let (kind, payload): (u8, &[u8]) = match message {
+        SyncMessage::SyncStep1 { checksum } => (SYNC_STEP_1, checksum.as_deref().unwrap_or_default().as_bytes()),
+        SyncMessage::SyncStep2(content) => (SYNC_STEP_2, content.as_deref().unwrap_or_default()),
+        SyncMessage::Update(content) => (UPDATE, content),
+        SyncMessage::Awareness(json) => (AWARENESS, json.as_bytes()),
+        SyncMessage::Ping => (PING, &[]),
+        SyncMessage::Pong => (PONG, &[]),
+        SyncMessage::SyncDelta(delta) => {
+            let mut frame = Vec::with_capacity(delta.insert.len() + 18);
+            frame.extend_from_slice(&[VERSION, SYNC_DELTA]);
+            frame.extend_from_slice(&(delta.start as u64).to_be_bytes());
+            frame.extend_from_slice(&(delta.delete_len as u64).to_be_bytes());
+            frame.extend_from_slice(&delta.insert);
+            return frame;
+        }
+    };
+    let mut frame = Vec::with_capacity(payload.len() + 2);
+    frame.push(VERSION);
+    frame.push(kind);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Decodes a binary frame, rejecting anything `encode` wouldn't produce.
+pub fn decode(frame: &[u8]) -> Result<SyncMessage, MalformedFrame> {
+    let [version, kind, payload @ ..] = frame else {
+        return Err(MalformedFrame::Truncated);
+    };
+    if *version != VERSION {
+        return Err(MalformedFrame::UnsupportedVersion(*version));
+    }
+    match *kind {
+        SYNC_STEP_1 if payload.is_empty() => Ok(SyncMessage::SyncStep1 { checksum: None }),
+        SYNC_STEP_1 => match std::str::from_utf8(payload) {
+            Ok(checksum) if checksum.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                Ok(SyncMessage::SyncStep1 { checksum: Some(checksum.to_string()) })
+            }
+            _ => Err(MalformedFrame::InvalidPayload("checksum is not hexadecimal")),
+        },
+        SYNC_STEP_2 if payload.is_empty() => Ok(SyncMessage::SyncStep2(None)),
+        SYNC_STEP_2 => Ok(SyncMessage::SyncStep2(Some(payload.to_vec()))),
+        UPDATE => Ok(SyncMessage::Update(payload.to_vec())),
+        AWARENESS => match std::str::from_utf8(payload) {
+            Ok(json) => Ok(SyncMessage::Awareness(json.to_string())),
+            Err(_) => Err(MalformedFrame::InvalidPayload("awareness is not UTF-8")),
+        },
+        PING | PONG if !payload.is_empty() => Err(MalformedFrame::InvalidPayload("unexpected payload")),
+        PING => Ok(SyncMessage::Ping),
+        PONG => Ok(SyncMessage::Pong),
+        SYNC_DELTA => {
+            let Some((span, insert)) = payload.split_first_chunk::<16>() else {
+                return Err(MalformedFrame::InvalidPayload("delta is missing its span"));
+            };
+            let (start, delete_len) = span.split_at(8);
+            let start = u64::from_be_bytes(start.try_into().expect("8 bytes"));
+            let delete_len = u64::from_be_bytes(delete_len.try_into().expect("8 bytes"));
+            Ok(SyncMessage::SyncDelta(ContentDelta { start: start as usize, delete_len: delete_len as usize, insert: insert.to_vec() }))
+        }
+        other => Err(MalformedFrame::UnknownType(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_round_trip() {
+        let messages = [
+            SyncMessage::SyncStep1 { checksum: None },
+            SyncMessage::SyncStep1 { checksum: Some(crate::integrity::checksum(b"hello")) },
+            SyncMessage::SyncStep2(None),
+            SyncMessage::SyncStep2(Some(b"hello".to_vec())),
+            SyncMessage::Update(b"hello, world".to_vec()),
+            SyncMessage::Update(Vec::new()),
+            SyncMessage::Awareness(r#"{"type":"heartbeat"}"#.to_string()),
+            SyncMessage::Ping,
+            SyncMessage::Pong,
+            SyncMessage::SyncDelta(ContentDelta::between(b"hello world", b"hello brave world")),
+            SyncMessage::SyncDelta(ContentDelta::between(b"hello", b"hello")),
+        ];
+        for message in messages {
+            let frame = encode(&message);
+            assert_eq!(frame[0], VERSION);
+            assert_eq!(decode(&frame), Ok(message));
+        }
+    }
+
+    #[test]
+    fn test_malformed_frames_are_rejected() {
+        assert_eq!(decode(&[]), Err(MalformedFrame::Truncated));
+        assert_eq!(decode(&[VERSION]), Err(MalformedFrame::Truncated));
+        assert_eq!(decode(&[2, UPDATE, b'x']), Err(MalformedFrame::UnsupportedVersion(2)));
+        assert_eq!(decode(&[VERSION, 42]), Err(MalformedFrame::UnknownType(42)));
+        assert!(matches!(decode(&[VERSION, PING, 0]), Err(MalformedFrame::InvalidPayload(_))));
+        assert!(matches!(decode(&[VERSION, AWARENESS, 0xff, 0xfe]), Err(MalformedFrame::InvalidPayload(_))));
+        assert!(matches!(decode(&[VERSION, SYNC_STEP_1, b'z']), Err(MalformedFrame::InvalidPayload(_))));
+        assert!(matches!(decode(&[VERSION, SYNC_DELTA, 0, 0, 0]), Err(MalformedFrame::InvalidPayload(_))));
+    }
+}
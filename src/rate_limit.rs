@@ -0,0 +1,374 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Per-client request limits. Counts are kept in memory by default, so each node enforces
+//! its own; with `COLLABORATE_RATE_LIMIT_BACKEND=database` they are kept in CockroachDB
+//! and shared by every node behind the load balancer, at the cost of a write per request.
+
+use crate::api_error::ApiError;
+use crate::db::Manager;
+use crate::migrations::{self, Component, Migration};
+use anyhow::{anyhow, Context, Result};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+const RATE_LIMIT_ENV: &str = "COLLABORATE_RATE_LIMIT_PER_MINUTE";
+const RATE_LIMIT_BACKEND_ENV: &str = "COLLABORATE_RATE_LIMIT_BACKEND";
+/// Expired windows are swept once this many clients are being tracked.
+const SWEEP_THRESHOLD: usize = 10_000;
+/// How often each node deletes expired windows from the database.
+const SHARED_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const SHARED_SWEEP_BATCH_SIZE: i64 = 1000;
+
+/// How many requests a client may make per window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub window: Duration,
+}
+
+impl RateLimit {
+    pub fn per_minute(requests: u32) -> Self {
+        RateLimit { requests, window: Duration::from_secs(60) }
+    }
+
+    pub fn from_env() -> Result<Self> {
+        match env::var(RATE_LIMIT_ENV) {
+            Ok(requests) => Ok(RateLimit::per_minute(
+                requests.parse().context(format!("{} must be a number of requests", RATE_LIMIT_ENV))?,
+            )),
+            Err(_) => Ok(RateLimit::default()),
+        }
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit::per_minute(120)
+    }
+}
+
+/// Where request counts are kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RateLimitBackend {
+    /// In this node's memory; limits apply per node.
+    #[default]
+    Local,
+    /// In the database, shared by every node.
+    Database,
+}
+
+impl RateLimitBackend {
+    pub fn from_env() -> Result<Self> {
+        match env::var(RATE_LIMIT_BACKEND_ENV) {
+            Ok(backend) => backend.parse().context(format!("{} must be 'local' or 'database'", RATE_LIMIT_BACKEND_ENV)),
+            Err(_) => Ok(RateLimitBackend::default()),
+        }
+    }
+}
+
+impl FromStr for RateLimitBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "local" => Ok(RateLimitBackend::Local),
+            "database" => Ok(RateLimitBackend::Database),
+            other => Err(anyhow!("Unknown rate limit backend '{}'", other)),
+        }
+    }
+}
+
+/// Who a request is counted against. Authentication layers insert this as a request
+/// extension, carrying any quota override stored with the credential; anonymous
+/// requests are counted per client address with the default limit.
+#[derive(Clone, Debug)]
+pub struct RateLimitSubject {
+    pub key: String,
+    pub limit: Option<RateLimit>,
+}
+
+/// The outcome of counting one request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Unix time, in seconds, at which the current window ends.
+    pub reset_at: u64,
+}
+
+impl RateLimitDecision {
+    fn apply_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("x-ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("x-ratelimit-reset", HeaderValue::from(self.reset_at));
+    }
+}
+
+struct Window {
+    started: Instant,
+    length: Duration,
+    count: u32,
+    reset_at: u64,
+}
+
+impl Window {
+    fn start(now: Instant, length: Duration) -> Self {
+        let reset_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default() + length;
+        Window { started: now, length, count: 0, reset_at: reset_at.as_secs_f64().ceil() as u64 }
+    }
+}
+
+/// Fixed-window counts kept in the database. Windows are aligned to the clock rather than
+/// to a client's first request, so every node agrees on when one starts.
+struct SharedCounts {
+    db_manager: Arc<Manager>,
+    last_sweep: Mutex<Instant>,
+    /// Set while the database is unreachable, so the outage is logged once.
+    failing: AtomicBool,
+}
+
+impl SharedCounts {
+    async fn count(&self, key: &str, limit: RateLimit) -> Result<RateLimitDecision> {
+        let length = limit.window.as_secs().max(1);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let window_start = now - now % length;
+        let reset_at = window_start + length;
+        let count: i64 = sqlx::query_scalar(
+                "INSERT INTO rate_limit_windows (key, window_start, count, reset_at) VALUES ($1, $2, 1, $3)
+                 ON CONFLICT (key, window_start) DO UPDATE SET count = rate_limit_windows.count + 1
+                 RETURNING count"
+            )
+            .bind(key)
+            .bind(window_start as i64)
+            .bind(reset_at as i64)
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to count request from '{}'", key))?;
+        let sweep_due = {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+            let due = last_sweep.elapsed() >= SHARED_SWEEP_INTERVAL;
+            if due {
+                *last_sweep = Instant::now();
+            }
+            due
+        };
+        if sweep_due {
+            sqlx::query(
+                    "DELETE FROM rate_limit_windows WHERE (key, window_start) IN
+                     (SELECT key, window_start FROM rate_limit_windows WHERE reset_at <= $1 LIMIT $2)"
+                )
+                .bind(now as i64)
+                .bind(SHARED_SWEEP_BATCH_SIZE)
+                .execute(&*self.db_manager.pool)
+                .await
+                .context("Failed to sweep expired rate limit windows")?;
+        }
+        let count = u32::try_from(count).unwrap_or(u32::MAX);
+        Ok(RateLimitDecision {
+            allowed: count <= limit.requests,
+            limit: limit.requests,
+            remaining: limit.requests.saturating_sub(count),
+            reset_at,
+        })
+    }
+}
+
+/// Fixed-window request counter, local to this node unless created with `shared`.
+pub struct RateLimiter {
+    default: RateLimit,
+    windows: Mutex<HashMap<String, Window>>,
+    shared: Option<SharedCounts>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "rate_limit",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS rate_limit_windows (
+                key TEXT NOT NULL,
+                window_start BIGINT NOT NULL,
+                count BIGINT NOT NULL,
+                reset_at BIGINT NOT NULL,
+                PRIMARY KEY (key, window_start)
+            )",
+            "CREATE INDEX IF NOT EXISTS rate_limit_windows_by_reset ON rate_limit_windows (reset_at)",
+        ],
+        down: &[
+            "DROP TABLE IF EXISTS rate_limit_windows",
+        ],
+    }],
+};
+
+impl RateLimiter {
+    pub fn new(default: RateLimit) -> Self {
+        RateLimiter { default, windows: Mutex::new(HashMap::new()), shared: None }
+    }
+
+    /// A limiter whose counts are kept in the database and shared with every other node
+    /// created this way. While the database is unreachable, requests are counted locally.
+    pub async fn shared(default: RateLimit, db_manager: Arc<Manager>) -> Result<Self> {
+        migrations::apply(&db_manager.pool, &SCHEMA).await?;
+        info!("Rate limit schema initialized.");
+        let shared = SharedCounts { db_manager, last_sweep: Mutex::new(Instant::now()), failing: AtomicBool::new(false) };
+        Ok(RateLimiter { shared: Some(shared), ..RateLimiter::new(default) })
+    }
+
+    /// Counts a request from `key` against `limit` (or the default) and reports what is left.
+    pub async fn check(&self, key: &str, limit: Option<RateLimit>) -> RateLimitDecision {
+        let limit = limit.unwrap_or(self.default);
+        if let Some(shared) = &self.shared {
+            match shared.count(key, limit).await {
+                Ok(decision) => {
+                    if shared.failing.swap(false, Ordering::Relaxed) {
+                        info!("Shared rate limit counting recovered");
+                    }
+                    return decision;
+                }
+                Err(e) => {
+                    if !shared.failing.swap(true, Ordering::Relaxed) {
+                        warn!("Shared rate limit counting failed; counting on this node until it recovers: {:#}", e);
+                    }
+                }
+            }
+        }
+        self.check_at(key, limit, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, limit: RateLimit, now: Instant) -> RateLimitDecision {
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= SWEEP_THRESHOLD {
+            windows.retain(|_, window| now.duration_since(window.started) < window.length);
+        }
+        let window = windows
+            .entry(key.to_string())
+            .or_insert_with(|| Window::start(now, limit.window));
+        // A changed override takes effect with the next window.
+        if now.duration_since(window.started) >= window.length {
+            *window = Window::start(now, limit.window);
+        }
+        let allowed = window.count < limit.requests;
+        if allowed {
+            window.count += 1;
+        }
+        RateLimitDecision {
+            allowed,
+            limit: limit.requests,
+            remaining: limit.requests.saturating_sub(window.count),
+            reset_at: window.reset_at,
+        }
+    }
+}
+
+/// Middleware for rate-limited routes: rejects clients over their quota and reports
+/// the quota on every response via `X-RateLimit-Limit/Remaining/Reset`.
+pub async fn enforce(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let decision = match request.extensions().get::<RateLimitSubject>() {
+        Some(subject) => limiter.check(&subject.key, subject.limit).await,
+        None => limiter.check(&addr.ip().to_string(), None).await,
+    };
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let retry_after = decision.reset_at.saturating_sub(unix_now);
+        let mut response = ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate-limited").into_response();
+        response.headers_mut().insert("retry-after", HeaderValue::from(retry_after));
+        response
+    };
+    decision.apply_headers(response.headers_mut());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_window_counting() {
+        let limiter = RateLimiter::new(RateLimit::per_minute(2));
+        let start = Instant::now();
+        let limit = RateLimit::per_minute(2);
+
+        let first = limiter.check_at("client", limit, start);
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert!(limiter.check_at("client", limit, start).allowed);
+        let rejected = limiter.check_at("client", limit, start + Duration::from_secs(30));
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.remaining, 0);
+        assert_eq!(rejected.reset_at, first.reset_at);
+
+        // Other clients have their own windows, and the window resets after it elapses.
+        assert!(limiter.check_at("other", limit, start).allowed);
+        assert!(limiter.check_at("client", limit, start + Duration::from_secs(60)).allowed);
+    }
+
+    #[tokio::test]
+    async fn test_subject_override_replaces_default() {
+        let limiter = RateLimiter::new(RateLimit::per_minute(1));
+        assert!(limiter.check("anonymous", None).await.allowed);
+        assert!(!limiter.check("anonymous", None).await.allowed);
+
+        let partner = Some(RateLimit::per_minute(1000));
+        for _ in 0..10 {
+            assert!(limiter.check("key:partner", partner).await.allowed);
+        }
+        assert_eq!(limiter.check("key:partner", partner).await.limit, 1000);
+    }
+
+    #[tokio::test]
+    async fn test_shared_counts_hold_across_nodes() -> Result<()> {
+        let manager = Arc::new(Manager::new("root@localhost:26257", "collaborate_core_doc_service_test").await?);
+        let first = RateLimiter::shared(RateLimit::per_minute(3), manager.clone()).await?;
+        let second = RateLimiter::shared(RateLimit::per_minute(3), manager).await?;
+        let key = format!("test:{}", uuid::Uuid::new_v4());
+
+        // A window can end mid-test; the limit then starts over, so try again once.
+        for _ in 0..2 {
+            let decisions = [
+                first.check(&key, None).await,
+                second.check(&key, None).await,
+                first.check(&key, None).await,
+                second.check(&key, None).await,
+            ];
+            if decisions.iter().any(|decision| decision.reset_at != decisions[0].reset_at) {
+                continue;
+            }
+            let allowed: Vec<bool> = decisions.iter().map(|decision| decision.allowed).collect();
+            assert_eq!(allowed, [true, true, true, false]);
+            assert_eq!(decisions[2].remaining, 0);
+            return Ok(());
+        }
+        panic!("two consecutive windows ended mid-test");
+    }
+}
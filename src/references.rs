@@ -0,0 +1,178 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Links between documents. Each snapshot is scanned for links to other documents, in
+//! any form that has `documents/<id>` in it, and the targets are kept in the same
+//! transaction as the snapshot. Updates logged since the last snapshot are taken in
+//! when they are compacted. Targets are not foreign keys, so a link to a document that
+//! is in the trash, or has been purged, stays behind as a broken link.
+
+use crate::document_service::DocumentMetadata;
+use crate::migrations::{Component, Migration};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+use std::collections::BTreeSet;
+use uuid::Uuid;
+
+/// Links kept per document; the rest of a document that links to more is left out.
+const MAX_LINKS_PER_DOCUMENT: usize = 1000;
+const LINK_PREFIX: &str = "documents/";
+const UUID_CHARS: usize = 36;
+
+/// The distinct documents `content` links to.
+pub fn links(content: &[u8]) -> BTreeSet<Uuid> {
+    let text = String::from_utf8_lossy(content);
+    let mut found = BTreeSet::new();
+    for (at, _) in text.match_indices(LINK_PREFIX) {
+        let rest = &text[at + LINK_PREFIX.len()..];
+        let Some(id) = rest.get(..UUID_CHARS) else { continue };
+        // A longer run of ID characters is something else that happens to start like an ID.
+        if rest[UUID_CHARS..].starts_with(|c: char| c.is_ascii_hexdigit() || c == '-') {
+            continue;
+        }
+        if let Ok(id) = Uuid::try_parse(id) {
+            found.insert(id);
+            if found.len() == MAX_LINKS_PER_DOCUMENT {
+                break;
+            }
+        }
+    }
+    found
+}
+
+/// A link from one document to another.
+#[derive(Clone, Debug, FromRow, PartialEq, Serialize)]
+pub struct DocumentLink {
+    pub target_id: Uuid,
+    /// The target is in the trash or no longer exists.
+    pub broken: bool,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "references",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            // Filled in as each document's next snapshot is saved.
+            "CREATE TABLE IF NOT EXISTS document_references (
+                source_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                target_id UUID NOT NULL,
+                PRIMARY KEY (source_id, target_id)
+            )",
+            "CREATE INDEX IF NOT EXISTS document_references_by_target ON document_references (target_id)",
+        ],
+        down: &["DROP TABLE IF EXISTS document_references"],
+    }],
+};
+
+/// Replaces what `doc_id` links to with the links in `content`, its new snapshot.
+pub(crate) async fn record(tx: &mut Transaction<'_, Postgres>, doc_id: Uuid, content: &[u8]) -> Result<()> {
+    let targets: Vec<Uuid> = links(content).into_iter().filter(|target| *target != doc_id).collect();
+    sqlx::query("DELETE FROM document_references WHERE source_id = $1")
+        .bind(doc_id)
+        .execute(&mut **tx)
+        .await
+        .context(format!("Failed to clear links of document ID {}", doc_id))?;
+    sqlx::query("INSERT INTO document_references (source_id, target_id) SELECT $1, UNNEST($2::UUID[])")
+        .bind(doc_id)
+        .bind(&targets)
+        .execute(&mut **tx)
+        .await
+        .context(format!("Failed to record links of document ID {}", doc_id))?;
+    Ok(())
+}
+
+/// Documents outside the trash that link to `doc_id`, by name.
+pub(crate) async fn backlinks(pool: &PgPool, doc_id: Uuid) -> Result<Vec<DocumentMetadata>> {
+    sqlx::query_as(
+            "SELECT m.id, m.name, m.created_at, m.updated_at, m.language
+             FROM document_references r JOIN documents_metadata m ON m.id = r.source_id
+             WHERE r.target_id = $1 AND m.deleted_at IS NULL
+             ORDER BY m.name, m.id"
+        )
+        .bind(doc_id)
+        .fetch_all(pool)
+        .await
+        .context(format!("Failed to query backlinks of document ID {}", doc_id))
+}
+
+/// What `doc_id` links to as of its last snapshot.
+pub(crate) async fn outgoing(pool: &PgPool, doc_id: Uuid) -> Result<Vec<DocumentLink>> {
+    sqlx::query_as(
+            "SELECT r.target_id, (m.id IS NULL OR m.deleted_at IS NOT NULL) AS broken
+             FROM document_references r LEFT JOIN documents_metadata m ON m.id = r.target_id
+             WHERE r.source_id = $1
+             ORDER BY r.target_id"
+        )
+        .bind(doc_id)
+        .fetch_all(pool)
+        .await
+        .context(format!("Failed to query links of document ID {}", doc_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Manager;
+    use crate::document_service::DocumentService;
+    use std::sync::Arc;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[test]
+    fn test_links() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let content = format!(
+            "See [notes](/app/documents/{a}) and https://example.com/app/documents/{b}#intro, \
+             again /api/documents/{a}, but not documents/{b}0 or documents/not-an-id."
+        );
+        assert_eq!(links(content.as_bytes()), BTreeSet::from([a, b]));
+        assert!(links(b"no links here").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_references_follow_snapshots() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager).await?;
+        let target = doc_service.create_document("Target").await?;
+        let source = doc_service.create_document("Source").await?;
+        let missing = Uuid::new_v4();
+        let kept = format!("[self](/app/documents/{}) [m](/app/documents/{})", source.id, missing);
+        let content = format!("{} [t](/app/documents/{})", kept, target.id);
+        doc_service.update_document_content(source.id, content.clone().into_bytes()).await?;
+        let backlinks = doc_service.backlinks(target.id).await?;
+        assert_eq!(backlinks.iter().map(|doc| doc.id).collect::<Vec<_>>(), vec![source.id]);
+        let mut expected = vec![
+            DocumentLink { target_id: target.id, broken: false },
+            DocumentLink { target_id: missing, broken: true },
+        ];
+        expected.sort_by_key(|link| link.target_id);
+        assert_eq!(doc_service.links(source.id).await?, expected);
+
+        // Edits are picked up once compacted into a snapshot.
+        doc_service.append_document_update(source.id, content.as_bytes(), kept.into_bytes(), None).await?;
+        assert_eq!(doc_service.backlinks(target.id).await?.len(), 1);
+        assert!(doc_service.compact_document(source.id).await?);
+        assert!(doc_service.backlinks(target.id).await?.is_empty());
+
+        // Deleting the target breaks the link to it.
+        doc_service.update_document_content(source.id, format!("/app/documents/{}", target.id).into_bytes()).await?;
+        assert!(doc_service.delete_document(target.id).await?);
+        assert_eq!(doc_service.links(source.id).await?, vec![DocumentLink { target_id: target.id, broken: true }]);
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::{anyhow, Result};
+
+/// A database endpoint, optionally tagged with the region it is located in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegionalEndpoint {
+    pub region: Option<String>,
+    pub base_uri: String,
+}
+
+impl RegionalEndpoint {
+    /// Parses `base-uri` or `region=base-uri`.
+    pub fn parse(entry: &str) -> Self {
+        match entry.trim().split_once('=') {
+            Some((region, base_uri)) => RegionalEndpoint {
+                region: Some(region.trim().to_string()),
+                base_uri: base_uri.trim().to_string(),
+            },
+            None => RegionalEndpoint { region: None, base_uri: entry.trim().to_string() },
+        }
+    }
+}
+
+/// The region this instance runs in and the database endpoints it may connect to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegionConfig {
+    pub region: Option<String>,
+    pub db_endpoints: Vec<RegionalEndpoint>,
+}
+
+impl RegionConfig {
+    /// Parses comma separated endpoints, each optionally prefixed with `region=`,
+    /// e.g. `us-east1=root@db-east:26257,eu-west1=root@db-west:26257`.
+    pub fn parse_endpoints(spec: &str) -> Result<Vec<RegionalEndpoint>> {
+        let endpoints: Vec<RegionalEndpoint> = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(RegionalEndpoint::parse)
+            .collect();
+        if endpoints.is_empty() {
+            return Err(anyhow!("Database endpoints must list at least one endpoint"));
+        }
+        Ok(endpoints)
+    }
+
+    /// Endpoints in the order they should be tried: those in this instance's region first,
+    /// then the rest in their configured order.
+    pub fn preferred_endpoints(&self) -> Vec<&RegionalEndpoint> {
+        let (mut local, remote): (Vec<_>, Vec<_>) = self
+            .db_endpoints
+            .iter()
+            .partition(|endpoint| self.region.is_some() && endpoint.region == self.region);
+        local.extend(remote);
+        local
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoints() -> Result<()> {
+        let endpoints = RegionConfig::parse_endpoints("us-east1=root@east:26257, root@anywhere:26257,")?;
+        assert_eq!(endpoints, vec![
+            RegionalEndpoint { region: Some("us-east1".to_string()), base_uri: "root@east:26257".to_string() },
+            RegionalEndpoint { region: None, base_uri: "root@anywhere:26257".to_string() },
+        ]);
+        assert!(RegionConfig::parse_endpoints(" , ").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_endpoints_are_preferred() -> Result<()> {
+        let config = RegionConfig {
+            region: Some("eu-west1".to_string()),
+            db_endpoints: RegionConfig::parse_endpoints(
+                "us-east1=root@east:26257,eu-west1=root@west:26257,root@anywhere:26257",
+            )?,
+        };
+        let order: Vec<&str> = config.preferred_endpoints().iter().map(|e| e.base_uri.as_str()).collect();
+        assert_eq!(order, vec!["root@west:26257", "root@east:26257", "root@anywhere:26257"]);
+        Ok(())
+    }
+}
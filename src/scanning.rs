@@ -0,0 +1,146 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Malware scanning of uploaded files. Implement `Scanner` to plug in a scanning service;
+//! `ClamdScanner` talks to a ClamAV daemon.
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use std::env;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const CLAMD_ADDR_ENV: &str = "COLLABORATE_CLAMD_ADDR";
+const CLAMD_TIMEOUT: Duration = Duration::from_secs(60);
+/// clamd's default StreamMaxLength is 25 MiB; chunks just need to stay well below it.
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScanVerdict {
+    Clean,
+    /// `signature` names what was found, as reported by the scanner.
+    Infected { signature: String },
+}
+
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    /// Identifies the scanner in logs.
+    fn name(&self) -> &str;
+
+    /// Fails if the file could not be scanned; it must then be treated as unscanned.
+    async fn scan(&self, data: &[u8]) -> Result<ScanVerdict>;
+}
+
+/// Streams files to a ClamAV daemon with its `INSTREAM` command.
+pub struct ClamdScanner {
+    addr: String,
+}
+
+impl ClamdScanner {
+    pub fn new(addr: String) -> Self {
+        ClamdScanner { addr }
+    }
+
+    /// Returns `None` when `COLLABORATE_CLAMD_ADDR` (`host:port`) is unset, i.e. scanning is disabled.
+    pub fn from_env() -> Option<Self> {
+        env::var(CLAMD_ADDR_ENV).ok().map(ClamdScanner::new)
+    }
+
+    async fn instream(&self, data: &[u8]) -> Result<String> {
+        let mut stream = TcpStream::connect(&self.addr)
+            .await
+            .context(format!("Failed to connect to clamd at {}", self.addr))?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        for chunk in data.chunks(CLAMD_CHUNK_SIZE) {
+            stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+            stream.write_all(chunk).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await?;
+        Ok(String::from_utf8_lossy(&reply).trim_end_matches(['\0', '\n']).to_string())
+    }
+}
+
+#[async_trait]
+impl Scanner for ClamdScanner {
+    fn name(&self) -> &str {
+        "clamd"
+    }
+
+    async fn scan(&self, data: &[u8]) -> Result<ScanVerdict> {
+        let reply = tokio::time::timeout(CLAMD_TIMEOUT, self.instream(data))
+            .await
+            .map_err(|_| anyhow!("clamd at {} did not answer within {:?}", self.addr, CLAMD_TIMEOUT))??;
+        parse_clamd_reply(&reply)
+    }
+}
+
+// Replies look like "stream: OK" or "stream: Eicar-Signature FOUND".
+fn parse_clamd_reply(reply: &str) -> Result<ScanVerdict> {
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        return Ok(ScanVerdict::Clean);
+    }
+    if let Some(signature) = result.strip_suffix(" FOUND") {
+        return Ok(ScanVerdict::Infected { signature: signature.to_string() });
+    }
+    bail!("clamd could not scan the file: {}", reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // Answers one INSTREAM request, flagging streams that contain `marker`.
+    async fn fake_clamd(marker: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut command = [0u8; 10];
+            socket.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut data = Vec::new();
+            loop {
+                let len = socket.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut chunk = vec![0; len];
+                socket.read_exact(&mut chunk).await.unwrap();
+                data.extend(chunk);
+            }
+            let infected = data.windows(marker.len()).any(|window| window == marker);
+            let reply: &[u8] = if infected { b"stream: Test-Signature FOUND\0" } else { b"stream: OK\0" };
+            socket.write_all(reply).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_clamd_scanner() {
+        let scanner = ClamdScanner::new(fake_clamd(b"EVIL").await);
+        let mut data = vec![b'a'; CLAMD_CHUNK_SIZE * 2];
+        data.extend(b"EVIL");
+        assert_eq!(scanner.scan(&data).await.unwrap(), ScanVerdict::Infected { signature: "Test-Signature".to_string() });
+
+        let scanner = ClamdScanner::new(fake_clamd(b"EVIL").await);
+        assert_eq!(scanner.scan(b"harmless").await.unwrap(), ScanVerdict::Clean);
+
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR").is_err());
+    }
+}
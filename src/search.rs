@@ -0,0 +1,287 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::consistency::ReadConsistency;
+use crate::document_service::{DocumentMetadata, DocumentService};
+use crate::search_index::{self, SearchIndex};
+use crate::user_service::{AccountKind, UserService};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::warn;
+use uuid::Uuid;
+
+/// Each source gets this long; slower sources are left out rather than delaying the palette.
+const QUICK_SEARCH_BUDGET: Duration = Duration::from_millis(150);
+/// Candidates fetched per source before ranking.
+const CANDIDATES_PER_SOURCE: i64 = 25;
+pub const MAX_QUICK_RESULTS: usize = 20;
+/// Documents found only by their content rank below any title match.
+const CONTENT_MATCH_SCORE: f32 = 0.3;
+/// Candidates fetched per source by `search_documents`, before filtering by permission.
+pub const SEARCH_CANDIDATES: i64 = 200;
+
+/// Escapes `LIKE` wildcards so user input matches literally.
+pub(crate) fn escape_like(query: &str) -> String {
+    let mut escaped = String::with_capacity(query.len());
+    for c in query.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// A `LIKE` pattern matching `query` anywhere in a string.
+pub(crate) fn like_pattern(query: &str) -> String {
+    format!("%{}%", escape_like(query))
+}
+
+/// How well `title` matches `query`: exact beats prefix beats word prefix beats substring.
+fn match_score(title: &str, query: &str) -> f32 {
+    let title = title.to_lowercase();
+    let query = query.to_lowercase();
+    if title == query {
+        1.0
+    } else if title.starts_with(&query) {
+        0.8
+    } else if title.split(|c: char| !c.is_alphanumeric()).any(|word| word.starts_with(&query)) {
+        0.6
+    } else if title.contains(&query) {
+        0.4
+    } else {
+        0.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultKind {
+    Document,
+    Person,
+    Bot,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QuickResult {
+    pub kind: ResultKind,
+    pub id: Uuid,
+    pub title: String,
+    pub score: f32,
+}
+
+/// How many candidates of each type matched, before truncation.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Facets {
+    pub documents: usize,
+    pub people: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct QuickSearchResults {
+    pub results: Vec<QuickResult>,
+    pub facets: Facets,
+    /// Set when a source missed the latency budget or failed and was left out.
+    pub partial: bool,
+}
+
+/// Runs a search source within the budget; `None` means it was dropped.
+async fn within_budget<T>(source: &str, search: impl Future<Output = Result<Vec<T>>>) -> Option<Vec<T>> {
+    match timeout(QUICK_SEARCH_BUDGET, search).await {
+        Ok(Ok(found)) => Some(found),
+        Ok(Err(e)) => {
+            warn!("Quick search of {} failed: {:#}", source, e);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Searches documents, by name and content, and people at once for command-palette style
+/// lookups. `visible_documents` restricts document hits for callers with scoped access.
+pub async fn quick_search(
+    doc_service: &DocumentService,
+    index: &SearchIndex,
+    users: &UserService,
+    query: &str,
+    visible_documents: Option<&[Uuid]>,
+    limit: usize,
+) -> QuickSearchResults {
+    let query = query.trim();
+    if query.is_empty() {
+        return QuickSearchResults::default();
+    }
+    // Slightly stale results are fine here and keep reads local to the nearest replica.
+    let consistency = ReadConsistency::Follower;
+    let (documents, contents, people) = tokio::join!(
+        within_budget(
+            "documents",
+            doc_service.search_documents_by_name(query, visible_documents, CANDIDATES_PER_SOURCE, consistency),
+        ),
+        within_budget("content", index.search(query, visible_documents, CANDIDATES_PER_SOURCE, consistency)),
+        within_budget("people", users.search_users(query, CANDIDATES_PER_SOURCE, consistency)),
+    );
+
+    let partial = documents.is_none() || contents.is_none() || people.is_none();
+    let mut results = QuickSearchResults { partial, ..Default::default() };
+    let mut documents = documents.unwrap_or_default();
+    let named: HashSet<Uuid> = documents.iter().map(|doc| doc.id).collect();
+    documents.extend(contents.unwrap_or_default().into_iter().filter(|doc| !named.contains(&doc.id)));
+    let people = people.unwrap_or_default();
+    results.facets = Facets { documents: documents.len(), people: people.len() };
+    results.results = rank(
+        query,
+        documents.into_iter().map(|doc| (ResultKind::Document, doc.id, doc.name)).chain(people.into_iter().map(|user| {
+            let kind = match user.kind {
+                AccountKind::Human => ResultKind::Person,
+                AccountKind::Bot => ResultKind::Bot,
+            };
+            (kind, user.id, user.display_name)
+        })),
+        limit.min(MAX_QUICK_RESULTS),
+    );
+    results
+}
+
+/// A document found by `search_documents`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub id: Uuid,
+    pub name: String,
+    pub updated_at: DateTime<Utc>,
+    pub language: Option<String>,
+    pub score: f32,
+}
+
+/// Searches document names and content, best matches first. A document scores how well
+/// its name matches `query` as a whole, plus the share of the query's words found in its
+/// name or content. `visible_documents` restricts hits for callers with scoped access; the
+/// caller must still filter the results by what it may read. `language` keeps only
+/// documents detected to be in that language.
+pub async fn search_documents(
+    doc_service: &DocumentService,
+    index: &SearchIndex,
+    query: &str,
+    visible_documents: Option<&[Uuid]>,
+    language: Option<&str>,
+    consistency: ReadConsistency,
+) -> Result<Vec<SearchResult>> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+    let (named, indexed) = tokio::join!(
+        doc_service.search_documents_by_name(query, visible_documents, SEARCH_CANDIDATES, consistency),
+        index.search_any(query, visible_documents, SEARCH_CANDIDATES, consistency),
+    );
+    let (mut named, mut indexed) = (named?, indexed?);
+    if let Some(language) = language {
+        named.retain(|doc| doc.language.as_deref() == Some(language));
+        indexed.retain(|(doc, _)| doc.language.as_deref() == Some(language));
+    }
+    Ok(rank_documents(query, named, indexed))
+}
+
+fn rank_documents(query: &str, named: Vec<DocumentMetadata>, indexed: Vec<(DocumentMetadata, i64)>) -> Vec<SearchResult> {
+    let words = search_index::terms(query).len().max(1) as f32;
+    let mut found: HashMap<Uuid, (DocumentMetadata, i64)> = named.into_iter().map(|doc| (doc.id, (doc, 0))).collect();
+    for (doc, matched) in indexed {
+        found.insert(doc.id, (doc, matched));
+    }
+    let mut ranked: Vec<SearchResult> = found
+        .into_values()
+        .map(|(doc, matched)| SearchResult {
+            score: match_score(&doc.name, query) + matched as f32 / words,
+            id: doc.id,
+            name: doc.name,
+            updated_at: doc.updated_at,
+            language: doc.language,
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| b.updated_at.cmp(&a.updated_at)));
+    ranked
+}
+
+fn rank(query: &str, candidates: impl Iterator<Item = (ResultKind, Uuid, String)>, limit: usize) -> Vec<QuickResult> {
+    let mut ranked: Vec<QuickResult> = candidates
+        .map(|(kind, id, title)| {
+            // Only documents come from a content search, so a title that doesn't match was found by content.
+            let score = match match_score(&title, query) {
+                0.0 if kind == ResultKind::Document => CONTENT_MATCH_SCORE,
+                score => score,
+            };
+            QuickResult { kind, id, score, title }
+        })
+        .collect();
+    // Shorter titles first among equal scores: they are closer to what was typed.
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.title.len().cmp(&b.title.len())));
+    ranked.truncate(limit);
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_like_patterns_are_literal() {
+        assert_eq!(like_pattern("50%_off\\"), "%50\\%\\_off\\\\%");
+    }
+
+    #[test]
+    fn test_ranking_prefers_closer_matches() {
+        let candidates = [
+            (ResultKind::Document, "Quarterly planning notes"),
+            (ResultKind::Person, "Plan B"),
+            (ResultKind::Document, "Workplan"),
+            (ResultKind::Document, "plan"),
+            (ResultKind::Bot, "Release planner"),
+        ];
+        let ranked = rank(
+            "Plan",
+            candidates.iter().map(|(kind, title)| (*kind, Uuid::new_v4(), title.to_string())),
+            4,
+        );
+        let titles: Vec<&str> = ranked.iter().map(|result| result.title.as_str()).collect();
+        assert_eq!(titles, ["plan", "Plan B", "Release planner", "Quarterly planning notes"]);
+    }
+
+    #[test]
+    fn test_documents_rank_by_name_then_words_found() {
+        let doc = |name: &str, minutes: i64| DocumentMetadata {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now() - chrono::TimeDelta::minutes(minutes),
+            language: None,
+        };
+        let budget = doc("Budget review", 5);
+        let notes = doc("Meeting notes", 1);
+        let older_notes = doc("Old notes", 9);
+        let minutes = doc("Minutes", 3);
+        let ranked = rank_documents(
+            "budget review",
+            vec![budget.clone()],
+            vec![(budget, 2), (notes, 1), (older_notes, 1), (minutes, 2)],
+        );
+        let names: Vec<&str> = ranked.iter().map(|result| result.name.as_str()).collect();
+        assert_eq!(names, ["Budget review", "Minutes", "Meeting notes", "Old notes"]);
+        assert_eq!(ranked[0].score, 2.0);
+    }
+}
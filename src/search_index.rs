@@ -0,0 +1,413 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+//! Full-text index of document names and content. Saves only announce themselves on the event bus;
+//! the indexer picks the change up afterwards, so indexing never slows a save down.
+//! Each document records the `updated_at` it was last indexed at, which lets the indexer
+//! catch up on anything it missed while lagging or stopped, and `collaborate reindex`
+//! rebuild the index from scratch.
+
+use crate::consistency::ReadConsistency;
+use crate::content_analysis::{TextExtractor, Utf8Extractor};
+use crate::db::Manager;
+use crate::document_service::{DocumentMetadata, DocumentService};
+use crate::events::{DocumentEvent, EventBus};
+use crate::migrations::{self, Component, Migration};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, FromRow};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+/// Words longer than this are left out of the index.
+const MAX_TERM_CHARS: usize = 64;
+/// Distinct words indexed per document; the rest of a very long document is not searchable.
+const MAX_TERMS_PER_DOCUMENT: usize = 10_000;
+const CATCH_UP_BATCH_SIZE: i64 = 100;
+/// How often the indexer looks for documents it failed to index.
+const CATCH_UP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The distinct lowercase words of `text`, ignoring single characters and very long words.
+pub fn terms(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| (2..=MAX_TERM_CHARS).contains(&word.chars().count()))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+#[derive(FromRow)]
+struct RankedRow {
+    #[sqlx(flatten)]
+    metadata: DocumentMetadata,
+    matched: i64,
+}
+
+pub struct SearchIndex {
+    db_manager: Arc<Manager>,
+    doc_service: Arc<DocumentService>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "search_index",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS document_search_terms (
+                document_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                term TEXT NOT NULL,
+                PRIMARY KEY (document_id, term)
+            )",
+            "CREATE INDEX IF NOT EXISTS document_search_terms_by_term ON document_search_terms (term)",
+            "CREATE TABLE IF NOT EXISTS document_search_state (
+                document_id UUID PRIMARY KEY REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                indexed_at TIMESTAMPTZ NOT NULL
+            )",
+        ],
+        down: &[
+            "DROP TABLE IF EXISTS document_search_state",
+            "DROP TABLE IF EXISTS document_search_terms",
+        ],
+    }],
+};
+
+impl SearchIndex {
+    pub async fn new(db_manager: Arc<Manager>, doc_service: Arc<DocumentService>) -> Result<Self> {
+        let index = SearchIndex { db_manager, doc_service };
+        index.initialize_schema().await?;
+        Ok(index)
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("Search index schema initialized.");
+        Ok(())
+    }
+
+    /// Replaces a document's entry in the index with its current content.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn index_document(&self, doc_id: Uuid) -> Result<()> {
+        // Read the version before the content: if a save lands in between, the document
+        // stays behind its `updated_at` and the next catch-up indexes it again.
+        let Some(metadata) = self.doc_service.get_document_metadata(doc_id).await? else {
+            return self.remove_document(doc_id).await;
+        };
+        let content = self.doc_service.get_document_content(doc_id).await?;
+        let text = content.and_then(|content| Utf8Extractor.extract(&content.crdt_data)).unwrap_or_default();
+        // Words of the name are indexed first, so the cap on terms never leaves them out.
+        let named = terms(&metadata.name);
+        let mut indexed: Vec<String> = named.iter().cloned().collect();
+        indexed.extend(terms(&text).into_iter().filter(|term| !named.contains(term)));
+        indexed.truncate(MAX_TERMS_PER_DOCUMENT);
+
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        sqlx::query("DELETE FROM document_search_terms WHERE document_id = $1")
+            .bind(doc_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to clear search terms of document ID {}", doc_id))?;
+        sqlx::query("INSERT INTO document_search_terms (document_id, term) SELECT $1, unnest($2::TEXT[])")
+            .bind(doc_id)
+            .bind(&indexed)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to index document ID {}", doc_id))?;
+        let indexed = sqlx::query(
+                "INSERT INTO document_search_state (document_id, indexed_at)
+                 SELECT $1, $2 WHERE EXISTS (SELECT 1 FROM documents_metadata WHERE id = $1)
+                 ON CONFLICT (document_id) DO UPDATE SET indexed_at = excluded.indexed_at"
+            )
+            .bind(doc_id)
+            .bind(metadata.updated_at)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to record indexing of document ID {}", doc_id))?;
+        // Deleted while we were reading it; the cascade has already removed its terms.
+        if indexed.rows_affected() == 0 {
+            tx.rollback().await.ok();
+            return Ok(());
+        }
+        tx.commit().await.context(format!("Failed to commit index of document ID {}", doc_id))?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn remove_document(&self, doc_id: Uuid) -> Result<()> {
+        sqlx::query("DELETE FROM document_search_terms WHERE document_id = $1")
+            .bind(doc_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to remove document ID {} from the search index", doc_id))?;
+        Ok(())
+    }
+
+    /// Documents containing every word of `query`, most recently updated first.
+    /// `restrict_to` limits the search to the given documents, for callers with scoped access.
+    #[instrument(skip_all)]
+    pub async fn search(
+        &self,
+        query: &str,
+        restrict_to: Option<&[Uuid]>,
+        limit: i64,
+        consistency: ReadConsistency,
+    ) -> Result<Vec<DocumentMetadata>> {
+        let terms: Vec<String> = terms(query).into_iter().collect();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let consistency = consistency.resolve_in(&self.db_manager.pool).await?;
+        sqlx::query_as(&format!(
+                "SELECT m.id, m.name, m.created_at, m.updated_at, m.language
+                 FROM document_search_terms t JOIN documents_metadata m ON m.id = t.document_id{}
+                 WHERE t.term = ANY($1) AND ($2::UUID[] IS NULL OR m.id = ANY($2)) AND m.deleted_at IS NULL
+                 GROUP BY m.id, m.name, m.created_at, m.updated_at, m.language
+                 HAVING count(*) = $3
+                 ORDER BY m.updated_at DESC LIMIT $4",
+                consistency.as_of_clause()
+            ))
+            .bind(&terms)
+            .bind(restrict_to)
+            .bind(terms.len() as i64)
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to search document content for '{}'", query))
+    }
+
+    /// Documents containing any word of `query`, with how many of its distinct words each
+    /// contains; those containing the most come first, then the most recently updated.
+    /// `restrict_to` limits the search to the given documents, for callers with scoped access.
+    #[instrument(skip_all)]
+    pub async fn search_any(
+        &self,
+        query: &str,
+        restrict_to: Option<&[Uuid]>,
+        limit: i64,
+        consistency: ReadConsistency,
+    ) -> Result<Vec<(DocumentMetadata, i64)>> {
+        let terms: Vec<String> = terms(query).into_iter().collect();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let consistency = consistency.resolve_in(&self.db_manager.pool).await?;
+        let rows: Vec<RankedRow> = sqlx::query_as(&format!(
+                "SELECT m.id, m.name, m.created_at, m.updated_at, m.language, count(*) AS matched
+                 FROM document_search_terms t JOIN documents_metadata m ON m.id = t.document_id{}
+                 WHERE t.term = ANY($1) AND ($2::UUID[] IS NULL OR m.id = ANY($2)) AND m.deleted_at IS NULL
+                 GROUP BY m.id, m.name, m.created_at, m.updated_at, m.language
+                 ORDER BY matched DESC, m.updated_at DESC LIMIT $3",
+                consistency.as_of_clause()
+            ))
+            .bind(&terms)
+            .bind(restrict_to)
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to search documents for '{}'", query))?;
+        Ok(rows.into_iter().map(|row| (row.metadata, row.matched)).collect())
+    }
+
+    /// Documents saved since they were last indexed, or never indexed, in ID order after `after`.
+    async fn find_stale_documents(&self, after: Option<Uuid>, limit: i64) -> Result<Vec<Uuid>> {
+        sqlx::query_scalar(
+                "SELECT m.id FROM documents_metadata m
+                 LEFT JOIN document_search_state s ON s.document_id = m.id
+                 WHERE (s.indexed_at IS NULL OR s.indexed_at < m.updated_at) AND ($1::UUID IS NULL OR m.id > $1)
+                   AND m.deleted_at IS NULL
+                 ORDER BY m.id LIMIT $2"
+            )
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to query documents to index")
+    }
+
+    /// Indexes every document saved since it was last indexed. Returns how many were indexed;
+    /// documents that fail are logged and left for the next catch-up.
+    #[instrument(skip_all)]
+    pub async fn catch_up(&self) -> Result<usize> {
+        let mut indexed = 0;
+        let mut after = None;
+        loop {
+            let stale = self.find_stale_documents(after, CATCH_UP_BATCH_SIZE).await?;
+            for doc_id in &stale {
+                match self.index_document(*doc_id).await {
+                    Ok(()) => indexed += 1,
+                    Err(e) => warn!("Failed to index document ID {}: {:#}", doc_id, e),
+                }
+            }
+            match stale.last() {
+                Some(last) if stale.len() as i64 == CATCH_UP_BATCH_SIZE => after = Some(*last),
+                _ => return Ok(indexed),
+            }
+        }
+    }
+
+    /// Empties the index and indexes every document again. Content searches miss documents
+    /// not yet reindexed until this returns.
+    #[instrument(skip_all)]
+    pub async fn rebuild(&self) -> Result<usize> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        tx.execute("DELETE FROM document_search_terms").await.context("Failed to clear search terms")?;
+        tx.execute("DELETE FROM document_search_state").await.context("Failed to clear search index state")?;
+        tx.commit().await.context("Failed to commit clearing of the search index")?;
+        self.catch_up().await
+    }
+
+    /// How many documents `catch_up`, or with `rebuild` set `rebuild`, would index now.
+    #[instrument(skip_all)]
+    pub async fn count_unindexed(&self, rebuild: bool) -> Result<i64> {
+        let query = if rebuild {
+            "SELECT count(*) FROM documents_metadata WHERE deleted_at IS NULL"
+        } else {
+            "SELECT count(*) FROM documents_metadata m
+             LEFT JOIN document_search_state s ON s.document_id = m.id
+             WHERE (s.indexed_at IS NULL OR s.indexed_at < m.updated_at) AND m.deleted_at IS NULL"
+        };
+        sqlx::query_scalar(query)
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context("Failed to count documents to index")
+    }
+
+    /// When a document was last indexed, if ever.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn indexed_at(&self, doc_id: Uuid) -> Result<Option<DateTime<Utc>>> {
+        sqlx::query_scalar("SELECT indexed_at FROM document_search_state WHERE document_id = $1")
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query index state of document ID {}", doc_id))
+    }
+}
+
+/// Keeps the index up to date with saves announced on `events`, catching up on startup,
+/// whenever it falls behind the bus, and periodically to retry documents that failed.
+pub async fn run_indexer(index: Arc<SearchIndex>, events: EventBus) {
+    // Subscribe before the first catch-up so saves made during it are not missed.
+    let mut receiver = events.subscribe();
+    let mut catch_up = tokio::time::interval(CATCH_UP_INTERVAL);
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let result = match event {
+                    Ok(DocumentEvent::Changed { doc_id }) => index.index_document(doc_id).await,
+                    Ok(DocumentEvent::Deleted { doc_id }) => index.remove_document(doc_id).await,
+                    // A deletion that goes ahead is announced as `Deleted`.
+                    Ok(_) => Ok(()),
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Search indexer missed {} document events; catching up", missed);
+                        index.catch_up().await.map(|_| ())
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if let Err(e) = result {
+                    warn!("Search indexing failed; will retry on the next catch-up: {:#}", e);
+                }
+            },
+            _ = catch_up.tick() => match index.catch_up().await {
+                Ok(0) => {}
+                Ok(indexed) => info!("Caught up the search index on {} documents", indexed),
+                Err(e) => warn!("Search index catch-up failed; will retry: {:#}", e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[test]
+    fn test_terms_are_lowercase_words() {
+        let found: Vec<String> = terms("Über-Planung: the PLAN, a plan. x").into_iter().collect();
+        assert_eq!(found, ["plan", "planung", "the", "über"]);
+    }
+
+    #[tokio::test]
+    async fn test_saves_are_indexed_by_the_indexer() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = Arc::new(DocumentService::new(manager.clone()).await?);
+        let index = Arc::new(SearchIndex::new(manager, doc_service.clone()).await?);
+        let marker = format!("marker{}", Uuid::new_v4().simple());
+        let metadata = doc_service.create_document("Test Document for the Search Index").await?;
+
+        let indexer = tokio::spawn(run_indexer(index.clone(), doc_service.events().clone()));
+        doc_service.update_document_content(metadata.id, format!("Quarterly {} report", marker).into_bytes()).await?;
+
+        let mut found = Vec::new();
+        for _ in 0..50 {
+            found = index.search(&format!("REPORT {}", marker), None, 10, ReadConsistency::Strong).await?;
+            if !found.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        indexer.abort();
+        assert_eq!(found.iter().map(|doc| doc.id).collect::<Vec<_>>(), vec![metadata.id]);
+        assert!(index.search(&format!("summary {}", marker), None, 10, ReadConsistency::Strong).await?.is_empty());
+        assert!(index.search(&marker, Some(&[]), 10, ReadConsistency::Strong).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_catch_up_indexes_missed_saves() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = Arc::new(DocumentService::new(manager.clone()).await?);
+        let index = SearchIndex::new(manager, doc_service.clone()).await?;
+        let marker = format!("marker{}", Uuid::new_v4().simple());
+        let metadata = doc_service.create_document("Test Document for Index Catch-up").await?;
+        doc_service.update_document_content(metadata.id, marker.clone().into_bytes()).await?;
+
+        // Nothing was listening, so the save is only picked up by catching up.
+        assert!(index.search(&marker, None, 10, ReadConsistency::Strong).await?.is_empty());
+        assert!(index.count_unindexed(false).await? >= 1);
+        index.catch_up().await?;
+        assert_eq!(index.search(&marker, None, 10, ReadConsistency::Strong).await?.len(), 1);
+        let updated_at = doc_service.get_document_metadata(metadata.id).await?.unwrap().updated_at;
+        assert_eq!(index.indexed_at(metadata.id).await?, Some(updated_at));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_names_are_indexed_and_partial_matches_ranked() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = Arc::new(DocumentService::new(manager.clone()).await?);
+        let index = SearchIndex::new(manager, doc_service.clone()).await?;
+        let marker = format!("marker{}", Uuid::new_v4().simple());
+        let named = doc_service.create_document(&format!("Roadmap {}", marker)).await?;
+        let both = doc_service.create_document("Planning").await?;
+        doc_service.update_document_content(both.id, format!("{} roadmap", marker).into_bytes()).await?;
+        index.index_document(named.id).await?;
+        index.index_document(both.id).await?;
+
+        // Other tests' documents mention roadmaps too.
+        let ours = [named.id, both.id];
+        let found = index.search_any(&format!("{} roadmap quarterly", marker), Some(&ours), 10, ReadConsistency::Strong).await?;
+        let found: Vec<(Uuid, i64)> = found.into_iter().map(|(doc, matched)| (doc.id, matched)).collect();
+        assert_eq!(found, [(both.id, 2), (named.id, 2)]);
+        assert_eq!(index.search_any(&marker, None, 10, ReadConsistency::Strong).await?.len(), 2);
+        assert_eq!(index.search_any(&marker, Some(&[named.id]), 10, ReadConsistency::Strong).await?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::blob_store;
+use crate::document_service::{DocumentMetadata, DocumentService};
+use crate::integrity::{self, ChecksumMismatch};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::TryStreamExt;
+use object_store::path::Path;
+use object_store::{ObjectStore, PutPayload};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+const SNAPSHOT_URL_ENV: &str = "COLLABORATE_SNAPSHOT_URL";
+const SNAPSHOT_INTERVAL_ENV: &str = "COLLABORATE_SNAPSHOT_INTERVAL_SECS";
+const SNAPSHOT_RETENTION_ENV: &str = "COLLABORATE_SNAPSHOT_RETENTION_DAYS";
+const SNAPSHOT_EXTENSION: &str = "snap";
+
+/// Where and how often document snapshots are shipped.
+#[derive(Clone, Debug)]
+pub struct SnapshotArchiveConfig {
+    /// Object storage URL; see `blob_store::open`.
+    pub url: String,
+    pub interval: Duration,
+    pub retention: TimeDelta,
+}
+
+impl SnapshotArchiveConfig {
+    /// Returns `None` when `COLLABORATE_SNAPSHOT_URL` is unset, i.e. shipping is disabled.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(url) = env::var(SNAPSHOT_URL_ENV) else {
+            return Ok(None);
+        };
+        let interval_secs = match env::var(SNAPSHOT_INTERVAL_ENV) {
+            Ok(secs) => secs.parse().context(format!("{} must be a number of seconds", SNAPSHOT_INTERVAL_ENV))?,
+            Err(_) => 15 * 60,
+        };
+        let retention_days = match env::var(SNAPSHOT_RETENTION_ENV) {
+            Ok(days) => days.parse().context(format!("{} must be a number of days", SNAPSHOT_RETENTION_ENV))?,
+            Err(_) => 30,
+        };
+        Ok(Some(SnapshotArchiveConfig {
+            url,
+            interval: Duration::from_secs(interval_secs),
+            retention: TimeDelta::days(retention_days),
+        }))
+    }
+}
+
+// Stored at the start of every snapshot object so a document can be recreated
+// even if its metadata row is gone.
+#[derive(Serialize, Deserialize)]
+struct SnapshotHeader {
+    id: Uuid,
+    name: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    // Of the content following the header; absent in snapshots shipped before checksums.
+    #[serde(default)]
+    checksum: Option<String>,
+}
+
+/// A snapshot stored in the archive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchivedSnapshot {
+    pub document_id: Uuid,
+    pub taken_at: DateTime<Utc>,
+    location: Path,
+}
+
+/// Document snapshots in object storage, laid out as
+/// `<root>/documents/<document-id>/<updated-at-millis>.snap`.
+pub struct SnapshotArchive {
+    store: Arc<dyn ObjectStore>,
+    root: Path,
+    retention: TimeDelta,
+}
+
+impl SnapshotArchive {
+    pub fn new(store: Arc<dyn ObjectStore>, root: Path, retention: TimeDelta) -> Self {
+        SnapshotArchive { store, root, retention }
+    }
+
+    pub fn from_config(config: &SnapshotArchiveConfig) -> Result<Self> {
+        let (store, root) = blob_store::open(&config.url)?;
+        Ok(SnapshotArchive::new(store, root, config.retention))
+    }
+
+    fn document_prefix(&self, doc_id: Uuid) -> Path {
+        self.root.child("documents").child(doc_id.to_string())
+    }
+
+    fn snapshot_location(&self, metadata: &DocumentMetadata) -> Path {
+        self.document_prefix(metadata.id)
+            .child(format!("{:013}.{}", metadata.updated_at.timestamp_millis(), SNAPSHOT_EXTENSION))
+    }
+
+    /// Whether this version of the document is already archived.
+    #[instrument(skip_all, fields(doc_id = %metadata.id))]
+    pub async fn contains(&self, metadata: &DocumentMetadata) -> bool {
+        self.store.head(&self.snapshot_location(metadata)).await.is_ok()
+    }
+
+    /// Uploads a snapshot of the document unless this version is already archived.
+    #[instrument(skip_all, fields(doc_id = %metadata.id))]
+    pub async fn ship(&self, metadata: &DocumentMetadata, crdt_data: &[u8]) -> Result<ArchivedSnapshot> {
+        let snapshot = ArchivedSnapshot {
+            document_id: metadata.id,
+            taken_at: metadata.updated_at,
+            location: self.snapshot_location(metadata),
+        };
+        if self.store.head(&snapshot.location).await.is_ok() {
+            return Ok(snapshot);
+        }
+
+        let header = serde_json::to_vec(&SnapshotHeader {
+            id: metadata.id,
+            name: metadata.name.clone(),
+            created_at: metadata.created_at,
+            updated_at: metadata.updated_at,
+            checksum: Some(integrity::checksum(crdt_data)),
+        })?;
+        let mut body = Vec::with_capacity(4 + header.len() + crdt_data.len());
+        body.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        body.extend_from_slice(&header);
+        body.extend_from_slice(crdt_data);
+        self.store
+            .put(&snapshot.location, PutPayload::from(body))
+            .await
+            .context(format!("Failed to upload snapshot {}", snapshot.location))?;
+        Ok(snapshot)
+    }
+
+    /// All archived snapshots of a document, oldest first.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn list(&self, doc_id: Uuid) -> Result<Vec<ArchivedSnapshot>> {
+        let objects: Vec<_> = self
+            .store
+            .list(Some(&self.document_prefix(doc_id)))
+            .try_collect()
+            .await
+            .context(format!("Failed to list snapshots for document ID {}", doc_id))?;
+        let mut snapshots: Vec<ArchivedSnapshot> = objects
+            .into_iter()
+            .filter_map(|object| {
+                let millis = object.location.filename()?.strip_suffix(SNAPSHOT_EXTENSION)?.strip_suffix('.')?.parse().ok()?;
+                Some(ArchivedSnapshot {
+                    document_id: doc_id,
+                    taken_at: DateTime::from_timestamp_millis(millis)?,
+                    location: object.location,
+                })
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.taken_at);
+        Ok(snapshots)
+    }
+
+    /// Downloads the newest snapshot taken at or before `at` (or the newest overall).
+    /// Fails with `ChecksumMismatch` if that snapshot is corrupt.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn fetch(&self, doc_id: Uuid, at: Option<DateTime<Utc>>) -> Result<Option<(DocumentMetadata, Vec<u8>)>> {
+        let snapshot = self
+            .list(doc_id)
+            .await?
+            .into_iter()
+            .rev()
+            .find(|snapshot| at.is_none_or(|at| snapshot.taken_at <= at));
+        match snapshot {
+            Some(snapshot) => self.read(&snapshot).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Downloads the newest snapshot whose content still matches its checksum,
+    /// skipping over corrupt ones.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn fetch_latest_intact(&self, doc_id: Uuid) -> Result<Option<(DocumentMetadata, Vec<u8>)>> {
+        for snapshot in self.list(doc_id).await?.iter().rev() {
+            match self.read(snapshot).await {
+                Ok(fetched) => return Ok(Some(fetched)),
+                Err(e) if e.is::<ChecksumMismatch>() => warn!("Skipping corrupt snapshot: {:#}", e),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    async fn read(&self, snapshot: &ArchivedSnapshot) -> Result<(DocumentMetadata, Vec<u8>)> {
+        let body = self
+            .store
+            .get(&snapshot.location)
+            .await
+            .context(format!("Failed to download snapshot {}", snapshot.location))?
+            .bytes()
+            .await?;
+        let header_len = body
+            .get(..4)
+            .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+            .ok_or_else(|| anyhow!("Snapshot {} is truncated", snapshot.location))?;
+        let header_bytes = body
+            .get(4..4 + header_len)
+            .ok_or_else(|| anyhow!("Snapshot {} is truncated", snapshot.location))?;
+        let header: SnapshotHeader = serde_json::from_slice(header_bytes)
+            .context(format!("Snapshot {} has a corrupt header", snapshot.location))?;
+        let crdt_data = body[4 + header_len..].to_vec();
+        integrity::verify(&snapshot.location, &crdt_data, header.checksum.as_deref())?;
+        let metadata = DocumentMetadata {
+            id: header.id,
+            name: header.name,
+            created_at: header.created_at,
+            updated_at: header.updated_at,
+            // Detected again when the snapshot is restored.
+            language: None,
+        };
+        Ok((metadata, crdt_data))
+    }
+
+    /// Deletes snapshots older than the retention window, always keeping the newest one.
+    #[instrument(skip_all, fields(doc_id = %doc_id))]
+    pub async fn prune(&self, doc_id: Uuid, now: DateTime<Utc>) -> Result<usize> {
+        let mut snapshots = self.list(doc_id).await?;
+        snapshots.pop();
+        let mut pruned = 0;
+        for snapshot in snapshots.iter().filter(|s| s.taken_at < now - self.retention) {
+            self.store
+                .delete(&snapshot.location)
+                .await
+                .context(format!("Failed to delete snapshot {}", snapshot.location))?;
+            pruned += 1;
+        }
+        Ok(pruned)
+    }
+}
+
+/// Periodically ships snapshots of recently changed documents and applies the retention policy.
+pub async fn run_shipper(doc_service: Arc<DocumentService>, archive: Arc<SnapshotArchive>, interval: Duration) {
+    // Start from the beginning of time so documents changed while we were down are archived too;
+    // versions already in the archive are skipped cheaply.
+    let mut shipped_until = DateTime::<Utc>::UNIX_EPOCH;
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let started = Utc::now();
+        match ship_changed_documents(&doc_service, &archive, shipped_until).await {
+            Ok(shipped) => {
+                if shipped > 0 {
+                    info!("Shipped {} document snapshots to the archive", shipped);
+                }
+                shipped_until = started;
+            }
+            Err(e) => warn!("Snapshot shipping failed; will retry: {:#}", e),
+        }
+    }
+}
+
+async fn ship_changed_documents(
+    doc_service: &DocumentService,
+    archive: &SnapshotArchive,
+    since: DateTime<Utc>,
+) -> Result<usize> {
+    let changed = doc_service.list_documents_updated_since(since).await?;
+    let now = Utc::now();
+    for metadata in &changed {
+        // Checked before loading content so unchanged cold documents aren't rehydrated.
+        if archive.contains(metadata).await {
+            continue;
+        }
+        let Some(document) = doc_service.get_document(metadata.id).await? else {
+            continue; // Deleted since it was listed.
+        };
+        let crdt_data = document.content.map(|content| content.crdt_data).unwrap_or_default();
+        archive.ship(&document.metadata, &crdt_data).await?;
+        archive.prune(metadata.id, now).await?;
+    }
+    Ok(changed.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::SubsecRound;
+    use object_store::memory::InMemory;
+
+    fn test_archive() -> SnapshotArchive {
+        SnapshotArchive::new(Arc::new(InMemory::new()), Path::from("archive"), TimeDelta::days(7))
+    }
+
+    fn metadata_at(id: Uuid, updated_at: DateTime<Utc>) -> DocumentMetadata {
+        DocumentMetadata {
+            id,
+            name: "Archived Document".to_string(),
+            created_at: DateTime::from_timestamp_millis(0).unwrap(),
+            updated_at,
+            language: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ship_and_fetch_point_in_time() -> Result<()> {
+        let archive = test_archive();
+        let id = Uuid::new_v4();
+        let first = DateTime::from_timestamp_millis(1_000_000).unwrap();
+        let second = DateTime::from_timestamp_millis(2_000_000).unwrap();
+
+        archive.ship(&metadata_at(id, first), &[1, 2]).await?;
+        archive.ship(&metadata_at(id, second), &[3, 4]).await?;
+        // Re-shipping an archived version is a no-op.
+        archive.ship(&metadata_at(id, second), &[3, 4]).await?;
+        assert_eq!(archive.list(id).await?.len(), 2);
+
+        let (metadata, data) = archive.fetch(id, None).await?.unwrap();
+        assert_eq!(metadata, metadata_at(id, second));
+        assert_eq!(data, vec![3, 4]);
+
+        let (_, data) = archive.fetch(id, Some(second - TimeDelta::seconds(1))).await?.unwrap();
+        assert_eq!(data, vec![1, 2]);
+        assert!(archive.fetch(id, Some(first - TimeDelta::seconds(1))).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_snapshots_are_detected() -> Result<()> {
+        let archive = test_archive();
+        let id = Uuid::new_v4();
+        let first = DateTime::from_timestamp_millis(1_000_000).unwrap();
+        let second = DateTime::from_timestamp_millis(2_000_000).unwrap();
+        archive.ship(&metadata_at(id, first), &[1, 2]).await?;
+        let newest = archive.ship(&metadata_at(id, second), &[3, 4]).await?;
+
+        // Flip the last content byte of the newest snapshot.
+        let mut body = archive.store.get(&newest.location).await?.bytes().await?.to_vec();
+        *body.last_mut().unwrap() ^= 0xff;
+        archive.store.put(&newest.location, PutPayload::from(body)).await?;
+
+        let err = archive.fetch(id, None).await.unwrap_err();
+        assert!(err.is::<ChecksumMismatch>());
+        let (metadata, data) = archive.fetch_latest_intact(id).await?.unwrap();
+        assert_eq!(metadata, metadata_at(id, first));
+        assert_eq!(data, vec![1, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prune_keeps_newest_snapshot() -> Result<()> {
+        let archive = test_archive();
+        let id = Uuid::new_v4();
+        let now = Utc::now();
+
+        archive.ship(&metadata_at(id, now - TimeDelta::days(30)), &[1]).await?;
+        archive.ship(&metadata_at(id, now - TimeDelta::days(20)), &[2]).await?;
+        assert_eq!(archive.prune(id, now).await?, 1);
+
+        let remaining = archive.list(id).await?;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].taken_at, (now - TimeDelta::days(20)).trunc_subsecs(3));
+        Ok(())
+    }
+}
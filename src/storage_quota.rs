@@ -0,0 +1,238 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! How many bytes each user stores, and an optional cap on it. Every write adjusts the
+//! document's `stored_bytes` and its owner's row in `storage_usage` in the same transaction
+//! as the write itself. Snapshots count wherever they live, along with logged updates,
+//! saved versions and attachments. Documents without an owner count against nobody.
+
+use crate::migrations::{Component, Migration};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::env;
+use std::fmt;
+use uuid::Uuid;
+
+const STORAGE_QUOTA_ENV: &str = "COLLABORATE_STORAGE_QUOTA_BYTES";
+
+/// The per-user cap set with `COLLABORATE_STORAGE_QUOTA_BYTES`; `None` when unset.
+pub fn quota_from_env() -> Result<Option<i64>> {
+    match env::var(STORAGE_QUOTA_ENV) {
+        Ok(bytes) => Ok(Some(bytes.parse().context(format!("{} must be a number of bytes", STORAGE_QUOTA_ENV))?)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct StorageUsage {
+    pub used_bytes: i64,
+    /// `None` when storage is unlimited.
+    pub quota_bytes: Option<i64>,
+}
+
+/// Returned by writes that would take the owner of a document past their quota.
+#[derive(Debug, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    /// What the owner stores without the refused write.
+    pub used_bytes: i64,
+    pub quota_bytes: i64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Storage quota of {} bytes exceeded ({} bytes in use)", self.quota_bytes, self.used_bytes)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// The usage table, and counts of what documents stored before it existed. Applied after
+/// the document tables are in place.
+pub(crate) const SCHEMA: Component = Component {
+    name: "storage_quota",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS storage_usage (
+                owner_id UUID PRIMARY KEY,
+                bytes BIGINT NOT NULL
+            )",
+            // Offloaded snapshots have no crdt_data to measure, so the size is kept alongside.
+            "ALTER TABLE documents_content ADD COLUMN IF NOT EXISTS snapshot_bytes BIGINT",
+            "UPDATE documents_content SET snapshot_bytes = octet_length(crdt_data)
+             WHERE snapshot_bytes IS NULL AND crdt_data IS NOT NULL",
+            // Documents counted so far have a size; new ones start at zero once the default is set.
+            "ALTER TABLE documents_metadata ADD COLUMN IF NOT EXISTS stored_bytes BIGINT",
+            "WITH counted AS (
+                 UPDATE documents_metadata m SET stored_bytes =
+                     COALESCE((SELECT c.snapshot_bytes FROM documents_content c WHERE c.document_id = m.id), 0)
+                     + COALESCE((SELECT sum(octet_length(u.data))::BIGINT FROM document_updates u WHERE u.document_id = m.id), 0)
+                     + COALESCE((SELECT sum(octet_length(v.crdt_data))::BIGINT FROM document_versions v WHERE v.document_id = m.id), 0)
+                 WHERE m.stored_bytes IS NULL
+                 RETURNING m.owner_id, m.stored_bytes
+             )
+             INSERT INTO storage_usage (owner_id, bytes)
+             SELECT owner_id, sum(stored_bytes)::BIGINT FROM counted WHERE owner_id IS NOT NULL GROUP BY owner_id
+             ON CONFLICT (owner_id) DO UPDATE SET bytes = storage_usage.bytes + excluded.bytes",
+            "ALTER TABLE documents_metadata ALTER COLUMN stored_bytes SET DEFAULT 0",
+        ],
+        down: &[
+            "ALTER TABLE documents_metadata DROP COLUMN IF EXISTS stored_bytes",
+            "ALTER TABLE documents_content DROP COLUMN IF EXISTS snapshot_bytes",
+            "DROP TABLE IF EXISTS storage_usage",
+        ],
+    }],
+};
+
+/// Counts attachments uploaded before they were charged for. The attachments component
+/// applies this as its version 2, once its table is in place.
+pub(crate) const COUNT_ATTACHMENTS: Migration = Migration {
+    version: 2,
+    description: "count attachments toward storage quotas",
+    up: &[
+        "ALTER TABLE attachments ADD COLUMN IF NOT EXISTS counted BOOLEAN",
+        "WITH counted AS (
+             UPDATE attachments SET counted = true WHERE counted IS NULL RETURNING document_id, size_bytes
+         ), charged AS (
+             UPDATE documents_metadata m SET stored_bytes = m.stored_bytes + a.bytes
+             FROM (SELECT document_id, sum(size_bytes)::BIGINT AS bytes FROM counted GROUP BY document_id) a
+             WHERE m.id = a.document_id
+             RETURNING m.owner_id, a.bytes
+         )
+         INSERT INTO storage_usage (owner_id, bytes)
+         SELECT owner_id, sum(bytes)::BIGINT FROM charged WHERE owner_id IS NOT NULL GROUP BY owner_id
+         ON CONFLICT (owner_id) DO UPDATE SET bytes = storage_usage.bytes + excluded.bytes",
+        "ALTER TABLE attachments ALTER COLUMN counted SET DEFAULT true",
+    ],
+    down: &["ALTER TABLE attachments DROP COLUMN IF EXISTS counted"],
+};
+
+/// Adds `delta` bytes to a document and to its owner. Growth that takes the owner past
+/// `quota` fails with `QuotaExceeded`, leaving the caller's transaction to roll back;
+/// shrinking always succeeds.
+pub(crate) async fn charge(tx: &mut Transaction<'_, Postgres>, doc_id: Uuid, delta: i64, quota: Option<i64>) -> Result<()> {
+    if delta == 0 {
+        return Ok(());
+    }
+    let owner_id: Option<Option<Uuid>> = sqlx::query_scalar(
+            "UPDATE documents_metadata SET stored_bytes = stored_bytes + $1 WHERE id = $2 RETURNING owner_id"
+        )
+        .bind(delta)
+        .bind(doc_id)
+        .fetch_optional(&mut **tx)
+        .await
+        .context(format!("Failed to count storage of document ID {}", doc_id))?;
+    let Some(Some(owner_id)) = owner_id else {
+        return Ok(());
+    };
+    let used_bytes: i64 = sqlx::query_scalar(
+            "INSERT INTO storage_usage (owner_id, bytes) VALUES ($1, $2)
+             ON CONFLICT (owner_id) DO UPDATE SET bytes = storage_usage.bytes + excluded.bytes
+             RETURNING bytes"
+        )
+        .bind(owner_id)
+        .bind(delta)
+        .fetch_one(&mut **tx)
+        .await
+        .context(format!("Failed to count storage of user ID {}", owner_id))?;
+    match quota {
+        Some(quota_bytes) if delta > 0 && used_bytes > quota_bytes => {
+            Err(QuotaExceeded { used_bytes: used_bytes - delta, quota_bytes }.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns what the given documents store to their owners, before they are deleted for good.
+pub(crate) async fn release(tx: &mut Transaction<'_, Postgres>, doc_ids: &[Uuid]) -> Result<()> {
+    sqlx::query(
+            "UPDATE storage_usage u SET bytes = u.bytes - d.bytes
+             FROM (SELECT owner_id, sum(stored_bytes)::BIGINT AS bytes FROM documents_metadata
+                   WHERE id = ANY($1) AND owner_id IS NOT NULL GROUP BY owner_id) d
+             WHERE u.owner_id = d.owner_id"
+        )
+        .bind(doc_ids)
+        .execute(&mut **tx)
+        .await
+        .context("Failed to release storage of purged documents")?;
+    Ok(())
+}
+
+/// Bytes stored by documents `owner_id` owns.
+pub(crate) async fn used_bytes(pool: &PgPool, owner_id: Uuid) -> Result<i64> {
+    let used: Option<i64> = sqlx::query_scalar("SELECT bytes FROM storage_usage WHERE owner_id = $1")
+        .bind(owner_id)
+        .fetch_optional(pool)
+        .await
+        .context(format!("Failed to query storage used by user ID {}", owner_id))?;
+    Ok(used.unwrap_or(0))
+}
+
+/// Bytes stored by every owner's documents together.
+pub(crate) async fn total_bytes(pool: &PgPool) -> Result<i64> {
+    sqlx::query_scalar("SELECT CAST(COALESCE(SUM(bytes), 0) AS BIGINT) FROM storage_usage")
+        .fetch_one(pool)
+        .await
+        .context("Failed to query total storage used")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Manager;
+    use crate::document_service::DocumentService;
+    use chrono::{TimeDelta, Utc};
+    use std::sync::Arc;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[tokio::test]
+    async fn test_storage_accounting() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager).await?.with_storage_quota(100);
+        let owner = Uuid::new_v4();
+        let usage = |used_bytes| StorageUsage { used_bytes, quota_bytes: Some(100) };
+        assert_eq!(doc_service.storage_usage(owner).await?, usage(0));
+
+        // A new snapshot replaces the old one; logged updates add what they insert.
+        let doc = doc_service.create_document_for("Notes", Some(owner)).await?;
+        doc_service.update_document_content(doc.id, vec![b'a'; 40]).await?;
+        doc_service.update_document_content(doc.id, vec![b'b'; 30]).await?;
+        assert_eq!(doc_service.storage_usage(owner).await?, usage(30));
+        let mut edited = vec![b'b'; 30];
+        edited.extend_from_slice(b"12345");
+        doc_service.append_document_update(doc.id, &[b'b'; 30], edited.clone(), None).await?;
+        assert_eq!(doc_service.storage_usage(owner).await?, usage(35));
+        assert!(doc_service.compact_document(doc.id).await?);
+        assert_eq!(doc_service.storage_usage(owner).await?, usage(35));
+
+        // Going over the quota refuses the write and leaves nothing behind.
+        doc_service.create_version(doc.id, Some(owner)).await?;
+        let refused = doc_service.create_version(doc.id, Some(owner)).await.unwrap_err();
+        assert_eq!(refused.downcast_ref::<QuotaExceeded>(), Some(&QuotaExceeded { used_bytes: 70, quota_bytes: 100 }));
+        assert_eq!(doc_service.list_versions(doc.id).await?.len(), 1);
+        assert_eq!(doc_service.storage_usage(owner).await?, usage(70));
+
+        // Shrinking is always allowed, and purging returns everything.
+        doc_service.update_document_content(doc.id, vec![b'c'; 5]).await?;
+        assert_eq!(doc_service.storage_usage(owner).await?, usage(40));
+        assert!(doc_service.delete_document(doc.id).await?);
+        while !doc_service.purge_deleted(Utc::now() + TimeDelta::seconds(1), 1000).await?.is_empty() {}
+        assert_eq!(doc_service.storage_usage(owner).await?, usage(0));
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Batched catch-up for clients that were offline, such as mobile apps: one request
+//! names every document the client holds and the sequence number it last saw, and
+//! one response carries everything that changed since.
+//!
+//! Document content is stored whole, so a changed document is sent as its latest
+//! content rather than as the individual updates the client missed.
+
+use crate::consistency::ReadConsistency;
+use crate::document_service::DocumentService;
+use crate::validation::{FieldError, FieldErrors, Validate};
+use anyhow::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+pub const MAX_SYNC_DOCUMENTS: usize = 500;
+/// Content beyond this is left for a follow-up request, so one sync can't stall on a slow link.
+const RESPONSE_CONTENT_BUDGET: usize = 8 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct SyncRequest {
+    /// Sequence number of the version the client holds, per document; 0 for none.
+    pub documents: HashMap<Uuid, i64>,
+}
+
+impl Validate for SyncRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            "documents",
+            self.documents.len() <= MAX_SYNC_DOCUMENTS,
+            FieldError::new("sync-too-many-documents").with_arg("max", MAX_SYNC_DOCUMENTS),
+        );
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SyncedDocument {
+    pub id: Uuid,
+    pub seq: i64,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Base64 of the latest content.
+    pub content: String,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct SyncResponse {
+    /// Documents that changed since the client's sequence number.
+    pub documents: Vec<SyncedDocument>,
+    /// Documents that were deleted or are no longer visible to the caller.
+    pub removed: Vec<Uuid>,
+    /// Set when the content budget ran out; the client should sync again.
+    pub more: bool,
+}
+
+/// Everything the client missed for the documents in `request`. Only those in
+/// `visible_documents` are sent, so pass the ones the caller may read; the rest are
+/// reported as removed.
+pub async fn sync(
+    doc_service: &DocumentService,
+    request: &SyncRequest,
+    visible_documents: Option<&[Uuid]>,
+) -> Result<SyncResponse> {
+    let requested: Vec<Uuid> = request
+        .documents
+        .keys()
+        .copied()
+        .filter(|id| visible_documents.is_none_or(|visible| visible.contains(id)))
+        .collect();
+    // Strong reads, so a client syncing right after an edit elsewhere sees it.
+    let mut current = doc_service.get_document_sequences(&requested, ReadConsistency::Strong).await?;
+    current.sort_by_key(|(metadata, _)| metadata.updated_at);
+
+    let mut response = SyncResponse {
+        removed: request
+            .documents
+            .keys()
+            .filter(|id| !current.iter().any(|(metadata, _)| metadata.id == **id))
+            .copied()
+            .collect(),
+        ..Default::default()
+    };
+    let mut budget = RESPONSE_CONTENT_BUDGET;
+    for (metadata, seq) in current {
+        if seq <= request.documents[&metadata.id] {
+            continue;
+        }
+        let Some(content) = doc_service.get_document_content(metadata.id).await? else {
+            continue;
+        };
+        // Always send at least one document, however large, so sync makes progress.
+        if content.crdt_data.len() > budget && !response.documents.is_empty() {
+            response.more = true;
+            break;
+        }
+        budget = budget.saturating_sub(content.crdt_data.len());
+        response.documents.push(SyncedDocument {
+            id: metadata.id,
+            seq,
+            name: metadata.name,
+            created_at: metadata.created_at,
+            updated_at: metadata.updated_at,
+            content: BASE64.encode(&content.crdt_data),
+        });
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Manager;
+    use crate::permissions::PermissionService;
+    use crate::user_service::UserService;
+    use std::sync::Arc;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[tokio::test]
+    async fn test_sync_returns_only_missed_changes() -> Result<()> {
+        let manager = Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?;
+        let doc_service = DocumentService::new(Arc::new(manager)).await?;
+        let stale = doc_service.create_document("Test Document for Sync").await?;
+        let current = doc_service.create_document("Test Document for Sync").await?;
+        let hidden = doc_service.create_document("Test Document for Sync").await?;
+        let deleted = Uuid::new_v4();
+
+        doc_service.update_document_content(stale.id, vec![1, 2]).await?;
+        let known = doc_service.get_document_sequences(&[current.id], ReadConsistency::Strong).await?[0].1;
+        let request = SyncRequest {
+            documents: HashMap::from([(stale.id, 0), (current.id, known), (hidden.id, 0), (deleted, 3)]),
+        };
+        let response = sync(&doc_service, &request, Some(&[stale.id, current.id])).await?;
+
+        assert_eq!(response.documents.len(), 1);
+        assert_eq!(response.documents[0].id, stale.id);
+        assert_eq!(response.documents[0].content, BASE64.encode([1, 2]));
+        let mut removed = response.removed;
+        removed.sort();
+        let mut expected = vec![hidden.id, deleted];
+        expected.sort();
+        assert_eq!(removed, expected);
+        assert!(!response.more);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_reports_other_users_private_documents_as_removed() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let users = UserService::new(manager.clone()).await?;
+        let permissions = PermissionService::new(manager).await?;
+        let suffix = Uuid::new_v4();
+        let owner = users.register_user("Owner", &format!("owner-{}@example.com", suffix), "correct horse battery").await?;
+        let other = users.register_user("Other", &format!("other-{}@example.com", suffix), "correct horse battery").await?;
+        let private = doc_service.create_document_for("Test Document for Sync", Some(owner.id)).await?;
+        doc_service.update_document_content(private.id, b"secret".to_vec()).await?;
+
+        // What `readable_documents` lets a signed-in user sync.
+        let request = SyncRequest { documents: HashMap::from([(private.id, 0)]) };
+        let accesses = permissions.document_accesses(Some(other.id), &[private.id]).await?;
+        let readable: Vec<Uuid> = request.documents.keys()
+            .copied()
+            .filter(|id| accesses.get(id).is_none_or(|access| access.read))
+            .collect();
+        let response = sync(&doc_service, &request, Some(&readable)).await?;
+
+        assert!(response.documents.is_empty());
+        assert_eq!(response.removed, vec![private.id]);
+        Ok(())
+    }
+}
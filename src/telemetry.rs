@@ -0,0 +1,101 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+//! Log output. Events are written to stderr as text, or as one JSON object per line with
+//! `COLLABORATE_LOG_FORMAT=json`, and filtered by `RUST_LOG` (`info` by default). Each HTTP
+//! request runs in a span carrying its request ID and, once authenticated, its user ID.
+
+use anyhow::{anyhow, bail, Result};
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::env;
+use std::time::Instant;
+use tracing::{info, info_span, Instrument};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+const LOG_FORMAT_ENV: &str = "COLLABORATE_LOG_FORMAT";
+const DEFAULT_FILTER: &str = "info";
+/// Identifies a request in logs; taken from the request if a proxy already assigned one.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    fn parse(value: Option<&str>) -> Result<Self> {
+        match value {
+            None | Some("text") => Ok(LogFormat::Text),
+            Some("json") => Ok(LogFormat::Json),
+            Some(other) => bail!("{} must be 'text' or 'json', not '{}'", LOG_FORMAT_ENV, other),
+        }
+    }
+}
+
+/// Installs the global subscriber. Call once, before anything logs.
+pub fn init() -> Result<()> {
+    let format = LogFormat::parse(env::var(LOG_FORMAT_ENV).ok().as_deref())?;
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    let installed = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).try_init(),
+    };
+    installed.map_err(|e| anyhow!("Failed to install the log subscriber: {}", e))
+}
+
+/// Middleware that runs each request in a span named by its request ID, logs how it
+/// finished and echoes the ID in the response. `tokens::authenticate` adds the user ID.
+pub async fn trace_requests(request: Request, next: Next) -> Response {
+    let request_id = request.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| (1..=MAX_REQUEST_ID_LENGTH).contains(&id.len()))
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_string);
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        user_id = tracing::field::Empty,
+    );
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.in_scope(|| {
+        info!(status = response.status().as_u16(), elapsed_ms = started.elapsed().as_millis() as u64, "Finished request");
+    });
+    if let Ok(id) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_format_parsing() {
+        assert_eq!(LogFormat::parse(None).unwrap(), LogFormat::Text);
+        assert_eq!(LogFormat::parse(Some("json")).unwrap(), LogFormat::Json);
+        assert!(LogFormat::parse(Some("xml")).is_err());
+    }
+}
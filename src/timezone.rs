@@ -0,0 +1,90 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::api_error::ApiError;
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use chrono::{DateTime, FixedOffset, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct TimezoneQuery {
+    tz: Option<String>,
+}
+
+/// The timezone a client asked timestamps to be rendered in via `?tz=<IANA name>`.
+/// Timestamps are always stored in UTC; this only changes the offset they are written with,
+/// so clients without timezone data can display them as-is.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RenderTimezone(Option<Tz>);
+
+impl RenderTimezone {
+    pub fn parse(name: &str) -> Option<Self> {
+        name.parse::<Tz>().ok().map(|tz| RenderTimezone(Some(tz)))
+    }
+
+    /// The IANA name of the timezone, or `None` when rendering in UTC.
+    pub fn name(&self) -> Option<&'static str> {
+        self.0.map(|tz| tz.name())
+    }
+
+    /// The same instant, expressed in the requested timezone's offset at that moment.
+    pub fn render(&self, time: DateTime<Utc>) -> DateTime<FixedOffset> {
+        match self.0 {
+            Some(tz) => time.with_timezone(&tz).fixed_offset(),
+            None => time.fixed_offset(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RenderTimezone {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Ok(Query(query)) = Query::<TimezoneQuery>::from_request_parts(parts, state).await else {
+            return Ok(RenderTimezone::default());
+        };
+        match query.tz {
+            Some(name) => RenderTimezone::parse(&name)
+                .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "invalid-timezone").with_arg("timezone", name)),
+            None => Ok(RenderTimezone::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_render_keeps_the_instant() {
+        let time = Utc.with_ymd_and_hms(2025, 7, 1, 12, 0, 0).unwrap();
+        let berlin = RenderTimezone::parse("Europe/Berlin").unwrap();
+        assert_eq!(berlin.render(time).to_rfc3339(), "2025-07-01T14:00:00+02:00");
+        assert_eq!(berlin.render(time), time);
+        assert_eq!(berlin.name(), Some("Europe/Berlin"));
+
+        // Offsets follow daylight saving for the instant being rendered.
+        let winter = Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 0).unwrap();
+        assert_eq!(berlin.render(winter).to_rfc3339(), "2025-01-01T13:00:00+01:00");
+
+        assert_eq!(RenderTimezone::default().render(time).to_rfc3339(), "2025-07-01T12:00:00+00:00");
+        assert!(RenderTimezone::parse("Mars/Olympus_Mons").is_none());
+    }
+}
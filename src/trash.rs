@@ -0,0 +1,87 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Empties the trash. Deleting a document only moves it to the trash (see
+//! `DocumentService::delete_document`); once it has been there for the retention window,
+//! set with `COLLABORATE_TRASH_RETENTION_DAYS` (30 by default), it is purged for good.
+//! Deletions awaiting a second approval lapse after `COLLABORATE_DELETION_APPROVAL_HOURS`
+//! (72 by default); see `DocumentService::request_deletion`.
+
+use crate::document_service::{self, DocumentService};
+use anyhow::{Context, Result};
+use chrono::{TimeDelta, Utc};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const TRASH_RETENTION_DAYS_ENV: &str = "COLLABORATE_TRASH_RETENTION_DAYS";
+const DELETION_APPROVAL_HOURS_ENV: &str = "COLLABORATE_DELETION_APPROVAL_HOURS";
+const PURGE_BATCH_SIZE: i64 = 100;
+
+#[derive(Clone, Debug)]
+pub struct TrashConfig {
+    /// How long deleted documents can still be restored.
+    pub retention: TimeDelta,
+    /// How long a deletion can await approval.
+    pub approval_window: TimeDelta,
+    pub interval: Duration,
+}
+
+impl TrashConfig {
+    pub fn from_env() -> Result<Self> {
+        let days = match env::var(TRASH_RETENTION_DAYS_ENV) {
+            Ok(days) => days.parse().context(format!("{} must be a number of days", TRASH_RETENTION_DAYS_ENV))?,
+            Err(_) => 30,
+        };
+        let approval_window = match env::var(DELETION_APPROVAL_HOURS_ENV) {
+            Ok(hours) => TimeDelta::hours(
+                hours.parse().context(format!("{} must be a number of hours", DELETION_APPROVAL_HOURS_ENV))?,
+            ),
+            Err(_) => document_service::DEFAULT_DELETION_APPROVAL_WINDOW,
+        };
+        Ok(TrashConfig { retention: TimeDelta::days(days), approval_window, interval: Duration::from_secs(60 * 60) })
+    }
+}
+
+/// Periodically purges documents that have outstayed the retention window in the trash, and
+/// expires deletions that went unapproved.
+pub async fn run_purger(doc_service: Arc<DocumentService>, config: TrashConfig) {
+    let mut ticker = tokio::time::interval(config.interval);
+    loop {
+        ticker.tick().await;
+        match purge_trash(&doc_service, &config).await {
+            Ok(0) => {}
+            Ok(purged) => info!("Purged {} documents from the trash", purged),
+            Err(e) => warn!("Purging the trash failed; will retry: {:#}", e),
+        }
+        match doc_service.expire_deletion_requests().await {
+            Ok(0) => {}
+            Ok(expired) => info!("Expired {} deletion requests nobody approved", expired),
+            Err(e) => warn!("Expiring deletion requests failed; will retry: {:#}", e),
+        }
+    }
+}
+
+async fn purge_trash(doc_service: &DocumentService, config: &TrashConfig) -> Result<usize> {
+    let deleted_before = Utc::now() - config.retention;
+    let mut purged = 0;
+    loop {
+        let batch = doc_service.purge_deleted(deleted_before, PURGE_BATCH_SIZE).await?;
+        purged += batch.len();
+        if (batch.len() as i64) < PURGE_BATCH_SIZE {
+            return Ok(purged);
+        }
+    }
+}
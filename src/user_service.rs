@@ -0,0 +1,2266 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::consistency::ReadConsistency;
+use crate::db::Manager;
+use crate::email::{Email, Mailer};
+use crate::i18n::{self, Locale};
+use crate::migrations::{self, Component, Migration};
+use crate::password::{self, Verification};
+use crate::preview::escape_html;
+use crate::search;
+use crate::validation;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+/// Prefix that makes bot tokens recognisable in logs and secret scanners.
+const BOT_TOKEN_PREFIX: &str = "cbt_";
+/// Likewise for the session tokens handed out at login.
+const SESSION_TOKEN_PREFIX: &str = "cst_";
+/// Likewise for the secrets kept in session cookies.
+const SESSION_COOKIE_PREFIX: &str = "csc_";
+/// Likewise for the tokens that identify guests.
+const GUEST_TOKEN_PREFIX: &str = "cgt_";
+/// Likewise for the tokens in email verification links.
+const EMAIL_VERIFICATION_TOKEN_PREFIX: &str = "cvt_";
+/// Likewise for the tokens in password reset links.
+const PASSWORD_RESET_TOKEN_PREFIX: &str = "crt_";
+/// Likewise for the tokens in magic login links.
+const MAGIC_LINK_TOKEN_PREFIX: &str = "cml_";
+/// Likewise for the secrets that tie a magic link to the device that asked for it.
+const MAGIC_LINK_DEVICE_PREFIX: &str = "cmd_";
+const SESSION_LIFETIME: TimeDelta = TimeDelta::days(30);
+/// Cookie sessions end after this long without use.
+pub const COOKIE_SESSION_LIFETIME: TimeDelta = TimeDelta::days(14);
+/// Cookie sessions are extended at most this often, so every request need not write.
+const COOKIE_SESSION_SLIDE_INTERVAL: TimeDelta = TimeDelta::hours(1);
+const EMAIL_VERIFICATION_LIFETIME: TimeDelta = TimeDelta::days(2);
+const PASSWORD_RESET_LIFETIME: TimeDelta = TimeDelta::hours(1);
+pub const MAGIC_LINK_LIFETIME: TimeDelta = TimeDelta::minutes(15);
+const MAGIC_LINK_ENV: &str = "COLLABORATE_MAGIC_LINK_LOGIN";
+/// Consecutive wrong passwords after which an account is locked.
+pub const MAX_FAILED_LOGINS: i32 = 5;
+/// How long a locked account refuses logins, even with the right password.
+pub const LOCKOUT_DURATION: TimeDelta = TimeDelta::minutes(15);
+pub const MIN_PASSWORD_LENGTH: usize = 10;
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountKind {
+    Human,
+    /// An automation account. Bots cannot log in with a password and need no verified
+    /// email; they act only through scoped tokens.
+    Bot,
+}
+
+impl AccountKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AccountKind::Human => "human",
+            AccountKind::Bot => "bot",
+        }
+    }
+}
+
+impl FromStr for AccountKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "human" => Ok(AccountKind::Human),
+            "bot" => Ok(AccountKind::Bot),
+            other => Err(anyhow!("Unknown account kind '{}'", other)),
+        }
+    }
+}
+
+/// How many accounts there are, for server statistics.
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+pub struct UserCounts {
+    pub total: i64,
+    pub bots: i64,
+    /// Neither deactivated nor anonymized.
+    pub active: i64,
+    /// Admins and superadmins.
+    pub administrators: i64,
+}
+
+/// What an account may administer. Each role can do everything the ones before it can.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserRole {
+    #[default]
+    User,
+    /// Manages accounts and sees how the server is doing.
+    Admin,
+    /// Also decides who else is an administrator.
+    Superadmin,
+}
+
+impl UserRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserRole::User => "user",
+            UserRole::Admin => "admin",
+            UserRole::Superadmin => "superadmin",
+        }
+    }
+}
+
+impl FromStr for UserRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "user" => Ok(UserRole::User),
+            "admin" => Ok(UserRole::Admin),
+            "superadmin" => Ok(UserRole::Superadmin),
+            other => Err(anyhow!("Unknown role '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub id: Uuid,
+    pub kind: AccountKind,
+    pub display_name: String,
+    pub email: Option<String>,
+    /// Whether the owner of `email` followed a verification link sent to it.
+    pub email_verified: bool,
+    /// The account that created this one; set for bots.
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    /// When the account's personal data was replaced with placeholders.
+    pub anonymized_at: Option<DateTime<Utc>>,
+    /// When the account was deactivated, for disuse or by an administrator. Logging in
+    /// reactivates it unless it is `suspended`.
+    pub deactivated_at: Option<DateTime<Utc>>,
+    /// Whether an administrator deactivated the account, which only they can undo.
+    pub suspended: bool,
+    pub role: UserRole,
+}
+
+impl User {
+    pub fn is_bot(&self) -> bool {
+        self.kind == AccountKind::Bot
+    }
+
+    pub fn is_anonymized(&self) -> bool {
+        self.anonymized_at.is_some()
+    }
+}
+
+#[derive(FromRow)]
+struct UserRow {
+    id: Uuid,
+    kind: String,
+    display_name: String,
+    email: Option<String>,
+    email_verified: bool,
+    created_by: Option<Uuid>,
+    created_at: DateTime<Utc>,
+    anonymized_at: Option<DateTime<Utc>>,
+    deactivated_at: Option<DateTime<Utc>>,
+    suspended: bool,
+    role: String,
+}
+
+// What a login needs to know about the account before checking the password.
+#[derive(FromRow)]
+struct CredentialsRow {
+    id: Uuid,
+    password_hash: Option<String>,
+    failed_logins: i32,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<UserRow> for User {
+    type Error = anyhow::Error;
+
+    fn try_from(row: UserRow) -> Result<Self> {
+        Ok(User {
+            id: row.id,
+            kind: row.kind.parse()?,
+            display_name: row.display_name,
+            email: row.email,
+            email_verified: row.email_verified,
+            created_by: row.created_by,
+            created_at: row.created_at,
+            anonymized_at: row.anonymized_at,
+            deactivated_at: row.deactivated_at,
+            suspended: row.suspended,
+            role: row.role.parse()?,
+        })
+    }
+}
+
+/// Something a bot token is allowed to do. Written as `document:<id>:<action>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    ReadDocument(Uuid),
+    /// Submit updates to a document without necessarily being able to read it.
+    AppendDocument(Uuid),
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::ReadDocument(doc_id) => write!(f, "document:{}:read", doc_id),
+            Scope::AppendDocument(doc_id) => write!(f, "document:{}:append", doc_id),
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let [resource, id, action] = parts[..] else {
+            bail!("Malformed scope '{}'; expected document:<id>:<action>", s);
+        };
+        if resource != "document" {
+            bail!("Unknown scope resource '{}'", resource);
+        }
+        let doc_id = Uuid::parse_str(id).context(format!("Malformed document ID in scope '{}'", s))?;
+        match action {
+            "read" => Ok(Scope::ReadDocument(doc_id)),
+            "append" => Ok(Scope::AppendDocument(doc_id)),
+            other => bail!("Unknown scope action '{}'", other),
+        }
+    }
+}
+
+/// A caller's standing on a document, for rules finer than read and write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentRole {
+    #[default]
+    Editor,
+    Owner,
+}
+
+impl DocumentRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DocumentRole::Editor => "editor",
+            DocumentRole::Owner => "owner",
+        }
+    }
+}
+
+impl FromStr for DocumentRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "editor" => Ok(DocumentRole::Editor),
+            "owner" => Ok(DocumentRole::Owner),
+            other => Err(anyhow!("Unknown document role '{}'", other)),
+        }
+    }
+}
+
+/// What a caller may do with one document.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DocumentAccess {
+    pub read: bool,
+    pub write: bool,
+    /// May add comments, which does not require `write`.
+    pub comment: bool,
+    pub role: DocumentRole,
+}
+
+impl DocumentAccess {
+    pub const FULL: DocumentAccess = DocumentAccess { read: true, write: true, comment: true, role: DocumentRole::Owner };
+
+    pub fn any(&self) -> bool {
+        self.read || self.write
+    }
+}
+
+/// A bot authenticated by one of its tokens.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BotPrincipal {
+    pub bot_id: Uuid,
+    pub token_id: Uuid,
+    pub scopes: Vec<Scope>,
+}
+
+impl BotPrincipal {
+    /// Documents this bot can read.
+    pub fn visible_documents(&self) -> Vec<Uuid> {
+        self.scopes
+            .iter()
+            .filter_map(|scope| match scope {
+                Scope::ReadDocument(doc_id) => Some(*doc_id),
+                Scope::AppendDocument(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn document_access(&self, doc_id: Uuid) -> DocumentAccess {
+        DocumentAccess {
+            read: self.scopes.contains(&Scope::ReadDocument(doc_id)),
+            write: self.scopes.contains(&Scope::AppendDocument(doc_id)),
+            comment: false,
+            role: DocumentRole::Editor,
+        }
+    }
+}
+
+/// A newly issued bot token. `secret` is shown once and never stored.
+pub struct IssuedToken {
+    pub token_id: Uuid,
+    pub secret: String,
+}
+
+/// A session started by logging in. `secret` is shown once and never stored.
+pub struct IssuedSession {
+    pub session_id: Uuid,
+    pub user: User,
+    pub secret: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// How a client holds on to its session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionKind {
+    /// A refresh token, exchanged for short-lived access tokens.
+    Token,
+    /// A cookie, checked against the database on every request. Its expiry slides with use.
+    Cookie,
+}
+
+impl SessionKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SessionKind::Token => "token",
+            SessionKind::Cookie => "cookie",
+        }
+    }
+
+    fn secret_prefix(&self) -> &'static str {
+        match self {
+            SessionKind::Token => SESSION_TOKEN_PREFIX,
+            SessionKind::Cookie => SESSION_COOKIE_PREFIX,
+        }
+    }
+
+    fn lifetime(&self) -> TimeDelta {
+        match self {
+            SessionKind::Token => SESSION_LIFETIME,
+            SessionKind::Cookie => COOKIE_SESSION_LIFETIME,
+        }
+    }
+}
+
+impl FromStr for SessionKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "token" => Ok(SessionKind::Token),
+            "cookie" => Ok(SessionKind::Cookie),
+            other => Err(anyhow!("Unknown session kind '{}'", other)),
+        }
+    }
+}
+
+/// A live session, as listed to its owner.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub kind: SessionKind,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A session cookie resolved to its account.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CookieSession {
+    pub session_id: Uuid,
+    pub user_id: Uuid,
+    /// The new expiry, if this use extended the session; the cookie should be reissued.
+    pub extended_until: Option<DateTime<Utc>>,
+}
+
+/// A pseudonymous collaborator without an account, identified by a token kept in a cookie.
+/// The ID stays the same across sessions so edits and cursors stay attributed to them.
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+pub struct Guest {
+    pub id: Uuid,
+    pub display_name: String,
+    /// Cursor colour as `#rrggbb`.
+    pub color: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A newly created guest. `secret` is shown once and never stored.
+pub struct IssuedGuest {
+    pub guest: Guest,
+    pub secret: String,
+}
+
+/// Why a registration was refused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegistrationError {
+    InvalidDisplayName,
+    InvalidEmail,
+    WeakPassword,
+    EmailTaken,
+}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrationError::InvalidDisplayName => {
+                write!(f, "Display name must be 1 to {} characters", MAX_DISPLAY_NAME_LENGTH)
+            }
+            RegistrationError::InvalidEmail => write!(f, "Email address is malformed"),
+            RegistrationError::WeakPassword => write!(f, "Password must be at least {} characters", MIN_PASSWORD_LENGTH),
+            RegistrationError::EmailTaken => write!(f, "An account with this email address already exists"),
+        }
+    }
+}
+
+impl std::error::Error for RegistrationError {}
+
+/// Who an identity provider says signed in; see `UserService::login_with_identity`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExternalIdentity {
+    /// The provider's configured name, e.g. `google`.
+    pub provider: String,
+    /// The provider's ID for the account. Unlike the email address, it never changes.
+    pub subject: String,
+    pub email: Option<String>,
+    /// Whether the provider vouches that the account owns `email`.
+    pub email_verified: bool,
+    pub display_name: Option<String>,
+}
+
+/// Why a login through an identity provider was refused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FederatedLoginError {
+    /// The identity is linked to an account other than the signed-in one.
+    LinkedElsewhere,
+    /// An account uses the identity's email address but has not verified it, so it may not
+    /// be the same person's; its owner has to sign in and link the identity themselves.
+    EmailTaken,
+}
+
+impl fmt::Display for FederatedLoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FederatedLoginError::LinkedElsewhere => write!(f, "The identity is linked to another account"),
+            FederatedLoginError::EmailTaken => write!(f, "An unverified account already uses this email address"),
+        }
+    }
+}
+
+impl std::error::Error for FederatedLoginError {}
+
+/// Why a login with an otherwise acceptable request was refused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthenticationError {
+    /// Too many consecutive wrong passwords; no password is checked until `until`.
+    AccountLocked { until: DateTime<Utc> },
+}
+
+impl fmt::Display for AuthenticationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthenticationError::AccountLocked { until } => write!(f, "Account is locked until {}", until),
+        }
+    }
+}
+
+impl std::error::Error for AuthenticationError {}
+
+impl RegistrationError {
+    /// The i18n message key shown to users.
+    pub fn message_key(&self) -> &'static str {
+        match self {
+            RegistrationError::InvalidDisplayName => "invalid-display-name",
+            RegistrationError::InvalidEmail => "invalid-email",
+            RegistrationError::WeakPassword => "weak-password",
+            RegistrationError::EmailTaken => "email-taken",
+        }
+    }
+}
+
+// The address is only confirmed by whoever delivers mail to it; see `validation::is_email`.
+fn normalize_email(email: &str) -> Option<String> {
+    let email = email.trim().to_lowercase();
+    validation::is_email(&email).then_some(email)
+}
+
+pub(crate) fn generate_secret(prefix: &str) -> String {
+    let mut secret = [0u8; 32];
+    rand::rng().fill_bytes(&mut secret);
+    format!("{}{}", prefix, hex::encode(secret))
+}
+
+/// Stand-in display name for an anonymized account. Derived from the account ID, which
+/// is kept so documents, bots and audit records still point at the same account.
+fn anonymized_display_name(user_id: Uuid) -> String {
+    format!("Deleted user {}", &user_id.simple().to_string()[..8])
+}
+
+pub(crate) fn hash_token(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Reads `COLLABORATE_MAGIC_LINK_LOGIN`; magic links are off unless it is `true`.
+pub fn magic_links_from_env() -> Result<bool> {
+    match std::env::var(MAGIC_LINK_ENV) {
+        Ok(enabled) => enabled.parse().context(format!("{} must be true or false", MAGIC_LINK_ENV)),
+        Err(_) => Ok(false),
+    }
+}
+
+/// A secret for the device asking for a magic link. It stays on that device, and the
+/// link only signs in whoever presents it along with the link's token.
+pub fn magic_link_device_secret() -> String {
+    generate_secret(MAGIC_LINK_DEVICE_PREFIX)
+}
+
+// Human accounts that are live and not the only way into a document shared with others.
+// Such owners are left alone until they hand their documents over.
+const INACTIVITY_CANDIDATE: &str = "kind = 'human' AND anonymized_at IS NULL AND deactivated_at IS NULL
+    AND COALESCE(last_login_at, created_at) < $1
+    AND NOT EXISTS (
+        SELECT 1 FROM documents_metadata d JOIN document_permissions s ON s.document_id = d.id
+        WHERE d.owner_id = users.id AND d.workspace_id IS NULL AND s.user_id <> users.id
+    )";
+
+fn render_inactivity_warning(locale: Locale, to: &str, deactivate_on: DateTime<Utc>) -> Email {
+    let body = i18n::message(locale, "inactive-account-email-body", &[("date", deactivate_on.format("%Y-%m-%d").to_string())]);
+    Email {
+        to: to.to_string(),
+        subject: i18n::message(locale, "inactive-account-email-subject", &[]),
+        text: format!("{}\n", body),
+        html: format!("<html><body><p>{}</p></body></html>", escape_html(&body)),
+        unsubscribe_url: None,
+    }
+}
+
+// An email asking the recipient to follow `link`. `kind` prefixes the message keys, e.g.
+// `verify-email` for `verify-email-subject`, `verify-email-body` and `verify-email-action`.
+fn render_link_email(locale: Locale, kind: &str, to: &str, link: &str) -> Email {
+    let body = i18n::message(locale, &format!("{}-body", kind), &[]);
+    let action = i18n::message(locale, &format!("{}-action", kind), &[]);
+    Email {
+        to: to.to_string(),
+        subject: i18n::message(locale, &format!("{}-subject", kind), &[]),
+        text: format!("{}\n\n{}: {}\n", body, action, link),
+        html: format!(
+            "<html><body><p>{}</p><p><a href=\"{}\">{}</a></p></body></html>",
+            escape_html(&body),
+            escape_html(link),
+            escape_html(&action)
+        ),
+        unsubscribe_url: None,
+    }
+}
+
+#[derive(Clone)]
+pub struct UserService {
+    db_manager: Arc<Manager>,
+    /// Sends verification links, which point at `public_url`. Unset when email is disabled.
+    mailer: Option<Arc<dyn Mailer>>,
+    public_url: String,
+    /// Whether accounts may sign in with links emailed to them instead of passwords.
+    magic_links: bool,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "users",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS users (
+                id UUID PRIMARY KEY,
+                kind TEXT NOT NULL,
+                display_name TEXT NOT NULL,
+                email TEXT UNIQUE,
+                created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+                created_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS bot_tokens (
+                id UUID PRIMARY KEY,
+                bot_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                token_hash TEXT NOT NULL UNIQUE,
+                scopes TEXT[] NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                expires_at TIMESTAMPTZ,
+                revoked_at TIMESTAMPTZ
+            )",
+            // Columns added after the initial schema.
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS anonymized_at TIMESTAMPTZ",
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS password_hash TEXT",
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified BOOL NOT NULL DEFAULT false",
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS failed_logins INT NOT NULL DEFAULT 0",
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS last_login_at TIMESTAMPTZ",
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS inactivity_warned_at TIMESTAMPTZ",
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS deactivated_at TIMESTAMPTZ",
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS locked_until TIMESTAMPTZ",
+            // A link verifies the address it was sent to, so changing the address voids it.
+            "CREATE TABLE IF NOT EXISTS email_verifications (
+                token_hash TEXT PRIMARY KEY,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                email TEXT NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS password_reset_tokens (
+                token_hash TEXT PRIMARY KEY,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                created_at TIMESTAMPTZ NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS magic_link_tokens (
+                token_hash TEXT PRIMARY KEY,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                device_hash TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS user_sessions (
+                id UUID PRIMARY KEY,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                token_hash TEXT NOT NULL UNIQUE,
+                created_at TIMESTAMPTZ NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL,
+                revoked_at TIMESTAMPTZ
+            )",
+            "ALTER TABLE user_sessions ADD COLUMN IF NOT EXISTS previous_token_hash TEXT",
+            "ALTER TABLE user_sessions ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'token'",
+            "CREATE TABLE IF NOT EXISTS guests (
+                id UUID PRIMARY KEY,
+                token_hash TEXT NOT NULL UNIQUE,
+                display_name TEXT NOT NULL,
+                color TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                last_seen_at TIMESTAMPTZ NOT NULL
+            )",
+        ],
+        down: &[
+            "DROP TABLE IF EXISTS guests",
+            "DROP TABLE IF EXISTS user_sessions",
+            "DROP TABLE IF EXISTS magic_link_tokens",
+            "DROP TABLE IF EXISTS password_reset_tokens",
+            "DROP TABLE IF EXISTS email_verifications",
+            "DROP TABLE IF EXISTS bot_tokens",
+            "DROP TABLE IF EXISTS users",
+        ],
+    }, Migration {
+        version: 2,
+        description: "federated identities",
+        up: &[
+            // An account at an identity provider, by the provider's stable ID for it.
+            "CREATE TABLE IF NOT EXISTS federated_identities (
+                provider TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                created_at TIMESTAMPTZ NOT NULL,
+                last_login_at TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (provider, subject)
+            )",
+            "CREATE INDEX IF NOT EXISTS federated_identities_by_user ON federated_identities (user_id)",
+        ],
+        down: &["DROP TABLE IF EXISTS federated_identities"],
+    }, Migration {
+        version: 3,
+        description: "roles",
+        up: &[
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'user'",
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS suspended BOOL NOT NULL DEFAULT false",
+        ],
+        down: &[
+            "ALTER TABLE users DROP COLUMN IF EXISTS suspended",
+            "ALTER TABLE users DROP COLUMN IF EXISTS role",
+        ],
+    }],
+};
+
+impl UserService {
+    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
+        let service = UserService { db_manager, mailer: None, public_url: String::new(), magic_links: false };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    /// Emails verification links to new accounts, pointing at the server's `public_url`.
+    pub fn with_mailer(mut self, mailer: Arc<dyn Mailer>, public_url: String) -> Self {
+        self.mailer = Some(mailer);
+        self.public_url = public_url;
+        self
+    }
+
+    /// Whether verification links can be sent.
+    pub fn sends_email(&self) -> bool {
+        self.mailer.is_some()
+    }
+
+    /// Lets accounts sign in with magic links, which only works once a mailer is set too.
+    pub fn with_magic_links(mut self) -> Self {
+        self.magic_links = true;
+        self
+    }
+
+    /// Whether magic links can be requested.
+    pub fn magic_links_enabled(&self) -> bool {
+        self.magic_links && self.mailer.is_some()
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("User service schema initialized.");
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn get_user(&self, user_id: Uuid) -> Result<Option<User>> {
+        let row: Option<UserRow> = sqlx::query_as(
+                "SELECT id, kind, display_name, email, email_verified, created_by, created_at, anonymized_at, deactivated_at, suspended, role FROM users WHERE id = $1"
+            )
+            .bind(user_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to query user ID {}", user_id))?;
+        row.map(User::try_from).transpose()
+    }
+
+    /// Accounts whose display name contains `query`, or whose email starts with it.
+    #[instrument(skip_all)]
+    pub async fn search_users(&self, query: &str, limit: i64, consistency: ReadConsistency) -> Result<Vec<User>> {
+        let consistency = consistency.resolve_in(&self.db_manager.pool).await?;
+        let rows: Vec<UserRow> = sqlx::query_as(&format!(
+                "SELECT id, kind, display_name, email, email_verified, created_by, created_at, anonymized_at, deactivated_at, suspended, role FROM users{}
+                 WHERE anonymized_at IS NULL AND (display_name ILIKE $1 OR email ILIKE $2)
+                 ORDER BY display_name LIMIT $3",
+                consistency.as_of_clause()
+            ))
+            .bind(search::like_pattern(query))
+            .bind(format!("{}%", search::escape_like(query)))
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to search users for '{}'", query))?;
+        rows.into_iter().map(User::try_from).collect()
+    }
+
+    /// Creates a human account that logs in with `email` and `password`.
+    /// Fails with `RegistrationError` if the details are unacceptable or the email is in use.
+    #[instrument(skip_all)]
+    pub async fn register_user(&self, display_name: &str, email: &str, password: &str) -> Result<User> {
+        let display_name = display_name.trim();
+        if display_name.is_empty() || display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+            return Err(RegistrationError::InvalidDisplayName.into());
+        }
+        let email = normalize_email(email).ok_or(RegistrationError::InvalidEmail)?;
+        if password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(RegistrationError::WeakPassword.into());
+        }
+
+        let user = User {
+            id: Uuid::new_v4(),
+            kind: AccountKind::Human,
+            display_name: display_name.to_string(),
+            email: Some(email),
+            created_by: None,
+            created_at: DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap_or_default(),
+            email_verified: false,
+            anonymized_at: None,
+            deactivated_at: None,
+            suspended: false,
+            role: UserRole::User,
+        };
+        let result = sqlx::query(
+                "INSERT INTO users (id, kind, display_name, email, created_at, password_hash) VALUES ($1, $2, $3, $4, $5, $6)"
+            )
+            .bind(user.id)
+            .bind(user.kind.as_str())
+            .bind(&user.display_name)
+            .bind(&user.email)
+            .bind(user.created_at)
+            .bind(password::hash(password)?)
+            .execute(&*self.db_manager.pool)
+            .await;
+        match result {
+            Ok(_) => {}
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Err(RegistrationError::EmailTaken.into()),
+            Err(e) => return Err(anyhow::Error::new(e).context("Failed to register user")),
+        }
+        info!("Registered user with ID: {}", user.id);
+        Ok(user)
+    }
+
+    /// Emails a link that verifies the account's address, written in `locale`. Returns false
+    /// if email is disabled or there is nothing to verify, e.g. the address is verified already.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn send_email_verification(&self, user_id: Uuid, locale: Locale) -> Result<bool> {
+        let Some(mailer) = &self.mailer else {
+            return Ok(false);
+        };
+        let Some(user) = self.get_user(user_id).await? else {
+            return Ok(false);
+        };
+        if user.email_verified || user.is_bot() || user.is_anonymized() {
+            return Ok(false);
+        }
+        let Some(email) = user.email else {
+            return Ok(false);
+        };
+
+        let secret = generate_secret(EMAIL_VERIFICATION_TOKEN_PREFIX);
+        sqlx::query("INSERT INTO email_verifications (token_hash, user_id, email, expires_at) VALUES ($1, $2, $3, $4)")
+            .bind(hash_token(&secret))
+            .bind(user_id)
+            .bind(&email)
+            .bind(Utc::now() + EMAIL_VERIFICATION_LIFETIME)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to issue email verification for user ID {}", user_id))?;
+        let link = format!("{}/api/users/verify?token={}", self.public_url, secret);
+        mailer.send(&render_link_email(locale, "verify-email", &email, &link)).await?;
+        Ok(true)
+    }
+
+    /// Marks the address a verification link was sent to as verified. Each link works once;
+    /// returns the account, or `None` if the link is unknown, used, expired or for an
+    /// address the account no longer has.
+    #[instrument(skip_all)]
+    pub async fn verify_email(&self, token: &str) -> Result<Option<Uuid>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let claimed: Option<(Uuid, String)> = sqlx::query_as(
+                "DELETE FROM email_verifications WHERE token_hash = $1 AND expires_at > $2 RETURNING user_id, email"
+            )
+            .bind(hash_token(token))
+            .bind(Utc::now())
+            .fetch_optional(&mut *tx)
+            .await
+            .context("Failed to claim email verification")?;
+        let Some((user_id, email)) = claimed else {
+            return Ok(None);
+        };
+        let verified = sqlx::query("UPDATE users SET email_verified = true WHERE id = $1 AND email = $2 AND anonymized_at IS NULL")
+            .bind(user_id)
+            .bind(&email)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to verify email of user ID {}", user_id))?;
+        tx.commit().await.context(format!("Failed to commit email verification of user ID {}", user_id))?;
+        if verified.rows_affected() == 0 {
+            return Ok(None);
+        }
+        info!("Verified email of user ID {}", user_id);
+        Ok(Some(user_id))
+    }
+
+    /// Emails a link for choosing a new password to the account registered with `email`,
+    /// written in `locale`. Returns false if email is disabled or there is no such account;
+    /// callers should not reveal which.
+    #[instrument(skip_all)]
+    pub async fn request_password_reset(&self, email: &str, locale: Locale) -> Result<bool> {
+        let Some(mailer) = &self.mailer else {
+            return Ok(false);
+        };
+        let Some(email) = normalize_email(email) else {
+            return Ok(false);
+        };
+        let user_id: Option<Uuid> = sqlx::query_scalar(
+                "SELECT id FROM users WHERE email = $1 AND kind = 'human' AND anonymized_at IS NULL"
+            )
+            .bind(&email)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to look up account for password reset")?;
+        let Some(user_id) = user_id else {
+            return Ok(false);
+        };
+
+        let secret = generate_secret(PASSWORD_RESET_TOKEN_PREFIX);
+        let now = Utc::now();
+        sqlx::query("INSERT INTO password_reset_tokens (token_hash, user_id, created_at, expires_at) VALUES ($1, $2, $3, $4)")
+            .bind(hash_token(&secret))
+            .bind(user_id)
+            .bind(now)
+            .bind(now + PASSWORD_RESET_LIFETIME)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to issue password reset for user ID {}", user_id))?;
+        let link = format!("{}/app/reset-password?token={}", self.public_url, secret);
+        mailer.send(&render_link_email(locale, "password-reset", &email, &link)).await?;
+        Ok(true)
+    }
+
+    /// Sets a new password using a token from a reset link. The token and any others issued
+    /// for the account stop working, and the account's sessions end. Returns the account, or
+    /// `None` if the token is unknown, used or expired. Fails with
+    /// `RegistrationError::WeakPassword` if the password is too short.
+    #[instrument(skip_all)]
+    pub async fn reset_password(&self, token: &str, new_password: &str) -> Result<Option<Uuid>> {
+        if new_password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(RegistrationError::WeakPassword.into());
+        }
+        let password_hash = password::hash(new_password)?;
+        let now = Utc::now();
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let user_id: Option<Uuid> = sqlx::query_scalar(
+                "DELETE FROM password_reset_tokens WHERE token_hash = $1 AND expires_at > $2 RETURNING user_id"
+            )
+            .bind(hash_token(token))
+            .bind(now)
+            .fetch_optional(&mut *tx)
+            .await
+            .context("Failed to claim password reset")?;
+        let Some(user_id) = user_id else {
+            return Ok(None);
+        };
+        // Resetting proves control of the mailbox, so it lifts any lockout too.
+        let updated = sqlx::query(
+                "UPDATE users SET password_hash = $1, failed_logins = 0, locked_until = NULL
+                 WHERE id = $2 AND kind = 'human' AND anonymized_at IS NULL"
+            )
+            .bind(&password_hash)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to reset password of user ID {}", user_id))?;
+        if updated.rows_affected() == 0 {
+            return Ok(None);
+        }
+        self.invalidate_credentials(&mut tx, user_id, now).await?;
+        tx.commit().await.context(format!("Failed to commit password reset of user ID {}", user_id))?;
+        info!("Reset password of user ID {}", user_id);
+        Ok(Some(user_id))
+    }
+
+    // What every password change must do: outstanding reset and magic links and sessions were
+    // granted on the strength of the old password, or of access to the mailbox before the change.
+    async fn invalidate_credentials(&self, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, user_id: Uuid, now: DateTime<Utc>) -> Result<()> {
+        sqlx::query("DELETE FROM password_reset_tokens WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await
+            .context(format!("Failed to invalidate password resets of user ID {}", user_id))?;
+        sqlx::query("DELETE FROM magic_link_tokens WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await
+            .context(format!("Failed to invalidate magic links of user ID {}", user_id))?;
+        sqlx::query("UPDATE user_sessions SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL")
+            .bind(now)
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await
+            .context(format!("Failed to end sessions of user ID {}", user_id))?;
+        Ok(())
+    }
+
+    /// Starts a token session if `password` is right for the account registered with `email`.
+    pub async fn login(&self, email: &str, password: &str) -> Result<Option<IssuedSession>> {
+        self.login_with(email, password, SessionKind::Token).await
+    }
+
+    /// Starts a session of the given kind if `password` is right for the account registered
+    /// with `email`. The secret is a refresh token or a cookie value accordingly.
+    ///
+    /// After `MAX_FAILED_LOGINS` consecutive wrong passwords the account is locked for
+    /// `LOCKOUT_DURATION`, and logins fail with `AuthenticationError::AccountLocked`.
+    #[instrument(skip_all)]
+    pub async fn login_with(&self, email: &str, password: &str, kind: SessionKind) -> Result<Option<IssuedSession>> {
+        let row: Option<CredentialsRow> = match normalize_email(email) {
+            Some(email) => sqlx::query_as(
+                    "SELECT id, password_hash, failed_logins, locked_until FROM users
+                     WHERE email = $1 AND kind = 'human' AND anonymized_at IS NULL"
+                )
+                .bind(email)
+                .fetch_optional(&*self.db_manager.pool)
+                .await
+                .context("Failed to look up account for login")?,
+            None => None,
+        };
+        let Some(CredentialsRow { id: user_id, password_hash: Some(password_hash), failed_logins, locked_until }) = row else {
+            password::verify_decoy(password);
+            return Ok(None);
+        };
+        if let Some(until) = locked_until
+            && until > Utc::now()
+        {
+            return Err(AuthenticationError::AccountLocked { until }.into());
+        }
+        match password::verify(password, &password_hash) {
+            Verification::Invalid => return self.record_failed_login(user_id).await,
+            Verification::Valid { needs_rehash: false } => {}
+            // Only now is the plaintext at hand to upgrade the hash with.
+            Verification::Valid { needs_rehash: true } => {
+                sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2 AND password_hash = $3")
+                    .bind(password::hash(password)?)
+                    .bind(user_id)
+                    .bind(&password_hash)
+                    .execute(&*self.db_manager.pool)
+                    .await
+                    .context(format!("Failed to upgrade password hash of user ID {}", user_id))?;
+            }
+        }
+        if failed_logins > 0 {
+            sqlx::query("UPDATE users SET failed_logins = 0 WHERE id = $1")
+                .bind(user_id)
+                .execute(&*self.db_manager.pool)
+                .await
+                .context(format!("Failed to clear failed logins of user ID {}", user_id))?;
+        }
+        let Some(user) = self.record_login(user_id).await? else {
+            return Ok(None);
+        };
+        self.start_session(user, kind).await.map(Some)
+    }
+
+    async fn start_session(&self, user: User, kind: SessionKind) -> Result<IssuedSession> {
+        let user_id = user.id;
+        let secret = generate_secret(kind.secret_prefix());
+        let session_id = Uuid::new_v4();
+        let now = Utc::now();
+        let expires_at = now + kind.lifetime();
+        sqlx::query(
+                "INSERT INTO user_sessions (id, user_id, token_hash, created_at, expires_at, kind) VALUES ($1, $2, $3, $4, $5, $6)"
+            )
+            .bind(session_id)
+            .bind(user_id)
+            .bind(hash_token(&secret))
+            .bind(now)
+            .bind(expires_at)
+            .bind(kind.as_str())
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to start session for user ID {}", user_id))?;
+        Ok(IssuedSession { session_id, user, secret, expires_at })
+    }
+
+    /// Emails a link that signs in the account registered with `email`, written in `locale`.
+    /// The link only works alongside `device_secret`, which the requesting device keeps; see
+    /// `magic_link_device_secret`. Returns false if magic links are disabled or there is no
+    /// such account; callers should not reveal which.
+    #[instrument(skip_all)]
+    pub async fn request_magic_link(&self, email: &str, device_secret: &str, locale: Locale) -> Result<bool> {
+        let Some(mailer) = self.mailer.as_ref().filter(|_| self.magic_links) else {
+            return Ok(false);
+        };
+        let Some(email) = normalize_email(email) else {
+            return Ok(false);
+        };
+        let user_id: Option<Uuid> = sqlx::query_scalar(
+                "SELECT id FROM users WHERE email = $1 AND kind = 'human' AND anonymized_at IS NULL"
+            )
+            .bind(&email)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to look up account for magic link")?;
+        let Some(user_id) = user_id else {
+            return Ok(false);
+        };
+
+        let secret = generate_secret(MAGIC_LINK_TOKEN_PREFIX);
+        let now = Utc::now();
+        sqlx::query(
+                "INSERT INTO magic_link_tokens (token_hash, user_id, device_hash, created_at, expires_at)
+                 VALUES ($1, $2, $3, $4, $5)"
+            )
+            .bind(hash_token(&secret))
+            .bind(user_id)
+            .bind(hash_token(device_secret))
+            .bind(now)
+            .bind(now + MAGIC_LINK_LIFETIME)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to issue magic link for user ID {}", user_id))?;
+        let link = format!("{}/app/magic-link?token={}", self.public_url, secret);
+        mailer.send(&render_link_email(locale, "magic-link", &email, &link)).await?;
+        Ok(true)
+    }
+
+    /// Starts a session of the given kind with the token from a magic link, if
+    /// `device_secret` is the one the link was requested with. Each link works once, and
+    /// presenting it from another device uses it up. Returns `None` if the link is unknown,
+    /// used, expired or for another device.
+    #[instrument(skip_all)]
+    pub async fn login_with_magic_link(&self, token: &str, device_secret: &str, kind: SessionKind) -> Result<Option<IssuedSession>> {
+        if !self.magic_links {
+            return Ok(None);
+        }
+        let claimed: Option<(Uuid, String)> = sqlx::query_as(
+                "DELETE FROM magic_link_tokens WHERE token_hash = $1 AND expires_at > $2 RETURNING user_id, device_hash"
+            )
+            .bind(hash_token(token))
+            .bind(Utc::now())
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to claim magic link")?;
+        let Some((user_id, device_hash)) = claimed else {
+            return Ok(None);
+        };
+        if device_hash != hash_token(device_secret) {
+            warn!("Magic link for user ID {} was opened on another device", user_id);
+            return Ok(None);
+        }
+        let session = self.login_as(user_id, kind).await?;
+        if session.is_some() {
+            info!("User ID {} signed in with a magic link", user_id);
+        }
+        Ok(session)
+    }
+
+    /// Signs in with an account at an identity provider. An identity seen before signs in
+    /// to the account it is linked to. A new one is linked to `link_to`, the signed-in
+    /// account if any; failing that, to the account with the same email address if both
+    /// the provider and the account have verified it; failing that, to a new account.
+    /// `None` if the account may not sign in, e.g. it was anonymized.
+    #[instrument(skip_all, fields(provider = %identity.provider))]
+    pub async fn login_with_identity(&self, identity: &ExternalIdentity, link_to: Option<Uuid>, kind: SessionKind) -> Result<Option<IssuedSession>> {
+        let now = Utc::now();
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let linked: Option<Uuid> = sqlx::query_scalar(
+                "UPDATE federated_identities SET last_login_at = $3 WHERE provider = $1 AND subject = $2 RETURNING user_id"
+            )
+            .bind(&identity.provider)
+            .bind(&identity.subject)
+            .bind(now)
+            .fetch_optional(&mut *tx)
+            .await
+            .context("Failed to look up federated identity")?;
+        let user_id = match (linked, link_to) {
+            (Some(user_id), Some(link_to)) if user_id != link_to => return Err(FederatedLoginError::LinkedElsewhere.into()),
+            (Some(user_id), _) => user_id,
+            (None, Some(user_id)) => user_id,
+            (None, None) => {
+                // Only an address both sides have verified shows the accounts are one person's.
+                let email = identity.email.as_deref().filter(|_| identity.email_verified).and_then(normalize_email);
+                let existing: Option<(Uuid, bool)> = match &email {
+                    Some(email) => sqlx::query_as(
+                            "SELECT id, email_verified FROM users WHERE email = $1 AND kind = 'human' AND anonymized_at IS NULL"
+                        )
+                        .bind(email)
+                        .fetch_optional(&mut *tx)
+                        .await
+                        .context("Failed to look up account for federated login")?,
+                    None => None,
+                };
+                match existing {
+                    Some((user_id, true)) => user_id,
+                    Some((_, false)) => return Err(FederatedLoginError::EmailTaken.into()),
+                    None => self.register_federated_user(&mut tx, identity, email).await?,
+                }
+            }
+        };
+        if linked.is_none() {
+            sqlx::query(
+                    "INSERT INTO federated_identities (provider, subject, user_id, created_at, last_login_at) VALUES ($1, $2, $3, $4, $4)"
+                )
+                .bind(&identity.provider)
+                .bind(&identity.subject)
+                .bind(user_id)
+                .bind(now)
+                .execute(&mut *tx)
+                .await
+                .context(format!("Failed to link {} identity to user ID {}", identity.provider, user_id))?;
+            info!("Linked {} identity to user ID {}", identity.provider, user_id);
+        }
+        tx.commit().await.context("Failed to commit federated login")?;
+        let session = self.login_as(user_id, kind).await?;
+        if session.is_some() {
+            info!("User ID {} signed in with {}", user_id, identity.provider);
+        }
+        Ok(session)
+    }
+
+    async fn register_federated_user(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        identity: &ExternalIdentity,
+        email: Option<String>,
+    ) -> Result<Uuid> {
+        let display_name = identity.display_name.as_deref()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .or_else(|| email.as_deref().and_then(|email| email.split('@').next()))
+            .unwrap_or(&identity.subject);
+        let display_name: String = display_name.chars().take(MAX_DISPLAY_NAME_LENGTH).collect();
+        let user_id = Uuid::new_v4();
+        let result = sqlx::query(
+                "INSERT INTO users (id, kind, display_name, email, email_verified, created_at) VALUES ($1, $2, $3, $4, $5, $6)"
+            )
+            .bind(user_id)
+            .bind(AccountKind::Human.as_str())
+            .bind(&display_name)
+            .bind(&email)
+            .bind(email.is_some())
+            .bind(Utc::now())
+            .execute(&mut **tx)
+            .await;
+        match result {
+            Ok(_) => {}
+            // Someone registered the address in the meantime.
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Err(FederatedLoginError::EmailTaken.into()),
+            Err(e) => return Err(anyhow::Error::new(e).context("Failed to register user")),
+        }
+        info!("Registered user with ID {} through {}", user_id, identity.provider);
+        Ok(user_id)
+    }
+
+    /// Starts a session for `user_id`, whose owner has been identified some way other than
+    /// their password. `None` for bots and anonymized accounts.
+    async fn login_as(&self, user_id: Uuid, kind: SessionKind) -> Result<Option<IssuedSession>> {
+        if !self.get_user(user_id).await?.is_some_and(|user| !user.is_bot() && !user.is_anonymized()) {
+            return Ok(None);
+        }
+        let Some(user) = self.record_login(user_id).await? else {
+            return Ok(None);
+        };
+        self.start_session(user, kind).await.map(Some)
+    }
+
+    // Counts a wrong password, locking the account once too many have been tried in a row.
+    // The count starts over with the lock, so each lockout allows a fresh set of attempts.
+    async fn record_failed_login(&self, user_id: Uuid) -> Result<Option<IssuedSession>> {
+        let now = Utc::now();
+        let locked_until: Option<DateTime<Utc>> = sqlx::query_scalar(
+                "UPDATE users SET
+                     failed_logins = CASE WHEN failed_logins + 1 >= $2 THEN 0 ELSE failed_logins + 1 END,
+                     locked_until = CASE WHEN failed_logins + 1 >= $2 THEN $3 ELSE locked_until END
+                 WHERE id = $1 RETURNING locked_until"
+            )
+            .bind(user_id)
+            .bind(MAX_FAILED_LOGINS)
+            .bind(now + LOCKOUT_DURATION)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to record failed login of user ID {}", user_id))?
+            .flatten();
+        match locked_until {
+            Some(until) if until > now => {
+                warn!("Locked user ID {} after {} failed logins", user_id, MAX_FAILED_LOGINS);
+                Err(AuthenticationError::AccountLocked { until }.into())
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Resolves a session token to its account, if the session is live.
+    #[instrument(skip_all)]
+    pub async fn authenticate_session(&self, secret: &str) -> Result<Option<User>> {
+        if !secret.starts_with(SESSION_TOKEN_PREFIX) {
+            return Ok(None);
+        }
+        let user_id: Option<Uuid> = sqlx::query_scalar(
+                "SELECT user_id FROM user_sessions
+                 WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > $2"
+            )
+            .bind(hash_token(secret))
+            .bind(Utc::now())
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to look up session")?;
+        match user_id {
+            Some(user_id) => self.get_user(user_id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Swaps a session's token for a new one and extends the session. The old token stops
+    /// working; if it is presented again it must have been copied, so the session is ended.
+    #[instrument(skip_all)]
+    pub async fn refresh_session(&self, secret: &str) -> Result<Option<IssuedSession>> {
+        if !secret.starts_with(SESSION_TOKEN_PREFIX) {
+            return Ok(None);
+        }
+        let new_secret = generate_secret(SESSION_TOKEN_PREFIX);
+        let now = Utc::now();
+        let expires_at = now + SESSION_LIFETIME;
+        let row: Option<(Uuid, Uuid)> = sqlx::query_as(
+                "UPDATE user_sessions SET token_hash = $1, previous_token_hash = $2, expires_at = $3
+                 WHERE token_hash = $2 AND revoked_at IS NULL AND expires_at > $4
+                 RETURNING id, user_id"
+            )
+            .bind(hash_token(&new_secret))
+            .bind(hash_token(secret))
+            .bind(expires_at)
+            .bind(now)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to refresh session")?;
+        let Some((session_id, user_id)) = row else {
+            let reused = sqlx::query(
+                    "UPDATE user_sessions SET revoked_at = $1 WHERE previous_token_hash = $2 AND revoked_at IS NULL"
+                )
+                .bind(now)
+                .bind(hash_token(secret))
+                .execute(&*self.db_manager.pool)
+                .await
+                .context("Failed to end session after token reuse")?;
+            if reused.rows_affected() > 0 {
+                warn!("Ended a session whose replaced refresh token was presented again");
+            }
+            return Ok(None);
+        };
+        Ok(self.record_login(user_id).await?
+            .map(|user| IssuedSession { session_id, user, secret: new_secret, expires_at }))
+    }
+
+    // Notes that the account is in use, which also reactivates it and withdraws any warning.
+    // Refreshing a session counts, so staying signed in keeps an account active. `None` for
+    // suspended accounts, which may not sign in.
+    async fn record_login(&self, user_id: Uuid) -> Result<Option<User>> {
+        let Some(user) = self.get_user(user_id).await?.filter(|user| !user.suspended) else {
+            return Ok(None);
+        };
+        sqlx::query("UPDATE users SET last_login_at = $1, inactivity_warned_at = NULL, deactivated_at = NULL WHERE id = $2")
+            .bind(Utc::now())
+            .bind(user_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to record login of user ID {}", user_id))?;
+        if user.deactivated_at.is_some() {
+            info!("Reactivated user ID {}", user_id);
+        }
+        Ok(Some(User { deactivated_at: None, ..user }))
+    }
+
+    /// Warns the owners of accounts unused since `inactive_before` that they will be
+    /// deactivated on `deactivate_on` unless they log in. Each account is warned once per
+    /// lapse; accounts without a mailer to reach them are marked warned all the same.
+    /// Returns the number of accounts warned.
+    #[instrument(skip_all)]
+    pub async fn warn_inactive_accounts(&self, inactive_before: DateTime<Utc>, deactivate_on: DateTime<Utc>, now: DateTime<Utc>) -> Result<usize> {
+        let warned: Vec<(Uuid, Option<String>)> = sqlx::query_as(&format!(
+                "UPDATE users SET inactivity_warned_at = $2 WHERE {} AND inactivity_warned_at IS NULL RETURNING id, email",
+                INACTIVITY_CANDIDATE
+            ))
+            .bind(inactive_before)
+            .bind(now)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to mark inactive accounts as warned")?;
+        if let Some(mailer) = &self.mailer {
+            for (user_id, email) in &warned {
+                let Some(email) = email else { continue };
+                let warning = render_inactivity_warning(Locale::FALLBACK, email, deactivate_on);
+                if let Err(e) = mailer.send(&warning).await {
+                    warn!("Failed to warn user ID {} of deactivation: {:#}", user_id, e);
+                }
+            }
+        }
+        Ok(warned.len())
+    }
+
+    /// Deactivates accounts unused since `inactive_before` whose owners were warned before
+    /// `warned_before`, ending their sessions. Returns the IDs of the deactivated accounts.
+    #[instrument(skip_all)]
+    pub async fn deactivate_inactive_accounts(&self, inactive_before: DateTime<Utc>, warned_before: DateTime<Utc>, now: DateTime<Utc>) -> Result<Vec<Uuid>> {
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let deactivated: Vec<Uuid> = sqlx::query_scalar(&format!(
+                "UPDATE users SET deactivated_at = $3 WHERE {} AND inactivity_warned_at < $2 RETURNING id",
+                INACTIVITY_CANDIDATE
+            ))
+            .bind(inactive_before)
+            .bind(warned_before)
+            .bind(now)
+            .fetch_all(&mut *tx)
+            .await
+            .context("Failed to deactivate inactive accounts")?;
+        sqlx::query("UPDATE user_sessions SET revoked_at = $1 WHERE user_id = ANY($2) AND revoked_at IS NULL")
+            .bind(now)
+            .bind(&deactivated)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to end sessions of deactivated accounts")?;
+        tx.commit().await.context("Failed to commit deactivation of inactive accounts")?;
+        Ok(deactivated)
+    }
+
+    /// Accounts in the order they were created, starting after the account `after`.
+    #[instrument(skip_all)]
+    pub async fn list_users(&self, after: Option<Uuid>, limit: i64) -> Result<Vec<User>> {
+        let rows: Vec<UserRow> = sqlx::query_as(
+                "SELECT id, kind, display_name, email, email_verified, created_by, created_at, anonymized_at, deactivated_at, suspended, role
+                 FROM users
+                 WHERE $1::UUID IS NULL OR (created_at, id) > (SELECT created_at, id FROM users WHERE id = $1)
+                 ORDER BY created_at, id LIMIT $2"
+            )
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to list users")?;
+        rows.into_iter().map(User::try_from).collect()
+    }
+
+    #[instrument(skip_all)]
+    pub async fn count_users(&self) -> Result<UserCounts> {
+        sqlx::query_as(
+                "SELECT COUNT(*) AS total,
+                        COUNT(*) FILTER (WHERE kind = 'bot') AS bots,
+                        COUNT(*) FILTER (WHERE deactivated_at IS NULL AND anonymized_at IS NULL) AS active,
+                        COUNT(*) FILTER (WHERE role <> 'user') AS administrators
+                 FROM users"
+            )
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context("Failed to count users")
+    }
+
+    /// Gives an account a role. Returns false if there is no such account.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn set_role(&self, user_id: Uuid, role: UserRole) -> Result<bool> {
+        let result = sqlx::query("UPDATE users SET role = $1 WHERE id = $2")
+            .bind(role.as_str())
+            .bind(user_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to set role of user ID {}", user_id))?;
+        if result.rows_affected() > 0 {
+            info!("User ID {} is now {}", user_id, role.as_str());
+        }
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Suspends an account, ending its sessions. Unlike deactivation for disuse, signing in
+    /// doesn't undo this; only `reactivate_user` does. Returns false if there is no such
+    /// account or it is already suspended.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn deactivate_user(&self, user_id: Uuid) -> Result<bool> {
+        let now = Utc::now();
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let result = sqlx::query(
+                "UPDATE users SET suspended = true, deactivated_at = COALESCE(deactivated_at, $1) WHERE id = $2 AND NOT suspended"
+            )
+            .bind(now)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to deactivate user ID {}", user_id))?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query("UPDATE user_sessions SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL")
+            .bind(now)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to end sessions of user ID {}", user_id))?;
+        tx.commit().await.context(format!("Failed to commit deactivation of user ID {}", user_id))?;
+        info!("Deactivated user ID {}", user_id);
+        Ok(true)
+    }
+
+    /// Lifts a suspension. Returns false if there is no such account or it isn't suspended.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn reactivate_user(&self, user_id: Uuid) -> Result<bool> {
+        let result = sqlx::query(
+                "UPDATE users SET suspended = false, deactivated_at = NULL, inactivity_warned_at = NULL WHERE id = $1 AND suspended"
+            )
+            .bind(user_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to reactivate user ID {}", user_id))?;
+        if result.rows_affected() > 0 {
+            info!("Reactivated user ID {}", user_id);
+        }
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Resolves a session cookie to its account if the session is live, extending the
+    /// session when it was last extended more than `COOKIE_SESSION_SLIDE_INTERVAL` ago.
+    #[instrument(skip_all)]
+    pub async fn authenticate_cookie(&self, secret: &str) -> Result<Option<CookieSession>> {
+        if !secret.starts_with(SESSION_COOKIE_PREFIX) {
+            return Ok(None);
+        }
+        let now = Utc::now();
+        let row: Option<(Uuid, Uuid, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT s.id, s.user_id, s.expires_at FROM user_sessions s JOIN users u ON u.id = s.user_id
+                 WHERE s.token_hash = $1 AND s.kind = 'cookie' AND s.revoked_at IS NULL AND s.expires_at > $2
+                   AND u.anonymized_at IS NULL"
+            )
+            .bind(hash_token(secret))
+            .bind(now)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to look up session cookie")?;
+        let Some((session_id, user_id, expires_at)) = row else {
+            return Ok(None);
+        };
+        let mut extended_until = None;
+        if expires_at < now + COOKIE_SESSION_LIFETIME - COOKIE_SESSION_SLIDE_INTERVAL {
+            let until = now + COOKIE_SESSION_LIFETIME;
+            sqlx::query("UPDATE user_sessions SET expires_at = $1 WHERE id = $2 AND revoked_at IS NULL")
+                .bind(until)
+                .bind(session_id)
+                .execute(&*self.db_manager.pool)
+                .await
+                .context(format!("Failed to extend session ID {}", session_id))?;
+            extended_until = Some(until);
+        }
+        Ok(Some(CookieSession { session_id, user_id, extended_until }))
+    }
+
+    /// A user's live sessions, newest first.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn list_sessions(&self, user_id: Uuid) -> Result<Vec<SessionInfo>> {
+        let rows: Vec<(Uuid, String, DateTime<Utc>, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT id, kind, created_at, expires_at FROM user_sessions
+                 WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > $2 ORDER BY created_at DESC"
+            )
+            .bind(user_id)
+            .bind(Utc::now())
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list sessions of user ID {}", user_id))?;
+        rows.into_iter()
+            .map(|(id, kind, created_at, expires_at)| Ok(SessionInfo { id, kind: kind.parse()?, created_at, expires_at }))
+            .collect()
+    }
+
+    /// Ends one of a user's sessions. Returns false if the user has no such live session.
+    #[instrument(skip_all, fields(user_id = %user_id, session_id = %session_id))]
+    pub async fn revoke_session(&self, user_id: Uuid, session_id: Uuid) -> Result<bool> {
+        let now = Utc::now();
+        let result = sqlx::query(
+                "UPDATE user_sessions SET revoked_at = $1 WHERE id = $2 AND user_id = $3 AND revoked_at IS NULL AND expires_at > $1"
+            )
+            .bind(now)
+            .bind(session_id)
+            .bind(user_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to revoke session ID {}", session_id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Ends the session a token belongs to, e.g. on logout.
+    #[instrument(skip_all)]
+    pub async fn end_session(&self, secret: &str) -> Result<()> {
+        sqlx::query("UPDATE user_sessions SET revoked_at = $1 WHERE token_hash = $2 AND revoked_at IS NULL")
+            .bind(Utc::now())
+            .bind(hash_token(secret))
+            .execute(&*self.db_manager.pool)
+            .await
+            .context("Failed to end session")?;
+        Ok(())
+    }
+
+    /// Whether a session has neither ended nor expired, for connections that outlive a request.
+    #[instrument(skip_all, fields(session_id = %session_id))]
+    pub async fn session_active(&self, session_id: Uuid) -> Result<bool> {
+        sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM user_sessions WHERE id = $1 AND revoked_at IS NULL AND expires_at > $2)"
+            )
+            .bind(session_id)
+            .bind(Utc::now())
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to check session {}", session_id))
+    }
+
+    /// Whether a bot token has neither been revoked nor expired.
+    #[instrument(skip_all, fields(token_id = %token_id))]
+    pub async fn bot_token_active(&self, token_id: Uuid) -> Result<bool> {
+        sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM bot_tokens
+                 WHERE id = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > $2))"
+            )
+            .bind(token_id)
+            .bind(Utc::now())
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to check bot token {}", token_id))
+    }
+
+    /// Creates an automation account. `created_by` is the administrator responsible for it.
+    #[instrument(skip_all, fields(created_by = ?created_by))]
+    pub async fn create_bot(&self, display_name: &str, created_by: Option<Uuid>) -> Result<User> {
+        let user = User {
+            id: Uuid::new_v4(),
+            kind: AccountKind::Bot,
+            display_name: display_name.to_string(),
+            email: None,
+            created_by,
+            created_at: DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap_or_default(),
+            email_verified: false,
+            anonymized_at: None,
+            deactivated_at: None,
+            suspended: false,
+            role: UserRole::User,
+        };
+        sqlx::query(
+                "INSERT INTO users (id, kind, display_name, email, created_by, created_at) VALUES ($1, $2, $3, $4, $5, $6)"
+            )
+            .bind(user.id)
+            .bind(user.kind.as_str())
+            .bind(&user.display_name)
+            .bind(&user.email)
+            .bind(user.created_by)
+            .bind(user.created_at)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to create bot account '{}'", display_name))?;
+        info!("Created bot account '{}' with ID: {}", display_name, user.id);
+        Ok(user)
+    }
+
+    /// Issues a token for a bot, limited to `scopes`.
+    #[instrument(skip_all, fields(bot_id = %bot_id))]
+    pub async fn issue_bot_token(
+        &self,
+        bot_id: Uuid,
+        scopes: &[Scope],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<IssuedToken> {
+        match self.get_user(bot_id).await? {
+            Some(user) if user.is_bot() => {}
+            Some(_) => bail!("Account {} is not a bot; only bots can hold scoped tokens", bot_id),
+            None => bail!("No account with ID {}", bot_id),
+        }
+        if scopes.is_empty() {
+            bail!("A bot token needs at least one scope");
+        }
+
+        let secret = generate_secret(BOT_TOKEN_PREFIX);
+        let token_id = Uuid::new_v4();
+        sqlx::query(
+                "INSERT INTO bot_tokens (id, bot_id, token_hash, scopes, created_at, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $6)"
+            )
+            .bind(token_id)
+            .bind(bot_id)
+            .bind(hash_token(&secret))
+            .bind(scopes.iter().map(Scope::to_string).collect::<Vec<_>>())
+            .bind(Utc::now())
+            .bind(expires_at)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to issue token for bot ID {}", bot_id))?;
+        Ok(IssuedToken { token_id, secret })
+    }
+
+    /// Resolves a presented token to its bot, if the token is live.
+    #[instrument(skip_all)]
+    pub async fn authenticate_bot_token(&self, secret: &str) -> Result<Option<BotPrincipal>> {
+        if !secret.starts_with(BOT_TOKEN_PREFIX) {
+            return Ok(None);
+        }
+        let row: Option<(Uuid, Uuid, Vec<String>)> = sqlx::query_as(
+                "SELECT t.id, t.bot_id, t.scopes FROM bot_tokens t JOIN users u ON u.id = t.bot_id
+                 WHERE t.token_hash = $1 AND t.revoked_at IS NULL AND (t.expires_at IS NULL OR t.expires_at > $2)
+                   AND NOT u.suspended"
+            )
+            .bind(hash_token(secret))
+            .bind(Utc::now())
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to look up bot token")?;
+        let Some((token_id, bot_id, scopes)) = row else {
+            return Ok(None);
+        };
+        let scopes = scopes.iter().map(|scope| scope.parse()).collect::<Result<_>>()?;
+        Ok(Some(BotPrincipal { bot_id, token_id, scopes }))
+    }
+
+    /// Creates a guest identity. The display name and colour must already be validated.
+    #[instrument(skip_all)]
+    pub async fn create_guest(&self, display_name: &str, color: &str) -> Result<IssuedGuest> {
+        let secret = generate_secret(GUEST_TOKEN_PREFIX);
+        let guest = sqlx::query_as(
+                "INSERT INTO guests (id, token_hash, display_name, color, created_at, last_seen_at)
+                 VALUES ($1, $2, $3, $4, $5, $5)
+                 RETURNING id, display_name, color, created_at"
+            )
+            .bind(Uuid::new_v4())
+            .bind(hash_token(&secret))
+            .bind(display_name.trim())
+            .bind(color)
+            .bind(Utc::now())
+            .fetch_one(&*self.db_manager.pool)
+            .await
+            .context("Failed to create guest")?;
+        Ok(IssuedGuest { guest, secret })
+    }
+
+    /// Resolves a guest token to its guest and records that the guest was seen.
+    #[instrument(skip_all)]
+    pub async fn authenticate_guest(&self, secret: &str) -> Result<Option<Guest>> {
+        if !secret.starts_with(GUEST_TOKEN_PREFIX) {
+            return Ok(None);
+        }
+        sqlx::query_as(
+                "UPDATE guests SET last_seen_at = $1 WHERE token_hash = $2
+                 RETURNING id, display_name, color, created_at"
+            )
+            .bind(Utc::now())
+            .bind(hash_token(secret))
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context("Failed to look up guest")
+    }
+
+    /// Changes how a guest appears to others. Returns `None` if there is no such guest.
+    #[instrument(skip_all, fields(guest_id = %guest_id))]
+    pub async fn update_guest(&self, guest_id: Uuid, display_name: &str, color: &str) -> Result<Option<Guest>> {
+        sqlx::query_as(
+                "UPDATE guests SET display_name = $1, color = $2 WHERE id = $3
+                 RETURNING id, display_name, color, created_at"
+            )
+            .bind(display_name.trim())
+            .bind(color)
+            .bind(guest_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to update guest ID {}", guest_id))
+    }
+
+    /// Replaces an account's personal data with placeholders instead of deleting it, so
+    /// references to the account stay valid. Its tokens and sessions are revoked and it drops out of
+    /// user search. Returns false if there is no such account or it was already anonymized.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn anonymize_user(&self, user_id: Uuid) -> Result<bool> {
+        let now = Utc::now();
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        let result = sqlx::query(
+                "UPDATE users SET display_name = $1, email = NULL, email_verified = false, password_hash = NULL, anonymized_at = $2
+                 WHERE id = $3 AND anonymized_at IS NULL"
+            )
+            .bind(anonymized_display_name(user_id))
+            .bind(now)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to anonymize user ID {}", user_id))?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query("UPDATE bot_tokens SET revoked_at = $1 WHERE bot_id = $2 AND revoked_at IS NULL")
+            .bind(now)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to revoke tokens of user ID {}", user_id))?;
+        sqlx::query("UPDATE user_sessions SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL")
+            .bind(now)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to end sessions of user ID {}", user_id))?;
+        sqlx::query("DELETE FROM federated_identities WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to unlink identities of user ID {}", user_id))?;
+        tx.commit().await.context(format!("Failed to commit anonymization of user ID {}", user_id))?;
+        info!("Anonymized user ID {}", user_id);
+        Ok(true)
+    }
+
+    #[instrument(skip_all, fields(token_id = %token_id))]
+    pub async fn revoke_bot_token(&self, token_id: Uuid) -> Result<()> {
+        sqlx::query("UPDATE bot_tokens SET revoked_at = $1 WHERE id = $2 AND revoked_at IS NULL")
+            .bind(Utc::now())
+            .bind(token_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to revoke bot token ID {}", token_id))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_service::DocumentService;
+    use crate::permissions::{PermissionService, PolicyRole};
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    async fn get_test_user_service() -> Result<UserService> {
+        let manager = Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME)
+            .await
+            .context(format!("Failed to initialize Manager for test database '{}'", TEST_DB_NAME))?;
+        UserService::new(Arc::new(manager)).await
+    }
+
+    #[test]
+    fn test_scope_round_trip() {
+        let doc_id = Uuid::new_v4();
+        for scope in [Scope::ReadDocument(doc_id), Scope::AppendDocument(doc_id)] {
+            assert_eq!(scope.to_string().parse::<Scope>().unwrap(), scope);
+        }
+        assert!("document:not-a-uuid:read".parse::<Scope>().is_err());
+        assert!(format!("document:{}:delete", doc_id).parse::<Scope>().is_err());
+        assert!("folder".parse::<Scope>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bot_tokens_are_scoped_and_revocable() -> Result<()> {
+        let users = get_test_user_service().await?;
+        let bot = users.create_bot("Changelog Bot", None).await?;
+        assert!(users.get_user(bot.id).await?.unwrap().is_bot());
+
+        let doc_id = Uuid::new_v4();
+        let token = users.issue_bot_token(bot.id, &[Scope::AppendDocument(doc_id)], None).await?;
+        let principal = users.authenticate_bot_token(&token.secret).await?.expect("token should authenticate");
+        assert_eq!(principal.bot_id, bot.id);
+        assert_eq!(principal.document_access(doc_id), DocumentAccess { read: false, write: true, comment: false, role: DocumentRole::Editor });
+        assert!(!principal.document_access(Uuid::new_v4()).any());
+
+        assert!(users.authenticate_bot_token("cbt_guess").await?.is_none());
+        assert!(users.bot_token_active(token.token_id).await?);
+        users.revoke_bot_token(token.token_id).await?;
+        assert!(users.authenticate_bot_token(&token.secret).await?.is_none());
+        assert!(!users.bot_token_active(token.token_id).await?);
+
+        let expired = users.issue_bot_token(bot.id, &[Scope::ReadDocument(doc_id)], Some(Utc::now())).await?;
+        assert!(users.authenticate_bot_token(&expired.secret).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_anonymize_user_keeps_references() -> Result<()> {
+        let users = get_test_user_service().await?;
+        let marker = Uuid::new_v4().simple().to_string();
+        let owner = users.create_bot(&format!("Owner {}", marker), None).await?;
+        let bot = users.create_bot("Report Bot", Some(owner.id)).await?;
+        let token = users.issue_bot_token(owner.id, &[Scope::ReadDocument(Uuid::new_v4())], None).await?;
+
+        assert!(users.anonymize_user(owner.id).await?);
+        assert!(!users.anonymize_user(owner.id).await?, "Anonymizing twice should be a no-op");
+
+        let anonymized = users.get_user(owner.id).await?.unwrap();
+        assert!(anonymized.is_anonymized());
+        assert!(!anonymized.display_name.contains(&marker));
+        assert_eq!(anonymized.email, None);
+        assert_eq!(users.get_user(bot.id).await?.unwrap().created_by, Some(owner.id));
+        assert!(users.authenticate_bot_token(&token.secret).await?.is_none());
+        assert!(users.search_users(&marker, 10, ReadConsistency::Strong).await?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_email() {
+        assert_eq!(normalize_email("  Ada@Example.COM "), Some("ada@example.com".to_string()));
+        for invalid in ["ada", "@example.com", "ada@example", "ada@.com", "ada@example.com.", "a da@example.com", "a@b@example.com"] {
+            assert_eq!(normalize_email(invalid), None, "{} should be rejected", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_and_login() -> Result<()> {
+        let users = get_test_user_service().await?;
+        let email = format!("{}@example.com", Uuid::new_v4().simple());
+        let password = "correct horse battery";
+
+        let user = users.register_user("Ada", &email.to_uppercase(), password).await?;
+        assert_eq!(user.email.as_deref(), Some(email.as_str()));
+        let err = users.register_user("Imposter", &email, "another password").await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RegistrationError>(), Some(&RegistrationError::EmailTaken));
+        let err = users.register_user("Ada", "someone@example.com", "short").await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RegistrationError>(), Some(&RegistrationError::WeakPassword));
+
+        assert!(users.login(&email, "wrong password").await?.is_none());
+        assert!(users.login("nobody@example.com", password).await?.is_none());
+        let session = users.login(&email, password).await?.expect("login should succeed");
+        assert_eq!(session.user.id, user.id);
+        assert_eq!(users.authenticate_session(&session.secret).await?.map(|user| user.id), Some(user.id));
+
+        // Anonymizing the account ends its sessions and stops it logging in.
+        users.anonymize_user(user.id).await?;
+        assert!(users.authenticate_session(&session.secret).await?.is_none());
+        assert!(users.login(&email, password).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rotates_session_tokens() -> Result<()> {
+        let users = get_test_user_service().await?;
+        let email = format!("{}@example.com", Uuid::new_v4().simple());
+        let user = users.register_user("Linus", &email, "correct horse battery").await?;
+        let session = users.login(&email, "correct horse battery").await?.expect("login should succeed");
+
+        let refreshed = users.refresh_session(&session.secret).await?.expect("refresh should succeed");
+        assert_eq!((refreshed.session_id, refreshed.user.id), (session.session_id, user.id));
+        assert_ne!(refreshed.secret, session.secret);
+        assert!(users.authenticate_session(&refreshed.secret).await?.is_some());
+
+        // Replaying the replaced token ends the session for whoever holds the new one too.
+        assert!(users.refresh_session(&session.secret).await?.is_none());
+        assert!(users.authenticate_session(&refreshed.secret).await?.is_none());
+
+        let session = users.login(&email, "correct horse battery").await?.expect("login should succeed");
+        assert!(users.session_active(session.session_id).await?);
+        users.end_session(&session.secret).await?;
+        assert!(users.refresh_session(&session.secret).await?.is_none());
+        assert!(!users.session_active(session.session_id).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_login_upgrades_outdated_password_hashes() -> Result<()> {
+        use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+        use argon2::{Algorithm, Argon2, Params, Version};
+
+        let users = get_test_user_service().await?;
+        let email = format!("{}@example.com", Uuid::new_v4().simple());
+        let user = users.register_user("Grace", &email, "correct horse battery").await?;
+        let weak = Argon2::new(Algorithm::Argon2i, Version::V0x13, Params::new(8 * 1024, 1, 1, None).unwrap());
+        let outdated = weak
+            .hash_password(b"correct horse battery", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
+            .bind(&outdated)
+            .bind(user.id)
+            .execute(&*users.db_manager.pool)
+            .await?;
+
+        assert!(users.login(&email, "correct horse battery").await?.is_some());
+        let stored: String = sqlx::query_scalar("SELECT password_hash FROM users WHERE id = $1")
+            .bind(user.id)
+            .fetch_one(&*users.db_manager.pool)
+            .await?;
+        assert_eq!(password::verify("correct horse battery", &stored), Verification::Valid { needs_rehash: false });
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_repeated_wrong_passwords_lock_the_account() -> Result<()> {
+        let users = get_test_user_service().await?;
+        let email = format!("{}@example.com", Uuid::new_v4().simple());
+        let user = users.register_user("Mallory's Target", &email, "correct horse battery").await?;
+        let is_locked = |result: Result<Option<IssuedSession>>| {
+            matches!(result.map_err(|e| e.downcast::<AuthenticationError>()), Err(Ok(AuthenticationError::AccountLocked { .. })))
+        };
+
+        // A successful login starts the count over.
+        for _ in 1..MAX_FAILED_LOGINS {
+            assert!(users.login(&email, "wrong password").await?.is_none());
+        }
+        assert!(users.login(&email, "correct horse battery").await?.is_some());
+        for _ in 1..MAX_FAILED_LOGINS {
+            assert!(users.login(&email, "wrong password").await?.is_none());
+        }
+        assert!(is_locked(users.login(&email, "wrong password").await));
+        assert!(is_locked(users.login(&email, "correct horse battery").await));
+
+        // The lock expires on its own.
+        sqlx::query("UPDATE users SET locked_until = $1 WHERE id = $2")
+            .bind(Utc::now() - TimeDelta::seconds(1))
+            .bind(user.id)
+            .execute(&*users.db_manager.pool)
+            .await?;
+        assert!(users.login(&email, "wrong password").await?.is_none());
+        assert!(users.login(&email, "correct horse battery").await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_guests_keep_their_identity() -> Result<()> {
+        let users = get_test_user_service().await?;
+        let issued = users.create_guest("  Curious Otter ", "#3a7bff").await?;
+        assert_eq!(issued.guest.display_name, "Curious Otter");
+        assert_eq!(users.authenticate_guest(&issued.secret).await?, Some(issued.guest.clone()));
+
+        let renamed = users.update_guest(issued.guest.id, "Otter", "#ff8800").await?.expect("guest should exist");
+        assert_eq!((renamed.id, renamed.color.as_str()), (issued.guest.id, "#ff8800"));
+        assert_eq!(users.authenticate_guest(&issued.secret).await?, Some(renamed));
+
+        assert!(users.authenticate_guest("cgt_guess").await?.is_none());
+        assert!(users.authenticate_guest(&issued.secret.replace("cgt_", "cst_")).await?.is_none());
+        assert!(users.update_guest(Uuid::new_v4(), "Nobody", "#000000").await?.is_none());
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct RecordingMailer {
+        sent: std::sync::Mutex<Vec<Email>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Mailer for RecordingMailer {
+        async fn send(&self, email: &Email) -> Result<()> {
+            self.sent.lock().unwrap().push(email.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_email_verification_links_work_once() -> Result<()> {
+        let mailer = Arc::new(RecordingMailer::default());
+        let users = get_test_user_service().await?.with_mailer(mailer.clone(), "https://collaborate.example".to_string());
+        let email = format!("{}@example.com", Uuid::new_v4().simple());
+        let user = users.register_user("Barbara", &email, "correct horse battery").await?;
+        assert!(!user.email_verified);
+
+        assert!(users.send_email_verification(user.id, Locale::FALLBACK).await?);
+        let sent = mailer.sent.lock().unwrap().pop().expect("a verification email");
+        assert_eq!(sent.to, email);
+        let token = sent.text.split("?token=").nth(1).expect("a verification link").trim();
+        assert!(token.starts_with(EMAIL_VERIFICATION_TOKEN_PREFIX));
+
+        assert_eq!(users.verify_email("cvt_guess").await?, None);
+        assert_eq!(users.verify_email(token).await?, Some(user.id));
+        assert!(users.get_user(user.id).await?.unwrap().email_verified);
+        assert_eq!(users.verify_email(token).await?, None, "Links work once");
+        assert!(!users.send_email_verification(user.id, Locale::FALLBACK).await?, "Nothing left to verify");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_magic_links_work_once_on_the_requesting_device() -> Result<()> {
+        let mailer = Arc::new(RecordingMailer::default());
+        let users = get_test_user_service().await?.with_mailer(mailer.clone(), "https://collaborate.example".to_string());
+        let email = format!("{}@example.com", Uuid::new_v4().simple());
+        let user = users.register_user("Bianca", &email, "correct horse battery").await?;
+        let device = magic_link_device_secret();
+        assert!(!users.request_magic_link(&email, &device, Locale::FALLBACK).await?, "Disabled by default");
+
+        let users = users.with_magic_links();
+        assert!(!users.request_magic_link("nobody@example.com", &device, Locale::FALLBACK).await?);
+        let mut tokens = Vec::new();
+        for _ in 0..2 {
+            assert!(users.request_magic_link(&email, &device, Locale::FALLBACK).await?);
+            let sent = mailer.sent.lock().unwrap().pop().expect("a magic link email");
+            assert_eq!(sent.to, email);
+            let token = sent.text.split("?token=").nth(1).expect("a magic link").trim().to_string();
+            assert!(token.starts_with(MAGIC_LINK_TOKEN_PREFIX));
+            tokens.push(token);
+        }
+
+        let other_device = magic_link_device_secret();
+        assert!(users.login_with_magic_link(&tokens[0], &other_device, SessionKind::Token).await?.is_none());
+        assert!(
+            users.login_with_magic_link(&tokens[0], &device, SessionKind::Token).await?.is_none(),
+            "Opening a link elsewhere uses it up"
+        );
+        let session = users.login_with_magic_link(&tokens[1], &device, SessionKind::Cookie).await?.expect("a session");
+        assert_eq!(session.user.id, user.id);
+        assert!(users.authenticate_cookie(&session.secret).await?.is_some());
+        assert!(users.login_with_magic_link(&tokens[1], &device, SessionKind::Cookie).await?.is_none(), "Links work once");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_federated_identities_link_to_one_account() -> Result<()> {
+        let users = get_test_user_service().await?;
+        let suffix = Uuid::new_v4().simple();
+        let identity = |subject: &str, email: &str, email_verified: bool| ExternalIdentity {
+            provider: "github".to_string(),
+            subject: format!("{}-{}", subject, suffix),
+            email: Some(email.to_string()),
+            email_verified,
+            display_name: Some("Octo Cat".to_string()),
+        };
+
+        // A new identity gets an account, and signs in to it from then on.
+        let new_email = format!("octo-{}@example.com", suffix);
+        let first = users.login_with_identity(&identity("1", &new_email, true), None, SessionKind::Cookie).await?.expect("a session");
+        assert_eq!(first.user.email.as_deref(), Some(new_email.as_str()));
+        assert!(first.user.email_verified);
+        assert_eq!(first.user.display_name, "Octo Cat");
+        let again = users.login_with_identity(&identity("1", "changed@example.com", true), None, SessionKind::Token).await?.unwrap();
+        assert_eq!(again.user.id, first.user.id);
+
+        // Addresses only link accounts when both sides have verified them.
+        let unverified_email = format!("unverified-{}@example.com", suffix);
+        users.register_user("Unverified", &unverified_email, "correct horse battery").await?;
+        let error = users.login_with_identity(&identity("2", &unverified_email, true), None, SessionKind::Cookie).await.map(|_| ()).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&FederatedLoginError::EmailTaken));
+        let taken = users.login_with_identity(&identity("3", &unverified_email, false), None, SessionKind::Cookie).await?.unwrap();
+        assert_eq!(taken.user.email, None, "An address the provider does not vouch for is not kept");
+
+        // Someone signed in links the identity to their own account, once.
+        let password_user = users.register_user("Linker", &format!("linker-{}@example.com", suffix), "correct horse battery").await?;
+        let linked = users.login_with_identity(&identity("4", "x@example.com", false), Some(password_user.id), SessionKind::Cookie).await?;
+        assert_eq!(linked.unwrap().user.id, password_user.id);
+        let error = users.login_with_identity(&identity("1", &new_email, true), Some(password_user.id), SessionKind::Cookie).await.map(|_| ()).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&FederatedLoginError::LinkedElsewhere));
+
+        // Anonymizing an account forgets its identities.
+        assert!(users.anonymize_user(first.user.id).await?);
+        let fresh = users.login_with_identity(&identity("1", &new_email, true), None, SessionKind::Cookie).await?.unwrap();
+        assert_ne!(fresh.user.id, first.user.id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inactive_accounts_are_warned_then_deactivated() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let docs = DocumentService::new(manager.clone()).await?;
+        let permissions = PermissionService::new(manager.clone()).await?;
+        let mailer = Arc::new(RecordingMailer::default());
+        let users = UserService::new(manager.clone()).await?.with_mailer(mailer.clone(), "https://collaborate.example".to_string());
+        let password = "correct horse battery";
+        let idle_email = format!("{}@example.com", Uuid::new_v4().simple());
+        let idle = users.register_user("Idle", &idle_email, password).await?;
+        let owner_email = format!("{}@example.com", Uuid::new_v4().simple());
+        let owner = users.register_user("Owner", &owner_email, password).await?;
+        let doc = docs.create_document_for("Shared Document for Inactivity", Some(owner.id)).await?;
+        permissions.share_document(doc.id, idle.id, PolicyRole::Viewer, owner.id).await?;
+        let session = users.login(&idle_email, password).await?.expect("login should succeed");
+
+        // Backdate both accounts so that only they are old enough to be affected.
+        let long_ago = DateTime::parse_from_rfc3339("2001-01-01T00:00:00Z")?.with_timezone(&Utc);
+        sqlx::query("UPDATE users SET created_at = $1, last_login_at = $1 WHERE id = ANY($2)")
+            .bind(long_ago)
+            .bind(vec![idle.id, owner.id])
+            .execute(&*manager.pool)
+            .await?;
+        let cutoff = long_ago + TimeDelta::days(30);
+        let now = long_ago + TimeDelta::days(400);
+        let notice = TimeDelta::days(14);
+
+        users.warn_inactive_accounts(cutoff, now + notice, now).await?;
+        let warned_to = |email: &str| mailer.sent.lock().unwrap().iter().filter(|sent| sent.to == email).count();
+        assert_eq!(warned_to(&idle_email), 1);
+        assert_eq!(warned_to(&owner_email), 0, "Sole owners of shared documents are spared");
+        assert!(!users.deactivate_inactive_accounts(cutoff, now - notice, now).await?.contains(&idle.id), "Too soon after the warning");
+
+        let later = now + notice + TimeDelta::days(1);
+        let deactivated = users.deactivate_inactive_accounts(cutoff, later - notice, later).await?;
+        assert!(deactivated.contains(&idle.id));
+        assert!(!deactivated.contains(&owner.id));
+        assert!(users.get_user(idle.id).await?.unwrap().deactivated_at.is_some());
+        assert!(users.authenticate_session(&session.secret).await?.is_none());
+
+        // Logging in again reactivates the account.
+        let session = users.login(&idle_email, password).await?.expect("deactivated accounts can still log in");
+        assert_eq!(session.user.deactivated_at, None);
+        assert_eq!(users.get_user(idle.id).await?.unwrap().deactivated_at, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_suspended_accounts_stay_deactivated_until_reactivated() -> Result<()> {
+        let users = get_test_user_service().await?;
+        let password = "correct horse battery";
+        let email = format!("{}@example.com", Uuid::new_v4().simple());
+        let user = users.register_user("Suspended", &email, password).await?;
+        assert_eq!(user.role, UserRole::User);
+        let session = users.login(&email, password).await?.expect("login should succeed");
+
+        assert!(users.set_role(user.id, UserRole::Admin).await?);
+        assert_eq!(users.get_user(user.id).await?.unwrap().role, UserRole::Admin);
+        assert!(!users.set_role(Uuid::new_v4(), UserRole::Admin).await?);
+        assert!(users.list_users(None, i64::MAX).await?.iter().any(|listed| listed.id == user.id));
+        assert!(!users.list_users(Some(user.id), i64::MAX).await?.iter().any(|listed| listed.id == user.id));
+
+        assert!(users.deactivate_user(user.id).await?);
+        assert!(!users.deactivate_user(user.id).await?, "Already suspended");
+        assert!(users.authenticate_session(&session.secret).await?.is_none());
+        assert!(users.login(&email, password).await?.is_none(), "Signing in doesn't lift a suspension");
+        assert!(users.get_user(user.id).await?.unwrap().suspended);
+
+        assert!(users.reactivate_user(user.id).await?);
+        assert!(!users.reactivate_user(user.id).await?);
+        let session = users.login(&email, password).await?.expect("reactivated accounts can log in");
+        assert_eq!(session.user.deactivated_at, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_tokens_work_once() -> Result<()> {
+        let mailer = Arc::new(RecordingMailer::default());
+        let users = get_test_user_service().await?
+            .with_mailer(mailer.clone(), "https://collaborate.example".to_string())
+            .with_magic_links();
+        let email = format!("{}@example.com", Uuid::new_v4().simple());
+        let user = users.register_user("Margaret", &email, "forgotten password").await?;
+        let session = users.login(&email, "forgotten password").await?.expect("login should succeed");
+        let device = magic_link_device_secret();
+        assert!(users.request_magic_link(&email, &device, Locale::FALLBACK).await?);
+        let sent = mailer.sent.lock().unwrap().pop().expect("a magic link email");
+        let magic_link = sent.text.split("?token=").nth(1).expect("a magic link").trim().to_string();
+        let reset_token = || {
+            let sent = mailer.sent.lock().unwrap().pop().expect("a password reset email");
+            assert_eq!(sent.to, email);
+            sent.text.split("?token=").nth(1).expect("a reset link").trim().to_string()
+        };
+
+        assert!(!users.request_password_reset("nobody@example.com", Locale::FALLBACK).await?);
+        assert!(users.request_password_reset(&email.to_uppercase(), Locale::FALLBACK).await?);
+        let first = reset_token();
+        assert!(users.request_password_reset(&email, Locale::FALLBACK).await?);
+        let second = reset_token();
+
+        let err = users.reset_password(&second, "short").await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RegistrationError>(), Some(&RegistrationError::WeakPassword));
+        assert_eq!(users.reset_password("crt_guess", "remembered password").await?, None);
+        assert_eq!(users.reset_password(&second, "remembered password").await?, Some(user.id));
+
+        // Using one token voids the rest, and the old password and sessions stop working.
+        assert_eq!(users.reset_password(&first, "another password").await?, None);
+        assert_eq!(users.reset_password(&second, "another password").await?, None);
+        assert!(users.authenticate_session(&session.secret).await?.is_none());
+        assert!(users.login_with_magic_link(&magic_link, &device, SessionKind::Token).await?.is_none());
+        assert!(users.login(&email, "forgotten password").await?.is_none());
+        assert!(users.login(&email, "remembered password").await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cookie_sessions_slide_and_can_be_revoked() -> Result<()> {
+        let users = get_test_user_service().await?;
+        let email = format!("{}@example.com", Uuid::new_v4().simple());
+        let user = users.register_user("Frances", &email, "correct horse battery").await?;
+        let token_session = users.login(&email, "correct horse battery").await?.expect("login should succeed");
+        let cookie_session = users.login_with(&email, "correct horse battery", SessionKind::Cookie).await?.expect("login should succeed");
+
+        // Each kind of secret only works where it belongs.
+        let session = users.authenticate_cookie(&cookie_session.secret).await?.expect("cookie should authenticate");
+        assert_eq!(session, CookieSession { session_id: cookie_session.session_id, user_id: user.id, extended_until: None });
+        assert!(users.authenticate_cookie(&token_session.secret).await?.is_none());
+        assert!(users.refresh_session(&cookie_session.secret).await?.is_none());
+
+        let sessions = users.list_sessions(user.id).await?;
+        let kinds: Vec<_> = sessions.iter().map(|session| (session.id, session.kind)).collect();
+        assert_eq!(kinds, vec![(cookie_session.session_id, SessionKind::Cookie), (token_session.session_id, SessionKind::Token)]);
+
+        // Using a session that is closer to expiring extends it.
+        sqlx::query("UPDATE user_sessions SET expires_at = $1 WHERE id = $2")
+            .bind(Utc::now() + TimeDelta::days(1))
+            .bind(cookie_session.session_id)
+            .execute(&*users.db_manager.pool)
+            .await?;
+        let session = users.authenticate_cookie(&cookie_session.secret).await?.expect("cookie should authenticate");
+        assert!(session.extended_until.is_some_and(|until| until > Utc::now() + TimeDelta::days(13)));
+
+        assert!(!users.revoke_session(Uuid::new_v4(), cookie_session.session_id).await?, "Only the owner can revoke");
+        assert!(users.revoke_session(user.id, cookie_session.session_id).await?);
+        assert!(users.authenticate_cookie(&cookie_session.secret).await?.is_none());
+        assert!(!users.revoke_session(user.id, cookie_session.session_id).await?);
+        assert_eq!(users.list_sessions(user.id).await?.len(), 1);
+        Ok(())
+    }
+}
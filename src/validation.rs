@@ -0,0 +1,229 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Declarative validation of request bodies. A request type states its rules in a
+//! `Validate` impl and handlers receive it through the `Valid` extractor, which answers
+//! with a 422 naming every offending field rather than stopping at the first.
+
+use crate::api_error::ApiError;
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, Request};
+use axum::http::{header, StatusCode};
+use serde::de::DeserializeOwned;
+
+/// A message key describing what is wrong with a field, and the values it interpolates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldError {
+    pub key: &'static str,
+    pub args: Vec<(&'static str, String)>,
+}
+
+impl FieldError {
+    pub fn new(key: &'static str) -> Self {
+        FieldError { key, args: Vec::new() }
+    }
+
+    pub fn with_arg(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.args.push((name, value.to_string()));
+        self
+    }
+}
+
+/// Errors by field, in the order the fields were checked. Only a field's first error is kept.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FieldErrors(Vec<(String, FieldError)>);
+
+impl FieldErrors {
+    /// Records `error` against `field` unless `valid`.
+    pub fn check(&mut self, field: &str, valid: bool, error: FieldError) -> &mut Self {
+        if !valid {
+            self.add(field, error);
+        }
+        self
+    }
+
+    pub fn add(&mut self, field: impl Into<String>, error: FieldError) {
+        let field = field.into();
+        if !self.0.iter().any(|(existing, _)| *existing == field) {
+            self.0.push((field, error));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &FieldError)> {
+        self.0.iter().map(|(field, error)| (field.as_str(), error))
+    }
+}
+
+/// Rules a request must satisfy beyond deserializing, which already settles types, UUIDs
+/// and enum values.
+pub trait Validate {
+    fn validate(&self, _errors: &mut FieldErrors) {}
+}
+
+/// Whether `value` has between `min` and `max` characters once surrounding whitespace is trimmed.
+pub fn length(value: &str, min: usize, max: usize) -> bool {
+    (min..=max).contains(&value.trim().chars().count())
+}
+
+/// Whether `email` looks like a deliverable address: a dot-atom local part and a domain
+/// of at least two labels ending in an alphabetic top-level domain. Whoever delivers mail
+/// to it has the final say.
+pub fn is_email(email: &str) -> bool {
+    const LOCAL_SYMBOLS: &str = "!#$%&'*+/=?^_`{|}~.-";
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+    let local_ok = !local.is_empty()
+        && local.len() <= 64
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local.chars().all(|c| c.is_alphanumeric() || LOCAL_SYMBOLS.contains(c));
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        })
+        && labels.last().is_some_and(|tld| tld.chars().count() >= 2 && tld.chars().all(char::is_alphabetic));
+    email.len() <= 254 && local_ok && domain_ok
+}
+
+pub fn is_timezone(name: &str) -> bool {
+    name.parse::<chrono_tz::Tz>().is_ok()
+}
+
+/// Whether `color` is a hex colour written `#rrggbb`.
+pub fn is_color(color: &str) -> bool {
+    color.strip_prefix('#').is_some_and(|hex| hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// A JSON request body that deserialized and passed its `Validate` rules. Bodies over the
+/// route's `DefaultBodyLimit` are refused with a 413.
+pub struct Valid<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Valid<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let invalid_body = || ApiError::new(StatusCode::BAD_REQUEST, "invalid-request-body");
+        let is_json = request.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json") || value.contains("+json"));
+        if !is_json {
+            return Err(invalid_body());
+        }
+        let body = Bytes::from_request(request, state).await.map_err(|rejection| match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "request-body-too-large"),
+            _ => invalid_body(),
+        })?;
+        let mut deserializer = serde_json::Deserializer::from_slice(&body);
+        let value: T = match serde_path_to_error::deserialize(&mut deserializer) {
+            Ok(value) => value,
+            Err(e) => return Err(match deserialize_error(&e) {
+                Some(errors) => ApiError::invalid_fields(errors),
+                None => invalid_body(),
+            }),
+        };
+        deserializer.end().map_err(|_| invalid_body())?;
+
+        let mut errors = FieldErrors::default();
+        value.validate(&mut errors);
+        if !errors.is_empty() {
+            return Err(ApiError::invalid_fields(errors));
+        }
+        Ok(Valid(value))
+    }
+}
+
+/// The field a deserialization error is about, or `None` if the body is not a JSON object.
+fn deserialize_error(e: &serde_path_to_error::Error<serde_json::Error>) -> Option<FieldErrors> {
+    if !e.inner().is_data() {
+        return None;
+    }
+    let path = e.path().to_string();
+    let parent = (path != ".").then_some(path);
+    let mut errors = FieldErrors::default();
+    // A missing field is reported against the object it is missing from.
+    let message = e.inner().to_string();
+    match message.strip_prefix("missing field `").and_then(|rest| rest.split_once('`')) {
+        Some((field, _)) => {
+            let field = parent.map_or_else(|| field.to_string(), |parent| format!("{}.{}", parent, field));
+            errors.add(field, FieldError::new("missing-field"));
+        }
+        None => errors.add(parent?, FieldError::new("invalid-field-value")),
+    }
+    Some(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[allow(dead_code)]
+    #[derive(Debug, Deserialize)]
+    struct Signup {
+        name: String,
+        age: u8,
+    }
+
+    fn errors_for(body: &str) -> Option<Vec<(String, &'static str)>> {
+        let e = serde_path_to_error::deserialize::<_, Signup>(&mut serde_json::Deserializer::from_str(body)).unwrap_err();
+        deserialize_error(&e).map(|errors| errors.iter().map(|(field, error)| (field.to_string(), error.key)).collect())
+    }
+
+    #[test]
+    fn test_deserialize_errors_name_fields() {
+        assert_eq!(errors_for(r#"{"name": "Ada"}"#), Some(vec![("age".to_string(), "missing-field")]));
+        assert_eq!(errors_for(r#"{"name": "Ada", "age": "old"}"#), Some(vec![("age".to_string(), "invalid-field-value")]));
+        assert_eq!(errors_for(r#"["Ada", 36"#), None);
+        assert_eq!(errors_for("[]"), None);
+    }
+
+    #[test]
+    fn test_rules() {
+        assert!(length("  Plan  ", 1, 4));
+        assert!(!length("   ", 1, 4));
+        assert!(is_email("ada.lovelace+notes@example.co.uk"));
+        for invalid in ["ada", "ada@example", "ada@-example.com", "ada..l@example.com", "ada@example.c0m", "a@b@example.com"] {
+            assert!(!is_email(invalid), "{} should be rejected", invalid);
+        }
+        assert!(is_timezone("Europe/Berlin") && !is_timezone("Mars/Olympus"));
+        assert!(is_color("#3a7BFF"));
+        for invalid in ["3a7bff", "#3a7bf", "#3a7bffe", "#3a7bfg", "#ÿÿÿ"] {
+            assert!(!is_color(invalid), "{} should be rejected", invalid);
+        }
+
+        let mut errors = FieldErrors::default();
+        errors
+            .check("name", false, FieldError::new("invalid-document-name"))
+            .check("name", false, FieldError::new("missing-field"))
+            .check("email", true, FieldError::new("invalid-email"));
+        assert_eq!(errors.iter().map(|(field, error)| (field, error.key)).collect::<Vec<_>>(), [("name", "invalid-document-name")]);
+    }
+}
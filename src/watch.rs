@@ -0,0 +1,314 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Document watches: users follow documents without keeping a connection open and
+//! get a periodic digest of what changed instead of a notification per edit.
+
+use crate::db::Manager;
+use crate::migrations::{self, Component, Migration};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{FromRow};
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+const DIGEST_INTERVAL_ENV: &str = "COLLABORATE_DIGEST_INTERVAL_SECS";
+pub const DOCUMENT_DIGEST: &str = "document_digest";
+/// Asks an owner to approve or reject deleting a document.
+pub const DELETION_REQUESTED: &str = "deletion_requested";
+/// Tells whoever asked to delete a document what became of it.
+pub const DELETION_DECIDED: &str = "deletion_decided";
+
+/// How often watch digests are assembled. Reads `COLLABORATE_DIGEST_INTERVAL_SECS`.
+pub fn digest_interval_from_env() -> Result<Duration> {
+    let secs = match env::var(DIGEST_INTERVAL_ENV) {
+        Ok(secs) => secs.parse().context(format!("{} must be a number of seconds", DIGEST_INTERVAL_ENV))?,
+        Err(_) => 15 * 60,
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+/// An in-app notification.
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+pub struct Notification {
+    pub id: Uuid,
+    pub kind: String,
+    pub payload: Value,
+    pub created_at: DateTime<Utc>,
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+// A watched document that changed since its watcher was last notified.
+#[derive(FromRow)]
+struct PendingChange {
+    user_id: Uuid,
+    document_id: Uuid,
+    name: Option<String>,
+    seq: i64,
+    notified_seq: i64,
+}
+
+#[derive(Clone)]
+pub struct WatchService {
+    db_manager: Arc<Manager>,
+}
+
+pub(crate) const SCHEMA: Component = Component {
+    name: "watch",
+    migrations: &[Migration {
+        version: 1,
+        description: "baseline",
+        up: &[
+            "CREATE TABLE IF NOT EXISTS document_watches (
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                document_id UUID NOT NULL REFERENCES documents_metadata(id) ON DELETE CASCADE,
+                notified_seq BIGINT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (user_id, document_id)
+            )",
+            "CREATE TABLE IF NOT EXISTS notifications (
+                id UUID PRIMARY KEY,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                kind TEXT NOT NULL,
+                payload JSONB NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                read_at TIMESTAMPTZ
+            )",
+        ],
+        down: &[
+            "DROP TABLE IF EXISTS notifications",
+            "DROP TABLE IF EXISTS document_watches",
+        ],
+    }],
+};
+
+impl WatchService {
+    pub async fn new(db_manager: Arc<Manager>) -> Result<Self> {
+        let service = WatchService { db_manager };
+        service.initialize_schema().await?;
+        Ok(service)
+    }
+
+    async fn initialize_schema(&self) -> Result<()> {
+        migrations::apply(&self.db_manager.pool, &SCHEMA).await?;
+        info!("Watch schema initialized.");
+        Ok(())
+    }
+
+    /// Starts watching a document; only changes from now on are reported.
+    /// Returns false if the document does not exist.
+    #[instrument(skip_all, fields(user_id = %user_id, doc_id = %doc_id))]
+    pub async fn watch(&self, user_id: Uuid, doc_id: Uuid) -> Result<bool> {
+        let result = sqlx::query(
+                "INSERT INTO document_watches (user_id, document_id, notified_seq, created_at)
+                 SELECT $1, id, seq, $3 FROM documents_metadata WHERE id = $2 AND deleted_at IS NULL
+                 ON CONFLICT (user_id, document_id) DO NOTHING"
+            )
+            .bind(user_id)
+            .bind(doc_id)
+            .bind(Utc::now())
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to watch document ID {} for user ID {}", doc_id, user_id))?;
+        if result.rows_affected() > 0 {
+            return Ok(true);
+        }
+        // Nothing inserted: either already watching, or there is no such document.
+        let watching: Option<i32> = sqlx::query_scalar("SELECT 1 FROM document_watches WHERE user_id = $1 AND document_id = $2")
+            .bind(user_id)
+            .bind(doc_id)
+            .fetch_optional(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to check watch on document ID {}", doc_id))?;
+        Ok(watching.is_some())
+    }
+
+    #[instrument(skip_all, fields(user_id = %user_id, doc_id = %doc_id))]
+    pub async fn unwatch(&self, user_id: Uuid, doc_id: Uuid) -> Result<()> {
+        sqlx::query("DELETE FROM document_watches WHERE user_id = $1 AND document_id = $2")
+            .bind(user_id)
+            .bind(doc_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to unwatch document ID {} for user ID {}", doc_id, user_id))?;
+        Ok(())
+    }
+
+    /// A user's unread notifications, newest first.
+    #[instrument(skip_all, fields(user_id = %user_id))]
+    pub async fn unread_notifications(&self, user_id: Uuid, limit: i64) -> Result<Vec<Notification>> {
+        sqlx::query_as(
+                "SELECT id, kind, payload, created_at, read_at FROM notifications
+                 WHERE user_id = $1 AND read_at IS NULL ORDER BY created_at DESC LIMIT $2"
+            )
+            .bind(user_id)
+            .bind(limit)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to list notifications for user ID {}", user_id))
+    }
+
+    #[instrument(skip_all, fields(user_id = %user_id, notification_id = %notification_id))]
+    pub async fn mark_notification_read(&self, user_id: Uuid, notification_id: Uuid) -> Result<()> {
+        sqlx::query("UPDATE notifications SET read_at = $1 WHERE id = $2 AND user_id = $3 AND read_at IS NULL")
+            .bind(Utc::now())
+            .bind(notification_id)
+            .bind(user_id)
+            .execute(&*self.db_manager.pool)
+            .await
+            .context(format!("Failed to mark notification ID {} read", notification_id))?;
+        Ok(())
+    }
+
+    /// Sends the same notification to each of `user_ids`.
+    #[instrument(skip_all, fields(kind = kind))]
+    pub async fn notify(&self, user_ids: &[Uuid], kind: &str, payload: Value) -> Result<()> {
+        let now = Utc::now();
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        for user_id in user_ids {
+            sqlx::query(
+                    "INSERT INTO notifications (id, user_id, kind, payload, created_at) VALUES ($1, $2, $3, $4, $5)"
+                )
+                .bind(Uuid::new_v4())
+                .bind(user_id)
+                .bind(kind)
+                .bind(&payload)
+                .bind(now)
+                .execute(&mut *tx)
+                .await
+                .context(format!("Failed to notify user ID {}", user_id))?;
+        }
+        tx.commit().await.context(format!("Failed to commit {} notifications", kind))
+    }
+
+    /// Sends each watcher one notification covering every watched document that changed
+    /// since their last digest. Returns the number of notifications created.
+    #[instrument(skip_all)]
+    pub async fn send_digests(&self) -> Result<usize> {
+        let changes: Vec<PendingChange> = sqlx::query_as(
+                "SELECT w.user_id, w.document_id, m.name, m.seq, w.notified_seq
+                 FROM document_watches w JOIN documents_metadata m ON m.id = w.document_id
+                 WHERE m.seq > w.notified_seq"
+            )
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to find changes to watched documents")?;
+        let mut by_user: BTreeMap<Uuid, Vec<PendingChange>> = BTreeMap::new();
+        for change in changes {
+            by_user.entry(change.user_id).or_default().push(change);
+        }
+
+        let now = Utc::now();
+        for (user_id, changes) in &by_user {
+            let documents: Vec<Value> = changes
+                .iter()
+                .map(|change| json!({
+                    "id": change.document_id,
+                    "name": change.name,
+                    "changes": change.seq - change.notified_seq,
+                }))
+                .collect();
+            let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+            sqlx::query(
+                    "INSERT INTO notifications (id, user_id, kind, payload, created_at) VALUES ($1, $2, $3, $4, $5)"
+                )
+                .bind(Uuid::new_v4())
+                .bind(user_id)
+                .bind(DOCUMENT_DIGEST)
+                .bind(json!({ "documents": documents }))
+                .bind(now)
+                .execute(&mut *tx)
+                .await
+                .context(format!("Failed to create digest for user ID {}", user_id))?;
+            for change in changes {
+                // Only advance to what this digest reported; later changes go in the next one.
+                sqlx::query(
+                        "UPDATE document_watches SET notified_seq = $1
+                         WHERE user_id = $2 AND document_id = $3 AND notified_seq < $1"
+                    )
+                    .bind(change.seq)
+                    .bind(user_id)
+                    .bind(change.document_id)
+                    .execute(&mut *tx)
+                    .await
+                    .context(format!("Failed to record digest of document ID {}", change.document_id))?;
+            }
+            tx.commit().await.context(format!("Failed to commit digest for user ID {}", user_id))?;
+        }
+        Ok(by_user.len())
+    }
+}
+
+/// Periodically sends watch digests.
+pub async fn run_digester(watches: Arc<WatchService>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match watches.send_digests().await {
+            Ok(0) => {}
+            Ok(sent) => info!("Sent {} watch digests", sent),
+            Err(e) => warn!("Sending watch digests failed; will retry: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_service::DocumentService;
+    use crate::user_service::UserService;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[tokio::test]
+    async fn test_digests_cover_changes_since_last_digest() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = DocumentService::new(manager.clone()).await?;
+        let users = UserService::new(manager.clone()).await?;
+        let watches = WatchService::new(manager).await?;
+
+        let watcher = users.create_bot("Watcher", None).await?;
+        let metadata = doc_service.create_document("Test Document for Watches").await?;
+        assert!(watches.watch(watcher.id, metadata.id).await?);
+        assert!(watches.watch(watcher.id, metadata.id).await?, "Watching twice is fine");
+        assert!(!watches.watch(watcher.id, Uuid::new_v4()).await?);
+
+        doc_service.update_document_content(metadata.id, vec![1]).await?;
+        doc_service.update_document_content(metadata.id, vec![2]).await?;
+        watches.send_digests().await?;
+        let notifications = watches.unread_notifications(watcher.id, 10).await?;
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].kind, DOCUMENT_DIGEST);
+        assert_eq!(notifications[0].payload["documents"][0]["changes"], 2);
+
+        // Nothing new to report, so no second digest.
+        watches.send_digests().await?;
+        assert_eq!(watches.unread_notifications(watcher.id, 10).await?.len(), 1);
+        watches.mark_notification_read(watcher.id, notifications[0].id).await?;
+        assert!(watches.unread_notifications(watcher.id, 10).await?.is_empty());
+
+        watches.unwatch(watcher.id, metadata.id).await?;
+        doc_service.update_document_content(metadata.id, vec![3]).await?;
+        watches.send_digests().await?;
+        assert!(watches.unread_notifications(watcher.id, 10).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Outgoing webhooks: events are POSTed as JSON to the URL in `COLLABORATE_WEBHOOK_URL`.
+
+use crate::events::schema::{EventSchema, WebhookEnvelope};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::env;
+use std::time::Duration;
+
+const WEBHOOK_URL_ENV: &str = "COLLABORATE_WEBHOOK_URL";
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Webhooks {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Webhooks {
+    pub fn new(url: String) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .context("Failed to build webhook HTTP client")?;
+        Ok(Webhooks { client, url })
+    }
+
+    /// Returns `None` when `COLLABORATE_WEBHOOK_URL` is unset, i.e. webhooks are disabled.
+    pub fn from_env() -> Result<Option<Self>> {
+        match env::var(WEBHOOK_URL_ENV) {
+            Ok(url) => Ok(Some(Webhooks::new(url)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Delivers `data`, which must have the shape `schema` registers.
+    pub async fn send(&self, schema: &EventSchema, data: impl Serialize) -> Result<()> {
+        self.client
+            .post(&self.url)
+            .json(&WebhookEnvelope::new(schema, data))
+            .send()
+            .await
+            .context(format!("Failed to deliver {} webhook to {}", schema.name, self.url))?
+            .error_for_status()
+            .context(format!("Webhook endpoint rejected {} event", schema.name))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,639 @@
+// Copyright (C) 2025 Kevin Exton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Moving a workspace between deployments. An export is a self-contained JSON archive of
+//! the workspace's folders and documents with their versions, comments and shares. Accounts
+//! are referenced by email rather than copied, and every ID is replaced on import, so one
+//! deployment's workspaces can be consolidated into another's without collisions.
+
+use crate::db::Manager;
+use crate::document_service::{self, CommentAnchor, DocumentMetadata, DocumentService};
+use crate::integrity;
+use crate::permissions::{MemberRole, PolicyRole};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+/// Bumped whenever the archive layout changes incompatibly.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorkspaceArchive {
+    pub format_version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub workspace: ArchivedWorkspace,
+    /// Every account the rest of the archive refers to that existed in the exporting deployment.
+    pub users: Vec<UserReference>,
+    pub members: Vec<ArchivedMember>,
+    /// Parents come before their children.
+    pub folders: Vec<ArchivedFolder>,
+    /// Documents in the trash are left out.
+    pub documents: Vec<ArchivedDocument>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedWorkspace {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub policy: Option<ArchivedPolicy>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedPolicy {
+    pub member_role: PolicyRole,
+    pub updated_by: Uuid,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// An account as the exporting deployment knew it. Accounts are matched on import by email,
+/// so bots and anonymized accounts, which have none, are never matched.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserReference {
+    pub id: Uuid,
+    pub email: Option<String>,
+    pub display_name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedMember {
+    pub user_id: Uuid,
+    pub role: MemberRole,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ArchivedFolder {
+    pub id: Uuid,
+    pub name: String,
+    pub parent_id: Option<Uuid>,
+    #[serde(default)]
+    pub deletion_requires_approval: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedDocument {
+    pub id: Uuid,
+    pub name: String,
+    pub owner_id: Option<Uuid>,
+    pub folder_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    #[serde(with = "content")]
+    pub content: Vec<u8>,
+    /// Oldest first.
+    pub versions: Vec<ArchivedVersion>,
+    /// Oldest first.
+    pub comments: Vec<ArchivedComment>,
+    pub permissions: Vec<ArchivedPermission>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ArchivedVersion {
+    pub version: i64,
+    pub name: String,
+    #[serde(with = "content")]
+    pub content: Vec<u8>,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedComment {
+    /// Missing from archives made before comments had threads, where each comment starts
+    /// its own.
+    #[serde(default)]
+    pub id: Option<Uuid>,
+    #[serde(default)]
+    pub thread_id: Option<Uuid>,
+    #[serde(default)]
+    pub anchor: Option<CommentAnchor>,
+    pub author: Option<Uuid>,
+    pub body: String,
+    #[serde(default)]
+    pub resolved: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedPermission {
+    pub user_id: Uuid,
+    pub role: PolicyRole,
+    pub granted_by: Uuid,
+    pub granted_at: DateTime<Utc>,
+}
+
+/// The columns of `documents_metadata` a `DocumentRow` is read from.
+pub(crate) const DOCUMENT_COLUMNS: &str = "id, name, owner_id, parent_folder_id, created_at, updated_at";
+
+#[derive(FromRow)]
+pub(crate) struct DocumentRow {
+    id: Uuid,
+    name: String,
+    owner_id: Option<Uuid>,
+    parent_folder_id: Option<Uuid>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+mod content {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        BASE64.decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Archives one document with its content, versions, comments and shares.
+pub(crate) async fn archive_document(pool: &PgPool, doc_service: &DocumentService, row: DocumentRow) -> Result<ArchivedDocument> {
+    let id = row.id;
+    let content = doc_service.get_document_content(id).await?.map(|content| content.crdt_data).unwrap_or_default();
+    let mut versions: Vec<ArchivedVersion> = sqlx::query_as(
+            "SELECT version, name, crdt_data AS content, created_by, created_at FROM document_versions
+             WHERE document_id = $1 ORDER BY version"
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await
+        .context(format!("Failed to list versions of document ID {}", id))?;
+    for version in &mut versions {
+        version.content = doc_service.open(id, std::mem::take(&mut version.content)).await?;
+    }
+    let comments = doc_service.list_comments(id).await?;
+    let permissions: Vec<(Uuid, String, Uuid, DateTime<Utc>)> = sqlx::query_as(
+            "SELECT user_id, role, granted_by, granted_at FROM document_permissions WHERE document_id = $1 ORDER BY granted_at, user_id"
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await
+        .context(format!("Failed to list shares of document ID {}", id))?;
+    Ok(ArchivedDocument {
+        id,
+        name: row.name,
+        owner_id: row.owner_id,
+        folder_id: row.parent_folder_id,
+        created_at: row.created_at,
+        updated_at: row.updated_at,
+        content,
+        versions,
+        comments: comments.into_iter()
+            .map(|comment| ArchivedComment {
+                id: Some(comment.id),
+                thread_id: Some(comment.thread_id),
+                anchor: comment.anchor,
+                author: comment.author,
+                body: comment.body,
+                resolved: comment.resolved,
+                created_at: comment.created_at,
+            })
+            .collect(),
+        permissions: permissions.into_iter()
+            .map(|(user_id, role, granted_by, granted_at)| Ok(ArchivedPermission { user_id, role: role.parse()?, granted_by, granted_at }))
+            .collect::<Result<_>>()?,
+    })
+}
+
+/// What an import did, or with `plan_import`, would do.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ImportReport {
+    /// The new workspace; `None` for a plan.
+    pub workspace_id: Option<Uuid>,
+    pub folders: usize,
+    pub documents: usize,
+    pub members: usize,
+    /// Accounts with no counterpart here. Their memberships and shares are dropped, and
+    /// what they owned or wrote is kept without them.
+    pub unmatched_users: Vec<UserReference>,
+}
+
+pub struct WorkspaceArchiver {
+    db_manager: Arc<Manager>,
+    doc_service: Arc<DocumentService>,
+}
+
+impl WorkspaceArchiver {
+    /// Expects the user, permission and folder schemas to be initialized.
+    pub fn new(db_manager: Arc<Manager>, doc_service: Arc<DocumentService>) -> Self {
+        WorkspaceArchiver { db_manager, doc_service }
+    }
+
+    /// Archives a workspace. `None` if there is no such workspace.
+    #[instrument(skip_all, fields(workspace_id = %workspace_id))]
+    pub async fn export(&self, workspace_id: Uuid) -> Result<Option<WorkspaceArchive>> {
+        let pool = &*self.db_manager.pool;
+        let row: Option<(String, DateTime<Utc>)> = sqlx::query_as("SELECT name, created_at FROM workspaces WHERE id = $1")
+            .bind(workspace_id)
+            .fetch_optional(pool)
+            .await
+            .context(format!("Failed to query workspace ID {}", workspace_id))?;
+        let Some((name, created_at)) = row else {
+            return Ok(None);
+        };
+        let policy: Option<(String, Uuid, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT member_role, updated_by, updated_at FROM workspace_policies WHERE workspace_id = $1"
+            )
+            .bind(workspace_id)
+            .fetch_optional(pool)
+            .await
+            .context(format!("Failed to query default policy of workspace ID {}", workspace_id))?;
+        let policy = match policy {
+            Some((member_role, updated_by, updated_at)) => Some(ArchivedPolicy { member_role: member_role.parse()?, updated_by, updated_at }),
+            None => None,
+        };
+        let members: Vec<(Uuid, String, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT user_id, role, created_at FROM workspace_members WHERE workspace_id = $1 ORDER BY created_at, user_id"
+            )
+            .bind(workspace_id)
+            .fetch_all(pool)
+            .await
+            .context(format!("Failed to list members of workspace ID {}", workspace_id))?;
+        let members = members.into_iter()
+            .map(|(user_id, role, created_at)| Ok(ArchivedMember { user_id, role: role.parse()?, created_at }))
+            .collect::<Result<Vec<_>>>()?;
+        let folders: Vec<ArchivedFolder> = sqlx::query_as(
+                "WITH RECURSIVE tree AS (
+                     SELECT id, name, parent_id, deletion_requires_approval, created_at, updated_at, 0 AS depth FROM folders
+                     WHERE workspace_id = $1 AND parent_id IS NULL
+                     UNION ALL
+                     SELECT f.id, f.name, f.parent_id, f.deletion_requires_approval, f.created_at, f.updated_at, tree.depth + 1
+                     FROM folders f JOIN tree ON f.parent_id = tree.id
+                 )
+                 SELECT id, name, parent_id, deletion_requires_approval, created_at, updated_at FROM tree ORDER BY depth, name, id"
+            )
+            .bind(workspace_id)
+            .fetch_all(pool)
+            .await
+            .context(format!("Failed to list folders of workspace ID {}", workspace_id))?;
+
+        let document_rows: Vec<DocumentRow> = sqlx::query_as(&format!(
+                "SELECT {DOCUMENT_COLUMNS} FROM documents_metadata
+                 WHERE workspace_id = $1 AND deleted_at IS NULL ORDER BY created_at, id"
+            ))
+            .bind(workspace_id)
+            .fetch_all(pool)
+            .await
+            .context(format!("Failed to list documents of workspace ID {}", workspace_id))?;
+        let mut documents = Vec::with_capacity(document_rows.len());
+        for row in document_rows {
+            documents.push(archive_document(pool, &self.doc_service, row).await?);
+        }
+
+        let mut archive = WorkspaceArchive {
+            format_version: FORMAT_VERSION,
+            exported_at: Utc::now(),
+            workspace: ArchivedWorkspace { id: workspace_id, name, created_at, policy },
+            users: Vec::new(),
+            members,
+            folders,
+            documents,
+        };
+        let referenced: Vec<Uuid> = archive.referenced_users().into_iter().collect();
+        archive.users = sqlx::query_as::<_, (Uuid, Option<String>, String)>(
+                "SELECT id, email, display_name FROM users WHERE id = ANY($1) ORDER BY id"
+            )
+            .bind(&referenced)
+            .fetch_all(pool)
+            .await
+            .context("Failed to look up referenced users")?
+            .into_iter()
+            .map(|(id, email, display_name)| UserReference { id, email, display_name })
+            .collect();
+        info!("Exported workspace ID {} with {} documents", workspace_id, archive.documents.len());
+        Ok(Some(archive))
+    }
+
+    /// Reports what `import` would do without changing anything.
+    #[instrument(skip_all, fields(workspace_id = %archive.workspace.id))]
+    pub async fn plan_import(&self, archive: &WorkspaceArchive) -> Result<ImportReport> {
+        let (_, report) = self.match_users(archive).await?;
+        Ok(report)
+    }
+
+    /// Recreates an archived workspace under new IDs, with archived accounts replaced by the
+    /// ones here that have the same email. The workspace's structure is created in one
+    /// transaction; document content is then written document by document, subject to
+    /// the deployment's hooks.
+    #[instrument(skip_all, fields(workspace_id = %archive.workspace.id))]
+    pub async fn import(&self, archive: &WorkspaceArchive) -> Result<ImportReport> {
+        let (users, mut report) = self.match_users(archive).await?;
+        let user = |id: Uuid| users.get(&id).copied();
+        // Audit columns must name someone; nobody here is the honest answer when the
+        // account did not come along.
+        let audited = |id: Uuid| user(id).unwrap_or(Uuid::nil());
+
+        let workspace_id = Uuid::new_v4();
+        let mut tx = self.db_manager.pool.begin().await.context("Failed to start transaction")?;
+        sqlx::query("INSERT INTO workspaces (id, name, created_at) VALUES ($1, $2, $3)")
+            .bind(workspace_id)
+            .bind(&archive.workspace.name)
+            .bind(archive.workspace.created_at)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to create workspace '{}'", archive.workspace.name))?;
+        if let Some(policy) = &archive.workspace.policy {
+            sqlx::query("INSERT INTO workspace_policies (workspace_id, member_role, updated_by, updated_at) VALUES ($1, $2, $3, $4)")
+                .bind(workspace_id)
+                .bind(policy.member_role.as_str())
+                .bind(audited(policy.updated_by))
+                .bind(policy.updated_at)
+                .execute(&mut *tx)
+                .await
+                .context("Failed to import the default policy")?;
+        }
+        for member in &archive.members {
+            let Some(user_id) = user(member.user_id) else {
+                continue;
+            };
+            sqlx::query(
+                    "INSERT INTO workspace_members (workspace_id, user_id, role, created_at) VALUES ($1, $2, $3, $4)
+                     ON CONFLICT (workspace_id, user_id) DO NOTHING"
+                )
+                .bind(workspace_id)
+                .bind(user_id)
+                .bind(member.role.as_str())
+                .bind(member.created_at)
+                .execute(&mut *tx)
+                .await
+                .context(format!("Failed to add user ID {} to workspace ID {}", user_id, workspace_id))?;
+        }
+
+        let mut folders = HashMap::new();
+        for folder in &archive.folders {
+            let parent_id = match folder.parent_id {
+                Some(parent_id) => Some(*folders.get(&parent_id)
+                    .ok_or_else(|| anyhow!("Folder ID {} comes before its parent in the archive", folder.id))?),
+                None => None,
+            };
+            let id = Uuid::new_v4();
+            sqlx::query(
+                    "INSERT INTO folders (id, name, parent_id, owner_id, workspace_id, deletion_requires_approval, created_at, updated_at)
+                     VALUES ($1, $2, $3, NULL, $4, $5, $6, $7)"
+                )
+                .bind(id)
+                .bind(&folder.name)
+                .bind(parent_id)
+                .bind(workspace_id)
+                .bind(folder.deletion_requires_approval)
+                .bind(folder.created_at)
+                .bind(folder.updated_at)
+                .execute(&mut *tx)
+                .await
+                .context(format!("Failed to import folder '{}'", folder.name))?;
+            folders.insert(folder.id, id);
+        }
+
+        let mut imported = Vec::with_capacity(archive.documents.len());
+        for document in &archive.documents {
+            let id = Uuid::new_v4();
+            let folder_id = document.folder_id.and_then(|folder_id| folders.get(&folder_id).copied());
+            sqlx::query(
+                    "INSERT INTO documents_metadata (id, name, created_at, updated_at, owner_id, workspace_id, parent_folder_id)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7)"
+                )
+                .bind(id)
+                .bind(&document.name)
+                .bind(document.created_at)
+                .bind(document.updated_at)
+                .bind(document.owner_id.and_then(user))
+                .bind(workspace_id)
+                .bind(folder_id)
+                .execute(&mut *tx)
+                .await
+                .context(format!("Failed to import document '{}'", document.name))?;
+            if !document_service::claim_name(&mut tx, folder_id.unwrap_or(workspace_id), &document.name, id).await? {
+                bail!("The archive has more than one document named '{}' in the same folder", document.name);
+            }
+            for version in &document.versions {
+                sqlx::query(
+                        "INSERT INTO document_versions (document_id, version, name, crdt_data, size, checksum, created_by, created_at)
+                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"
+                    )
+                    .bind(id)
+                    .bind(version.version)
+                    .bind(&version.name)
+                    .bind(&version.content)
+                    .bind(version.content.len() as i64)
+                    .bind(integrity::checksum(&version.content))
+                    .bind(version.created_by.and_then(user))
+                    .bind(version.created_at)
+                    .execute(&mut *tx)
+                    .await
+                    .context(format!("Failed to import version {} of document '{}'", version.version, document.name))?;
+            }
+            // Comments are oldest first, so each thread's first comment is imported before its replies.
+            let mut comment_ids = HashMap::new();
+            for comment in &document.comments {
+                let comment_id = Uuid::new_v4();
+                if let Some(archived) = comment.id {
+                    comment_ids.insert(archived, comment_id);
+                }
+                let thread_id = comment.thread_id.and_then(|thread| comment_ids.get(&thread).copied()).unwrap_or(comment_id);
+                let anchor = comment.anchor.as_ref();
+                sqlx::query(
+                        "INSERT INTO document_comments (id, document_id, thread_id, anchor_start, anchor_end, author, body, resolved, created_at)
+                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"
+                    )
+                    .bind(comment_id)
+                    .bind(id)
+                    .bind(thread_id)
+                    .bind(anchor.map(|anchor| &anchor.start))
+                    .bind(anchor.and_then(|anchor| anchor.end.as_ref()))
+                    .bind(comment.author.and_then(user))
+                    .bind(&comment.body)
+                    .bind(comment.resolved)
+                    .bind(comment.created_at)
+                    .execute(&mut *tx)
+                    .await
+                    .context(format!("Failed to import a comment on document '{}'", document.name))?;
+            }
+            for permission in &document.permissions {
+                let Some(user_id) = user(permission.user_id) else {
+                    continue;
+                };
+                sqlx::query(
+                        "INSERT INTO document_permissions (document_id, user_id, role, granted_by, granted_at) VALUES ($1, $2, $3, $4, $5)
+                         ON CONFLICT (document_id, user_id) DO NOTHING"
+                    )
+                    .bind(id)
+                    .bind(user_id)
+                    .bind(permission.role.as_str())
+                    .bind(audited(permission.granted_by))
+                    .bind(permission.granted_at)
+                    .execute(&mut *tx)
+                    .await
+                    .context(format!("Failed to import a share of document '{}'", document.name))?;
+            }
+            let metadata = DocumentMetadata {
+                id,
+                name: document.name.clone(),
+                created_at: document.created_at,
+                updated_at: document.updated_at,
+                language: None,
+            };
+            imported.push((metadata, &document.content));
+        }
+        tx.commit().await.context(format!("Failed to commit import of workspace '{}'", archive.workspace.name))?;
+
+        for (metadata, content) in imported {
+            self.doc_service.restore_document(&metadata, content.clone()).await?;
+        }
+        info!("Imported workspace '{}' as ID {}", archive.workspace.name, workspace_id);
+        report.workspace_id = Some(workspace_id);
+        Ok(report)
+    }
+
+    /// Maps archived accounts to accounts here with the same email.
+    async fn match_users(&self, archive: &WorkspaceArchive) -> Result<(HashMap<Uuid, Uuid>, ImportReport)> {
+        if archive.format_version != FORMAT_VERSION {
+            bail!("Unsupported workspace archive format version {}", archive.format_version);
+        }
+        let emails: Vec<String> = archive.users.iter()
+            .filter_map(|user| user.email.as_ref())
+            .map(|email| email.trim().to_lowercase())
+            .collect();
+        let found: HashMap<String, Uuid> = sqlx::query_as::<_, (String, Uuid)>(
+                "SELECT email, id FROM users WHERE email = ANY($1) AND kind = 'human' AND anonymized_at IS NULL"
+            )
+            .bind(&emails)
+            .fetch_all(&*self.db_manager.pool)
+            .await
+            .context("Failed to match archived users by email")?
+            .into_iter()
+            .collect();
+
+        let mut users = HashMap::new();
+        let mut unmatched_users = Vec::new();
+        for user in &archive.users {
+            match user.email.as_ref().and_then(|email| found.get(&email.trim().to_lowercase())) {
+                Some(id) => {
+                    users.insert(user.id, *id);
+                }
+                None => unmatched_users.push(user.clone()),
+            }
+        }
+        let members = archive.members.iter().filter(|member| users.contains_key(&member.user_id)).count();
+        let report = ImportReport {
+            workspace_id: None,
+            folders: archive.folders.len(),
+            documents: archive.documents.len(),
+            members,
+            unmatched_users,
+        };
+        Ok((users, report))
+    }
+}
+
+impl WorkspaceArchive {
+    fn referenced_users(&self) -> HashSet<Uuid> {
+        let mut ids: HashSet<Uuid> = self.members.iter().map(|member| member.user_id).collect();
+        ids.extend(self.workspace.policy.as_ref().map(|policy| policy.updated_by));
+        for document in &self.documents {
+            ids.extend(document.owner_id);
+            ids.extend(document.versions.iter().filter_map(|version| version.created_by));
+            ids.extend(document.comments.iter().filter_map(|comment| comment.author));
+            for permission in &document.permissions {
+                ids.extend([permission.user_id, permission.granted_by]);
+            }
+        }
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_service::CommentThread;
+    use crate::folders::FolderService;
+    use crate::permissions::PermissionService;
+    use crate::user_service::UserService;
+
+    const TEST_DB_NAME: &str = "collaborate_core_doc_service_test";
+    const COCKROACH_BASE_URI: &str = "root@localhost:26257";
+
+    #[tokio::test]
+    async fn test_workspaces_survive_a_round_trip_under_new_ids() -> Result<()> {
+        let manager = Arc::new(Manager::new(COCKROACH_BASE_URI, TEST_DB_NAME).await?);
+        let doc_service = Arc::new(DocumentService::new(manager.clone()).await?);
+        let users = UserService::new(manager.clone()).await?;
+        let permissions = PermissionService::new(manager.clone()).await?;
+        let folders = FolderService::new(manager.clone(), doc_service.clone()).await?;
+        let archiver = WorkspaceArchiver::new(manager, doc_service.clone());
+        let suffix = Uuid::new_v4();
+        let admin = users.register_user("Admin", &format!("admin-{}@example.com", suffix), "correct horse battery").await?;
+        let reader = users.register_user("Reader", &format!("reader-{}@example.com", suffix), "correct horse battery").await?;
+
+        let workspace = permissions.create_workspace("Team", admin.id).await?;
+        permissions.set_member(workspace.id, reader.id, MemberRole::Member).await?;
+        permissions.set_default_policy(workspace.id, PolicyRole::Commenter, admin.id).await?;
+        let plans = folders.create_folder("Plans", None, Some(workspace.id), None).await?;
+        let doc = doc_service.create_document_in("Roadmap", Some(admin.id), Some(workspace.id)).await?;
+        folders.move_document(doc.id, Some(plans.id)).await?;
+        doc_service.update_document_content(doc.id, b"first draft".to_vec()).await?;
+        doc_service.create_version(doc.id, Some(admin.id)).await?;
+        doc_service.update_document_content(doc.id, b"second draft".to_vec()).await?;
+        let comment = doc_service.add_comment(doc.id, Some(reader.id), CommentThread::Start { anchor: None }, "Looks good").await?.unwrap();
+        doc_service.add_comment(doc.id, Some(admin.id), CommentThread::Reply { to: comment.id }, "Thanks").await?;
+        permissions.share_document(doc.id, reader.id, PolicyRole::Editor, admin.id).await?;
+
+        let mut archive = archiver.export(workspace.id).await?.expect("workspace should exist");
+        assert!(archiver.export(Uuid::new_v4()).await?.is_none());
+        let decoded: WorkspaceArchive = serde_json::from_slice(&serde_json::to_vec(&archive)?)?;
+        assert_eq!(decoded, archive);
+        assert_eq!(archive.users.len(), 2);
+        assert_eq!(archive.documents[0].versions[0].content, b"first draft");
+
+        // The reader has no account in the deployment the archive is imported into.
+        let unknown = format!("elsewhere-{}@example.com", suffix);
+        archive.users.iter_mut().find(|user| user.id == reader.id).unwrap().email = Some(unknown.clone());
+        let plan = archiver.plan_import(&archive).await?;
+        assert_eq!((plan.workspace_id, plan.documents, plan.members), (None, 1, 1));
+        let report = archiver.import(&archive).await?;
+        assert_eq!(report.unmatched_users.iter().map(|user| user.email.clone()).collect::<Vec<_>>(), [Some(unknown)]);
+        let imported_id = report.workspace_id.expect("import should create a workspace");
+        assert_ne!(imported_id, workspace.id);
+
+        let imported = archiver.export(imported_id).await?.expect("imported workspace should exist");
+        assert_eq!(imported.workspace.policy.as_ref().map(|policy| policy.member_role), Some(PolicyRole::Commenter));
+        assert_eq!(imported.members.iter().map(|member| (member.user_id, member.role)).collect::<Vec<_>>(), [(admin.id, MemberRole::Admin)]);
+        assert_eq!(imported.folders.len(), 1);
+        assert_ne!(imported.folders[0].id, plans.id);
+        let copy = &imported.documents[0];
+        assert_ne!(copy.id, doc.id);
+        assert_eq!((copy.name.as_str(), copy.owner_id, copy.folder_id), ("Roadmap", Some(admin.id), Some(imported.folders[0].id)));
+        assert_eq!(copy.content, b"second draft");
+        assert_eq!(copy.versions.iter().map(|version| version.content.clone()).collect::<Vec<_>>(), [b"first draft".to_vec()]);
+        // What the unmatched reader wrote stays, without them; their share does not.
+        assert_eq!(
+            copy.comments.iter().map(|comment| (comment.author, comment.body.as_str())).collect::<Vec<_>>(),
+            [(None, "Looks good"), (Some(admin.id), "Thanks")]
+        );
+        assert_ne!(copy.comments[0].id, Some(comment.id));
+        assert_eq!(copy.comments[1].thread_id, copy.comments[0].id);
+        assert!(copy.permissions.is_empty());
+        Ok(())
+    }
+}